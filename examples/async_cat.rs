@@ -0,0 +1,23 @@
+//! Stream the virtual disk of a qcow2 image to stdout, as raw data.
+//!
+//! Run it with `cargo run --features tokio --example async_cat IMAGE > disk.raw`.
+
+extern crate qcow2;
+extern crate tokio;
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+
+use qcow2::{AsyncSeqReader, Qcow2};
+
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let path = env::args().nth(1).ok_or("usage: async_cat IMAGE")?;
+    let qcow = Qcow2::open(File::open(path)?)?;
+    let mut reader = AsyncSeqReader::new(qcow.into_reader()?);
+    let copied = tokio::io::copy(&mut reader, &mut tokio::io::stdout()).await?;
+    eprintln!("Copied {} bytes", copied);
+    Ok(())
+}
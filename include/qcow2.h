@@ -0,0 +1,70 @@
+#ifndef QCOW2_H
+#define QCOW2_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * An open qcow2 image. Known as `qcow2_t` in C.
+ */
+typedef struct qcow2_t qcow2_t;
+
+/**
+ * Open a qcow2 image.
+ *
+ * On success, stores a new handle in `out` and returns zero. On failure, returns -1, and the
+ * error is available from `qcow2_last_error`.
+ *
+ * # Safety
+ *
+ * `path` must be a valid NUL-terminated string, and `out` must be valid for writes.
+ */
+int qcow2_open(const char *path, qcow2_t **out);
+
+/**
+ * Read guest data from an image.
+ *
+ * Reads up to `len` bytes at guest offset `off` into `buf`. Returns the number of bytes read,
+ * which is less than `len` only at the end of the virtual disk. On failure, returns -1, and
+ * the error is available from `qcow2_last_error`.
+ *
+ * # Safety
+ *
+ * `handle` must be a handle from `qcow2_open` that hasn't been closed, and `buf` must be
+ * valid for writes of `len` bytes.
+ */
+int64_t qcow2_pread(const qcow2_t *handle, void *buf, size_t len, uint64_t off);
+
+/**
+ * Get the size of the virtual disk of an image.
+ *
+ * # Safety
+ *
+ * `handle` must be a handle from `qcow2_open` that hasn't been closed.
+ */
+uint64_t qcow2_virtual_size(const qcow2_t *handle);
+
+/**
+ * Close an image, releasing its resources. Passing NULL does nothing.
+ *
+ * # Safety
+ *
+ * `handle` must be NULL or a handle from `qcow2_open` that hasn't been closed. It must not be
+ * used again afterwards.
+ */
+void qcow2_close(qcow2_t *handle);
+
+/**
+ * Get a description of the last error that occurred on this thread.
+ *
+ * Returns NULL if there has been no error. The string remains valid until the next failing
+ * call on this thread.
+ */
+const char *qcow2_last_error(void);
+
+#endif  /* QCOW2_H */
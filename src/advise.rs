@@ -0,0 +1,221 @@
+use std::cmp::min;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Result};
+use super::diff::GuestRange;
+use super::read::{L2Entry, Reader};
+
+
+// How far past the end of each read to hint data, when reading sequentially.
+const READAHEAD_DATA: u64 = 2 << 20;
+
+/// A hint about how a `Reader` will be used, see `Reader::advise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// No particular pattern, the default.
+    Normal,
+    /// Reads will mostly go forwards through the virtual disk.
+    Sequential,
+    /// Reads will jump around the virtual disk.
+    Random,
+    /// This range of the virtual disk will be read soon.
+    WillNeed(GuestRange),
+}
+
+/// Storage that can be told how it will be read, such as a file.
+///
+/// With the `fadvise` feature, this is implemented for `File` on Linux using `posix_fadvise`.
+pub trait AdviseIo {
+    /// Hint that a range of the storage will be read soon.
+    fn will_need(&self, pos: u64, len: u64) -> io::Result<()>;
+
+    /// Hint how the storage as a whole will be read.
+    ///
+    /// This is only called with `Normal`, `Sequential` or `Random`.
+    fn access_pattern(&self, advice: Advice) -> io::Result<()> {
+        let _ = advice;
+        Ok(())
+    }
+}
+
+impl<S: AdviseIo + ?Sized> AdviseIo for &S {
+    fn will_need(&self, pos: u64, len: u64) -> io::Result<()> {
+        (**self).will_need(pos, len)
+    }
+
+    fn access_pattern(&self, advice: Advice) -> io::Result<()> {
+        (**self).access_pattern(advice)
+    }
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "fadvise"))]
+mod file {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    use super::{Advice, AdviseIo};
+
+    fn fadvise(file: &File, pos: u64, len: u64, advice: libc::c_int) -> io::Result<()> {
+        // A length of zero means to the end of the file.
+        let ret = unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), pos as libc::off_t, len as libc::off_t, advice)
+        };
+        match ret {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+
+    impl AdviseIo for File {
+        fn will_need(&self, pos: u64, len: u64) -> io::Result<()> {
+            fadvise(self, pos, len, libc::POSIX_FADV_WILLNEED)
+        }
+
+        fn access_pattern(&self, advice: Advice) -> io::Result<()> {
+            let advice = match advice {
+                Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+                Advice::Random => libc::POSIX_FADV_RANDOM,
+                _ => libc::POSIX_FADV_NORMAL,
+            };
+            fadvise(self, 0, 0, advice)
+        }
+    }
+}
+
+// Passes hints on to storage, if it can take them.
+pub(crate) type HostHint<I> = fn(&I, u64, u64) -> io::Result<()>;
+
+// The state of readahead for a reader.
+pub(crate) struct ReadAhead<I> {
+    advice: Advice,
+    host: Option<HostHint<I>>,
+    // The last L2 table read ahead, plus one so zero means none.
+    table: AtomicU64,
+    // How far data has been hinted to storage.
+    hinted: AtomicU64,
+}
+
+impl<I> Default for ReadAhead<I> {
+    fn default() -> Self {
+        ReadAhead {
+            advice: Advice::Normal,
+            host: None,
+            table: AtomicU64::new(0),
+            hinted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<I> ReadAhead<I> {
+    // Forget what was read ahead, since the image changed.
+    pub(crate) fn reset(&mut self) {
+        self.table = AtomicU64::new(0);
+        self.hinted = AtomicU64::new(0);
+    }
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    /// Hint how this reader will be used, so it can read ahead.
+    ///
+    /// With `Sequential`, each read also loads the next L2 table into the cache. `Random` and
+    /// `Normal` turn that off again. `WillNeed` loads the L2 tables for a range right away, so
+    /// later reads there only need to read data. How many L2 tables stay cached depends on the
+    /// image's `MetadataCache`.
+    ///
+    /// To also pass hints on to the storage, use `advise_io`.
+    pub fn advise(&mut self, advice: Advice) -> Result<()> {
+        match advice {
+            Advice::WillNeed(range) => self.will_need(range),
+            _ => {
+                self.ahead.advice = advice;
+                Ok(())
+            }
+        }
+    }
+
+    /// Hint how this reader will be used, and pass the hint on to the storage.
+    ///
+    /// This is like `advise`, but the storage also hears about the parts of the file that hold
+    /// data which will be read. When reading sequentially, data is hinted a little ahead of each
+    /// read, so the operating system can fetch it in the background. Hints made while reading
+    /// are ignored if they fail.
+    pub fn advise_io(&mut self, advice: Advice) -> Result<()>
+        where I: AdviseIo
+    {
+        self.ahead.host = Some(<I as AdviseIo>::will_need);
+        if !matches!(advice, Advice::WillNeed(_)) {
+            self.q.data_io()?.access_pattern(advice)?;
+        }
+        self.advise(advice)
+    }
+
+    fn will_need(&self, range: GuestRange) -> Result<()> {
+        let extents = self.host_extents(range)?;
+        if let Some(hint) = self.ahead.host {
+            let io = self.q.data_io()?;
+            for (pos, len) in extents {
+                hint(io, pos, len)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Find the parts of the file holding data for a range of the virtual disk, loading the L2
+    // tables on the way.
+    fn host_extents(&self, range: GuestRange) -> Result<Vec<(u64, u64)>> {
+        let q = self.q;
+        let cluster_size = q.cluster_size();
+        let table_span = q.header().l2_entries() * cluster_size;
+        let end = min(range.end(), self.size).div_ceil(cluster_size) * cluster_size;
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        let mut pos = range.offset - range.offset % cluster_size;
+        while pos < end {
+            let table_end = min((pos / table_span + 1) * table_span, end);
+            let count = ((table_end - pos) / cluster_size) as usize;
+            for entry in q.l2_entries_read(&self.l1, pos, count)? {
+                let (host, len) = match entry {
+                    L2Entry::Standard { pos, zero: false, .. } => (pos, cluster_size),
+                    L2Entry::Compressed { pos, size, .. } => (pos, size),
+                    _ => continue,
+                };
+                match extents.last_mut() {
+                    Some(last) if last.0 + last.1 == host => last.1 += len,
+                    _ => extents.push((host, len)),
+                }
+            }
+            pos = table_end;
+        }
+        Ok(extents)
+    }
+
+    // Read ahead after a read that ended at `end`. This is only a hint, so it can't fail.
+    pub(crate) fn read_ahead(&self, end: u64) {
+        if self.ahead.advice != Advice::Sequential {
+            return;
+        }
+        let q = self.q;
+        let table_span = q.header().l2_entries() * q.cluster_size();
+        let next = (end / table_span + 1) * table_span;
+        let idx = next / table_span + 1;
+        if next < self.size && self.ahead.table.swap(idx, Ordering::Relaxed) != idx {
+            let _ = self.host_extents(GuestRange { offset: next, len: 1 });
+        }
+
+        // Hint more data once half of what was hinted has been read, or after seeking back.
+        let hinted = self.ahead.hinted.load(Ordering::Relaxed);
+        let behind = hinted > end + READAHEAD_DATA;
+        if self.ahead.host.is_some() && end < self.size &&
+           (behind || end + READAHEAD_DATA / 2 > hinted) {
+            let start = if behind { end } else { hinted.max(end) };
+            self.ahead.hinted.store(end + READAHEAD_DATA, Ordering::Relaxed);
+            let _ = self.will_need(GuestRange { offset: start, len: end + READAHEAD_DATA - start });
+        }
+    }
+}
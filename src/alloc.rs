@@ -0,0 +1,344 @@
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Error, Qcow2, Result};
+use super::int::to_usize;
+use super::refcount::{REFT_POS, max_refcount, refcount_entry, set_refcount_entry};
+use super::tx::{MetaTx, Stage};
+
+
+// The position in the header of the refcount table offset, followed by its size in clusters.
+const HEADER_REFCOUNT_TABLE: u64 = 48;
+
+// Merge a range of changed bytes into another.
+fn extend_range(range: &mut Option<Range<usize>>, other: Range<usize>) {
+    *range = Some(match range.take() {
+        Some(r) => min(r.start, other.start)..max(r.end, other.end),
+        None => other,
+    });
+}
+
+// The state of a refcount block, while changes to it are pending.
+struct Block {
+    pos: u64,
+    // Whether the block is new, and not yet pointed to by the refcount table.
+    new: bool,
+    // Refcounts with only pending increases applied. New allocations are found using these, so
+    // a cluster freed in a transaction is never reused by the same transaction.
+    allocated: Vec<u8>,
+    // Refcounts with all pending changes applied.
+    freed: Vec<u8>,
+    // The bytes changed by increases, and by decreases.
+    allocated_dirty: Option<Range<usize>>,
+    freed_dirty: Option<Range<usize>>,
+}
+
+// Tracks refcounts while an image is being modified, and finds free clusters.
+//
+// Changes are kept in memory until they're staged into a transaction. Increases are written in
+// the Allocate stage, and decreases in the Free stage.
+pub struct Allocator {
+    table: Vec<u64>,
+    table_offset: u64,
+    table_clusters: u64,
+    // Whether the refcount table has been replaced since the last commit.
+    grown: bool,
+    blocks: HashMap<u64, Block>,
+    // No cluster before this one is free.
+    hint: u64,
+    // The lowest cluster freed since the last commit.
+    freed_min: Option<u64>,
+}
+
+impl Allocator {
+    pub fn new<I: ReadAt, C: CachePolicy>(q: &Qcow2<I, C>) -> Result<Self> {
+        Ok(Allocator {
+            table: q.refcount_table_read()?,
+            table_offset: q.header().c.refcount_table_offset,
+            table_clusters: q.header().c.refcount_table_clusters as u64,
+            grown: false,
+            blocks: HashMap::new(),
+            hint: 0,
+            freed_min: None,
+        })
+    }
+
+    // Get the state of the refcount block at an index of the refcount table, creating the block
+    // if there is none.
+    fn block<I, C>(&mut self, q: &Qcow2<I, C>, table_idx: u64) -> Result<&mut Block>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        if !self.blocks.contains_key(&table_idx) {
+            let entry = *self.table.get(table_idx as usize).ok_or_else(|| {
+                Error::Internal(format!("refcount block {} is past the refcount table",
+                                        table_idx))
+            })?;
+            let mut buf = vec![0; q.cluster_size() as usize];
+            let pos = q.refcount_block_pos(table_idx, entry)?;
+            let new = pos == 0;
+            let pos = if new {
+                // Nothing in the range of a missing block is in use, so put the block in the
+                // first cluster it covers, and count it in itself.
+                set_refcount_entry(&mut buf, 0, q.header().v3.refcount_order, 1);
+                table_idx * q.refcount_block_size() * q.cluster_size()
+            } else {
+                q.io.read_exact_at(pos, &mut buf)?;
+                pos
+            };
+            self.blocks.insert(table_idx,
+                               Block {
+                                   pos,
+                                   new,
+                                   allocated: buf.clone(),
+                                   freed: buf,
+                                   allocated_dirty: None,
+                                   freed_dirty: None,
+                               });
+        }
+        Ok(self.blocks.get_mut(&table_idx).unwrap())
+    }
+
+    // Get the refcount of a host cluster, including pending increases but not decreases.
+    pub fn get<I: ReadAt, C: CachePolicy>(&mut self, q: &Qcow2<I, C>, cluster: u64) -> Result<u64> {
+        let per_block = q.refcount_block_size();
+        let (table_idx, idx) = (cluster / per_block, cluster % per_block);
+        match self.table.get(table_idx as usize) {
+            None => return Ok(0),
+            Some(&entry) if entry & REFT_POS == 0 && !self.blocks.contains_key(&table_idx) => {
+                return Ok(0)
+            }
+            _ => {}
+        }
+        let order = q.header().v3.refcount_order;
+        Ok(refcount_entry(&self.block(q, table_idx)?.allocated, idx, order))
+    }
+
+    // Increase the refcount of a host cluster.
+    pub fn increment<I, C>(&mut self, q: &Qcow2<I, C>, cluster: u64) -> Result<()>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        let per_block = q.refcount_block_size();
+        let (table_idx, idx) = (cluster / per_block, cluster % per_block);
+        let order = q.header().v3.refcount_order;
+        let block = self.block(q, table_idx)?;
+        let value = refcount_entry(&block.freed, idx, order);
+        if value == max_refcount(order) {
+            return Err(Error::UnsupportedFeature(format!("refcount of cluster {} would \
+                                                          overflow",
+                                                         cluster)));
+        }
+        set_refcount_entry(&mut block.freed, idx, order, value + 1);
+        let value = refcount_entry(&block.allocated, idx, order);
+        let range = set_refcount_entry(&mut block.allocated, idx, order, value + 1);
+        extend_range(&mut block.allocated_dirty, range);
+        Ok(())
+    }
+
+    // Decrease the refcount of a host cluster.
+    pub fn decrement<I, C>(&mut self, q: &Qcow2<I, C>, cluster: u64) -> Result<()>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        let per_block = q.refcount_block_size();
+        let (table_idx, idx) = (cluster / per_block, cluster % per_block);
+        let order = q.header().v3.refcount_order;
+        let block = self.block(q, table_idx)?;
+        let value = refcount_entry(&block.freed, idx, order);
+        if value == 0 {
+            return Err(Error::FileFormat(format!("refcount of cluster {} is already zero",
+                                                 cluster)));
+        }
+        let range = set_refcount_entry(&mut block.freed, idx, order, value - 1);
+        extend_range(&mut block.freed_dirty, range);
+        if value == 1 {
+            self.freed_min = Some(self.freed_min.map_or(cluster, |c| min(c, cluster)));
+        }
+        Ok(())
+    }
+
+    // Replace the refcount table with one at least twice as big, which covers more refcount
+    // blocks.
+    //
+    // Nothing past what the current table covers can be in use, so everything new goes just
+    // past that: first the new refcount blocks, then the table. The blocks must cover both the
+    // table and themselves.
+    fn grow<I: ReadAt, C: CachePolicy>(&mut self, q: &Qcow2<I, C>) -> Result<()> {
+        let cluster_size = q.cluster_size();
+        let per_block = q.refcount_block_size();
+        let per_cluster = cluster_size / size_of::<u64>() as u64;
+        let old_len = self.table.len() as u64;
+        let start = old_len * per_block;
+
+        let mut clusters = max(1, self.table_clusters * 2);
+        let blocks = loop {
+            let blocks = clusters.div_ceil(per_block - 1);
+            if clusters * per_cluster >= old_len + blocks {
+                break blocks;
+            }
+            clusters *= 2;
+        };
+        if clusters > u32::MAX as u64 {
+            return Err(Error::UnsupportedFeature("refcount table too big".to_owned()));
+        }
+
+        let order = q.header().v3.refcount_order;
+        let end = start + blocks + clusters;
+        for i in 0..blocks {
+            let first = start + i * per_block;
+            let mut buf = vec![0; cluster_size as usize];
+            for cluster in first..min(end, first + per_block) {
+                set_refcount_entry(&mut buf, cluster - first, order, 1);
+            }
+            self.blocks.insert(old_len + i,
+                               Block {
+                                   pos: (start + i) * cluster_size,
+                                   new: true,
+                                   allocated: buf.clone(),
+                                   freed: buf,
+                                   allocated_dirty: None,
+                                   freed_dirty: None,
+                               });
+        }
+
+        // The old table is no longer needed once the header points at the new one.
+        let old_first = self.table_offset / cluster_size;
+        for cluster in old_first..old_first + self.table_clusters {
+            self.decrement(q, cluster)?;
+        }
+        self.table.resize(to_usize(clusters * per_cluster, "refcount table entries")?, 0);
+        self.table_offset = (start + blocks) * cluster_size;
+        self.table_clusters = clusters;
+        self.grown = true;
+        Ok(())
+    }
+
+    // Find a free cluster and take a reference to it. Returns the offset of the cluster.
+    pub fn allocate<I: ReadAt, C: CachePolicy>(&mut self, q: &Qcow2<I, C>) -> Result<u64> {
+        self.allocate_clusters(q, 1)
+    }
+
+    // Find a run of contiguous free clusters and take a reference to each. Returns the offset of
+    // the first one.
+    pub fn allocate_clusters<I, C>(&mut self, q: &Qcow2<I, C>, count: u64) -> Result<u64>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        let per_block = q.refcount_block_size();
+        let mut start = self.hint;
+        // The first free cluster we had to skip over, if any.
+        let mut skipped = None;
+        let mut cluster = start;
+        while cluster < start + count {
+            if cluster >= self.table.len() as u64 * per_block {
+                self.grow(q)?;
+                continue;
+            }
+            if self.get(q, cluster)? == 0 {
+                // Make sure there's a refcount block, which may itself take this cluster.
+                self.block(q, cluster / per_block)?;
+            }
+            if self.get(q, cluster)? != 0 {
+                if cluster > start {
+                    skipped.get_or_insert(start);
+                }
+                start = cluster + 1;
+            }
+            cluster += 1;
+        }
+        for cluster in start..start + count {
+            self.increment(q, cluster)?;
+        }
+        self.hint = skipped.unwrap_or(start + count);
+        Ok(start * q.cluster_size())
+    }
+
+    // Count the clusters up to the last one in use.
+    pub fn used_clusters<I: ReadAt, C: CachePolicy>(&mut self, q: &Qcow2<I, C>) -> Result<u64> {
+        let per_block = q.refcount_block_size();
+        let order = q.header().v3.refcount_order;
+        for table_idx in (0..self.table.len() as u64).rev() {
+            if self.table[table_idx as usize] & REFT_POS == 0 &&
+               !self.blocks.contains_key(&table_idx) {
+                continue;
+            }
+            let block = &self.block(q, table_idx)?.freed;
+            let last = (0..per_block).rev().find(|&i| refcount_entry(block, i, order) != 0);
+            if let Some(idx) = last {
+                return Ok(table_idx * per_block + idx + 1);
+            }
+        }
+        Ok(0)
+    }
+
+    // Add the pending refcount changes to a transaction.
+    pub fn stage<I: ReadAt, C: CachePolicy>(&self, q: &Qcow2<I, C>, tx: &mut MetaTx) {
+        let mut table = if self.grown { Some(self.table.clone()) } else { None };
+        for (&table_idx, block) in &self.blocks {
+            if block.new {
+                // Nothing points at a new block yet, so it can be written in full right away.
+                tx.write(Stage::RefcountBlock, block.pos, block.allocated.clone());
+                match table {
+                    Some(ref mut table) => table[table_idx as usize] = block.pos,
+                    None => {
+                        let entry_pos = self.table_offset + table_idx * size_of::<u64>() as u64;
+                        tx.write_u64(Stage::Allocate, entry_pos, block.pos);
+                    }
+                }
+            } else if let Some(ref r) = block.allocated_dirty {
+                tx.write(Stage::Allocate,
+                         block.pos + r.start as u64,
+                         block.allocated[r.clone()].to_vec());
+            }
+            if let Some(ref r) = block.freed_dirty {
+                if block.freed[r.clone()] != block.allocated[r.clone()] {
+                    tx.write(Stage::Free,
+                             block.pos + r.start as u64,
+                             block.freed[r.clone()].to_vec());
+                }
+            }
+        }
+
+        // A new table is also written in full, and must be in use before any of the clusters
+        // that only it covers.
+        if let Some(table) = table {
+            // The table is already in memory, so its size fits.
+            let mut buf = vec![0; (self.table_clusters * q.cluster_size()) as usize];
+            for (chunk, &entry) in buf.chunks_mut(size_of::<u64>()).zip(&table) {
+                BigEndian::write_u64(chunk, entry);
+            }
+            tx.write(Stage::RefcountBlock, self.table_offset, buf);
+            let mut header = self.table_offset.to_be_bytes().to_vec();
+            header.extend_from_slice(&(self.table_clusters as u32).to_be_bytes());
+            tx.write(Stage::Allocate, HEADER_REFCOUNT_TABLE, header);
+        }
+    }
+
+    // Mark the staged changes as written.
+    pub fn committed<I: ReadAt, C: CachePolicy>(&mut self, q: &mut Qcow2<I, C>) {
+        if self.grown {
+            let c = &mut q.header_mut().c;
+            c.refcount_table_offset = self.table_offset;
+            c.refcount_table_clusters = self.table_clusters as u32;
+            self.grown = false;
+        }
+        for (&table_idx, block) in &mut self.blocks {
+            if block.new {
+                self.table[table_idx as usize] = block.pos;
+                block.new = false;
+            }
+            block.allocated.clone_from(&block.freed);
+            block.allocated_dirty = None;
+            block.freed_dirty = None;
+        }
+        if let Some(c) = self.freed_min.take() {
+            self.hint = min(self.hint, c);
+        }
+    }
+}
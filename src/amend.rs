@@ -0,0 +1,94 @@
+use positioned_io::Size;
+
+use super::{CachePolicy, Error, FeatureNameTable, Qcow2, Result};
+use super::header::{COMPATIBLE_LAZY_REFCOUNTS, Header, INCOMPATIBLE_DIRTY};
+use super::tx::{MetaTx, Stage};
+use super::write::Storage;
+
+
+// The position in the header of the compatible feature bits.
+const HEADER_COMPATIBLE: u64 = 80;
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    /// Replace the feature name table of this image.
+    ///
+    /// The header extensions are rewritten, moving the backing file name if needed. An empty
+    /// table removes the extension. Use `FeatureNameTableBuilder` to build a table.
+    pub fn set_feature_name_table(&mut self, table: FeatureNameTable) -> Result<()> {
+        self.header_mut().v3.feature_name_table = table;
+        self.header_rewrite()
+    }
+
+    // Write the in-memory header to the first cluster, and sync.
+    //
+    // The header is then read back, whether or not writing succeeded, so it always matches the
+    // file.
+    pub(crate) fn header_rewrite(&mut self) -> Result<()> {
+        let written = self.header().write().and_then(|buf| {
+            let mut tx = MetaTx::default();
+            tx.write(Stage::Header, 0, buf);
+            self.commit(tx)
+        });
+        let mut header = Header::default();
+        header.read(&mut self.io, self.header_options)?;
+        *self.header_mut() = header;
+        written?;
+        self.sync()
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage + Size,
+          C: CachePolicy
+{
+    /// Turn a compatible feature bit on or off.
+    ///
+    /// Only compatible features can be changed this way, since other implementations may safely
+    /// ignore them. Incompatible and autoclear bits change the meaning of the image, so they
+    /// can't be set here.
+    ///
+    /// Turning off lazy refcounts promises that refcounts are exact, so the image is checked
+    /// first, and must have no refcount errors. Leaked clusters are allowed.
+    pub fn set_compatible_feature(&mut self, bit: u8, enabled: bool) -> Result<()> {
+        if bit > 63 {
+            return Err(Error::UnsupportedFeature(format!("feature bit {}", bit)));
+        }
+        let mask = 1 << bit;
+        if !enabled && mask == COMPATIBLE_LAZY_REFCOUNTS &&
+           self.header().v3.compatible.enabled(mask) {
+            if self.header().v3.incompatible.enabled(INCOMPATIBLE_DIRTY) {
+                return Err(Error::UnsupportedFeature("disabling lazy refcounts on a dirty \
+                                                      image"
+                    .to_owned()));
+            }
+            let result = self.check()?;
+            if result.corruptions > 0 {
+                return Err(Error::FileFormat(format!("can't disable lazy refcounts, found {} \
+                                                      corruptions",
+                                                     result.corruptions)));
+            }
+        }
+
+        let bits = if enabled {
+            self.header().v3.compatible.bits() | mask
+        } else {
+            self.header().v3.compatible.bits() & !mask
+        };
+        let mut tx = MetaTx::default();
+        tx.write_u64(Stage::Header, HEADER_COMPATIBLE, bits);
+        self.commit(tx)?;
+        self.sync()?;
+        self.header_mut().v3.compatible.set(bits);
+        Ok(())
+    }
+
+    /// Turn lazy refcounts on or off.
+    ///
+    /// See `set_compatible_feature` for the checks made when turning them off.
+    pub fn set_lazy_refcounts(&mut self, enabled: bool) -> Result<()> {
+        self.set_compatible_feature(COMPATIBLE_LAZY_REFCOUNTS.trailing_zeros() as u8, enabled)
+    }
+}
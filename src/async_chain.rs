@@ -0,0 +1,189 @@
+use std::future::Future;
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+
+use positioned_io::{ReadAt, Size};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinHandle};
+
+use super::{Backing, Error, OpenOptions, Qcow2, Result};
+use super::options::open_tables;
+use super::probe::probe;
+
+
+// How many images `open_chain` opens at once, by default.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Choices for `open_chain`.
+#[derive(Clone)]
+pub struct ChainOptions {
+    open: OpenOptions,
+    concurrency: usize,
+}
+
+impl Default for ChainOptions {
+    fn default() -> Self {
+        ChainOptions {
+            open: OpenOptions::new(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+impl ChainOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open each qcow2 image in the chain with these options.
+    pub fn open_options(&mut self, open: OpenOptions) -> &mut Self {
+        self.open = open;
+        self
+    }
+
+    /// Read from at most this many images at once, including resolving their names.
+    ///
+    /// This keeps a deep chain from opening a connection for every image at the same time. The
+    /// default is 4, and the least is 1, which opens one image after another.
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+// The result of finishing the open of a qcow2 image: the image, and its active L1 table if it's
+// a backing file.
+type Opened<I> = JoinHandle<Result<(Qcow2<I>, Option<Vec<u8>>)>>;
+
+/// Open a qcow2 image and its chain of backing files, overlapping the work of each.
+///
+/// As soon as an image's header is read, `resolver` is asked for the storage of its backing
+/// file, given the name recorded in the image, and that file starts to open. Meanwhile, the
+/// rest of the image above, such as its snapshot table, is read. The returned image has each
+/// backing file attached, see `Qcow2::set_backing`.
+///
+/// The storage is read on tokio's blocking thread pool, so it can be any `ReadAt`, and this
+/// must be called from within a tokio runtime. A backing file is opened as a raw image if its
+/// format is recorded as `raw`, or if it doesn't look like a qcow2 image. A raw image ends the
+/// chain. Images with an external data file can't be opened this way.
+///
+/// The resolver is responsible for finding names relative to the right place, and for
+/// refusing a chain that loops.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use qcow2::ChainOptions;
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// # tokio::runtime::Builder::new_multi_thread().build()?.block_on(async {
+/// let dir = Path::new("tests");
+/// let resolve = |name: &Path| {
+///     let path = dir.join(name);
+///     async move { Ok(std::fs::File::open(path)?) }
+/// };
+/// let top = std::fs::File::open(dir.join("test.qcow2"))?;
+/// let qcow = qcow2::open_chain(top, resolve, &ChainOptions::new()).await?;
+/// assert!(qcow.backing().is_none());
+/// # Ok(()) })
+/// # } fn main() { foo().unwrap(); }
+/// ```
+pub async fn open_chain<I, R, F>(io: I, mut resolver: R, opts: &ChainOptions) -> Result<Qcow2<I>>
+    where I: ReadAt + Size + Send + 'static,
+          R: FnMut(&Path) -> F,
+          F: Future<Output = Result<I>>
+{
+    let permits = Arc::new(Semaphore::new(opts.concurrency));
+    // Each qcow2 image from the top down, still being opened.
+    let mut opening: Vec<Opened<I>> = Vec::new();
+    let mut raw = None;
+    let mut io = io;
+    let mut permit = acquire(&permits).await;
+    loop {
+        let open = opts.open.clone();
+        let q = blocking(move || open.start_open(io)).await?;
+        let backing = q.backing_file_name()
+            .map(|name| (name.to_owned(), q.backing_format().map(str::to_owned)));
+        let is_backing = !opening.is_empty();
+        opening.push(task::spawn_blocking(move || finish_open(q, is_backing, permit)));
+
+        let (name, format) = match backing {
+            Some(backing) => backing,
+            None => break,
+        };
+        permit = acquire(&permits).await;
+        let next = resolver(&name).await?;
+        let (is_qcow2, next) = match format.as_deref() {
+            Some("qcow2") => (true, next),
+            Some("raw") => (false, next),
+            Some(other) => return Err(Error::UnsupportedFeature(format!("{} format", other))),
+            None => blocking(move || Ok((probe(&next)?.is_qcow2, next))).await?,
+        };
+        if !is_qcow2 {
+            raw = Some(blocking(move || Backing::raw(next)).await?);
+            break;
+        }
+        io = next;
+    }
+
+    let mut images = Vec::with_capacity(opening.len());
+    for task in opening {
+        images.push(join(task).await?);
+    }
+    // Attach each backing file from the bottom up.
+    let (mut top, _) = images.remove(0);
+    let mut backing = raw;
+    for (mut q, l1) in images.into_iter().rev() {
+        if let Some(b) = backing.take() {
+            q.set_backing(b);
+        }
+        let l1 = l1.expect("backing files have an L1 table");
+        backing = Some(Backing::qcow2_with_l1(q, l1)?);
+    }
+    if let Some(b) = backing {
+        top.set_backing(b);
+    }
+    Ok(top)
+}
+
+// Read the rest of an image whose header has been read. A backing file also needs its active
+// L1 table. The permit is held until then.
+fn finish_open<I>(mut q: Qcow2<I>,
+                  is_backing: bool,
+                  permit: OwnedSemaphorePermit)
+                  -> Result<(Qcow2<I>, Option<Vec<u8>>)>
+    where I: ReadAt
+{
+    open_tables(&mut q)?;
+    let l1 = if is_backing {
+        Some(q.l1_read(q.header().c.l1_table_offset, q.header().l1_entries())?)
+    } else {
+        None
+    };
+    drop(permit);
+    Ok((q, l1))
+}
+
+async fn acquire(permits: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    permits.clone().acquire_owned().await.expect("semaphore is never closed")
+}
+
+// Run some blocking I/O on tokio's blocking thread pool.
+async fn blocking<T, F>(f: F) -> Result<T>
+    where T: Send + 'static,
+          F: FnOnce() -> Result<T> + Send + 'static
+{
+    join(task::spawn_blocking(f)).await
+}
+
+// Wait for a blocking task. If it panicked, so do we.
+async fn join<T>(task: JoinHandle<Result<T>>) -> Result<T> {
+    match task.await {
+        Ok(r) => r,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(Error::Internal(format!("opening was stopped: {}", e))),
+    }
+}
@@ -0,0 +1,191 @@
+use std::cmp::min;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use positioned_io::ReadAt;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::task::JoinHandle;
+
+use super::OwnedReader;
+use super::read::L2Entry;
+
+
+// Part of the virtual disk, loaded into memory.
+enum Chunk {
+    // A run of this many bytes that read as zeros.
+    Zero(u64),
+    // The contents of one cluster.
+    Data(Vec<u8>),
+}
+
+impl Chunk {
+    fn len(&self) -> u64 {
+        match *self {
+            Chunk::Zero(len) => len,
+            Chunk::Data(ref buf) => buf.len() as u64,
+        }
+    }
+}
+
+/// A stream of the contents of a virtual disk, for tokio.
+///
+/// This reads the disk from start to end, or from wherever it's seeked to, one cluster at a
+/// time. Each cluster is read on tokio's blocking thread pool, so the image's storage can be
+/// any `ReadAt`. Runs of clusters that read as zeros are produced without reading their data.
+/// It must be used from within a tokio runtime.
+///
+/// A read that is dropped before it completes loses nothing: the cluster it was waiting for is
+/// kept, for the next read to use.
+///
+/// # Examples
+///
+/// ```
+/// use qcow2::{AsyncSeqReader, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// # tokio::runtime::Builder::new_multi_thread().build()?.block_on(async {
+/// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let size = qcow.guest_size();
+/// let mut reader = AsyncSeqReader::new(qcow.into_reader()?);
+/// let copied = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+/// assert_eq!(copied, size);
+/// # Ok(()) })
+/// # } fn main() { foo().unwrap(); }
+/// ```
+pub struct AsyncSeqReader<I: ReadAt + Send + Sync + 'static> {
+    reader: Arc<OwnedReader<I>>,
+    pos: u64,
+    // The chunk most recently loaded, and where it starts.
+    chunk: Option<(u64, Chunk)>,
+    // A chunk being loaded, and where it starts.
+    pending: Option<(u64, JoinHandle<io::Result<Chunk>>)>,
+}
+
+impl<I: ReadAt + Send + Sync + 'static> AsyncSeqReader<I> {
+    /// Stream the virtual disk an `OwnedReader` reads, from the start.
+    pub fn new(reader: OwnedReader<I>) -> Self {
+        AsyncSeqReader {
+            reader: Arc::new(reader),
+            pos: 0,
+            chunk: None,
+            pending: None,
+        }
+    }
+
+    /// Get the reader being streamed.
+    pub fn get_ref(&self) -> &OwnedReader<I> {
+        &self.reader
+    }
+
+    /// Get the position in the virtual disk where the next read starts.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // Start loading the chunk that starts at a cluster boundary, unless it's already loading.
+    fn start_load(&mut self, start: u64) {
+        if let Some((pending, _)) = self.pending {
+            if pending == start {
+                return;
+            }
+        }
+        // Reuse the buffer of the last chunk, which is no use anymore.
+        let buf = match self.chunk.take() {
+            Some((_, Chunk::Data(buf))) => buf,
+            _ => Vec::new(),
+        };
+        let reader = self.reader.clone();
+        // Anything still loading is for a position we've seeked away from, so it's left to
+        // finish on its own.
+        let handle = tokio::task::spawn_blocking(move || load(&reader, start, buf));
+        self.pending = Some((start, handle));
+    }
+}
+
+// Load the chunk starting at a cluster boundary: a run of clusters that read as zeros, up to
+// the end of their L2 table, or else one cluster of data.
+fn load<I: ReadAt>(reader: &OwnedReader<I>, start: u64, mut buf: Vec<u8>) -> io::Result<Chunk> {
+    let q = &reader.q;
+    let cluster_size = q.cluster_size();
+    let reads_zero = |entry: &L2Entry| match *entry {
+        L2Entry::Empty => q.backing().is_none(),
+        L2Entry::Zero { .. } |
+        L2Entry::Standard { zero: true, .. } => true,
+        _ => false,
+    };
+    let len = min(cluster_size, reader.size - start);
+    if reads_zero(&q.l2_entry_read(&reader.l1, start)?) {
+        let table_span = q.header().l2_entries() * cluster_size;
+        let end = min((start / table_span + 1) * table_span, reader.size);
+        let rest = (end - start).div_ceil(cluster_size) as usize - 1;
+        let entries = q.l2_entries_read(&reader.l1, start + cluster_size, rest)?;
+        let run = entries.iter().take_while(|e| reads_zero(e)).count() as u64;
+        return Ok(Chunk::Zero(min(len + run * cluster_size, reader.size - start)));
+    }
+    buf.resize(len as usize, 0);
+    reader.read_exact_at(start, &mut buf)?;
+    Ok(Chunk::Data(buf))
+}
+
+impl<I: ReadAt + Send + Sync + 'static> AsyncRead for AsyncSeqReader<I> {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context<'_>,
+                 buf: &mut ReadBuf<'_>)
+                 -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let size = this.reader.size;
+        if this.pos >= size || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if let Some((start, ref chunk)) = this.chunk {
+                if start <= this.pos && this.pos < start + chunk.len() {
+                    let offset = (this.pos - start) as usize;
+                    let len = min(buf.remaining() as u64, start + chunk.len() - this.pos) as usize;
+                    match *chunk {
+                        Chunk::Zero(_) => buf.initialize_unfilled_to(len).fill(0),
+                        Chunk::Data(ref data) => {
+                            buf.initialize_unfilled_to(len).copy_from_slice(&data[offset..][..len])
+                        }
+                    }
+                    buf.advance(len);
+                    this.pos += len as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let cluster_size = this.reader.q.cluster_size();
+            this.start_load(this.pos - this.pos % cluster_size);
+            let (start, handle) = this.pending.as_mut().unwrap();
+            let start = *start;
+            // Only once the load finishes does the chunk change, so a read dropped while
+            // waiting leaves everything as it was.
+            let loaded = ready!(Pin::new(handle).poll(cx));
+            this.pending = None;
+            let chunk = loaded.map_err(io::Error::other)??;
+            this.chunk = Some((start, chunk));
+        }
+    }
+}
+
+impl<I: ReadAt + Send + Sync + 'static> AsyncSeek for AsyncSeqReader<I> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.reader.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        this.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
@@ -0,0 +1,168 @@
+use std::cmp::min;
+use std::path::Path;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt, Size};
+
+use super::{CachePolicy, MapEntry, Qcow2, Result};
+
+
+/// The source of guest data for clusters that an image doesn't allocate.
+///
+/// A backing file may be a raw image, or a qcow2 image with its own backing file attached.
+pub struct Backing<I>
+    where I: ReadAt
+{
+    kind: Kind<I>,
+    size: u64,
+}
+
+enum Kind<I>
+    where I: ReadAt
+{
+    Raw(I),
+    Qcow2 {
+        image: Box<Qcow2<I>>,
+        l1: ByteIo<Vec<u8>, BigEndian>,
+    },
+}
+
+impl<I> Clone for Backing<I>
+    where I: ReadAt + Clone
+{
+    fn clone(&self) -> Self {
+        let kind = match self.kind {
+            Kind::Raw(ref io) => Kind::Raw(io.clone()),
+            Kind::Qcow2 { ref image, ref l1 } => {
+                Kind::Qcow2 {
+                    image: image.clone(),
+                    l1: ByteIo::new((**l1).clone()),
+                }
+            }
+        };
+        Backing { kind, size: self.size }
+    }
+}
+
+impl<I> Backing<I>
+    where I: ReadAt
+{
+    /// Use a raw image as a backing file.
+    ///
+    /// If the size of `io` isn't known, reads past its end give zeros.
+    pub fn raw(io: I) -> Result<Self>
+        where I: Size
+    {
+        let size = io.size()?.unwrap_or(u64::MAX);
+        Ok(Backing {
+            kind: Kind::Raw(io),
+            size,
+        })
+    }
+
+    /// Use a qcow2 image as a backing file.
+    ///
+    /// The virtual disk is read as it is now. If the image has a backing file of its own, it
+    /// must already be attached with `Qcow2::set_backing`.
+    pub fn qcow2(image: Qcow2<I>) -> Result<Self> {
+        let l1 = image.l1_read(image.header().c.l1_table_offset, image.header().l1_entries())?;
+        Self::qcow2_with_l1(image, l1)
+    }
+
+    // Use a qcow2 image as a backing file, given its active L1 table.
+    pub(crate) fn qcow2_with_l1(image: Qcow2<I>, l1: Vec<u8>) -> Result<Self> {
+        image.ensure_readable()?;
+        let size = image.guest_size();
+        Ok(Backing {
+            kind: Kind::Qcow2 {
+                image: Box::new(image),
+                l1: ByteIo::new(l1),
+            },
+            size,
+        })
+    }
+
+    /// Get the size of the backing virtual disk.
+    ///
+    /// A backing file may be smaller than the image it backs. The rest of the image reads as
+    /// zeros.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the qcow2 image, if the backing file is one.
+    pub fn image(&self) -> Option<&Qcow2<I>> {
+        match self.kind {
+            Kind::Raw(_) => None,
+            Kind::Qcow2 { ref image, .. } => Some(image),
+        }
+    }
+
+    // Fill a buffer with guest data. Anything past the end reads as zeros.
+    pub(crate) fn read(&self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let len = min(buf.len() as u64, self.size.saturating_sub(pos)) as usize;
+        let read = match self.kind {
+            Kind::Raw(ref io) => Qcow2::read_partial_at(io, pos, &mut buf[..len])?,
+            Kind::Qcow2 { ref image, ref l1 } => {
+                image.guest_read(l1, self.size, pos, &mut buf[..len])?
+            }
+        };
+        Qcow2::<I>::zero_fill(&mut buf[read..]);
+        Ok(())
+    }
+
+    // Describe the extent at a guest offset within the backing file, for `Reader::map`.
+    pub(crate) fn map_entry(&self, pos: u64, depth: u64) -> Result<MapEntry> {
+        match self.kind {
+            Kind::Raw(_) => {
+                Ok(MapEntry {
+                    start: pos,
+                    length: self.size - pos,
+                    depth,
+                    present: true,
+                    zero: false,
+                    data: true,
+                    compressed: false,
+                    offset: Some(pos),
+                })
+            }
+            Kind::Qcow2 { ref image, ref l1 } => image.map_entry(l1, self.size, pos, depth),
+        }
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the name of the backing file, if the image has one.
+    ///
+    /// Images with a backing file need it attached with `Qcow2::set_backing` to read guest data.
+    pub fn backing_file_name(&self) -> Option<&Path> {
+        if self.header().has_backing_file() {
+            Some(&self.header().v3.backing_file_name)
+        } else {
+            None
+        }
+    }
+
+    /// Get the format of the backing file, if the image records it.
+    pub fn backing_format(&self) -> Option<&str> {
+        self.backing_file_name()?;
+        self.header().v3.backing_format.0.as_deref()
+    }
+
+    /// Attach the backing file, so guest data can be read.
+    ///
+    /// If the image doesn't have a backing file, `backing` is ignored.
+    pub fn set_backing(&mut self, backing: Backing<I>) {
+        if self.header().has_backing_file() {
+            self.backing = Some(backing);
+        }
+    }
+
+    /// Get the attached backing file.
+    pub fn backing(&self) -> Option<&Backing<I>> {
+        self.backing.as_ref()
+    }
+}
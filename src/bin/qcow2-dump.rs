@@ -1,32 +1,973 @@
+extern crate positioned_io;
 extern crate qcow2;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
+use std::cell::Cell;
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::process;
+
+use positioned_io::{ReadAt, Size};
+use qcow2::{ChainLayer, Difference, FeatureKind, OpenOptions, Qcow2, Severity, ValidationReport};
+
+
+static USAGE: &str = "\
+Usage: qcow2-dump [COMMAND] [OPTIONS] QCOW2 [...]
+
+Commands:
+    info [--json]   Show information about each image. This is the default.
+                    With --json, print an object for one image, or an array for several.
+    check [--json] [--header-only]
+                    Check an image for consistency, like `qemu-img check'. Exits with 2 if
+                    corruptions were found, or 3 if only leaks were found, the same codes as
+                    qemu-img uses. With --header-only, list everything wrong with the header
+                    without opening the image, and exit with 2 if there are errors, since
+                    they're corruptions too.
+    read [--offset N] [--length N] [--out FILE | --hex] [--snapshot NAME]
+                    Read guest data from an image, by default to standard output. Sizes may
+                    have a K, M, G or T suffix.
+    extract [--no-sparse] [--snapshot NAME] [--jobs N] QCOW2 OUTPUT
+                    Write the contents of an image to a raw image file. Zeros are skipped,
+                    leaving holes in the output, unless --no-sparse is given. With --jobs,
+                    read with N threads.
+    compare [--quiet] [--strict] [--format FMT] QCOW2 [--format FMT] OTHER
+                    Compare the contents of two images, like `qemu-img compare'. A format of
+                    `raw' may be given before either image. With --strict, sizes and
+                    allocation must also match, and both images must be qcow2. Exits with 0
+                    if the images are identical, 1 if they differ, or 2 on error.
+    tables [--header] [--l1] [--l2 INDEX] [--refcounts] QCOW2
+                    Show the raw contents of metadata tables: a hexdump of each part of the
+                    header, the L1 table, the L2 table for an L1 index, or the refcount table
+                    and blocks. Problems are flagged rather than stopping the dump. The
+                    header can be shown even if the image can't be opened. The default is
+                    --l1.
+    features QCOW2  List the feature bits set in an image, with their names and whether they
+                    stop the image from being opened. This works even if the image can't be
+                    opened.
+    refcounts [--raw START..END] QCOW2
+                    Summarize the refcounts of an image: how many clusters there are, how
+                    many have each refcount, and how many are leaked. With --raw, list the
+                    refcount of each cluster from START up to, but not including, END.
+    chain [--json] QCOW2
+                    List the backing chain of an image, one line per image, starting with the
+                    image itself. Missing backing files, backing files smaller than the image
+                    above them and cycles are flagged. With --json, print an array of layers.
+                    Exits with 2 if there are any problems.
+    debug           Show the internal structure of each image, for developers.";
+
+// How much guest data to read at a time.
+const READ_CHUNK: u64 = 1 << 20;
+
+// The most findings to list individually when checking.
+const MAX_FINDINGS: usize = 100;
 
 trait OrDie<T> {
-    fn or_die(self, msg: &str, path: &str) -> T;
+    fn or_exit(self, msg: &str, path: &str, code: i32) -> T;
+    fn or_die(self, msg: &str, path: &str) -> T
+        where Self: Sized
+    {
+        self.or_exit(msg, path, 1)
+    }
 }
 impl<T, E: std::fmt::Display> OrDie<T> for Result<T, E> {
-    fn or_die(self, msg: &str, path: &str) -> T {
+    fn or_exit(self, msg: &str, path: &str, code: i32) -> T {
         match self {
             Ok(t) => t,
             Err(e) => {
                 eprintln!("{} `{}': {}", msg, path, e);
-                std::process::exit(1);
+                process::exit(code);
+            }
+        }
+    }
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(1);
+}
+
+fn open(path: &str) -> Qcow2<File> {
+    let f = File::open(path).or_die("Error opening file", path);
+    let q = Qcow2::open(f).or_die("Error reading qcow2", path);
+
+    // Open any external data file, relative to the image.
+    let data_path = match q.data_file_name() {
+        Some(name) => Path::new(path).parent().unwrap_or(Path::new("")).join(name),
+        None => return q,
+    };
+    let data_path = data_path.to_string_lossy();
+    let f = File::open(path).or_die("Error opening file", path);
+    let data = File::open(&*data_path).or_die("Error opening data file", &data_path);
+    OpenOptions::new().open_with_data_file(f, data).or_die("Error reading qcow2", path)
+}
+
+// Command-line arguments for a command.
+struct Args {
+    flags: Vec<String>,
+    values: Vec<(String, String)>,
+    paths: Vec<String>,
+}
+
+impl Args {
+    // Parse arguments, given the flags and the options taking a value that a command accepts.
+    fn parse(args: Vec<String>, flags: &[&str], valued: &[&str]) -> Self {
+        let mut ret = Args {
+            flags: Vec::new(),
+            values: Vec::new(),
+            paths: Vec::new(),
+        };
+        let mut iter = args.into_iter();
+        while let Some(a) = iter.next() {
+            if !a.starts_with("--") {
+                ret.paths.push(a);
+            } else if flags.contains(&a.as_str()) {
+                ret.flags.push(a);
+            } else if valued.contains(&a.as_str()) {
+                match iter.next() {
+                    Some(v) => ret.values.push((a, v)),
+                    None => usage_error(&format!("Option `{}' needs a value", a)),
+                }
+            } else {
+                usage_error(&format!("Unknown option `{}'", a));
+            }
+        }
+        if ret.paths.is_empty() {
+            usage_error("No images given");
+        }
+        ret
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.iter().rev().find(|v| v.0 == name).map(|v| v.1.as_str())
+    }
+
+    // Get an option that is a size, such as "4096" or "1M".
+    fn size(&self, name: &str) -> Option<u64> {
+        self.value(name).map(|v| match parse_size(v) {
+            Some(size) => size,
+            None => usage_error(&format!("Invalid size `{}' for {}", v, name)),
+        })
+    }
+}
+
+// Parse a size with an optional binary suffix.
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn info(args: Vec<String>) {
+    let args = Args::parse(args, &["--json"], &[]);
+    if args.flag("--json") {
+        return info_json(&args.paths);
+    }
+
+    for (i, path) in args.paths.iter().enumerate() {
+        let info = open(path).info().or_die("Error reading qcow2", path);
+        if i > 0 {
+            println!();
+        }
+        println!("image: {}", path);
+        print!("{}", info);
+    }
+}
+
+#[cfg(feature = "serde")]
+fn info_json(paths: &[String]) {
+    let mut infos = Vec::new();
+    for path in paths {
+        let info = open(path).info().or_die("Error reading qcow2", path);
+        let mut json = serde_json::to_value(&info).or_die("Error serializing", path);
+        json["filename"] = serde_json::Value::String(path.clone());
+        infos.push(json);
+    }
+    let json = if infos.len() == 1 {
+        infos.pop().unwrap()
+    } else {
+        serde_json::Value::Array(infos)
+    };
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+#[cfg(not(feature = "serde"))]
+fn info_json(_paths: &[String]) {
+    no_json();
+}
+
+#[cfg(not(feature = "serde"))]
+fn no_json() -> ! {
+    eprintln!("JSON output requires the `serde' feature");
+    process::exit(1);
+}
+
+fn check(args: Vec<String>) {
+    let args = Args::parse(args, &["--json", "--header-only"], &[]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be checked");
+    }
+    let path = &args.paths[0];
+    if args.flag("--header-only") {
+        check_header(path, args.flag("--json"));
+    }
+    let result = open(path).check().or_die("Error checking qcow2", path);
+
+    if args.flag("--json") {
+        check_json(path, &result);
+    } else {
+        for f in result.findings.iter().take(MAX_FINDINGS) {
+            println!("{}", f);
+        }
+        if result.findings.len() > MAX_FINDINGS {
+            println!("... and {} more problems", result.findings.len() - MAX_FINDINGS);
+        }
+        if result.is_clean() {
+            println!("No errors were found on the image.");
+        } else {
+            println!();
+        }
+        if result.corruptions > 0 {
+            println!("{} errors were found on the image.", result.corruptions);
+            println!("Data may be corrupted, or further writes to the image may corrupt it.");
+        }
+        if result.leaks > 0 {
+            if result.corruptions > 0 {
+                println!();
+            }
+            println!("{} leaked clusters were found on the image.", result.leaks);
+            println!("This means waste of disk space, but no harm to data.");
+        }
+        println!("Image end offset: {}", result.image_end_offset);
+    }
+
+    // The same codes as qemu-img, so scripts can use either.
+    if result.corruptions > 0 {
+        process::exit(2);
+    } else if result.leaks > 0 {
+        process::exit(3);
+    }
+}
+
+fn check_header(path: &str, json: bool) -> ! {
+    let f = File::open(path).or_die("Error opening file", path);
+    let report = qcow2::validate(f).or_die("Error reading qcow2", path);
+    if json {
+        validate_json(path, &report);
+    } else {
+        print!("{}", report);
+        if report.is_valid() {
+            println!("No errors were found in the header.");
+        } else {
+            println!("{} errors were found in the header.", report.count(Severity::Error));
+        }
+    }
+    process::exit(if report.is_valid() { 0 } else { 2 });
+}
+
+#[cfg(feature = "serde")]
+fn validate_json(path: &str, report: &ValidationReport) {
+    let mut json = serde_json::to_value(report).or_die("Error serializing", path);
+    json["filename"] = serde_json::Value::String(path.to_owned());
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+#[cfg(not(feature = "serde"))]
+fn validate_json(_path: &str, _report: &ValidationReport) {
+    no_json();
+}
+
+#[cfg(feature = "serde")]
+fn check_json(path: &str, result: &qcow2::CheckResult) {
+    let mut json = serde_json::to_value(result).or_die("Error serializing", path);
+    json["filename"] = serde_json::Value::String(path.to_owned());
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+#[cfg(not(feature = "serde"))]
+fn check_json(_path: &str, _result: &qcow2::CheckResult) {
+    no_json();
+}
+
+// Writes data in the format of `hexdump -C'.
+struct HexDump<W: Write> {
+    out: W,
+    offset: u64,
+    line: Vec<u8>,
+    prev: Option<Vec<u8>>,
+    squeezed: bool,
+}
+
+impl<W: Write> HexDump<W> {
+    fn new(out: W, offset: u64) -> Self {
+        HexDump {
+            out,
+            offset,
+            line: Vec::with_capacity(16),
+            prev: None,
+            squeezed: false,
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        // Repeated lines are shown as a single `*'.
+        if self.prev.as_ref() == Some(&self.line) && self.line.len() == 16 {
+            if !self.squeezed {
+                writeln!(self.out, "*")?;
+                self.squeezed = true;
+            }
+        } else {
+            self.squeezed = false;
+            write!(self.out, "{:08x} ", self.offset)?;
+            for i in 0..16 {
+                if i == 8 {
+                    write!(self.out, " ")?;
+                }
+                match self.line.get(i) {
+                    Some(b) => write!(self.out, " {:02x}", b)?,
+                    None => write!(self.out, "   ")?,
+                }
+            }
+            let text: String = self.line
+                .iter()
+                .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+                .collect();
+            writeln!(self.out, "  |{}|", text)?;
+        }
+        self.offset += self.line.len() as u64;
+        self.prev = Some(std::mem::take(&mut self.line));
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.squeezed = false;
+            self.write_line()?;
+        }
+        writeln!(self.out, "{:08x}", self.offset)?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Write for HexDump<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = min(16 - self.line.len(), buf.len());
+        self.line.extend_from_slice(&buf[..n]);
+        if self.line.len() == 16 {
+            self.write_line()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn read(args: Vec<String>) {
+    let args = Args::parse(args, &["--hex"], &["--offset", "--length", "--out", "--snapshot"]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be read");
+    }
+    if args.flag("--hex") && args.value("--out").is_some() {
+        usage_error("Only one of --hex and --out may be used");
+    }
+    let path = &args.paths[0];
+    let q = open(path);
+    let reader = match args.value("--snapshot") {
+        Some(name) => q.snapshot_reader(name),
+        None => q.reader(),
+    };
+    let reader = reader.or_die("Error reading qcow2", path);
+
+    let size = reader.size().or_die("Error reading qcow2", path).unwrap_or(0);
+    let offset = args.size("--offset").unwrap_or(0);
+    let mut length = args.size("--length").unwrap_or_else(|| size.saturating_sub(offset));
+    if offset.saturating_add(length) > size {
+        length = size.saturating_sub(offset);
+        eprintln!("Warning: the virtual disk ends at {}, only reading {} bytes", size, length);
+    }
+
+    let out: Box<dyn Write> = match args.value("--out") {
+        Some(out) => Box::new(File::create(out).or_die("Error creating file", out)),
+        None => Box::new(io::stdout().lock()),
+    };
+    let out = BufWriter::new(out);
+    if args.flag("--hex") {
+        let mut hex = HexDump::new(out, offset);
+        copy_guest(&reader, offset, length, &mut hex).or_die("Error reading from", path);
+        hex.finish().or_die("Error writing output for", path);
+    } else {
+        let mut out = out;
+        copy_guest(&reader, offset, length, &mut out).or_die("Error reading from", path);
+        out.flush().or_die("Error writing output for", path);
+    }
+}
+
+// Copy a range of guest data to an output.
+fn copy_guest<R: ReadAt, W: Write>(reader: &R,
+                                   offset: u64,
+                                   length: u64,
+                                   out: &mut W)
+                                   -> io::Result<()> {
+    let mut buf = vec![0; min(length, READ_CHUNK) as usize];
+    let mut pos = offset;
+    while pos < offset + length {
+        let n = min(offset + length - pos, READ_CHUNK) as usize;
+        reader.read_exact_at(pos, &mut buf[..n])?;
+        out.write_all(&buf[..n])?;
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+// Shows a progress bar on standard error, if it's a terminal.
+struct ProgressBar {
+    total: u64,
+    shown: Cell<Option<u64>>,
+}
+
+impl ProgressBar {
+    const WIDTH: u64 = 50;
+
+    fn new(total: u64) -> Self {
+        ProgressBar {
+            total,
+            shown: Cell::new(if io::stderr().is_terminal() { None } else { Some(Self::WIDTH) }),
+        }
+    }
+
+    fn update(&self, done: u64) {
+        let filled = done.checked_mul(Self::WIDTH).map_or(0, |d| d / self.total.max(1));
+        if self.shown.get().is_some_and(|s| s >= filled) {
+            return;
+        }
+        self.shown.set(Some(filled));
+        eprint!("\r[{:<width$}] {:3}%",
+                "=".repeat(filled as usize),
+                filled * 100 / Self::WIDTH,
+                width = Self::WIDTH as usize);
+        if filled == Self::WIDTH {
+            eprintln!();
+        }
+    }
+}
+
+fn extract(args: Vec<String>) {
+    let args = Args::parse(args, &["--no-sparse"], &["--snapshot", "--jobs"]);
+    if args.paths.len() != 2 {
+        usage_error("An image and an output file are needed");
+    }
+    let jobs = args.value("--jobs").map(|v| match v.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => usage_error(&format!("Invalid number of jobs `{}'", v)),
+    });
+    let (path, out_path) = (&args.paths[0], &args.paths[1]);
+    let q = open(path);
+    let reader = match args.value("--snapshot") {
+        Some(name) => q.snapshot_reader(name),
+        None => q.reader(),
+    };
+    let reader = reader.or_die("Error reading qcow2", path);
+
+    let mut out = File::create(out_path).or_die("Error creating file", out_path);
+    let bar = ProgressBar::new(reader.size().or_die("Error reading qcow2", path).unwrap_or(0));
+    let sparse = !args.flag("--no-sparse");
+    let progress = |done, _total, _physical| {
+        bar.update(done);
+        ControlFlow::Continue(())
+    };
+    let stats = match jobs {
+        Some(jobs) => reader.export_raw_at(&mut out, sparse, jobs, &progress),
+        None => reader.export_raw_reporting(&mut out, sparse, &progress),
+    };
+    let stats = stats.or_die("Error extracting", path);
+    println!("Wrote {} bytes, skipped {} bytes of zeros", stats.written, stats.skipped);
+}
+
+// Any source of guest data.
+trait Source: ReadAt + Size {}
+impl<T: ReadAt + Size + ?Sized> Source for T {}
+
+// An image opened for comparison.
+enum Image {
+    Qcow2(Box<Qcow2<File>>),
+    Raw(File),
+}
+
+impl Image {
+    fn open(path: &str, format: Option<&str>) -> Self {
+        let f = File::open(path).or_exit("Error opening file", path, 2);
+        match format {
+            None | Some("qcow2") => {
+                Image::Qcow2(Box::new(Qcow2::open(f).or_exit("Error reading qcow2", path, 2)))
+            }
+            Some("raw") => Image::Raw(f),
+            Some(fmt) => usage_error(&format!("Unknown format `{}'", fmt)),
+        }
+    }
+
+    fn source(&self, path: &str) -> Box<dyn Source + '_> {
+        match *self {
+            Image::Qcow2(ref q) => Box::new(q.reader().or_exit("Error reading qcow2", path, 2)),
+            Image::Raw(ref f) => Box::new(f),
+        }
+    }
+}
+
+fn compare(args: Vec<String>) {
+    // Each --format applies to the image following it.
+    let (mut rest, mut formats, mut format) = (Vec::new(), Vec::new(), None);
+    let mut iter = args.into_iter();
+    while let Some(a) = iter.next() {
+        if a == "--format" {
+            let value = iter.next();
+            format = Some(value.unwrap_or_else(|| usage_error("Option `--format' needs a value")));
+        } else {
+            if !a.starts_with("--") {
+                formats.push(format.take());
+            }
+            rest.push(a);
+        }
+    }
+    let args = Args::parse(rest, &["--quiet", "--strict"], &[]);
+    if args.paths.len() != 2 {
+        usage_error("Exactly two images must be compared");
+    }
+    let (a_path, b_path) = (&args.paths[0], &args.paths[1]);
+    let a = Image::open(a_path, formats[0].as_deref());
+    let b = Image::open(b_path, formats[1].as_deref());
+
+    let diff = if args.flag("--strict") {
+        match (&a, &b) {
+            (Image::Qcow2(qa), Image::Qcow2(qb)) => {
+                let ra = qa.reader().or_exit("Error reading qcow2", a_path, 2);
+                let rb = qb.reader().or_exit("Error reading qcow2", b_path, 2);
+                ra.compare_strict(&rb)
+            }
+            _ => usage_error("Strict comparison needs two qcow2 images"),
+        }
+    } else {
+        qcow2::compare(&*a.source(a_path), &*b.source(b_path))
+    };
+    let diff = diff.or_exit("Error comparing", a_path, 2);
+
+    if !args.flag("--quiet") {
+        match diff {
+            None => println!("Images are identical."),
+            Some(Difference::Content(pos)) => println!("Content mismatch at offset {}!", pos),
+            Some(Difference::Allocation(pos)) => {
+                println!("Strict mode: Offset {} block status mismatch!", pos)
+            }
+            Some(Difference::Size) => println!("Strict mode: Image size mismatch!"),
+        }
+    }
+    if diff.is_some() {
+        process::exit(1);
+    }
+}
+
+// Describe the flags of a table entry, followed by any problems with it.
+fn entry_flags(mut flags: Vec<String>, reserved_bits: u64, misaligned: bool) -> String {
+    if reserved_bits != 0 {
+        flags.push(format!("RESERVED BITS {:#x}", reserved_bits));
+    }
+    if misaligned {
+        flags.push("MISALIGNED".to_owned());
+    }
+    flags.join(" ")
+}
+
+fn tables_l1(q: &Qcow2<File>, path: &str) {
+    let entries = q.l1_table_entries().or_die("Error reading L1 table of", path);
+    println!("L1 table, {} entries:", entries.len());
+    println!("{:>8}  {:<18}  {:<18}  flags", "index", "raw", "L2 offset");
+    let mut empty = 0;
+    for (i, e) in entries.iter().enumerate() {
+        if e.raw == 0 {
+            empty += 1;
+            continue;
+        }
+        let flags = if e.copied { vec!["COPIED".to_owned()] } else { vec![] };
+        let line = format!("{:>8}  {:#018x}  {:#018x}  {}",
+                           i,
+                           e.raw,
+                           e.l2_offset,
+                           entry_flags(flags, e.reserved_bits, e.misaligned));
+        println!("{}", line.trim_end());
+    }
+    println!("({} empty entries not shown)", empty);
+}
+
+fn tables_l2(q: &Qcow2<File>, path: &str, index: u64) {
+    let l1 = q.l1_table_entries().or_die("Error reading L1 table of", path);
+    let l2_offset = match l1.get(index as usize) {
+        Some(e) if e.l2_offset != 0 => e.l2_offset,
+        Some(_) => {
+            println!("L1 entry {} has no L2 table", index);
+            return;
+        }
+        None => usage_error(&format!("L1 index {} is out of range", index)),
+    };
+    let entries = q.l2_table_entries(l2_offset).or_die("Error reading L2 table of", path);
+    let guest_base = index * entries.len() as u64 * q.cluster_size();
+    println!("L2 table for L1 index {}, at {:#x}:", index, l2_offset);
+    println!("{:>8}  {:<18}  {:<18}  {:<18}  flags",
+             "index",
+             "guest offset",
+             "raw",
+             "host offset");
+    let mut empty = 0;
+    for (i, e) in entries.iter().enumerate() {
+        if e.raw == 0 {
+            empty += 1;
+            continue;
+        }
+        let mut flags = Vec::new();
+        if e.copied {
+            flags.push("COPIED".to_owned());
+        }
+        if e.zero {
+            flags.push("ZERO".to_owned());
+        }
+        if let Some(size) = e.compressed_size {
+            flags.push(format!("COMPRESSED {} bytes", size));
+        }
+        let line = format!("{:>8}  {:#018x}  {:#018x}  {:#018x}  {}",
+                           i,
+                           guest_base + i as u64 * q.cluster_size(),
+                           e.raw,
+                           e.host_offset,
+                           entry_flags(flags, e.reserved_bits, e.misaligned));
+        println!("{}", line.trim_end());
+    }
+    println!("({} empty entries not shown)", empty);
+}
+
+fn tables_refcounts(q: &Qcow2<File>, path: &str) {
+    let table = q.refcount_table_entries().or_die("Error reading refcount table of", path);
+    println!("Refcount table, {} entries:", table.len());
+    println!("{:>8}  {:<18}  {:<18}  flags", "index", "raw", "block offset");
+    let mut blocks = Vec::new();
+    for (i, e) in table.iter().enumerate() {
+        if e.raw == 0 {
+            continue;
+        }
+        let line = format!("{:>8}  {:#018x}  {:#018x}  {}",
+                           i,
+                           e.raw,
+                           e.block_offset,
+                           entry_flags(vec![], e.reserved_bits, e.misaligned));
+        println!("{}", line.trim_end());
+        if e.block_offset != 0 && !e.misaligned {
+            blocks.push((i as u64, e.block_offset));
+        }
+    }
+
+    for (i, offset) in blocks {
+        println!();
+        let refcounts = match q.refcount_block_entries(offset) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Refcount block {} at {:#x}: {}", i, offset, e);
+                continue;
+            }
+        };
+        println!("Refcount block {} at {:#x}, nonzero entries:", i, offset);
+        println!("{:>12}  refcount", "cluster");
+        let base = i * refcounts.len() as u64;
+        for (j, &r) in refcounts.iter().enumerate() {
+            if r != 0 {
+                println!("{:>12}  {}", base + j as u64, r);
+            }
+        }
+    }
+}
+
+fn tables_header(path: &str) {
+    // Don't open the image, so broken headers can be shown too.
+    let f = File::open(path).or_die("Error opening file", path);
+    let layout = qcow2::header_layout(f).or_die("Error reading header of", path);
+    println!("Header, {} parts:", layout.len());
+    let out = io::stdout();
+    for span in layout {
+        println!();
+        println!("{}: {} bytes at {:#x}", span.name, span.len, span.offset);
+        let mut hex = HexDump::new(out.lock(), span.offset);
+        hex.write_all(&span.bytes).or_die("Error writing output for", path);
+        hex.finish().or_die("Error writing output for", path);
+    }
+}
+
+fn tables(args: Vec<String>) {
+    let args = Args::parse(args, &["--header", "--l1", "--refcounts"], &["--l2"]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be given");
+    }
+    let path = &args.paths[0];
+    let l2 = args.value("--l2").map(|v| {
+        v.parse().unwrap_or_else(|_| usage_error(&format!("Invalid L1 index `{}'", v)))
+    });
+
+    let mut sections = Vec::new();
+    if args.flag("--header") {
+        sections.push(0);
+    }
+    if args.flag("--l1") || (sections.is_empty() && l2.is_none() && !args.flag("--refcounts")) {
+        sections.push(1);
+    }
+    if l2.is_some() {
+        sections.push(2);
+    }
+    if args.flag("--refcounts") {
+        sections.push(3);
+    }
+    // Only the header can be shown without opening the image.
+    let q = if sections.iter().any(|&s| s != 0) { Some(open(path)) } else { None };
+    for (i, section) in sections.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match (section, &q) {
+            (0, _) => tables_header(path),
+            (1, Some(q)) => tables_l1(q, path),
+            (2, Some(q)) => tables_l2(q, path, l2.unwrap()),
+            (_, Some(q)) => tables_refcounts(q, path),
+            (_, None) => unreachable!(),
+        }
+    }
+}
+
+fn features(args: Vec<String>) {
+    let args = Args::parse(args, &[], &[]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be given");
+    }
+    let path = &args.paths[0];
+    // Don't open the image, so features that stop it opening can be shown.
+    let f = File::open(path).or_die("Error opening file", path);
+    let bits = qcow2::feature_bits(f).or_die("Error reading qcow2", path);
+    for (i, kind) in [FeatureKind::Incompatible, FeatureKind::Compatible, FeatureKind::Autoclear]
+        .into_iter()
+        .enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("{:?} features:", kind);
+        let mut none = true;
+        for b in bits.iter().filter(|b| b.kind == kind) {
+            none = false;
+            let line = format!("{:>4}  {:<7}  {:<32}  {}",
+                               b.bit,
+                               if b.known { "known" } else { "unknown" },
+                               b.name.as_deref().unwrap_or("(no name)"),
+                               if b.blocks_open { "BLOCKS OPENING" } else { "" });
+            println!("{}", line.trim_end());
+        }
+        if none {
+            println!("  (none)");
+        }
+    }
+}
+
+// Get the refcounts of clusters, loading refcount blocks as needed.
+struct RefcountReader<'a> {
+    q: &'a Qcow2<File>,
+    path: &'a str,
+    table: Vec<qcow2::RefcountTableEntry>,
+    // The index and entries of the last block loaded, if it could be.
+    block: Option<(usize, Option<Vec<u64>>)>,
+}
+
+impl<'a> RefcountReader<'a> {
+    fn new(q: &'a Qcow2<File>, path: &'a str) -> Self {
+        let table = q.refcount_table_entries().or_die("Error reading refcount table of", path);
+        RefcountReader {
+            q,
+            path,
+            table,
+            block: None,
+        }
+    }
+
+    // The number of refcounts in each block.
+    fn per_block(&self) -> u64 {
+        (self.q.cluster_size() * 8) >> self.q.header_info().refcount_order
+    }
+
+    // Get the refcount of a cluster, or None if no block covers it.
+    fn get(&mut self, cluster: u64) -> Option<u64> {
+        let idx = (cluster / self.per_block()) as usize;
+        if self.block.as_ref().is_none_or(|b| b.0 != idx) {
+            let entries = match self.table.get(idx) {
+                Some(e) if e.block_offset != 0 && !e.misaligned => {
+                    let entries = self.q.refcount_block_entries(e.block_offset);
+                    Some(entries.or_die("Error reading refcount block of", self.path))
+                }
+                _ => None,
+            };
+            self.block = Some((idx, entries));
+        }
+        let entries = self.block.as_ref().unwrap().1.as_ref()?;
+        Some(entries[(cluster % self.per_block()) as usize])
+    }
+}
+
+// Parse a range of clusters, such as "16..32".
+fn parse_range(s: &str) -> Option<(u64, u64)> {
+    let (start, end) = s.split_once("..")?;
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn refcounts(args: Vec<String>) {
+    let args = Args::parse(args, &[], &["--raw"]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be given");
+    }
+    let path = &args.paths[0];
+    let raw = args.value("--raw").map(|v| {
+        parse_range(v).unwrap_or_else(|| usage_error(&format!("Invalid cluster range `{}'", v)))
+    });
+    let q = open(path);
+    let mut refcounts = RefcountReader::new(&q, path);
+    let cs = q.cluster_size();
+
+    if let Some((start, end)) = raw {
+        println!("{:>12}  {:<18}  refcount", "cluster", "offset");
+        for cluster in start..end {
+            let refcount = refcounts.get(cluster).map_or("- (no refcount block)".to_owned(),
+                                                         |r| r.to_string());
+            println!("{:>12}  {:#018x}  {}", cluster, cluster * cs, refcount);
+        }
+        return;
+    }
+
+    // Only clusters with refcount blocks can be allocated.
+    let file_len = std::fs::metadata(path).or_die("Error reading", path).len();
+    let per_block = refcounts.per_block();
+    let (mut allocated, mut past_end) = (0, 0);
+    let mut histogram = [0u64; 3];
+    for idx in 0..refcounts.table.len() as u64 {
+        for cluster in idx * per_block..(idx + 1) * per_block {
+            let refcount = match refcounts.get(cluster) {
+                Some(r) => r,
+                None => break,
+            };
+            if refcount == 0 {
+                continue;
+            }
+            allocated += 1;
+            if cluster * cs >= file_len {
+                past_end += 1;
+            }
+            histogram[min(refcount, 3) as usize - 1] += 1;
+        }
+    }
+    println!("Refcounts of {}:", path);
+    println!("{:>12}  clusters in the file", file_len.div_ceil(cs));
+    println!("{:>12}  allocated clusters", allocated);
+    println!("{:>12}  with refcount 1", histogram[0]);
+    println!("{:>12}  with refcount 2", histogram[1]);
+    println!("{:>12}  with refcount >2", histogram[2]);
+    if past_end > 0 {
+        println!("{:>12}  allocated past the end of the file", past_end);
+    }
+    match q.check() {
+        Ok(result) => println!("{:>12}  leaked clusters", result.leaks),
+        Err(e) => println!("{:>12}  leaked clusters, checking failed: {}", "?", e),
+    }
+}
+
+fn chain(args: Vec<String>) {
+    let args = Args::parse(args, &["--json"], &[]);
+    if args.paths.len() != 1 {
+        usage_error("Exactly one image must be given");
+    }
+    let chain = qcow2::backing_chain(&args.paths[0]);
+    if args.flag("--json") {
+        chain_json(&args.paths[0], &chain);
+    } else {
+        println!("{:>5}  {:<6}  {:>14}  {:>14}  path",
+                 "layer",
+                 "format",
+                 "virtual size",
+                 "allocated");
+        let size = |s: Option<u64>| s.map_or("-".to_owned(), |s| s.to_string());
+        for (i, layer) in chain.iter().enumerate() {
+            println!("{:>5}  {:<6}  {:>14}  {:>14}  {}",
+                     i,
+                     layer.format.as_deref().unwrap_or("-"),
+                     size(layer.virtual_size),
+                     size(layer.allocated_size),
+                     layer.path.display());
+            if let Some(ref e) = layer.error {
+                println!("{:>7}error: {}", "", e);
+            }
+            for p in &layer.problems {
+                println!("{:>7}problem: {}", "", p);
             }
         }
     }
+    let ok = chain.iter().all(|l| l.error.is_none() && l.problems.is_empty());
+    process::exit(if ok { 0 } else { 2 });
+}
+
+#[cfg(feature = "serde")]
+fn chain_json(path: &str, chain: &[ChainLayer]) {
+    let json = serde_json::to_value(chain).or_die("Error serializing", path);
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+#[cfg(not(feature = "serde"))]
+fn chain_json(_path: &str, _chain: &[ChainLayer]) {
+    no_json();
+}
+
+fn debug(args: Vec<String>) {
+    let args = Args::parse(args, &[], &[]);
+    for path in args.paths.iter() {
+        println!("{:#?}", open(path));
+    }
 }
 
 fn main() {
-    let mut args: Vec<String> = std::env::args().collect();
-    args.remove(0);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
     if args.is_empty() {
-        println!("Usage: qcow2-dump QCOW2 [...]");
+        println!("{}", USAGE);
         return;
     }
 
-    for a in args.iter() {
-        let f = std::fs::File::open(a).or_die("Error opening file", a);
-        let q = qcow2::Qcow2::open(f).or_die("Error reading qcow2", a);
-        println!("{:#?}", q);
+    match args[0].as_str() {
+        "info" => info(args.split_off(1)),
+        "check" => check(args.split_off(1)),
+        "read" => read(args.split_off(1)),
+        "extract" => extract(args.split_off(1)),
+        "compare" => compare(args.split_off(1)),
+        "tables" => tables(args.split_off(1)),
+        "features" => features(args.split_off(1)),
+        "refcounts" => refcounts(args.split_off(1)),
+        "chain" => chain(args.split_off(1)),
+        "debug" => debug(args.split_off(1)),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => info(args),
     }
 }
@@ -0,0 +1,692 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::process;
+
+use positioned_io::{ReadAt, Size};
+#[cfg(all(unix, feature = "locking"))]
+use qcow2::LockedFile;
+use qcow2::{Backing, CachePolicy, CheckResult, CreateOptions, OpenOptions, OverlayOptions, Phase,
+            Preallocation, Progress, Qcow2, Qcow2Local, Repair, Storage, create_overlay, probe};
+
+
+// The file type images are written through. `File` isn't `Storage` on Windows, since positioned-io
+// would write to it with a read.
+#[cfg(not(windows))]
+type WriteFile = File;
+#[cfg(windows)]
+type WriteFile = qcow2::RandomAccessFile;
+
+#[cfg(not(windows))]
+fn writable(f: File) -> io::Result<WriteFile> {
+    Ok(f)
+}
+
+#[cfg(windows)]
+fn writable(f: File) -> io::Result<WriteFile> {
+    qcow2::RandomAccessFile::new(f)
+}
+
+static USAGE: &str = "\
+Usage: qcow2-img COMMAND [OPTIONS] ...
+
+Commands:
+    check [-q] [--repair | --repair-all] [--dry-run] IMAGE
+                    Check an image for consistency, like `qemu-img check'. With --repair,
+                    show each change that fixes a problem and ask before making it. With
+                    --repair-all, make every change without asking. With --dry-run, show the
+                    changes without making them. Before anything changes, the first cluster,
+                    the refcount table and the refcount blocks to change are saved to
+                    IMAGE.repair-backup, as records of a 64-bit big-endian offset and length
+                    followed by the bytes from the image. Repairing needs the `locking'
+                    feature, and images in use by qemu are refused. Exits with 2 if
+                    corruptions remain, or 3 if only leaks remain.
+    convert [-q] [-f FMT] [-O FMT] [-c] [-l SNAPSHOT] SOURCE OUTPUT
+                    Copy an image to a new file, like `qemu-img convert'. The source may be a
+                    qcow2 or raw image, which is probed for unless -f gives the format. The
+                    output format may be raw, the default, or qcow2. Data from backing files
+                    is copied, so the output stands alone. With -c, qcow2 output is
+                    compressed. With -l, copy a snapshot rather than the current contents.
+    create [-q] [-f qcow2] [-o OPTIONS] [-b BACKING [-F FMT]] IMAGE [SIZE]
+                    Create a new qcow2 image, like `qemu-img create'. OPTIONS are separated by
+                    commas, and may be cluster_size=SIZE or preallocation=off, metadata or
+                    full. With -b, the image is an empty overlay on BACKING, of the same size
+                    unless SIZE is given. IMAGE must not exist yet.
+    resize [--shrink] IMAGE [+|-]SIZE
+                    Change the size of a qcow2 image, like `qemu-img resize'. Sizes may have a
+                    K, M, G or T suffix. Making an image smaller needs --shrink.
+    snapshot -l IMAGE
+                    List the snapshots of an image.
+
+While checking, repairing or converting, progress is shown if standard error is a terminal,
+unless -q or --quiet is given.
+
+Committing to a backing file, and creating, applying or deleting snapshots aren't supported
+yet.";
+
+// What's added to the name of an image to get the name of its backup, when repairing.
+const BACKUP_SUFFIX: &str = ".repair-backup";
+
+// The most backing files to follow, so a chain that loops doesn't go on forever.
+const MAX_CHAIN: usize = 64;
+
+trait OrDie<T> {
+    fn or_die(self, msg: &str, path: &str) -> T;
+}
+impl<T, E: std::fmt::Display> OrDie<T> for Result<T, E> {
+    fn or_die(self, msg: &str, path: &str) -> T {
+        match self {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("{} `{}': {}", msg, path, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(1);
+}
+
+fn unsupported(what: &str) -> ! {
+    eprintln!("qcow2-img: {} isn't supported yet", what);
+    process::exit(1);
+}
+
+// Open an image and its backing files, so all of its data can be read.
+fn open(path: &str, depth: usize) -> Qcow2<File> {
+    let f = File::open(path).or_die("Error opening file", path);
+    let mut q = Qcow2::open(f).or_die("Error reading qcow2", path);
+    open_backing(&mut q, path, depth);
+    q
+}
+
+// Open an image that's only read from this thread, and its backing files.
+fn open_local(path: &str) -> Qcow2Local<File> {
+    let f = File::open(path).or_die("Error opening file", path);
+    let mut q = OpenOptions::new().open_local(f).or_die("Error reading qcow2", path);
+    open_backing(&mut q, path, 0);
+    q
+}
+
+// Attach the backing file of an image, if it has one.
+fn open_backing<C: CachePolicy>(q: &mut Qcow2<File, C>, path: &str, depth: usize) {
+    let name = match q.backing_file_name() {
+        Some(name) => Path::new(path).parent().unwrap_or(Path::new("")).join(name),
+        None => return,
+    };
+    if depth >= MAX_CHAIN {
+        eprintln!("Error reading qcow2 `{}': too many backing files", path);
+        process::exit(1);
+    }
+    let name = name.to_string_lossy().into_owned();
+    let f = File::open(&name).or_die("Error opening file", &name);
+    // Without a recorded format, guess it like qemu does.
+    let is_qcow2 = match q.backing_format() {
+        Some(format) => format != "raw",
+        None => probe(&f).or_die("Error reading file", &name).is_qcow2,
+    };
+    let backing = if is_qcow2 {
+        Backing::qcow2(open(&name, depth + 1))
+    } else {
+        Backing::raw(f)
+    };
+    q.set_backing(backing.or_die("Error opening backing file", &name));
+}
+
+// Command-line arguments for a command, with options in the style of qemu-img.
+struct Args {
+    flags: Vec<String>,
+    values: Vec<(String, String)>,
+    paths: Vec<String>,
+}
+
+impl Args {
+    // Parse arguments, given the flags and the options taking a value that a command accepts.
+    fn parse(args: Vec<String>, flags: &[&str], valued: &[&str]) -> Self {
+        let mut ret = Args {
+            flags: Vec::new(),
+            values: Vec::new(),
+            paths: Vec::new(),
+        };
+        let mut iter = args.into_iter();
+        while let Some(a) = iter.next() {
+            if !a.starts_with('-') || a == "-" {
+                ret.paths.push(a);
+            } else if flags.contains(&a.as_str()) {
+                ret.flags.push(a);
+            } else if valued.contains(&a.as_str()) {
+                match iter.next() {
+                    Some(v) => ret.values.push((a, v)),
+                    None => usage_error(&format!("Option `{}' needs a value", a)),
+                }
+            } else {
+                usage_error(&format!("Unknown option `{}'", a));
+            }
+        }
+        ret
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.iter().rev().find(|v| v.0 == name).map(|v| v.1.as_str())
+    }
+
+    // Every value given for an option that may be repeated, in order.
+    fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values.iter().filter(move |v| v.0 == name).map(|v| v.1.as_str())
+    }
+
+    fn quiet(&self) -> bool {
+        self.flag("-q") || self.flag("--quiet")
+    }
+}
+
+// Parse a size with an optional binary suffix.
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// A progress bar on standard error, only shown if that's a terminal.
+struct ProgressBar {
+    shown: bool,
+    // The phase in progress, its number and how many phases there are.
+    phase: Cell<(Phase, usize, usize)>,
+    // The last progress drawn, in tenths of a percent, so the bar is only redrawn if it changes.
+    drawn: Cell<Option<u64>>,
+}
+
+impl ProgressBar {
+    const WIDTH: u64 = 40;
+
+    fn new(args: &Args) -> Self {
+        ProgressBar {
+            shown: !args.quiet() && io::stderr().is_terminal(),
+            phase: Cell::new((Phase::Copying, 0, 1)),
+            drawn: Cell::new(None),
+        }
+    }
+
+    // Clear the bar, so other output can follow.
+    fn finish(&self) {
+        if self.drawn.take().is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl Progress for ProgressBar {
+    fn update(&self, done: u64, total: u64, _physical: u64) -> ControlFlow<()> {
+        let permille = match total {
+            0 => 1000,
+            _ => (done as u128 * 1000 / total as u128) as u64,
+        };
+        if !self.shown || self.drawn.get() == Some(permille) {
+            return ControlFlow::Continue(());
+        }
+        self.drawn.set(Some(permille));
+        let (phase, step, steps) = self.phase.get();
+        let filled = (permille * Self::WIDTH / 1000) as usize;
+        eprint!("\r\x1b[K{} ({}/{}) [{:<width$}] {:>3}.{}%",
+                phase,
+                step + 1,
+                steps,
+                "#".repeat(filled),
+                permille / 10,
+                permille % 10,
+                width = Self::WIDTH as usize);
+        ControlFlow::Continue(())
+    }
+
+    fn phase(&self, phase: Phase, step: usize, steps: usize) {
+        self.phase.set((phase, step, steps));
+        self.drawn.set(None);
+    }
+}
+
+// How the check command repairs an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RepairMode {
+    None,
+    DryRun,
+    Ask,
+    All,
+}
+
+// Open an image to check, locked so nobody writes to it meanwhile.
+#[cfg(all(unix, feature = "locking"))]
+fn open_for_check(path: &str, write: bool) -> Qcow2<LockedFile> {
+    let f = if write { LockedFile::open_rw(path) } else { LockedFile::open(path) };
+    let f = f.or_die("Error opening file", path);
+    Qcow2::open(f).or_die("Error reading qcow2", path)
+}
+
+// Without locks there's no telling whether qemu is using the image, so it's only read.
+#[cfg(not(all(unix, feature = "locking")))]
+fn open_for_check(path: &str, write: bool) -> Qcow2<WriteFile> {
+    if write {
+        eprintln!("qcow2-img: repairing needs the `locking' feature, to be sure `{}' isn't in \
+                   use",
+                  path);
+        process::exit(1);
+    }
+    let f = File::open(path).and_then(writable).or_die("Error opening file", path);
+    Qcow2::open(f).or_die("Error reading qcow2", path)
+}
+
+fn check(args: Vec<String>) {
+    let args = Args::parse(args,
+                           &["--repair", "--repair-all", "--dry-run", "-q", "--quiet"],
+                           &[]);
+    if args.paths.len() != 1 {
+        usage_error("One image is needed");
+    }
+    let mode = if args.flag("--dry-run") {
+        RepairMode::DryRun
+    } else if args.flag("--repair-all") {
+        RepairMode::All
+    } else if args.flag("--repair") {
+        RepairMode::Ask
+    } else {
+        RepairMode::None
+    };
+    let path = &args.paths[0];
+    let mut q = open_for_check(path, mode == RepairMode::Ask || mode == RepairMode::All);
+    let bar = ProgressBar::new(&args);
+    let result = q.check_reporting(&bar);
+    bar.finish();
+    let result = result.or_die("Error checking qcow2", path);
+    for f in &result.findings {
+        println!("{}", f);
+    }
+    check_summary(&result);
+    if mode == RepairMode::None {
+        check_exit(&result);
+    }
+
+    let plan = q.repair_plan(&result).or_die("Error checking qcow2", path);
+    if plan.is_empty() {
+        println!("Nothing can be repaired.");
+        check_exit(&result);
+    }
+    if mode == RepairMode::DryRun {
+        for r in &plan {
+            println!("Would {}", r);
+        }
+        check_exit(&result);
+    }
+    repair(path, &mut q, &plan, mode == RepairMode::Ask, &bar);
+    let result = q.check_reporting(&bar);
+    bar.finish();
+    let result = result.or_die("Error checking qcow2", path);
+    check_summary(&result);
+    check_exit(&result);
+}
+
+fn check_summary(result: &CheckResult) {
+    if result.is_clean() {
+        println!("No errors were found on the image.");
+    } else {
+        println!("{} errors and {} leaked clusters were found on the image.",
+                 result.corruptions,
+                 result.leaks);
+    }
+}
+
+// Exit like qemu-img does after checking.
+fn check_exit(result: &CheckResult) -> ! {
+    if result.corruptions > 0 {
+        process::exit(2);
+    } else if result.leaks > 0 {
+        process::exit(3);
+    }
+    process::exit(0);
+}
+
+// Make the changes in a repair plan, optionally asking about each one.
+fn repair<I: Storage + Size>(path: &str,
+                             q: &mut Qcow2<I>,
+                             plan: &[Repair],
+                             ask: bool,
+                             bar: &ProgressBar) {
+    let backup = format!("{}{}", path, BACKUP_SUFFIX);
+    save_backup(path, &backup, q, plan).or_die("Error saving backup", &backup);
+    println!("Saved the metadata to change in `{}'.", backup);
+    if !ask {
+        let result = q.repair_all(plan, bar);
+        bar.finish();
+        result.or_die("Error repairing", path);
+        for r in plan {
+            println!("Repaired: {}", r);
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut skipped = false;
+    for r in plan {
+        // The dirty bit promises nothing about refcounts, so it stays until they're all right.
+        if *r == Repair::ClearDirty && skipped {
+            println!("Leaving the image dirty, since some refcounts weren't repaired.");
+            continue;
+        }
+        print!("Repair: {}? [y/N] ", r);
+        io::stdout().flush().or_die("Error writing to", "stdout");
+        let answer = match lines.next() {
+            Some(line) => line.or_die("Error reading from", "stdin"),
+            None => String::new(),
+        };
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            skipped = true;
+            continue;
+        }
+        q.repair(r).or_die("Error repairing", path);
+        println!("Repaired: {}", r);
+    }
+}
+
+// Save the first cluster, the refcount table and the refcount blocks that a plan changes, to a
+// new file. An existing backup is never replaced, it may be the only good copy.
+fn save_backup<I: ReadAt>(path: &str,
+                          backup: &str,
+                          q: &Qcow2<I>,
+                          plan: &[Repair])
+                          -> io::Result<()> {
+    let h = q.header_info();
+    let cs = q.cluster_size();
+    let mut regions = vec![(0, cs),
+                           (h.refcount_table_offset, h.refcount_table_clusters as u64 * cs)];
+    for r in plan {
+        if let Repair::Refcount { block, .. } = *r {
+            if !regions.contains(&(block, cs)) {
+                regions.push((block, cs));
+            }
+        }
+    }
+
+    let image = File::open(path)?;
+    let mut out = fs::OpenOptions::new().write(true).create_new(true).open(backup)?;
+    let mut buf = Vec::new();
+    for (offset, len) in regions {
+        buf.resize(len as usize, 0);
+        image.read_exact_at(offset, &mut buf)?;
+        out.write_all(&offset.to_be_bytes())?;
+        out.write_all(&len.to_be_bytes())?;
+        out.write_all(&buf)?;
+    }
+    out.sync_all()
+}
+
+fn convert(args: Vec<String>) {
+    let args = Args::parse(args, &["-c", "-q", "--quiet"], &["-f", "-O", "-l"]);
+    if args.paths.len() != 2 {
+        usage_error("A source image and an output file are needed");
+    }
+    let (path, out_path) = (&args.paths[0], &args.paths[1]);
+    let raw_src = match args.value("-f") {
+        None => {
+            let f = File::open(path).or_die("Error opening file", path);
+            !probe(&f).or_die("Error reading file", path).is_qcow2
+        }
+        Some("qcow2") => false,
+        Some("raw") => true,
+        Some(fmt) => usage_error(&format!("Unknown format `{}'", fmt)),
+    };
+    let raw = match args.value("-O") {
+        None | Some("raw") => true,
+        Some("qcow2") => false,
+        Some(fmt) => usage_error(&format!("Unknown format `{}'", fmt)),
+    };
+    let mut opts = CreateOptions::new();
+    if args.flag("-c") {
+        if raw {
+            usage_error("Compressed output needs -O qcow2");
+        }
+        opts.compress(true);
+    }
+    if raw_src {
+        convert_raw(&args, &opts);
+        return;
+    }
+
+    let q = open_local(path);
+    let reader = match args.value("-l") {
+        Some(name) => q.snapshot_reader(name),
+        None => q.reader(),
+    };
+    let reader = reader.or_die("Error reading qcow2", path);
+    let mut out = create_output(out_path);
+    let bar = ProgressBar::new(&args);
+    if raw {
+        let result = reader.export_raw_reporting(&mut out, true, &bar);
+        bar.finish();
+        result.or_die("Error converting", path);
+    } else {
+        let out = writable(out).or_die("Error creating file", out_path);
+        let result = Qcow2::create_from_reporting(&reader, out, &opts, &bar);
+        bar.finish();
+        result.or_die("Error converting", path);
+    }
+}
+
+// Convert a raw image, which can only become a qcow2 image.
+fn convert_raw(args: &Args, opts: &CreateOptions) {
+    let (path, out_path) = (&args.paths[0], &args.paths[1]);
+    if args.value("-l").is_some() {
+        usage_error("Raw images have no snapshots");
+    }
+    if args.value("-O") != Some("qcow2") {
+        unsupported("converting raw images to raw");
+    }
+    let src = File::open(path).or_die("Error opening file", path);
+    let out = writable(create_output(out_path)).or_die("Error creating file", out_path);
+    let bar = ProgressBar::new(args);
+    let result = Qcow2::create_from_raw_reporting(&src, out, opts, &bar);
+    bar.finish();
+    result.or_die("Error converting", path);
+}
+
+// Create the output file of a conversion, replacing anything already there.
+fn create_output(path: &str) -> File {
+    // Creating a qcow2 image reads it back, so it must be readable too.
+    let out = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path);
+    out.or_die("Error creating file", path)
+}
+
+fn create(args: Vec<String>) {
+    let args = Args::parse(args, &["-q", "--quiet"], &["-f", "-o", "-b", "-F"]);
+    match args.value("-f") {
+        None | Some("qcow2") => {}
+        Some(fmt) => unsupported(&format!("creating {} images", fmt)),
+    }
+    let (path, size) = match &args.paths[..] {
+        [path] => (path, None),
+        [path, size] => {
+            match parse_size(size) {
+                Some(size) => (path, Some(size)),
+                None => usage_error(&format!("Invalid size `{}'", size)),
+            }
+        }
+        _ => usage_error("An image and a size are needed"),
+    };
+    let (mut cluster_bits, mut prealloc) = (None, None);
+    for opt in args.values("-o").flat_map(|o| o.split(',')) {
+        match opt.split_once('=') {
+            Some(("cluster_size", v)) => {
+                match parse_size(v) {
+                    Some(cs) if cs.is_power_of_two() => cluster_bits = Some(cs.trailing_zeros()),
+                    _ => usage_error(&format!("Invalid cluster size `{}'", v)),
+                }
+            }
+            Some(("preallocation", "off")) => prealloc = None,
+            Some(("preallocation", "metadata")) => prealloc = Some(Preallocation::Metadata),
+            Some(("preallocation", "full")) => prealloc = Some(Preallocation::Full),
+            _ => usage_error(&format!("Invalid option `{}'", opt)),
+        }
+    }
+
+    match args.value("-b") {
+        Some(base) => {
+            if prealloc.is_some() {
+                unsupported("preallocating overlays");
+            }
+            // The format is found by probing, so a given one just has to match.
+            if let Some(fmt) = args.value("-F") {
+                let f = File::open(base).or_die("Error opening file", base);
+                let is_qcow2 = probe(&f).or_die("Error reading file", base).is_qcow2;
+                if fmt != if is_qcow2 { "qcow2" } else { "raw" } {
+                    eprintln!("qcow2-img: `{}' isn't a {} image", base, fmt);
+                    process::exit(1);
+                }
+            }
+            let mut opts = OverlayOptions::new();
+            if let Some(size) = size {
+                opts.size(size);
+            }
+            if let Some(bits) = cluster_bits {
+                opts.cluster_bits(bits);
+            }
+            create_overlay(base, path, &opts).or_die("Error creating image", path);
+        }
+        None => {
+            if args.value("-F").is_some() {
+                usage_error("A backing format needs a backing file");
+            }
+            let size = match size {
+                Some(size) => size,
+                None => usage_error("An image and a size are needed"),
+            };
+            let mut opts = CreateOptions::new();
+            if let Some(bits) = cluster_bits {
+                opts.cluster_bits(bits);
+            }
+            let out = fs::OpenOptions::new().read(true).write(true).create_new(true).open(path);
+            let out = out.and_then(writable).or_die("Error creating file", path);
+            let mut q = Qcow2::create(out, size, &opts).or_die("Error creating image", path);
+            if let Some(mode) = prealloc {
+                let mut writer = q.writer().or_die("Error preallocating", path);
+                writer.preallocate(0, size, mode).or_die("Error preallocating", path);
+            }
+        }
+    }
+
+    if !args.quiet() {
+        let f = File::open(path).or_die("Error opening file", path);
+        let q = Qcow2::open(f).or_die("Error reading qcow2", path);
+        print!("Formatting '{}', fmt=qcow2 cluster_size={} size={}",
+               path,
+               q.cluster_size(),
+               q.guest_size());
+        if let Some(name) = q.backing_file_name() {
+            print!(" backing_file={}", name.display());
+        }
+        if let Some(format) = q.backing_format() {
+            print!(" backing_fmt={}", format);
+        }
+        println!();
+    }
+}
+
+fn resize(mut args: Vec<String>) {
+    // The size may start with a minus sign, so it can't be parsed as an option.
+    let size = match args.pop() {
+        Some(size) => size,
+        None => usage_error("An image and a size are needed"),
+    };
+    let args = Args::parse(args, &["--shrink"], &[]);
+    if args.paths.len() != 1 {
+        usage_error("An image and a size are needed");
+    }
+    let path = &args.paths[0];
+    let (sign, amount) = match size.as_bytes().first() {
+        Some(b'+') | Some(b'-') => (Some(size.as_bytes()[0]), &size[1..]),
+        _ => (None, &size[..]),
+    };
+    let amount = match parse_size(amount) {
+        Some(amount) => amount,
+        None => usage_error(&format!("Invalid size `{}'", size)),
+    };
+
+    let f = fs::OpenOptions::new().read(true).write(true).open(path).and_then(writable);
+    let f = f.or_die("Error opening file", path);
+    let mut q = Qcow2::open(f).or_die("Error reading qcow2", path);
+    let current = q.guest_size();
+    let new_size = match sign {
+        Some(b'+') => current.checked_add(amount),
+        Some(_) => current.checked_sub(amount),
+        None => Some(amount),
+    };
+    let new_size = match new_size {
+        Some(new_size) => new_size,
+        None => usage_error(&format!("Invalid size `{}'", size)),
+    };
+    if new_size < current && !args.flag("--shrink") {
+        eprintln!("Use the --shrink option to make `{}' smaller", path);
+        process::exit(1);
+    }
+    q.resize(new_size).or_die("Error resizing", path);
+    println!("Image resized.");
+}
+
+fn snapshot(args: Vec<String>) {
+    let args = Args::parse(args, &["-l"], &["-c", "-d", "-a"]);
+    if args.value("-c").is_some() {
+        unsupported("creating snapshots");
+    }
+    if args.value("-d").is_some() {
+        unsupported("deleting snapshots");
+    }
+    if args.value("-a").is_some() {
+        unsupported("applying snapshots");
+    }
+    if !args.flag("-l") {
+        usage_error("No snapshot operation given");
+    }
+    if args.paths.len() != 1 {
+        usage_error("One image is needed");
+    }
+    let path = &args.paths[0];
+    let f = File::open(path).or_die("Error opening file", path);
+    let q = Qcow2::open(f).or_die("Error reading qcow2", path);
+    print!("{}", q.info().or_die("Error reading qcow2", path).snapshot_table());
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        println!("{}", USAGE);
+        return;
+    }
+
+    match args[0].as_str() {
+        "check" => check(args.split_off(1)),
+        "convert" => convert(args.split_off(1)),
+        "create" => create(args.split_off(1)),
+        "resize" => resize(args.split_off(1)),
+        "snapshot" => snapshot(args.split_off(1)),
+        "commit" => unsupported("committing to a backing file"),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        cmd => usage_error(&format!("Unknown command `{}'", cmd)),
+    }
+}
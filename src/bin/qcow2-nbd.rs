@@ -0,0 +1,687 @@
+extern crate byteorder;
+extern crate positioned_io;
+extern crate qcow2;
+
+use std::cmp::{max, min};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use positioned_io::{ReadAt, Size};
+use qcow2::{Backing, ExtentKind, GuestRange, OpenOptions, Qcow2, Reader, probe};
+
+
+static USAGE: &str = "\
+Usage: qcow2-nbd [OPTIONS] QCOW2
+
+Serve a qcow2 image read-only over the NBD protocol, until killed.
+
+Options:
+    --port N        Listen for TCP connections on port N. The default is 10809, and 0 picks
+                    any free port.
+    --bind ADDR     Listen on address ADDR. The default is 127.0.0.1.
+    --socket PATH   Listen on a unix socket at PATH, rather than over TCP.
+    --name NAME     Call the export NAME. Clients may also ask for the default export, with
+                    an empty name.
+    --snapshot NAME Serve an internal snapshot, rather than the current contents.
+
+Backing files are opened relative to the image that names them, and served along with it.";
+
+// Handshake.
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options.
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_LIST_META_CONTEXT: u32 = 9;
+const OPT_SET_META_CONTEXT: u32 = 10;
+
+// Option replies.
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+// Transmission flags.
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Commands.
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_BLOCK_STATUS: u16 = 7;
+const CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+// Replies.
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) + 1;
+const STATE_HOLE: u32 = 1 << 0;
+const STATE_ZERO: u32 = 1 << 1;
+
+// Errors.
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+// The only metadata context, and its ID.
+const BASE_ALLOCATION: &str = "base:allocation";
+const BASE_ALLOCATION_ID: u32 = 1;
+
+// The most backing files to follow, so a chain that loops doesn't go on forever.
+const MAX_CHAIN: usize = 64;
+
+// The largest option and request we accept.
+const MAX_OPTION: u32 = 64 << 10;
+const MAX_REQUEST: u32 = 32 << 20;
+
+fn die(msg: &str, what: &str, err: impl Display) -> ! {
+    eprintln!("{} `{}': {}", msg, what, err);
+    process::exit(1);
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(1);
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Open an image, any external data file and its backing files, each relative to the image
+// that names it.
+fn open(path: &str, depth: usize) -> Qcow2<File> {
+    let mut q = open_image(path);
+    let name = match q.backing_file_name() {
+        Some(name) => Path::new(path).parent().unwrap_or(Path::new("")).join(name),
+        None => return q,
+    };
+    if depth >= MAX_CHAIN {
+        die("Error reading qcow2", path, "too many backing files");
+    }
+    let name = name.to_string_lossy().into_owned();
+    let f = File::open(&name).unwrap_or_else(|e| die("Error opening file", &name, e));
+    // Without a recorded format, guess it like qemu does.
+    let is_qcow2 = match q.backing_format() {
+        Some(format) => format != "raw",
+        None => probe(&f).unwrap_or_else(|e| die("Error reading file", &name, e)).is_qcow2,
+    };
+    let backing = if is_qcow2 {
+        Backing::qcow2(open(&name, depth + 1))
+    } else {
+        Backing::raw(f)
+    };
+    q.set_backing(backing.unwrap_or_else(|e| die("Error opening backing file", &name, e)));
+    q
+}
+
+// Open an image and any external data file, relative to the image.
+fn open_image(path: &str) -> Qcow2<File> {
+    let f = File::open(path).unwrap_or_else(|e| die("Error opening file", path, e));
+    let q = Qcow2::open(f).unwrap_or_else(|e| die("Error reading qcow2", path, e));
+    let data_path = match q.data_file_name() {
+        Some(name) => Path::new(path).parent().unwrap_or(Path::new("")).join(name),
+        None => return q,
+    };
+    let data_path = data_path.to_string_lossy();
+    let f = File::open(path).unwrap_or_else(|e| die("Error opening file", path, e));
+    let data = File::open(&*data_path)
+        .unwrap_or_else(|e| die("Error opening data file", &data_path, e));
+    OpenOptions::new()
+        .open_with_data_file(f, data)
+        .unwrap_or_else(|e| die("Error reading qcow2", path, e))
+}
+
+// What is being served.
+struct Export {
+    path: String,
+    q: Qcow2<File>,
+    name: String,
+    snapshot: Option<String>,
+}
+
+impl Export {
+    fn reader(&self) -> qcow2::Result<Reader<'_, File>> {
+        match self.snapshot {
+            Some(ref name) => self.q.snapshot_reader(name),
+            None => self.q.reader(),
+        }
+    }
+
+    // Check if a client is asking for this export.
+    fn is_named(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+}
+
+// A qcow2 image in the chain being served, and how much of it shows through the images above.
+struct Layer<'a> {
+    reader: Reader<'a, File>,
+    size: u64,
+}
+
+// The state of one client's connection.
+struct Client<'a, S> {
+    stream: S,
+    // What's served, then each qcow2 backing file below it.
+    layers: Vec<Layer<'a>>,
+    // The size of a raw backing file at the bottom of the chain, if there is one.
+    raw_size: Option<u64>,
+    size: u64,
+    cluster_size: u64,
+    no_zeroes: bool,
+    structured: bool,
+    allocation: bool,
+}
+
+impl<'a, S> Client<'a, S>
+    where S: Read + Write
+{
+    // Negotiate how to talk. Returns false if the client gave up.
+    fn handshake(&mut self, export: &Export) -> io::Result<bool> {
+        let mut hello = Vec::new();
+        hello.write_u64::<BigEndian>(NBDMAGIC)?;
+        hello.write_u64::<BigEndian>(IHAVEOPT)?;
+        hello.write_u16::<BigEndian>(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)?;
+        self.stream.write_all(&hello)?;
+        let flags = self.stream.read_u32::<BigEndian>()?;
+        self.no_zeroes = flags & FLAG_C_NO_ZEROES != 0;
+
+        loop {
+            if self.stream.read_u64::<BigEndian>()? != IHAVEOPT {
+                return Err(invalid("bad option magic"));
+            }
+            let opt = self.stream.read_u32::<BigEndian>()?;
+            let len = self.stream.read_u32::<BigEndian>()?;
+            if len > MAX_OPTION {
+                return Err(invalid("option too big"));
+            }
+            let mut data = vec![0; len as usize];
+            self.stream.read_exact(&mut data)?;
+
+            match opt {
+                OPT_EXPORT_NAME => {
+                    if !export.is_named(&data) {
+                        return Ok(false);
+                    }
+                    let mut buf = Vec::new();
+                    buf.write_u64::<BigEndian>(self.size)?;
+                    buf.write_u16::<BigEndian>(transmission_flags())?;
+                    if !self.no_zeroes {
+                        buf.extend_from_slice(&[0; 124]);
+                    }
+                    self.stream.write_all(&buf)?;
+                    return Ok(true);
+                }
+                OPT_ABORT => {
+                    self.option_reply(opt, REP_ACK, &[])?;
+                    return Ok(false);
+                }
+                OPT_LIST if !data.is_empty() => self.option_reply(opt, REP_ERR_INVALID, &[])?,
+                OPT_LIST => {
+                    let mut buf = Vec::new();
+                    buf.write_u32::<BigEndian>(export.name.len() as u32)?;
+                    buf.extend_from_slice(export.name.as_bytes());
+                    self.option_reply(opt, REP_SERVER, &buf)?;
+                    self.option_reply(opt, REP_ACK, &[])?;
+                }
+                OPT_INFO | OPT_GO => {
+                    if self.info(export, opt, &data)? && opt == OPT_GO {
+                        return Ok(true);
+                    }
+                }
+                OPT_STRUCTURED_REPLY if !data.is_empty() => {
+                    self.option_reply(opt, REP_ERR_INVALID, &[])?
+                }
+                OPT_STRUCTURED_REPLY => {
+                    self.structured = true;
+                    self.option_reply(opt, REP_ACK, &[])?;
+                }
+                OPT_LIST_META_CONTEXT | OPT_SET_META_CONTEXT => {
+                    self.meta_context(export, opt, &data)?
+                }
+                _ => self.option_reply(opt, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    fn option_reply(&mut self, opt: u32, kind: u32, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(20 + data.len());
+        buf.write_u64::<BigEndian>(REPLY_MAGIC)?;
+        buf.write_u32::<BigEndian>(opt)?;
+        buf.write_u32::<BigEndian>(kind)?;
+        buf.write_u32::<BigEndian>(data.len() as u32)?;
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)
+    }
+
+    // Answer NBD_OPT_INFO or NBD_OPT_GO. Returns whether the export was found.
+    fn info(&mut self, export: &Export, opt: u32, data: &[u8]) -> io::Result<bool> {
+        let mut cur = data;
+        let requests = match read_string(&mut cur) {
+            Some(name) if !export.is_named(name) => {
+                self.option_reply(opt, REP_ERR_UNKNOWN, &[])?;
+                return Ok(false);
+            }
+            Some(_) => read_u16s(&mut cur),
+            None => None,
+        };
+        let requests = match requests {
+            Some(r) if cur.is_empty() => r,
+            _ => {
+                self.option_reply(opt, REP_ERR_INVALID, &[])?;
+                return Ok(false);
+            }
+        };
+
+        let mut buf = Vec::new();
+        buf.write_u16::<BigEndian>(INFO_EXPORT)?;
+        buf.write_u64::<BigEndian>(self.size)?;
+        buf.write_u16::<BigEndian>(transmission_flags())?;
+        self.option_reply(opt, REP_INFO, &buf)?;
+        if requests.contains(&INFO_BLOCK_SIZE) {
+            let mut buf = Vec::new();
+            buf.write_u16::<BigEndian>(INFO_BLOCK_SIZE)?;
+            buf.write_u32::<BigEndian>(1)?;
+            buf.write_u32::<BigEndian>(min(self.cluster_size, MAX_REQUEST as u64) as u32)?;
+            buf.write_u32::<BigEndian>(MAX_REQUEST)?;
+            self.option_reply(opt, REP_INFO, &buf)?;
+        }
+        self.option_reply(opt, REP_ACK, &[])?;
+        Ok(true)
+    }
+
+    // Answer NBD_OPT_LIST_META_CONTEXT or NBD_OPT_SET_META_CONTEXT.
+    fn meta_context(&mut self, export: &Export, opt: u32, data: &[u8]) -> io::Result<()> {
+        if opt == OPT_SET_META_CONTEXT && !self.structured {
+            return self.option_reply(opt, REP_ERR_INVALID, &[]);
+        }
+        let mut cur = data;
+        let name = read_string(&mut cur);
+        let mut queries = Vec::new();
+        let mut valid = name.is_some();
+        if valid {
+            match cur.read_u32::<BigEndian>() {
+                Ok(count) => {
+                    for _ in 0..count {
+                        match read_string(&mut cur) {
+                            Some(q) => queries.push(q),
+                            None => valid = false,
+                        }
+                    }
+                }
+                Err(_) => valid = false,
+            }
+        }
+        if !valid || !cur.is_empty() {
+            return self.option_reply(opt, REP_ERR_INVALID, &[]);
+        }
+        if !name.is_some_and(|n| export.is_named(n)) {
+            return self.option_reply(opt, REP_ERR_UNKNOWN, &[]);
+        }
+
+        let found = if opt == OPT_LIST_META_CONTEXT {
+            queries.is_empty() || queries.iter().any(|&q| q == b"base:" || q == b"base:allocation")
+        } else {
+            queries.contains(&BASE_ALLOCATION.as_bytes())
+        };
+        if opt == OPT_SET_META_CONTEXT {
+            self.allocation = found;
+        }
+        if found {
+            let mut buf = Vec::new();
+            buf.write_u32::<BigEndian>(BASE_ALLOCATION_ID)?;
+            buf.extend_from_slice(BASE_ALLOCATION.as_bytes());
+            self.option_reply(opt, REP_META_CONTEXT, &buf)?;
+        }
+        self.option_reply(opt, REP_ACK, &[])
+    }
+
+    // Handle requests until the client disconnects.
+    fn transmission(&mut self) -> io::Result<()> {
+        loop {
+            if self.stream.read_u32::<BigEndian>()? != REQUEST_MAGIC {
+                return Err(invalid("bad request magic"));
+            }
+            let flags = self.stream.read_u16::<BigEndian>()?;
+            let kind = self.stream.read_u16::<BigEndian>()?;
+            let handle = self.stream.read_u64::<BigEndian>()?;
+            let offset = self.stream.read_u64::<BigEndian>()?;
+            let len = self.stream.read_u32::<BigEndian>()?;
+            let in_bounds = offset.checked_add(len as u64).is_some_and(|end| end <= self.size);
+
+            match kind {
+                CMD_READ if !in_bounds || len > MAX_REQUEST => self.done(handle, Err(EINVAL))?,
+                CMD_READ => self.read(handle, offset, len)?,
+                CMD_WRITE => {
+                    // Skip the data, then refuse.
+                    let skipped = io::copy(&mut (&mut self.stream).take(len as u64),
+                                           &mut io::sink())?;
+                    if skipped < len as u64 {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                    self.done(handle, Err(EPERM))?;
+                }
+                CMD_DISC => return Ok(()),
+                CMD_FLUSH => self.done(handle, Ok(()))?,
+                CMD_TRIM | CMD_WRITE_ZEROES => self.done(handle, Err(EPERM))?,
+                CMD_BLOCK_STATUS if !self.allocation || !in_bounds || len == 0 => {
+                    self.done(handle, Err(EINVAL))?
+                }
+                CMD_BLOCK_STATUS => {
+                    self.block_status(handle, offset, len, flags & CMD_FLAG_REQ_ONE != 0)?
+                }
+                _ => self.done(handle, Err(EINVAL))?,
+            }
+            self.stream.flush()?;
+        }
+    }
+
+    fn read(&mut self, handle: u64, offset: u64, len: u32) -> io::Result<()> {
+        let mut data = vec![0; len as usize];
+        if self.layers[0].reader.read_exact_at(offset, &mut data).is_err() {
+            return self.done(handle, Err(EIO));
+        }
+        if len == 0 {
+            return self.done(handle, Ok(()));
+        }
+
+        let mut buf = Vec::with_capacity(32 + data.len());
+        if self.structured {
+            chunk_header(&mut buf, REPLY_TYPE_OFFSET_DATA, handle, 8 + len)?;
+            buf.write_u64::<BigEndian>(offset)?;
+        } else {
+            simple_header(&mut buf, handle, 0)?;
+        }
+        buf.extend_from_slice(&data);
+        self.stream.write_all(&buf)
+    }
+
+    fn block_status(&mut self,
+                    handle: u64,
+                    offset: u64,
+                    len: u32,
+                    one: bool)
+                    -> io::Result<()> {
+        let mut descriptors = Vec::new();
+        let end = offset + len as u64;
+        if allocation(&self.layers, self.raw_size, offset, end, &mut descriptors).is_err() {
+            return self.done(handle, Err(EIO));
+        }
+        if one {
+            descriptors.truncate(1);
+        }
+
+        let mut buf = Vec::new();
+        chunk_header(&mut buf, REPLY_TYPE_BLOCK_STATUS, handle, 4 + 8 * descriptors.len() as u32)?;
+        buf.write_u32::<BigEndian>(BASE_ALLOCATION_ID)?;
+        for (length, state) in descriptors {
+            buf.write_u32::<BigEndian>(length as u32)?;
+            buf.write_u32::<BigEndian>(state)?;
+        }
+        self.stream.write_all(&buf)
+    }
+
+    // Finish a request without data, successfully or with an error.
+    fn done(&mut self, handle: u64, result: Result<(), u32>) -> io::Result<()> {
+        let mut buf = Vec::new();
+        match result {
+            Ok(()) if self.structured => chunk_header(&mut buf, REPLY_TYPE_NONE, handle, 0)?,
+            Err(err) if self.structured => {
+                chunk_header(&mut buf, REPLY_TYPE_ERROR, handle, 6)?;
+                buf.write_u32::<BigEndian>(err)?;
+                buf.write_u16::<BigEndian>(0)?;
+            }
+            _ => simple_header(&mut buf, handle, result.err().unwrap_or(0))?,
+        }
+        self.stream.write_all(&buf)
+    }
+}
+
+// Find the base:allocation state of part of the disk, as a length and state for each run. A
+// range that isn't allocated in one layer is looked up in the layers below it.
+fn allocation(layers: &[Layer],
+              raw_size: Option<u64>,
+              offset: u64,
+              end: u64,
+              states: &mut Vec<(u64, u32)>)
+              -> qcow2::Result<()> {
+    let (layer, below) = match layers.split_first() {
+        Some(layers) => layers,
+        None => {
+            // The bottom of the chain. A raw image is all data, and there's nothing past it.
+            let data_end = max(min(raw_size.unwrap_or(0), end), offset);
+            add_state(states, data_end - offset, 0);
+            add_state(states, end - data_end, STATE_HOLE | STATE_ZERO);
+            return Ok(());
+        }
+    };
+
+    let layer_end = max(min(layer.size, end), offset);
+    for extent in layer.reader.extents(GuestRange { offset, len: layer_end - offset }) {
+        let extent = extent?;
+        let start = max(extent.range.offset, offset);
+        let stop = min(extent.range.end(), layer_end);
+        match extent.kind {
+            ExtentKind::Data { .. } | ExtentKind::Compressed => add_state(states, stop - start, 0),
+            ExtentKind::Zero => add_state(states, stop - start, STATE_ZERO),
+            ExtentKind::Unallocated => allocation(below, raw_size, start, stop, states)?,
+        }
+    }
+    // Past the end of a backing file reads as zeros.
+    add_state(states, end - layer_end, STATE_HOLE | STATE_ZERO);
+    Ok(())
+}
+
+// Add a run to a list of states, merging it with the last if they're the same.
+fn add_state(states: &mut Vec<(u64, u32)>, len: u64, state: u32) {
+    if len == 0 {
+        return;
+    }
+    match states.last_mut() {
+        Some(last) if last.1 == state => last.0 += len,
+        _ => states.push((len, state)),
+    }
+}
+
+fn transmission_flags() -> u16 {
+    FLAG_HAS_FLAGS | FLAG_READ_ONLY | FLAG_CAN_MULTI_CONN
+}
+
+fn simple_header(buf: &mut Vec<u8>, handle: u64, err: u32) -> io::Result<()> {
+    buf.write_u32::<BigEndian>(SIMPLE_REPLY_MAGIC)?;
+    buf.write_u32::<BigEndian>(err)?;
+    buf.write_u64::<BigEndian>(handle)
+}
+
+// Write the header of the only chunk of a structured reply.
+fn chunk_header(buf: &mut Vec<u8>, kind: u16, handle: u64, len: u32) -> io::Result<()> {
+    buf.write_u32::<BigEndian>(STRUCTURED_REPLY_MAGIC)?;
+    buf.write_u16::<BigEndian>(REPLY_FLAG_DONE)?;
+    buf.write_u16::<BigEndian>(kind)?;
+    buf.write_u64::<BigEndian>(handle)?;
+    buf.write_u32::<BigEndian>(len)
+}
+
+// Read a string prefixed by its 32-bit length.
+fn read_string<'a>(cur: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = cur.read_u32::<BigEndian>().ok()? as usize;
+    if len > cur.len() {
+        return None;
+    }
+    let (s, rest) = cur.split_at(len);
+    *cur = rest;
+    Some(s)
+}
+
+// Read a list of 16-bit numbers prefixed by its 16-bit length.
+fn read_u16s(cur: &mut &[u8]) -> Option<Vec<u16>> {
+    let count = cur.read_u16::<BigEndian>().ok()?;
+    (0..count).map(|_| cur.read_u16::<BigEndian>().ok()).collect()
+}
+
+fn serve<S: Read + Write>(export: &Export, stream: S) -> io::Result<()> {
+    let reader = export.reader().map_err(io::Error::other)?;
+    let size = reader.size().map_err(io::Error::other)?.unwrap_or(0);
+    let mut layers = vec![Layer { reader, size }];
+    let mut raw_size = None;
+    let mut backing = export.q.backing();
+    while let Some(b) = backing {
+        match b.image() {
+            Some(image) => {
+                let reader = image.reader().map_err(io::Error::other)?;
+                layers.push(Layer { reader, size: b.size() });
+                backing = image.backing();
+            }
+            None => {
+                raw_size = Some(b.size());
+                backing = None;
+            }
+        }
+    }
+
+    let mut client = Client {
+        stream,
+        layers,
+        raw_size,
+        size,
+        cluster_size: export.q.cluster_size(),
+        no_zeroes: false,
+        structured: false,
+        allocation: false,
+    };
+    if client.handshake(export)? {
+        client.transmission()?;
+    }
+    Ok(())
+}
+
+// Serve each connection in its own thread.
+fn listen<S, L>(export: Arc<Export>, incoming: L)
+    where S: Read + Write + Send + 'static,
+          L: Iterator<Item = io::Result<S>>
+{
+    for stream in incoming {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let export = export.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(&export, stream) {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    eprintln!("Error serving `{}': {}", export.path, e);
+                }
+            }
+        });
+    }
+}
+
+fn main() {
+    let mut port: u16 = 10809;
+    let mut bind = "127.0.0.1".to_owned();
+    let mut socket = None;
+    let mut name = String::new();
+    let mut snapshot = None;
+    let mut paths = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        if a == "--help" || a == "-h" {
+            println!("{}", USAGE);
+            return;
+        }
+        if !a.starts_with("--") {
+            paths.push(a);
+            continue;
+        }
+        let value = match args.next() {
+            Some(v) => v,
+            None => usage_error(&format!("Option `{}' needs a value", a)),
+        };
+        match a.as_str() {
+            "--port" => {
+                port = value.parse()
+                    .unwrap_or_else(|_| usage_error(&format!("Invalid port `{}'", value)))
+            }
+            "--bind" => bind = value,
+            "--socket" => socket = Some(value),
+            "--name" => name = value,
+            "--snapshot" => snapshot = Some(value),
+            _ => usage_error(&format!("Unknown option `{}'", a)),
+        }
+    }
+    if paths.len() != 1 {
+        usage_error("Exactly one image must be served");
+    }
+
+    let path = paths.remove(0);
+    let q = open(&path, 0);
+    let export = Arc::new(Export { path, q, name, snapshot });
+    if let Err(e) = export.reader() {
+        die("Error reading qcow2", &export.path, e);
+    }
+
+    match socket {
+        #[cfg(unix)]
+        Some(socket) => {
+            let listener = UnixListener::bind(&socket)
+                .unwrap_or_else(|e| die("Error listening on", &socket, e));
+            eprintln!("Listening on {}", socket);
+            listen(export, listener.incoming());
+        }
+        #[cfg(not(unix))]
+        Some(_) => usage_error("Unix sockets are not supported on this platform"),
+        None => {
+            let addr = format!("{}:{}", bind, port);
+            let listener = TcpListener::bind(&addr)
+                .unwrap_or_else(|e| die("Error listening on", &addr, e));
+            match listener.local_addr() {
+                Ok(local) => eprintln!("Listening on {}", local),
+                Err(_) => eprintln!("Listening on {}", addr),
+            }
+            let incoming = listener.incoming().map(|s| {
+                s.inspect(|s| {
+                    let _ = s.set_nodelay(true);
+                })
+            });
+            listen(export, incoming);
+        }
+    }
+}
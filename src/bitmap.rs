@@ -0,0 +1,655 @@
+use std::cmp::min;
+use std::io::{Cursor, ErrorKind, Read};
+use std::mem::size_of;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::{ByteIo, ReadAt, ReadInt};
+
+use super::{CachePolicy, Error, GuestRange, Qcow2, Reader, Result, SharedCache};
+use super::alloc::Allocator;
+use super::extension::BitmapDirectory;
+use super::header::{AUTOCLEAR_BITMAPS, Header};
+use super::int::padding_to_multiple;
+use super::read::L2Entry;
+use super::tx::{MetaTx, Stage};
+use super::write::Storage;
+
+
+// Limits on the bitmap directory, matching what qemu accepts.
+const MAX_BITMAPS: u32 = 65535;
+const MAX_DIRECTORY_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_TABLE_SIZE: u32 = 0x8000000;
+const MAX_NAME_SIZE: u16 = 1023;
+const MIN_GRANULARITY_BITS: u8 = 9;
+const MAX_GRANULARITY_BITS: u8 = 31;
+
+// Size of the fixed part of a bitmap directory entry.
+const DIRECTORY_ENTRY_FIXED_SIZE: u64 = 24;
+
+// Fields of bitmap table entries.
+const BITMAP_TABLE_ALL_ONES: u64 = 1;
+pub const BITMAP_TABLE_POS: u64 = 0x00ff_ffff_ffff_fe00;
+pub const BITMAP_TABLE_RESERVED: u64 = !(BITMAP_TABLE_POS | BITMAP_TABLE_ALL_ONES);
+
+// Bitmap flags.
+const BITMAP_IN_USE: u32 = 1;
+const BITMAP_AUTO: u32 = 1 << 1;
+
+// The only bitmap type, which tracks changes to the virtual disk.
+const BITMAP_TYPE_DIRTY: u8 = 1;
+
+/// A persistent dirty bitmap stored in a qcow2 image.
+///
+/// Each bit records whether a part of the virtual disk, of size `granularity`, changed since
+/// the bitmap was cleared. The name is stored as a string, with any invalid UTF-8 replaced.
+///
+/// A bitmap is only useful if everything that wrote to the image updated it, see `consistent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bitmap {
+    /// The name of the bitmap.
+    pub name: String,
+    /// The raw flags of the bitmap.
+    pub flags: u32,
+    /// The type of the bitmap. Only dirty tracking bitmaps, of type 1, are defined.
+    pub bitmap_type: u8,
+    /// The log base 2 of the granularity.
+    pub granularity_bits: u8,
+    /// The offset of the bitmap table.
+    pub table_offset: u64,
+    /// The number of entries in the bitmap table.
+    pub table_size: u32,
+    /// Extra data, which no current version of qcow2 defines.
+    pub extra_data: Vec<u8>,
+    /// Whether the contents of the bitmap can be trusted.
+    ///
+    /// This is false if the bitmap is in use, or if the image was written by something that
+    /// doesn't know about bitmaps, which clears the bitmaps autoclear bit. Finding the dirty
+    /// ranges of an inconsistent bitmap fails, unless this is set to true.
+    pub consistent: bool,
+}
+
+impl Bitmap {
+    /// Whether the bitmap is in use, so its contents are out of date.
+    ///
+    /// Qemu sets this while it owns the bitmap, so it stays set if qemu crashes. Writers from
+    /// this library set it on bitmaps with the auto flag, since they don't update them.
+    pub fn in_use(&self) -> bool {
+        self.flags & BITMAP_IN_USE != 0
+    }
+
+    /// Whether the bitmap records every write to the virtual disk, which qemu calls enabled.
+    pub fn auto(&self) -> bool {
+        self.flags & BITMAP_AUTO != 0
+    }
+
+    /// Get the number of bytes of the virtual disk that each bit covers.
+    pub fn granularity(&self) -> u64 {
+        1 << self.granularity_bits
+    }
+
+    fn read<R: Read>(io: &mut ByteIo<R, BigEndian>, cluster_size: u64) -> Result<Self> {
+        let table_offset = io.read_u64()?;
+        let table_size = io.read_u32()?;
+        let flags = io.read_u32()?;
+        let bitmap_type = io.read_u8()?;
+        let granularity_bits = io.read_u8()?;
+        let name_size = io.read_u16()?;
+        let extra_data_size = io.read_u32()?;
+        if !table_offset.is_multiple_of(cluster_size) {
+            return Err(Error::FileFormat("bitmap table is not cluster aligned".to_owned()));
+        }
+        if table_size > MAX_TABLE_SIZE {
+            return Err(Error::FileFormat(format!("bitmap table too big ({} entries)",
+                                                 table_size)));
+        }
+        if !(MIN_GRANULARITY_BITS..=MAX_GRANULARITY_BITS).contains(&granularity_bits) {
+            return Err(Error::FileFormat(format!("unsupported bitmap granularity bits {}",
+                                                 granularity_bits)));
+        }
+        if name_size > MAX_NAME_SIZE {
+            return Err(Error::FileFormat(format!("bitmap name too long ({} bytes)", name_size)));
+        }
+        // The rest of the entry must stay within the directory, which limits it.
+        let mut extra_data = Vec::new();
+        io.by_ref().take(extra_data_size as u64).read_to_end(&mut extra_data)?;
+        if extra_data.len() != extra_data_size as usize {
+            return Err(Error::FileFormat("bitmap directory is truncated".to_owned()));
+        }
+        let mut name = vec![0; name_size as usize];
+        io.read_exact(&mut name)?;
+
+        let len = DIRECTORY_ENTRY_FIXED_SIZE + extra_data_size as u64 + name_size as u64;
+        let mut pad = vec![0; padding_to_multiple(len, 8)];
+        io.read_exact(&mut pad)?;
+
+        Ok(Bitmap {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            flags,
+            bitmap_type,
+            granularity_bits,
+            table_offset,
+            table_size,
+            extra_data,
+            consistent: flags & BITMAP_IN_USE == 0,
+        })
+    }
+
+    // Add the directory entry for this bitmap to a directory.
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.table_size.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.push(self.bitmap_type);
+        buf.push(self.granularity_bits);
+        buf.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.extra_data);
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.resize(buf.len() + padding_to_multiple(buf.len() as u64, 8), 0);
+    }
+
+    // Read the bitmap table.
+    pub(crate) fn table<I: ReadAt, C: CachePolicy>(&self, q: &Qcow2<I, C>) -> Result<Vec<u64>> {
+        let mut buf = vec![0; self.table_size as usize * size_of::<u64>()];
+        q.io.read_exact_at(self.table_offset, &mut buf)?;
+        Ok(buf.chunks(size_of::<u64>()).map(BigEndian::read_u64).collect())
+    }
+
+    // Get the number of clusters the bitmap table takes.
+    fn table_clusters(&self, cluster_size: u64) -> u64 {
+        (self.table_size as u64 * size_of::<u64>() as u64).div_ceil(cluster_size)
+    }
+
+    /// Find the parts of the virtual disk marked as changed.
+    ///
+    /// Ranges are aligned to the granularity, apart from at the end of the virtual disk. Any
+    /// part of the bitmap past the end of the virtual disk is ignored.
+    ///
+    /// Fails with `Error::InconsistentBitmap` if the bitmap isn't consistent.
+    pub fn dirty_ranges<I, C>(&self, q: &Qcow2<I, C>) -> Result<Vec<GuestRange>>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        if !self.consistent {
+            return Err(Error::InconsistentBitmap(self.name.clone()));
+        }
+        let cluster_size = q.cluster_size();
+        let table = self.table(q)?;
+        let granularity = self.granularity();
+        let bits_per_cluster = cluster_size * 8;
+        let size = q.guest_size();
+        let mut ranges: Vec<GuestRange> = Vec::new();
+        let mut add = |offset: u64, len: u64| {
+            if offset >= size {
+                return;
+            }
+            let len = min(len, size - offset);
+            match ranges.last_mut() {
+                Some(r) if r.end() == offset => r.len += len,
+                _ => ranges.push(GuestRange { offset, len }),
+            }
+        };
+
+        let mut data = vec![0; cluster_size as usize];
+        for (idx, entry) in table.into_iter().enumerate() {
+            let first = match (idx as u64).checked_mul(bits_per_cluster * granularity) {
+                Some(first) if first < size => first,
+                _ => break,
+            };
+            if entry & BITMAP_TABLE_RESERVED != 0 {
+                return Err(Error::FileFormat("reserved bit used in bitmap table entry"
+                    .to_owned()));
+            }
+            let pos = entry & BITMAP_TABLE_POS;
+            if pos == 0 {
+                if entry & BITMAP_TABLE_ALL_ONES != 0 {
+                    add(first, bits_per_cluster * granularity);
+                }
+                continue;
+            }
+            if entry & BITMAP_TABLE_ALL_ONES != 0 {
+                return Err(Error::FileFormat("bitmap data cluster is also all ones".to_owned()));
+            }
+
+            q.io.read_exact_at(pos, &mut data)?;
+            for (byte_idx, &byte) in data.iter().enumerate() {
+                if byte == 0 {
+                    continue;
+                }
+                // Bits are in order from the least significant.
+                for bit in 0..8 {
+                    if byte & (1 << bit) != 0 {
+                        let chunk = byte_idx as u64 * 8 + bit;
+                        add(first + chunk * granularity, granularity);
+                    }
+                }
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Find what to copy for an incremental backup, from the changed parts of a virtual disk.
+    ///
+    /// Each changed range is widened to whole clusters, and split by what the clusters of
+    /// `reader` hold. Adjacent clusters of the same kind are merged, if data clusters are also
+    /// adjacent in the file.
+    pub fn backup_extents<'a, I, C>(&self,
+                                    reader: &'a Reader<'a, I, C>)
+                                    -> Result<BackupExtents<'a, I, C>>
+        where I: 'a + ReadAt,
+              C: CachePolicy
+    {
+        let cluster_size = reader.q.cluster_size();
+        let mut dirty: Vec<GuestRange> = Vec::new();
+        for range in self.dirty_ranges(reader.q)? {
+            if range.offset >= reader.size {
+                break;
+            }
+            let offset = range.offset / cluster_size * cluster_size;
+            let end = min(range.end().div_ceil(cluster_size) * cluster_size, reader.size);
+            match dirty.last_mut() {
+                Some(r) if r.end() >= offset => r.len = end - r.offset,
+                _ => dirty.push(GuestRange { offset, len: end - offset }),
+            }
+        }
+        Ok(BackupExtents {
+            reader,
+            dirty,
+            idx: 0,
+            pos: 0,
+        })
+    }
+}
+
+/// What a range of the virtual disk holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentKind {
+    /// Data at an offset in the image file, or in the external data file if there is one.
+    Data {
+        /// The offset of the start of the data.
+        host_offset: u64,
+    },
+    /// Compressed data, which must be read with a `Reader`.
+    Compressed,
+    /// Zeros, without any data stored.
+    Zero,
+    /// Not allocated in the image, so it reads from the backing file, or as zeros.
+    Unallocated,
+}
+
+/// A range of the virtual disk to copy for an incremental backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupExtent {
+    /// The range of the virtual disk.
+    pub range: GuestRange,
+    /// What the range holds.
+    pub kind: ExtentKind,
+}
+
+/// An iterator over what ranges of the virtual disk hold.
+///
+/// Created by `Bitmap::backup_extents`, for the extents to copy for an incremental backup, or by
+/// `Reader::extents`.
+pub struct BackupExtents<'a, I: 'a + ReadAt, C: CachePolicy = SharedCache> {
+    reader: &'a Reader<'a, I, C>,
+    dirty: Vec<GuestRange>,
+    idx: usize,
+    // The position within the current dirty range.
+    pos: u64,
+}
+
+impl<'a, I, C> BackupExtents<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    fn kind(&self, pos: u64) -> Result<ExtentKind> {
+        let q = self.reader.q;
+        Ok(match q.l2_entry_read(&self.reader.l1, pos)? {
+            L2Entry::Empty => ExtentKind::Unallocated,
+            L2Entry::Zero { .. } |
+            L2Entry::Standard { zero: true, .. } => ExtentKind::Zero,
+            L2Entry::Standard { pos: host_offset, .. } => ExtentKind::Data { host_offset },
+            L2Entry::Compressed { .. } => ExtentKind::Compressed,
+        })
+    }
+
+    fn next_extent(&mut self) -> Result<Option<BackupExtent>> {
+        let range = match self.dirty.get(self.idx) {
+            Some(&r) => r,
+            None => return Ok(None),
+        };
+        let cluster_size = self.reader.q.cluster_size();
+        let offset = range.offset + self.pos;
+        let kind = self.kind(offset)?;
+        let mut len = min(cluster_size, range.end() - offset);
+        while offset + len < range.end() {
+            let next = self.kind(offset + len)?;
+            let same = match (kind, next) {
+                (ExtentKind::Data { host_offset: a }, ExtentKind::Data { host_offset: b }) => {
+                    b == a + len
+                }
+                (ExtentKind::Compressed, ExtentKind::Compressed) |
+                (ExtentKind::Zero, ExtentKind::Zero) |
+                (ExtentKind::Unallocated, ExtentKind::Unallocated) => true,
+                _ => false,
+            };
+            if !same {
+                break;
+            }
+            len += min(cluster_size, range.end() - offset - len);
+        }
+
+        self.pos += len;
+        if self.pos >= range.len {
+            self.idx += 1;
+            self.pos = 0;
+        }
+        Ok(Some(BackupExtent {
+            range: GuestRange { offset, len },
+            kind,
+        }))
+    }
+}
+
+impl<'a, I, C> Iterator for BackupExtents<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    type Item = Result<BackupExtent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_extent() {
+            Ok(e) => e.map(Ok),
+            Err(e) => {
+                self.idx = self.dirty.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    /// Find what a range of the virtual disk holds.
+    ///
+    /// The range is widened to whole clusters, and cut short at the end of the disk. Adjacent
+    /// clusters of the same kind are merged, if data clusters are also adjacent in the file.
+    /// Only this image is looked at, so unallocated ranges may still have data in a backing
+    /// file.
+    pub fn extents(&'a self, range: GuestRange) -> BackupExtents<'a, I, C> {
+        let cluster_size = self.q.cluster_size();
+        let mut dirty = Vec::new();
+        if range.offset < self.size && range.len > 0 {
+            let offset = range.offset / cluster_size * cluster_size;
+            let end = min(range.end().div_ceil(cluster_size) * cluster_size, self.size);
+            dirty.push(GuestRange { offset, len: end - offset });
+        }
+        BackupExtents {
+            reader: self,
+            dirty,
+            idx: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the persistent dirty bitmaps stored in this image.
+    pub fn bitmaps(&self) -> Result<Vec<Bitmap>> {
+        let dir = match self.header().v3.bitmaps.0 {
+            Some(dir) => dir,
+            None => return Ok(Vec::new()),
+        };
+        if dir.nb_bitmaps > MAX_BITMAPS {
+            return Err(Error::FileFormat(format!("too many bitmaps ({})", dir.nb_bitmaps)));
+        }
+        if dir.size > MAX_DIRECTORY_SIZE {
+            return Err(Error::FileFormat("bitmap directory too big".to_owned()));
+        }
+
+        let mut buf = vec![0; dir.size as usize];
+        self.io.read_exact_at(dir.offset, &mut buf).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => {
+                Error::FileFormat("bitmap directory is past the end of the file".to_owned())
+            }
+            _ => e.into(),
+        })?;
+        let mut io: ByteIo<_, BigEndian> = ByteIo::new(Cursor::new(buf));
+        let trusted = self.header().v3.autoclear.enabled(AUTOCLEAR_BITMAPS);
+        let mut ret = Vec::new();
+        for idx in 0..dir.nb_bitmaps {
+            let mut bitmap = Bitmap::read(&mut io, self.cluster_size()).map_err(|e| match e {
+                Error::Io(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    Error::FileFormat("bitmap directory is truncated".to_owned())
+                }
+                Error::FileFormat(msg) => Error::FileFormat(format!("bitmap {}: {}", idx, msg)),
+                e => e,
+            })?;
+            bitmap.consistent &= trusted;
+            ret.push(bitmap);
+        }
+        Ok(ret)
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    // Check that the bitmaps of this image can be modified.
+    fn ensure_bitmaps_writable(&self) -> Result<()> {
+        self.ensure_writable()?;
+        // Without the autoclear bit, something that didn't know about bitmaps wrote to the
+        // image, so the directory can't be trusted.
+        if self.header().v3.bitmaps.0.is_some() &&
+           !self.header().v3.autoclear.enabled(AUTOCLEAR_BITMAPS) {
+            return Err(Error::UnsupportedFeature("modifying bitmaps that may be out of date"
+                .to_owned()));
+        }
+        Ok(())
+    }
+
+    // Get all the bitmaps, and the index of one that can be modified.
+    fn bitmap_for_write(&self, name: &str) -> Result<(Vec<Bitmap>, usize)> {
+        self.ensure_bitmaps_writable()?;
+        let bitmaps = self.bitmaps()?;
+        let idx = bitmaps.iter()
+            .position(|b| b.name == name)
+            .ok_or_else(|| Error::NoBitmap(name.to_owned()))?;
+        if bitmaps[idx].flags & BITMAP_IN_USE != 0 {
+            return Err(Error::UnsupportedFeature(format!("modifying bitmap {}, which is in use",
+                                                         name)));
+        }
+        Ok((bitmaps, idx))
+    }
+
+    // Mark bitmaps with the auto flag as in use, before anything writes to the virtual disk
+    // without updating them.
+    pub(crate) fn bitmaps_mark_in_use(&mut self) -> Result<()> {
+        let offset = match self.header().v3.bitmaps.0 {
+            Some(dir) if self.header().v3.autoclear.enabled(AUTOCLEAR_BITMAPS) => dir.offset,
+            _ => return Ok(()),
+        };
+        let mut bitmaps = self.bitmaps()?;
+        let mut changed = false;
+        for bitmap in bitmaps.iter_mut().filter(|b| b.auto() && !b.in_use()) {
+            bitmap.flags |= BITMAP_IN_USE;
+            changed = true;
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        // Only flags change, so the directory can be rewritten in place.
+        let mut buf = Vec::new();
+        for bitmap in &bitmaps {
+            bitmap.write(&mut buf);
+        }
+        let mut tx = MetaTx::default();
+        tx.write(Stage::L1, offset, buf);
+        self.commit(tx)?;
+        self.sync()
+    }
+
+    // Drop the references a bitmap table holds to data clusters.
+    fn bitmap_free_data(&self, alloc: &mut Allocator, bitmap: &Bitmap) -> Result<()> {
+        for entry in bitmap.table(self)? {
+            let pos = entry & BITMAP_TABLE_POS;
+            if pos != 0 {
+                alloc.decrement(self, pos / self.cluster_size())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replace the bitmap directory, as part of a transaction.
+    //
+    // The new directory is written before the header points at it, and the old one is freed
+    // afterwards. The header is then read back, whether or not writing succeeded, so it always
+    // matches the file.
+    fn bitmaps_commit(&mut self,
+                      mut alloc: Allocator,
+                      mut tx: MetaTx,
+                      bitmaps: &[Bitmap])
+                      -> Result<()> {
+        let cluster_size = self.cluster_size();
+        let mut dir = Vec::new();
+        for bitmap in bitmaps {
+            bitmap.write(&mut dir);
+        }
+        if dir.len() as u64 > MAX_DIRECTORY_SIZE {
+            return Err(Error::UnsupportedFeature("bitmap directory too big".to_owned()));
+        }
+
+        let new = if bitmaps.is_empty() {
+            None
+        } else {
+            let offset = alloc.allocate_clusters(self, (dir.len() as u64).div_ceil(cluster_size))?;
+            let size = dir.len() as u64;
+            tx.write(Stage::L1, offset, dir);
+            Some(BitmapDirectory {
+                nb_bitmaps: bitmaps.len() as u32,
+                size,
+                offset,
+            })
+        };
+        if let Some(old) = self.header().v3.bitmaps.0 {
+            let first = old.offset / cluster_size;
+            for cluster in first..(old.offset + old.size).div_ceil(cluster_size) {
+                alloc.decrement(self, cluster)?;
+            }
+        }
+
+        // Qemu only trusts the directory if the autoclear bit is set.
+        let autoclear = self.header().v3.autoclear.bits();
+        let autoclear = if new.is_some() {
+            autoclear | AUTOCLEAR_BITMAPS
+        } else {
+            autoclear & !AUTOCLEAR_BITMAPS
+        };
+        let v3 = &mut self.header_mut().v3;
+        v3.bitmaps.0 = new;
+        v3.autoclear.set(autoclear);
+        let written = self.header().write().and_then(|buf| {
+            tx.write(Stage::Header, 0, buf);
+            alloc.stage(self, &mut tx);
+            self.commit(tx)
+        });
+        let mut header = Header::default();
+        header.read(&mut self.io, self.header_options)?;
+        *self.header_mut() = header;
+        written
+    }
+
+    /// Add a new persistent dirty bitmap, with no bits set.
+    ///
+    /// Each bit covers `1 << granularity_bits` bytes of the virtual disk. If `enabled`, the
+    /// bitmap has the auto flag, so qemu records writes to the virtual disk in it. This library
+    /// doesn't update bitmaps when writing.
+    pub fn add_bitmap(&mut self, name: &str, granularity_bits: u8, enabled: bool) -> Result<()> {
+        self.ensure_bitmaps_writable()?;
+        let mut bitmaps = self.bitmaps()?;
+        if name.is_empty() || name.len() > MAX_NAME_SIZE as usize {
+            return Err(Error::UnsupportedFeature(format!("bitmap name of {} bytes",
+                                                         name.len())));
+        }
+        if bitmaps.iter().any(|b| b.name == name) {
+            return Err(Error::UnsupportedFeature(format!("bitmap {} already exists", name)));
+        }
+        if !(MIN_GRANULARITY_BITS..=MAX_GRANULARITY_BITS).contains(&granularity_bits) {
+            return Err(Error::UnsupportedFeature(format!("bitmap granularity bits {}",
+                                                         granularity_bits)));
+        }
+        if bitmaps.len() >= MAX_BITMAPS as usize {
+            return Err(Error::UnsupportedFeature("too many bitmaps".to_owned()));
+        }
+        let cluster_size = self.cluster_size();
+        let table_size = self.guest_size()
+            .div_ceil(1 << granularity_bits)
+            .div_ceil(cluster_size * 8);
+        if table_size > MAX_TABLE_SIZE as u64 {
+            return Err(Error::UnsupportedFeature("bitmap table too big".to_owned()));
+        }
+
+        let mut bitmap = Bitmap {
+            name: name.to_owned(),
+            flags: if enabled { BITMAP_AUTO } else { 0 },
+            bitmap_type: BITMAP_TYPE_DIRTY,
+            granularity_bits,
+            table_offset: 0,
+            table_size: table_size as u32,
+            extra_data: Vec::new(),
+            consistent: true,
+        };
+        let mut alloc = Allocator::new(self)?;
+        let mut tx = MetaTx::default();
+        let clusters = bitmap.table_clusters(cluster_size);
+        if clusters > 0 {
+            bitmap.table_offset = alloc.allocate_clusters(self, clusters)?;
+            tx.write_zeroes(Stage::L2, bitmap.table_offset, clusters * cluster_size);
+        }
+        bitmaps.push(bitmap);
+        self.bitmaps_commit(alloc, tx, &bitmaps)
+    }
+
+    /// Clear every bit of a persistent dirty bitmap, such as after a backup.
+    ///
+    /// Bitmaps that are in use by qemu can't be modified.
+    pub fn clear_bitmap(&mut self, name: &str) -> Result<()> {
+        let (bitmaps, idx) = self.bitmap_for_write(name)?;
+        let bitmap = &bitmaps[idx];
+        if bitmap.bitmap_type != BITMAP_TYPE_DIRTY {
+            return Err(Error::UnsupportedFeature(format!("bitmap type {}", bitmap.bitmap_type)));
+        }
+        let mut alloc = Allocator::new(self)?;
+        let mut tx = MetaTx::default();
+        self.bitmap_free_data(&mut alloc, bitmap)?;
+        tx.write_zeroes(Stage::L2,
+                        bitmap.table_offset,
+                        bitmap.table_size as u64 * size_of::<u64>() as u64);
+        alloc.stage(self, &mut tx);
+        self.commit(tx)
+    }
+
+    /// Remove a persistent dirty bitmap, and free the space it used.
+    ///
+    /// Bitmaps that are in use by qemu can't be removed. Removing the last bitmap also removes
+    /// the bitmaps extension.
+    pub fn remove_bitmap(&mut self, name: &str) -> Result<()> {
+        let (mut bitmaps, idx) = self.bitmap_for_write(name)?;
+        let bitmap = bitmaps.remove(idx);
+        let mut alloc = Allocator::new(self)?;
+        self.bitmap_free_data(&mut alloc, &bitmap)?;
+        let first = bitmap.table_offset / self.cluster_size();
+        for cluster in first..first + bitmap.table_clusters(self.cluster_size()) {
+            alloc.decrement(self, cluster)?;
+        }
+        self.bitmaps_commit(alloc, MetaTx::default(), &bitmaps)
+    }
+}
@@ -0,0 +1,119 @@
+use std::io;
+
+use positioned_io::{ReadAt, Size};
+
+use super::{OwnedReader, Qcow2, Result};
+
+
+/// Storage that can be read at any position, and knows its size.
+///
+/// This is implemented for everything that implements both `ReadAt` and `Size`, so it can be
+/// used as a trait object, see `DynBackend`.
+pub trait ReadAtSize: ReadAt + Size {}
+
+impl<T: ReadAt + Size + ?Sized> ReadAtSize for T {}
+
+/// Storage of any type, boxed up.
+///
+/// This lets images from different sources share one type, such as to keep them in a single
+/// collection.
+///
+/// # Examples
+///
+/// ```
+/// use qcow2::{DynBackend, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let file = std::fs::File::open("tests/test.qcow2")?;
+/// let bytes = std::fs::read("tests/test.qcow2")?;
+/// let images: Vec<Qcow2<DynBackend>> = vec![
+///     Qcow2::open_dyn(Box::new(file))?,
+///     Qcow2::open_dyn(Box::new(bytes))?,
+/// ];
+/// assert_eq!(images[0].guest_size(), images[1].guest_size());
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub type DynBackend = Box<dyn ReadAtSize + Send + Sync>;
+
+impl ReadAt for DynBackend {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(pos, buf)
+    }
+}
+
+impl Size for DynBackend {
+    fn size(&self) -> io::Result<Option<u64>> {
+        (**self).size()
+    }
+}
+
+/// A reader of a virtual disk, which knows how it likes to be read.
+///
+/// This is implemented by `OwnedReader`, so it can be used as a trait object, see `DynReader`.
+pub trait GuestReader: ReadAt + Size {
+    /// Get the preferred granularity of reads, in bytes, such as for picking how far to read
+    /// ahead.
+    ///
+    /// For a qcow2 image, this is the cluster size.
+    fn io_granularity(&self) -> u64;
+}
+
+impl<I: ReadAt> GuestReader for OwnedReader<I> {
+    fn io_granularity(&self) -> u64 {
+        self.qcow2().cluster_size()
+    }
+}
+
+/// A reader of any type, boxed up.
+///
+/// This gives consumers such as filesystems one type to hold, whatever the storage of the image
+/// or which snapshot is being read. Dropping it drops the image and its storage.
+///
+/// # Examples
+///
+/// ```
+/// use positioned_io::{ReadAt, Size};
+/// use qcow2::{DynReader, GuestReader, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let reader: DynReader = qcow.into_dyn_reader()?;
+/// assert_eq!(reader.io_granularity(), 65536);
+/// assert_eq!(reader.size()?, Some(1000 << 20));
+/// let mut buf = [0; 512];
+/// reader.read_exact_at(0, &mut buf)?;
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub type DynReader = Box<dyn GuestReader + Send + Sync>;
+
+impl ReadAt for DynReader {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(pos, buf)
+    }
+}
+
+impl Size for DynReader {
+    fn size(&self) -> io::Result<Option<u64>> {
+        (**self).size()
+    }
+}
+
+impl<I> Qcow2<I>
+    where I: ReadAt + Send + Sync + 'static
+{
+    /// Turn this image into a boxed reader of its main virtual disk.
+    ///
+    /// To read a snapshot instead, box the result of `into_snapshot_reader`.
+    pub fn into_dyn_reader(self) -> Result<DynReader> {
+        Ok(Box::new(self.into_reader()?))
+    }
+}
+
+impl Qcow2<DynBackend> {
+    /// Open a qcow2 image from boxed storage.
+    ///
+    /// This is the same as `Qcow2::open`, but saves spelling out the type of the box.
+    pub fn open_dyn(io: DynBackend) -> Result<Self> {
+        Self::open(io)
+    }
+}
@@ -0,0 +1,652 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+use std::result;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+
+/// The default memory limit of `LruMetadataCache`, in bytes.
+///
+/// This is enough for 32 L2 tables of an image with the default cluster size.
+pub const DEFAULT_CACHE_SIZE: usize = 2 << 20;
+
+/// The identity of an image, for the purposes of caching.
+///
+/// A single cache may be shared by many images, so cache entries are keyed by image as well as
+/// by offset. By default every opened image gets a unique identity. Images known to have
+/// identical contents, such as one base image opened read-only several times, can be given the
+/// same user-chosen identity so they share cache entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageId(IdKind);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum IdKind {
+    Unique(u64),
+    User(u64),
+}
+
+impl ImageId {
+    /// Create a new identity, distinct from every other.
+    pub fn unique() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ImageId(IdKind::Unique(NEXT.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Create an identity chosen by the user.
+    ///
+    /// All images with the same user identity share cache entries, so they must have identical
+    /// metadata. User identities never collide with unique ones.
+    pub fn user(id: u64) -> Self {
+        ImageId(IdKind::User(id))
+    }
+}
+
+/// The key of a cached metadata table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// The image the table belongs to.
+    pub image: ImageId,
+    /// The offset of the table in the image file.
+    pub offset: u64,
+}
+
+/// How much a cache is holding.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of cached tables.
+    pub entries: usize,
+    /// The memory used by cached tables, in bytes.
+    pub used: usize,
+    /// The most memory the cache will use, in bytes.
+    pub limit: usize,
+}
+
+/// A cache of qcow2 metadata.
+///
+/// Reading guest data requires looking up L2 tables, which would otherwise mean extra reads of
+/// the underlying file. A cache keeps recently used L2 tables in memory.
+///
+/// Whole tables are cached, so finding that a cluster is unallocated costs no reads either, as
+/// long as its table stays cached. Clusters without any L2 table are found to be unallocated
+/// from the L1 table, which a reader always keeps in memory.
+///
+/// Tables are stored as decoded entries. A cache may be shared between images, see `ImageId`.
+/// Implementations must be safe to use from multiple threads, and may drop entries whenever
+/// they like.
+pub trait MetadataCache: Send + Sync {
+    /// Look up an L2 table.
+    fn get_l2(&self, key: CacheKey) -> Option<Arc<[u64]>>;
+
+    /// Store an L2 table in the cache.
+    fn put_l2(&self, key: CacheKey, table: Arc<[u64]>);
+
+    /// Remove an L2 table from the cache, if it's present.
+    fn invalidate(&self, key: CacheKey);
+
+    /// Remove every table belonging to an image from the cache.
+    fn invalidate_image(&self, image: ImageId);
+
+    /// Report how much the cache is holding.
+    ///
+    /// Caches that don't keep track report nothing.
+    fn stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+}
+
+// The memory used by a cached table.
+fn table_size(table: &[u64]) -> usize {
+    mem::size_of_val(table)
+}
+
+/// A metadata cache that keeps recently used L2 tables, up to a memory limit.
+///
+/// This is the cache used by default. The limit is in bytes rather than tables, since tables
+/// of images with large clusters are much bigger. When shared between several images, the limit
+/// applies to all of them together.
+///
+/// Tables are spread over several shards, each with its own lock, and lookups only take a
+/// shared lock on one shard, so many threads can read at once without all touching the same
+/// lock. Instead of strict LRU order, eviction goes round the shards, and gives tables used
+/// since they were last considered a second chance.
+pub struct LruMetadataCache {
+    shards: Box<[Shard]>,
+    // The memory used by all the shards together.
+    used: AtomicUsize,
+    // The shard to evict from next.
+    hand: AtomicUsize,
+    limit: usize,
+}
+
+// The number of shards of an `LruMetadataCache` is `1 << SHARD_BITS`.
+const SHARD_BITS: u32 = 4;
+
+// Each shard gets its own cache line, so locking one doesn't slow down the others.
+#[repr(align(64))]
+#[derive(Default)]
+struct Shard(RwLock<LruInner>);
+
+struct LruEntry {
+    table: Arc<[u64]>,
+    // Set on every lookup, and cleared when eviction passes over the table.
+    used: AtomicBool,
+    // Tells this entry apart from older ones with the same key.
+    seq: u64,
+}
+
+#[derive(Default)]
+struct LruInner {
+    tables: HashMap<CacheKey, LruEntry>,
+    // Tables in the order eviction considers them. Removed tables leave stale entries behind,
+    // which are skipped.
+    queue: VecDeque<(CacheKey, u64)>,
+    next_seq: u64,
+    used: usize,
+}
+
+impl LruInner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.tables.remove(key) {
+            self.used -= table_size(&entry.table);
+        }
+    }
+
+    // Evict one table other than `keep`, returning false if there are none.
+    fn evict(&mut self, keep: Option<CacheKey>) -> bool {
+        let mut kept = None;
+        let evicted = loop {
+            let Some((key, seq)) = self.queue.pop_front() else {
+                break false;
+            };
+            match self.tables.get(&key) {
+                Some(entry) if entry.seq == seq => {
+                    if Some(key) == keep {
+                        kept = Some((key, seq));
+                    } else if entry.used.swap(false, Ordering::Relaxed) {
+                        self.queue.push_back((key, seq));
+                    } else {
+                        self.remove(&key);
+                        break true;
+                    }
+                }
+                _ => {}
+            }
+        };
+        self.queue.extend(kept);
+        evicted
+    }
+
+    fn insert(&mut self, key: CacheKey, table: Arc<[u64]>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.used += table_size(&table);
+        self.tables.insert(key, LruEntry { table, used: AtomicBool::new(false), seq });
+        self.queue.push_back((key, seq));
+
+        // Don't let stale entries pile up if tables are often invalidated.
+        if self.queue.len() > 2 * self.tables.len() + 16 {
+            let tables = &self.tables;
+            self.queue.retain(|(k, seq)| tables.get(k).is_some_and(|e| e.seq == *seq));
+        }
+    }
+
+    // Store a table, evicting others to stay within `limit` bytes.
+    fn put(&mut self, key: CacheKey, table: Arc<[u64]>, limit: usize) {
+        self.remove(&key);
+        // Evicting everything else wouldn't make room for a table bigger than the whole limit.
+        let size = table_size(&table);
+        if size > limit {
+            return;
+        }
+        while self.used + size > limit && self.evict(None) {}
+        self.insert(key, table);
+    }
+
+    fn remove_image(&mut self, image: ImageId) {
+        let keys: Vec<_> = self.tables.keys().copied().filter(|k| k.image == image).collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+impl Shard {
+    // A panic while holding the lock can't leave the cache inconsistent, so ignore poisoning.
+    fn read(&self) -> RwLockReadGuard<'_, LruInner> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, LruInner> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LruMetadataCache {
+    /// Create a cache using up to `bytes` bytes of memory.
+    pub fn new(bytes: usize) -> Self {
+        LruMetadataCache {
+            shards: (0..1 << SHARD_BITS).map(|_| Shard::default()).collect(),
+            used: AtomicUsize::new(0),
+            hand: AtomicUsize::new(0),
+            limit: bytes,
+        }
+    }
+
+    // Find the shard holding a table. Tables are at least 512 bytes apart, and a multiplicative
+    // hash spreads their offsets over the shards.
+    fn shard(&self, key: &CacheKey) -> &Shard {
+        let hash = (key.offset >> 9).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(hash >> (64 - SHARD_BITS)) as usize]
+    }
+
+    // Change a shard, keeping track of the memory used by all of them.
+    fn modify<R, F>(&self, shard: &Shard, f: F) -> R
+        where F: FnOnce(&mut LruInner) -> R
+    {
+        let mut inner = shard.write();
+        let before = inner.used;
+        let ret = f(&mut inner);
+        if inner.used > before {
+            self.used.fetch_add(inner.used - before, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(before - inner.used, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    // Evict one table other than `keep` from any shard, going round them in turn. Returns false
+    // if there are none.
+    fn evict(&self, keep: CacheKey) -> bool {
+        (0..self.shards.len()).any(|_| {
+            let i = self.hand.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+            self.modify(&self.shards[i], |inner| inner.evict(Some(keep)))
+        })
+    }
+}
+
+impl Default for LruMetadataCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl MetadataCache for LruMetadataCache {
+    fn get_l2(&self, key: CacheKey) -> Option<Arc<[u64]>> {
+        let inner = self.shard(&key).read();
+        let entry = inner.tables.get(&key)?;
+        // Only write the flag when it changes, so threads using the same table don't fight over
+        // its cache line.
+        if !entry.used.load(Ordering::Relaxed) {
+            entry.used.store(true, Ordering::Relaxed);
+        }
+        Some(entry.table.clone())
+    }
+    fn put_l2(&self, key: CacheKey, table: Arc<[u64]>) {
+        // Evicting everything else wouldn't make room for a table bigger than the whole limit.
+        if table_size(&table) > self.limit {
+            self.invalidate(key);
+            return;
+        }
+        self.modify(self.shard(&key), |inner| {
+            inner.remove(&key);
+            inner.insert(key, table);
+        });
+        // Other shards are only locked once this one isn't, so shards never wait for each other.
+        while self.used.load(Ordering::Relaxed) > self.limit && self.evict(key) {}
+    }
+    fn invalidate(&self, key: CacheKey) {
+        self.modify(self.shard(&key), |inner| inner.remove(&key));
+    }
+    fn invalidate_image(&self, image: ImageId) {
+        for shard in self.shards.iter() {
+            self.modify(shard, |inner| inner.remove_image(image));
+        }
+    }
+    fn stats(&self) -> CacheStats {
+        let entries = self.shards.iter().map(|shard| shard.read().tables.len()).sum();
+        CacheStats { entries, used: self.used.load(Ordering::Relaxed), limit: self.limit }
+    }
+}
+
+impl Debug for LruMetadataCache {
+    fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
+        let stats = self.stats();
+        f.debug_struct("LruMetadataCache")
+            .field("tables", &stats.entries)
+            .field("used", &stats.used)
+            .field("limit", &stats.limit)
+            .finish()
+    }
+}
+
+// An L2 table being loaded, which other threads can wait for.
+#[derive(Default)]
+struct Load {
+    // Set once the load is done, to the table if it succeeded.
+    table: Mutex<Option<Option<Arc<[u64]>>>>,
+    done: Condvar,
+    // A tombstone, set if the table is invalidated while it's loaded, so what was read isn't
+    // cached. Only changed with the loads locked.
+    stale: AtomicBool,
+}
+
+/// Tracks L2 tables being loaded, so concurrent misses of one table only read it once.
+#[derive(Default)]
+struct L2Loads {
+    loads: Mutex<HashMap<u64, Arc<Load>>>,
+    // The number of loads that have finished.
+    finished: AtomicU64,
+    // How many lookups found a cached table, and how many read one, for logging. They're only
+    // counted with the `logging` feature, so hits don't all update one shared counter.
+    #[cfg(feature = "logging")]
+    hits: AtomicU64,
+    #[cfg(feature = "logging")]
+    misses: AtomicU64,
+}
+
+// Finishes a load even if the loading thread panics, so waiting threads don't hang.
+struct LoadGuard<'a> {
+    loads: &'a L2Loads,
+    offset: u64,
+    load: Arc<Load>,
+    table: Option<Arc<[u64]>>,
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        *self.load.table.lock().unwrap_or_else(|e| e.into_inner()) = Some(self.table.take());
+        self.load.done.notify_all();
+        self.loads.finished.fetch_add(1, Ordering::Release);
+        self.loads.lock().remove(&self.offset);
+    }
+}
+
+impl L2Loads {
+    // Get the number of cache hits and misses.
+    #[cfg(feature = "logging")]
+    fn counts(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+    #[cfg(not(feature = "logging"))]
+    fn counts(&self) -> (u64, u64) {
+        (0, 0)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Arc<Load>>> {
+        self.loads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the L2 table at `offset`, using `cached` to look in the cache, `load` to read it and
+    /// `store` to put what was read in the cache.
+    ///
+    /// If another thread is already loading the table, wait for it instead of reading again.
+    /// A hit only takes a plain load besides looking in the cache. Tables invalidated while
+    /// they're read aren't stored.
+    fn get<E, C, F, S>(&self,
+                       offset: u64,
+                       cached: C,
+                       load: F,
+                       store: S)
+                       -> result::Result<Arc<[u64]>, E>
+        where C: Fn() -> Option<Arc<[u64]>>,
+              F: Fn() -> result::Result<Arc<[u64]>, E>,
+              S: FnOnce(Arc<[u64]>)
+    {
+        // Acquiring keeps the cache lookup from happening first, which could miss a table whose
+        // load is counted already.
+        let finished = self.finished.load(Ordering::Acquire);
+        if let Some(table) = cached() {
+            #[cfg(feature = "logging")]
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(table);
+        }
+        let (pending, leader) = {
+            let mut loads = self.lock();
+            match loads.get(&offset) {
+                Some(pending) => (pending.clone(), false),
+                None => {
+                    let pending = Arc::new(Load::default());
+                    loads.insert(offset, pending.clone());
+                    (pending, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut table = pending.table.lock().unwrap_or_else(|e| e.into_inner());
+            while table.is_none() {
+                table = pending.done.wait(table).unwrap_or_else(|e| e.into_inner());
+            }
+            return match *table {
+                Some(Some(ref table)) => Ok(table.clone()),
+                // The other load failed, try again to get our own error.
+                _ => load(),
+            };
+        }
+
+        let mut guard = LoadGuard { loads: self, offset, load: pending, table: None };
+        // Another load may have finished since the cache lookup, and cached the table.
+        let again = if self.finished.load(Ordering::Acquire) != finished { cached() } else { None };
+        let table = match again {
+            Some(table) => table,
+            None => {
+                #[cfg(feature = "logging")]
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let table = load()?;
+                // With the loads locked, no tombstone can be left between checking and storing.
+                let loads = self.lock();
+                if !guard.load.stale.load(Ordering::Relaxed) {
+                    store(table.clone());
+                }
+                drop(loads);
+                table
+            }
+        };
+        guard.table = Some(table.clone());
+        Ok(table)
+    }
+
+    // Invalidate cached tables with `invalidate`, leaving a tombstone on the loads of those at
+    // `offset`, or of every table if it's `None`, so they aren't cached afterwards.
+    fn invalidate<F: FnOnce()>(&self, offset: Option<u64>, invalidate: F) {
+        let loads = self.lock();
+        for (&at, load) in loads.iter() {
+            if offset.is_none_or(|offset| offset == at) {
+                load.stale.store(true, Ordering::Relaxed);
+            }
+        }
+        invalidate();
+        drop(loads);
+    }
+}
+
+/// A metadata cache that never stores anything.
+///
+/// Every lookup reads metadata from the image, but no memory is used for caching.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoMetadataCache;
+
+impl MetadataCache for NoMetadataCache {
+    fn get_l2(&self, _key: CacheKey) -> Option<Arc<[u64]>> {
+        None
+    }
+    fn put_l2(&self, _key: CacheKey, _table: Arc<[u64]>) {}
+    fn invalidate(&self, _key: CacheKey) {}
+    fn invalidate_image(&self, _image: ImageId) {}
+}
+
+/// How an image caches its metadata, chosen by the type of the image.
+///
+/// A `Qcow2` uses a `SharedCache` by default, so it can be read from many threads at once. A
+/// `Qcow2Local` uses a `LocalCache`, which is faster but can't be shared between threads. There
+/// are no other policies, this trait can't be implemented outside this crate.
+pub trait CachePolicy: private::Policy {}
+
+pub(crate) mod private {
+    use std::result;
+    use std::sync::Arc;
+
+    use super::{CacheKey, ImageId};
+
+    pub trait Policy {
+        // Get an L2 table, using `load` to read it if it's not cached.
+        fn l2_table<E, F>(&self, key: CacheKey, load: F) -> result::Result<Arc<[u64]>, E>
+            where F: Fn() -> result::Result<Arc<[u64]>, E>;
+
+        // Look at an L2 table without keeping it, using `load` to read it if it's not cached.
+        fn with_l2_table<R, E, F, G>(&self,
+                                     key: CacheKey,
+                                     load: F,
+                                     f: G)
+                                     -> result::Result<R, E>
+            where F: Fn() -> result::Result<Arc<[u64]>, E>,
+                  G: FnOnce(&[u64]) -> R
+        {
+            self.l2_table(key, load).map(|table| f(&table))
+        }
+
+        fn contains(&self, key: CacheKey) -> bool;
+        fn put_l2(&self, key: CacheKey, table: Arc<[u64]>);
+        fn invalidate(&self, key: CacheKey);
+        fn invalidate_image(&self, image: ImageId);
+        // Get the number of cache hits and misses.
+        fn counts(&self) -> (u64, u64);
+    }
+}
+
+/// Caching that can be shared between threads and images.
+///
+/// This is the default policy of a `Qcow2`. Tables are kept in a `MetadataCache`, see
+/// `OpenOptions::cache`, and when several threads miss the same table it's only read once.
+#[derive(Clone)]
+pub struct SharedCache {
+    cache: Arc<dyn MetadataCache>,
+    loads: Arc<L2Loads>,
+}
+
+impl SharedCache {
+    pub(crate) fn new(cache: Arc<dyn MetadataCache>) -> Self {
+        SharedCache { cache, loads: Default::default() }
+    }
+}
+
+impl CachePolicy for SharedCache {}
+
+impl private::Policy for SharedCache {
+    fn l2_table<E, F>(&self, key: CacheKey, load: F) -> result::Result<Arc<[u64]>, E>
+        where F: Fn() -> result::Result<Arc<[u64]>, E>
+    {
+        self.loads.get(key.offset,
+                       || self.cache.get_l2(key),
+                       load,
+                       |table| self.cache.put_l2(key, table))
+    }
+    fn contains(&self, key: CacheKey) -> bool {
+        self.cache.get_l2(key).is_some()
+    }
+    fn put_l2(&self, key: CacheKey, table: Arc<[u64]>) {
+        self.cache.put_l2(key, table);
+    }
+    fn invalidate(&self, key: CacheKey) {
+        self.loads.invalidate(Some(key.offset), || self.cache.invalidate(key));
+    }
+    fn invalidate_image(&self, image: ImageId) {
+        self.loads.invalidate(None, || self.cache.invalidate_image(image));
+    }
+    fn counts(&self) -> (u64, u64) {
+        self.loads.counts()
+    }
+}
+
+/// Caching for an image used from only one thread.
+///
+/// This is the policy of a `Qcow2Local`. Lookups take no locks and need no atomic
+/// read-modify-write operations, which adds up when looking at millions of clusters. Tables are
+/// evicted like in an `LruMetadataCache`, and each handle of an image has its own cache, which
+/// starts out empty.
+pub struct LocalCache {
+    inner: RefCell<LruInner>,
+    limit: usize,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl LocalCache {
+    pub(crate) fn new(bytes: usize) -> Self {
+        LocalCache {
+            inner: RefCell::new(LruInner::default()),
+            limit: bytes,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    // Find a cached table, marking it used. Only the owning thread touches the flag, so the
+    // relaxed store is as cheap as a plain one.
+    fn lookup<R, G>(&self, key: CacheKey, f: G) -> result::Result<R, G>
+        where G: FnOnce(&Arc<[u64]>) -> R
+    {
+        let inner = self.inner.borrow();
+        match inner.tables.get(&key) {
+            Some(entry) => {
+                entry.used.store(true, Ordering::Relaxed);
+                self.hits.set(self.hits.get() + 1);
+                Ok(f(&entry.table))
+            }
+            None => Err(f),
+        }
+    }
+
+    fn load<E, F>(&self, key: CacheKey, load: F) -> result::Result<Arc<[u64]>, E>
+        where F: Fn() -> result::Result<Arc<[u64]>, E>
+    {
+        self.misses.set(self.misses.get() + 1);
+        let table = load()?;
+        self.inner.borrow_mut().put(key, table.clone(), self.limit);
+        Ok(table)
+    }
+}
+
+impl Clone for LocalCache {
+    fn clone(&self) -> Self {
+        LocalCache::new(self.limit)
+    }
+}
+
+impl CachePolicy for LocalCache {}
+
+impl private::Policy for LocalCache {
+    fn l2_table<E, F>(&self, key: CacheKey, load: F) -> result::Result<Arc<[u64]>, E>
+        where F: Fn() -> result::Result<Arc<[u64]>, E>
+    {
+        match self.lookup(key, Arc::clone) {
+            Ok(table) => Ok(table),
+            Err(_) => self.load(key, load),
+        }
+    }
+    fn with_l2_table<R, E, F, G>(&self, key: CacheKey, load: F, f: G) -> result::Result<R, E>
+        where F: Fn() -> result::Result<Arc<[u64]>, E>,
+              G: FnOnce(&[u64]) -> R
+    {
+        match self.lookup(key, |table: &Arc<[u64]>| f(table)) {
+            Ok(r) => Ok(r),
+            Err(f) => self.load(key, load).map(|table| f(&table)),
+        }
+    }
+    fn contains(&self, key: CacheKey) -> bool {
+        self.inner.borrow().tables.contains_key(&key)
+    }
+    fn put_l2(&self, key: CacheKey, table: Arc<[u64]>) {
+        self.inner.borrow_mut().put(key, table, self.limit);
+    }
+    fn invalidate(&self, key: CacheKey) {
+        self.inner.borrow_mut().remove(&key);
+    }
+    fn invalidate_image(&self, image: ImageId) {
+        self.inner.borrow_mut().remove_image(image);
+    }
+    fn counts(&self) -> (u64, u64) {
+        (self.hits.get(), self.misses.get())
+    }
+}
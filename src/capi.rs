@@ -0,0 +1,156 @@
+//! A C API for reading qcow2 images.
+//!
+//! This is available with the `capi` feature. To build a C library, use something like:
+//!
+//! ```sh
+//! cargo rustc --release --lib --features capi --crate-type cdylib
+//! ```
+//!
+//! The matching header is `include/qcow2.h`, generated with `cbindgen`.
+//!
+//! # Thread safety
+//!
+//! A `qcow2_t` handle may be used by several threads at once: `qcow2_pread` and
+//! `qcow2_virtual_size` only need shared access, and the internal cache is protected by a mutex.
+//! `qcow2_close` must not be called while any other thread is still using the handle.
+//!
+//! Error messages are kept per-thread, so `qcow2_last_error` always describes the most recent
+//! failure on the calling thread.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use byteorder::BigEndian;
+use positioned_io::ByteIo;
+
+use super::{Error, Qcow2, Result};
+
+
+/// An open qcow2 image. Known as `qcow2_t` in C.
+pub struct Handle {
+    q: Qcow2<File>,
+    l1: ByteIo<Vec<u8>, BigEndian>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(msg: String) {
+    // Interior NULs can't be represented, so drop them.
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+// Run a function, turning errors and panics into a return value.
+fn guard<T, F: FnOnce() -> Result<T>>(fail: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(t)) => t,
+        Ok(Err(e)) => {
+            set_error(e.to_string());
+            fail
+        }
+        Err(_) => {
+            set_error("internal panic".to_owned());
+            fail
+        }
+    }
+}
+
+/// Open a qcow2 image.
+///
+/// On success, stores a new handle in `out` and returns zero. On failure, returns -1, and the
+/// error is available from `qcow2_last_error`.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string, and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn qcow2_open(path: *const c_char, out: *mut *mut Handle) -> c_int {
+    guard(-1, || {
+        if path.is_null() || out.is_null() {
+            return Err(Error::Internal("null argument".to_owned()));
+        }
+        let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+        let q = Qcow2::open(File::open(path)?)?;
+        q.ensure_readable()?;
+        let l1 = ByteIo::new(q.l1_read(q.header().c.l1_table_offset, q.header().l1_entries())?);
+        *out = Box::into_raw(Box::new(Handle { q, l1 }));
+        Ok(0)
+    })
+}
+
+/// Read guest data from an image.
+///
+/// Reads up to `len` bytes at guest offset `off` into `buf`. Returns the number of bytes read,
+/// which is less than `len` only at the end of the virtual disk. On failure, returns -1, and
+/// the error is available from `qcow2_last_error`.
+///
+/// # Safety
+///
+/// `handle` must be a handle from `qcow2_open` that hasn't been closed, and `buf` must be
+/// valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn qcow2_pread(handle: *const Handle,
+                                     buf: *mut c_void,
+                                     len: usize,
+                                     off: u64)
+                                     -> i64 {
+    guard(-1, || {
+        if handle.is_null() || (buf.is_null() && len > 0) {
+            return Err(Error::Internal("null argument".to_owned()));
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let h = &*handle;
+        let buf = slice::from_raw_parts_mut(buf as *mut u8, len);
+        let n = h.q.guest_read(&h.l1, h.q.guest_size(), off, buf)?;
+        Ok(n as i64)
+    })
+}
+
+/// Get the size of the virtual disk of an image.
+///
+/// # Safety
+///
+/// `handle` must be a handle from `qcow2_open` that hasn't been closed.
+#[no_mangle]
+pub unsafe extern "C" fn qcow2_virtual_size(handle: *const Handle) -> u64 {
+    guard(0, || {
+        if handle.is_null() {
+            return Err(Error::Internal("null argument".to_owned()));
+        }
+        Ok((*handle).q.guest_size())
+    })
+}
+
+/// Close an image, releasing its resources. Passing NULL does nothing.
+///
+/// # Safety
+///
+/// `handle` must be NULL or a handle from `qcow2_open` that hasn't been closed. It must not be
+/// used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn qcow2_close(handle: *mut Handle) {
+    if !handle.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle))));
+    }
+}
+
+/// Get a description of the last error that occurred on this thread.
+///
+/// Returns NULL if there has been no error. The string remains valid until the next failing
+/// call on this thread.
+#[no_mangle]
+pub extern "C" fn qcow2_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match *e.borrow() {
+        Some(ref s) => s.as_ptr(),
+        None => ptr::null(),
+    })
+}
@@ -0,0 +1,134 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use super::{Error, Qcow2, Result};
+use super::probe::probe;
+
+
+/// One image in a backing chain, found by `backing_chain`.
+///
+/// With the `serde` feature, this can be serialized. The path is represented as a string, with
+/// any invalid UTF-8 replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChainLayer {
+    /// Where the image was looked for.
+    #[cfg_attr(feature = "serde", serde(with = "crate::info::lossy_path::required"))]
+    pub path: PathBuf,
+    /// The format of the image, such as `qcow2` or `raw`, if it's known.
+    ///
+    /// This is the format recorded by the image above, or else the format the file looks like.
+    pub format: Option<String>,
+    /// The size of the virtual disk, if it could be found.
+    pub virtual_size: Option<u64>,
+    /// The number of bytes of guest data allocated in this image, if it could be found.
+    ///
+    /// For raw images, this is the size of the file.
+    pub allocated_size: Option<u64>,
+    /// Why the image couldn't be opened, if it couldn't.
+    pub error: Option<String>,
+    /// Anything wrong with how this image fits into the chain.
+    pub problems: Vec<String>,
+}
+
+/// Find every image in the backing chain of an image file, starting with the image itself.
+///
+/// Backing file names are resolved the way qemu does, relative to the directory of the image
+/// that names them. The chain continues until an image has no backing file, or can't be opened.
+/// Problems don't stop the search, they're recorded in the layers instead. These include a
+/// missing backing file, one whose virtual disk is smaller than the image above it, and a
+/// backing file that's already in the chain.
+///
+/// # Examples
+///
+/// ```
+/// let chain = qcow2::backing_chain("tests/test.qcow2");
+/// assert_eq!(chain.len(), 1);
+/// assert_eq!(chain[0].format.as_deref(), Some("qcow2"));
+/// assert!(chain[0].error.is_none());
+/// ```
+pub fn backing_chain<P: AsRef<Path>>(path: P) -> Vec<ChainLayer> {
+    let mut chain: Vec<ChainLayer> = Vec::new();
+    let mut seen = Vec::new();
+    let mut next = Some((path.as_ref().to_owned(), None));
+    while let Some((path, format)) = next.take() {
+        // Compare canonical paths, so the same file reached another way is still found.
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if seen.contains(&canonical) {
+            chain.last_mut().unwrap().problems
+                .push(format!("backing file {} is already in the chain", path.display()));
+            break;
+        }
+        seen.push(canonical);
+
+        let (mut layer, backing) = chain_layer(path, format);
+        if let Some(above) = chain.last() {
+            if let (Some(size), Some(above_size)) = (layer.virtual_size, above.virtual_size) {
+                if size < above_size {
+                    layer.problems.push(format!("virtual size {} is smaller than the {} of \
+                                                 the image above",
+                                                size,
+                                                above_size));
+                }
+            }
+        }
+        next = backing.map(|(name, format)| (resolve(&layer.path, &name), format));
+        chain.push(layer);
+    }
+    chain
+}
+
+// Find a backing file name relative to the image naming it.
+fn resolve(image: &Path, name: &Path) -> PathBuf {
+    image.parent().unwrap_or(Path::new("")).join(name)
+}
+
+// Describe one image, given the format the image above says it has. Also returns the name and
+// format of its backing file, if it has one.
+fn chain_layer(path: PathBuf,
+               format: Option<String>)
+               -> (ChainLayer, Option<(PathBuf, Option<String>)>) {
+    let mut layer = ChainLayer {
+        path,
+        format,
+        virtual_size: None,
+        allocated_size: None,
+        error: None,
+        problems: Vec::new(),
+    };
+    let backing = match open_layer(&mut layer) {
+        Ok(backing) => backing,
+        Err(e) => {
+            layer.error = Some(e.to_string());
+            None
+        }
+    };
+    (layer, backing)
+}
+
+fn open_layer(layer: &mut ChainLayer) -> Result<Option<(PathBuf, Option<String>)>> {
+    let file = File::open(&layer.path)?;
+    let found = probe(&file)?;
+    let format = layer.format
+        .get_or_insert_with(|| if found.is_qcow2 { "qcow2" } else { "raw" }.to_owned());
+    match format.as_str() {
+        "qcow2" => {}
+        "raw" => {
+            let len = file.metadata()?.len();
+            layer.virtual_size = Some(len);
+            layer.allocated_size = Some(len);
+            return Ok(None);
+        }
+        other => return Err(Error::UnsupportedFeature(format!("{} format", other))),
+    }
+    if found.is_qcow2 {
+        // Images that can't be opened still say how big they are.
+        layer.virtual_size = Some(found.virtual_size);
+    }
+
+    let q = Qcow2::open(file)?;
+    let info = q.info()?;
+    layer.virtual_size = Some(info.virtual_size);
+    layer.allocated_size = Some(info.allocated_size);
+    Ok(info.backing_file.map(|name| (name, info.backing_format)))
+}
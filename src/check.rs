@@ -0,0 +1,357 @@
+use std::cmp::max;
+use std::fmt::{self, Display, Formatter};
+use std::mem::size_of;
+use std::ops::ControlFlow;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::{ReadAt, Size};
+
+use super::{CachePolicy, Error, Phase, Progress, Qcow2, Result};
+use super::bitmap::{BITMAP_TABLE_POS, BITMAP_TABLE_RESERVED};
+use super::int::to_usize;
+use super::progress::{REPORT_INTERVAL, Reporter};
+use super::read::{L1_POS, L1_RESERVED, L2_COMPRESSED, L2_RESERVED, L2Entry};
+use super::refcount::{REFT_POS, REFT_RESERVED, Refcounts};
+use super::snapshot;
+
+
+/// A problem found when checking an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CheckFinding {
+    /// The refcount of a cluster doesn't match the number of references to it.
+    ///
+    /// If the refcount is too high, the cluster is leaked. If it's too low, the cluster could be
+    /// reused while still in use, which is a corruption.
+    Refcount {
+        /// The index of the host cluster.
+        cluster: u64,
+        /// The refcount stored in the image.
+        refcount: u64,
+        /// The number of references found.
+        references: u64,
+    },
+    /// Some metadata is invalid, so what it describes can't be checked.
+    Invalid {
+        /// The offset in the image file where the problem was found.
+        offset: u64,
+        /// A description of the problem.
+        message: String,
+    },
+}
+
+impl CheckFinding {
+    /// Whether this finding is a leak, rather than a corruption.
+    ///
+    /// Leaks waste space, but are harmless to data.
+    pub fn is_leak(&self) -> bool {
+        match *self {
+            CheckFinding::Refcount { refcount, references, .. } => refcount > references,
+            CheckFinding::Invalid { .. } => false,
+        }
+    }
+}
+
+impl Display for CheckFinding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            CheckFinding::Refcount { cluster, refcount, references } => {
+                write!(f,
+                       "{} cluster {} refcount={} reference={}",
+                       if self.is_leak() { "Leaked" } else { "ERROR" },
+                       cluster,
+                       refcount,
+                       references)
+            }
+            CheckFinding::Invalid { offset, ref message } => {
+                write!(f, "ERROR at offset {:#x}: {}", offset, message)
+            }
+        }
+    }
+}
+
+/// The results of checking an image for consistency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckResult {
+    /// The number of corruptions found.
+    pub corruptions: u64,
+    /// The number of leaked clusters found.
+    pub leaks: u64,
+    /// The end of the last cluster in use.
+    pub image_end_offset: u64,
+    /// Each problem found.
+    pub findings: Vec<CheckFinding>,
+}
+
+impl CheckResult {
+    /// Whether no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+// State while checking an image.
+struct Checker<'a, I: 'a + ReadAt, C: CachePolicy, P: 'a + Progress + ?Sized> {
+    q: &'a Qcow2<I, C>,
+    progress: &'a P,
+    // Progress through the entries of all the L1 tables.
+    tables: Reporter<'a, P>,
+    tables_done: u64,
+    file_size: u64,
+    references: Vec<u64>,
+    findings: Vec<CheckFinding>,
+}
+
+impl<'a, I, C, P> Checker<'a, I, C, P>
+    where I: 'a + ReadAt,
+          C: CachePolicy,
+          P: 'a + Progress + ?Sized
+{
+    fn invalid(&mut self, offset: u64, message: String) {
+        self.findings.push(CheckFinding::Invalid { offset, message });
+    }
+
+    // Add a reference to each cluster in a range. Returns whether the range is valid.
+    fn reference(&mut self, what: &str, offset: u64, len: u64) -> bool {
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= self.file_size => end,
+            _ => {
+                self.invalid(offset, format!("{} is past the end of the file", what));
+                return false;
+            }
+        };
+        let cs = self.q.cluster_size();
+        for cluster in offset / cs..end.div_ceil(cs) {
+            self.references[cluster as usize] += 1;
+        }
+        true
+    }
+
+    // Reference a cluster-aligned table.
+    fn reference_table(&mut self, what: &str, offset: u64, len: u64) -> bool {
+        if !offset.is_multiple_of(self.q.cluster_size()) {
+            self.invalid(offset, format!("{} is not cluster aligned", what));
+            return false;
+        }
+        self.reference(what, offset, len)
+    }
+
+    fn check_l2(&mut self, l2_pos: u64) -> Result<()> {
+        let table = self.q.l2_table_load(l2_pos)?;
+        for (idx, &raw) in table.iter().enumerate() {
+            let offset = l2_pos + (idx * size_of::<u64>()) as u64;
+            // Report reserved bits even if the image was opened to ignore them.
+            if raw & L2_COMPRESSED == 0 && raw & L2_RESERVED != 0 {
+                self.invalid(offset, "reserved bit used in L2 entry".to_owned());
+                continue;
+            }
+            match self.q.l2_entry_parse(raw) {
+                Ok(L2Entry::Empty) |
+                Ok(L2Entry::Zero { .. }) => {}
+                // Data in an external data file isn't refcounted.
+                Ok(L2Entry::Standard { .. }) if self.q.header().has_data_file() => {}
+                Ok(L2Entry::Standard { pos, .. }) => {
+                    self.reference_table("data cluster", pos, self.q.cluster_size());
+                }
+                Ok(L2Entry::Compressed { pos, size, .. }) => {
+                    self.reference("compressed cluster", pos, size);
+                }
+                Err(Error::FileFormat(msg)) => self.invalid(offset, msg),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_l1(&mut self, l1_offset: u64, l1_size: u64) -> Result<()> {
+        if !self.reference_table("L1 table", l1_offset, l1_size * size_of::<u64>() as u64) {
+            return Ok(());
+        }
+        let mut l1 = vec![0; to_usize(l1_size * size_of::<u64>() as u64, "L1 table size")?];
+        self.q.io.read_exact_at(l1_offset, &mut l1)?;
+        for (idx, raw) in l1.chunks(size_of::<u64>()).map(BigEndian::read_u64).enumerate() {
+            self.tables.update(self.tables_done, 0)?;
+            self.tables_done += 1;
+            let offset = l1_offset + (idx * size_of::<u64>()) as u64;
+            if raw & L1_RESERVED != 0 {
+                self.invalid(offset, "reserved bit used in L1 entry".to_owned());
+                continue;
+            }
+            let pos = raw & L1_POS;
+            if pos != 0 && self.reference_table("L2 table", pos, self.q.cluster_size()) {
+                self.check_l2(pos)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_bitmaps(&mut self) -> Result<()> {
+        let dir = match self.q.header().v3.bitmaps.0 {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if !self.reference_table("bitmap directory", dir.offset, dir.size) {
+            return Ok(());
+        }
+        let bitmaps = match self.q.bitmaps() {
+            Ok(bitmaps) => bitmaps,
+            Err(Error::FileFormat(msg)) => {
+                self.invalid(dir.offset, msg);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        for bitmap in bitmaps {
+            let len = bitmap.table_size as u64 * size_of::<u64>() as u64;
+            if !self.reference_table("bitmap table", bitmap.table_offset, len) {
+                continue;
+            }
+            for (idx, raw) in bitmap.table(self.q)?.into_iter().enumerate() {
+                let offset = bitmap.table_offset + (idx * size_of::<u64>()) as u64;
+                if raw & BITMAP_TABLE_RESERVED != 0 {
+                    self.invalid(offset, "reserved bit used in bitmap table entry".to_owned());
+                    continue;
+                }
+                let pos = raw & BITMAP_TABLE_POS;
+                if pos != 0 {
+                    self.reference_table("bitmap data cluster", pos, self.q.cluster_size());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_refcount_table(&mut self, refcounts: &Refcounts<I, C>) {
+        let c = &self.q.header().c;
+        let len = c.refcount_table_clusters as u64 * self.q.cluster_size();
+        self.reference_table("refcount table", c.refcount_table_offset, len);
+        for (idx, &raw) in refcounts.table().iter().enumerate() {
+            let offset = c.refcount_table_offset + (idx * size_of::<u64>()) as u64;
+            if raw & REFT_RESERVED != 0 {
+                self.invalid(offset, "reserved bit used in refcount table entry".to_owned());
+                continue;
+            }
+            let pos = raw & REFT_POS;
+            if pos != 0 {
+                self.reference_table("refcount block", pos, self.q.cluster_size());
+            }
+        }
+    }
+
+    fn run(mut self) -> Result<CheckResult> {
+        let q = self.q;
+        let cs = q.cluster_size();
+
+        // The header, extensions and backing file name all live in the first cluster.
+        self.reference("header", 0, cs);
+        self.findings.extend(q.header().strict_findings.iter().cloned());
+        self.check_l1(q.header().c.l1_table_offset, q.header().c.l1_size as u64)?;
+
+        if q.header().c.nb_snapshots > 0 {
+            let (_, size) = snapshot::read_snapshots(&q.io, q.header())?;
+            self.reference_table("snapshot table", q.header().c.snapshots_offset, size);
+        }
+        // Lookups by ID would find only one of the snapshots.
+        for (idx, snap) in q.snapshots().iter().enumerate() {
+            if q.snapshots()[..idx].iter().any(|s| s.id == snap.id) {
+                let msg = format!("duplicate snapshot ID {}", snap.id);
+                self.invalid(q.header().c.snapshots_offset, msg);
+            }
+        }
+        for snap in q.snapshots() {
+            self.check_l1(snap.l1_table_offset, snap.l1_size as u64)?;
+        }
+
+        self.tables.finish(0)?;
+
+        self.check_bitmaps()?;
+
+        let mut refcounts = Refcounts::new(q)?;
+        self.check_refcount_table(&refcounts);
+
+        let mut result = CheckResult {
+            corruptions: 0,
+            leaks: 0,
+            image_end_offset: 0,
+            findings: Vec::new(),
+        };
+        let clusters = self.references.len() as u64;
+        let interval = max(1, REPORT_INTERVAL / cs);
+        let mut reporter =
+            Reporter::phase(self.progress, Phase::CheckingRefcounts, (1, 2), clusters, interval);
+        for cluster in 0..clusters {
+            reporter.update(cluster, 0)?;
+            let refcount = refcounts.get(cluster)?;
+            let references = self.references[cluster as usize];
+            if refcount > 0 || references > 0 {
+                result.image_end_offset = (cluster + 1) * cs;
+            }
+            if refcount != references {
+                self.findings.push(CheckFinding::Refcount {
+                    cluster,
+                    refcount,
+                    references,
+                });
+            }
+        }
+
+        reporter.finish(0)?;
+
+        for f in &self.findings {
+            if f.is_leak() {
+                result.leaks += 1;
+            } else {
+                result.corruptions += 1;
+            }
+        }
+        result.findings = self.findings;
+        Ok(result)
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt + Size,
+          C: CachePolicy
+{
+    /// Check the image for consistency, similar to `qemu-img check`.
+    ///
+    /// This verifies that the refcount of every cluster matches the number of references to it
+    /// from the L1, L2, refcount, snapshot and bitmap tables, that those tables only point to
+    /// valid locations, and that snapshot IDs are unique. Problems in the image are reported in
+    /// the result, rather than as errors.
+    ///
+    /// If the image was opened with `OpenOptions::strict`, problems found then are included.
+    pub fn check(&self) -> Result<CheckResult> {
+        self.check_reporting(&|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Check the image for consistency, reporting progress to a `Progress`, which may cancel
+    /// the check.
+    ///
+    /// This is like `check`. There are two phases: `Phase::ScanningTables`, while the L1 and
+    /// L2 tables are read, and `Phase::CheckingRefcounts`, while each cluster's refcount is
+    /// compared with its references. Progress is reported before each entry of the L1 tables,
+    /// and for each megabyte of clusters while comparing refcounts. Checking only reads the
+    /// image, so if cancelled, this fails with `Error::Cancelled`, leaving nothing behind.
+    pub fn check_reporting<P: Progress + ?Sized>(&self, progress: &P) -> Result<CheckResult> {
+        let file_size = match self.io.size()? {
+            Some(size) => size,
+            None => return Err(Error::Internal("can't determine the size of the file".to_owned())),
+        };
+        let clusters = to_usize(file_size.div_ceil(self.cluster_size()), "number of clusters")?;
+        let l1_entries = self.header().c.l1_size as u64 +
+                         self.snapshots().iter().map(|s| s.l1_size as u64).sum::<u64>();
+        let checker = Checker {
+            q: self,
+            progress,
+            tables: Reporter::phase(progress, Phase::ScanningTables, (0, 2), l1_entries, 1),
+            tables_done: 0,
+            file_size,
+            references: vec![0; clusters],
+            findings: Vec::new(),
+        };
+        checker.run()
+    }
+}
@@ -0,0 +1,194 @@
+use std::cmp::min;
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Reader, Result, SharedCache};
+use super::read::L2Entry;
+
+
+/// The contents of one cluster of the virtual disk, see `Reader::clusters`.
+///
+/// The last cluster is shorter than the others if the size of the disk isn't a multiple of the
+/// cluster size. More kinds may be added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Cluster {
+    /// Not allocated in the image, and there's no backing file, so it reads as zeros. The value
+    /// is the length.
+    Hole(u64),
+    /// Zeros, without any data stored. The value is the length.
+    Zero(u64),
+    /// Data stored in the image, or in its external data file.
+    Data(Vec<u8>),
+    /// Data stored compressed in the image, decompressed.
+    Compressed(Vec<u8>),
+    /// Not allocated in the image, with data read from the backing file.
+    Backing(Vec<u8>),
+}
+
+impl Cluster {
+    /// Get the number of bytes of the virtual disk in this cluster.
+    pub fn size(&self) -> u64 {
+        match *self {
+            Cluster::Hole(len) | Cluster::Zero(len) => len,
+            Cluster::Data(ref d) | Cluster::Compressed(ref d) | Cluster::Backing(ref d) => {
+                d.len() as u64
+            }
+        }
+    }
+}
+
+/// The contents of one cluster of the virtual disk, borrowing its data, see
+/// `Reader::for_each_cluster`.
+///
+/// This has the same kinds of cluster as `Cluster`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClusterRef<'b> {
+    /// Not allocated in the image, and there's no backing file, so it reads as zeros. The value
+    /// is the length.
+    Hole(u64),
+    /// Zeros, without any data stored. The value is the length.
+    Zero(u64),
+    /// Data stored in the image, or in its external data file.
+    Data(&'b [u8]),
+    /// Data stored compressed in the image, decompressed.
+    Compressed(&'b [u8]),
+    /// Not allocated in the image, with data read from the backing file.
+    Backing(&'b [u8]),
+}
+
+impl<'b> ClusterRef<'b> {
+    /// Get the number of bytes of the virtual disk in this cluster.
+    pub fn size(&self) -> u64 {
+        match *self {
+            ClusterRef::Hole(len) | ClusterRef::Zero(len) => len,
+            ClusterRef::Data(d) | ClusterRef::Compressed(d) | ClusterRef::Backing(d) => {
+                d.len() as u64
+            }
+        }
+    }
+
+    /// Copy the data of this cluster, if it has any.
+    pub fn to_cluster(&self) -> Cluster {
+        match *self {
+            ClusterRef::Hole(len) => Cluster::Hole(len),
+            ClusterRef::Zero(len) => Cluster::Zero(len),
+            ClusterRef::Data(d) => Cluster::Data(d.to_vec()),
+            ClusterRef::Compressed(d) => Cluster::Compressed(d.to_vec()),
+            ClusterRef::Backing(d) => Cluster::Backing(d.to_vec()),
+        }
+    }
+}
+
+/// An iterator over the clusters of the virtual disk.
+///
+/// Created by `Reader::clusters`.
+pub struct Clusters<'a, I: 'a + ReadAt, C: CachePolicy = SharedCache> {
+    reader: &'a Reader<'a, I, C>,
+    buf: Vec<u8>,
+    pos: u64,
+}
+
+impl<'a, I, C> Iterator for Clusters<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    type Item = Result<Cluster>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.reader.size {
+            return None;
+        }
+        match self.reader.cluster_read(self.pos, &mut self.buf) {
+            Ok(c) => {
+                self.pos += c.size();
+                Some(Ok(c.to_cluster()))
+            }
+            Err(e) => {
+                // Stop after an error.
+                self.pos = self.reader.size;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    /// Iterate over each cluster of the virtual disk in order, with its contents.
+    ///
+    /// Each data cluster is copied into a new buffer. To avoid that, use `for_each_cluster`.
+    /// Iteration stops after an error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qcow2::{Cluster, Qcow2};
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let mut data = 0;
+    /// for cluster in qcow.reader()?.clusters() {
+    ///     if let Cluster::Data(buf) = cluster? {
+    ///         data += buf.len();
+    ///     }
+    /// }
+    /// assert_eq!(data, 65536);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn clusters(&'a self) -> Clusters<'a, I, C> {
+        Clusters {
+            reader: self,
+            buf: vec![0; self.q.cluster_size() as usize],
+            pos: 0,
+        }
+    }
+
+    /// Call a function with the guest offset and contents of each cluster of the virtual disk,
+    /// in order.
+    ///
+    /// Data is read into one buffer, reused for every cluster, and compressed clusters are
+    /// decompressed into it. Holes and zero clusters need no reads. The first error, from reading
+    /// or from the function, stops the iteration and is returned.
+    pub fn for_each_cluster<F>(&self, mut f: F) -> Result<()>
+        where F: FnMut(u64, ClusterRef) -> Result<()>
+    {
+        let mut buf = vec![0; self.q.cluster_size() as usize];
+        let mut pos = 0;
+        while pos < self.size {
+            let cluster = self.cluster_read(pos, &mut buf)?;
+            let len = cluster.size();
+            f(pos, cluster)?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    // Read the cluster at a cluster-aligned guest offset, using a buffer of the cluster size
+    // for any data.
+    fn cluster_read<'b>(&self, pos: u64, buf: &'b mut [u8]) -> Result<ClusterRef<'b>> {
+        let q = self.q;
+        let len = min(q.cluster_size(), self.size - pos);
+        let entry = q.l2_entry_read(&self.l1, pos)?;
+        q.count(|m| m.add_guest(len));
+        let cluster = match entry {
+            L2Entry::Empty if q.backing.is_none() => ClusterRef::Hole(len),
+            L2Entry::Zero { .. } |
+            L2Entry::Standard { zero: true, .. } => ClusterRef::Zero(len),
+            _ => {
+                let buf = &mut buf[..len as usize];
+                q.guest_block_read(entry, pos, 0, buf)?;
+                return Ok(match entry {
+                    L2Entry::Empty => ClusterRef::Backing(buf),
+                    L2Entry::Compressed { .. } => ClusterRef::Compressed(buf),
+                    _ => ClusterRef::Data(buf),
+                });
+            }
+        };
+        q.count(|m| m.add_zero(len));
+        Ok(cluster)
+    }
+}
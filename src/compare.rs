@@ -0,0 +1,109 @@
+use std::cmp::{max, min};
+
+use positioned_io::{ReadAt, Size};
+
+use super::{CachePolicy, Result};
+use super::read::{L2Entry, Reader};
+
+
+// How much data to compare at a time.
+const COMPARE_CHUNK: u64 = 1 << 20;
+
+/// A difference found when comparing images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Difference {
+    /// The contents differ, starting at this offset.
+    Content(u64),
+    /// The range starting at this offset is allocated in only one of the images.
+    Allocation(u64),
+    /// The images have different sizes.
+    Size,
+}
+
+// Read a range of data, filling in zeros past the end of the source.
+fn read_padded<R>(io: &R, size: u64, pos: u64, buf: &mut [u8]) -> Result<()>
+    where R: ReadAt + Size + ?Sized
+{
+    let avail = min(size.saturating_sub(pos), buf.len() as u64) as usize;
+    io.read_exact_at(pos, &mut buf[..avail])?;
+    for b in &mut buf[avail..] {
+        *b = 0;
+    }
+    Ok(())
+}
+
+fn source_size<R: Size + ?Sized>(io: &R) -> Result<u64> {
+    Ok(io.size()?.unwrap_or(0))
+}
+
+// Compare a range of two sources, returning the offset of the first difference.
+fn compare_range<A, B>(a: &A,
+                       a_size: u64,
+                       b: &B,
+                       b_size: u64,
+                       start: u64,
+                       end: u64)
+                       -> Result<Option<u64>>
+    where A: ReadAt + Size + ?Sized,
+          B: ReadAt + Size + ?Sized
+{
+    let chunk = min(end - start, COMPARE_CHUNK) as usize;
+    let (mut a_buf, mut b_buf) = (vec![0; chunk], vec![0; chunk]);
+    let mut pos = start;
+    while pos < end {
+        let len = min(end - pos, COMPARE_CHUNK) as usize;
+        read_padded(a, a_size, pos, &mut a_buf[..len])?;
+        read_padded(b, b_size, pos, &mut b_buf[..len])?;
+        if let Some(i) = a_buf[..len].iter().zip(&b_buf[..len]).position(|(x, y)| x != y) {
+            return Ok(Some(pos + i as u64));
+        }
+        pos += len as u64;
+    }
+    Ok(None)
+}
+
+/// Compare the contents of two sources of data, such as a qcow2 `Reader` and a raw image.
+///
+/// Returns the first difference, or `None` if the contents are identical. Like `qemu-img
+/// compare`, if one source is larger the extra part must contain only zeros, so the result is
+/// never `Difference::Size`.
+pub fn compare<A, B>(a: &A, b: &B) -> Result<Option<Difference>>
+    where A: ReadAt + Size + ?Sized,
+          B: ReadAt + Size + ?Sized
+{
+    let (a_size, b_size) = (source_size(a)?, source_size(b)?);
+    let diff = compare_range(a, a_size, b, b_size, 0, max(a_size, b_size))?;
+    Ok(diff.map(Difference::Content))
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    // Whether the cluster containing an offset is allocated in this image.
+    fn is_allocated(&self, pos: u64) -> Result<bool> {
+        Ok(!matches!(self.q.l2_entry_read(&self.l1, pos)?, L2Entry::Empty))
+    }
+
+    /// Compare with another reader, requiring identical sizes and allocation as well as
+    /// identical contents.
+    ///
+    /// A cluster with the zero flag counts as allocated. Differences in allocation are reported
+    /// before differences in content.
+    pub fn compare_strict<J: ReadAt>(&self, other: &Reader<J>) -> Result<Option<Difference>> {
+        if self.size != other.size {
+            return Ok(Some(Difference::Size));
+        }
+        // Cluster sizes are powers of two, so allocation is uniform within the smaller one.
+        let step = min(self.q.cluster_size(), other.q.cluster_size());
+        let mut pos = 0;
+        while pos < self.size {
+            if self.is_allocated(pos)? != other.is_allocated(pos)? {
+                return Ok(Some(Difference::Allocation(pos)));
+            }
+            pos += step;
+        }
+        let diff = compare_range(self, self.size, other, other.size, 0, self.size)?;
+        Ok(diff.map(Difference::Content))
+    }
+}
@@ -0,0 +1,27 @@
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::inflate::core::{DecompressorOxide, decompress};
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+
+
+// Decompress a compressed cluster, which qemu stores as raw deflate data without a zlib header,
+// into a buffer of the cluster size. Returns whether it filled the buffer.
+//
+// Like qemu, anything left once the buffer is full is ignored, since the stored size is rounded
+// up to whole sectors.
+pub fn inflate(data: &[u8], out: &mut [u8]) -> bool {
+    let mut state = Box::<DecompressorOxide>::default();
+    let (status, _, written) =
+        decompress(&mut state, data, out, 0, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF);
+    match status {
+        TINFLStatus::Done | TINFLStatus::HasMoreOutput => written == out.len(),
+        _ => false,
+    }
+}
+
+// Compress a cluster as raw deflate data, the way qemu does. Returns `None` if that doesn't make
+// it smaller, so it's better stored as it is.
+pub fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let stored = compress_to_vec(data, 6);
+    if stored.len() < data.len() { Some(stored) } else { None }
+}
@@ -0,0 +1,282 @@
+use std::cmp::{max, min};
+use std::mem::size_of;
+use std::ops::ControlFlow;
+
+use positioned_io::{ReadAt, WriteAt};
+
+use super::{CachePolicy, Progress, Reader, Result};
+use super::progress::Reporter;
+use super::read::L2Entry;
+use super::write::{ClusterOp, Storage, Writer};
+
+
+// The most data to copy in one transaction.
+const COPY_BATCH: u64 = 16 << 20;
+
+/// Choices for copying with `copy_range`.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    skip_equal: bool,
+}
+
+impl CopyOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave alone any part of the destination that already holds the same data as the source,
+    /// rather than writing it again.
+    ///
+    /// This costs a read of the destination, but avoids allocating clusters and copying
+    /// clusters shared with snapshots, when restoring a range that has mostly not changed.
+    pub fn skip_equal(&mut self, skip: bool) -> &mut Self {
+        self.skip_equal = skip;
+        self
+    }
+}
+
+/// Statistics about a range copied by `copy_range`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    /// The number of bytes of data written to the destination.
+    pub written: u64,
+    /// The number of bytes marked as reading zeros, without writing any data.
+    pub zeroed: u64,
+    /// The number of bytes whose mappings were dropped, leaving them unallocated.
+    pub discarded: u64,
+    /// The number of bytes left alone, since the destination already held the same data. Only
+    /// counted with `CopyOptions::skip_equal`.
+    pub unchanged: u64,
+}
+
+impl CopyStats {
+    /// Get the total number of bytes of the range copied.
+    pub fn total(&self) -> u64 {
+        self.written + self.zeroed + self.discarded + self.unchanged
+    }
+}
+
+// What a range of the source holds, going by its L2 tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    // Unallocated, with no backing file.
+    Hole,
+    // Reads as zeros, with no data stored.
+    Zero,
+    // Anything that needs reading.
+    Data,
+}
+
+/// Copy a range of one virtual disk into another, keeping it sparse.
+///
+/// `len` bytes at `src_off` in the source are copied to `dst_off` in the destination. Whole
+/// clusters of the destination are filled by what the source's L2 tables say: unallocated parts
+/// of the source are discarded in the destination, zero clusters become zero clusters, and only
+/// data is read and written. The two images needn't have the same cluster size. If
+/// the destination has a backing file, unallocated parts are made zero clusters instead, so
+/// they still read as zeros. Any part of a cluster at either end of the range is read and
+/// written like normal data.
+///
+/// This is how to restore part of a disk from a snapshot or backup, without filling in all its
+/// holes. The range is cut short at the end of either virtual disk. Changes are committed once
+/// per L2 table of the destination, or more often when there's a lot of data.
+///
+/// # Examples
+///
+/// ```
+/// use qcow2::{CopyOptions, Qcow2, copy_range};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let src = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let mut dst = Qcow2::open(std::fs::read("tests/test.qcow2")?)?;
+/// let reader = src.reader()?;
+/// let mut writer = dst.writer()?;
+/// // Copy the whole disk. Only the one data cluster is written.
+/// let size = src.guest_size();
+/// let stats = copy_range(&reader, 0, &mut writer, 0, size, &CopyOptions::new())?;
+/// assert_eq!(stats.written, 65536);
+/// assert_eq!(stats.total(), size);
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn copy_range<I, J, C, D>(src: &Reader<'_, J, D>,
+                              src_off: u64,
+                              dst: &mut Writer<'_, I, C>,
+                              dst_off: u64,
+                              len: u64,
+                              opts: &CopyOptions)
+                              -> Result<CopyStats>
+    where I: Storage,
+          J: ReadAt,
+          C: CachePolicy,
+          D: CachePolicy
+{
+    copy_range_reporting(src,
+                         src_off,
+                         dst,
+                         dst_off,
+                         len,
+                         opts,
+                         &|_, _, _| ControlFlow::Continue(()))
+}
+
+/// Copy a range of one virtual disk into another, keeping it sparse, and reporting progress to
+/// a `Progress`, which may cancel the copy.
+///
+/// This is like `copy_range`. Progress counts bytes of the range, and the physical bytes
+/// reported are those written to the destination.
+///
+/// If cancelled, this fails with `Error::Cancelled`. Everything copied up to the last progress
+/// report is committed to the destination, and the rest of the range is left as it was, so
+/// copying can go on from the reported offset.
+pub fn copy_range_reporting<I, J, C, D, P>(src: &Reader<'_, J, D>,
+                                           src_off: u64,
+                                           dst: &mut Writer<'_, I, C>,
+                                           dst_off: u64,
+                                           len: u64,
+                                           opts: &CopyOptions,
+                                           progress: &P)
+                                           -> Result<CopyStats>
+    where I: Storage,
+          J: ReadAt,
+          C: CachePolicy,
+          D: CachePolicy,
+          P: Progress + ?Sized
+{
+    let len = min(len,
+                  min(src.size.saturating_sub(src_off),
+                      dst.q.guest_size().saturating_sub(dst_off)));
+    let cluster_size = dst.q.cluster_size();
+    let end = dst_off + len;
+    // The whole clusters of the destination in the range.
+    let first = min(dst_off.next_multiple_of(cluster_size), end);
+    let last = max(first, end - end % cluster_size);
+
+    let mut reporter = Reporter::new(progress, len);
+    let mut stats = CopyStats::default();
+    copy_bytes(src, src_off, dst, dst_off, first - dst_off, opts, &mut stats)?;
+
+    let table_size = cluster_size / size_of::<u64>() as u64 * cluster_size;
+    let mut pos = first;
+    while pos < last {
+        let table_end = min(last, (pos / table_size + 1) * table_size);
+        let mut ops = Vec::new();
+        let mut batch = 0;
+        let mut reported = Ok(());
+        while pos < table_end && batch < COPY_BATCH {
+            let src_pos = src_off + (pos - dst_off);
+            let op = match src_kind(src, src_pos, cluster_size)? {
+                Kind::Hole if dst.q.backing().is_none() => ClusterOp::Discard,
+                Kind::Hole | Kind::Zero => ClusterOp::Zero,
+                Kind::Data => {
+                    let mut buf = vec![0; cluster_size as usize];
+                    src.read_exact_at(src_pos, &mut buf)?;
+                    batch += cluster_size;
+                    ClusterOp::Write(buf)
+                }
+            };
+            if opts.skip_equal && dst_holds(dst, pos, &op)? {
+                stats.unchanged += cluster_size;
+            } else {
+                match op {
+                    ClusterOp::Write(_) => stats.written += cluster_size,
+                    ClusterOp::Zero => stats.zeroed += cluster_size,
+                    ClusterOp::Discard => stats.discarded += cluster_size,
+                }
+                ops.push((pos, op));
+            }
+            pos += cluster_size;
+            // Commit what was copied before stopping, so it's all there as reported.
+            reported = reporter.update(pos - dst_off, stats.written);
+            if reported.is_err() {
+                break;
+            }
+        }
+        if !ops.is_empty() {
+            dst.clusters_apply(ops)?;
+        }
+        reported?;
+    }
+
+    copy_bytes(src, src_off + (last - dst_off), dst, last, end - last, opts, &mut stats)?;
+    reporter.finish(stats.written)?;
+    Ok(stats)
+}
+
+// Find what a range of the source holds. The range may cover many clusters, or part of one.
+fn src_kind<J: ReadAt, D: CachePolicy>(src: &Reader<'_, J, D>, pos: u64, len: u64) -> Result<Kind> {
+    let q = src.q;
+    let cluster_size = q.cluster_size();
+    let mut kind = Kind::Hole;
+    let mut cluster = pos - pos % cluster_size;
+    while cluster < pos + len {
+        match q.l2_entry_read(&src.l1, cluster)? {
+            L2Entry::Empty if q.backing().is_none() => {}
+            L2Entry::Zero { .. } |
+            L2Entry::Standard { zero: true, .. } => kind = Kind::Zero,
+            _ => return Ok(Kind::Data),
+        }
+        cluster += cluster_size;
+    }
+    Ok(kind)
+}
+
+// Check whether a whole cluster of the destination already holds what an operation would
+// leave there.
+fn dst_holds<I, C>(dst: &Writer<'_, I, C>, pos: u64, op: &ClusterOp) -> Result<bool>
+    where I: Storage,
+          C: CachePolicy
+{
+    let q = &*dst.q;
+    let reads_zero = match q.l2_entry_read(&dst.l1, pos)? {
+        L2Entry::Empty => q.backing().is_none(),
+        L2Entry::Zero { .. } |
+        L2Entry::Standard { zero: true, .. } => true,
+        _ => false,
+    };
+    let zeros = |buf: &[u8]| buf.iter().all(|&b| b == 0);
+    if reads_zero {
+        return Ok(match *op {
+            ClusterOp::Write(ref data) => zeros(data),
+            _ => true,
+        });
+    }
+    let mut buf = vec![0; q.cluster_size() as usize];
+    dst.read_exact_at(pos, &mut buf)?;
+    Ok(match *op {
+        ClusterOp::Write(ref data) => buf == *data,
+        _ => zeros(&buf),
+    })
+}
+
+// Copy part of a cluster of the destination by reading and writing it.
+fn copy_bytes<I, J, C, D>(src: &Reader<'_, J, D>,
+                          src_off: u64,
+                          dst: &mut Writer<'_, I, C>,
+                          dst_off: u64,
+                          len: u64,
+                          opts: &CopyOptions,
+                          stats: &mut CopyStats)
+                          -> Result<()>
+    where I: Storage,
+          J: ReadAt,
+          C: CachePolicy,
+          D: CachePolicy
+{
+    if len == 0 {
+        return Ok(());
+    }
+    let mut buf = vec![0; len as usize];
+    src.read_exact_at(src_off, &mut buf)?;
+    if opts.skip_equal {
+        let mut old = vec![0; len as usize];
+        dst.read_exact_at(dst_off, &mut old)?;
+        if old == buf {
+            stats.unchanged += len;
+            return Ok(());
+        }
+    }
+    dst.write_all_at(dst_off, &buf)?;
+    stats.written += len;
+    Ok(())
+}
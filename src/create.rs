@@ -0,0 +1,417 @@
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ops::ControlFlow;
+
+use positioned_io::{ReadAt, Size};
+
+use super::{CachePolicy, Error, Progress, Qcow2, Result};
+use super::compress;
+use super::progress::Reporter;
+use super::header::{Header, MAGIC};
+use super::int::to_usize;
+use super::read::{L1_COW, L2_COMPRESSED, L2_COW, L2_ZERO, L2Entry, Reader};
+use super::write::Storage;
+
+
+// Created images use 16-bit refcounts, like qemu.
+const REFCOUNT_ORDER: u32 = 4;
+// The cluster size of new images, unless another is chosen, like qemu.
+pub(crate) const DEFAULT_CLUSTER_BITS: u32 = 16;
+// The cluster sizes qemu can create.
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+/// Choices for creating an image with `Qcow2::create_from`.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::File;
+/// use qcow2::{CreateOptions, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = Qcow2::open(File::open("tests/test.qcow2")?)?;
+/// let reader = qcow.reader()?;
+/// let copy = Qcow2::create_from(&reader, Vec::new(), &CreateOptions::new())?;
+/// assert_eq!(copy.guest_size(), qcow.guest_size());
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    keep_backing: bool,
+    compress: bool,
+    cluster_bits: Option<u32>,
+}
+
+impl CreateOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the new image use the same backing file as the source, rather than copying the data
+    /// it provides.
+    ///
+    /// By default, the new image has no backing file, and data from the source's backing file is
+    /// copied into it. Keeping the backing file makes the new image an overlay, holding only the
+    /// source's own data.
+    pub fn keep_backing(&mut self, keep: bool) -> &mut Self {
+        self.keep_backing = keep;
+        self
+    }
+
+    /// Compress the data clusters of the new image with deflate, like `qemu-img convert -c`.
+    ///
+    /// Compressed clusters are packed together in the file, so the image takes less space, but
+    /// reads must decompress them, and writes to them fail. Clusters that don't get smaller
+    /// are stored uncompressed.
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Give the new image clusters of `1 << bits` bytes, from 512 bytes to 2 MiB.
+    ///
+    /// This is for `create` and `create_from_raw`, and by default the clusters are 64 KiB.
+    /// `create_from` always uses the cluster size of the source.
+    pub fn cluster_bits(&mut self, bits: u32) -> &mut Self {
+        self.cluster_bits = Some(bits);
+        self
+    }
+}
+
+// Start the header of a new image, checking that its cluster size and size can be created.
+pub(crate) fn new_header(cluster_bits: u32, size: u64) -> Result<Header> {
+    if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        return Err(Error::UnsupportedFeature(format!("cluster_bits {}", cluster_bits)));
+    }
+    let mut header = Header::default();
+    header.c.cluster_bits = cluster_bits;
+    header.c.size = size;
+    if size > header.geometry().max_size() {
+        return Err(Error::UnsupportedFeature(format!("images of size {}", size)));
+    }
+    Ok(header)
+}
+
+// Places the data clusters of a new image, right after its header, packing any compressed
+// ones together.
+struct DataClusters {
+    cluster_bits: u32,
+    compress: bool,
+    // The end of the clusters allocated so far.
+    next: u64,
+    // Where the next compressed cluster could go, or zero if nothing was compressed yet.
+    packed: u64,
+    // The number of compressed clusters using each host cluster, by index.
+    refcounts: BTreeMap<u64, u64>,
+}
+
+impl DataClusters {
+    fn new(cluster_bits: u32, compress: bool) -> Self {
+        DataClusters {
+            cluster_bits,
+            compress,
+            next: 1 << cluster_bits,
+            packed: 0,
+            refcounts: BTreeMap::new(),
+        }
+    }
+
+    // Write a whole cluster of data, and get its L2 entry.
+    fn write<I: Storage>(&mut self, dst: &mut I, data: &[u8]) -> Result<u64> {
+        let cluster_size = 1 << self.cluster_bits;
+        let stored = if self.compress { compress::deflate(data) } else { None };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => {
+                let pos = self.next;
+                dst.write_all_at(pos, data)?;
+                self.next += cluster_size;
+                return Ok(pos | L2_COW);
+            }
+        };
+
+        // Fill up the cluster the last compressed data went in. It can only spill into the
+        // next cluster if that's not allocated yet.
+        let len = stored.len() as u64;
+        let space_end = self.packed.next_multiple_of(cluster_size);
+        let fits = self.packed + len <= space_end || space_end == self.next;
+        let pos = if self.packed != 0 && fits { self.packed } else { self.next };
+        dst.write_all_at(pos, &stored)?;
+        let end = pos + len;
+        self.next = max(self.next, end.next_multiple_of(cluster_size));
+        self.packed = end;
+        for cluster in pos / cluster_size..end.div_ceil(cluster_size) {
+            *self.refcounts.entry(cluster).or_insert(0) += 1;
+        }
+
+        // The size is recorded as a count of the sectors the data touches, minus one.
+        let sectors = end.div_ceil(512) - pos / 512;
+        Ok(L2_COMPRESSED | ((sectors - 1) << (62 - (self.cluster_bits - 8))) | pos)
+    }
+}
+
+impl<I> Qcow2<I>
+    where I: Storage
+{
+    /// Create a new, empty image, with a virtual disk of `size` bytes and no backing file.
+    ///
+    /// Nothing is allocated, so the whole virtual disk reads as zeros until it's written to.
+    /// `Writer::preallocate` can allocate it up front. The cluster size may be chosen with
+    /// `CreateOptions::cluster_bits`.
+    ///
+    /// The storage should be empty. The header is written last, so if creating fails part way,
+    /// the storage won't look like a qcow2 image.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qcow2::{CreateOptions, Qcow2};
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::create(Vec::new(), 1 << 30, &CreateOptions::new())?;
+    /// assert_eq!(qcow.guest_size(), 1 << 30);
+    /// assert_eq!(qcow.cluster_size(), 65536);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn create(mut dst: I, size: u64, opts: &CreateOptions) -> Result<Self> {
+        let header = new_header(opts.cluster_bits.unwrap_or(DEFAULT_CLUSTER_BITS), size)?;
+        let cluster_size = header.cluster_size();
+        Self::write_metadata(&mut dst, header, &BTreeMap::new(), &BTreeMap::new(), cluster_size)?;
+        Qcow2::open(dst)
+    }
+
+    /// Create a new image, holding the contents of a raw image.
+    ///
+    /// The virtual disk is the size of `src`. Clusters that are all zeros are left unallocated,
+    /// so the new image is sparse wherever the raw image reads as zeros, whether or not it's
+    /// sparse itself. The cluster size may be chosen with `CreateOptions::cluster_bits`, and
+    /// the data compressed with `CreateOptions::compress`.
+    ///
+    /// The storage should be empty. The header is written last, so if creating fails part way,
+    /// the storage won't look like a qcow2 image.
+    pub fn create_from_raw<J: ReadAt + Size>(src: &J,
+                                             dst: I,
+                                             opts: &CreateOptions)
+                                             -> Result<Self> {
+        Self::create_from_raw_reporting(src, dst, opts, &|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Create a new image, holding the contents of a raw image, and report progress to a
+    /// `Progress`, which may cancel creating it.
+    ///
+    /// This is like `create_from_raw`. The physical bytes reported are those of data clusters
+    /// written to the new image.
+    ///
+    /// If cancelled, this fails with `Error::Cancelled`. Since the header is written last, the
+    /// storage then doesn't look like a qcow2 image, and should be discarded.
+    pub fn create_from_raw_reporting<J, P>(src: &J,
+                                           mut dst: I,
+                                           opts: &CreateOptions,
+                                           progress: &P)
+                                           -> Result<Self>
+        where J: ReadAt + Size,
+              P: Progress + ?Sized
+    {
+        let size = match src.size()? {
+            Some(size) => size,
+            None => return Err(Error::UnsupportedFeature("raw images of unknown size".to_owned())),
+        };
+        let header = new_header(opts.cluster_bits.unwrap_or(DEFAULT_CLUSTER_BITS), size)?;
+        let cluster_size = header.cluster_size();
+        let l2_entries = header.l2_entries();
+
+        // Data clusters go first, right after the header, in guest order.
+        let mut l2_tables: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        let mut data = DataClusters::new(header.c.cluster_bits, opts.compress);
+        let mut buf = vec![0; cluster_size as usize];
+        let mut reporter = Reporter::new(progress, size);
+        let mut guest_pos = 0;
+        while guest_pos < size {
+            let len = min(cluster_size, size - guest_pos) as usize;
+            Self::zero_fill(&mut buf[len..]);
+            src.read_exact_at(guest_pos, &mut buf[..len])?;
+            if buf.iter().any(|&b| b != 0) {
+                let cluster = guest_pos / cluster_size;
+                l2_tables.entry(cluster / l2_entries)
+                    .or_insert_with(|| vec![0; l2_entries as usize])
+                    [(cluster % l2_entries) as usize] = data.write(&mut dst, &buf)?;
+            }
+            guest_pos += len as u64;
+            reporter.update(guest_pos, data.next - cluster_size)?;
+        }
+
+        Self::write_metadata(&mut dst, header, &l2_tables, &data.refcounts, data.next)?;
+        Qcow2::open(dst)
+    }
+
+    /// Create a new image, holding what a reader sees of the virtual disk.
+    ///
+    /// The reader may be of the main virtual disk or of a snapshot, so this can export a
+    /// snapshot as an image of its own. The new image has the same size and cluster size as the
+    /// source. It's sparse wherever the source is, going by the source's L2 tables rather than
+    /// looking for zeros. Data from a backing file is the exception, since the backing file may
+    /// not know what it has allocated, so it's copied unless it's all zeros. Compressed
+    /// clusters are decompressed, and only compressed again if `CreateOptions::compress` asks.
+    ///
+    /// The storage should be empty. The header is written last, so if creating fails part way,
+    /// the storage won't look like a qcow2 image.
+    pub fn create_from<J: ReadAt, C: CachePolicy>(src: &Reader<'_, J, C>,
+                                                  dst: I,
+                                                  opts: &CreateOptions)
+                                                  -> Result<Self> {
+        Self::create_from_reporting(src, dst, opts, &|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Create a new image, holding what a reader sees of the virtual disk, and report progress
+    /// to a `Progress`, which may cancel creating it.
+    ///
+    /// This is like `create_from`. The physical bytes reported are those of data clusters
+    /// written to the new image.
+    ///
+    /// If cancelled, this fails with `Error::Cancelled`. Since the header is written last, the
+    /// storage then doesn't look like a qcow2 image, and should be discarded.
+    pub fn create_from_reporting<J, C, P>(src: &Reader<'_, J, C>,
+                                          mut dst: I,
+                                          opts: &CreateOptions,
+                                          progress: &P)
+                                          -> Result<Self>
+        where J: ReadAt,
+              C: CachePolicy,
+              P: Progress + ?Sized
+    {
+        let q = src.q;
+        let cluster_size = q.cluster_size();
+        let size = src.size;
+        let has_backing = q.header().has_backing_file();
+        let keep_backing = opts.keep_backing && has_backing;
+
+        // Data clusters go first, right after the header, in guest order.
+        let l2_entries = cluster_size / size_of::<u64>() as u64;
+        let clusters = size.div_ceil(cluster_size);
+        let l1_entries = clusters.div_ceil(l2_entries);
+        let mut l2_tables: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+        let mut data = DataClusters::new(q.header().c.cluster_bits, opts.compress);
+        let mut buf = vec![0; cluster_size as usize];
+        let mut reporter = Reporter::new(progress, size);
+        for l1_idx in 0..l1_entries {
+            let first = l1_idx * l2_entries;
+            let count = min(l2_entries, clusters - first);
+            let entries = q.l2_entries_read(&src.l1, first * cluster_size, count as usize)?;
+            for (l2_idx, entry) in entries.into_iter().enumerate() {
+                let guest_pos = (first + l2_idx as u64) * cluster_size;
+                let len = min(cluster_size, size - guest_pos) as usize;
+                let raw = match entry {
+                    L2Entry::Empty if keep_backing || !has_backing => None,
+                    L2Entry::Zero { .. } |
+                    L2Entry::Standard { zero: true, .. } => Some(L2_ZERO),
+                    _ => {
+                        Self::zero_fill(&mut buf[len..]);
+                        src.read_exact_at(guest_pos, &mut buf[..len])?;
+                        // Only data from the backing file needs checking for zeros.
+                        if matches!(entry, L2Entry::Empty) && buf.iter().all(|&b| b == 0) {
+                            None
+                        } else {
+                            Some(data.write(&mut dst, &buf)?)
+                        }
+                    }
+                };
+                if let Some(raw) = raw {
+                    l2_tables.entry(l1_idx).or_insert_with(|| vec![0; l2_entries as usize])
+                        [l2_idx] = raw;
+                }
+                reporter.update(guest_pos + len as u64, data.next - cluster_size)?;
+            }
+        }
+
+        let mut header = Header::default();
+        header.c.cluster_bits = q.header().c.cluster_bits;
+        header.c.size = size;
+        if keep_backing {
+            header.c.backing_file_offset = q.header().c.backing_file_offset;
+            header.v3.backing_file_name = q.header().v3.backing_file_name.clone();
+            header.v3.backing_format = q.header().v3.backing_format.clone();
+        }
+        Self::write_metadata(&mut dst, header, &l2_tables, &data.refcounts, data.next)?;
+        Qcow2::open(dst)
+    }
+
+    // Write the L2 tables and everything else an image needs after its data clusters, which
+    // end at `next`. The header should have the size, cluster size and any backing file set.
+    // Data clusters have a refcount of one, unless `refcounts` has another for their index.
+    //
+    // The header is written last, once everything it points to is in place.
+    pub(crate) fn write_metadata(dst: &mut I,
+                                 mut header: Header,
+                                 l2_tables: &BTreeMap<u64, Vec<u64>>,
+                                 refcounts: &BTreeMap<u64, u64>,
+                                 mut next: u64)
+                                 -> Result<()> {
+        let cluster_size = header.cluster_size();
+        let l1_entries = header.l1_entries();
+
+        // The L2 and L1 tables.
+        let mut l1 = vec![0; to_usize(l1_entries * size_of::<u64>() as u64, "L1 table size")?];
+        for (&l1_idx, table) in l2_tables {
+            let bytes: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+            dst.write_all_at(next, &bytes)?;
+            let at = l1_idx as usize * size_of::<u64>();
+            l1[at..at + size_of::<u64>()].copy_from_slice(&(next | L1_COW).to_be_bytes());
+            next += cluster_size;
+        }
+        let l1_offset = next;
+        let l1_clusters = (l1.len() as u64).div_ceil(cluster_size);
+        if !l1.is_empty() {
+            dst.write_all_at(l1_offset, &l1)?;
+        }
+        next += l1_clusters * cluster_size;
+
+        // Then the refcounts, which cover every cluster including their own.
+        let per_block = cluster_size * 8 / (1 << REFCOUNT_ORDER);
+        let (mut table_clusters, mut blocks) = (1, 1);
+        let total = loop {
+            let total = next / cluster_size + table_clusters + blocks;
+            let need_blocks = total.div_ceil(per_block);
+            let need_table = (need_blocks * size_of::<u64>() as u64).div_ceil(cluster_size);
+            if need_blocks <= blocks && need_table <= table_clusters {
+                break total;
+            }
+            blocks = max(blocks, need_blocks);
+            table_clusters = max(table_clusters, need_table);
+        };
+        let table_offset = next;
+        let blocks_offset = table_offset + table_clusters * cluster_size;
+        let table: Vec<u8> = (0..blocks)
+            .flat_map(|i| (blocks_offset + i * cluster_size).to_be_bytes())
+            .collect();
+        dst.write_all_at(table_offset, &table)?;
+        let mut block = vec![0; cluster_size as usize];
+        for i in 0..blocks {
+            let used = min(per_block, total - i * per_block) as usize;
+            block.fill(0);
+            for refcount in block[..used * 2].chunks_mut(2) {
+                refcount[1] = 1;
+            }
+            for (&cluster, &refcount) in refcounts.range(i * per_block..(i + 1) * per_block) {
+                let at = (cluster - i * per_block) as usize * 2;
+                block[at..at + 2].copy_from_slice(&(refcount as u16).to_be_bytes());
+            }
+            dst.write_all_at(blocks_offset + i * cluster_size, &block)?;
+        }
+        dst.sync()?;
+
+        // Finally the header.
+        header.c.magic = MAGIC;
+        header.c.version = 3;
+        header.c.l1_size = l1_entries as u32;
+        header.c.l1_table_offset = l1_offset;
+        header.c.refcount_table_offset = table_offset;
+        header.c.refcount_table_clusters = table_clusters as u32;
+        header.v3.refcount_order = REFCOUNT_ORDER;
+        dst.write_all_at(0, &header.write()?)?;
+        dst.sync()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::cmp::{Reverse, min};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::ops::ControlFlow;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{CachePolicy, Error, Phase, Progress, Qcow2, Result};
+use super::progress::{REPORT_INTERVAL, Reporter};
+use super::read::{L1Entry, L2Entry};
+
+
+/// Choices for `Qcow2::dedup_report_with`.
+///
+/// By default, no duplicated contents are listed.
+#[derive(Debug, Clone, Default)]
+pub struct DedupOptions {
+    top: usize,
+    offsets: bool,
+}
+
+impl DedupOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// List the contents duplicated most often, up to this many.
+    pub fn top(&mut self, top: usize) -> &mut Self {
+        self.top = top;
+        self
+    }
+
+    /// List every guest offset holding each of the listed contents, not just the first.
+    ///
+    /// This keeps the offsets of all data in memory while hashing, so it needs much more memory
+    /// for a large image.
+    pub fn offsets(&mut self, offsets: bool) -> &mut Self {
+        self.offsets = offsets;
+        self
+    }
+}
+
+/// Contents found more than once in a virtual disk, see `DedupReport::top`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DuplicateGroup {
+    /// The hash of the contents.
+    pub hash: u64,
+    /// The number of blocks with these contents.
+    pub count: u64,
+    /// The first guest offset with these contents, where they can be read.
+    pub first_offset: u64,
+    /// Every guest offset with these contents, in order, if `DedupOptions::offsets` asked for
+    /// them. Otherwise this is empty.
+    pub offsets: Vec<u64>,
+}
+
+/// How much of a virtual disk is duplicated, found by `Qcow2::dedup_report`.
+///
+/// The data is split into blocks of `hash_window` bytes, and each block is hashed. Blocks with
+/// the same hash are counted as duplicates, so there's a tiny chance that some are counted
+/// wrongly: with 64-bit hashes, it takes billions of distinct blocks before any mistake is
+/// likely.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DedupReport {
+    /// The size of each block hashed, in bytes.
+    pub hash_window: u64,
+    /// The number of blocks hashed.
+    pub blocks: u64,
+    /// The number of bytes in those blocks.
+    pub bytes: u64,
+    /// The number of distinct contents among those blocks.
+    pub unique_blocks: u64,
+    /// The number of contents found in more than one block.
+    pub duplicate_groups: u64,
+    /// The number of blocks whose contents were already found in an earlier block.
+    pub duplicate_blocks: u64,
+    /// The number of bytes that could be saved by storing each distinct content only once.
+    pub savings: u64,
+    /// The number of blocks that are all zeros. Those could be zero clusters instead, taking no
+    /// space at all.
+    pub zero_blocks: u64,
+    /// The number of compressed clusters among the clusters hashed. They're hashed once
+    /// decompressed, like any other data.
+    pub compressed_clusters: u64,
+    /// The contents duplicated most often, most often first, if `DedupOptions::top` asked for
+    /// them. Contents found the same number of times are ordered by their first offset.
+    pub top: Vec<DuplicateGroup>,
+}
+
+// What's known about blocks with the same hash.
+#[derive(Clone, Copy)]
+struct Seen {
+    count: u64,
+    first_offset: u64,
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Find how much of the main virtual disk holds duplicated data.
+    ///
+    /// Every allocated data cluster of this image is read, decompressing compressed clusters,
+    /// and split into blocks of `hash_window` bytes to hash, which must divide the cluster size.
+    /// Use the cluster size to see what sharing whole clusters would save, or a smaller window
+    /// such as 4096 to see what a filesystem that deduplicates smaller blocks might save. Holes,
+    /// zero clusters and data from any backing file are not counted.
+    ///
+    /// Only a count is kept for each distinct block, so memory use grows with the number of
+    /// distinct blocks, by a few dozen bytes each. Clusters that the image already shares,
+    /// such as with a snapshot, are counted each time they're used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = qcow2::Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let report = qcow.dedup_report(qcow.cluster_size())?;
+    /// assert_eq!(report.bytes, qcow.info()?.allocated_size);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn dedup_report(&self, hash_window: u64) -> Result<DedupReport> {
+        self.dedup_report_with(hash_window, &DedupOptions::new())
+    }
+
+    /// Find how much of the main virtual disk holds duplicated data, listing the contents
+    /// duplicated most often.
+    ///
+    /// See `dedup_report`.
+    pub fn dedup_report_with(&self, hash_window: u64, opts: &DedupOptions) -> Result<DedupReport> {
+        self.dedup_report_reporting(hash_window, opts, &|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Find how much of the main virtual disk holds duplicated data, reporting progress to a
+    /// `Progress`, which may cancel it.
+    ///
+    /// This is like `dedup_report_with`. There is one phase, `Phase::Reading`, which counts
+    /// bytes of the virtual disk, and progress is reported before a cluster is read. This only
+    /// reads the image, so if cancelled, it fails with `Error::Cancelled`, leaving nothing
+    /// behind.
+    pub fn dedup_report_reporting<P>(&self,
+                                     hash_window: u64,
+                                     opts: &DedupOptions,
+                                     progress: &P)
+                                     -> Result<DedupReport>
+        where P: Progress + ?Sized
+    {
+        let cs = self.cluster_size();
+        if hash_window == 0 || !cs.is_multiple_of(hash_window) {
+            return Err(Error::UnsupportedFeature(format!("hash windows of {} bytes, with \
+                                                          clusters of {} bytes",
+                                                         hash_window,
+                                                         cs)));
+        }
+        let size = self.guest_size();
+        let mut reporter = Reporter::phase(progress, Phase::Reading, (0, 1), size, REPORT_INTERVAL);
+        let l1 = self.l1_read(self.header().c.l1_table_offset, self.header().l1_entries())?;
+        let l1 = ByteIo::<_, BigEndian>::new(l1);
+        let mut report = DedupReport { hash_window, ..Default::default() };
+        let mut seen: HashMap<u64, Seen> = HashMap::new();
+        let mut offsets: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut buf = vec![0; cs as usize];
+        for l1_idx in 0..self.header().l1_entries() {
+            let entries = match self.l1_entry_read(&l1, l1_idx)? {
+                L1Entry::Empty => continue,
+                L1Entry::Standard { pos, .. } => self.l2_table_load(pos)?,
+            };
+            let first = l1_idx * self.header().l2_entries() * cs;
+            for (l2_idx, &raw) in entries.iter().enumerate() {
+                let guest_pos = first + l2_idx as u64 * cs;
+                if guest_pos >= size {
+                    break;
+                }
+                reporter.update(guest_pos, 0)?;
+                let entry = self.l2_entry_parse(raw)?;
+                match entry {
+                    L2Entry::Standard { zero: false, .. } => {}
+                    L2Entry::Compressed { .. } => report.compressed_clusters += 1,
+                    _ => continue,
+                }
+                let data = &mut buf[..min(cs, size - guest_pos) as usize];
+                self.guest_block_read(entry, guest_pos, 0, data)?;
+
+                for (idx, block) in data.chunks(hash_window as usize).enumerate() {
+                    let offset = guest_pos + idx as u64 * hash_window;
+                    let mut hasher = DefaultHasher::new();
+                    hasher.write(block);
+                    let hash = hasher.finish();
+
+                    report.blocks += 1;
+                    report.bytes += block.len() as u64;
+                    if block.iter().all(|&b| b == 0) {
+                        report.zero_blocks += 1;
+                    }
+                    let s = seen.entry(hash).or_insert(Seen {
+                        count: 0,
+                        first_offset: offset,
+                    });
+                    s.count += 1;
+                    if s.count > 1 {
+                        report.duplicate_blocks += 1;
+                        report.savings += block.len() as u64;
+                    }
+                    if s.count == 2 {
+                        report.duplicate_groups += 1;
+                    }
+                    if opts.top > 0 && opts.offsets {
+                        offsets.entry(hash).or_default().push(offset);
+                    }
+                }
+            }
+        }
+        reporter.finish(0)?;
+        report.unique_blocks = seen.len() as u64;
+
+        if opts.top > 0 {
+            let mut groups: Vec<(u64, Seen)> =
+                seen.into_iter().filter(|&(_, s)| s.count > 1).collect();
+            groups.sort_unstable_by_key(|&(_, s)| (Reverse(s.count), s.first_offset));
+            groups.truncate(opts.top);
+            report.top = groups.into_iter()
+                .map(|(hash, s)| {
+                    DuplicateGroup {
+                        hash,
+                        count: s.count,
+                        first_offset: s.first_offset,
+                        offsets: offsets.remove(&hash).unwrap_or_default(),
+                    }
+                })
+                .collect();
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{CachePolicy, Error, Qcow2, Result, SharedCache};
+use super::read::{L1Entry, L2_COW};
+
+
+/// A range of the virtual disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GuestRange {
+    /// The offset of the start of the range.
+    pub offset: u64,
+    /// The length of the range in bytes.
+    pub len: u64,
+}
+
+impl GuestRange {
+    /// Get the offset just past the end of the range.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// An iterator over the parts of the virtual disk that changed since a snapshot was taken.
+///
+/// Created by `Qcow2::diff_snapshot`.
+pub struct SnapshotDiff<'a, I: 'a + ReadAt, C: CachePolicy = SharedCache> {
+    q: &'a Qcow2<I, C>,
+    active: ByteIo<Vec<u8>, BigEndian>,
+    snapshot: ByteIo<Vec<u8>, BigEndian>,
+    snapshot_entries: u64,
+    l1_idx: u64,
+    // The changed range being built, which may continue into the next L2 table.
+    current: Option<GuestRange>,
+    ready: VecDeque<GuestRange>,
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Find the parts of the active virtual disk that changed since a snapshot was taken.
+    ///
+    /// Clusters are compared by where they're stored, so everything written since the snapshot
+    /// is found, even if it was overwritten with the same data. Unchanged L2 tables are
+    /// skipped without being read. Ranges are cluster aligned, apart from at the end of the
+    /// disk, and can be read with a `Reader` to make an incremental backup.
+    ///
+    /// The snapshot may be identified by either its ID or its name, see `Qcow2::find_snapshot`.
+    pub fn diff_snapshot(&self, name: &str) -> Result<SnapshotDiff<'_, I, C>> {
+        self.ensure_readable()?;
+        if self.header().data_file_raw() {
+            return Err(Error::UnsupportedFeature("snapshots of a raw data file".to_owned()));
+        }
+        let snap = self.find_snapshot(name).ok_or_else(|| Error::NoSnapshot(name.to_owned()))?;
+        let active = self.l1_read(self.header().c.l1_table_offset, self.header().l1_entries())?;
+        let snapshot = self.l1_read(snap.l1_table_offset, snap.l1_size as u64)?;
+        Ok(SnapshotDiff {
+            q: self,
+            active: ByteIo::new(active),
+            snapshot: ByteIo::new(snapshot),
+            snapshot_entries: snap.l1_size as u64,
+            l1_idx: 0,
+            current: None,
+            ready: VecDeque::new(),
+        })
+    }
+}
+
+impl<'a, I, C> SnapshotDiff<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    fn l2_pos(&self, entry: L1Entry) -> u64 {
+        match entry {
+            L1Entry::Empty => 0,
+            L1Entry::Standard { pos, .. } => pos,
+        }
+    }
+
+    fn l2_table(&self, pos: u64) -> Result<Arc<[u64]>> {
+        if pos == 0 {
+            return Ok(vec![0; self.q.header().l2_entries() as usize].into());
+        }
+        self.q.l2_table(pos)
+    }
+
+    fn finish_range(&mut self) {
+        if let Some(range) = self.current.take() {
+            self.ready.push_back(range);
+        }
+    }
+
+    fn changed(&mut self, offset: u64, len: u64) {
+        match self.current {
+            Some(ref mut range) if range.end() == offset => range.len += len,
+            _ => {
+                self.finish_range();
+                self.current = Some(GuestRange { offset, len });
+            }
+        }
+    }
+
+    // Compare the L2 tables for the next L1 entry.
+    fn compare_next(&mut self) -> Result<()> {
+        let l1_idx = self.l1_idx;
+        self.l1_idx += 1;
+        let active = self.l2_pos(self.q.l1_entry_read(&self.active, l1_idx)?);
+        let snapshot = if l1_idx < self.snapshot_entries {
+            self.l2_pos(self.q.l1_entry_read(&self.snapshot, l1_idx)?)
+        } else {
+            0
+        };
+        if active == snapshot {
+            self.finish_range();
+            return Ok(());
+        }
+
+        let (active, snapshot) = (self.l2_table(active)?, self.l2_table(snapshot)?);
+        let cluster_size = self.q.cluster_size();
+        let size = self.q.guest_size();
+        let first = l1_idx * self.q.header().l2_entries() * cluster_size;
+        for (idx, (&a, &s)) in active.iter().zip(snapshot.iter()).enumerate() {
+            let offset = first + idx as u64 * cluster_size;
+            if offset >= size {
+                break;
+            }
+            // Copying a table changes the COPIED flags, but not where data is.
+            if a & !L2_COW != s & !L2_COW {
+                self.changed(offset, cluster_size.min(size - offset));
+            } else {
+                self.finish_range();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, I, C> Iterator for SnapshotDiff<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    type Item = Result<GuestRange>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() {
+            if self.l1_idx >= self.q.header().l1_entries() {
+                self.finish_range();
+                break;
+            }
+            if let Err(e) = self.compare_next() {
+                self.l1_idx = self.q.header().l1_entries();
+                self.current = None;
+                return Some(Err(e));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,152 @@
+use std::cmp::{max, min};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use positioned_io::{ReadAt, Size, WriteAt};
+
+use super::write::Storage;
+
+
+// The alignment needed for offsets, lengths and buffers. Logical blocks are rarely bigger.
+const ALIGN: u64 = 4096;
+
+/// A file opened with `O_DIRECT`, bypassing the operating system's page cache.
+///
+/// Direct IO needs aligned offsets, lengths and buffer addresses. Requests that are already
+/// aligned go straight to the file, while others bounce through an aligned buffer. Reads and
+/// writes of whole clusters are aligned, so most metadata traffic doesn't need bouncing.
+///
+/// Some filesystems, such as tmpfs, don't support direct IO, and opening fails.
+#[derive(Debug)]
+pub struct DirectFile {
+    file: File,
+}
+
+impl DirectFile {
+    /// Open a file for reading only.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with(fs::OpenOptions::new().read(true), path)
+    }
+
+    /// Open a file for reading and writing.
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with(fs::OpenOptions::new().read(true).write(true), path)
+    }
+
+    /// Open a file with custom options, adding `O_DIRECT`.
+    pub fn open_with<P: AsRef<Path>>(options: &mut fs::OpenOptions, path: P) -> io::Result<Self> {
+        let file = options.custom_flags(libc::O_DIRECT).open(path)?;
+        Ok(DirectFile { file })
+    }
+
+    /// Get the underlying file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    fn aligned(pos: u64, buf: &[u8]) -> bool {
+        pos.is_multiple_of(ALIGN) && (buf.len() as u64).is_multiple_of(ALIGN) &&
+        (buf.as_ptr() as usize).is_multiple_of(ALIGN as usize)
+    }
+
+    // Read as much of an aligned range as exists, returning the number of bytes read.
+    fn read_aligned(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut done = 0;
+        while done < buf.len() {
+            match FileExt::read_at(&self.file, &mut buf[done..], pos + done as u64) {
+                Ok(0) => break,
+                Ok(n) => {
+                    done += n;
+                    // Only the end of the file gives an unaligned short read.
+                    if !(n as u64).is_multiple_of(ALIGN) {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+}
+
+// A buffer with an aligned start, covering the aligned range around some bytes.
+struct Bounce {
+    data: Vec<u8>,
+    offset: usize,
+    start: u64,
+    len: usize,
+}
+
+impl Bounce {
+    fn new(pos: u64, len: usize) -> Self {
+        let start = pos / ALIGN * ALIGN;
+        let end = (pos + len as u64).div_ceil(ALIGN) * ALIGN;
+        let len = (end - start) as usize;
+        let data = vec![0; len + ALIGN as usize];
+        let offset = data.as_ptr().align_offset(ALIGN as usize);
+        Bounce { data, offset, start, len }
+    }
+
+    fn buf(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+impl ReadAt for DirectFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if Self::aligned(pos, buf) {
+            return FileExt::read_at(&self.file, buf, pos);
+        }
+        let mut bounce = Bounce::new(pos, buf.len());
+        let start = bounce.start;
+        let read = self.read_aligned(start, bounce.buf())?;
+        let skip = (pos - start) as usize;
+        let n = min(read.saturating_sub(skip), buf.len());
+        buf[..n].copy_from_slice(&bounce.buf()[skip..skip + n]);
+        Ok(n)
+    }
+}
+
+impl WriteAt for DirectFile {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        if Self::aligned(pos, buf) {
+            return FileExt::write_at(&self.file, buf, pos);
+        }
+        // Fill in the rest of the aligned range from the file. Anything past the end reads as
+        // zeros, and is cut off again afterwards.
+        let mut bounce = Bounce::new(pos, buf.len());
+        let start = bounce.start;
+        let read = self.read_aligned(start, bounce.buf())?;
+        let skip = (pos - start) as usize;
+        bounce.buf()[skip..skip + buf.len()].copy_from_slice(buf);
+        FileExt::write_all_at(&self.file, bounce.buf(), start)?;
+        if read < bounce.len {
+            let end = max(start + read as u64, pos + buf.len() as u64);
+            self.file.set_len(end)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Size for DirectFile {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.file.metadata()?.len()))
+    }
+}
+
+impl Storage for DirectFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
@@ -25,12 +25,39 @@ pub enum Error {
     /// An error was detected in a qcow2 file. The file may be corrupt.
     FileFormat(String),
 
+    /// The requested snapshot does not exist.
+    NoSnapshot(String),
+
+    /// The requested bitmap does not exist.
+    NoBitmap(String),
+
+    /// The contents of the requested bitmap can't be trusted. See `Bitmap::consistent`.
+    InconsistentBitmap(String),
+
     /// An internal error was detected, there must be a bug in this library.
     Internal(String),
+
+    /// The file is too short to hold the tables its header points to, such as when a download
+    /// was cut short.
+    Truncated {
+        /// The size the file should be, at least.
+        expected_at_least: u64,
+        /// The actual size of the file.
+        actual: u64,
+    },
+
+    /// The operation was cancelled, by a `Progress` asking it to stop or by a `CancelToken`.
+    Cancelled,
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
+        // Unwrap errors of ours that went through an `io::Error`, such as from `Reader`.
+        if err.get_ref().is_some_and(|e| e.is::<Error>()) {
+            // Checked just above, so neither can fail.
+            let inner = err.into_inner().expect("wrapped error");
+            return *inner.downcast::<Error>().expect("qcow2 error");
+        }
         Error::Io(err)
     }
 }
@@ -58,14 +85,45 @@ impl Display for Error {
             Error::Version(found) => write!(f, "Unsupported version {}", found),
             Error::UnsupportedFeature(ref feat) => write!(f, "Unsupported feature: {}", feat),
             Error::FileFormat(ref err) => write!(f, "Malformed qcow2 file: {}", err),
+            Error::NoSnapshot(ref name) => write!(f, "No such snapshot: {}", name),
+            Error::NoBitmap(ref name) => write!(f, "No such bitmap: {}", name),
+            Error::InconsistentBitmap(ref name) => write!(f, "Inconsistent bitmap: {}", name),
             Error::Internal(ref err) => write!(f, "Internal error: {}", err),
+            Error::Truncated { expected_at_least, actual } => {
+                write!(f,
+                       "File is truncated: expected at least {} bytes, but it has {}",
+                       expected_at_least,
+                       actual)
+            }
             Error::Poison(ref s) => f.write_str(s),
+            Error::Cancelled => f.write_str("Cancelled"),
         }
     }
 }
 
+impl Error {
+    /// Get the kind of `io::Error` this error becomes.
+    pub fn io_kind(&self) -> ErrorKind {
+        match *self {
+            Error::Io(ref err) => err.kind(),
+            Error::FileType | Error::FileFormat(_) | Error::InconsistentBitmap(_) => {
+                ErrorKind::InvalidData
+            }
+            Error::Version(_) | Error::UnsupportedFeature(_) => ErrorKind::Unsupported,
+            Error::NoSnapshot(_) | Error::NoBitmap(_) => ErrorKind::NotFound,
+            Error::Poison(_) | Error::Internal(_) | Error::Cancelled => ErrorKind::Other,
+            Error::Truncated { .. } => ErrorKind::UnexpectedEof,
+        }
+    }
+}
+
+/// I/O errors are passed through unchanged. Other errors get a matching `ErrorKind`, see
+/// `Error::io_kind`, and can be recovered with `get_ref` and `downcast_ref`.
 impl From<Error> for io::Error {
     fn from(err: Error) -> io::Error {
-        io::Error::new(ErrorKind::Other, err)
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
     }
 }
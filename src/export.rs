@@ -0,0 +1,369 @@
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::sync::{Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use positioned_io::{ReadAt, WriteAt};
+
+use super::{CachePolicy, Progress, Result};
+use super::progress::Reporter;
+use super::read::{L2Entry, Reader};
+
+
+// The least of the virtual disk that each thread reads at once, when exporting in parallel.
+const PARALLEL_CHUNK: u64 = 1 << 20;
+
+// A part of the virtual disk read by an export thread.
+struct Chunk {
+    idx: u64,
+    pos: u64,
+    buf: Vec<u8>,
+    // Whether each cluster can be skipped.
+    zero: Vec<bool>,
+}
+
+
+/// Statistics about exported data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    /// The number of bytes written to the output.
+    pub written: u64,
+    /// The number of bytes of zeros skipped, leaving holes in the output.
+    pub skipped: u64,
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    /// Export the virtual disk as a raw image.
+    ///
+    /// If `sparse` is true, ranges that are unallocated or contain only zeros are skipped, rather
+    /// than written. The output must then already read as zeros, such as a newly created file,
+    /// so a file system that supports it will leave holes there.
+    pub fn export_raw<W: Write + Seek>(&self, out: &mut W, sparse: bool) -> Result<ExportStats> {
+        self.export_raw_with_progress(out, sparse, |_| ())
+    }
+
+    /// Export the virtual disk as a raw image, reporting progress.
+    ///
+    /// This is like `export_raw`, but `progress` is called after each cluster is handled with
+    /// the statistics so far.
+    pub fn export_raw_with_progress<W, F>(&self,
+                                          out: &mut W,
+                                          sparse: bool,
+                                          mut progress: F)
+                                          -> Result<ExportStats>
+        where W: Write + Seek,
+              F: FnMut(&ExportStats)
+    {
+        self.export_raw_until(out, sparse, |stats| {
+            progress(stats);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Export the virtual disk as a raw image, reporting progress to a `Progress`, which may
+    /// cancel the export.
+    ///
+    /// This is like `export_raw`. The physical bytes reported are those written to the output.
+    ///
+    /// If cancelled, this fails with `Error::Cancelled`. The output then holds the virtual disk
+    /// up to the last progress report, and nothing after it. It's not extended to the full size
+    /// of the disk, nor flushed, so it should be discarded, or truncated to the reported size
+    /// and the rest exported again.
+    pub fn export_raw_reporting<W, P>(&self,
+                                      out: &mut W,
+                                      sparse: bool,
+                                      progress: &P)
+                                      -> Result<ExportStats>
+        where W: Write + Seek,
+              P: Progress + ?Sized
+    {
+        let mut reporter = Reporter::new(progress, self.size);
+        let mut result = Ok(());
+        let stats = self.export_raw_until(out, sparse, |stats| {
+            result = reporter.update(stats.written + stats.skipped, stats.written);
+            if result.is_ok() { ControlFlow::Continue(()) } else { ControlFlow::Break(()) }
+        });
+        result?;
+        stats
+    }
+
+    // Export the virtual disk as a raw image, calling `progress` after each cluster. If it
+    // breaks, the export stops.
+    fn export_raw_until<W, F>(&self,
+                              out: &mut W,
+                              sparse: bool,
+                              mut progress: F)
+                              -> Result<ExportStats>
+        where W: Write + Seek,
+              F: FnMut(&ExportStats) -> ControlFlow<()>
+    {
+        let size = self.size;
+        let cluster_size = self.q.cluster_size();
+        let mut buf = vec![0; min(cluster_size, size) as usize];
+        let mut stats = ExportStats::default();
+        let mut out_pos = 0;
+
+        let mut pos = 0;
+        while pos < size {
+            let len = min(cluster_size, size - pos) as usize;
+            let buf = &mut buf[..len];
+            if self.export_cluster(pos, buf, sparse)? {
+                stats.skipped += len as u64;
+            } else {
+                if out_pos != pos {
+                    out.seek(SeekFrom::Start(pos))?;
+                }
+                out.write_all(buf)?;
+                stats.written += len as u64;
+                out_pos = pos + len as u64;
+            }
+            pos += len as u64;
+            if progress(&stats).is_break() {
+                return Ok(stats);
+            }
+        }
+
+        // Make sure the output has the full size, even if it ends with a hole.
+        if out_pos < size {
+            out.seek(SeekFrom::Start(size - 1))?;
+            out.write_all(&[0])?;
+        }
+        out.flush()?;
+        Ok(stats)
+    }
+
+    /// Export the virtual disk as a raw image, reading with several threads.
+    ///
+    /// This is like `export_raw_reporting`, but `parallelism` threads read the image at once,
+    /// which helps on fast storage. The calling thread writes the output in order, and reports
+    /// progress after each chunk of the virtual disk.
+    ///
+    /// If cancelled, this fails with `Error::Cancelled`, and the output is left as
+    /// `export_raw_reporting` leaves it: holding the virtual disk up to the last progress
+    /// report, neither extended to its full size nor flushed.
+    pub fn export_raw_parallel<W, P>(&self,
+                                     out: &mut W,
+                                     sparse: bool,
+                                     parallelism: usize,
+                                     progress: &P)
+                                     -> Result<ExportStats>
+        where I: Sync,
+              C: Sync,
+              W: Write + Seek,
+              P: Progress + ?Sized
+    {
+        let size = self.size;
+        let cluster_size = self.q.cluster_size();
+        let mut reporter = Reporter::new(progress, size);
+        let mut stats = ExportStats::default();
+        let mut out_pos = 0;
+        self.export_chunks(sparse, parallelism, true, |chunk| {
+            for (idx, &zero) in chunk.zero.iter().enumerate() {
+                let start = idx * cluster_size as usize;
+                let buf = &chunk.buf[start..min(start + cluster_size as usize, chunk.buf.len())];
+                let pos = chunk.pos + start as u64;
+                if zero {
+                    stats.skipped += buf.len() as u64;
+                    continue;
+                }
+                if out_pos != pos {
+                    out.seek(SeekFrom::Start(pos))?;
+                }
+                out.write_all(buf)?;
+                stats.written += buf.len() as u64;
+                out_pos = pos + buf.len() as u64;
+            }
+            reporter.update(stats.written + stats.skipped, stats.written)
+        })?;
+
+        if out_pos < size {
+            out.seek(SeekFrom::Start(size - 1))?;
+            out.write_all(&[0])?;
+        }
+        out.flush()?;
+        Ok(stats)
+    }
+
+    /// Export the virtual disk as a raw image, to an output written by position.
+    ///
+    /// This is like `export_raw_parallel`, but each chunk is written as soon as it's read,
+    /// in whatever order the threads finish.
+    ///
+    /// If cancelled, this fails with `Error::Cancelled`. The output then holds the chunks
+    /// written by the last progress report, which needn't be the first ones, so it should be
+    /// discarded.
+    pub fn export_raw_at<W, P>(&self,
+                               out: &mut W,
+                               sparse: bool,
+                               parallelism: usize,
+                               progress: &P)
+                               -> Result<ExportStats>
+        where I: Sync,
+              C: Sync,
+              W: WriteAt,
+              P: Progress + ?Sized
+    {
+        let size = self.size;
+        let cluster_size = self.q.cluster_size() as usize;
+        let mut reporter = Reporter::new(progress, size);
+        let mut stats = ExportStats::default();
+        let mut end = 0;
+        self.export_chunks(sparse, parallelism, false, |chunk| {
+            // Write each run of clusters that aren't skipped at once.
+            let mut idx = 0;
+            while idx < chunk.zero.len() {
+                let run = chunk.zero[idx..].iter().take_while(|&&z| z == chunk.zero[idx]).count();
+                let start = idx * cluster_size;
+                let buf = &chunk.buf[start..min(start + run * cluster_size, chunk.buf.len())];
+                if chunk.zero[idx] {
+                    stats.skipped += buf.len() as u64;
+                } else {
+                    let pos = chunk.pos + start as u64;
+                    out.write_all_at(pos, buf)?;
+                    stats.written += buf.len() as u64;
+                    end = max(end, pos + buf.len() as u64);
+                }
+                idx += run;
+            }
+            reporter.update(stats.written + stats.skipped, stats.written)
+        })?;
+
+        if end < size {
+            out.write_all_at(size - 1, &[0])?;
+        }
+        out.flush()?;
+        Ok(stats)
+    }
+
+    // Read a cluster for export. Returns whether it can be skipped, in which case the buffer
+    // may not be filled.
+    fn export_cluster(&self, pos: u64, buf: &mut [u8], sparse: bool) -> Result<bool> {
+        // Clusters known to be zero don't need to be read.
+        let zero = match self.q.l2_entry_read(&self.l1, pos)? {
+            L2Entry::Empty if self.q.backing().is_none() => sparse,
+            L2Entry::Zero { .. } |
+            L2Entry::Standard { zero: true, .. } => sparse,
+            _ => false,
+        };
+        if zero {
+            return Ok(true);
+        }
+        self.read_exact_at(pos, buf)?;
+        Ok(sparse && buf.iter().all(|&b| b == 0))
+    }
+
+    fn export_chunk(&self, chunk: &mut Chunk, chunk_size: u64, sparse: bool) -> Result<()> {
+        let cluster_size = self.q.cluster_size();
+        chunk.pos = chunk.idx * chunk_size;
+        let len = min(chunk_size, self.size - chunk.pos);
+        chunk.buf.resize(len as usize, 0);
+        chunk.zero.clear();
+        for buf in chunk.buf.chunks_mut(cluster_size as usize) {
+            let pos = chunk.pos + chunk.zero.len() as u64 * cluster_size;
+            chunk.zero.push(self.export_cluster(pos, buf, sparse)?);
+        }
+        Ok(())
+    }
+
+    // Read the virtual disk in chunks with several threads, and pass each chunk to `emit` on the
+    // calling thread. If `ordered`, chunks are passed in order.
+    //
+    // Threads never get more than a few chunks ahead of `emit`, so memory use is bounded.
+    fn export_chunks<F>(&self, sparse: bool, parallelism: usize, ordered: bool, mut emit: F)
+                        -> Result<()>
+        where I: Sync,
+              C: Sync,
+              F: FnMut(&Chunk) -> Result<()>
+    {
+        let parallelism = max(parallelism, 1);
+        let chunk_size = max(self.q.cluster_size(), PARALLEL_CHUNK);
+        let chunks = self.size.div_ceil(chunk_size);
+        let window = parallelism as u64 * 2;
+
+        let next = AtomicU64::new(0);
+        let failed = AtomicBool::new(false);
+        // The number of chunks emitted so far, when they're in order.
+        let emitted = (Mutex::new(0), Condvar::new());
+        // Buffers no longer in use, so threads don't need to allocate new ones.
+        let free = Mutex::new(Vec::new());
+        let (tx, rx) = mpsc::sync_channel(window as usize);
+
+        thread::scope(|scope| {
+            for _ in 0..parallelism {
+                let tx = tx.clone();
+                let (next, failed, emitted, free) = (&next, &failed, &emitted, &free);
+                scope.spawn(move || {
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::SeqCst);
+                        if idx >= chunks || failed.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        if ordered {
+                            let mut done = emitted.0.lock().unwrap();
+                            while idx >= *done + window && !failed.load(Ordering::SeqCst) {
+                                done = emitted.1.wait(done).unwrap();
+                            }
+                        }
+                        let buf = free.lock().unwrap().pop().unwrap_or_default();
+                        let mut chunk = Chunk { idx, pos: 0, buf, zero: Vec::new() };
+                        let result = self.export_chunk(&mut chunk, chunk_size, sparse)
+                            .map(|_| chunk);
+                        let err = result.is_err();
+                        if tx.send(result).is_err() || err {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            let mut result = Ok(());
+            let mut pending = BTreeMap::new();
+            let mut done = 0;
+            for chunk in rx {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                };
+                let mut ready = vec![chunk];
+                if ordered {
+                    pending.insert(ready[0].idx, ready.pop().unwrap());
+                    while let Some(chunk) = pending.remove(&done) {
+                        ready.push(chunk);
+                        done += 1;
+                    }
+                }
+                for chunk in ready {
+                    if let Err(e) = emit(&chunk) {
+                        result = Err(e);
+                        break;
+                    }
+                    free.lock().unwrap().push(chunk.buf);
+                }
+                if result.is_err() {
+                    break;
+                }
+                if ordered {
+                    *emitted.0.lock().unwrap() = done;
+                    emitted.1.notify_all();
+                }
+            }
+
+            // Stop the threads early, if something failed. Threads check this with the lock held,
+            // so none can miss the notification.
+            let _done = emitted.0.lock().unwrap();
+            failed.store(true, Ordering::SeqCst);
+            emitted.1.notify_all();
+            result
+        })
+    }
+}
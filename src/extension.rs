@@ -6,29 +6,52 @@ use std::result;
 use positioned_io::ReadInt;
 
 use super::{Result, Error};
-use super::feature::{FeatureKind, FEATURE_KIND_COUNT};
+use super::feature::FeatureKind;
+use super::header::{AUTOCLEAR_NAMES, COMPATIBLE_NAMES, INCOMPATIBLE_NAMES};
 
 
 pub const EXT_CODE_FEATURE_NAME_TABLE: u32 = 0x6803f857;
+pub const EXT_CODE_BACKING_FORMAT: u32 = 0xe2792aca;
+pub const EXT_CODE_DATA_FILE: u32 = 0x44415441;
+pub const EXT_CODE_BITMAPS: u32 = 0x23852875;
 pub const EXT_CODE_NONE: u32 = 0;
 
+// The length of the name in a feature name table entry.
+const FEATURE_NAME_LEN: usize = 46;
+
 pub trait Extension: Debug {
     fn extension_code(&self) -> u32;
     fn read(&mut self, io: &mut dyn ReadInt) -> Result<()>;
+    // Append the contents of the extension to a buffer, without the code, length or padding.
+    fn write(&self, buf: &mut Vec<u8>);
 }
 
 
+/// A header extension this library doesn't understand.
+///
+/// These are kept as they are, and written back in the same order when the header is rewritten.
+#[derive(Clone)]
 pub struct UnknownExtension {
     code: u32,
     data: Vec<u8>,
 }
 impl UnknownExtension {
-    pub fn new(code: u32) -> Self {
+    pub(crate) fn new(code: u32) -> Self {
         UnknownExtension {
             code,
             data: vec![],
         }
     }
+
+    /// Get the code identifying the type of this extension.
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    /// Get the contents of this extension, without its padding.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
 }
 impl Extension for UnknownExtension {
     fn extension_code(&self) -> u32 {
@@ -38,6 +61,9 @@ impl Extension for UnknownExtension {
         io.read_to_end(&mut self.data)?;
         Ok(())
     }
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.data);
+    }
 }
 impl Debug for UnknownExtension {
     fn fmt(&self, fmt: &mut Formatter) -> result::Result<(), fmt::Error> {
@@ -48,23 +74,129 @@ impl Debug for UnknownExtension {
     }
 }
 
-#[derive(Debug)]
-pub struct FeatureName {
-    kind: u8,
+// The format of the backing file, eg: "qcow2" or "raw".
+#[derive(Debug, Default, Clone)]
+pub struct BackingFormat(pub Option<String>);
+impl Extension for BackingFormat {
+    fn extension_code(&self) -> u32 {
+        EXT_CODE_BACKING_FORMAT
+    }
+    fn read(&mut self, io: &mut dyn ReadInt) -> Result<()> {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf)?;
+        self.0 = Some(String::from_utf8_lossy(&buf).into_owned());
+        Ok(())
+    }
+    fn write(&self, buf: &mut Vec<u8>) {
+        if let Some(ref s) = self.0 {
+            buf.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+// The name of the external data file.
+#[derive(Debug, Default, Clone)]
+pub struct DataFileName(pub Option<String>);
+impl Extension for DataFileName {
+    fn extension_code(&self) -> u32 {
+        EXT_CODE_DATA_FILE
+    }
+    fn read(&mut self, io: &mut dyn ReadInt) -> Result<()> {
+        let mut buf = Vec::new();
+        io.read_to_end(&mut buf)?;
+        self.0 = Some(String::from_utf8_lossy(&buf).into_owned());
+        Ok(())
+    }
+    fn write(&self, buf: &mut Vec<u8>) {
+        if let Some(ref s) = self.0 {
+            buf.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+// Where to find the bitmap directory.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BitmapDirectory {
+    pub nb_bitmaps: u32,
+    pub size: u64,
+    pub offset: u64,
+}
+
+// The bitmaps extension.
+#[derive(Debug, Default, Clone)]
+pub struct Bitmaps(pub Option<BitmapDirectory>);
+impl Extension for Bitmaps {
+    fn extension_code(&self) -> u32 {
+        EXT_CODE_BITMAPS
+    }
+    fn read(&mut self, io: &mut dyn ReadInt) -> Result<()> {
+        let nb_bitmaps = io.read_u32()?;
+        if io.read_u32()? != 0 {
+            return Err(Error::FileFormat("reserved field used in bitmaps extension".to_owned()));
+        }
+        let size = io.read_u64()?;
+        let offset = io.read_u64()?;
+        self.0 = Some(BitmapDirectory { nb_bitmaps, size, offset });
+        Ok(())
+    }
+    fn write(&self, buf: &mut Vec<u8>) {
+        if let Some(ref d) = self.0 {
+            buf.extend_from_slice(&d.nb_bitmaps.to_be_bytes());
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            buf.extend_from_slice(&d.size.to_be_bytes());
+            buf.extend_from_slice(&d.offset.to_be_bytes());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct FeatureName {
+    kind: FeatureKind,
     bit: u8,
     name: String,
 }
-#[derive(Debug, Default)]
+
+/// The names of features, as declared by the creator of a qcow2 image.
+///
+/// Images may contain a "feature name table" header extension, describing the bits that may be
+/// used in the feature bitmasks. This allows us to report useful information about unknown
+/// features.
+///
+/// With the `serde` feature, this is serialized as a list of entries with a kind, bit and name.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FeatureNameTable(Vec<FeatureName>);
 impl FeatureNameTable {
-    pub fn name(&self, kind: FeatureKind, bit: u8) -> Cow<String> {
+    /// Get the name of a feature bit.
+    ///
+    /// If the table doesn't name this bit, a generic description is returned instead.
+    pub fn name(&self, kind: FeatureKind, bit: u8) -> Cow<'_, str> {
         for n in &self.0 {
-            if n.kind == kind as u8 && n.bit == bit {
+            if n.kind == kind && n.bit == bit {
                 return Cow::Borrowed(&n.name);
             }
         }
         Cow::Owned(format!("bit {} of {:?}", bit, kind))
     }
+
+    /// Iterate over the entries in this table, in the order they appear in the image.
+    ///
+    /// Each item is the kind of feature, the bit number, and the name of the bit. Bits are
+    /// included whether or not they are set in the image.
+    pub fn iter(&self) -> impl Iterator<Item = (FeatureKind, u8, &str)> {
+        self.0.iter().map(|n| (n.kind, n.bit, n.name.as_str()))
+    }
+
+    /// Get the number of entries in this table.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check whether this table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 impl Extension for FeatureNameTable {
     fn extension_code(&self) -> u32 {
@@ -76,10 +208,9 @@ impl Extension for FeatureNameTable {
                 Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
                 Err(e) => return Err(Error::Io(e)),
                 Ok(kind) => {
-                    if kind >= FEATURE_KIND_COUNT as u8 {
-                        return Err(Error::FileFormat("unknown feature type in feature name table"
-                            .to_owned()));
-                    }
+                    let kind = FeatureKind::from_u8(kind).ok_or_else(|| {
+                        Error::FileFormat("unknown feature type in feature name table".to_owned())
+                    })?;
 
                     let bit = io.read_u8()?;
                     if bit > 63 {
@@ -87,22 +218,13 @@ impl Extension for FeatureNameTable {
                             .to_owned()));
                     }
 
-                    let mut buf = [0; 46];
+                    let mut buf = [0; FEATURE_NAME_LEN];
                     io.read_exact(&mut buf)?;
                     // Remove trailing zero bytes from name.
-                    let chars = buf.iter()
-                        .take_while(|&&c| c != 0).copied()
-                        .collect::<Vec<_>>();
-                    // Error on non-ASCII characters, are those supported?
-                    match chars.iter().find(|c| !c.is_ascii()) {
-                        None => {}
-                        Some(_) => {
-                            return Err(Error::FileFormat("unsafe characters in feature name table"
-                                .to_owned()))
-                        }
-                    }
-                    // This can't fail!
-                    let name = String::from_utf8(chars).unwrap();
+                    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+                    // Names are usually ASCII, but the spec doesn't require it. Accept UTF-8,
+                    // and replace anything invalid rather than rejecting the whole image.
+                    let name = String::from_utf8_lossy(&buf[..len]).into_owned();
                     self.0.push(FeatureName {
                         kind,
                         bit,
@@ -113,4 +235,87 @@ impl Extension for FeatureNameTable {
         }
         Ok(())
     }
+    fn write(&self, buf: &mut Vec<u8>) {
+        for n in &self.0 {
+            buf.push(n.kind as u8);
+            buf.push(n.bit);
+            let mut name = [0; FEATURE_NAME_LEN];
+            name[..n.name.len()].copy_from_slice(n.name.as_bytes());
+            buf.extend_from_slice(&name);
+        }
+    }
+}
+
+/// A builder for feature name tables, to write to images.
+///
+/// The table starts with entries for every feature this crate knows about. Custom entries can
+/// be added, or replace known ones.
+///
+/// # Examples
+///
+/// ```
+/// use qcow2::{FeatureKind, FeatureNameTableBuilder};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let table = FeatureNameTableBuilder::new()
+///     .feature(FeatureKind::Compatible, 5, "frobnication")
+///     .build()?;
+/// assert_eq!(table.name(FeatureKind::Compatible, 5), "frobnication");
+/// assert_eq!(table.name(FeatureKind::Incompatible, 0), "dirty");
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FeatureNameTableBuilder {
+    entries: Vec<(FeatureKind, u8, String)>,
+}
+
+impl Default for FeatureNameTableBuilder {
+    fn default() -> Self {
+        let known = [(FeatureKind::Incompatible, INCOMPATIBLE_NAMES),
+                     (FeatureKind::Compatible, COMPATIBLE_NAMES),
+                     (FeatureKind::Autoclear, AUTOCLEAR_NAMES)];
+        let entries = known.iter()
+            .flat_map(|&(kind, names)| {
+                names.iter().enumerate().map(move |(bit, &name)| (kind, bit as u8, name.to_owned()))
+            })
+            .collect();
+        FeatureNameTableBuilder { entries }
+    }
+}
+
+impl FeatureNameTableBuilder {
+    /// Create a builder with entries for the known features.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name a feature bit, replacing any existing name for it.
+    pub fn feature(&mut self, kind: FeatureKind, bit: u8, name: &str) -> &mut Self {
+        self.entries.retain(|e| e.0 != kind || e.1 != bit);
+        self.entries.push((kind, bit, name.to_owned()));
+        self
+    }
+
+    /// Build the table.
+    ///
+    /// Bits must be at most 63, and names at most 46 bytes long.
+    pub fn build(&self) -> Result<FeatureNameTable> {
+        let mut names = Vec::new();
+        for &(kind, bit, ref name) in &self.entries {
+            if bit > 63 {
+                return Err(Error::UnsupportedFeature(format!("feature bit {}", bit)));
+            }
+            if name.len() > FEATURE_NAME_LEN {
+                return Err(Error::UnsupportedFeature(format!("feature names longer than {} \
+                                                              bytes",
+                                                             FEATURE_NAME_LEN)));
+            }
+            names.push(FeatureName {
+                kind,
+                bit,
+                name: name.clone(),
+            });
+        }
+        Ok(FeatureNameTable(names))
+    }
 }
@@ -0,0 +1,208 @@
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+
+use positioned_io::{ReadAt, Size, WriteAt};
+
+use super::write::Storage;
+
+
+/// An operation on storage, as recorded by `FailpointIo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOp {
+    /// A read of some bytes at an offset.
+    Read {
+        /// Where the read started.
+        pos: u64,
+        /// The size of the buffer read into.
+        len: u64,
+    },
+    /// A write of some bytes at an offset.
+    Write {
+        /// Where the write started.
+        pos: u64,
+        /// The number of bytes asked to be written.
+        len: u64,
+    },
+    /// A call to `WriteAt::flush`.
+    Flush,
+    /// A call to `Storage::sync`.
+    Sync,
+    /// A call to `Storage::set_len`.
+    SetLen(u64),
+}
+
+/// Something that can go wrong with a write, see `FailpointIo::fail_write`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with an error, writing nothing.
+    Error,
+    /// Claim success, while writing nothing.
+    Drop,
+    /// Write only this many bytes, returning a short count.
+    Short(usize),
+    /// Write nothing, and fail this and every later operation, as if the machine crashed.
+    Crash,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    journal: Vec<IoOp>,
+    writes: u64,
+    crashed: bool,
+}
+
+/// Storage that can be told to fail, for testing what happens when writes go wrong.
+///
+/// Writes are numbered from zero, in the order they happen. Any of them can be made to fail with
+/// `fail_write`. Every operation is recorded in a journal, so tests can check what was done.
+/// Opening an image with `&mut FailpointIo` keeps it around, to look at afterwards.
+///
+/// # Examples
+///
+/// Find out if an image survives a crash at each point while writing:
+///
+/// ```
+/// use positioned_io::WriteAt;
+/// use qcow2::{Fault, FailpointIo, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let image = std::fs::read("tests/test.qcow2")?;
+/// let write = |io: &mut FailpointIo<Vec<u8>>| -> qcow2::Result<()> {
+///     let mut qcow = Qcow2::open(io)?;
+///     qcow.writer()?.write_all_at(0, b"data")?;
+///     qcow.sync()
+/// };
+/// let mut io = FailpointIo::new(image.clone());
+/// write(&mut io)?;
+/// for n in 0..io.writes() {
+///     let mut io = FailpointIo::new(image.clone());
+///     io.fail_write(n, Fault::Crash);
+///     assert!(write(&mut io).is_err());
+///     assert_eq!(Qcow2::open(io.into_inner())?.check()?.corruptions, 0);
+/// }
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug)]
+pub struct FailpointIo<I> {
+    inner: I,
+    faults: BTreeMap<u64, Fault>,
+    state: Mutex<State>,
+}
+
+impl<I> FailpointIo<I> {
+    /// Wrap some storage, with no faults.
+    pub fn new(inner: I) -> Self {
+        FailpointIo {
+            inner,
+            faults: BTreeMap::new(),
+            state: Default::default(),
+        }
+    }
+
+    /// Make a write go wrong.
+    ///
+    /// The write numbered `n`, counting from zero, will fail in the way `fault` says.
+    pub fn fail_write(&mut self, n: u64, fault: Fault) -> &mut Self {
+        self.faults.insert(n, fault);
+        self
+    }
+
+    /// Get every operation so far, in order.
+    pub fn journal(&self) -> Vec<IoOp> {
+        self.state().journal.clone()
+    }
+
+    /// Get the number of writes so far, including ones that failed.
+    pub fn writes(&self) -> u64 {
+        self.state().writes
+    }
+
+    /// Whether a `Fault::Crash` has happened.
+    pub fn crashed(&self) -> bool {
+        self.state().crashed
+    }
+
+    /// Get the underlying storage.
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    /// Stop injecting faults, and get the underlying storage back.
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // Nothing can panic while the state is locked.
+        self.state.lock().unwrap()
+    }
+
+    // Record an operation, failing if we've crashed.
+    fn record(&self, op: IoOp) -> io::Result<()> {
+        let mut state = self.state();
+        state.journal.push(op);
+        if state.crashed {
+            return Err(io::Error::other("storage crashed"));
+        }
+        Ok(())
+    }
+}
+
+impl<I: ReadAt> ReadAt for FailpointIo<I> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.record(IoOp::Read {
+            pos,
+            len: buf.len() as u64,
+        })?;
+        self.inner.read_at(pos, buf)
+    }
+}
+
+impl<I: WriteAt> WriteAt for FailpointIo<I> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.record(IoOp::Write {
+            pos,
+            len: buf.len() as u64,
+        })?;
+        let n = {
+            let mut state = self.state();
+            state.writes += 1;
+            state.writes - 1
+        };
+        match self.faults.get(&n) {
+            None => self.inner.write_at(pos, buf),
+            Some(&Fault::Error) => Err(io::Error::other(format!("write {} failed", n))),
+            Some(&Fault::Drop) => Ok(buf.len()),
+            Some(&Fault::Short(len)) => self.inner.write_at(pos, &buf[..min(len, buf.len())]),
+            Some(&Fault::Crash) => {
+                self.state().crashed = true;
+                Err(io::Error::other("storage crashed"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.record(IoOp::Flush)?;
+        self.inner.flush()
+    }
+}
+
+impl<I: Size> Size for FailpointIo<I> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.inner.size()
+    }
+}
+
+impl<I: Storage> Storage for FailpointIo<I> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.record(IoOp::Sync)?;
+        self.inner.sync()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.record(IoOp::SetLen(len))?;
+        self.inner.set_len(len)
+    }
+}
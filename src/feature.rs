@@ -1,15 +1,36 @@
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
 use super::{Result, Error};
-use super::extension::FeatureNameTable;
+use super::extension::{EXT_CODE_FEATURE_NAME_TABLE, Extension, FeatureNameTable};
+use super::header::{INCOMPATIBLE_CORRUPT, MAGIC};
+use super::layout::RawHeader;
 
-#[derive(Debug, Clone, Copy)]
+/// The kinds of feature bits a qcow2 image can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum FeatureKind {
+    /// Features that must be understood to open the image.
     Incompatible = 0,
+    /// Features that can be safely ignored.
     Compatible = 1,
+    /// Features that must be cleared by writers that don't understand them.
     Autoclear = 2,
 }
-pub const FEATURE_KIND_COUNT: usize = 3;
+impl FeatureKind {
+    pub(crate) fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(FeatureKind::Incompatible),
+            1 => Some(FeatureKind::Compatible),
+            2 => Some(FeatureKind::Autoclear),
+            _ => None,
+        }
+    }
+}
 
 // We can't use bitflags, since there may be unknown bits.
+#[derive(Clone)]
 pub struct Feature {
     bits: u64,
     kind: FeatureKind,
@@ -61,8 +82,8 @@ impl Feature {
         }
     }
 
-    // Show a nice representation of a feature set.
-    pub fn to_string(&self, table: &FeatureNameTable) -> String {
+    // Get the names of all enabled features.
+    pub fn names(&self, table: &FeatureNameTable) -> Vec<String> {
         let known = self.names.len();
         let mut pos = 0;
         let mut bits = self.bits;
@@ -85,6 +106,81 @@ impl Feature {
             pos += 1;
         }
 
-        descs.join(" | ")
+        descs
+    }
+
+    // Show a nice representation of a feature set.
+    pub fn to_string(&self, table: &FeatureNameTable) -> String {
+        self.names(table).join(" | ")
+    }
+}
+
+/// A feature bit that's set in an image, found by `feature_bits`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FeatureBit {
+    /// The kind of feature.
+    pub kind: FeatureKind,
+    /// The bit number, from zero to 63.
+    pub bit: u8,
+    /// Whether this crate understands the bit.
+    pub known: bool,
+    /// The name of the bit, from this crate or else from the image's feature name table.
+    pub name: Option<String>,
+    /// Whether this bit stops `Qcow2::open` from opening the image.
+    pub blocks_open: bool,
+}
+
+/// List the feature bits that are set in an image, without opening it.
+///
+/// This only reads the header, and doesn't care whether the rest of it makes sense. So it
+/// works on images that fail to open because of their features. Bits are listed incompatible
+/// first, then compatible, then autoclear. Version 2 images have no feature bits.
+///
+/// # Examples
+///
+/// ```
+/// # fn foo() -> qcow2::Result<()> {
+/// let bits = qcow2::feature_bits(std::fs::File::open("tests/test.qcow2")?)?;
+/// assert!(bits.iter().all(|b| !b.blocks_open));
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn feature_bits<I: ReadAt>(io: I) -> Result<Vec<FeatureBit>> {
+    let raw = RawHeader::read(&io)?;
+    if raw.header.c.magic != MAGIC {
+        return Err(Error::FileType);
+    }
+    let mut table = FeatureNameTable::default();
+    if let Some(payload) = raw.payload(EXT_CODE_FEATURE_NAME_TABLE) {
+        // Keep whatever names can be read from a broken table.
+        let _ = table.read(&mut ByteIo::<_, BigEndian>::new(payload));
+    }
+
+    let mut found = Vec::new();
+    if !raw.v3 {
+        return Ok(found);
+    }
+    let v3 = &raw.header.v3;
+    for feature in &[&v3.incompatible, &v3.compatible, &v3.autoclear] {
+        for bit in (0..64).filter(|&b| feature.enabled(1 << b)) {
+            let known = (bit as usize) < feature.names.len();
+            let name = if known {
+                Some(feature.names[bit as usize].to_owned())
+            } else {
+                table.iter()
+                    .find(|&(k, b, _)| k == feature.kind && b == bit)
+                    .map(|(_, _, n)| n.to_owned())
+            };
+            let blocks_open = feature.kind == FeatureKind::Incompatible &&
+                              (!known || 1 << bit == INCOMPATIBLE_CORRUPT);
+            found.push(FeatureBit {
+                kind: feature.kind,
+                bit,
+                known,
+                name,
+                blocks_open,
+            });
+        }
     }
+    Ok(found)
 }
@@ -0,0 +1,113 @@
+use std::cmp::min;
+use std::mem::size_of;
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Qcow2};
+use super::diff::GuestRange;
+use super::int::{div_ceil, div_rem};
+
+
+// The largest L1 table qemu will accept, 32 MiB of entries.
+pub(crate) const MAX_L1_ENTRIES: u64 = (32 << 20) / size_of::<u64>() as u64;
+
+/// How a qcow2 image maps its virtual disk onto clusters and tables.
+///
+/// Tools that want their I/O to line up with clusters or L2 tables, such as when picking
+/// backup chunk sizes, can use this rather than redoing the arithmetic.
+///
+/// # Examples
+///
+/// ```
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = qcow2::Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let geometry = qcow.geometry();
+/// let (l1_index, l2_index, offset) = geometry.split(geometry.l2_coverage() + 5);
+/// assert_eq!((l1_index, l2_index, offset), (1, 0, 5));
+/// # Ok(())
+/// # }
+/// # foo().unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    cluster_bits: u32,
+    size: u64,
+}
+
+impl Geometry {
+    pub(crate) fn new(cluster_bits: u32, size: u64) -> Self {
+        Geometry { cluster_bits, size }
+    }
+
+    /// Get the size of each cluster, in bytes.
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Get the size of the virtual disk, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the number of entries in each L2 table.
+    pub fn l2_entries(&self) -> u64 {
+        self.cluster_size() / size_of::<u64>() as u64
+    }
+
+    /// Get the number of bytes of the virtual disk mapped by each L2 table.
+    pub fn l2_coverage(&self) -> u64 {
+        self.l2_entries() * self.cluster_size()
+    }
+
+    /// Get the largest virtual disk supported with this cluster size, in bytes.
+    ///
+    /// This is as much as an L1 table of the largest size qemu accepts, 32 MiB, can map: 2 PiB
+    /// with the default 64 KiB clusters, and 8 EiB with the largest clusters. Images claiming to
+    /// be bigger fail to open.
+    pub fn max_size(&self) -> u64 {
+        MAX_L1_ENTRIES * self.l2_coverage()
+    }
+
+    /// Get the number of clusters in the virtual disk, counting a partial one at the end.
+    pub fn clusters(&self) -> u64 {
+        div_ceil(self.size, self.cluster_size())
+    }
+
+    /// Get the number of L1 entries needed to map the whole virtual disk.
+    pub fn l1_entries(&self) -> u64 {
+        div_ceil(self.clusters(), self.l2_entries())
+    }
+
+    /// Split an offset in the virtual disk into its index in the L1 table, its index in the L2
+    /// table, and its offset within the cluster.
+    ///
+    /// The offset may be past the end of the disk, the result then refers to where it would be.
+    pub fn split(&self, guest_offset: u64) -> (u64, u64, u64) {
+        let (cluster, offset) = div_rem(guest_offset, self.cluster_size());
+        let (l1_index, l2_index) = div_rem(cluster, self.l2_entries());
+        (l1_index, l2_index, offset)
+    }
+
+    /// Get the range of the virtual disk in the same cluster as an offset.
+    ///
+    /// The last cluster may be cut short by the end of the disk. Returns `None` if the offset
+    /// is past the end.
+    pub fn cluster_containing(&self, guest_offset: u64) -> Option<GuestRange> {
+        if guest_offset >= self.size {
+            return None;
+        }
+        let offset = guest_offset - guest_offset % self.cluster_size();
+        let len = min(self.cluster_size(), self.size - offset);
+        Some(GuestRange { offset, len })
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the layout of this image's clusters and tables.
+    pub fn geometry(&self) -> Geometry {
+        self.header().geometry()
+    }
+}
@@ -1,30 +1,55 @@
+use std::cmp::{max, min};
 use std::collections::HashSet;
+#[cfg(unix)]
 use std::ffi::OsStr;
 use std::fmt::{self, Debug, Formatter};
-use std::io::Read;
-use std::mem::size_of;
-use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 use std::result;
 
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 
 use byteorder::BigEndian;
-use positioned_io::{ByteIo, ReadAt, ReadInt, Cursor};
+use positioned_io::{ByteIo, ReadAt, ReadInt, Cursor, WriteInt};
 
-use super::{Result, Error};
-use super::int::{is_multiple_of, padding_to_multiple, div_ceil, div_rem};
-use super::extension::{self, Extension, FeatureNameTable, UnknownExtension};
+use super::{CheckFinding, Geometry, Qcow2, Result, Error};
+use super::int::padding_to_multiple;
+use super::extension::{self, BackingFormat, Bitmaps, DataFileName, Extension, FeatureNameTable,
+                       UnknownExtension};
 use super::feature::{Feature, FeatureKind};
 
-const MAGIC: u32 = 0x514649fb;
-const SUPPORTED_VERSION: u32 = 3;
+pub const MAGIC: u32 = 0x514649fb;
+pub const SUPPORTED_VERSION: u32 = 3;
+
+
+// Paths on unix are arbitrary byte sequences, so use them as is.
+#[cfg(unix)]
+fn path_from_bytes(buf: Vec<u8>) -> PathBuf {
+    From::from(OsStr::from_bytes(&buf))
+}
+
+// Elsewhere, paths can't hold arbitrary bytes. Qemu writes UTF-8 names on Windows, so decode
+// them that way, replacing anything invalid.
+#[cfg(not(unix))]
+fn path_from_bytes(buf: Vec<u8>) -> PathBuf {
+    From::from(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
 
 
 // Common header for all versions.
 #[repr(C)]
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct HeaderCommon {
     pub magic: u32,
     pub version: u32,
@@ -41,21 +66,60 @@ pub struct HeaderCommon {
     pub snapshots_offset: u64,
 }
 
-#[allow(dead_code)]
-const INCOMPATIBLE_DIRTY: u64 = 0b1;
-#[allow(dead_code)]
-const INCOMPATIBLE_CORRUPT: u64 = 0b10;
-#[allow(dead_code)]
-const COMPATIBLE_LAZY_REFCOUNTS: u64 = 0b1;
-#[allow(dead_code)]
-const AUTOCLEAR_BITMAPS: u64 = 0b1;
-
-static INCOMPATIBLE_NAMES: &[&str] = &["dirty", "corrupt"];
-static COMPATIBLE_NAMES: &[&str] = &["lazy refcounts"];
-static AUTOCLEAR_NAMES: &[&str] = &["bitmaps"];
-
-const HEADER_LENGTH_V3: usize = 104;
+pub const INCOMPATIBLE_DIRTY: u64 = 0b1;
+pub const INCOMPATIBLE_CORRUPT: u64 = 0b10;
+pub const INCOMPATIBLE_DATA_FILE: u64 = 0b100;
+pub const COMPATIBLE_LAZY_REFCOUNTS: u64 = 0b1;
+pub const AUTOCLEAR_BITMAPS: u64 = 0b1;
+pub const AUTOCLEAR_DATA_FILE_RAW: u64 = 0b10;
+
+pub static INCOMPATIBLE_NAMES: &[&str] = &["dirty", "corrupt", "external data file"];
+pub static COMPATIBLE_NAMES: &[&str] = &["lazy refcounts"];
+pub static AUTOCLEAR_NAMES: &[&str] = &["bitmaps", "raw external data"];
+
+// The fixed fields of the header and their sizes, in order. Fields from
+// `incompatible_features` on are only in version 3.
+pub const FIXED_FIELDS: &[(&str, u64)] = &[("magic", 4),
+                                           ("version", 4),
+                                           ("backing_file_offset", 8),
+                                           ("backing_file_size", 4),
+                                           ("cluster_bits", 4),
+                                           ("size", 8),
+                                           ("crypt_method", 4),
+                                           ("l1_size", 4),
+                                           ("l1_table_offset", 8),
+                                           ("refcount_table_offset", 8),
+                                           ("refcount_table_clusters", 4),
+                                           ("nb_snapshots", 4),
+                                           ("snapshots_offset", 8),
+                                           ("incompatible_features", 8),
+                                           ("compatible_features", 8),
+                                           ("autoclear_features", 8),
+                                           ("refcount_order", 4),
+                                           ("header_length", 4)];
+pub const HEADER_LENGTH_V2: usize = 72;
+pub const HEADER_LENGTH_V3: usize = 104;
+// How much of the file to read at first when reading the header. Headers rarely need more.
+pub const HEADER_READ: usize = 4096;
+// The longest backing file name qemu accepts.
+pub const MAX_BACKING_FILE_NAME: usize = 1023;
+// Limits on header extensions, so hostile images can't make opening slow. Real images have
+// only a few.
+pub const DEFAULT_MAX_EXTENSIONS: usize = 64;
+const MAX_UNKNOWN_EXTENSIONS_SIZE: u64 = 64 * 1024;
+// The end of the compression type field, and its padding.
+pub const COMPRESSION_TYPE_END: u64 = 105;
+pub const COMPRESSION_PADDING_END: u64 = 112;
+
+// Choices for how to read a header.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    pub max_extensions: usize,
+    // Check that bytes which must be zero are, see `OpenOptions::strict`.
+    pub strict: bool,
+}
 
+#[derive(Clone)]
 pub struct HeaderV3 {
     pub incompatible: Feature,
     pub compatible: Feature,
@@ -63,17 +127,33 @@ pub struct HeaderV3 {
 
     pub refcount_order: u32,
     pub header_length: u32,
+    pub additional_fields: Vec<u8>,
 
     pub feature_name_table: FeatureNameTable,
+    pub backing_format: BackingFormat,
+    pub data_file_name: DataFileName,
+    pub bitmaps: Bitmaps,
     pub unknown_extensions: Vec<UnknownExtension>,
+    // The codes of the extensions, in the order they were read.
+    pub extension_order: Vec<u32>,
 
     pub backing_file_name: PathBuf,
 }
 impl HeaderV3 {
+    // Is this the code of an extension we understand?
+    pub fn is_known_extension(&self, code: u32) -> bool {
+        matches!(code,
+                 extension::EXT_CODE_FEATURE_NAME_TABLE | extension::EXT_CODE_BACKING_FORMAT |
+                 extension::EXT_CODE_DATA_FILE | extension::EXT_CODE_BITMAPS)
+    }
+
     // Get an extension by extension code. If we can't find one, use UnknownExtension.
     pub fn extension(&mut self, code: u32) -> &mut dyn Extension {
         match code {
             extension::EXT_CODE_FEATURE_NAME_TABLE => &mut self.feature_name_table,
+            extension::EXT_CODE_BACKING_FORMAT => &mut self.backing_format,
+            extension::EXT_CODE_DATA_FILE => &mut self.data_file_name,
+            extension::EXT_CODE_BITMAPS => &mut self.bitmaps,
             _ => {
                 let u = UnknownExtension::new(code);
                 self.unknown_extensions.push(u);
@@ -93,7 +173,11 @@ impl Debug for HeaderV3 {
                    &self.autoclear.to_string(&self.feature_name_table))
             .field("refcount_order", &self.refcount_order)
             .field("header_length", &self.header_length)
+            .field("additional_fields", &self.additional_fields)
             .field("feature_name_table", &self.feature_name_table)
+            .field("backing_format", &self.backing_format)
+            .field("data_file_name", &self.data_file_name)
+            .field("bitmaps", &self.bitmaps)
             .field("backing_file_name", &self.backing_file_name)
             .field("unknown extensions", &self.unknown_extensions)
             .finish()
@@ -107,18 +191,25 @@ impl Default for HeaderV3 {
             autoclear: Feature::new(FeatureKind::Autoclear, AUTOCLEAR_NAMES),
             refcount_order: 0,
             header_length: 0,
+            additional_fields: Vec::new(),
             backing_file_name: PathBuf::new(),
             feature_name_table: FeatureNameTable::default(),
+            backing_format: BackingFormat::default(),
+            data_file_name: DataFileName::default(),
+            bitmaps: Bitmaps::default(),
             unknown_extensions: Vec::new(),
+            extension_order: Vec::new(),
         }
     }
 }
 
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Header {
     pub c: HeaderCommon,
     pub v3: HeaderV3,
+    // Problems found when reading strictly.
+    pub strict_findings: Vec<CheckFinding>,
 }
 
 impl Header {
@@ -129,32 +220,38 @@ impl Header {
         if self.c.version != SUPPORTED_VERSION {
             return Err(Error::Version(self.c.version));
         }
-        if self.c.backing_file_offset != 0 {
-            return Err(Error::UnsupportedFeature("backing file".to_owned()));
-            // if self.c.backing_file_offset > self.cluster_size() {
-            //     return Err(Error::FileFormat("backing file name not in first cluster"
-            //          .to_owned()));
-            // }
-            // if self.c.backing_file_size > 1023 {
-            //     return Err(Error::FileFormat("backing file name size too big".to_owned()));
-            // }
-        }
         if self.c.cluster_bits < 9 || self.c.cluster_bits > 22 {
             return Err(Error::FileFormat(format!("bad cluster_bits {}", self.c.cluster_bits)));
         }
+        if self.has_backing_file() {
+            // Anything may follow the name in the first cluster.
+            let end = self.c.backing_file_offset.checked_add(self.c.backing_file_size as u64);
+            if end.is_none_or(|end| end > self.cluster_size()) {
+                return Err(Error::FileFormat("backing file name not in first cluster"
+                    .to_owned()));
+            }
+            if self.c.backing_file_size > 1023 {
+                return Err(Error::FileFormat("backing file name size too big".to_owned()));
+            }
+        }
         if self.c.crypt_method != 0 {
             return Err(Error::UnsupportedFeature("encryption".to_owned()));
         }
+        // Past this, the L1 table would be too big, and offsets near the end of the disk could
+        // overflow.
+        if self.c.size > self.geometry().max_size() {
+            return Err(Error::FileFormat(format!("virtual size {} too big", self.c.size)));
+        }
         if self.c.l1_size as u64 != self.l1_entries() {
             return Err(Error::FileFormat("bad L1 entry count".to_owned()));
         }
-        if !is_multiple_of(self.c.l1_table_offset, self.cluster_size()) {
+        if !self.c.l1_table_offset.is_multiple_of(self.cluster_size()) {
             return Err(Error::FileFormat("bad L1 offset".to_owned()));
         }
-        if !is_multiple_of(self.c.refcount_table_offset, self.cluster_size()) {
+        if !self.c.refcount_table_offset.is_multiple_of(self.cluster_size()) {
             return Err(Error::FileFormat("bad refcount offset".to_owned()));
         }
-        if !is_multiple_of(self.c.snapshots_offset, self.cluster_size()) {
+        if !self.c.snapshots_offset.is_multiple_of(self.cluster_size()) {
             return Err(Error::FileFormat("bad snapshots offset".to_owned()));
         }
         Ok(())
@@ -162,6 +259,13 @@ impl Header {
 
     // Read the common header.
     fn read_common<I: Read>(&mut self, io: &mut ByteIo<I, BigEndian>) -> Result<()> {
+        self.read_common_fields(io)?;
+        self.validate_common()?;
+        Ok(())
+    }
+
+    // Read the fields of the common header, without checking them.
+    fn read_common_fields<I: Read>(&mut self, io: &mut ByteIo<I, BigEndian>) -> Result<()> {
         self.c.magic = io.read_u32()?;
         self.c.version = io.read_u32()?;
         self.c.backing_file_offset = io.read_u64()?;
@@ -175,32 +279,66 @@ impl Header {
         self.c.refcount_table_clusters = io.read_u32()?;
         self.c.nb_snapshots = io.read_u32()?;
         self.c.snapshots_offset = io.read_u64()?;
-        self.validate_common()?;
         Ok(())
     }
 
-    fn read_extensions<I: ReadAt>(&mut self, io: &mut ByteIo<Cursor<I>, BigEndian>) -> Result<()> {
+    fn read_extensions<I: ReadAt>(&mut self,
+                                  io: &mut ByteIo<Cursor<I>, BigEndian>,
+                                  opts: ReadOptions)
+                                  -> Result<()> {
         let mut seen = HashSet::<u32>::new();
+        let mut count = 0;
+        let mut unknown_size = 0;
         loop {
             let ext_code = io.read_u32()?;
+            if count >= opts.max_extensions && ext_code != extension::EXT_CODE_NONE {
+                return Err(Error::FileFormat(format!("more than {} header extensions",
+                                                     opts.max_extensions)));
+            }
+            count += 1;
 
-            // No duplicates allowed.
-            if seen.contains(&ext_code) {
-                return Err(Error::FileFormat(format!("duplicate header extension {:#x}",
-                                                     ext_code)));
+            // Duplicates of extensions we understand would be ambiguous. Like qemu, allow
+            // duplicate unknown ones, some tools write them.
+            if !seen.insert(ext_code) {
+                if self.v3.is_known_extension(ext_code) {
+                    return Err(Error::FileFormat(format!("duplicate header extension {:#x}",
+                                                         ext_code)));
+                }
+                if opts.strict {
+                    let msg = format!("duplicate header extension {:#x}", ext_code);
+                    self.strict_finding(io.position() - 4, &msg);
+                } else {
+                    warn!("ignoring duplicate header extension {:#x}", ext_code);
+                }
             }
-            seen.insert(ext_code);
 
             let len = io.read_u32()? as u64;
             if ext_code == extension::EXT_CODE_NONE {
+                if opts.strict && len != 0 {
+                    self.strict_finding(io.position() - 4, "end of header extensions has a length");
+                }
                 break;
             }
 
             if len + io.position() > self.cluster_size() {
-                // Don't try to read too much dynamic data!
+                // Don't try to read too much dynamic data! This is also what qemu allows, it
+                // stops at the first cluster even when clusters are small.
                 return Err(Error::FileFormat("complete header too big for first cluster"
                     .to_owned()));
             }
+            debug!("header extension {:#x} at {:#x}, {} bytes{}",
+                   ext_code,
+                   io.position() - 8,
+                   len,
+                   if self.v3.is_known_extension(ext_code) { "" } else { ", unknown" });
+            self.v3.extension_order.push(ext_code);
+            if !self.v3.is_known_extension(ext_code) {
+                // Unknown extensions are kept so they can be written back, limit their size.
+                unknown_size += len;
+                if unknown_size > MAX_UNKNOWN_EXTENSIONS_SIZE {
+                    return Err(Error::FileFormat("unknown header extensions too big".to_owned()));
+                }
+            }
             {
                 let take = io.take(len);
                 let mut sub = ByteIo::<_, BigEndian>::new(take);
@@ -208,7 +346,7 @@ impl Header {
                 ext.read(&mut sub)?;
 
                 // Verify all is read.
-                let remain = sub.bytes().count();
+                let remain = io::copy(&mut sub, &mut io::sink())?;
                 if remain > 0 {
                     return Err(Error::FileFormat(format!("{} bytes left after reading \
                                                           extension {:#x}",
@@ -218,46 +356,103 @@ impl Header {
             }
 
             // Read padding.
+            let pad_offset = io.position();
             let mut pad = vec![0; padding_to_multiple(len, 8)];
             io.read_exact(&mut pad)?;
+            if opts.strict && pad.iter().any(|&b| b != 0) {
+                let msg = format!("padding of header extension {:#x} is not zero", ext_code);
+                self.strict_finding(pad_offset, &msg);
+            }
         }
         Ok(())
     }
 
+    fn strict_finding(&mut self, offset: u64, message: &str) {
+        self.strict_findings.push(CheckFinding::Invalid {
+            offset,
+            message: message.to_owned(),
+        });
+    }
+
     // Read a filesystem path.
     fn read_path<I: Read>(&mut self, io: &mut ByteIo<I, BigEndian>, len: usize) -> Result<PathBuf> {
         let mut buf = vec![0; len];
         io.read_exact(&mut buf)?;
-
-        if cfg!(unix) {
-            // Paths on unix are arbitrary byte sequences.
-            Ok(From::from(OsStr::from_bytes(&buf)))
-        } else {
-            // On other platforms, who knows what to do with non-UTF8 data in there?
-            let s: String = String::from_utf8_lossy(&buf).into_owned();
-            Ok(From::from(s))
-        }
+        Ok(path_from_bytes(buf))
     }
 
-    // Read the version 3 header.
-    fn read_v3<I: ReadAt>(&mut self, io: &mut ByteIo<Cursor<I>, BigEndian>) -> Result<()> {
+    // Read the fixed fields of the version 3 header, without checking them.
+    fn read_v3_fields<I: Read>(&mut self, io: &mut ByteIo<I, BigEndian>) -> Result<()> {
         self.v3.incompatible.set(io.read_u64()?);
         self.v3.compatible.set(io.read_u64()?);
         self.v3.autoclear.set(io.read_u64()?);
         self.v3.refcount_order = io.read_u32()?;
         self.v3.header_length = io.read_u32()?;
+        Ok(())
+    }
+
+    // Read as many fixed fields of the header as there are, without checking them. Fields
+    // past the end of the buffer are left as zero. Returns whether the version 3 fields were
+    // read.
+    pub fn read_fields(&mut self, buf: &[u8]) -> bool {
+        let mut io: ByteIo<_, BigEndian> = ByteIo::new(buf);
+        self.read_common_fields(&mut io).is_ok() && self.read_v3_fields(&mut io).is_ok()
+    }
+
+    // Read the version 3 header.
+    fn read_v3<I: ReadAt>(&mut self,
+                          io: &mut ByteIo<Cursor<I>, BigEndian>,
+                          opts: ReadOptions)
+                          -> Result<()> {
+        self.read_v3_fields(io)?;
         let actual_length = io.position();
-        self.read_extensions(io)?;
-        if self.c.backing_file_offset != 0 {
-            println!("{}, {}", self.c.backing_file_offset, io.position());
-            if self.c.backing_file_offset != io.position() {
-                return Err(Error::FileFormat("backing file offset not consistent with extensions"
+        if actual_length != HEADER_LENGTH_V3 as u64 {
+            return Err(Error::Internal(format!("header must be {} bytes, but we read {}",
+                                               HEADER_LENGTH_V3,
+                                               actual_length)));
+        }
+
+        // Newer versions of qemu add fields to the end of the header, and pad it.
+        let header_length = self.v3.header_length as u64;
+        if header_length < actual_length {
+            return Err(Error::FileFormat(format!("header is {} bytes, file claims {}",
+                                                 actual_length,
+                                                 header_length)));
+        }
+        if !header_length.is_multiple_of(8) {
+            return Err(Error::FileFormat(format!("header length {} is not a multiple of 8",
+                                                 header_length)));
+        }
+        if header_length > self.cluster_size() {
+            return Err(Error::FileFormat("complete header too big for first cluster".to_owned()));
+        }
+        // Keep any fields we don't understand.
+        let mut additional = vec![0; (header_length - actual_length) as usize];
+        io.read_exact(&mut additional)?;
+        let padding_end = min(header_length, COMPRESSION_PADDING_END);
+        if opts.strict && padding_end > COMPRESSION_TYPE_END {
+            let start = (COMPRESSION_TYPE_END - actual_length) as usize;
+            let end = (padding_end - actual_length) as usize;
+            if additional[start..end].iter().any(|&b| b != 0) {
+                self.strict_finding(COMPRESSION_TYPE_END, "header padding is not zero");
+            }
+        }
+        self.v3.additional_fields = additional;
+
+        self.read_extensions(io, opts)?;
+        if self.has_backing_file() {
+            if self.c.backing_file_offset < io.position() {
+                return Err(Error::FileFormat("backing file name overlaps header extensions"
                     .to_owned()));
             }
+            // There may be a gap after the extensions.
+            let mut pad = vec![0; (self.c.backing_file_offset - io.position()) as usize];
+            io.read_exact(&mut pad)?;
             // Need an extra copy to defeat borrow checker.
             // See https://github.com/rust-lang/rust/issues/29975
             let backing_file_size = self.c.backing_file_size;
             self.v3.backing_file_name = self.read_path(io, backing_file_size as usize)?;
+            debug!("backing file {:?}", self.v3.backing_file_name);
         }
 
         // Validation.
@@ -265,19 +460,14 @@ impl Header {
             return Err(Error::UnsupportedFeature("corrupt bit".to_owned()));
         }
         self.v3.incompatible.ensure_known(&self.v3.feature_name_table)?;
+        if self.data_file_raw() && !self.has_data_file() {
+            return Err(Error::FileFormat("raw external data bit set without an external data \
+                                          file"
+                .to_owned()));
+        }
         if self.v3.refcount_order > 6 {
             return Err(Error::FileFormat(format!("bad refcount_order {}", self.v3.refcount_order)));
         }
-        if self.v3.header_length as u64 != io.position() {
-            return Err(Error::FileFormat(format!("header is {} bytes, file claims {}",
-                                                 io.position(),
-                                                 self.v3.header_length)));
-        }
-        if actual_length != HEADER_LENGTH_V3 as u64 {
-            return Err(Error::Internal(format!("header must be {} bytes, but we read {}",
-                                               HEADER_LENGTH_V3,
-                                               io.position())));
-        }
         if io.position() > self.cluster_size() {
             return Err(Error::FileFormat("complete header too big for first cluster".to_owned()));
         }
@@ -285,18 +475,155 @@ impl Header {
         Ok(())
     }
 
-    pub fn read<I: ReadAt>(&mut self, io: &mut ByteIo<I, BigEndian>) -> Result<()> {
+    pub fn read<I: ReadAt>(&mut self,
+                           io: &mut ByteIo<I, BigEndian>,
+                           opts: ReadOptions)
+                           -> Result<()> {
+        // Reading each field separately would be slow over a network, so read the start of the
+        // file at once and parse it from memory. If the header turns out to be bigger, read
+        // everything it may use: the first cluster, and a backing file name just past it.
+        let mut len = HEADER_READ;
+        loop {
+            let mut buf = vec![0; len];
+            let read = Qcow2::read_partial_at(&**io, 0, &mut buf)?;
+            buf.truncate(read);
+
+            let mut header = Header::default();
+            match header.read_buf(buf, opts) {
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof && read == len &&
+                                         len < header.cluster_size() as usize +
+                                               MAX_BACKING_FILE_NAME => {
+                    len = header.cluster_size() as usize + MAX_BACKING_FILE_NAME;
+                }
+                // The file ends before the header does.
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof && read < len => {
+                    let length = max(header.v3.header_length as usize, HEADER_LENGTH_V3);
+                    return Err(Error::Truncated {
+                        expected_at_least: max(length, read + 1) as u64,
+                        actual: read as u64,
+                    });
+                }
+                r => {
+                    r?;
+                    debug!("read qcow2 header: version {}, cluster size {}, virtual size {}, \
+                            header length {}",
+                           header.c.version,
+                           header.cluster_size(),
+                           header.guest_size(),
+                           header.v3.header_length);
+                    *self = header;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Parse the header from the start of the file.
+    fn read_buf(&mut self, buf: Vec<u8>, opts: ReadOptions) -> Result<()> {
         // The headers are best read sequentially, rather than positioned.
         // So get a sequential cursor to read from.
-        let curs = Cursor::new(io.deref_mut());
-        let mut io: ByteIo<_, BigEndian> = ByteIo::new(curs);
-
-        // Read the header.
+        let mut io: ByteIo<_, BigEndian> = ByteIo::new(Cursor::new(buf));
         self.read_common(&mut io)?;
-        self.read_v3(&mut io)?;
+        self.read_v3(&mut io, opts)?;
         Ok(())
     }
 
+    // Write a header extension, with its code, length and padding.
+    fn write_extension<W: Write>(io: &mut ByteIo<W, BigEndian>, ext: &dyn Extension) -> Result<()> {
+        let mut payload = Vec::new();
+        ext.write(&mut payload);
+        io.write_u32(ext.extension_code())?;
+        io.write_u32(payload.len() as u32)?;
+        io.write_all(&payload)?;
+        io.write_all(&vec![0; padding_to_multiple(payload.len() as u64, 8)])?;
+        Ok(())
+    }
+
+    // Serialize the header, its extensions and the backing file name, filling the first cluster.
+    //
+    // The backing file name goes right after the extensions, wherever it was before.
+    pub fn write(&self) -> Result<Vec<u8>> {
+        let mut known: Vec<&dyn Extension> = Vec::new();
+        if self.v3.backing_format.0.is_some() {
+            known.push(&self.v3.backing_format);
+        }
+        if self.v3.data_file_name.0.is_some() {
+            known.push(&self.v3.data_file_name);
+        }
+        if !self.v3.feature_name_table.is_empty() {
+            known.push(&self.v3.feature_name_table);
+        }
+        if self.v3.bitmaps.0.is_some() {
+            known.push(&self.v3.bitmaps);
+        }
+
+        // Extensions go back in the order they were read, so ones we don't understand keep
+        // their place. Any that are new go at the end.
+        let mut ext = ByteIo::<_, BigEndian>::new(Vec::new());
+        let mut unknown = self.v3.unknown_extensions.iter();
+        for &code in &self.v3.extension_order {
+            if !self.v3.is_known_extension(code) {
+                if let Some(u) = unknown.next() {
+                    Self::write_extension(&mut ext, u)?;
+                }
+            } else if let Some(i) = known.iter().position(|e| e.extension_code() == code) {
+                Self::write_extension(&mut ext, known.remove(i))?;
+            }
+        }
+        for e in known {
+            Self::write_extension(&mut ext, e)?;
+        }
+        for u in unknown {
+            Self::write_extension(&mut ext, u)?;
+        }
+        ext.write_u32(extension::EXT_CODE_NONE)?;
+        ext.write_u32(0)?;
+        let ext = ext.to_vec();
+
+        let backing = if self.has_backing_file() {
+            path_to_bytes(&self.v3.backing_file_name)
+        } else {
+            Vec::new()
+        };
+        let backing_offset = if backing.is_empty() {
+            0
+        } else {
+            (HEADER_LENGTH_V3 + self.v3.additional_fields.len() + ext.len()) as u64
+        };
+
+        let mut io = ByteIo::<_, BigEndian>::new(Vec::new());
+        let c = &self.c;
+        io.write_u32(c.magic)?;
+        io.write_u32(c.version)?;
+        io.write_u64(backing_offset)?;
+        io.write_u32(backing.len() as u32)?;
+        io.write_u32(c.cluster_bits)?;
+        io.write_u64(c.size)?;
+        io.write_u32(c.crypt_method)?;
+        io.write_u32(c.l1_size)?;
+        io.write_u64(c.l1_table_offset)?;
+        io.write_u64(c.refcount_table_offset)?;
+        io.write_u32(c.refcount_table_clusters)?;
+        io.write_u32(c.nb_snapshots)?;
+        io.write_u64(c.snapshots_offset)?;
+        io.write_u64(self.v3.incompatible.bits())?;
+        io.write_u64(self.v3.compatible.bits())?;
+        io.write_u64(self.v3.autoclear.bits())?;
+        io.write_u32(self.v3.refcount_order)?;
+        io.write_u32((HEADER_LENGTH_V3 + self.v3.additional_fields.len()) as u32)?;
+        io.write_all(&self.v3.additional_fields)?;
+        io.write_all(&ext)?;
+        io.write_all(&backing)?;
+
+        let mut buf = io.to_vec();
+        if buf.len() as u64 > self.cluster_size() {
+            return Err(Error::UnsupportedFeature("header too big for the first cluster"
+                .to_owned()));
+        }
+        buf.resize(self.cluster_size() as usize, 0);
+        Ok(buf)
+    }
+
     // How big is each cluster, in bytes?
     pub fn cluster_size(&self) -> u64 {
         1 << self.c.cluster_bits
@@ -307,26 +634,39 @@ impl Header {
         self.c.size
     }
 
-    // How many virtual blocks can there be?
-    pub fn max_virtual_blocks(&self) -> u64 {
-        div_ceil(self.c.size, self.cluster_size())
+    // How is the guest laid out in clusters and tables?
+    pub fn geometry(&self) -> Geometry {
+        Geometry::new(self.c.cluster_bits, self.c.size)
     }
 
     // How many entries are in an L2?
     pub fn l2_entries(&self) -> u64 {
-        self.cluster_size() / size_of::<u64>() as u64
+        self.geometry().l2_entries()
     }
 
     // How many entries are in an L1?
     pub fn l1_entries(&self) -> u64 {
-        div_ceil(self.max_virtual_blocks(), self.l2_entries())
+        self.geometry().l1_entries()
+    }
+
+    // Does this image have a backing file?
+    pub fn has_backing_file(&self) -> bool {
+        self.c.backing_file_offset != 0
+    }
+
+    // Are data clusters stored in an external data file?
+    pub fn has_data_file(&self) -> bool {
+        self.v3.incompatible.enabled(INCOMPATIBLE_DATA_FILE)
+    }
+
+    // Is the external data file a raw image, with each cluster at its guest offset?
+    pub fn data_file_raw(&self) -> bool {
+        self.v3.autoclear.enabled(AUTOCLEAR_DATA_FILE_RAW)
     }
 
     // Find how an offset fits in the guest block hierarchy.
     // Returns (l1_l2_idx, l2_block_idx, block_offset).
     pub fn guest_offset_info(&self, pos: u64) -> (u64, u64, u64) {
-        let (block_idx, block_offset) = div_rem(pos, self.cluster_size());
-        let (l1_l2_idx, l2_block_idx) = div_rem(block_idx, self.l2_entries());
-        (l1_l2_idx, l2_block_idx, block_offset)
+        self.geometry().split(pos)
     }
 }
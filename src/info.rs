@@ -0,0 +1,316 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Qcow2, Result};
+use super::header::{COMPATIBLE_LAZY_REFCOUNTS, INCOMPATIBLE_CORRUPT, INCOMPATIBLE_DIRTY};
+use super::snapshot::{Snapshot, format_date};
+
+
+/// The method used to compress clusters in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum CompressionType {
+    /// Deflate compression, the default.
+    Zlib,
+}
+
+impl Display for CompressionType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            CompressionType::Zlib => f.write_str("zlib"),
+        }
+    }
+}
+
+/// The raw fields of a qcow2 header, as stored in the image.
+///
+/// Offsets are in bytes from the start of the image file. More fields may be added in future
+/// versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct HeaderInfo {
+    /// The version of the qcow2 format.
+    pub version: u32,
+    /// The offset of the backing file name, or zero if there is none.
+    pub backing_file_offset: u64,
+    /// The length of the backing file name.
+    pub backing_file_size: u32,
+    /// The base-2 logarithm of the cluster size.
+    pub cluster_bits: u32,
+    /// The size of the virtual disk.
+    pub size: u64,
+    /// The encryption method. Zero means no encryption.
+    pub crypt_method: u32,
+    /// The number of entries in the active L1 table.
+    pub l1_size: u32,
+    /// The offset of the active L1 table.
+    pub l1_table_offset: u64,
+    /// The offset of the refcount table.
+    pub refcount_table_offset: u64,
+    /// The number of clusters in the refcount table.
+    pub refcount_table_clusters: u32,
+    /// The number of snapshots.
+    pub nb_snapshots: u32,
+    /// The offset of the snapshot table.
+    pub snapshots_offset: u64,
+    /// The incompatible feature bits.
+    pub incompatible_features: u64,
+    /// The compatible feature bits.
+    pub compatible_features: u64,
+    /// The autoclear feature bits.
+    pub autoclear_features: u64,
+    /// The base-2 logarithm of the width of refcount entries, in bits.
+    pub refcount_order: u32,
+    /// The length of the header, not counting extensions.
+    pub header_length: u32,
+}
+
+/// A summary of information about a qcow2 image, similar to what `qemu-img info` shows.
+///
+/// With the `serde` feature, this can be serialized. The backing file name is represented as a
+/// string, with any invalid UTF-8 replaced.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImageInfo {
+    /// The version of the qcow2 format.
+    pub version: u32,
+    /// The size of the virtual disk.
+    pub virtual_size: u64,
+    /// The size of each cluster.
+    pub cluster_size: u64,
+    /// The number of bytes of guest data allocated in this image.
+    pub allocated_size: u64,
+    /// The name of the backing file, if any.
+    ///
+    /// On unix this is exactly the bytes stored in the image. Elsewhere the name is decoded as
+    /// UTF-8, with invalid sequences replaced.
+    #[cfg_attr(feature = "serde", serde(with = "lossy_path"))]
+    pub backing_file: Option<PathBuf>,
+    /// The format of the backing file, if specified.
+    pub backing_format: Option<String>,
+    /// The encryption method. Zero means no encryption.
+    pub crypt_method: u32,
+    /// The compression method used for compressed clusters.
+    pub compression_type: CompressionType,
+    /// The snapshots in this image.
+    pub snapshots: Vec<Snapshot>,
+    /// Whether refcounts may be out of date, and need to be repaired after a crash.
+    pub lazy_refcounts: bool,
+    /// The width of each refcount entry, in bits.
+    pub refcount_bits: u32,
+    /// Whether the image was not closed cleanly.
+    pub dirty: bool,
+    /// Whether the image was marked corrupt.
+    pub corrupt: bool,
+    /// The names of all enabled feature bits, of every kind.
+    pub features: Vec<String>,
+}
+
+// Paths may not be valid UTF-8, but a string is by far the most useful representation.
+#[cfg(feature = "serde")]
+pub(crate) mod lossy_path {
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &Option<PathBuf>, s: S) -> Result<S::Ok, S::Error> {
+        match *path {
+            Some(ref p) => s.serialize_some(&p.to_string_lossy()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<PathBuf>, D::Error> {
+        let s = Option::<String>::deserialize(d)?;
+        Ok(s.map(|s| Path::new(&s).to_owned()))
+    }
+
+    // The same, for paths that are always present.
+    #[cfg(any(unix, windows))]
+    pub mod required {
+        use std::path::{Path, PathBuf};
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(path: &Path, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_str(&path.to_string_lossy())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PathBuf, D::Error> {
+            Ok(Path::new(&String::deserialize(d)?).to_owned())
+        }
+    }
+}
+
+// Format a size with binary units, the way qemu does.
+fn human_size(size: u64) -> String {
+    static SUFFIXES: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    // Prefer "0.977 KiB" to "1000 B", keeping at most three digits before the point.
+    let mut unit = 0;
+    while unit + 1 < SUFFIXES.len() && size as f64 >= 1000.0 * 1024f64.powi(unit as i32) {
+        unit += 1;
+    }
+    let div = 1u64 << (unit * 10);
+    if size.is_multiple_of(div) {
+        return format!("{} {}", size / div, SUFFIXES[unit]);
+    }
+
+    // Three significant digits, without trailing zeros.
+    let val = size as f64 / div as f64;
+    let decimals = (2 - val.log10().floor() as i32).max(0) as usize;
+    let s = format!("{:.*}", decimals, val);
+    let s = if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &s
+    };
+    format!("{} {}", s, SUFFIXES[unit])
+}
+
+impl ImageInfo {
+    /// Get the list of snapshots, as a table like the one `qemu-img snapshot -l` prints.
+    ///
+    /// This table is part of what `Display` shows. It's empty if there are no snapshots.
+    pub fn snapshot_table(&self) -> String {
+        let mut table = String::new();
+        // Writing to a string can't fail.
+        self.write_snapshot_table(&mut table).unwrap();
+        table
+    }
+
+    fn write_snapshot_table<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
+        if self.snapshots.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "Snapshot list:")?;
+        writeln!(f,
+                 "{:<10}{:<20}{:>11}{:>21}{:>19}",
+                 "ID",
+                 "TAG",
+                 "VM SIZE",
+                 "DATE",
+                 "VM CLOCK")?;
+        for s in &self.snapshots {
+            let clock = s.vm_clock_nsec / 1_000_000;
+            let date = s.date().map_or_else(|| "unknown".to_owned(), format_date);
+            writeln!(f,
+                     "{:<10}{:<20}{:>11}{:>21}{:>7}:{:02}:{:02}.{:03}",
+                     s.id,
+                     s.name,
+                     human_size(s.vm_state_size),
+                     date,
+                     clock / 3_600_000,
+                     clock / 60_000 % 60,
+                     clock / 1000 % 60,
+                     clock % 1000)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ImageInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "file format: qcow2")?;
+        writeln!(f,
+                 "virtual size: {} ({} bytes)",
+                 human_size(self.virtual_size),
+                 self.virtual_size)?;
+        writeln!(f, "allocated size: {}", human_size(self.allocated_size))?;
+        writeln!(f, "cluster_size: {}", self.cluster_size)?;
+        if let Some(ref backing) = self.backing_file {
+            writeln!(f, "backing file: {}", backing.display())?;
+        }
+        if let Some(ref format) = self.backing_format {
+            writeln!(f, "backing file format: {}", format)?;
+        }
+        self.write_snapshot_table(f)?;
+        if self.crypt_method != 0 {
+            writeln!(f, "encrypted: yes")?;
+        }
+        writeln!(f, "Format specific information:")?;
+        writeln!(f,
+                 "    compat: {}",
+                 if self.version >= 3 { "1.1" } else { "0.10" })?;
+        writeln!(f, "    compression type: {}", self.compression_type)?;
+        writeln!(f, "    lazy refcounts: {}", self.lazy_refcounts)?;
+        writeln!(f, "    refcount bits: {}", self.refcount_bits)?;
+        writeln!(f, "    corrupt: {}", self.corrupt)?;
+        writeln!(f, "    dirty: {}", self.dirty)?;
+        if !self.features.is_empty() {
+            writeln!(f, "    features: {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the raw fields of this image's header.
+    ///
+    /// Unlike `info`, this needs no reads. It reflects the header as this library last read or
+    /// wrote it.
+    pub fn header_info(&self) -> HeaderInfo {
+        let (c, v3) = (&self.header().c, &self.header().v3);
+        HeaderInfo {
+            version: c.version,
+            backing_file_offset: c.backing_file_offset,
+            backing_file_size: c.backing_file_size,
+            cluster_bits: c.cluster_bits,
+            size: c.size,
+            crypt_method: c.crypt_method,
+            l1_size: c.l1_size,
+            l1_table_offset: c.l1_table_offset,
+            refcount_table_offset: c.refcount_table_offset,
+            refcount_table_clusters: c.refcount_table_clusters,
+            nb_snapshots: c.nb_snapshots,
+            snapshots_offset: c.snapshots_offset,
+            incompatible_features: v3.incompatible.bits(),
+            compatible_features: v3.compatible.bits(),
+            autoclear_features: v3.autoclear.bits(),
+            refcount_order: v3.refcount_order,
+            header_length: v3.header_length,
+        }
+    }
+
+    /// Get a summary of information about this image.
+    ///
+    /// This walks the image's L1 and L2 tables to find the allocated size, so it may need to
+    /// read a lot of metadata for a large image.
+    pub fn info(&self) -> Result<ImageInfo> {
+        let h = self.header();
+        let backing_file = if h.has_backing_file() {
+            Some(h.v3.backing_file_name.clone())
+        } else {
+            None
+        };
+        let table = &h.v3.feature_name_table;
+        let mut features = h.v3.incompatible.names(table);
+        features.extend(h.v3.compatible.names(table));
+        features.extend(h.v3.autoclear.names(table));
+        Ok(ImageInfo {
+            version: h.c.version,
+            virtual_size: self.guest_size(),
+            cluster_size: self.cluster_size(),
+            allocated_size: self.allocated_size()?,
+            backing_file,
+            backing_format: h.v3.backing_format.0.clone(),
+            crypt_method: h.c.crypt_method,
+            // Only images with the standard header length are supported, so none can declare
+            // a different compression type.
+            compression_type: CompressionType::Zlib,
+            snapshots: self.snapshots().to_vec(),
+            lazy_refcounts: h.v3.compatible.enabled(COMPATIBLE_LAZY_REFCOUNTS),
+            refcount_bits: 1 << h.v3.refcount_order,
+            dirty: h.v3.incompatible.enabled(INCOMPATIBLE_DIRTY),
+            corrupt: h.v3.incompatible.enabled(INCOMPATIBLE_CORRUPT),
+            features,
+        })
+    }
+}
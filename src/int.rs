@@ -1,3 +1,17 @@
+use super::{Error, Result};
+
+
+// Convert a size to a usize, such as to allocate a buffer. Sizes from an image may be too big
+// for a target with a 32-bit usize, which must be an error rather than a silent truncation.
+pub fn to_usize(n: u64, what: &str) -> Result<usize> {
+    usize::try_from(n).map_err(|_| {
+        Error::UnsupportedFeature(format!("{} too big for a {}-bit target: {}",
+                                          what,
+                                          usize::BITS,
+                                          n))
+    })
+}
+
 // Divide and yield remainder.
 pub fn div_rem(a: u64, b: u64) -> (u64, u64) {
     (a / b, a % b)
@@ -13,7 +27,8 @@ pub fn div_ceil(a: u64, b: u64) -> u64 {
     }
 }
 
-// Get the smallest number that, added to `a`, yields a multiple of `b`.
+// Get the smallest number that, added to `a`, yields a multiple of `b`. It's less than `b`, so
+// it fits in a usize whenever `b` does.
 pub fn padding_to_multiple(a: u64, b: u64) -> usize {
     let m = a % b;
     let r = if m == 0 {
@@ -24,7 +39,3 @@ pub fn padding_to_multiple(a: u64, b: u64) -> usize {
     r as usize
 }
 
-// Check if `a` is a multiple of `b`.
-pub fn is_multiple_of(a: u64, b: u64) -> bool {
-    a % b == 0
-}
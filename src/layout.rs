@@ -0,0 +1,206 @@
+use std::cmp::{max, min};
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::ReadAt;
+
+use super::{Qcow2, Result};
+use super::extension;
+use super::header::{self, Header};
+use super::int::padding_to_multiple;
+
+
+/// Where a part of the header lies in the image file, see `header_layout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldSpan {
+    /// What this part of the header is, such as `cluster_bits` or `extension[0x6803f857]
+    /// payload`. Fixed fields have the names used by the qcow2 specification.
+    pub name: String,
+    /// The offset in the image file.
+    pub offset: u64,
+    /// The number of bytes.
+    pub len: u64,
+    /// The bytes, as stored in the file.
+    pub bytes: Vec<u8>,
+}
+
+/// Find which bytes of an image hold each part of its header.
+///
+/// This covers the fixed fields, any additional fields, each header extension and the backing
+/// file name, in order of offset. It doesn't care whether the header makes sense, so it helps
+/// with inspecting and patching headers that fail to open or `validate`. Parts that don't
+/// exist are left out, such as extensions after an end marker that's missing. A part cut off
+/// by the end of the file has only the bytes that are there. Errors are only returned if the
+/// file can't be read.
+///
+/// # Examples
+///
+/// ```
+/// # fn foo() -> qcow2::Result<()> {
+/// let layout = qcow2::header_layout(std::fs::File::open("tests/test.qcow2")?)?;
+/// let bits = layout.iter().find(|s| s.name == "cluster_bits").unwrap();
+/// assert_eq!((bits.offset, bits.len), (20, 4));
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn header_layout<I: ReadAt>(io: I) -> Result<Vec<FieldSpan>> {
+    let raw = RawHeader::read(&io)?;
+    let c = &raw.header.c;
+    let mut layout = Layout {
+        buf: &raw.buf,
+        spans: Vec::new(),
+    };
+    let mut pos = 0;
+    for &(name, len) in header::FIXED_FIELDS {
+        if !raw.v3 && pos == header::HEADER_LENGTH_V2 as u64 {
+            break;
+        }
+        layout.add(name.to_owned(), pos, len);
+        pos += len;
+    }
+    if c.magic != header::MAGIC {
+        return Ok(layout.spans);
+    }
+    if raw.v3 {
+        let len = raw.header.v3.header_length as u64;
+        if len > pos {
+            layout.add("compression_type".to_owned(), pos, 1);
+            let padding_end = min(len, header::COMPRESSION_PADDING_END);
+            layout.add("header padding".to_owned(),
+                       header::COMPRESSION_TYPE_END,
+                       padding_end.saturating_sub(header::COMPRESSION_TYPE_END));
+            if len > padding_end {
+                layout.add("additional fields".to_owned(), padding_end, len - padding_end);
+            }
+        }
+    }
+    let (extensions, end) = raw.extensions();
+    for ext in extensions {
+        let name = format!("extension[{:#x}]", ext.code);
+        layout.add(format!("{} type", name), ext.pos, 4);
+        layout.add(format!("{} length", name), ext.pos + 4, 4);
+        layout.add(format!("{} payload", name), ext.pos + 8, ext.len);
+        let padding = padding_to_multiple(ext.len, 8) as u64;
+        layout.add(format!("{} padding", name), ext.pos + 8 + ext.len, padding);
+    }
+    if let Some(end) = end {
+        layout.add("end of extensions".to_owned(), end, 8);
+    }
+    if c.backing_file_offset != 0 {
+        layout.add("backing file name".to_owned(),
+                   c.backing_file_offset,
+                   c.backing_file_size as u64);
+    }
+    layout.spans.sort_by_key(|s| s.offset);
+    Ok(layout.spans)
+}
+
+fn read<I: ReadAt>(io: &I, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    let read = Qcow2::read_partial_at(io, 0, &mut buf)?;
+    buf.truncate(read);
+    Ok(buf)
+}
+
+// The start of an image file, parsed only as far as it makes sense. This is for looking at
+// headers that may be broken.
+pub(crate) struct RawHeader {
+    pub buf: Vec<u8>,
+    // The fields, with any that couldn't be read left at zero.
+    pub header: Header,
+    // Whether there's a version 3 header.
+    pub v3: bool,
+    cluster_size: Option<u64>,
+}
+
+// A header extension found in a RawHeader.
+pub(crate) struct RawExtension {
+    pub code: u32,
+    // Where the extension starts, with its code.
+    pub pos: u64,
+    // The length of the payload, which may go past the end of what was read.
+    pub len: u64,
+}
+
+impl RawHeader {
+    pub fn read<I: ReadAt>(io: &I) -> Result<Self> {
+        let mut buf = read(io, header::HEADER_READ)?;
+        let mut header = Header::default();
+        header.read_fields(&buf);
+        // Read as much as the header may use, if the cluster size is believable.
+        let cluster_size = match header.c.cluster_bits {
+            9..=22 => Some(header.cluster_size()),
+            _ => None,
+        };
+        if let Some(cs) = cluster_size {
+            if buf.len() == header::HEADER_READ {
+                buf = read(io, cs as usize + header::MAX_BACKING_FILE_NAME)?;
+            }
+        }
+        let v3 = header.c.magic == header::MAGIC && header.c.version >= header::SUPPORTED_VERSION;
+        Ok(RawHeader {
+            buf,
+            header,
+            v3,
+            cluster_size,
+        })
+    }
+
+    // Find the header extensions, as far as they can be followed, and where the end marker is
+    // if there is one.
+    pub fn extensions(&self) -> (Vec<RawExtension>, Option<u64>) {
+        let mut found = Vec::new();
+        if self.header.c.magic != header::MAGIC {
+            return (found, None);
+        }
+        let mut pos = if self.v3 {
+            max(self.header.v3.header_length as u64, header::HEADER_LENGTH_V3 as u64)
+        } else {
+            header::HEADER_LENGTH_V2 as u64
+        };
+        let limit = min(self.buf.len() as u64, self.cluster_size.unwrap_or(u64::MAX));
+        for _ in 0..=header::DEFAULT_MAX_EXTENSIONS {
+            if pos + 8 > limit {
+                break;
+            }
+            let code = BigEndian::read_u32(&self.buf[pos as usize..]);
+            let len = BigEndian::read_u32(&self.buf[pos as usize + 4..]) as u64;
+            if code == extension::EXT_CODE_NONE {
+                return (found, Some(pos));
+            }
+            found.push(RawExtension { code, pos, len });
+            pos += 8 + len + padding_to_multiple(len, 8) as u64;
+        }
+        (found, None)
+    }
+
+    // Get the payload of the first extension with a code, as much of it as was read.
+    pub fn payload(&self, code: u32) -> Option<&[u8]> {
+        let (extensions, _) = self.extensions();
+        let ext = extensions.into_iter().find(|e| e.code == code)?;
+        let start = min(ext.pos + 8, self.buf.len() as u64) as usize;
+        let end = min(ext.pos + 8 + ext.len, self.buf.len() as u64) as usize;
+        Some(&self.buf[start..end])
+    }
+}
+
+// The start of the file, and the spans found in it so far.
+struct Layout<'a> {
+    buf: &'a [u8],
+    spans: Vec<FieldSpan>,
+}
+
+impl Layout<'_> {
+    // Add a span, cut off at the end of what was read. Empty spans are left out.
+    fn add(&mut self, name: String, offset: u64, len: u64) {
+        let end = min(offset.saturating_add(len), self.buf.len() as u64);
+        if len == 0 || offset >= end {
+            return;
+        }
+        self.spans.push(FieldSpan {
+            name,
+            offset,
+            len: end - offset,
+            bytes: self.buf[offset as usize..end as usize].to_vec(),
+        });
+    }
+}
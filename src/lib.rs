@@ -9,59 +9,219 @@
 //!
 //!  * Reading blocks of virtual disk data.
 //!  * Reading data that is not aligned to block boundaries.
+//!  * Reading compressed data, as written by `qemu-img convert -c`.
 //!  * Parsing and validation of the header.
 //!  * Reporting the names of any unsupported features, using the "feature name table" extension.
-//!  * Basic caching of guest data locations, so nearby reads will be fast.
+//!  * Caching of guest data locations, so nearby reads will be fast. The cache can be replaced,
+//!    or made cheaper for images used from one thread, see `Qcow2Local`.
+//!  * Reporting information about images, similar to `qemu-img info`.
+//!  * Cheaply probing whether a file is a qcow2 image.
+//!  * Listing and reading snapshots.
+//!  * Exporting to sparse raw images.
+//!  * Hints about access patterns, for reading ahead.
+//!  * Opening images from sources that can only read and seek, or from memory.
+//!  * Reading from many threads at once, through shared files. See `SharedBackend` for which
+//!    storage suits concurrent readers.
+//!  * Comparing images, similar to `qemu-img compare`.
+//!  * Checking images for inconsistencies, similar to `qemu-img check`.
+//!  * External data files, including raw data files.
+//!  * Writing virtual disk data, without disturbing snapshots.
+//!  * Growing images.
+//!  * Backing files, both raw and qcow2, with optional copy-on-read.
+//!  * Listing the backing chain of an image file, and what's wrong with it.
+//!  * Copying a virtual disk or snapshot into a new image, optionally keeping its backing file.
+//!  * Creating new, empty images, and images holding the contents of raw images.
+//!  * Persistent dirty bitmaps, for incremental backups. They can be added, cleared and removed.
+//!  * Iterating over the clusters of a virtual disk, classified by what they hold.
+//!  * Repairing refcounts that are out of date, similar to `qemu-img check -r`.
+//!  * Creating empty overlays on an image, for external snapshots.
+//!  * Copying ranges between virtual disks, keeping holes and zero clusters.
+//!  * Measuring how big an image needs to be, similar to `qemu-img measure`.
+//!  * Mapping where the virtual disk is stored, in the JSON format of `qemu-img map`.
+//!  * Finding how much data is duplicated, to judge whether compression or deduplication would
+//!    be worth it.
 //!
 //! These features are not yet supported, but should be easy to add:
 //!
-//! * Listing and reading snapshots.
 //! * Reading version 2, currently only version 3 is supported.
-//! * Reading compressed data.
-//! * Backing file support, so you can chain qcow2 files together.
-//! * Reporting information about images.
 //!
 //! These features are harder, or less interesting to me. Patches welcome!
 //!
 //! * Reading encrypted qcow2 files.
-//! * Writing virtual disk data.
-//! * Repairing the disk if refcounts are out of date.
 //! * Compacting the virtual disk so it takes less space.
-//! * Maintaining a "dirty bitmap" to make backups faster.
-//! * Creating new qcow2 images.
+//! * Updating dirty bitmaps when writing.
 //! * Creating new snapshots.
-//! * Checking qcow2 images for inconsistencies.
 //! * Merging images into their backing file.
-//! * Resizing images.
+//! * Shrinking images.
+//!
+//! With the optional `serde` feature, information types such as `ImageInfo` can be serialized. The
+//! `capi` feature provides a C API, see the `capi` module. On Linux, Android and FreeBSD, which
+//! have `O_DIRECT`, the `direct` feature provides `DirectFile`, for accessing images with it. On
+//! Linux, the `fadvise` feature lets `Reader::advise_io` pass access hints on to files. The `nbd`
+//! feature builds the `qcow2-nbd` binary, which serves an image read-only over the NBD protocol.
+//! The `random-access` feature provides `RandomAccessFile`, which reads and writes files through
+//! positioned-io 0.3, without a seek position. It's always there on Windows. With the `logging`
+//! feature, what happens while opening and reading images is logged with the `log` crate. On unix,
+//! the `locking` feature provides `LockedFile`, which locks images the way qemu does, so an image
+//! in use by a virtual machine isn't written at the same time. The `testing` feature provides
+//! `FailpointIo`, for testing how code that writes images copes with failures and crashes. The
+//! `tokio` feature provides `open_chain`, which opens an image and its backing files, overlapping
+//! their I/O, and `AsyncSeqReader`, which streams a virtual disk as a tokio `AsyncRead`.
+//!
+//! On targets without files, such as `wasm32-unknown-unknown`, images can still be read and
+//! written in memory, with a `Vec<u8>` or `MemBackend`. Only what needs a filesystem is left out:
+//! `backing_chain`, `create_overlay`, the C API, and `Storage` for `File`.
+//!
+//! `File` is only `Storage` on unix. On Windows, positioned-io writes to a `File` with a read,
+//! so images are written through a `RandomAccessFile` instead.
 //!
 //! The repository for this crate is at https://github.com/vasi/qcow2-rs
 
 extern crate byteorder;
-extern crate lru_cache;
 extern crate positioned_io;
+#[cfg(feature = "logging")]
+extern crate log;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+// This must come first, so the other modules can use its macros.
+#[macro_use]
+mod logging;
+
+mod advise;
+mod alloc;
+mod amend;
+#[cfg(feature = "tokio")]
+mod async_chain;
+#[cfg(feature = "tokio")]
+mod async_read;
+mod backing;
+mod bitmap;
+mod boxed;
+mod cache;
+#[cfg(any(unix, windows))]
+mod chain;
+mod clusters;
+#[cfg(all(any(unix, windows), feature = "capi"))]
+pub mod capi;
+mod check;
+mod compare;
+mod compress;
+mod copy;
+mod create;
+mod dedup;
+mod diff;
+#[cfg(all(any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+          feature = "direct"))]
+mod direct;
 mod error;
+mod export;
 mod extension;
+#[cfg(feature = "testing")]
+mod failpoint;
 mod feature;
+mod geometry;
 mod header;
+mod info;
 mod int;
+mod layout;
+#[cfg(all(unix, feature = "locking"))]
+mod lock;
+mod map;
+mod measure;
+mod mem;
+mod metrics;
+mod options;
+#[cfg(any(unix, windows))]
+mod overlay;
+mod probe;
+mod progress;
+#[cfg(any(windows, all(unix, feature = "random-access")))]
+mod random_access;
 mod read;
+mod refcount;
+mod repair;
+mod resize;
+mod seek;
+mod shared;
+mod snapshot;
+mod stats;
+mod tables;
+mod tx;
+mod validate;
+mod warm;
+mod write;
+pub use crate::advise::{Advice, AdviseIo};
+#[cfg(feature = "tokio")]
+pub use crate::async_chain::{ChainOptions, open_chain};
+#[cfg(feature = "tokio")]
+pub use crate::async_read::AsyncSeqReader;
+pub use crate::backing::Backing;
+pub use crate::bitmap::{BackupExtent, BackupExtents, Bitmap, ExtentKind};
+pub use crate::boxed::{DynBackend, DynReader, GuestReader, ReadAtSize};
+pub use crate::cache::{CacheKey, CachePolicy, CacheStats, DEFAULT_CACHE_SIZE, ImageId,
+                       LocalCache, LruMetadataCache, MetadataCache, NoMetadataCache,
+                       SharedCache};
+#[cfg(any(unix, windows))]
+pub use crate::chain::{ChainLayer, backing_chain};
+pub use crate::check::{CheckFinding, CheckResult};
+pub use crate::clusters::{Cluster, ClusterRef, Clusters};
+pub use crate::compare::{Difference, compare};
+pub use crate::copy::{CopyOptions, CopyStats, copy_range, copy_range_reporting};
+pub use crate::create::CreateOptions;
+pub use crate::dedup::{DedupOptions, DedupReport, DuplicateGroup};
+pub use crate::diff::{GuestRange, SnapshotDiff};
+#[cfg(all(any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+          feature = "direct"))]
+pub use crate::direct::DirectFile;
 pub use crate::error::Error;
-pub use crate::read::Reader;
+pub use crate::export::ExportStats;
+pub use crate::extension::{FeatureNameTable, FeatureNameTableBuilder, UnknownExtension};
+#[cfg(feature = "testing")]
+pub use crate::failpoint::{FailpointIo, Fault, IoOp};
+pub use crate::feature::{FeatureBit, FeatureKind, feature_bits};
+pub use crate::geometry::Geometry;
+pub use crate::info::{CompressionType, HeaderInfo, ImageInfo};
+pub use crate::layout::{FieldSpan, header_layout};
+#[cfg(all(unix, feature = "locking"))]
+pub use crate::lock::{LockMode, LockedFile};
+pub use crate::map::MapEntry;
+pub use crate::measure::{MeasureOptions, Measurement};
+pub use crate::mem::MemBackend;
+pub use crate::metrics::Metrics;
+pub use crate::options::{OpenOptions, Truncated};
+#[cfg(any(unix, windows))]
+pub use crate::overlay::{OverlayOptions, create_overlay};
+pub use crate::probe::{Probe, probe};
+pub use crate::progress::{CancelToken, Phase, Progress};
+#[cfg(any(windows, all(unix, feature = "random-access")))]
+pub use crate::random_access::RandomAccessFile;
+pub use crate::read::{CompressedCluster, OwnedReader, Reader, ReaderBuilder};
+pub use crate::repair::Repair;
+pub use crate::seek::SeekBackend;
+pub use crate::shared::SharedBackend;
+pub use crate::snapshot::Snapshot;
+pub use crate::stats::AllocationStats;
+pub use crate::validate::{Severity, ValidationFinding, ValidationReport, validate};
+pub use crate::tables::{L1TableEntry, L2TableEntry, RefcountTableEntry};
+pub use crate::warm::WarmStats;
+pub use crate::write::{Preallocation, Storage, Writer, ZeroMode};
 
 use std::fmt::{self, Debug, Formatter};
+use std::io;
 use std::result;
-use std::sync::Mutex;
+use std::sync::{Arc, OnceLock};
 
 use byteorder::BigEndian;
-use lru_cache::LruCache;
-use positioned_io::{ReadAt, ByteIo};
+use positioned_io::{ReadAt, ByteIo, Size};
 
 
-const L2_CACHE_SIZE: usize = 32;
 
 /// A qcow2 image.
 ///
+/// The image caches metadata as its `CachePolicy` says. By default the cache can be shared
+/// between threads, see `Qcow2Local` for images used from only one.
+///
 /// # Examples
 ///
 /// ```no_run
@@ -80,18 +240,129 @@ const L2_CACHE_SIZE: usize = 32;
 ///
 /// // Read some data.
 /// let reader = qcow.reader()?;
-/// let mut buf = vec![0, 4096];
+/// let mut buf = vec![0; 4096];
 /// reader.read_exact_at(5 * 1024 * 1024, &mut buf)?;
 ///
 /// # Ok(()) } fn main() { foo().unwrap(); }
 /// ```
-pub struct Qcow2<I>
-    where I: ReadAt
+pub struct Qcow2<I, C = SharedCache>
+    where I: ReadAt,
+          C: CachePolicy
 {
-    header: header::Header,
+    // The header and snapshots, shared by all clones of this image.
+    meta: Arc<Meta>,
     io: ByteIo<I, BigEndian>,
+    data_file: Option<I>,
+    backing: Option<backing::Backing<I>>,
+
+    l2_cache: C,
+    image_id: ImageId,
+    truncated: Truncated,
+    // Whether data clusters may be misaligned, see `OpenOptions::misaligned_data`.
+    misaligned_data: bool,
+    // Whether to ignore reserved bits in tables, see `OpenOptions::reserved_bits`.
+    reserved_bits: bool,
+    // How to read the header.
+    header_options: header::ReadOptions,
+    // Shared by all clones of this image, to tell if there are any.
+    handles: Arc<()>,
+    metrics: Option<Arc<metrics::Metrics>>,
+}
+
+// The metadata of an image that changes when it's refreshed, shared by all its handles.
+//
+// Handles may be borrowing it, so a refresh doesn't change it in place, but links a newer
+// version that every handle follows. Following the link is a single load.
+#[derive(Clone, Default)]
+struct Meta {
+    header: header::Header,
+    snapshots: Vec<snapshot::Snapshot>,
+    newer: OnceLock<Arc<Meta>>,
+}
+
+impl Drop for Meta {
+    fn drop(&mut self) {
+        // Unlink newer versions one at a time, so a long chain doesn't recurse deeply.
+        let mut next = self.newer.take();
+        while let Some(meta) = next {
+            next = Arc::try_unwrap(meta).ok().and_then(|mut meta| meta.newer.take());
+        }
+    }
+}
+
+/// A qcow2 image that's only used from one thread.
+///
+/// Looking up metadata takes no locks, so this is faster for tools that read every cluster of
+/// an image in turn. The compiler makes sure it isn't shared between threads, since its cache
+/// isn't `Sync`. Open one with `OpenOptions::open_local`. Attached backing files keep their own
+/// caches, which are shared ones.
+///
+/// # Examples
+///
+/// ```
+/// use positioned_io::ReadAt;
+/// use qcow2::{OpenOptions, Qcow2Local};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let file = std::fs::File::open("tests/test.qcow2")?;
+/// let qcow: Qcow2Local<_> = OpenOptions::new().open_local(file)?;
+/// let mut buf = vec![0; 4096];
+/// qcow.reader()?.read_exact_at(0, &mut buf)?;
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+///
+/// It can't be shared between threads:
+///
+/// ```compile_fail
+/// fn shared<T: Sync>(_: &T) {}
+/// let file = std::fs::File::open("tests/test.qcow2").unwrap();
+/// let qcow = qcow2::OpenOptions::new().open_local(file).unwrap();
+/// shared(&qcow);
+/// ```
+pub type Qcow2Local<I> = Qcow2<I, LocalCache>;
+
+impl<I, C> Drop for Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    fn drop(&mut self) {
+        // Summarize how well the cache did, once the last handle is gone.
+        if Arc::strong_count(&self.handles) == 1 {
+            let (hits, misses) = self.l2_cache.counts();
+            if hits + misses > 0 {
+                debug!("closing image, L2 cache: {} hits, {} misses", hits, misses);
+            }
+        }
+    }
+}
 
-    l2_cache: Mutex<LruCache<u64, u64>>,
+/// Cloning an image gives another handle to it, sharing its metadata cache and identity. Handles
+/// of a `Qcow2Local` each get a cache of their own. All handles share the header and snapshot
+/// table too, so `invalidate_caches` on any of them refreshes every one.
+///
+/// This is cheap if cloning `I` is, for example with `Arc<File>` or a shared reference. Each
+/// handle can read on its own, but an image can only be modified when it has no other handles,
+/// so they never see it change underneath them.
+impl<I, C> Clone for Qcow2<I, C>
+    where I: ReadAt + Clone,
+          C: CachePolicy + Clone
+{
+    fn clone(&self) -> Self {
+        Qcow2 {
+            meta: self.latest().clone(),
+            io: ByteIo::new((*self.io).clone()),
+            data_file: self.data_file.clone(),
+            backing: self.backing.clone(),
+            l2_cache: self.l2_cache.clone(),
+            image_id: self.image_id,
+            truncated: self.truncated,
+            misaligned_data: self.misaligned_data,
+            reserved_bits: self.reserved_bits,
+            header_options: self.header_options,
+            handles: self.handles.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
 }
 
 /// The result type for operations on qcow2 images.
@@ -102,37 +373,159 @@ impl<I> Qcow2<I>
 {
     /// Open a source of data as a qcow2 image.
     ///
-    /// Usually the data source `io` will be a file.
+    /// Usually the data source `io` will be a file. To customize how the image is opened, use
+    /// `OpenOptions`.
     pub fn open(io: I) -> Result<Self> {
-        let io: ByteIo<_, BigEndian> = ByteIo::new(io);
-        let mut q = Qcow2 {
-            header: Default::default(),
-            io,
-            l2_cache: Mutex::new(LruCache::new(L2_CACHE_SIZE)),
-        };
-        q.header.read(&mut q.io)?;
-        Ok(q)
+        OpenOptions::new().open(io)
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    // The latest metadata, following refreshes made through any handle.
+    fn latest(&self) -> &Arc<Meta> {
+        let mut meta = &self.meta;
+        while let Some(newer) = meta.newer.get() {
+            meta = newer;
+        }
+        meta
+    }
+
+    pub(crate) fn header(&self) -> &header::Header {
+        &self.latest().header
+    }
+
+    // Get the metadata to change in place. That's only done without other handles, which would
+    // otherwise keep the old version.
+    fn meta_mut(&mut self) -> &mut Meta {
+        self.meta = self.latest().clone();
+        Arc::make_mut(&mut self.meta)
+    }
+
+    pub(crate) fn header_mut(&mut self) -> &mut header::Header {
+        &mut self.meta_mut().header
+    }
+
+    pub(crate) fn set_snapshots(&mut self, snapshots: Vec<snapshot::Snapshot>) {
+        self.meta_mut().snapshots = snapshots;
+    }
+
+    // Replace the metadata with what was read again from the image, for every handle.
+    pub(crate) fn refresh_meta(&mut self,
+                               header: header::Header,
+                               snapshots: Vec<snapshot::Snapshot>) {
+        let mut meta = Arc::new(Meta { header, snapshots, newer: OnceLock::new() });
+        // Another handle may link a newer version first, then this one goes after it.
+        while let Err(rejected) = self.latest().newer.set(meta) {
+            meta = rejected;
+        }
+        self.meta = self.latest().clone();
     }
 
     /// Get the size of each block of this qcow2 image.
     pub fn cluster_size(&self) -> u64 {
-        self.header.cluster_size()
+        self.header().cluster_size()
     }
 
     /// Get the size of the virtual image.
     ///
     /// This is likely to differ from the size of the qcow2 file itself, since the file can grow.
     pub fn guest_size(&self) -> u64 {
-        self.header.guest_size()
+        self.header().guest_size()
+    }
+
+    /// Get the space the image takes up in storage, if known.
+    ///
+    /// This is the size of the qcow2 file, plus that of any external data file. Comparing it to
+    /// `guest_size` shows how sparse the image is.
+    pub fn physical_size(&self) -> Result<Option<u64>>
+        where I: Size
+    {
+        let size = match self.io.size()? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        match self.data_file {
+            Some(ref data) => Ok(data.size()?.map(|d| size + d)),
+            None => Ok(Some(size)),
+        }
+    }
+
+    /// Get the identity of this image, used as part of the key for cache entries.
+    pub fn image_id(&self) -> ImageId {
+        self.image_id
+    }
+
+    /// Get the snapshots stored in this image.
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.latest().snapshots
+    }
+
+    /// Find a snapshot by its ID.
+    pub fn find_snapshot_by_id(&self, id: &str) -> Option<&Snapshot> {
+        self.snapshots().iter().find(|s| s.id == id)
+    }
+
+    /// Find a snapshot by its name. If several have the name, the first is found.
+    pub fn find_snapshot_by_name(&self, name: &str) -> Option<&Snapshot> {
+        self.snapshots().iter().find(|s| s.name == name)
+    }
+
+    /// Find a snapshot by either its ID or its name.
+    ///
+    /// Like qemu, a matching ID wins, even if another snapshot has the name.
+    pub fn find_snapshot(&self, name: &str) -> Option<&Snapshot> {
+        self.find_snapshot_by_id(name).or_else(|| self.find_snapshot_by_name(name))
+    }
+
+    /// Get the name of the external data file, if the image uses one and records its name.
+    ///
+    /// Images with an external data file must be opened with `OpenOptions::open_with_data_file`
+    /// to read guest data.
+    pub fn data_file_name(&self) -> Option<&str> {
+        if self.header().has_data_file() {
+            self.header().v3.data_file_name.0.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Get the feature name table of this image.
+    ///
+    /// If the image has no feature name table extension, the table will be empty.
+    pub fn feature_name_table(&self) -> &FeatureNameTable {
+        &self.header().v3.feature_name_table
+    }
+
+    /// Get the header extensions of this image that this library doesn't understand.
+    ///
+    /// They're in the order they appear in the header. Some tools write more than one extension
+    /// with the same code, each copy is included.
+    pub fn unknown_extensions(&self) -> &[UnknownExtension] {
+        &self.header().v3.unknown_extensions
     }
 }
 
-impl<I> Debug for Qcow2<I>
-    where I: ReadAt
+/// The size of an image is the size of its virtual disk, see `guest_size`.
+impl<I, C> Size for Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.guest_size()))
+    }
+}
+
+impl<I, C> Debug for Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
 {
     fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
         f.debug_struct("Qcow2")
-            .field("header", &self.header)
+            .field("header", self.header())
+            .field("snapshots", &self.snapshots())
             .finish()
     }
 }
@@ -0,0 +1,209 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use positioned_io::{ReadAt, Size, WriteAt};
+
+use super::write::Storage;
+
+
+// qemu locks one byte at `PERM_BASE + bit` for each permission it holds, and one at
+// `SHARED_BASE + bit` for each permission it won't share with others.
+const PERM_BASE: u64 = 100;
+const SHARED_BASE: u64 = 200;
+
+// The permissions qemu locks, in bit order.
+const PERM_NAMES: [&str; 4] = ["consistent read", "write", "write unchanged", "resize"];
+const PERM_CONSISTENT_READ: u32 = 1 << 0;
+const PERM_WRITE: u32 = 1 << 1;
+const PERM_WRITE_UNCHANGED: u32 = 1 << 2;
+const PERM_RESIZE: u32 = 1 << 3;
+const PERM_ALL: u32 = (1 << PERM_NAMES.len()) - 1;
+
+// Open file description locks belong to the open file, rather than the process, so they're
+// the ones qemu prefers. Elsewhere, fall back to process-wide locks like qemu does.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const GET_LOCK: libc::c_int = libc::F_OFD_GETLK;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SET_LOCK: libc::c_int = libc::F_SETLK;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const GET_LOCK: libc::c_int = libc::F_GETLK;
+
+/// How a `LockedFile` shares an image with others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Read the image, while nobody writes to it. This is what `qemu-img info` does.
+    Shared,
+    /// Read and write the image, while nobody else reads or writes it. This is what a running
+    /// virtual machine does.
+    Exclusive,
+    /// Read the image, whatever anyone else is doing, like qemu's `--force-share`.
+    ///
+    /// Others can still see that the image is being read. If the image is being written, reads
+    /// may see it in an inconsistent state, so it's not meant for writing.
+    ForceShare,
+}
+
+impl LockMode {
+    // The permissions that are held, and those that are shared with others.
+    fn perms(self) -> (u32, u32) {
+        match self {
+            LockMode::Shared => (PERM_CONSISTENT_READ, PERM_ALL & !(PERM_WRITE | PERM_RESIZE)),
+            LockMode::Exclusive => (PERM_ALL, PERM_CONSISTENT_READ | PERM_WRITE_UNCHANGED),
+            LockMode::ForceShare => (PERM_CONSISTENT_READ, PERM_ALL),
+        }
+    }
+}
+
+/// A file locked the same way qemu locks images, so the two don't corrupt each other's images.
+///
+/// qemu takes advisory locks on particular bytes of each image it opens, saying what it's
+/// doing with the image and what it lets others do. Opening a `LockedFile` takes the same
+/// locks, and fails with an "image is in use" error of kind `ResourceBusy` if that conflicts
+/// with anyone else. The locks are released when the file is dropped.
+///
+/// Advisory locks only stop others who also take them, and some network filesystems ignore
+/// them. On platforms other than Linux, locks belong to the whole process, so two
+/// `LockedFile`s in one process don't conflict, and closing any handle on the file releases
+/// them.
+#[derive(Debug)]
+pub struct LockedFile {
+    file: File,
+}
+
+impl LockedFile {
+    /// Open a file for reading only, with `LockMode::Shared`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with(fs::OpenOptions::new().read(true), path, LockMode::Shared)
+    }
+
+    /// Open a file for reading and writing, with `LockMode::Exclusive`.
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with(fs::OpenOptions::new().read(true).write(true),
+                        path,
+                        LockMode::Exclusive)
+    }
+
+    /// Open a file with custom options, and lock it.
+    pub fn open_with<P: AsRef<Path>>(options: &fs::OpenOptions,
+                                     path: P,
+                                     mode: LockMode)
+                                     -> io::Result<Self> {
+        Self::lock(options.open(path)?, mode)
+    }
+
+    /// Lock a file that's already open.
+    pub fn lock(file: File, mode: LockMode) -> io::Result<Self> {
+        let (held, shared) = mode.perms();
+        for bit in 0..PERM_NAMES.len() as u32 {
+            if held & (1 << bit) != 0 {
+                lock_byte(&file, PERM_BASE + bit as u64, bit)?;
+            }
+            if shared & (1 << bit) == 0 {
+                lock_byte(&file, SHARED_BASE + bit as u64, bit)?;
+            }
+        }
+        // Others hold what we won't share, or won't share what we hold.
+        for bit in 0..PERM_NAMES.len() as u32 {
+            if held & (1 << bit) != 0 && is_locked(&file, SHARED_BASE + bit as u64)? {
+                return Err(in_use(bit, "lock"));
+            }
+            if shared & (1 << bit) == 0 && is_locked(&file, PERM_BASE + bit as u64)? {
+                return Err(in_use(bit, "shared lock"));
+            }
+        }
+        Ok(LockedFile { file })
+    }
+
+    /// Get the underlying file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+
+    /// Unlock the file, and get it back.
+    pub fn into_inner(self) -> File {
+        let file = self.file;
+        // Closing the file would release the locks, but it's staying open.
+        for bit in 0..PERM_NAMES.len() as u64 {
+            let _ = fcntl_lock(&file, SET_LOCK, libc::F_UNLCK, PERM_BASE + bit);
+            let _ = fcntl_lock(&file, SET_LOCK, libc::F_UNLCK, SHARED_BASE + bit);
+        }
+        file
+    }
+}
+
+fn in_use(bit: u32, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy,
+                   format!("image is in use, failed to get {} on \"{}\"",
+                           what,
+                           PERM_NAMES[bit as usize]))
+}
+
+fn fcntl_lock(file: &File,
+              cmd: libc::c_int,
+              kind: libc::c_int,
+              byte: u64)
+              -> io::Result<libc::flock> {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = byte as libc::off_t;
+    lock.l_len = 1;
+    match unsafe { libc::fcntl(file.as_raw_fd(), cmd, &mut lock) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(lock),
+    }
+}
+
+// Take a shared lock on one byte. Nobody takes exclusive locks, but if someone does, it's a
+// conflict too.
+fn lock_byte(file: &File, byte: u64, bit: u32) -> io::Result<()> {
+    match fcntl_lock(file, SET_LOCK, libc::F_RDLCK, byte) {
+        Err(ref e) if matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES)) => {
+            Err(in_use(bit, "lock"))
+        }
+        r => r.map(|_| ()),
+    }
+}
+
+// Whether anyone else holds a lock on one byte.
+fn is_locked(file: &File, byte: u64) -> io::Result<bool> {
+    let lock = fcntl_lock(file, GET_LOCK, libc::F_WRLCK, byte)?;
+    Ok(lock.l_type != libc::F_UNLCK as libc::c_short)
+}
+
+impl ReadAt for LockedFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        FileExt::read_at(&self.file, buf, pos)
+    }
+}
+
+impl WriteAt for LockedFile {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        FileExt::write_at(&self.file, buf, pos)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Size for LockedFile {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.file.metadata()?.len()))
+    }
+}
+
+impl Storage for LockedFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
@@ -0,0 +1,34 @@
+// Logging through the `log` crate, with the `logging` feature.
+//
+// Without the feature, these macros do nothing, but still check their arguments so that using
+// them doesn't depend on the feature.
+
+#[cfg(feature = "logging")]
+macro_rules! debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! trace {
+    ($($arg:tt)*) => { log::trace!($($arg)*) };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! warn {
+    ($($arg:tt)*) => { log::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($arg:tt)*) => { if false { let _ = format_args!($($arg)*); } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! trace {
+    ($($arg:tt)*) => { if false { let _ = format_args!($($arg)*); } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! warn {
+    ($($arg:tt)*) => { if false { let _ = format_args!($($arg)*); } };
+}
@@ -0,0 +1,195 @@
+use std::cmp::min;
+use std::io::Write;
+use std::ops::ControlFlow;
+
+use positioned_io::{ReadAt, ReadIntAt};
+
+use super::{CachePolicy, Phase, Progress, Qcow2, Reader, Result};
+use super::progress::Reporter;
+use super::read::L2Entry;
+
+
+/// An extent of the virtual disk, described the way `qemu-img map --output=json` does, see
+/// `Reader::map`.
+///
+/// With the `serde` feature, this can be serialized with the same field names as qemu uses. The
+/// offset is left out when there isn't one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MapEntry {
+    /// The guest offset where the extent starts.
+    pub start: u64,
+    /// The length of the extent, in bytes.
+    pub length: u64,
+    /// How far down the backing chain the contents come from. Zero means this image, one its
+    /// backing file, and so on.
+    pub depth: u64,
+    /// Whether an image in the chain allocates the extent, or marks it as zeros.
+    ///
+    /// Extents that aren't present read as zeros, since the chain ended or the backing file was
+    /// too short.
+    pub present: bool,
+    /// Whether the extent reads as zeros.
+    pub zero: bool,
+    /// Whether the extent's data is stored somewhere.
+    pub data: bool,
+    /// Whether the extent is compressed.
+    pub compressed: bool,
+    /// Where the extent is stored in the file holding it: the image, its data file, or a raw
+    /// backing file. Compressed extents and ones without space allocated have no offset.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub offset: Option<u64>,
+}
+
+impl MapEntry {
+    // Describe an extent that isn't in the image, and reads as zeros.
+    fn absent(start: u64, length: u64, depth: u64) -> Self {
+        MapEntry {
+            start,
+            length,
+            depth,
+            present: false,
+            zero: true,
+            data: false,
+            compressed: false,
+            offset: None,
+        }
+    }
+
+    // Can this be extended by the next extent, the way qemu merges them?
+    fn mergeable(&self, next: &MapEntry) -> bool {
+        (self.depth, self.present, self.zero, self.data, self.compressed) ==
+        (next.depth, next.present, next.zero, next.data, next.compressed) &&
+        self.offset.map(|o| o + self.length) == next.offset
+    }
+}
+
+impl<'a, I, C> Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    /// List the extents of the virtual disk, and where each one's contents come from, like
+    /// `qemu-img map`.
+    ///
+    /// Adjacent extents are merged when their contents come from the same place and are
+    /// contiguous on disk, as qemu does. Unallocated extents are looked up in the backing file,
+    /// if one is attached, and so on down the chain. Parts of a raw backing file are listed as
+    /// data, since the file isn't checked for holes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qcow2::Qcow2;
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let map = qcow.reader()?.map()?;
+    /// let data: u64 = map.iter().filter(|e| e.data).map(|e| e.length).sum();
+    /// assert_eq!(data, 65536);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn map(&self) -> Result<Vec<MapEntry>> {
+        self.map_reporting(&|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// List the extents of the virtual disk, reporting progress to a `Progress`, which may
+    /// cancel listing them.
+    ///
+    /// This is like `map`. There is one phase, `Phase::ScanningTables`, which counts the
+    /// entries of the L1 table. Mapping only reads the image, so if cancelled, this fails with
+    /// `Error::Cancelled`, leaving nothing behind.
+    pub fn map_reporting<P: Progress + ?Sized>(&self, progress: &P) -> Result<Vec<MapEntry>> {
+        let span = self.q.header().l2_entries() * self.q.cluster_size();
+        let tables = self.size.div_ceil(span);
+        let mut reporter = Reporter::phase(progress, Phase::ScanningTables, (0, 1), tables, 1);
+        let mut map: Vec<MapEntry> = Vec::new();
+        let mut pos = 0;
+        while pos < self.size {
+            reporter.update(pos / span, 0)?;
+            let entry = self.q.map_entry(&self.l1, self.size, pos, 0)?;
+            pos += entry.length;
+            match map.last_mut() {
+                Some(last) if last.mergeable(&entry) => last.length += entry.length,
+                _ => map.push(entry),
+            }
+        }
+        reporter.finish(0)?;
+        Ok(map)
+    }
+
+    /// Write the extents of the virtual disk as JSON, exactly as `qemu-img map --output=json`
+    /// does.
+    ///
+    /// This is the format of recent versions of qemu, which include the `present` and
+    /// `compressed` fields. See `map` for how extents are found.
+    pub fn map_json<W: Write>(&self, mut w: W) -> Result<()> {
+        let map = self.map()?;
+        write!(w, "[")?;
+        for (i, e) in map.iter().enumerate() {
+            write!(w,
+                   "{{ \"start\": {}, \"length\": {}, \"depth\": {}, \"present\": {}, \"zero\": \
+                    {}, \"data\": {}, \"compressed\": {}",
+                   e.start,
+                   e.length,
+                   e.depth,
+                   e.present,
+                   e.zero,
+                   e.data,
+                   e.compressed)?;
+            if let Some(offset) = e.offset {
+                write!(w, ", \"offset\": {}", offset)?;
+            }
+            write!(w, "}}")?;
+            if i + 1 < map.len() {
+                writeln!(w, ",")?;
+            }
+        }
+        writeln!(w, "]")?;
+        Ok(())
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    // Describe the extent starting at a guest offset, up to the end of its cluster, through an
+    // L1 table. `depth` is how far down the backing chain this image is.
+    pub(crate) fn map_entry<T: ReadIntAt>(&self,
+                                          l1: &T,
+                                          size: u64,
+                                          pos: u64,
+                                          depth: u64)
+                                          -> Result<MapEntry> {
+        let cs = self.cluster_size();
+        let offset = pos % cs;
+        let length = min(pos - offset + cs, size) - pos;
+        let stored = |data, zero, compressed, host: Option<u64>| {
+            MapEntry {
+                start: pos,
+                length,
+                depth,
+                present: true,
+                zero,
+                data,
+                compressed,
+                offset: host.map(|h| h + offset),
+            }
+        };
+        Ok(match self.l2_entry_read(l1, pos - offset)? {
+            L2Entry::Standard { pos: host, zero, .. } => stored(!zero, zero, false, Some(host)),
+            L2Entry::Zero { .. } => stored(false, true, false, None),
+            L2Entry::Compressed { .. } => stored(true, false, true, None),
+            L2Entry::Empty => {
+                match self.backing() {
+                    Some(b) if pos < b.size() => {
+                        let mut entry = b.map_entry(pos, depth + 1)?;
+                        entry.length = min(entry.length, length);
+                        entry
+                    }
+                    _ => MapEntry::absent(pos, length, depth),
+                }
+            }
+        })
+    }
+}
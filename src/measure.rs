@@ -0,0 +1,268 @@
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::ops::ControlFlow;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{CachePolicy, Error, Phase, Progress, Qcow2, Result};
+use super::progress::Reporter;
+use super::read::{L1Entry, L2Entry};
+use super::snapshot;
+
+
+/// Choices for measuring an image with `Qcow2::measure`.
+///
+/// By default, the measurement is for an image with the same cluster size and refcount width
+/// as this one, without its snapshots, like `qemu-img measure`.
+#[derive(Debug, Clone, Default)]
+pub struct MeasureOptions {
+    cluster_bits: Option<u32>,
+    refcount_bits: Option<u32>,
+    snapshots: bool,
+}
+
+impl MeasureOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Measure an image with clusters of `1 << bits` bytes, from 512 bytes to 2 MiB.
+    pub fn cluster_bits(&mut self, bits: u32) -> &mut Self {
+        self.cluster_bits = Some(bits);
+        self
+    }
+
+    /// Measure an image with refcounts of this many bits, a power of two up to 64.
+    pub fn refcount_bits(&mut self, bits: u32) -> &mut Self {
+        self.refcount_bits = Some(bits);
+        self
+    }
+
+    /// Also count the space the internal snapshots take: the snapshot table, the L1 table of
+    /// each snapshot, and the L2 tables and data that only snapshots use.
+    ///
+    /// This measures the image as it is, so the cluster size can't change.
+    pub fn snapshots(&mut self, snapshots: bool) -> &mut Self {
+        self.snapshots = snapshots;
+        self
+    }
+}
+
+/// How big an image file holding a virtual disk must be, found by `Qcow2::measure`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Measurement {
+    /// The size needed to hold the data allocated now.
+    ///
+    /// Like qemu, this still counts the metadata of a fully allocated image, so it's a little
+    /// more than a copy really needs.
+    pub required: u64,
+    /// The size needed with every cluster of the virtual disk allocated, such as by full
+    /// preallocation.
+    pub fully_allocated: u64,
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Find how big an image holding this virtual disk would be, similar to `qemu-img measure`.
+    ///
+    /// Both sizes count the header, the data clusters, the L1 and L2 tables, and the refcount
+    /// table and blocks, which need refcounts of their own. Allocated data is any cluster that
+    /// isn't a hole or a zero cluster. If a backing file is attached, the data it provides is
+    /// counted too, as a copy would need it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qcow2::{MeasureOptions, Qcow2};
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let measured = qcow.measure(&MeasureOptions::new())?;
+    /// assert!(measured.fully_allocated > qcow.guest_size());
+    /// assert_eq!(measured.fully_allocated - measured.required, qcow.guest_size() - 65536);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn measure(&self, opts: &MeasureOptions) -> Result<Measurement> {
+        self.measure_reporting(opts, &|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Find how big an image holding this virtual disk would be, reporting progress to a
+    /// `Progress`, which may cancel measuring.
+    ///
+    /// This is like `measure`. There is one phase, `Phase::ScanningTables`, which counts the
+    /// entries of the L1 table, and those of the snapshots when they're measured too. Measuring
+    /// only reads the image, so if cancelled, this fails with `Error::Cancelled`, leaving
+    /// nothing behind.
+    pub fn measure_reporting<P>(&self, opts: &MeasureOptions, progress: &P) -> Result<Measurement>
+        where P: Progress + ?Sized
+    {
+        let cluster_bits = opts.cluster_bits.unwrap_or(self.header().c.cluster_bits);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(Error::UnsupportedFeature(format!("cluster_bits {}", cluster_bits)));
+        }
+        let refcount_bits = opts.refcount_bits.unwrap_or(1 << self.header().v3.refcount_order);
+        if !refcount_bits.is_power_of_two() || refcount_bits > 64 {
+            return Err(Error::UnsupportedFeature(format!("refcounts of {} bits",
+                                                         refcount_bits)));
+        }
+        let snapshots = opts.snapshots && !self.snapshots().is_empty();
+        if snapshots && cluster_bits != self.header().c.cluster_bits {
+            return Err(Error::UnsupportedFeature("changing the cluster size of snapshots"
+                .to_owned()));
+        }
+
+        // The image's L1 table is scanned for data, and again with those of the snapshots.
+        let l1_entries = self.header().l1_entries();
+        let tables = if snapshots {
+            2 * l1_entries + self.snapshots().iter().map(|s| s.l1_size as u64).sum::<u64>()
+        } else {
+            l1_entries
+        };
+        let mut reporter = Reporter::phase(progress, Phase::ScanningTables, (0, 1), tables, 1);
+        let extra = if snapshots { self.snapshots_size(&mut reporter)? } else { 0 };
+
+        let cluster_size = 1 << cluster_bits;
+        let virtual_size = self.guest_size().next_multiple_of(cluster_size);
+        let fully_allocated = fully_allocated(virtual_size, cluster_size, refcount_bits, extra);
+        let data = self.data_size(cluster_size, (&mut reporter, tables - l1_entries))?;
+        reporter.finish(0)?;
+        Ok(Measurement {
+            required: fully_allocated - virtual_size + data,
+            fully_allocated,
+        })
+    }
+
+    // Count the bytes in clusters of `cluster_size` that would hold allocated data. Progress is
+    // reported after the number of L1 entries already scanned.
+    fn data_size<P>(&self,
+                    cluster_size: u64,
+                    (reporter, done): (&mut Reporter<'_, P>, u64))
+                    -> Result<u64>
+        where P: Progress + ?Sized
+    {
+        let cs = self.cluster_size();
+        let size = self.guest_size();
+        let backing_size = self.backing().map_or(0, |b| b.size());
+        let l1 = self.l1_read(self.header().c.l1_table_offset, self.header().l1_entries())?;
+        let l1 = ByteIo::<_, BigEndian>::new(l1);
+        let l2_entries = self.header().l2_entries();
+        let clusters = size.div_ceil(cs);
+
+        let mut count = 0;
+        // The first cluster of the new image that isn't yet counted.
+        let mut next = 0;
+        for l1_idx in 0..self.header().l1_entries() {
+            reporter.update(done + l1_idx, 0)?;
+            let first = l1_idx * l2_entries;
+            let n = min(l2_entries, clusters - first);
+            let entries = self.l2_entries_read(&l1, first * cs, n as usize)?;
+            for (i, entry) in entries.into_iter().enumerate() {
+                let pos = (first + i as u64) * cs;
+                let data = match entry {
+                    L2Entry::Empty => pos < backing_size,
+                    L2Entry::Zero { .. } |
+                    L2Entry::Standard { zero: true, .. } => false,
+                    _ => true,
+                };
+                if data {
+                    let start = max(pos / cluster_size, next);
+                    let end = min(pos + cs, size).div_ceil(cluster_size);
+                    count += end.saturating_sub(start);
+                    next = max(next, end);
+                }
+            }
+        }
+        Ok(count * cluster_size)
+    }
+
+    // Count the bytes taken by snapshots and nothing else.
+    fn snapshots_size<P>(&self, reporter: &mut Reporter<'_, P>) -> Result<u64>
+        where P: Progress + ?Sized
+    {
+        let cs = self.cluster_size();
+        let (_, table_size) = snapshot::read_snapshots(&self.io, self.header())?;
+        let mut clusters = table_size.div_ceil(cs);
+        let mut seen = HashSet::new();
+        let mut done = self.header().l1_entries();
+        self.clusters_used(self.header().c.l1_table_offset, done, &mut seen, (reporter, 0))?;
+        for snap in self.snapshots() {
+            let entries = snap.l1_size as u64;
+            clusters += (entries * size_of::<u64>() as u64).div_ceil(cs);
+            clusters += self.clusters_used(snap.l1_table_offset,
+                                           entries,
+                                           &mut seen,
+                                           (reporter, done))?;
+            done += entries;
+        }
+        Ok(clusters * cs)
+    }
+
+    // Find the host clusters an L1 table points to, directly or through its L2 tables. Returns
+    // how many weren't seen before. Progress is reported after the number of L1 entries already
+    // scanned.
+    fn clusters_used<P>(&self,
+                        l1_offset: u64,
+                        entries: u64,
+                        seen: &mut HashSet<u64>,
+                        (reporter, done): (&mut Reporter<'_, P>, u64))
+                        -> Result<u64>
+        where P: Progress + ?Sized
+    {
+        let cs = self.cluster_size();
+        let l1 = ByteIo::<_, BigEndian>::new(self.l1_read(l1_offset, entries)?);
+        let before = seen.len();
+        for l1_idx in 0..entries {
+            reporter.update(done + l1_idx, 0)?;
+            let pos = match self.l1_entry_read(&l1, l1_idx)? {
+                L1Entry::Empty => continue,
+                L1Entry::Standard { pos, .. } => pos,
+            };
+            if !seen.insert(pos / cs) {
+                // A shared table points only to clusters that are already counted.
+                continue;
+            }
+            for &raw in self.l2_table_load(pos)?.iter() {
+                match self.l2_entry_parse(raw)? {
+                    L2Entry::Empty | L2Entry::Zero { .. } => {}
+                    L2Entry::Standard { pos, .. } => {
+                        seen.insert(pos / cs);
+                    }
+                    L2Entry::Compressed { pos, size, .. } => {
+                        seen.extend(pos / cs..(pos + size).div_ceil(cs));
+                    }
+                }
+            }
+        }
+        Ok((seen.len() - before) as u64)
+    }
+}
+
+// Find the size of an image with every cluster allocated, the way qemu does. There may be
+// `extra` bytes of other clusters, which need refcounts too.
+fn fully_allocated(virtual_size: u64, cluster_size: u64, refcount_bits: u32, extra: u64) -> u64 {
+    let per_table = cluster_size / size_of::<u64>() as u64;
+    let l2_tables = (virtual_size / cluster_size).div_ceil(per_table);
+    let l1_clusters = l2_tables.div_ceil(per_table);
+    let meta = (1 + l2_tables + l1_clusters) * cluster_size + extra;
+
+    // Refcounts cover every cluster, including their own.
+    let clusters = (meta + virtual_size) / cluster_size;
+    let per_block = cluster_size * 8 / refcount_bits as u64;
+    let (mut table, mut blocks) = (0, 0);
+    loop {
+        let need_blocks = (clusters + table + blocks).div_ceil(per_block);
+        let need_table = need_blocks.div_ceil(per_table);
+        if need_blocks == blocks && need_table == table {
+            break;
+        }
+        blocks = need_blocks;
+        table = need_table;
+    }
+    meta + (table + blocks) * cluster_size + virtual_size
+}
@@ -0,0 +1,52 @@
+use std::io;
+
+use positioned_io::{ReadAt, Size};
+
+
+/// An image held in memory, in any buffer that can be borrowed as bytes.
+///
+/// A plain byte slice can already be opened with `Qcow2::open(&bytes[..])`. This wrapper is for
+/// owned and shared buffers, such as `Arc<[u8]>`, `Box<[u8]>`, or `bytes::Bytes`, which can't
+/// implement `ReadAt` themselves. Reads are just copies, and never fail.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use positioned_io::ReadAt;
+/// use qcow2::{MemBackend, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let bytes: Arc<[u8]> = std::fs::read("tests/test.qcow2")?.into();
+///
+/// // Borrow the bytes.
+/// let qcow = Qcow2::open(&bytes[..])?;
+/// let mut buf = [0; 512];
+/// qcow.reader()?.read_exact_at(0, &mut buf)?;
+///
+/// // Or share them between threads, without copying.
+/// let shared = bytes.clone();
+/// let other = thread::spawn(move || -> qcow2::Result<[u8; 512]> {
+///     let qcow = Qcow2::open(MemBackend(shared))?;
+///     let mut buf = [0; 512];
+///     qcow.reader()?.read_exact_at(0, &mut buf)?;
+///     Ok(buf)
+/// });
+/// assert_eq!(other.join().unwrap()?, buf);
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemBackend<T>(pub T);
+
+impl<T: AsRef<[u8]>> ReadAt for MemBackend<T> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.as_ref().read_at(pos, buf)
+    }
+}
+
+impl<T: AsRef<[u8]>> Size for MemBackend<T> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.0.as_ref().len() as u64))
+    }
+}
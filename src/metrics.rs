@@ -0,0 +1,153 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Qcow2};
+
+
+/// Counters of the reading an image has done, such as for capacity planning.
+///
+/// Metrics are off by default, turn them on with `OpenOptions::metrics`. Clones of an image
+/// share its metrics. Reads of a backing image are counted by the backing image's own metrics,
+/// if it has them.
+///
+/// Counters are updated separately while reads are happening, so they may not be quite
+/// consistent with each other until reads stop.
+///
+/// # Examples
+///
+/// ```
+/// use positioned_io::ReadAt;
+/// use qcow2::OpenOptions;
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let file = std::fs::File::open("tests/test.qcow2")?;
+/// let qcow = OpenOptions::new().metrics(true).open(file)?;
+/// let mut buf = vec![0; 1 << 20];
+/// qcow.reader()?.read_exact_at(0, &mut buf)?;
+/// let metrics = qcow.metrics().unwrap();
+/// assert_eq!(metrics.guest_bytes(), 1 << 20);
+/// println!("read amplification: {:?}", metrics.read_amplification());
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    guest_bytes: AtomicU64,
+    physical_bytes: AtomicU64,
+    physical_reads: AtomicU64,
+    metadata_reads: AtomicU64,
+    data_reads: AtomicU64,
+    zero_bytes: AtomicU64,
+    reserved_entries: AtomicU64,
+}
+
+impl Metrics {
+    /// Get the number of bytes of the virtual disk that were read.
+    pub fn guest_bytes(&self) -> u64 {
+        self.guest_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes read from storage, including metadata.
+    pub fn physical_bytes(&self) -> u64 {
+        self.physical_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of reads from storage.
+    ///
+    /// This is the sum of `metadata_reads` and `data_reads`.
+    pub fn physical_reads(&self) -> u64 {
+        self.physical_reads.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of reads of L1 and L2 tables from storage.
+    ///
+    /// Tables found in the metadata cache aren't counted.
+    pub fn metadata_reads(&self) -> u64 {
+        self.metadata_reads.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of reads of guest data from storage.
+    pub fn data_reads(&self) -> u64 {
+        self.data_reads.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes of the virtual disk that read as zeros without reading storage.
+    pub fn zero_bytes(&self) -> u64 {
+        self.zero_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of table entries read whose reserved bits were ignored.
+    ///
+    /// This is only ever non-zero if `OpenOptions::reserved_bits` allows such entries.
+    pub fn reserved_entries(&self) -> u64 {
+        self.reserved_entries.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of bytes read from storage for each byte of the virtual disk read.
+    ///
+    /// Returns `None` if nothing has been read from the virtual disk.
+    pub fn read_amplification(&self) -> Option<f64> {
+        match self.guest_bytes() {
+            0 => None,
+            guest => Some(self.physical_bytes() as f64 / guest as f64),
+        }
+    }
+
+    /// Set every counter back to zero.
+    pub fn reset(&self) {
+        for counter in [&self.guest_bytes,
+                        &self.physical_bytes,
+                        &self.physical_reads,
+                        &self.metadata_reads,
+                        &self.data_reads,
+                        &self.zero_bytes,
+                        &self.reserved_entries] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn add_guest(&self, bytes: u64) {
+        self.guest_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_metadata(&self, bytes: u64) {
+        self.metadata_reads.fetch_add(1, Ordering::Relaxed);
+        self.add_physical(bytes);
+    }
+
+    pub(crate) fn add_data(&self, bytes: u64) {
+        self.data_reads.fetch_add(1, Ordering::Relaxed);
+        self.add_physical(bytes);
+    }
+
+    pub(crate) fn add_zero(&self, bytes: u64) {
+        self.zero_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_reserved(&self) {
+        self.reserved_entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_physical(&self, bytes: u64) {
+        self.physical_reads.fetch_add(1, Ordering::Relaxed);
+        self.physical_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the counters of reading this image, if they were turned on with
+    /// `OpenOptions::metrics`.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_deref()
+    }
+
+    // Update the metrics, if there are any.
+    pub(crate) fn count<F: FnOnce(&Metrics)>(&self, f: F) {
+        if let Some(ref metrics) = self.metrics {
+            f(metrics);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{Error, Qcow2, Qcow2Local, Result};
+use super::cache::{CachePolicy, DEFAULT_CACHE_SIZE, ImageId, LocalCache, LruMetadataCache,
+                   MetadataCache, SharedCache};
+use super::header;
+use super::snapshot;
+
+
+/// Options for opening a qcow2 image.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use std::fs::File;
+/// # use std::sync::Arc;
+/// use qcow2::{LruMetadataCache, OpenOptions};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// // Share one cache between two images.
+/// let cache = Arc::new(LruMetadataCache::new(4 << 20));
+/// let first = OpenOptions::new()
+///     .cache(cache.clone())
+///     .open(File::open("first.qcow2")?)?;
+/// let second = OpenOptions::new()
+///     .cache(cache)
+///     .open(File::open("second.qcow2")?)?;
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Clone, Default)]
+pub struct OpenOptions {
+    cache: Option<Arc<dyn MetadataCache>>,
+    cache_size: Option<usize>,
+    image_id: Option<ImageId>,
+    truncated: Truncated,
+    misaligned_data: bool,
+    reserved_bits: bool,
+    max_extensions: Option<usize>,
+    strict: bool,
+    metrics: bool,
+}
+
+/// What to do when guest data lies past the end of the qcow2 file.
+///
+/// This can happen with interrupted copies, or buggy writers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Truncated {
+    /// Fail with `Error::FileFormat`, naming the guest and host offsets.
+    #[default]
+    Error,
+    /// Read the missing data as zeros. This is useful for salvaging data from damaged images.
+    Zero,
+}
+
+impl OpenOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use a custom cache for metadata.
+    ///
+    /// By default, each image gets its own `LruMetadataCache`, limited to `DEFAULT_CACHE_SIZE`
+    /// bytes. The same cache may be used for many images. A `Qcow2Local` always has a cache of
+    /// its own, so this is ignored by `open_local`.
+    pub fn cache(&mut self, cache: Arc<dyn MetadataCache>) -> &mut Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Set the memory limit of the cache an image gets of its own, in bytes.
+    ///
+    /// By default the limit is `DEFAULT_CACHE_SIZE`. This doesn't affect a cache chosen with
+    /// `cache`.
+    pub fn cache_size(&mut self, bytes: usize) -> &mut Self {
+        self.cache_size = Some(bytes);
+        self
+    }
+
+    /// Set the identity the image uses for caching.
+    ///
+    /// By default, each image gets a unique identity. Images sharing a cache and an identity
+    /// also share cache entries, see `ImageId`.
+    pub fn image_id(&mut self, id: ImageId) -> &mut Self {
+        self.image_id = Some(id);
+        self
+    }
+
+    /// Choose what to do when guest data lies past the end of the file.
+    ///
+    /// By default, reading such data is an error.
+    pub fn truncated(&mut self, truncated: Truncated) -> &mut Self {
+        self.truncated = truncated;
+        self
+    }
+
+    /// Read data clusters that aren't cluster aligned.
+    ///
+    /// The qcow2 specification requires data clusters to start on a cluster boundary, so by
+    /// default reading from a misaligned one is an error. Allowing them reads the data from
+    /// wherever the L2 entry points, which may help salvage data from damaged images.
+    pub fn misaligned_data(&mut self, allow: bool) -> &mut Self {
+        self.misaligned_data = allow;
+        self
+    }
+
+    /// Read L1 and L2 entries that use reserved bits, ignoring those bits.
+    ///
+    /// By default such entries are an error. Images written by experimental versions of qemu
+    /// may set bits that aren't part of the specification yet. When allowed, each use is logged
+    /// as a warning and counted by `Metrics::reserved_entries`. `Qcow2::check` reports them
+    /// either way.
+    pub fn reserved_bits(&mut self, allow: bool) -> &mut Self {
+        self.reserved_bits = allow;
+        self
+    }
+
+    /// Set the most header extensions an image may have.
+    ///
+    /// Images with more fail to open, so a hostile header can't make opening slow. The default
+    /// of 64 is far more than qemu ever writes.
+    pub fn max_header_extensions(&mut self, max: usize) -> &mut Self {
+        self.max_extensions = Some(max);
+        self
+    }
+
+    /// Check that everything the qcow2 specification says must be zero is zero.
+    ///
+    /// This covers padding in the header and its extensions, and the end of the extensions.
+    /// Duplicate header extensions that this library doesn't understand are also reported.
+    /// Problems don't stop the image from opening, they're reported by `Qcow2::check` along
+    /// with any others. This is meant for validating images, by default nothing is checked.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Count the reading done by the image, see `Metrics`.
+    ///
+    /// Counting is cheap, but not free, so by default it's off.
+    pub fn metrics(&mut self, enabled: bool) -> &mut Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Open a source of data as a qcow2 image, using these options.
+    pub fn open<I: ReadAt>(&self, io: I) -> Result<Qcow2<I>> {
+        self.open_inner(io, None, self.shared_cache())
+    }
+
+    /// Open a source of data as a qcow2 image that's only used from one thread.
+    ///
+    /// Its metadata cache takes no locks, see `Qcow2Local`.
+    pub fn open_local<I: ReadAt>(&self, io: I) -> Result<Qcow2Local<I>> {
+        self.open_inner(io, None, self.local_cache())
+    }
+
+    /// Open a qcow2 image whose guest data is stored in an external data file.
+    ///
+    /// The name of the data file can be found by first opening the image normally, and calling
+    /// `Qcow2::data_file_name`. If the image doesn't use an external data file, `data_file` is
+    /// ignored.
+    pub fn open_with_data_file<I: ReadAt>(&self, io: I, data_file: I) -> Result<Qcow2<I>> {
+        self.open_inner(io, Some(data_file), self.shared_cache())
+    }
+
+    /// Open a qcow2 image with an external data file, that's only used from one thread.
+    ///
+    /// This is like `open_with_data_file`, see `open_local`.
+    pub fn open_local_with_data_file<I: ReadAt>(&self,
+                                                io: I,
+                                                data_file: I)
+                                                -> Result<Qcow2Local<I>> {
+        self.open_inner(io, Some(data_file), self.local_cache())
+    }
+
+    fn shared_cache(&self) -> SharedCache {
+        let size = self.cache_size.unwrap_or(DEFAULT_CACHE_SIZE);
+        SharedCache::new(match self.cache {
+            Some(ref c) => c.clone(),
+            None => Arc::new(LruMetadataCache::new(size)),
+        })
+    }
+
+    fn local_cache(&self) -> LocalCache {
+        LocalCache::new(self.cache_size.unwrap_or(DEFAULT_CACHE_SIZE))
+    }
+
+    fn open_inner<I, C>(&self, io: I, data_file: Option<I>, cache: C) -> Result<Qcow2<I, C>>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        let mut q = self.open_header(io, data_file, cache)?;
+        open_tables(&mut q)?;
+        Ok(q)
+    }
+
+    // Start opening an image by reading its header, for `open_chain`. The image can't be used
+    // until `open_tables` reads the rest.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn start_open<I: ReadAt>(&self, io: I) -> Result<Qcow2<I>> {
+        self.open_header(io, None, self.shared_cache())
+    }
+
+    // Read and check the header of an image, the first step of opening it.
+    fn open_header<I, C>(&self, io: I, data_file: Option<I>, cache: C) -> Result<Qcow2<I, C>>
+        where I: ReadAt,
+              C: CachePolicy
+    {
+        let io: ByteIo<_, BigEndian> = ByteIo::new(io);
+        let mut q = Qcow2 {
+            meta: Default::default(),
+            io,
+            data_file,
+            backing: None,
+            l2_cache: cache,
+            image_id: self.image_id.unwrap_or_else(ImageId::unique),
+            truncated: self.truncated,
+            misaligned_data: self.misaligned_data,
+            reserved_bits: self.reserved_bits,
+            header_options: header::ReadOptions {
+                max_extensions: self.max_extensions.unwrap_or(header::DEFAULT_MAX_EXTENSIONS),
+                strict: self.strict,
+            },
+            handles: Arc::new(()),
+            metrics: if self.metrics { Some(Default::default()) } else { None },
+        };
+        let mut header = header::Header::default();
+        header.read(&mut q.io, q.header_options)?;
+        *q.header_mut() = header;
+        if !q.header().has_data_file() {
+            q.data_file = None;
+        }
+        Ok(q)
+    }
+}
+
+// Finish opening an image whose header has been read, by checking the file is long enough and
+// reading the snapshot table.
+pub(crate) fn open_tables<I, C>(q: &mut Qcow2<I, C>) -> Result<()>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    check_length(q)?;
+    let snapshots = snapshot::read_snapshots(&q.io, q.header())?.0;
+    q.set_snapshots(snapshots);
+    Ok(())
+}
+
+// Fail early if the file is too short for the tables the header points to, as happens with
+// partial downloads. Otherwise that would only show up as an unexpected EOF once a table is
+// read. The storage may not know its size, so this checks by reading the last byte needed.
+fn check_length<I: ReadAt, C: CachePolicy>(q: &Qcow2<I, C>) -> Result<()> {
+    let c = &q.header().c;
+    let tables = [
+        (c.refcount_table_offset, c.refcount_table_clusters as u64 * q.cluster_size()),
+        (c.l1_table_offset, c.l1_size as u64 * size_of::<u64>() as u64),
+        snapshot::table_bounds(q.header())?,
+    ];
+    let end = tables.iter()
+        .filter(|&&(_, len)| len > 0)
+        .map(|&(offset, len)| offset.saturating_add(len))
+        .max()
+        .unwrap_or(0);
+    if end == 0 || has_byte(&q.io, end - 1)? {
+        return Ok(());
+    }
+
+    // Search for the actual size. This is slow, but only happens for broken files.
+    let (mut lo, mut hi) = (0, end - 1);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if has_byte(&q.io, mid)? {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    Err(Error::Truncated {
+        expected_at_least: end,
+        actual: lo,
+    })
+}
+
+// Check whether there's any data at a position.
+pub(crate) fn has_byte<I: ReadAt>(io: &I, pos: u64) -> Result<bool> {
+    let mut buf = [0];
+    match Qcow2::read_partial_at(io, pos, &mut buf) {
+        Ok(read) => Ok(read > 0),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
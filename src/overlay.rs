@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::{Error, Qcow2, Result};
+use super::create::{DEFAULT_CLUSTER_BITS, new_header};
+use super::header::{Header, MAX_BACKING_FILE_NAME};
+use super::probe::probe;
+
+
+/// Choices for creating an overlay with `create_overlay`.
+///
+/// By default, the overlay has the same virtual size and cluster size as its base, and the
+/// base is named by a path relative to the overlay.
+#[derive(Debug, Clone, Default)]
+pub struct OverlayOptions {
+    size: Option<u64>,
+    cluster_bits: Option<u32>,
+    absolute_backing: bool,
+}
+
+impl OverlayOptions {
+    /// Create a new set of options, with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the overlay a virtual disk of this size, rather than the size of the base.
+    ///
+    /// It can't be smaller than the base. Past the end of the base, the overlay reads as zeros.
+    pub fn size(&mut self, size: u64) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Give the overlay clusters of `1 << bits` bytes, from 512 bytes to 2 MiB.
+    ///
+    /// By default, this is the cluster size of the base if it's a qcow2 image, or else 64 KiB.
+    pub fn cluster_bits(&mut self, bits: u32) -> &mut Self {
+        self.cluster_bits = Some(bits);
+        self
+    }
+
+    /// Name the base by its absolute path, rather than relative to the overlay.
+    ///
+    /// A relative name keeps working if the directories holding both images are moved
+    /// together. An absolute one keeps working if only the overlay is moved.
+    pub fn absolute_backing(&mut self, absolute: bool) -> &mut Self {
+        self.absolute_backing = absolute;
+        self
+    }
+}
+
+/// Create a new, empty qcow2 image at `dst`, using the image at `base` as its backing file.
+///
+/// Until it's written to, the overlay reads the same as the base. Writes go to the overlay,
+/// leaving the base as it was, so this is how to take an external snapshot: keep the base, and
+/// point the guest at the overlay. The base may be a qcow2 or raw image, and its format is
+/// recorded in the overlay, so it doesn't need to be guessed.
+///
+/// The base is opened read-only and checked to be readable first, and nothing in it changes.
+/// The overlay names the base by a path relative to the overlay's directory, which is how qemu
+/// resolves it, unless `OverlayOptions::absolute_backing` is set. `dst` must not already
+/// exist. If creating the overlay fails, nothing is left at `dst`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use qcow2::{OverlayOptions, create_overlay};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// create_overlay("base.qcow2", "snapshot.qcow2", &OverlayOptions::new())?;
+/// let chain = qcow2::backing_chain("snapshot.qcow2");
+/// assert_eq!(chain.len(), 2);
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn create_overlay<P, Q>(base: P, dst: Q, opts: &OverlayOptions) -> Result<()>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>
+{
+    let (base, dst) = (base.as_ref(), dst.as_ref());
+    let mut header = overlay_header(base, dst, opts)?;
+    // Any nonzero offset marks the image as having a backing file. The real offset is found
+    // when the header is written.
+    header.c.backing_file_offset = 1;
+
+    // The overlay is small, so it's put together in memory. Since `File` isn't `Storage`
+    // everywhere, it's then written sequentially, with the header last like `write_metadata`.
+    let cluster_size = header.cluster_size();
+    let mut img = Vec::new();
+    Qcow2::<Vec<u8>>::write_metadata(&mut img,
+                                     header,
+                                     &BTreeMap::new(),
+                                     &BTreeMap::new(),
+                                     cluster_size)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(dst)?;
+    let result = (|| {
+        let header_end = cluster_size as usize;
+        file.seek(SeekFrom::Start(cluster_size))?;
+        file.write_all(&img[header_end..])?;
+        file.sync_data()?;
+        file.rewind()?;
+        file.write_all(&img[..header_end])?;
+        file.sync_data()
+    })();
+    if result.is_err() {
+        drop(file);
+        let _ = fs::remove_file(dst);
+    }
+    Ok(result?)
+}
+
+// Find the header of an overlay, with its size, cluster size and backing file.
+fn overlay_header(base: &Path, dst: &Path, opts: &OverlayOptions) -> Result<Header> {
+    let file = File::open(base)?;
+    let (format, base_size, base_bits) = if probe(&file)?.is_qcow2 {
+        let q = Qcow2::open(file)?;
+        q.ensure_readable()?;
+        ("qcow2", q.guest_size(), q.header().c.cluster_bits)
+    } else {
+        ("raw", file.metadata()?.len(), DEFAULT_CLUSTER_BITS)
+    };
+
+    let size = opts.size.unwrap_or(base_size);
+    if size < base_size {
+        return Err(Error::UnsupportedFeature("overlays smaller than their base".to_owned()));
+    }
+    let mut header = new_header(opts.cluster_bits.unwrap_or(base_bits), size)?;
+    header.v3.backing_file_name = backing_name(base, dst, opts.absolute_backing)?;
+    if header.v3.backing_file_name.as_os_str().len() > MAX_BACKING_FILE_NAME {
+        return Err(Error::UnsupportedFeature(format!("backing file names longer than {} bytes",
+                                                     MAX_BACKING_FILE_NAME)));
+    }
+    header.v3.backing_format.0 = Some(format.to_owned());
+    Ok(header)
+}
+
+// Find the name an overlay at `dst` should use for its backing file at `base`.
+//
+// Both are made absolute, following symlinks, and the name is relative to the directory of the
+// overlay unless it must be absolute. If the two have no root in common, such as on different
+// drives on Windows, the name is absolute anyway.
+fn backing_name(base: &Path, dst: &Path, absolute: bool) -> Result<PathBuf> {
+    let base = fs::canonicalize(base)?;
+    if absolute {
+        return Ok(base);
+    }
+    let dir = match dst.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = fs::canonicalize(dir)?;
+
+    let mut base_parts = base.components().peekable();
+    let mut dir_parts = dir.components().peekable();
+    let mut common = 0;
+    while base_parts.peek().is_some() && base_parts.peek() == dir_parts.peek() {
+        base_parts.next();
+        dir_parts.next();
+        common += 1;
+    }
+    if common == 0 {
+        return Ok(base);
+    }
+    let mut name: PathBuf = dir_parts.map(|_| "..").collect();
+    name.extend(base_parts);
+    Ok(name)
+}
@@ -0,0 +1,72 @@
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::ReadAt;
+
+use super::Result;
+use super::header::MAGIC;
+
+
+// Size of the header fields common to all versions.
+const COMMON_HEADER_SIZE: usize = 72;
+
+/// Basic facts about a file that may be a qcow2 image, found by `probe`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Probe {
+    /// Whether the file starts with the qcow2 magic number. If not, the other fields are zero.
+    pub is_qcow2: bool,
+    /// The version of the qcow2 format.
+    pub version: u32,
+    /// The size of the virtual disk.
+    pub virtual_size: u64,
+    /// The number of bits in a guest offset that address bytes within a cluster.
+    pub cluster_bits: u32,
+    /// The encryption method. Zero means no encryption.
+    pub crypt_method: u32,
+    /// Whether the image has a backing file.
+    pub has_backing_file: bool,
+}
+
+/// Quickly find out whether a source of data is a qcow2 image, and some basic facts about it.
+///
+/// Only the start of the header is read, and nothing is validated, so this is much cheaper
+/// than opening the image. It also succeeds for images that `Qcow2::open` would reject, such as
+/// those with unknown features or encryption. Files too short to hold a header aren't qcow2
+/// images.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use std::fs::File;
+/// # fn foo() -> qcow2::Result<()> {
+/// let probe = qcow2::probe(&File::open("image.qcow2")?)?;
+/// if probe.is_qcow2 {
+///     println!("version {}, backing file: {}", probe.version, probe.has_backing_file);
+/// }
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn probe<I: ReadAt + ?Sized>(io: &I) -> Result<Probe> {
+    let mut buf = [0; COMMON_HEADER_SIZE];
+    let mut len = 0;
+    while len < buf.len() {
+        match io.read_at(len as u64, &mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if len < buf.len() || BigEndian::read_u32(&buf[0..]) != MAGIC {
+        return Ok(Probe::default());
+    }
+
+    Ok(Probe {
+        is_qcow2: true,
+        version: BigEndian::read_u32(&buf[4..]),
+        virtual_size: BigEndian::read_u64(&buf[24..]),
+        cluster_bits: BigEndian::read_u32(&buf[20..]),
+        crypt_method: BigEndian::read_u32(&buf[32..]),
+        has_backing_file: BigEndian::read_u64(&buf[8..]) != 0,
+    })
+}
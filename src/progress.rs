@@ -0,0 +1,210 @@
+use std::fmt::{self, Display, Formatter};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Error, Result};
+
+
+// The least of the virtual disk to get through between progress reports, so reporting doesn't
+// slow down copying.
+pub(crate) const REPORT_INTERVAL: u64 = 1 << 20;
+
+/// A step of a long operation, see `Progress::phase`.
+///
+/// Each phase says what its progress counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Copying the virtual disk, such as to export or convert it. Progress counts bytes of the
+    /// virtual disk.
+    Copying,
+    /// Reading the L1 and L2 tables of the image and its snapshots. Progress counts L1 table
+    /// entries, since each may point to an L2 table.
+    ScanningTables,
+    /// Reading the data of the virtual disk, such as to hash it. Progress counts bytes of the
+    /// virtual disk.
+    Reading,
+    /// Comparing the refcount of each cluster with the references to it. Progress counts
+    /// clusters of the image file.
+    CheckingRefcounts,
+    /// Writing changes to the image. Progress counts changes.
+    Writing,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Phase::Copying => "copying",
+            Phase::ScanningTables => "scanning tables",
+            Phase::Reading => "reading",
+            Phase::CheckingRefcounts => "checking refcounts",
+            Phase::Writing => "writing",
+        })
+    }
+}
+
+/// Something told how far a long operation has got, which can ask for it to stop.
+///
+/// Exporting, copying, checking, repairing, measuring and mapping an image all report progress
+/// this way, such as `Reader::export_raw_reporting` and `Qcow2::check_reporting`, so one
+/// progress bar can show any of them. An operation is made of one or more phases, each
+/// announced by `phase` with the number of phases, so the total work is known up front. Within
+/// a phase, reports come at least once for each megabyte of data, or each table or change, and
+/// once more when the phase is done.
+///
+/// Functions taking the same arguments as `update` are a `Progress`, which ignores phases.
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+/// use std::ops::ControlFlow;
+/// use qcow2::{Error, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let mut out = std::io::Cursor::new(Vec::new());
+///
+/// // Stop once a quarter of the disk is done.
+/// let last = Cell::new(0);
+/// let progress = |done, total, _physical| {
+///     last.set(done);
+///     if done * 4 >= total { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+/// };
+/// let result = qcow.reader()?.export_raw_reporting(&mut out, true, &progress);
+/// assert!(matches!(result, Err(Error::Cancelled)));
+/// assert_eq!(last.get(), qcow.guest_size() / 4);
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub trait Progress {
+    /// Report progress, and say whether to go on.
+    ///
+    /// `done` of the `total` work of the current phase has been handled, counted as the phase
+    /// says. When copying, these are bytes of the virtual disk, and `done_physical_bytes` have
+    /// been written to the destination, otherwise `done_physical_bytes` is zero. Returning
+    /// `ControlFlow::Break` stops the operation before it handles another cluster, and it fails
+    /// with `Error::Cancelled`.
+    fn update(&self, done: u64, total: u64, done_physical_bytes: u64) -> ControlFlow<()>;
+
+    /// Say that a phase of the operation is starting.
+    ///
+    /// This is phase `step`, counting from zero, of the `steps` phases of the operation.
+    /// Updates until the next phase is announced are for this one. By default, this does
+    /// nothing.
+    fn phase(&self, _phase: Phase, _step: usize, _steps: usize) {}
+}
+
+impl<F> Progress for F
+    where F: Fn(u64, u64, u64) -> ControlFlow<()>
+{
+    fn update(&self, done: u64, total: u64, physical: u64) -> ControlFlow<()> {
+        self(done, total, physical)
+    }
+}
+
+/// A flag for cancelling long operations, such as from another thread or a signal handler.
+///
+/// Clones share the same flag, so one clone can be given to an operation while another is kept
+/// to cancel it. A token is a `Progress`, so anything that reports progress takes one, such as
+/// `Qcow2::check_reporting` or `Qcow2::repair_all`. Operations check the flag whenever they
+/// report progress, and then fail with `Error::Cancelled`, so that callers can tell
+/// cancellation apart from failures. Each documents what it leaves behind when cancelled.
+///
+/// # Examples
+///
+/// ```
+/// use qcow2::{CancelToken, Error, Qcow2};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+/// let token = CancelToken::new();
+/// assert!(qcow.check_reporting(&token)?.is_clean());
+///
+/// token.clone().cancel();
+/// assert!(matches!(qcow.check_reporting(&token), Err(Error::Cancelled)));
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a token that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation using this token, or any clone of it, to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether this token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Progress for CancelToken {
+    fn update(&self, _done: u64, _total: u64, _physical: u64) -> ControlFlow<()> {
+        if self.is_cancelled() { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    }
+}
+
+// Passes progress on to a `Progress`, only once in a while.
+pub struct Reporter<'p, P: 'p + Progress + ?Sized> {
+    progress: &'p P,
+    total: u64,
+    // How far to get between reports.
+    interval: u64,
+    // How far to get before the next report.
+    next: u64,
+}
+
+impl<'p, P> Reporter<'p, P>
+    where P: 'p + Progress + ?Sized
+{
+    // Start reporting on copying a virtual disk, an operation with just that one phase.
+    pub fn new(progress: &'p P, total: u64) -> Self {
+        progress.phase(Phase::Copying, 0, 1);
+        Reporter {
+            progress,
+            total,
+            interval: REPORT_INTERVAL,
+            next: REPORT_INTERVAL,
+        }
+    }
+
+    // Start reporting on a phase, which is reported once right away, then whenever another
+    // `interval` is done.
+    pub fn phase(progress: &'p P,
+                 phase: Phase,
+                 (step, steps): (usize, usize),
+                 total: u64,
+                 interval: u64)
+                 -> Self {
+        progress.phase(phase, step, steps);
+        Reporter {
+            progress,
+            total,
+            interval,
+            next: 0,
+        }
+    }
+
+    // Report that everything is done.
+    pub fn finish(&mut self, physical: u64) -> Result<()> {
+        self.update(self.total, physical)
+    }
+
+    // Report progress, if enough was done since the last report, or if everything is done.
+    pub fn update(&mut self, done: u64, physical: u64) -> Result<()> {
+        if done < self.next && done < self.total {
+            return Ok(());
+        }
+        self.next = done + self.interval;
+        match self.progress.update(done, self.total, physical) {
+            ControlFlow::Continue(()) => Ok(()),
+            ControlFlow::Break(()) => Err(Error::Cancelled),
+        }
+    }
+}
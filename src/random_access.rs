@@ -0,0 +1,101 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use positioned_io::{ReadAt, Size, WriteAt};
+
+use super::{Qcow2, Result};
+use super::write::Storage;
+
+
+/// A file accessed through `RandomAccessFile` from positioned-io 0.3.
+///
+/// Every read and write says where it goes, without using the file's seek position, on every
+/// platform. On Windows, that makes it faster than `File`, and anything else using the same
+/// file isn't disturbed. On Linux, the operating system is also told to expect reads in random
+/// order, so it doesn't read ahead. Share one between threads with a `SharedBackend`.
+#[derive(Debug)]
+pub struct RandomAccessFile {
+    raf: positioned_io_03::RandomAccessFile,
+    // Another handle on the same file, to sync and resize it, which the wrapper can't do.
+    file: File,
+}
+
+impl RandomAccessFile {
+    /// Open a file for reading only.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+
+    /// Open a file for reading and writing.
+    pub fn open_rw<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(fs::OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    /// Use a file that's already open.
+    pub fn new(file: File) -> io::Result<Self> {
+        let handle = file.try_clone()?;
+        let raf = positioned_io_03::RandomAccessFile::try_new(file)?;
+        Ok(RandomAccessFile { raf, file: handle })
+    }
+
+    /// Get the underlying file.
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl ReadAt for RandomAccessFile {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        positioned_io_03::ReadAt::read_at(&self.raf, pos, buf)
+    }
+}
+
+impl WriteAt for RandomAccessFile {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        positioned_io_03::WriteAt::write_at(&mut self.raf, pos, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        positioned_io_03::WriteAt::flush(&mut self.raf)
+    }
+}
+
+impl Size for RandomAccessFile {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.file.metadata()?.len()))
+    }
+}
+
+impl Storage for RandomAccessFile {
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
+
+impl Qcow2<RandomAccessFile> {
+    /// Open an image file for reading, through a `RandomAccessFile`.
+    ///
+    /// To write to the image, open it with `RandomAccessFile::open_rw` and `Qcow2::open`
+    /// instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use positioned_io::ReadAt;
+    /// use qcow2::Qcow2;
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::open_random_access("tests/test.qcow2")?;
+    /// let mut buf = [0; 512];
+    /// qcow.reader()?.read_exact_at(0, &mut buf)?;
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn open_random_access<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Qcow2::open(RandomAccessFile::open(path)?)
+    }
+}
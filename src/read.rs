@@ -1,16 +1,26 @@
 use std::cmp::min;
 use std::io;
 use std::mem::size_of;
+use std::sync::Arc;
 
-use byteorder::BigEndian;
+use byteorder::{BigEndian, ByteOrder};
 use positioned_io::{ByteIo, ReadAt, ReadIntAt, Size};
 
-use super::{Error, Qcow2, Result};
+use super::{CacheKey, CachePolicy, CompressionType, Error, Qcow2, Result, SharedCache, Snapshot,
+            Truncated};
+use super::advise::ReadAhead;
+use super::compress;
+use super::geometry::MAX_L1_ENTRIES;
+use super::int::to_usize;
+use super::options::has_byte;
+use super::header::Header;
+use super::snapshot;
 
 
-const L1_COW: u64 = 1 << 63;
-const L1_RESERVED: u64 = (0x7F << 56) | 0xFF;
-const L1_POS: u64 = !(L1_COW | L1_RESERVED);
+pub const L1_COW: u64 = 1 << 63;
+pub const L1_RESERVED: u64 = (0x7F << 56) | 0xFF;
+pub const L1_POS: u64 = !(L1_COW | L1_RESERVED);
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum L1Entry {
     Empty,
@@ -20,15 +30,20 @@ pub enum L1Entry {
     },
 }
 
-const L2_COW: u64 = 1 << 63;
-const L2_COMPRESSED: u64 = 1 << 62;
-const L2_ZERO: u64 = 1;
-const L2_RESERVED: u64 = (0x3F << 56) | 0xFE;
-const L2_POS: u64 = !(L2_COW | L2_COMPRESSED | L2_ZERO | L2_RESERVED);
+pub const L2_COW: u64 = 1 << 63;
+pub const L2_COMPRESSED: u64 = 1 << 62;
+pub const L2_ZERO: u64 = 1;
+pub const L2_RESERVED: u64 = (0x3F << 56) | 0xFE;
+pub const L2_POS: u64 = !(L2_COW | L2_COMPRESSED | L2_ZERO | L2_RESERVED);
 const L2_COMPRESSED_MASK: u64 = !(L2_COW | L2_COMPRESSED);
-#[derive(Debug)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
 pub enum L2Entry {
     Empty,
+    // Reads as zeros, without a cluster.
+    Zero {
+        cow: bool,
+    },
     Standard {
         pos: u64,
         cow: bool,
@@ -42,163 +57,733 @@ pub enum L2Entry {
 }
 
 
+// Helpers that don't depend on how the image caches metadata.
 impl<I> Qcow2<I>
     where I: ReadAt
+{
+    pub(crate) fn zero_fill(buf: &mut [u8]) {
+        for i in buf {
+            *i = 0;
+        }
+    }
+    // Read as much as possible, stopping early only at the end of the file.
+    pub(crate) fn read_partial_at(io: &I, mut pos: u64, mut buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            match io.read_at(pos, buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                    pos += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(len - buf.len())
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
 {
     /// Get a Reader for the main virtual disk.
     ///
     /// This allows data to be read from inside the virtual disk image.
-    pub fn reader(&self) -> Result<Reader<I>> {
-        let offset = self.header.c.l1_table_offset;
-        let reader = Reader::new(self, offset)?;
-        Ok(reader)
+    pub fn reader(&self) -> Result<Reader<'_, I, C>> {
+        self.reader_builder().active()
+    }
+
+    /// Get a Reader for the virtual disk as it was when a snapshot was taken.
+    ///
+    /// The snapshot may be identified by either its ID or its name, see `Qcow2::find_snapshot`.
+    pub fn snapshot_reader(&self, name: &str) -> Result<Reader<'_, I, C>> {
+        self.reader_builder().snapshot(name)
+    }
+
+    /// Turn this image into a reader of its main virtual disk.
+    ///
+    /// Unlike a `Reader`, this doesn't borrow the image, so it can be kept anywhere. The
+    /// image and its storage are dropped along with it.
+    pub fn into_reader(self) -> Result<OwnedReader<I, C>> {
+        let Reader { l1, size, .. } = self.reader()?;
+        Ok(OwnedReader { q: self, l1, size })
+    }
+
+    /// Turn this image into a reader of the virtual disk as it was when a snapshot was taken.
+    ///
+    /// See `into_reader` and `snapshot_reader`.
+    pub fn into_snapshot_reader(self, name: &str) -> Result<OwnedReader<I, C>> {
+        let Reader { l1, size, .. } = self.snapshot_reader(name)?;
+        Ok(OwnedReader { q: self, l1, size })
+    }
+
+    /// Choose which L1 table a Reader should read through.
+    ///
+    /// Most callers want `reader` or `snapshot_reader`, which are shortcuts for this.
+    pub fn reader_builder(&self) -> ReaderBuilder<'_, I, C> {
+        ReaderBuilder { q: self }
+    }
+
+    // Find the L1 table of a snapshot, and the size of its virtual disk.
+    fn snapshot_l1(&self, snap: &Snapshot, guest_size: u64) -> Result<(u64, u64, u64)> {
+        // Old snapshots don't record a disk size, assume the current one.
+        let size = snap.disk_size.unwrap_or(guest_size);
+        let needed = size.div_ceil(self.cluster_size()).div_ceil(self.header().l2_entries());
+        if (snap.l1_size as u64) < needed {
+            return Err(Error::FileFormat(format!("L1 table of snapshot {} is too small",
+                                                 snap.id)));
+        }
+        Ok((snap.l1_table_offset, snap.l1_size as u64, size))
+    }
+
+    // Make sure an L1 table is somewhere it could be read from. An empty table, as in an image
+    // with a virtual size of zero, has nothing to read, and qemu leaves its offset at zero.
+    fn l1_check(&self, offset: u64, entries: u64) -> Result<()> {
+        if (offset == 0 && entries > 0) || !offset.is_multiple_of(self.cluster_size()) {
+            return Err(Error::FileFormat(format!("L1 table at {:#x} is misaligned", offset)));
+        }
+        if entries > MAX_L1_ENTRIES {
+            return Err(Error::FileFormat(format!("L1 table with {} entries is too large",
+                                                 entries)));
+        }
+        if offset.checked_add(entries * size_of::<u64>() as u64).is_none() {
+            return Err(Error::FileFormat(format!("L1 table at {:#x} is out of bounds", offset)));
+        }
+        Ok(())
     }
 
-    fn l1_entry_read<T: ReadIntAt>(&self, l1: &T, l1_l2_idx: u64) -> Result<L1Entry> {
+    // Read the header as it is now in the file.
+    fn header_load(&self) -> Result<Header> {
+        let mut header = Header::default();
+        header.read(&mut ByteIo::new(&*self.io), self.header_options)?;
+        if header.cluster_size() != self.cluster_size() {
+            return Err(Error::FileFormat("cluster size changed".to_owned()));
+        }
+        Ok(header)
+    }
+
+    /// Pick up changes made to the image by something else, such as qemu.
+    ///
+    /// Cached metadata of this image is dropped, and the header and snapshot table are read
+    /// again. Clones of this image see the new header and snapshots too, and readers created
+    /// afterwards from any of them see the image as it is now. Existing readers keep their own
+    /// L1 table, see `Reader::refresh`. It's up to the caller to make sure nothing is writing to
+    /// the image meanwhile.
+    pub fn invalidate_caches(&mut self) -> Result<()> {
+        let header = self.header_load()?;
+        let snapshots = snapshot::read_snapshots(&self.io, &header)?.0;
+        self.l2_cache.invalidate_image(self.image_id);
+        self.refresh_meta(header, snapshots);
+        Ok(())
+    }
+
+    pub(crate) fn l1_entry_read<T: ReadIntAt>(&self, l1: &T, l1_l2_idx: u64) -> Result<L1Entry> {
         let offset = l1_l2_idx * size_of::<u64>() as u64;
         let entry = l1.read_u64_at(offset)?;
-        if entry & L1_RESERVED != 0 {
-            return Err(Error::FileFormat("reserved bit used in L1 entry".to_owned()));
-        }
+        self.reserved_check("L1 entry", entry, L1_RESERVED)?;
 
         let pos = entry & L1_POS;
         if pos == 0 {
             return Ok(L1Entry::Empty);
         }
+        if !pos.is_multiple_of(self.cluster_size()) {
+            return Err(Error::FileFormat(format!("L2 table for L1 index {} at {:#x} is \
+                                                  misaligned",
+                                                 l1_l2_idx,
+                                                 pos)));
+        }
         Ok(L1Entry::Standard {
             pos,
             cow: (entry & L1_COW != 0),
         })
     }
+    // Read an L2 table from the image, bypassing the cache.
+    pub(crate) fn l2_table_load(&self, l2_pos: u64) -> Result<Arc<[u64]>> {
+        let mut buf = vec![0; self.cluster_size() as usize];
+        self.io.read_exact_at(l2_pos, &mut buf)?;
+        self.count(|m| m.add_metadata(buf.len() as u64));
+        Ok(buf.chunks(size_of::<u64>()).map(BigEndian::read_u64).collect())
+    }
+    // Get an L2 table, from the cache if possible.
+    pub(crate) fn l2_table(&self, l2_pos: u64) -> Result<Arc<[u64]>> {
+        let key = CacheKey {
+            image: self.image_id,
+            offset: l2_pos,
+        };
+        self.l2_cache.l2_table(key, || self.l2_table_miss(l2_pos))
+    }
+    // Look at an L2 table without keeping it, from the cache if possible.
+    fn with_l2_table<R, F>(&self, l2_pos: u64, f: F) -> Result<R>
+        where F: FnOnce(&[u64]) -> R
+    {
+        let key = CacheKey {
+            image: self.image_id,
+            offset: l2_pos,
+        };
+        self.l2_cache.with_l2_table(key, || self.l2_table_miss(l2_pos), f)
+    }
+    fn l2_table_miss(&self, l2_pos: u64) -> Result<Arc<[u64]>> {
+        trace!("reading L2 table at {:#x}", l2_pos);
+        self.l2_table_load(l2_pos)
+    }
     fn l2_entry_read_raw(&self, l2_pos: u64, l2_block_idx: u64) -> Result<u64> {
-        let offset = l2_pos + l2_block_idx * size_of::<u64>() as u64;
-
-        // Check the cache.
-        let mut cache = self.l2_cache.lock()?;
-        if let Some(ret) = cache.get_mut(&offset) {
-            return Ok(*ret);
+        self.with_l2_table(l2_pos, |table| table.get(l2_block_idx as usize).copied())?
+            .ok_or_else(|| Error::Internal(format!("L2 index {} out of range", l2_block_idx)))
+    }
+    // Find the host offset and size of a compressed cluster from its L2 entry.
+    //
+    // The low `62 - (cluster_bits - 8)` bits hold the offset, and the bits above hold the number
+    // of 512-byte sectors minus one. The first sector starts at the offset rounded down, so the
+    // size counts from the offset to the end of the last sector.
+    pub(crate) fn compressed_descriptor(&self, entry: u64) -> (u64, u64) {
+        let x = 62 - (self.header().c.cluster_bits - 8);
+        let entry = entry & L2_COMPRESSED_MASK;
+        let pos = entry & ((1 << x) - 1);
+        let sectors = (entry >> x) + 1;
+        (pos, sectors * 512 - pos % 512)
+    }
+    // Make sure a table entry uses no reserved bits, unless `OpenOptions::reserved_bits` allows
+    // them to be ignored.
+    fn reserved_check(&self, what: &str, entry: u64, reserved: u64) -> Result<()> {
+        if entry & reserved == 0 {
+            return Ok(());
         }
-
-        let ret = self.io.read_u64_at(offset)?;
-        cache.insert(offset, ret);
-        Ok(ret)
+        if !self.reserved_bits {
+            return Err(Error::FileFormat(format!("reserved bit used in {}", what)));
+        }
+        warn!("ignoring reserved bits {:#x} in {} {:#x}", entry & reserved, what, entry);
+        self.count(|m| m.add_reserved());
+        Ok(())
     }
-    fn l2_entry_parse(&self, entry: u64) -> Result<L2Entry> {
+    pub(crate) fn l2_entry_parse(&self, entry: u64) -> Result<L2Entry> {
         let cow = entry & L2_COW != 0;
         Ok(if entry & L2_COMPRESSED != 0 {
-            let x = 70 - self.header.c.cluster_bits;
-            let entry = entry & L2_COMPRESSED_MASK;
-            let pos = entry & ((1 << x) - 1);
-            let size = (entry >> x) * 512;
+            let (pos, size) = self.compressed_descriptor(entry);
             L2Entry::Compressed {
                 pos,
                 cow,
                 size,
             }
         } else {
-            if entry & L2_RESERVED != 0 {
-                return Err(Error::FileFormat("reserved bit used in L2 entry".to_owned()));
-            }
+            self.reserved_check("L2 entry", entry, L2_RESERVED)?;
             let pos = entry & L2_POS;
+            let zero = entry & L2_ZERO != 0;
             if pos != 0 {
-                L2Entry::Standard {
-                    pos,
-                    cow,
-                    zero: (entry & L2_ZERO != 0),
-                }
+                L2Entry::Standard { pos, cow, zero }
+            } else if zero {
+                L2Entry::Zero { cow }
             } else {
                 L2Entry::Empty
             }
         })
     }
-    fn l2_entry_read<T: ReadIntAt>(&self, l1: &T, guest_offset: u64) -> Result<L2Entry> {
-        let (l1_l2_idx, l2_block_idx, _) = self.header.guest_offset_info(guest_offset);
+    pub(crate) fn l2_entry_read<T: ReadIntAt>(&self, l1: &T, guest_offset: u64) -> Result<L2Entry> {
+        // A raw data file has every cluster at its guest offset, whatever the L2 tables say.
+        if self.header().data_file_raw() {
+            return Ok(L2Entry::Standard {
+                pos: guest_offset - guest_offset % self.cluster_size(),
+                cow: true,
+                zero: false,
+            });
+        }
+        let (l1_l2_idx, l2_block_idx, _) = self.header().guest_offset_info(guest_offset);
         let l1_entry = self.l1_entry_read(l1, l1_l2_idx)?;
         Ok(match l1_entry {
             L1Entry::Empty => L2Entry::Empty,
             L1Entry::Standard { pos, .. } => {
                 let raw = self.l2_entry_read_raw(pos, l2_block_idx)?;
-                self.l2_entry_parse(raw)?
+                self.l2_data_entry_parse(guest_offset, raw)?
             }
         })
     }
-    fn zero_fill(buf: &mut [u8]) {
-        for i in buf {
-            *i = 0;
+    // Get the L2 entries for consecutive clusters, which must all be within one L2 table.
+    pub(crate) fn l2_entries_read<T: ReadIntAt>(&self,
+                                     l1: &T,
+                                     guest_offset: u64,
+                                     count: usize)
+                                     -> Result<Vec<L2Entry>> {
+        let cluster_size = self.cluster_size();
+        if self.header().data_file_raw() {
+            return (0..count as u64)
+                .map(|i| self.l2_entry_read(l1, guest_offset + i * cluster_size))
+                .collect();
         }
+        let (l1_l2_idx, l2_block_idx, _) = self.header().guest_offset_info(guest_offset);
+        let pos = match self.l1_entry_read(l1, l1_l2_idx)? {
+            L1Entry::Empty => return Ok(vec![L2Entry::Empty; count]),
+            L1Entry::Standard { pos, .. } => pos,
+        };
+        let start = l2_block_idx as usize;
+        let parse = |table: &[u64]| -> Result<Vec<L2Entry>> {
+            let entries = table.get(start..start + count).ok_or_else(|| {
+                Error::Internal(format!("L2 index {} out of range", start + count))
+            })?;
+            entries.iter()
+                .enumerate()
+                .map(|(i, &raw)| {
+                    self.l2_data_entry_parse(guest_offset + i as u64 * cluster_size, raw)
+                })
+                .collect()
+        };
+        self.with_l2_table(pos, parse)?
+    }
+    // Parse the L2 entry for a guest offset, making sure any data cluster it points to is
+    // cluster aligned, unless `OpenOptions::misaligned_data` allows otherwise.
+    fn l2_data_entry_parse(&self, guest_offset: u64, raw: u64) -> Result<L2Entry> {
+        let entry = self.l2_entry_parse(raw)?;
+        if let L2Entry::Standard { pos, .. } = entry {
+            if !self.misaligned_data && !pos.is_multiple_of(self.cluster_size()) {
+                return Err(Error::FileFormat(format!("data cluster for guest offset {:#x} is \
+                                                      misaligned, L2 entry {:#x}",
+                                                     guest_offset,
+                                                     raw)));
+            }
+        }
+        Ok(entry)
+    }
+    // Check that we can read guest data from this image.
+    pub(crate) fn ensure_readable(&self) -> Result<()> {
+        if self.header().has_backing_file() && self.backing.is_none() {
+            return Err(Error::UnsupportedFeature("backing file that was not attached".to_owned()));
+        }
+        Ok(())
+    }
+    // Get the source of guest data, which may be an external data file.
+    pub(crate) fn data_io(&self) -> Result<&I> {
+        if !self.header().has_data_file() {
+            return Ok(&self.io);
+        }
+        self.data_file.as_ref().ok_or_else(|| {
+            Error::UnsupportedFeature("external data file that was not opened".to_owned())
+        })
     }
-    fn guest_block_read(&self, entry: L2Entry, offset: u64, buf: &mut [u8]) -> Result<()> {
+    pub(crate) fn guest_block_read(&self,
+                                   entry: L2Entry,
+                                   guest_pos: u64,
+                                   offset: u64,
+                                   buf: &mut [u8])
+                                   -> Result<()> {
         match entry {
-            L2Entry::Empty => Self::zero_fill(buf),
-            L2Entry::Standard { pos, zero, .. } => {
-                if zero {
-                    Self::zero_fill(buf)
-                } else {
-                    self.io.read_exact_at(pos + offset, buf)?
+            L2Entry::Empty => {
+                match self.backing {
+                    Some(ref backing) => backing.read(guest_pos + offset, buf)?,
+                    None => {
+                        Qcow2::<I>::zero_fill(buf);
+                        self.count(|m| m.add_zero(buf.len() as u64));
+                    }
                 }
             }
-            L2Entry::Compressed { .. } => {
-                return Err(Error::UnsupportedFeature("compressed blocks".to_owned()))
+            L2Entry::Zero { .. } |
+            L2Entry::Standard { zero: true, .. } => {
+                Qcow2::<I>::zero_fill(buf);
+                self.count(|m| m.add_zero(buf.len() as u64));
+            }
+            L2Entry::Standard { pos, .. } => {
+                let read = Qcow2::read_partial_at(self.data_io()?, pos + offset, buf)?;
+                self.count(|m| m.add_data(read as u64));
+                if read < buf.len() {
+                    match self.truncated {
+                        Truncated::Error => {
+                            let msg = format!("data for guest offset {:#x} at host offset \
+                                               {:#x} is past the end of the file",
+                                              guest_pos + offset,
+                                              pos + offset);
+                            return Err(Error::FileFormat(msg));
+                        }
+                        Truncated::Zero => {
+                            warn!("data for guest offset {:#x} at host offset {:#x} is past \
+                                   the end of the file, reading zeros",
+                                  guest_pos + offset,
+                                  pos + offset);
+                            Qcow2::<I>::zero_fill(&mut buf[read..]);
+                            self.count(|m| m.add_zero((buf.len() - read) as u64));
+                        }
+                    }
+                }
+            }
+            L2Entry::Compressed { pos, size, .. } => {
+                let mut cluster = vec![0; self.cluster_size() as usize];
+                self.compressed_decompress(guest_pos, pos, size, &mut cluster)?;
+                let start = offset as usize;
+                buf.copy_from_slice(&cluster[start..start + buf.len()]);
             }
         }
         Ok(())
     }
-    fn guest_read<T: ReadIntAt>(&self, l1: &T, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+    // Decompress the cluster at a guest offset, whose compressed data is at a host offset, into
+    // a buffer of the cluster size.
+    fn compressed_decompress(&self,
+                             guest_pos: u64,
+                             pos: u64,
+                             size: u64,
+                             out: &mut [u8])
+                             -> Result<()> {
+        self.compressed_check(guest_pos, pos, size)?;
+        let mut data = vec![0; to_usize(size, "compressed cluster size")?];
+        let read = Qcow2::read_partial_at(&self.io, pos, &mut data)?;
+        self.count(|m| m.add_data(read as u64));
+        if !compress::inflate(&data[..read], out) {
+            return Err(Error::FileFormat(format!("compressed data for guest offset {:#x} at \
+                                                  host offset {:#x}, {} bytes, can't be \
+                                                  decompressed",
+                                                 guest_pos,
+                                                 pos,
+                                                 size)));
+        }
+        Ok(())
+    }
+    // Make sure compressed data for a cluster is somewhere it could be read from.
+    //
+    // The last compressed cluster may legitimately end past the end of the file, since its
+    // size is rounded up to whole sectors, so only the start must be in the file.
+    fn compressed_check(&self, guest_pos: u64, pos: u64, size: u64) -> Result<()> {
+        let problem = if pos < self.cluster_size() {
+            "overlaps the header"
+        } else if pos.checked_add(size).is_none() || !has_byte(&*self.io, pos)? {
+            "starts past the end of the file"
+        } else {
+            return Ok(());
+        };
+        Err(Error::FileFormat(format!("compressed data for guest offset {:#x} at host offset \
+                                       {:#x}, {} bytes, {}",
+                                      guest_pos,
+                                      pos,
+                                      size,
+                                      problem)))
+    }
+    // Read the compressed data of the cluster containing a guest offset, if it's compressed.
+    fn compressed_cluster_read<T: ReadIntAt>(&self,
+                                             l1: &T,
+                                             size: u64,
+                                             guest_offset: u64)
+                                             -> Result<Option<CompressedCluster>> {
+        if guest_offset >= size {
+            return Ok(None);
+        }
+        let guest_pos = guest_offset - guest_offset % self.cluster_size();
+        let (pos, size) = match self.l2_entry_read(l1, guest_pos)? {
+            L2Entry::Compressed { pos, size, .. } => (pos, size),
+            _ => return Ok(None),
+        };
+        self.compressed_check(guest_pos, pos, size)?;
+        let mut data = vec![0; size as usize];
+        let read = Qcow2::read_partial_at(&self.io, pos, &mut data)?;
+        data.truncate(read);
+        self.count(|m| m.add_data(read as u64));
+        Ok(Some(CompressedCluster {
+            host_offset: pos,
+            data,
+            // Only zlib can be declared by the headers this library reads, see `Qcow2::info`.
+            compression: CompressionType::Zlib,
+        }))
+    }
+    pub(crate) fn guest_read<T: ReadIntAt>(&self,
+                                           l1: &T,
+                                           size: u64,
+                                           pos: u64,
+                                           buf: &mut [u8])
+                                           -> io::Result<usize> {
         // Check for reads past EOF.
-        if pos >= self.header.guest_size() {
+        if pos >= size {
             return Ok(0);
         }
-        let ret = min(buf.len() as u64, self.header.guest_size() - pos) as usize;
-        let mut buf = &mut buf[..ret];
+        let cluster_size = self.cluster_size();
+        let offset = pos % cluster_size;
 
-        let mut offset = pos % self.cluster_size();
-        let mut guest_block_pos = pos - offset;
-        while !buf.is_empty() {
-            let entry = self.l2_entry_read(l1, guest_block_pos)?;
-            let size = min(buf.len() as u64, self.cluster_size() - offset) as usize;
-            self.guest_block_read(entry, offset, &mut buf[..size])?;
+        // Most reads are of one whole cluster, or less. Those need just one lookup and one
+        // read, without the work of finding runs of clusters.
+        if !buf.is_empty() && offset + buf.len() as u64 <= cluster_size &&
+           buf.len() as u64 <= size - pos {
+            let entry = self.l2_entry_read(l1, pos - offset)?;
+            self.guest_block_read(entry, pos - offset, offset, buf)?;
+            self.count(|m| m.add_guest(buf.len() as u64));
+            return Ok(buf.len());
+        }
+
+        let ret = min(buf.len() as u64, size - pos) as usize;
+        let table_span = self.header().l2_entries() * cluster_size;
+
+        let mut done = 0;
+        while done < ret {
+            // Look up every cluster within the same L2 table at once.
+            let guest_pos = pos + done as u64;
+            let offset = guest_pos % cluster_size;
+            let table_end = (guest_pos / table_span + 1) * table_span;
+            let len = min((ret - done) as u64, table_end - guest_pos) as usize;
+            let count = (offset + len as u64).div_ceil(cluster_size) as usize;
+            let entries = self.l2_entries_read(l1, guest_pos - offset, count)?;
 
-            let tmp = buf;
-            buf = &mut tmp[size..];
-            guest_block_pos += self.cluster_size();
-            offset = 0;
+            // Then read each run of clusters that can be read together.
+            let first = guest_pos - offset;
+            let mut offset = offset;
+            let mut chunk_done = 0;
+            let mut idx = 0;
+            while idx < count {
+                let mut run = 1;
+                while idx + run < count && Self::l2_contiguous(entries[idx],
+                                                               entries[idx + run],
+                                                               run as u64 * cluster_size) {
+                    run += 1;
+                }
+                let n = min((run as u64 * cluster_size - offset) as usize, len - chunk_done);
+                let block_pos = first + idx as u64 * cluster_size;
+                let start = done + chunk_done;
+                self.guest_block_read(entries[idx], block_pos, offset, &mut buf[start..start + n])?;
+                chunk_done += n;
+                idx += run;
+                offset = 0;
+            }
+            done += len;
         }
+        self.count(|m| m.add_guest(ret as u64));
         Ok(ret)
     }
 
-    fn l1_read(&self, l1_offset: u64) -> Result<Vec<u8>> {
-        let mut buf = vec![0; self.header.l1_entries() as usize * size_of::<u64>()];
+    // Whether a cluster can be read along with an earlier one, `distance` bytes before it in the
+    // virtual disk.
+    fn l2_contiguous(first: L2Entry, other: L2Entry, distance: u64) -> bool {
+        match (first, other) {
+            (L2Entry::Standard { pos: a, zero: false, .. },
+             L2Entry::Standard { pos: b, zero: false, .. }) => b == a + distance,
+            (L2Entry::Empty, L2Entry::Empty) => true,
+            (L2Entry::Zero { .. }, L2Entry::Zero { .. }) |
+            (L2Entry::Zero { .. }, L2Entry::Standard { zero: true, .. }) |
+            (L2Entry::Standard { zero: true, .. }, L2Entry::Zero { .. }) |
+            (L2Entry::Standard { zero: true, .. }, L2Entry::Standard { zero: true, .. }) => true,
+            _ => false,
+        }
+    }
+
+    // Count the bytes of guest data allocated in this image.
+    pub(crate) fn allocated_size(&self) -> Result<u64> {
+        let l1 = self.l1_read(self.header().c.l1_table_offset, self.header().l1_entries())?;
+        let l1 = ByteIo::<_, BigEndian>::new(l1);
+        let mut total = 0;
+        for l1_idx in 0..self.header().l1_entries() {
+            let pos = match self.l1_entry_read(&l1, l1_idx)? {
+                L1Entry::Empty => continue,
+                L1Entry::Standard { pos, .. } => pos,
+            };
+            let l2 = self.l2_table_load(pos)?;
+            for (l2_idx, &entry) in l2.iter().enumerate() {
+                let l2_idx = l2_idx as u64;
+                let guest_pos = (l1_idx * self.header().l2_entries() + l2_idx) *
+                                self.cluster_size();
+                if guest_pos >= self.guest_size() {
+                    break;
+                }
+                match self.l2_entry_parse(entry)? {
+                    L2Entry::Standard { zero: false, .. } |
+                    L2Entry::Compressed { .. } => {
+                        total += min(self.cluster_size(), self.guest_size() - guest_pos);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    pub(crate) fn l1_read(&self, l1_offset: u64, entries: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; to_usize(entries * size_of::<u64>() as u64, "L1 table size")?];
         self.io.read_exact_at(l1_offset, &mut buf)?;
+        self.count(|m| m.add_metadata(buf.len() as u64));
         Ok(buf)
     }
 }
 
+// The L1 table a reader reads through.
+enum L1Source {
+    Active,
+    // The ID of a snapshot.
+    Snapshot(String),
+    Raw {
+        offset: u64,
+        entries: u64,
+    },
+}
+
+/// Chooses the L1 table a `Reader` reads through, see `Qcow2::reader_builder`.
+///
+/// Each method checks that the table is aligned and of a sane size before reading it.
+pub struct ReaderBuilder<'a, I: 'a + ReadAt, C: CachePolicy = SharedCache> {
+    q: &'a Qcow2<I, C>,
+}
+
+impl<'a, I: 'a + ReadAt, C: CachePolicy> ReaderBuilder<'a, I, C> {
+    /// Read the main virtual disk, through the active L1 table.
+    pub fn active(self) -> Result<Reader<'a, I, C>> {
+        let q = self.q;
+        let (offset, entries) = (q.header().c.l1_table_offset, q.header().l1_entries());
+        Reader::new(q, offset, entries, q.guest_size(), L1Source::Active)
+    }
+
+    /// Read the virtual disk as it was when a snapshot was taken.
+    ///
+    /// The snapshot may be identified by either its ID or its name, see `Qcow2::find_snapshot`.
+    pub fn snapshot(self, name: &str) -> Result<Reader<'a, I, C>> {
+        let q = self.q;
+        let snap = q.find_snapshot(name).ok_or_else(|| Error::NoSnapshot(name.to_owned()))?;
+        let (offset, entries, size) = q.snapshot_l1(snap, q.guest_size())?;
+        Reader::new(q, offset, entries, size, L1Source::Snapshot(snap.id.clone()))
+    }
+
+    /// Read through an L1 table at an arbitrary offset in the file.
+    ///
+    /// This is for forensics, such as recovering data through an old L1 table. Nothing checks
+    /// that the table belongs to the image, so reading may return garbage or fail. The virtual
+    /// disk is the size of the image, or less if the table covers less.
+    pub fn raw_l1(self, offset: u64, entries: u64) -> Result<Reader<'a, I, C>> {
+        let q = self.q;
+        let span = q.header().l2_entries() * q.cluster_size();
+        let size = min(q.guest_size(), entries.saturating_mul(span));
+        Reader::new(q, offset, entries, size, L1Source::Raw { offset, entries })
+    }
+}
+
 /// A reader of data from the virtual disk image.
-pub struct Reader<'a, I: 'a + ReadAt> {
-    q: &'a Qcow2<I>,
-    l1: ByteIo<Vec<u8>, BigEndian>,
+pub struct Reader<'a, I: 'a + ReadAt, C: CachePolicy = SharedCache> {
+    pub(crate) q: &'a Qcow2<I, C>,
+    pub(crate) l1: ByteIo<Vec<u8>, BigEndian>,
+    pub(crate) size: u64,
+    // Where the L1 table came from, so it can be found again.
+    source: L1Source,
+    pub(crate) ahead: ReadAhead<I>,
 }
 
-impl<'a, I: 'a + ReadAt> Reader<'a, I> {
-    fn new(q: &'a Qcow2<I>, l1_offset: u64) -> Result<Self> {
-        let buf = q.l1_read(l1_offset)?;
+impl<'a, I: 'a + ReadAt, C: CachePolicy> Reader<'a, I, C> {
+    fn new(q: &'a Qcow2<I, C>,
+           l1_offset: u64,
+           l1_entries: u64,
+           size: u64,
+           source: L1Source)
+           -> Result<Self> {
+        q.ensure_readable()?;
+        q.l1_check(l1_offset, l1_entries)?;
+        let buf = q.l1_read(l1_offset, l1_entries)?;
         let l1 = ByteIo::<_, BigEndian>::new(buf);
-        Ok(Reader { q, l1 })
+        Ok(Reader {
+            q,
+            l1,
+            size,
+            source,
+            ahead: ReadAhead::default(),
+        })
     }
+
+    /// Pick up changes made to the image by something else, such as qemu, since this reader
+    /// was created.
+    ///
+    /// Otherwise, a reader may see old or inconsistent data once the image changes. This drops
+    /// cached metadata of the image, and reloads the header and L1 table, which may have moved.
+    /// It's up to the caller to make sure nothing is writing to the image meanwhile.
+    ///
+    /// The image itself still has its old header, see `Qcow2::invalidate_caches`.
+    pub fn refresh(&mut self) -> Result<()> {
+        let q = self.q;
+        let header = q.header_load()?;
+        let (offset, entries, size) = match self.source {
+            L1Source::Active => {
+                (header.c.l1_table_offset, header.l1_entries(), header.guest_size())
+            }
+            L1Source::Raw { offset, entries } => (offset, entries, self.size),
+            L1Source::Snapshot(ref id) => {
+                let snapshots = snapshot::read_snapshots(&q.io, &header)?.0;
+                let snap = snapshots.iter()
+                    .find(|s| s.id == *id)
+                    .ok_or_else(|| Error::NoSnapshot(id.clone()))?;
+                q.snapshot_l1(snap, header.guest_size())?
+            }
+        };
+        q.l1_check(offset, entries)?;
+        q.l2_cache.invalidate_image(q.image_id);
+        *self.l1 = q.l1_read(offset, entries)?;
+        self.size = size;
+        self.ahead.reset();
+        Ok(())
+    }
+
+    /// Get the data of a compressed cluster as it's stored, without decompressing it.
+    ///
+    /// This finds the cluster containing `guest_offset`, and returns `None` if it isn't
+    /// compressed. The data is checked to be somewhere in the file, but nothing checks that it
+    /// can be decompressed.
+    pub fn raw_compressed_cluster(&self, guest_offset: u64) -> Result<Option<CompressedCluster>> {
+        self.q.compressed_cluster_read(&self.l1, self.size, guest_offset)
+    }
+}
+
+/// A compressed cluster, as stored in the image.
+///
+/// Returned by `Reader::raw_compressed_cluster`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompressedCluster {
+    /// The offset of the compressed data in the image file.
+    pub host_offset: u64,
+    /// The compressed data.
+    ///
+    /// Its size is rounded up to whole sectors, so there may be junk after the compressed
+    /// stream. The data of the last cluster in the file may end early.
+    pub data: Vec<u8>,
+    /// How the data is compressed.
+    pub compression: CompressionType,
 }
 
-impl<'a, I> ReadAt for Reader<'a, I>
-    where I: 'a + ReadAt
+impl<'a, I, C> ReadAt for Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
 {
     fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
-        self.q.guest_read(&self.l1, pos, buf)
+        let read = self.q.guest_read(&self.l1, self.size, pos, buf)?;
+        if read > 0 {
+            self.read_ahead(pos + read as u64);
+        }
+        Ok(read)
     }
 }
 
-impl<'a, I> Size for Reader<'a, I>
-    where I: 'a + ReadAt
+impl<'a, I, C> Size for Reader<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
 {
     fn size(&self) -> io::Result<Option<u64>> {
-        Ok(Some(self.q.guest_size()))
+        Ok(Some(self.size))
+    }
+}
+
+/// A reader that owns its image.
+///
+/// Created by `Qcow2::into_reader` or `Qcow2::into_snapshot_reader`.
+pub struct OwnedReader<I: ReadAt, C: CachePolicy = SharedCache> {
+    pub(crate) q: Qcow2<I, C>,
+    pub(crate) l1: ByteIo<Vec<u8>, BigEndian>,
+    pub(crate) size: u64,
+}
+
+impl<I: ReadAt, C: CachePolicy> OwnedReader<I, C> {
+    /// Get the image being read.
+    pub fn qcow2(&self) -> &Qcow2<I, C> {
+        &self.q
+    }
+
+    /// Stop reading, and get the image back.
+    pub fn into_inner(self) -> Qcow2<I, C> {
+        self.q
+    }
+}
+
+impl<I: ReadAt, C: CachePolicy> ReadAt for OwnedReader<I, C> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.q.guest_read(&self.l1, self.size, pos, buf)
+    }
+}
+
+impl<I: ReadAt, C: CachePolicy> Size for OwnedReader<I, C> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.size))
     }
 }
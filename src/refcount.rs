@@ -0,0 +1,132 @@
+use std::mem::size_of;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Error, Qcow2, Result};
+use super::int::to_usize;
+
+
+pub const REFT_RESERVED: u64 = 0x1FF;
+pub const REFT_POS: u64 = !REFT_RESERVED;
+
+// Get a refcount entry from a refcount block.
+//
+// Entries narrower than a byte are packed starting from the least significant bit.
+pub fn refcount_entry(block: &[u8], idx: u64, order: u32) -> u64 {
+    let bits = 1u64 << order;
+    if bits >= 8 {
+        let bytes = (bits / 8) as usize;
+        let start = idx as usize * bytes;
+        BigEndian::read_uint(&block[start..start + bytes], bytes)
+    } else {
+        let bit = idx * bits;
+        let byte = block[(bit / 8) as usize] as u64;
+        (byte >> (bit % 8)) & ((1 << bits) - 1)
+    }
+}
+
+// Set a refcount entry in a refcount block. Returns the range of bytes that changed.
+pub fn set_refcount_entry(block: &mut [u8], idx: u64, order: u32, value: u64) -> Range<usize> {
+    let bits = 1u64 << order;
+    if bits >= 8 {
+        let bytes = (bits / 8) as usize;
+        let start = idx as usize * bytes;
+        BigEndian::write_uint(&mut block[start..start + bytes], value, bytes);
+        start..start + bytes
+    } else {
+        let bit = idx * bits;
+        let byte = (bit / 8) as usize;
+        let mask = (((1u64 << bits) - 1) << (bit % 8)) as u8;
+        block[byte] = (block[byte] & !mask) | ((value << (bit % 8)) as u8 & mask);
+        byte..byte + 1
+    }
+}
+
+// Get the largest refcount that can be stored with a refcount order.
+pub fn max_refcount(order: u32) -> u64 {
+    u64::MAX >> (64 - (1 << order))
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    // Get the number of entries in each refcount block.
+    pub(crate) fn refcount_block_size(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.header().v3.refcount_order
+    }
+
+    // Read the raw entries of the refcount table.
+    pub(crate) fn refcount_table_read(&self) -> Result<Vec<u64>> {
+        let len = self.header().c.refcount_table_clusters as u64 * self.cluster_size();
+        let mut buf = vec![0; to_usize(len, "refcount table size")?];
+        self.io.read_exact_at(self.header().c.refcount_table_offset, &mut buf)?;
+        Ok(buf.chunks(size_of::<u64>()).map(BigEndian::read_u64).collect())
+    }
+
+    // Get the position of a refcount block from its refcount table entry, or zero if there's
+    // no block.
+    pub(crate) fn refcount_block_pos(&self, table_idx: u64, entry: u64) -> Result<u64> {
+        let pos = entry & REFT_POS;
+        if !pos.is_multiple_of(self.cluster_size()) {
+            return Err(Error::FileFormat(format!("refcount block for refcount table index {} \
+                                                  at {:#x} is misaligned",
+                                                 table_idx,
+                                                 pos)));
+        }
+        Ok(pos)
+    }
+}
+
+// A cursor for looking up refcounts, that keeps the most recently used refcount block.
+pub struct Refcounts<'a, I: 'a + ReadAt, C: CachePolicy> {
+    q: &'a Qcow2<I, C>,
+    table: Vec<u64>,
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl<'a, I, C> Refcounts<'a, I, C>
+    where I: 'a + ReadAt,
+          C: CachePolicy
+{
+    pub fn new(q: &'a Qcow2<I, C>) -> Result<Self> {
+        Ok(Refcounts {
+            q,
+            table: q.refcount_table_read()?,
+            block: None,
+        })
+    }
+
+    // Get the raw entries of the refcount table.
+    pub fn table(&self) -> &[u64] {
+        &self.table
+    }
+
+    // Get the refcount of a host cluster. Clusters not covered by any refcount block have a
+    // refcount of zero.
+    pub fn get(&mut self, cluster: u64) -> Result<u64> {
+        let per_block = self.q.refcount_block_size();
+        let (table_idx, block_idx) = (cluster / per_block, cluster % per_block);
+        let pos = match self.table.get(table_idx as usize) {
+            Some(&entry) => entry & REFT_POS,
+            None => return Ok(0),
+        };
+        if pos == 0 {
+            return Ok(0);
+        }
+
+        let cached = match self.block {
+            Some((idx, _)) => idx == table_idx,
+            None => false,
+        };
+        if !cached {
+            let mut buf = vec![0; self.q.cluster_size() as usize];
+            self.q.io.read_exact_at(pos, &mut buf)?;
+            self.block = Some((table_idx, buf));
+        }
+        let block = &self.block.as_ref().unwrap().1;
+        Ok(refcount_entry(block, block_idx, self.q.header().v3.refcount_order))
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::{self, Display, Formatter};
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, CheckFinding, CheckResult, Error, Phase, Progress, Qcow2, Result};
+use super::header::INCOMPATIBLE_DIRTY;
+use super::progress::Reporter;
+use super::refcount::{REFT_POS, max_refcount, refcount_entry, set_refcount_entry};
+use super::tx::{MetaTx, Stage};
+use super::write::Storage;
+
+
+// The position in the header of the incompatible feature bits.
+const HEADER_INCOMPATIBLE: u64 = 72;
+
+/// A change that fixes a problem found by `Qcow2::check`, see `Qcow2::repair_plan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Repair {
+    /// Set the refcount of a cluster to the number of references to it.
+    Refcount {
+        /// The index of the host cluster.
+        cluster: u64,
+        /// The offset in the image file of the refcount block holding the refcount.
+        block: u64,
+        /// The refcount stored in the image.
+        old: u64,
+        /// The refcount to store.
+        new: u64,
+    },
+    /// Clear the dirty bit in the incompatible features of the header, once the refcounts are
+    /// known to be right.
+    ClearDirty,
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Repair::Refcount { cluster, block, old, new } => {
+                write!(f,
+                       "set the refcount of cluster {} from {} to {}, in the refcount block at \
+                        {:#x}",
+                       cluster,
+                       old,
+                       new,
+                       block)
+            }
+            Repair::ClearDirty => {
+                write!(f,
+                       "clear the dirty bit, in the incompatible features at {:#x} in the header",
+                       HEADER_INCOMPATIBLE)
+            }
+        }
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Find the changes that fix the problems found by checking this image.
+    ///
+    /// Only wrong refcounts can be fixed, and only when there's a refcount block to hold the
+    /// right value. Leaked clusters are fixed by lowering their refcounts, which frees those no
+    /// longer referenced. If the image is dirty and every refcount can be fixed, clearing the
+    /// dirty bit comes last. Other problems are left alone.
+    ///
+    /// Nothing is changed until each repair is passed to `Qcow2::repair`.
+    pub fn repair_plan(&self, result: &CheckResult) -> Result<Vec<Repair>> {
+        let table = self.refcount_table_read()?;
+        let per_block = self.refcount_block_size();
+        let max = max_refcount(self.header().v3.refcount_order);
+        let mut plan = Vec::new();
+        let mut complete = true;
+        for finding in &result.findings {
+            let (cluster, old, new) = match *finding {
+                CheckFinding::Refcount { cluster, refcount, references } => {
+                    (cluster, refcount, references)
+                }
+                CheckFinding::Invalid { .. } => {
+                    complete = false;
+                    continue;
+                }
+            };
+            let table_idx = cluster / per_block;
+            let block = match table.get(table_idx as usize) {
+                Some(&entry) => self.refcount_block_pos(table_idx, entry)?,
+                None => 0,
+            };
+            if block == 0 || new > max {
+                complete = false;
+                continue;
+            }
+            plan.push(Repair::Refcount {
+                cluster,
+                block,
+                old,
+                new,
+            });
+        }
+        if complete && self.header().v3.incompatible.enabled(INCOMPATIBLE_DIRTY) {
+            plan.push(Repair::ClearDirty);
+        }
+        Ok(plan)
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    /// Make one of the changes found by `Qcow2::repair_plan`, and sync.
+    ///
+    /// A refcount is only changed if it still has the value it had when the image was checked,
+    /// otherwise an error is returned. Since the refcounts of a dirty image may be out of date,
+    /// this works on dirty images, unlike writing.
+    ///
+    /// Each repair is complete on its own once this returns, so a long list of repairs can
+    /// be stopped between any two of them, such as when a `CancelToken` is cancelled. The
+    /// refcounts fixed so far stay fixed, and the dirty bit stays set, since clearing it is
+    /// the last repair.
+    pub fn repair(&mut self, repair: &Repair) -> Result<()> {
+        let mut tx = MetaTx::default();
+        match *repair {
+            Repair::Refcount { cluster, block, old, new } => {
+                let table_idx = cluster / self.refcount_block_size();
+                let entry = self.refcount_table_read()?.get(table_idx as usize).copied();
+                if entry.map(|e| e & REFT_POS) != Some(block) {
+                    return Err(Error::FileFormat(format!("refcount block for cluster {} has \
+                                                          moved",
+                                                         cluster)));
+                }
+                let mut buf = vec![0; self.cluster_size() as usize];
+                self.io.read_exact_at(block, &mut buf)?;
+                let order = self.header().v3.refcount_order;
+                let idx = cluster % self.refcount_block_size();
+                let current = refcount_entry(&buf, idx, order);
+                if current != old {
+                    return Err(Error::FileFormat(format!("refcount of cluster {} is {}, not {}",
+                                                         cluster,
+                                                         current,
+                                                         old)));
+                }
+                let range = set_refcount_entry(&mut buf, idx, order, new);
+                let stage = if new > old { Stage::Allocate } else { Stage::Free };
+                tx.write(stage, block + range.start as u64, buf[range].to_vec());
+                self.commit(tx)?;
+                self.sync()
+            }
+            Repair::ClearDirty => {
+                let bits = self.header().v3.incompatible.bits() & !INCOMPATIBLE_DIRTY;
+                tx.write_u64(Stage::Header, HEADER_INCOMPATIBLE, bits);
+                self.commit(tx)?;
+                self.sync()?;
+                self.header_mut().v3.incompatible.set(bits);
+                Ok(())
+            }
+        }
+    }
+
+    /// Make every change in a plan found by `Qcow2::repair_plan`, in order, reporting progress
+    /// to a `Progress`, which may cancel repairing.
+    ///
+    /// There is one phase, `Phase::Writing`, which counts repairs. Each repair is made as by
+    /// `Qcow2::repair`. If cancelled, this fails with `Error::Cancelled` between two repairs:
+    /// those made so far stay made, and the rest are not, so the dirty bit stays set.
+    pub fn repair_all<P>(&mut self, plan: &[Repair], progress: &P) -> Result<()>
+        where P: Progress + ?Sized
+    {
+        let mut reporter = Reporter::phase(progress, Phase::Writing, (0, 1), plan.len() as u64, 1);
+        for (idx, repair) in plan.iter().enumerate() {
+            reporter.update(idx as u64, 0)?;
+            self.repair(repair)?;
+        }
+        reporter.finish(0)
+    }
+}
@@ -0,0 +1,87 @@
+use std::mem::size_of;
+
+use super::{CachePolicy, Error, Qcow2, Result};
+use super::alloc::Allocator;
+use super::int::to_usize;
+use super::tx::{MetaTx, Stage};
+use super::write::Storage;
+
+
+// The position of the size in the header, followed by the encryption method and L1 table.
+const HEADER_SIZE: u64 = 24;
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    /// Change the size of the virtual disk.
+    ///
+    /// Only growing is supported, and only for images without bitmaps. The new part of the disk
+    /// reads as zeros. If the L1 table no longer fits in its clusters, it's moved somewhere
+    /// bigger. Sizes past `Geometry::max_size` aren't supported.
+    ///
+    /// Readers and writers borrow the image, so none can be alive during a resize. Any created
+    /// afterwards see the new size.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        self.ensure_writable()?;
+        if size < self.guest_size() {
+            return Err(Error::UnsupportedFeature("shrinking images".to_owned()));
+        }
+        // Each bitmap table must match the size of the virtual disk.
+        if self.header().v3.bitmaps.0.is_some() {
+            return Err(Error::UnsupportedFeature("resizing images with bitmaps".to_owned()));
+        }
+        if size > self.geometry().max_size() {
+            return Err(Error::UnsupportedFeature(format!("images of size {}", size)));
+        }
+        let cluster_size = self.cluster_size();
+        let entries = size.div_ceil(cluster_size).div_ceil(self.header().l2_entries());
+
+        let old_entries = self.header().l1_entries();
+        let old_offset = self.header().c.l1_table_offset;
+        let entry_size = size_of::<u64>() as u64;
+        let old_clusters = (old_entries * entry_size).div_ceil(cluster_size);
+        let clusters = (entries * entry_size).div_ceil(cluster_size);
+
+        let mut tx = MetaTx::default();
+        let mut alloc = Allocator::new(self)?;
+        let offset = if clusters > old_clusters {
+            // Move the L1 table somewhere bigger, and free the old one once nothing uses it.
+            let offset = alloc.allocate_clusters(self, clusters)?;
+            let mut l1 = self.l1_read(old_offset, old_entries)?;
+            l1.resize(to_usize(clusters * cluster_size, "L1 table size")?, 0);
+            tx.write(Stage::L1, offset, l1);
+            let old_first = old_offset / cluster_size;
+            for cluster in old_first..old_first + old_clusters {
+                alloc.decrement(self, cluster)?;
+            }
+            offset
+        } else {
+            // The rest of the last cluster may hold anything, so clear the new entries.
+            if entries > old_entries {
+                let len = (entries - old_entries) * entry_size;
+                tx.write(Stage::L1,
+                         old_offset + old_entries * entry_size,
+                         vec![0; to_usize(len, "L1 table size")?]);
+            }
+            old_offset
+        };
+
+        // The size and L1 table must change together, so write them in one go, along with the
+        // encryption method between them.
+        let mut header = size.to_be_bytes().to_vec();
+        header.extend_from_slice(&self.header().c.crypt_method.to_be_bytes());
+        header.extend_from_slice(&(entries as u32).to_be_bytes());
+        header.extend_from_slice(&offset.to_be_bytes());
+        tx.write(Stage::Header, HEADER_SIZE, header);
+        alloc.stage(self, &mut tx);
+        self.commit(tx)?;
+        alloc.committed(self);
+
+        let c = &mut self.header_mut().c;
+        c.size = size;
+        c.l1_size = entries as u32;
+        c.l1_table_offset = offset;
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::result;
+use std::sync::{Mutex, MutexGuard};
+
+use positioned_io::{ReadAt, Size};
+
+
+/// An adapter that lets a `Read + Seek` source be used as an image, by implementing `ReadAt`.
+///
+/// Some sources, such as decompressing streams or object storage clients, can only seek and
+/// read. Each read locks the source, seeks if it isn't already in the right place, and reads,
+/// so reads are serialized even if the image is used from several threads. Reading forwards
+/// avoids seeking.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Cursor;
+/// use qcow2::{Qcow2, SeekBackend};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let bytes = std::fs::read("tests/test.qcow2")?;
+/// let qcow = Qcow2::open(SeekBackend::new(Cursor::new(bytes)))?;
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub struct SeekBackend<T> {
+    inner: Mutex<SeekState<T>>,
+}
+
+struct SeekState<T> {
+    source: T,
+    // Where the source is now, if known.
+    pos: Option<u64>,
+}
+
+impl<T: Read + Seek> SeekBackend<T> {
+    /// Wrap a source.
+    pub fn new(source: T) -> Self {
+        SeekBackend { inner: Mutex::new(SeekState { source, pos: None }) }
+    }
+
+    /// Get the source back.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().unwrap_or_else(|e| e.into_inner()).source
+    }
+
+    // After a panic while reading, the position is unknown.
+    fn lock(&self) -> MutexGuard<'_, SeekState<T>> {
+        self.inner.lock().unwrap_or_else(|e| {
+            let mut state = e.into_inner();
+            state.pos = None;
+            state
+        })
+    }
+}
+
+impl<T: Read + Seek> SeekState<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = None;
+        let new = self.source.seek(pos)?;
+        self.pos = Some(new);
+        Ok(new)
+    }
+}
+
+impl<T: Read + Seek> ReadAt for SeekBackend<T> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        if state.pos != Some(pos) {
+            state.seek(SeekFrom::Start(pos))?;
+        }
+        state.pos = None;
+        let read = state.source.read(buf)?;
+        state.pos = Some(pos + read as u64);
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek> Size for SeekBackend<T> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.lock().seek(SeekFrom::End(0)).map(Some)
+    }
+}
+
+impl<T> Debug for SeekBackend<T> {
+    fn fmt(&self, f: &mut Formatter) -> result::Result<(), fmt::Error> {
+        f.debug_struct("SeekBackend").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::sync::Arc;
+
+use positioned_io::{ReadAt, Size};
+
+use super::advise::{Advice, AdviseIo};
+
+
+/// Storage shared through an `Arc`, such as an `Arc<File>`.
+///
+/// `Arc` can't implement `ReadAt` itself, so wrap it in this. Cloning it is cheap, which makes
+/// cloning a `Qcow2` cheap too, giving each thread its own handle on one open file.
+///
+/// # Choosing storage for concurrent readers
+///
+/// Every `ReadAt` call says where to read, so there's no shared seek position, and many threads
+/// can read from one `Qcow2` at once.
+///
+/// * On Unix, `File` reads with `pread`, which never moves the file's seek position. It's the
+///   best choice, and can be shared with a `SharedBackend<File>` or just a `&File`.
+/// * On Windows, `File` reads with an offset, but moves the seek position as it goes. Reads
+///   through this crate are still correct, but anything reading the same handle with `Read`
+///   or `Seek` will see the position jump around. A `File` can't be written to there, since
+///   positioned-io 0.2 implements `WriteAt` for it with a read, so it isn't `Storage`.
+/// * A `RandomAccessFile` never moves the seek position on any platform, so it's the best
+///   choice on Windows, where it's always available, and the way to write images there.
+///   Elsewhere it needs the `random-access` feature.
+/// * A `SeekBackend` takes a lock around each seek and read, so readers wait for each other.
+///   Prefer a `File` when there is one.
+///
+/// # Examples
+///
+/// ```
+/// use std::fs::File;
+/// use std::thread;
+/// use positioned_io::ReadAt;
+/// use qcow2::{Qcow2, SharedBackend};
+///
+/// # fn foo() -> qcow2::Result<()> {
+/// let qcow = Qcow2::open(SharedBackend::new(File::open("tests/test.qcow2")?))?;
+/// let handles: Vec<_> = (0..4u64)
+///     .map(|i| {
+///         let qcow = qcow.clone();
+///         thread::spawn(move || -> qcow2::Result<()> {
+///             let mut buf = [0; 512];
+///             qcow.reader()?.read_exact_at(i * 512, &mut buf)?;
+///             Ok(())
+///         })
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap()?;
+/// }
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+#[derive(Debug, Default)]
+pub struct SharedBackend<T: ?Sized>(pub Arc<T>);
+
+impl<T> SharedBackend<T> {
+    /// Share some storage.
+    pub fn new(inner: T) -> Self {
+        SharedBackend(Arc::new(inner))
+    }
+}
+
+impl<T: ?Sized> Clone for SharedBackend<T> {
+    fn clone(&self) -> Self {
+        SharedBackend(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> From<Arc<T>> for SharedBackend<T> {
+    fn from(inner: Arc<T>) -> Self {
+        SharedBackend(inner)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for SharedBackend<T> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_at(pos, buf)
+    }
+}
+
+impl<T: Size + ?Sized> Size for SharedBackend<T> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.0.size()
+    }
+}
+
+impl<T: AdviseIo + ?Sized> AdviseIo for SharedBackend<T> {
+    fn will_need(&self, pos: u64, len: u64) -> io::Result<()> {
+        self.0.will_need(pos, len)
+    }
+
+    fn access_pattern(&self, advice: Advice) -> io::Result<()> {
+        self.0.access_pattern(advice)
+    }
+}
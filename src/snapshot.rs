@@ -0,0 +1,226 @@
+use std::cmp::min;
+use std::io::{self, ErrorKind, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::{ByteIo, ReadAt, ReadInt};
+
+use super::{Error, Result};
+use super::header::Header;
+use super::int::padding_to_multiple;
+
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_FIXED_SIZE: u64 = 40;
+
+// Limits on the snapshot table, so hostile images can't make us allocate huge amounts. These
+// match what qemu accepts.
+const MAX_SNAPSHOTS: u32 = 65536;
+const MAX_SNAPSHOTS_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_EXTRA_SIZE: u32 = 1024;
+const MAX_ID_SIZE: u16 = 128;
+const MAX_NAME_SIZE: u16 = 256;
+
+// The most space one entry can take up, given those limits. It's already a multiple of eight.
+const MAX_ENTRY_SIZE: u64 = SNAPSHOT_FIXED_SIZE + MAX_EXTRA_SIZE as u64 + MAX_ID_SIZE as u64 +
+                            MAX_NAME_SIZE as u64;
+
+// Room to allow for each entry past its fixed part, on the first read of the table. That fits
+// the 24 bytes of extra data qemu writes, and an ID and name of 64 bytes between them.
+const ENTRY_SLACK: u64 = 88;
+
+// The most the first read of the table asks for, however many entries the header claims.
+const FIRST_READ_MAX: u64 = 64 * 1024;
+
+/// A snapshot stored in a qcow2 image.
+///
+/// The ID and name are stored as strings, with any invalid UTF-8 replaced. The time the snapshot
+/// was taken is kept in its raw form, use `date` to get it as a `SystemTime`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    /// The unique ID of the snapshot, usually a number.
+    pub id: String,
+    /// The name of the snapshot.
+    pub name: String,
+    /// The offset of the L1 table of this snapshot.
+    pub l1_table_offset: u64,
+    /// The number of entries in the L1 table of this snapshot.
+    pub l1_size: u32,
+    /// The size of saved VM state, or zero if there is none.
+    pub vm_state_size: u64,
+    /// The time the snapshot was taken, seconds since the epoch.
+    pub date_sec: u32,
+    /// The nanosecond part of the time the snapshot was taken.
+    pub date_nsec: u32,
+    /// The time since the guest was started, in nanoseconds.
+    pub vm_clock_nsec: u64,
+    /// The virtual size of the disk when the snapshot was taken, if known.
+    pub disk_size: Option<u64>,
+    /// The instruction count of the guest when the snapshot was taken, if known.
+    pub icount: Option<u64>,
+}
+
+impl Snapshot {
+    /// Get the time the snapshot was taken, or `None` if it wasn't recorded.
+    ///
+    /// A nanosecond part of a whole second or more carries over into the seconds.
+    pub fn date(&self) -> Option<SystemTime> {
+        if self.date_sec == 0 && self.date_nsec == 0 {
+            return None;
+        }
+        Some(UNIX_EPOCH + Duration::new(self.date_sec as u64, self.date_nsec))
+    }
+
+    fn read<I: Read>(io: &mut ByteIo<I, BigEndian>) -> Result<Self> {
+        let l1_table_offset = io.read_u64()?;
+        let l1_size = io.read_u32()?;
+        let id_size = io.read_u16()?;
+        let name_size = io.read_u16()?;
+        let date_sec = io.read_u32()?;
+        let date_nsec = io.read_u32()?;
+        let vm_clock_nsec = io.read_u64()?;
+        let vm_state_size_small = io.read_u32()?;
+        let extra_size = io.read_u32()?;
+        if extra_size > MAX_EXTRA_SIZE {
+            return Err(Error::FileFormat(format!("snapshot extra data too big ({} bytes)",
+                                                 extra_size)));
+        }
+        if id_size > MAX_ID_SIZE {
+            return Err(Error::FileFormat(format!("snapshot ID too long ({} bytes)", id_size)));
+        }
+        if name_size > MAX_NAME_SIZE {
+            return Err(Error::FileFormat(format!("snapshot name too long ({} bytes)",
+                                                 name_size)));
+        }
+
+        // Extra data fields are optional, each is only present if there's room for it.
+        let mut extra = vec![0; extra_size as usize];
+        io.read_exact(&mut extra)?;
+        let extra_field = |idx: usize| {
+            extra.get(idx * 8..(idx + 1) * 8).map(BigEndian::read_u64)
+        };
+        let vm_state_size = extra_field(0).unwrap_or(vm_state_size_small as u64);
+        let disk_size = extra_field(1);
+        let icount = extra_field(2);
+
+        let id = Self::read_string(io, id_size)?;
+        let name = Self::read_string(io, name_size)?;
+
+        // Each entry is padded to a multiple of eight bytes.
+        let len = SNAPSHOT_FIXED_SIZE + extra_size as u64 + id_size as u64 + name_size as u64;
+        let mut pad = vec![0; padding_to_multiple(len, 8)];
+        io.read_exact(&mut pad)?;
+
+        Ok(Snapshot {
+            id,
+            name,
+            l1_table_offset,
+            l1_size,
+            vm_state_size,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            disk_size,
+            icount,
+        })
+    }
+
+    fn read_string<I: Read>(io: &mut ByteIo<I, BigEndian>, len: u16) -> Result<String> {
+        let mut buf = vec![0; len as usize];
+        io.read_exact(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
+
+// Format a time as RFC 3339, in UTC to the second.
+pub fn format_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a date, counting years from March so leap days are last.
+    let days = days + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60)
+}
+
+// Find where the snapshot table of an image starts, and the least space it can take up.
+pub fn table_bounds(header: &Header) -> Result<(u64, u64)> {
+    let count = header.c.nb_snapshots;
+    if count > MAX_SNAPSHOTS {
+        return Err(Error::FileFormat(format!("too many snapshots ({})", count)));
+    }
+    let offset = header.c.snapshots_offset;
+    if count > 0 && offset == 0 {
+        return Err(Error::FileFormat("snapshot table at offset zero".to_owned()));
+    }
+    Ok((offset, count as u64 * SNAPSHOT_FIXED_SIZE))
+}
+
+// Read the snapshot table of an image. Also returns the size of the table in bytes.
+//
+// Entries vary in size, so the first read is sized for entries with short IDs and names, and the
+// buffer grows by doubling if an entry doesn't fit. A typical table takes one read. The buffer
+// only grows once the storage has filled it, so a header claiming many snapshots can't make us
+// allocate much more than the file holds.
+pub fn read_snapshots<I: ReadAt>(io: &I, header: &Header) -> Result<(Vec<Snapshot>, u64)> {
+    let count = header.c.nb_snapshots;
+    let offset = table_bounds(header)?.0;
+
+    let max_size = min(count as u64 * MAX_ENTRY_SIZE, MAX_SNAPSHOTS_SIZE);
+    let first = min(count as u64 * (SNAPSHOT_FIXED_SIZE + ENTRY_SLACK), FIRST_READ_MAX);
+    let mut buf = vec![0; min(first, max_size) as usize];
+    let mut filled = 0;
+    let mut pos = 0;
+    let mut ret = Vec::new();
+    for idx in 0..count {
+        loop {
+            let mut entry = io::Cursor::new(&buf[pos..filled]);
+            match Snapshot::read(&mut ByteIo::<_, BigEndian>::new(&mut entry)) {
+                Ok(snap) => {
+                    pos += entry.position() as usize;
+                    ret.push(snap);
+                    break;
+                }
+                Err(Error::Io(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {}
+                Err(Error::FileFormat(msg)) => {
+                    return Err(Error::FileFormat(format!("snapshot {}: {}", idx, msg)));
+                }
+                Err(e) => return Err(e),
+            }
+
+            // The entry isn't all in the buffer yet.
+            if filled == buf.len() {
+                if buf.len() as u64 >= max_size {
+                    return Err(Error::FileFormat("snapshot table too big".to_owned()));
+                }
+                buf.resize(min(buf.len() as u64 * 2, max_size) as usize, 0);
+            }
+            let read = io.read_at(offset + filled as u64, &mut buf[filled..])?;
+            if read == 0 {
+                return Err(Error::FileFormat("snapshot table is past the end of the file"
+                    .to_owned()));
+            }
+            filled += read;
+        }
+    }
+    Ok((ret, pos as u64))
+}
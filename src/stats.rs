@@ -0,0 +1,177 @@
+use std::cmp::min;
+use std::ops::ControlFlow;
+
+use byteorder::BigEndian;
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{CachePolicy, Phase, Progress, Qcow2, Result};
+use super::progress::Reporter;
+use super::read::{L1Entry, L2Entry};
+
+
+/// How the clusters of a virtual disk are allocated, found by `Qcow2::allocation_stats`.
+///
+/// Byte totals count bytes of the virtual disk, so a last cluster cut short by the end of the
+/// disk only counts as far as the end.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllocationStats {
+    /// The number of clusters holding uncompressed data.
+    pub data_clusters: u64,
+    /// The number of bytes in those clusters.
+    pub data_bytes: u64,
+    /// The number of clusters that read as zeros because of the zero flag, whether or not
+    /// they have space allocated.
+    pub zero_clusters: u64,
+    /// The number of bytes in those clusters.
+    pub zero_bytes: u64,
+    /// The number of compressed clusters.
+    pub compressed_clusters: u64,
+    /// The number of bytes those clusters hold once decompressed.
+    pub compressed_bytes: u64,
+    /// The number of bytes those clusters take up in the image file.
+    ///
+    /// This is found from the sector counts in their L2 entries, so it may be a little more
+    /// than the compressed data really needs.
+    pub compressed_stored_bytes: u64,
+    /// The number of clusters with no data, which are read from the backing file if any.
+    pub hole_clusters: u64,
+    /// The number of bytes in those clusters.
+    pub hole_bytes: u64,
+    /// The number of L2 tables.
+    pub l2_tables: u64,
+    /// How many extents of each length there are, see `extent_bucket`.
+    ///
+    /// An extent is a run of data clusters that are next to each other both in the virtual disk
+    /// and in the image file. The last bucket with any extents is the last entry.
+    pub extent_histogram: Vec<u64>,
+}
+
+impl AllocationStats {
+    /// Get the range of extent lengths counted by an entry of `extent_histogram`, in clusters.
+    ///
+    /// Each range ends at a power of four: entry 0 counts extents of one cluster, entry 1
+    /// those of two to four clusters, entry 2 those of five to 16 clusters, and so on.
+    pub fn extent_bucket(index: usize) -> (u64, u64) {
+        match index {
+            0 => (1, 1),
+            _ => ((1 << (2 * (index - 1))) + 1, 1 << (2 * index)),
+        }
+    }
+
+    // Count an extent of some number of clusters.
+    fn add_extent(&mut self, clusters: u64) {
+        let mut index = 0;
+        while Self::extent_bucket(index).1 < clusters {
+            index += 1;
+        }
+        if self.extent_histogram.len() <= index {
+            self.extent_histogram.resize(index + 1, 0);
+        }
+        self.extent_histogram[index] += 1;
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Find out how the clusters of the main virtual disk are allocated.
+    ///
+    /// This walks every L2 table once, like `info` does to find the allocated size, so it may
+    /// need to read a lot of metadata for a large image. Tables are read directly rather than
+    /// through the cache, so they don't push out tables that are in use.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = qcow2::Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let stats = qcow.allocation_stats()?;
+    /// assert_eq!(stats.data_bytes, qcow.info()?.allocated_size);
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn allocation_stats(&self) -> Result<AllocationStats> {
+        self.allocation_stats_reporting(&|_, _, _| ControlFlow::Continue(()))
+    }
+
+    /// Find out how the clusters of the main virtual disk are allocated, reporting progress to
+    /// a `Progress`, which may cancel it.
+    ///
+    /// This is like `allocation_stats`. There is one phase, `Phase::ScanningTables`, which
+    /// counts the entries of the L1 table. This only reads the image, so if cancelled, it fails
+    /// with `Error::Cancelled`, leaving nothing behind.
+    pub fn allocation_stats_reporting<P>(&self, progress: &P) -> Result<AllocationStats>
+        where P: Progress + ?Sized
+    {
+        let cs = self.cluster_size();
+        let size = self.guest_size();
+        let l1_entries = self.header().l1_entries();
+        let mut reporter = Reporter::phase(progress, Phase::ScanningTables, (0, 1), l1_entries, 1);
+        let l1 = self.l1_read(self.header().c.l1_table_offset, l1_entries)?;
+        let l1 = ByteIo::<_, BigEndian>::new(l1);
+        let mut stats = AllocationStats::default();
+        // The host offset just past the extent being counted, and its length in clusters.
+        let mut extent: Option<(u64, u64)> = None;
+        for l1_idx in 0..l1_entries {
+            reporter.update(l1_idx, 0)?;
+            let first = l1_idx * self.header().l2_entries() * cs;
+            let entries = match self.l1_entry_read(&l1, l1_idx)? {
+                L1Entry::Empty => None,
+                L1Entry::Standard { pos, .. } => {
+                    stats.l2_tables += 1;
+                    Some(self.l2_table_load(pos)?)
+                }
+            };
+            for l2_idx in 0..self.header().l2_entries() {
+                let guest_pos = first + l2_idx * cs;
+                if guest_pos >= size {
+                    break;
+                }
+                let len = min(cs, size - guest_pos);
+                let entry = match entries {
+                    Some(ref e) => self.l2_entry_parse(e[l2_idx as usize])?,
+                    None => L2Entry::Empty,
+                };
+                let pos = match entry {
+                    L2Entry::Empty => {
+                        stats.hole_clusters += 1;
+                        stats.hole_bytes += len;
+                        None
+                    }
+                    L2Entry::Zero { .. } |
+                    L2Entry::Standard { zero: true, .. } => {
+                        stats.zero_clusters += 1;
+                        stats.zero_bytes += len;
+                        None
+                    }
+                    L2Entry::Compressed { size, .. } => {
+                        stats.compressed_clusters += 1;
+                        stats.compressed_bytes += len;
+                        stats.compressed_stored_bytes += size;
+                        None
+                    }
+                    L2Entry::Standard { pos, .. } => {
+                        stats.data_clusters += 1;
+                        stats.data_bytes += len;
+                        Some(pos)
+                    }
+                };
+                extent = match (extent, pos) {
+                    (Some((end, n)), Some(pos)) if pos == end => Some((end + cs, n + 1)),
+                    (extent, pos) => {
+                        if let Some((_, n)) = extent {
+                            stats.add_extent(n);
+                        }
+                        pos.map(|pos| (pos + cs, 1))
+                    }
+                };
+            }
+        }
+        if let Some((_, n)) = extent {
+            stats.add_extent(n);
+        }
+        reporter.finish(0)?;
+        Ok(stats)
+    }
+}
@@ -0,0 +1,150 @@
+use std::mem::size_of;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Qcow2, Result};
+use super::int::to_usize;
+use super::read::{L1_COW, L1_POS, L1_RESERVED, L2_COMPRESSED, L2_COW, L2_POS, L2_RESERVED,
+                  L2_ZERO};
+use super::refcount::{REFT_POS, REFT_RESERVED, refcount_entry};
+
+
+/// An entry of an L1 table, decoded without validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct L1TableEntry {
+    /// The raw value of the entry.
+    pub raw: u64,
+    /// The offset of the L2 table, or zero if there is none.
+    pub l2_offset: u64,
+    /// Whether the L2 table has a refcount of exactly one.
+    pub copied: bool,
+    /// Any reserved bits that are set. These should be zero.
+    pub reserved_bits: u64,
+    /// Whether the L2 offset is not aligned to a cluster.
+    pub misaligned: bool,
+}
+
+/// An entry of an L2 table, decoded without validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct L2TableEntry {
+    /// The raw value of the entry.
+    pub raw: u64,
+    /// The offset of the data, or zero if there is none.
+    pub host_offset: u64,
+    /// Whether the data has a refcount of exactly one.
+    pub copied: bool,
+    /// Whether the cluster reads as zeros.
+    pub zero: bool,
+    /// For a compressed cluster, the number of bytes of compressed data.
+    pub compressed_size: Option<u64>,
+    /// Any reserved bits that are set. These should be zero.
+    pub reserved_bits: u64,
+    /// Whether the host offset is not aligned to a cluster, for an uncompressed cluster.
+    pub misaligned: bool,
+}
+
+/// An entry of the refcount table, decoded without validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RefcountTableEntry {
+    /// The raw value of the entry.
+    pub raw: u64,
+    /// The offset of the refcount block, or zero if there is none.
+    pub block_offset: u64,
+    /// Any reserved bits that are set. These should be zero.
+    pub reserved_bits: u64,
+    /// Whether the block offset is not aligned to a cluster.
+    pub misaligned: bool,
+}
+
+fn read_u64s<I, C>(q: &Qcow2<I, C>, offset: u64, count: u64) -> Result<Vec<u64>>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    let mut buf = vec![0; to_usize(count * size_of::<u64>() as u64, "table size")?];
+    q.io.read_exact_at(offset, &mut buf)?;
+    Ok(buf.chunks(size_of::<u64>()).map(BigEndian::read_u64).collect())
+}
+
+/// Low-level access to metadata tables, for debugging.
+///
+/// These methods decode tables without rejecting invalid entries, so they can be used to
+/// inspect corrupt images. Only I/O errors are reported as errors.
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Get the entries of the active L1 table.
+    pub fn l1_table_entries(&self) -> Result<Vec<L1TableEntry>> {
+        let c = &self.header().c;
+        let raw = read_u64s(self, c.l1_table_offset, c.l1_size as u64)?;
+        Ok(raw.into_iter()
+            .map(|raw| {
+                let l2_offset = raw & L1_POS;
+                L1TableEntry {
+                    raw,
+                    l2_offset,
+                    copied: raw & L1_COW != 0,
+                    reserved_bits: raw & L1_RESERVED,
+                    misaligned: !l2_offset.is_multiple_of(self.cluster_size()),
+                }
+            })
+            .collect())
+    }
+
+    /// Get the entries of the L2 table at an offset.
+    pub fn l2_table_entries(&self, l2_offset: u64) -> Result<Vec<L2TableEntry>> {
+        let raw = read_u64s(self, l2_offset, self.header().l2_entries())?;
+        Ok(raw.into_iter()
+            .map(|raw| {
+                if raw & L2_COMPRESSED != 0 {
+                    let (host_offset, size) = self.compressed_descriptor(raw);
+                    L2TableEntry {
+                        raw,
+                        host_offset,
+                        copied: raw & L2_COW != 0,
+                        zero: false,
+                        compressed_size: Some(size),
+                        reserved_bits: 0,
+                        misaligned: false,
+                    }
+                } else {
+                    let host_offset = raw & L2_POS;
+                    L2TableEntry {
+                        raw,
+                        host_offset,
+                        copied: raw & L2_COW != 0,
+                        zero: raw & L2_ZERO != 0,
+                        compressed_size: None,
+                        reserved_bits: raw & L2_RESERVED,
+                        misaligned: !host_offset.is_multiple_of(self.cluster_size()),
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Get the entries of the refcount table.
+    pub fn refcount_table_entries(&self) -> Result<Vec<RefcountTableEntry>> {
+        Ok(self.refcount_table_read()?
+            .into_iter()
+            .map(|raw| {
+                let block_offset = raw & REFT_POS;
+                RefcountTableEntry {
+                    raw,
+                    block_offset,
+                    reserved_bits: raw & REFT_RESERVED,
+                    misaligned: !block_offset.is_multiple_of(self.cluster_size()),
+                }
+            })
+            .collect())
+    }
+
+    /// Get the refcounts in the refcount block at an offset.
+    pub fn refcount_block_entries(&self, block_offset: u64) -> Result<Vec<u64>> {
+        let mut buf = vec![0; self.cluster_size() as usize];
+        self.io.read_exact_at(block_offset, &mut buf)?;
+        let order = self.header().v3.refcount_order;
+        Ok((0..self.refcount_block_size()).map(|i| refcount_entry(&buf, i, order)).collect())
+    }
+}
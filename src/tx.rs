@@ -0,0 +1,115 @@
+use std::cmp::min;
+
+use super::{CachePolicy, Qcow2, Result};
+use super::write::Storage;
+
+
+// The most zeros to write at once.
+const ZERO_CHUNK: u64 = 1 << 20;
+
+// The order in which updates must reach stable storage.
+//
+// Each stage is synced before the next one is written. So if we crash part way through, the
+// worst that can happen is that some clusters are leaked, never that something points at
+// garbage or at a cluster that may be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // New refcount blocks, before the refcount table points at them.
+    RefcountBlock,
+    // Refcount increases and refcount table entries, before anything uses the clusters.
+    Allocate,
+    // Guest data, before L2 tables point at it.
+    Data,
+    // L2 tables, before L1 tables point at them.
+    L2,
+    // L1 tables, before the header points at them.
+    L1,
+    // The header.
+    Header,
+    // Refcount decreases, once nothing points at the clusters.
+    Free,
+}
+
+// The contents of a pending write.
+#[derive(Debug)]
+enum Contents {
+    Bytes(Vec<u8>),
+    // Zeros of some length, so big ranges don't need a buffer.
+    Zeroes(u64),
+}
+
+impl Contents {
+    fn len(&self) -> u64 {
+        match *self {
+            Contents::Bytes(ref b) => b.len() as u64,
+            Contents::Zeroes(len) => len,
+        }
+    }
+}
+
+// A set of pending updates to an image, that are written in a safe order.
+#[derive(Debug, Default)]
+pub struct MetaTx {
+    writes: Vec<(Stage, u64, Contents)>,
+}
+
+impl MetaTx {
+    fn add(&mut self, stage: Stage, pos: u64, contents: Contents) {
+        let same = self.writes
+            .iter_mut()
+            .find(|w| w.0 == stage && w.1 == pos && w.2.len() == contents.len());
+        match same {
+            Some(w) => w.2 = contents,
+            None => self.writes.push((stage, pos, contents)),
+        }
+    }
+
+    // Add a write at some stage. A later write of the same range in the same stage replaces an
+    // earlier one.
+    pub fn write(&mut self, stage: Stage, pos: u64, data: Vec<u8>) {
+        self.add(stage, pos, Contents::Bytes(data));
+    }
+
+    // Add a write of zeros at some stage.
+    pub fn write_zeroes(&mut self, stage: Stage, pos: u64, len: u64) {
+        self.add(stage, pos, Contents::Zeroes(len));
+    }
+
+    pub fn write_u64(&mut self, stage: Stage, pos: u64, n: u64) {
+        self.write(stage, pos, n.to_be_bytes().to_vec());
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    // Write a set of updates to the image, in order. Syncs only happen between stages that
+    // actually have writes, and not after the last one.
+    pub(crate) fn commit(&mut self, mut tx: MetaTx) -> Result<()> {
+        self.ensure_unshared()?;
+        // The sort is stable, so writes within a stage keep their order.
+        tx.writes.sort_by_key(|w| w.0);
+        let mut last = None;
+        let mut zeroes = Vec::new();
+        for (stage, pos, contents) in tx.writes {
+            if last.is_some_and(|s| s != stage) {
+                self.sync()?;
+            }
+            match contents {
+                Contents::Bytes(data) => self.write_all_at(pos, &data)?,
+                Contents::Zeroes(len) => {
+                    zeroes.resize(min(len, ZERO_CHUNK) as usize, 0);
+                    let mut done = 0;
+                    while done < len {
+                        let n = min(len - done, ZERO_CHUNK) as usize;
+                        self.write_all_at(pos + done, &zeroes[..n])?;
+                        done += n as u64;
+                    }
+                }
+            }
+            last = Some(stage);
+        }
+        Ok(())
+    }
+}
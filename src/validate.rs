@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::mem::size_of;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::{ByteIo, ReadAt};
+
+use super::{Qcow2, Result};
+use super::extension;
+use super::header::{self, Header};
+use super::int::padding_to_multiple;
+use super::options::has_byte;
+use super::snapshot;
+
+
+/// How serious a `ValidationFinding` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// Something worth knowing about the image, which isn't a problem.
+    Info,
+    /// Something unusual, which doesn't stop the image from being used.
+    Warning,
+    /// A problem that stops the image from being opened, or makes it unsafe to use.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Something found when validating the header of an image, see `validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValidationFinding {
+    /// How serious this is.
+    pub severity: Severity,
+    /// The header field or structure concerned, such as `cluster_bits` or `extensions`.
+    pub field: String,
+    /// A description of what was found.
+    pub message: String,
+    /// The offset in the image file, if this concerns a particular place.
+    pub offset: Option<u64>,
+}
+
+impl Display for ValidationFinding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.field, self.message)?;
+        if let Some(offset) = self.offset {
+            write!(f, " (at offset {:#x})", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything found when validating the header of an image, see `validate`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ValidationReport {
+    /// Each finding, in the order the header was read.
+    pub findings: Vec<ValidationFinding>,
+}
+
+impl ValidationReport {
+    /// Whether no errors were found. There may still be warnings.
+    pub fn is_valid(&self) -> bool {
+        self.count(Severity::Error) == 0
+    }
+
+    /// Count the findings of a severity.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.findings.iter().filter(|f| f.severity == severity).count()
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "{}", finding)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check everything about the header of a qcow2 image, without opening it.
+///
+/// Opening an image stops at the first problem. This instead keeps going, to list everything
+/// wrong with the header, its extensions, and where its tables are. It also notes things worth
+/// knowing, such as unknown extensions and feature bits. Problems with the image are findings,
+/// errors are only returned if the first cluster can't be read.
+///
+/// # Examples
+///
+/// ```
+/// # fn foo() -> qcow2::Result<()> {
+/// let report = qcow2::validate(std::fs::File::open("tests/test.qcow2")?)?;
+/// assert!(report.is_valid(), "{}", report);
+/// # Ok(()) } fn main() { foo().unwrap(); }
+/// ```
+pub fn validate<I: ReadAt>(io: I) -> Result<ValidationReport> {
+    let mut validator = Validator {
+        io: &io,
+        header: Header::default(),
+        findings: Vec::new(),
+    };
+    validator.run()?;
+    Ok(ValidationReport { findings: validator.findings })
+}
+
+// State while validating a header.
+struct Validator<'a, I: 'a + ReadAt> {
+    io: &'a I,
+    header: Header,
+    findings: Vec<ValidationFinding>,
+}
+
+impl<'a, I> Validator<'a, I>
+    where I: 'a + ReadAt
+{
+    fn add(&mut self, severity: Severity, field: &str, offset: Option<u64>, message: String) {
+        self.findings.push(ValidationFinding {
+            severity,
+            field: field.to_owned(),
+            message,
+            offset,
+        });
+    }
+
+    fn error(&mut self, field: &str, offset: u64, message: &str) {
+        self.add(Severity::Error, field, Some(offset), message.to_owned());
+    }
+
+    fn read(&self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let read = Qcow2::read_partial_at(self.io, 0, &mut buf)?;
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut buf = self.read(header::HEADER_READ)?;
+        let has_v3 = self.header.read_fields(&buf);
+        let c = self.header.c.clone();
+        if buf.len() < size_of::<u32>() || c.magic != header::MAGIC {
+            self.error("magic", 0, "not a qcow2 file");
+            return Ok(());
+        }
+        if c.version != header::SUPPORTED_VERSION {
+            self.error("version", 4, &format!("unsupported version {}", c.version));
+            return Ok(());
+        }
+        if !has_v3 {
+            let msg = format!("the file ends after {} bytes, inside the header", buf.len());
+            self.add(Severity::Error, "header", None, msg);
+            return Ok(());
+        }
+        if c.cluster_bits < 9 || c.cluster_bits > 22 {
+            self.error("cluster_bits", 20, &format!("bad cluster_bits {}", c.cluster_bits));
+            return Ok(());
+        }
+
+        // Read everything the header may use, like `Header::read`.
+        let cs = self.header.cluster_size();
+        if buf.len() == header::HEADER_READ {
+            buf = self.read(cs as usize + header::MAX_BACKING_FILE_NAME)?;
+        }
+        self.check_common();
+        let end = self.check_v3(&buf);
+        self.check_backing_file(&buf, end);
+        self.check_features();
+        self.check_bounds();
+        Ok(())
+    }
+
+    fn check_common(&mut self) {
+        let h = &self.header;
+        let (c, cs) = (h.c.clone(), h.cluster_size());
+        if h.has_backing_file() {
+            let end = c.backing_file_offset.checked_add(c.backing_file_size as u64);
+            if end.is_none_or(|end| end > cs) {
+                self.error("backing_file_offset", 8, "backing file name not in first cluster");
+            }
+            if c.backing_file_size as usize > header::MAX_BACKING_FILE_NAME {
+                self.error("backing_file_size", 16, "backing file name size too big");
+            }
+        }
+        if c.crypt_method != 0 {
+            self.error("crypt_method", 32, "encryption is not supported");
+        }
+        let max = self.header.geometry().max_size();
+        if c.size > max {
+            let msg = format!("virtual size {} is more than the maximum of {}", c.size, max);
+            self.error("size", 24, &msg);
+        }
+        let needed = self.header.l1_entries();
+        if c.l1_size as u64 != needed {
+            let msg = format!("L1 table has {} entries, but the disk needs {}", c.l1_size, needed);
+            self.error("l1_size", 36, &msg);
+        }
+        let aligned = [("l1_table_offset", 40, c.l1_table_offset),
+                       ("refcount_table_offset", 48, c.refcount_table_offset),
+                       ("snapshots_offset", 64, c.snapshots_offset)];
+        for &(field, offset, value) in &aligned {
+            if !value.is_multiple_of(cs) {
+                self.error(field, offset, &format!("{:#x} is not cluster aligned", value));
+            }
+        }
+        if c.refcount_table_clusters == 0 {
+            self.error("refcount_table_clusters", 56, "there is no refcount table");
+        }
+    }
+
+    // Check the version 3 header and its extensions. Returns where the extensions end, if they
+    // could be read.
+    fn check_v3(&mut self, buf: &[u8]) -> Option<u64> {
+        let cs = self.header.cluster_size();
+        let refcount_order = self.header.v3.refcount_order;
+        if refcount_order > 6 {
+            self.error("refcount_order", 96, &format!("bad refcount_order {}", refcount_order));
+        }
+
+        let len = self.header.v3.header_length as u64;
+        let fixed = header::HEADER_LENGTH_V3 as u64;
+        if len < fixed {
+            self.error("header_length", 100, &format!("header is {} bytes, file claims {}",
+                                                      fixed, len));
+            return None;
+        }
+        if !len.is_multiple_of(8) {
+            self.error("header_length", 100,
+                       &format!("header length {} is not a multiple of 8", len));
+            return None;
+        }
+        if len > cs {
+            self.error("header_length", 100, "complete header too big for first cluster");
+            return None;
+        }
+        if buf.len() < len as usize {
+            self.add(Severity::Error, "header_length", None,
+                     format!("the file ends after {} bytes, inside the header", buf.len()));
+            return None;
+        }
+        self.add(Severity::Info, "header_length", Some(100),
+                 format!("header is {} bytes", len));
+
+        if len > fixed {
+            let ty = buf[fixed as usize];
+            if ty != 0 {
+                self.error("compression_type", fixed,
+                           &format!("compression type {} is not supported", ty));
+            }
+            let pad_end = len.min(header::COMPRESSION_PADDING_END);
+            let pad = &buf[header::COMPRESSION_TYPE_END as usize..pad_end as usize];
+            if pad.iter().any(|&b| b != 0) {
+                self.add(Severity::Warning, "header_length", Some(header::COMPRESSION_TYPE_END),
+                         "header padding is not zero".to_owned());
+            }
+        }
+        self.check_extensions(buf, len)
+    }
+
+    fn check_extensions(&mut self, buf: &[u8], mut pos: u64) -> Option<u64> {
+        let cs = self.header.cluster_size();
+        let mut seen = HashSet::new();
+        let mut count = 0;
+        loop {
+            let field = "extensions";
+            let start = pos as usize;
+            if buf.len() < start + 8 {
+                self.error(field, pos, "header extensions run past the end of the file");
+                return None;
+            }
+            let code = BigEndian::read_u32(&buf[start..]);
+            let len = BigEndian::read_u32(&buf[start + 4..]) as u64;
+            if code == extension::EXT_CODE_NONE {
+                if len != 0 {
+                    self.add(Severity::Warning, field, Some(pos + 4),
+                             "end of header extensions has a length".to_owned());
+                }
+                return Some(pos + 8);
+            }
+
+            count += 1;
+            if count > header::DEFAULT_MAX_EXTENSIONS {
+                self.error(field, pos, &format!("more than {} header extensions",
+                                                header::DEFAULT_MAX_EXTENSIONS));
+                return None;
+            }
+            let padded = len + padding_to_multiple(len, 8) as u64;
+            let end = pos + 8 + padded;
+            if end > cs || end > buf.len() as u64 {
+                self.error(field, pos, "complete header too big for first cluster");
+                return None;
+            }
+
+            let data = &buf[start + 8..start + 8 + len as usize];
+            let known = self.header.v3.is_known_extension(code);
+            if !seen.insert(code) {
+                let msg = format!("duplicate header extension {:#x}", code);
+                let severity = if known { Severity::Error } else { Severity::Warning };
+                self.add(severity, field, Some(pos), msg);
+            } else if known {
+                self.check_extension(code, data, pos);
+            } else {
+                self.add(Severity::Info, field, Some(pos),
+                         format!("unknown header extension {:#x}, {} bytes", code, len));
+            }
+            if buf[start + 8 + len as usize..end as usize].iter().any(|&b| b != 0) {
+                self.add(Severity::Warning, field, Some(pos + 8 + len),
+                         format!("padding of header extension {:#x} is not zero", code));
+            }
+            pos = end;
+        }
+    }
+
+    // Parse an extension we understand, keeping it for later checks.
+    fn check_extension(&mut self, code: u32, data: &[u8], pos: u64) {
+        let mut io: ByteIo<_, BigEndian> = ByteIo::new(data);
+        let result = self.header.v3.extension(code).read(&mut io);
+        let msg = match result {
+            Ok(()) if !io.is_empty() => {
+                format!("{} bytes left after reading extension {:#x}", io.len(), code)
+            }
+            Ok(()) => return,
+            Err(e) => format!("extension {:#x}: {}", code, e),
+        };
+        self.add(Severity::Error, "extensions", Some(pos), msg);
+    }
+
+    fn check_backing_file(&mut self, buf: &[u8], extensions_end: Option<u64>) {
+        let c = self.header.c.clone();
+        if !self.header.has_backing_file() {
+            return;
+        }
+        if extensions_end.is_some_and(|end| c.backing_file_offset < end) {
+            self.error("backing_file_offset", 8, "backing file name overlaps header extensions");
+            return;
+        }
+        let start = c.backing_file_offset as usize;
+        if let Some(name) = buf.get(start..start + c.backing_file_size as usize) {
+            self.add(Severity::Info, "backing_file_offset", Some(c.backing_file_offset),
+                     format!("backing file is {}", String::from_utf8_lossy(name)));
+        }
+    }
+
+    fn check_features(&mut self) {
+        let v3 = &self.header.v3;
+        let mut notes = Vec::new();
+        if v3.incompatible.enabled(header::INCOMPATIBLE_DIRTY) {
+            notes.push((Severity::Warning, "image was not closed cleanly, refcounts may be wrong"));
+        }
+        if v3.incompatible.enabled(header::INCOMPATIBLE_CORRUPT) {
+            notes.push((Severity::Error, "image is marked corrupt"));
+        }
+        if v3.incompatible.enabled(header::INCOMPATIBLE_DATA_FILE) {
+            notes.push((Severity::Info, "guest data is in an external data file"));
+        }
+        let unknown = v3.incompatible.unknown();
+        let unknown = match unknown.bits() {
+            0 => None,
+            _ => Some(format!("unknown incompatible features: {}",
+                              unknown.to_string(&v3.feature_name_table))),
+        };
+        let raw_without_file = v3.autoclear.enabled(header::AUTOCLEAR_DATA_FILE_RAW) &&
+                               !v3.incompatible.enabled(header::INCOMPATIBLE_DATA_FILE);
+
+        for (severity, msg) in notes {
+            self.add(severity, "incompatible_features", Some(72), msg.to_owned());
+        }
+        if let Some(msg) = unknown {
+            self.add(Severity::Error, "incompatible_features", Some(72), msg);
+        }
+        if raw_without_file {
+            self.error("autoclear_features", 88,
+                       "raw external data bit set without an external data file");
+        }
+    }
+
+    // Make sure the tables the header points to are in the file.
+    fn check_bounds(&mut self) {
+        let c = self.header.c.clone();
+        let snapshots = match snapshot::table_bounds(&self.header) {
+            Ok((_, len)) => len,
+            Err(e) => {
+                self.add(Severity::Error, "nb_snapshots", Some(60), e.to_string());
+                0
+            }
+        };
+        let tables = [("refcount_table_offset",
+                       c.refcount_table_offset,
+                       c.refcount_table_clusters as u64 * self.header.cluster_size()),
+                      ("l1_table_offset",
+                       c.l1_table_offset,
+                       c.l1_size as u64 * size_of::<u64>() as u64),
+                      ("snapshots_offset", c.snapshots_offset, snapshots)];
+        for &(field, offset, len) in &tables {
+            if len == 0 {
+                continue;
+            }
+            let msg = match offset.checked_add(len) {
+                None => "table is past the end of the file".to_owned(),
+                Some(end) => {
+                    match has_byte(self.io, end - 1) {
+                        Ok(true) => continue,
+                        Ok(false) => "table is past the end of the file".to_owned(),
+                        Err(e) => format!("can't read table: {}", e),
+                    }
+                }
+            };
+            self.add(Severity::Error, field, Some(offset), msg);
+        }
+    }
+}
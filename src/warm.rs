@@ -0,0 +1,73 @@
+use std::cmp::min;
+
+use positioned_io::ReadAt;
+
+use super::{CachePolicy, Qcow2, Result};
+use super::cache::CacheKey;
+use super::diff::GuestRange;
+use super::read::L1Entry;
+
+
+/// What `Qcow2::warm` did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WarmStats {
+    /// The number of L2 tables covering the range.
+    pub tables: u64,
+    /// The number of those tables that had to be read from the image.
+    pub tables_loaded: u64,
+    /// The number of bytes of metadata read from the image.
+    pub bytes_loaded: u64,
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: ReadAt,
+          C: CachePolicy
+{
+    /// Load the metadata for a range of the main virtual disk into the cache.
+    ///
+    /// This reads every L2 table covering the range that isn't already cached, so that reading
+    /// the range later needs no metadata reads. Unlike reading the range, no guest data is read.
+    /// The part of the range past the end of the virtual disk is ignored.
+    ///
+    /// Tables stay in the cache only as long as it wants to keep them. If the range needs more
+    /// tables than fit in the cache, some of them will be dropped again, see `CacheStats`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use qcow2::{GuestRange, Qcow2};
+    ///
+    /// # fn foo() -> qcow2::Result<()> {
+    /// let qcow = Qcow2::open(std::fs::File::open("tests/test.qcow2")?)?;
+    /// let stats = qcow.warm(GuestRange { offset: 0, len: qcow.guest_size() })?;
+    /// assert_eq!(stats.bytes_loaded, stats.tables_loaded * qcow.cluster_size());
+    /// # Ok(()) } fn main() { foo().unwrap(); }
+    /// ```
+    pub fn warm(&self, range: GuestRange) -> Result<WarmStats> {
+        let mut stats = WarmStats::default();
+        let end = min(range.end(), self.guest_size());
+        if range.offset >= end {
+            return Ok(stats);
+        }
+        let reader = self.reader()?;
+        let coverage = self.geometry().l2_coverage();
+        for l1_idx in range.offset / coverage..end.div_ceil(coverage) {
+            let pos = match self.l1_entry_read(&reader.l1, l1_idx)? {
+                L1Entry::Empty => continue,
+                L1Entry::Standard { pos, .. } => pos,
+            };
+            stats.tables += 1;
+            let key = CacheKey {
+                image: self.image_id,
+                offset: pos,
+            };
+            if !self.l2_cache.contains(key) {
+                self.l2_table(pos)?;
+                stats.tables_loaded += 1;
+                stats.bytes_loaded += self.cluster_size();
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,691 @@
+use std::cmp::min;
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+use positioned_io::{ByteIo, ReadAt, Size, WriteAt, WriteIntAt};
+
+use super::{CacheKey, CachePolicy, Error, Qcow2, Result, SharedCache};
+use super::alloc::Allocator;
+use super::header::INCOMPATIBLE_DIRTY;
+use super::read::{L1_COW, L1Entry, L2_COW, L2_ZERO, L2Entry};
+use super::tx::{MetaTx, Stage};
+
+
+/// A source of data that can also be written, and synced to stable storage.
+///
+/// Operations that modify an image need it to be opened from a `Storage`. Read-only operations
+/// only need `ReadAt`.
+pub trait Storage: ReadAt + WriteAt {
+    /// Make sure all writes so far are on stable storage.
+    ///
+    /// Writes may be reordered between calls to `sync`, so it is used to order metadata updates.
+    /// The default implementation just flushes.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    /// Truncate or extend the storage to a length.
+    ///
+    /// This is only used to give back space that's no longer needed. The default
+    /// implementation does nothing, leaving the storage at its current length.
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Ok(())
+    }
+}
+
+// Not on Windows, where positioned-io implements `WriteAt` for `File` with a read.
+#[cfg(unix)]
+impl Storage for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+impl Storage for Vec<u8> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "vector size too big"))?;
+        // Fail, rather than abort, if there's no room.
+        self.try_reserve_exact(len.saturating_sub(self.len()))
+            .map_err(|e| io::Error::new(io::ErrorKind::OutOfMemory, e))?;
+        self.resize(len, 0);
+        Ok(())
+    }
+}
+
+impl<S: Storage + ?Sized> Storage for &mut S {
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+impl<I, C> Qcow2<I, C>
+    where I: Storage,
+          C: CachePolicy
+{
+    /// Make sure all changes to the image are on stable storage.
+    pub fn sync(&mut self) -> Result<()> {
+        self.io.flush()?;
+        self.io.sync()?;
+        Ok(())
+    }
+
+    /// Get a Writer for the main virtual disk.
+    ///
+    /// This allows data to be written inside the virtual disk image. Snapshots are left
+    /// unchanged, shared clusters are copied before they're modified.
+    ///
+    /// Writers don't update persistent dirty bitmaps. Any bitmap with the auto flag is marked as
+    /// in use, so nothing trusts it afterwards.
+    pub fn writer(&mut self) -> Result<Writer<'_, I, C>> {
+        Writer::new(self)
+    }
+
+    // Check that we can modify this image.
+    pub(crate) fn ensure_writable(&self) -> Result<()> {
+        self.ensure_readable()?;
+        self.ensure_unshared()?;
+        if self.header().has_data_file() {
+            return Err(Error::UnsupportedFeature("writing to an external data file".to_owned()));
+        }
+        // A dirty image may have out of date refcounts, we can't trust them for allocation.
+        if self.header().v3.incompatible.enabled(INCOMPATIBLE_DIRTY) {
+            return Err(Error::UnsupportedFeature("writing to a dirty image".to_owned()));
+        }
+        Ok(())
+    }
+
+    // Check that no other handle to this image exists, which would not see changes.
+    pub(crate) fn ensure_unshared(&self) -> Result<()> {
+        if Arc::strong_count(&self.handles) > 1 {
+            return Err(Error::UnsupportedFeature("modifying an image with other handles"
+                .to_owned()));
+        }
+        Ok(())
+    }
+
+    // Write bytes to the image file.
+    pub(crate) fn write_all_at(&mut self, pos: u64, buf: &[u8]) -> Result<()> {
+        self.io.write_all_at(pos, buf)?;
+        Ok(())
+    }
+
+    // Write big-endian integers to the image file.
+    #[allow(dead_code)]
+    pub(crate) fn write_u32_at(&mut self, pos: u64, n: u32) -> Result<()> {
+        WriteIntAt::write_u32_at(&mut self.io, pos, n)?;
+        Ok(())
+    }
+    #[allow(dead_code)]
+    pub(crate) fn write_u64_at(&mut self, pos: u64, n: u64) -> Result<()> {
+        WriteIntAt::write_u64_at(&mut self.io, pos, n)?;
+        Ok(())
+    }
+}
+
+// The most shared data to copy in one transaction, when preallocating.
+const PREALLOCATE_COPY_LIMIT: u64 = 16 << 20;
+
+/// What `Writer::zero_entire_image` does with each cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroMode {
+    /// Drop every mapping. If the image has a backing file, reads fall through to it again.
+    Discard,
+    /// Mark every cluster with the zero flag, so it reads as zeros even if the image has a
+    /// backing file. This needs an L2 table for every L1 entry, but no data clusters.
+    ZeroFlag,
+}
+
+/// How to preallocate a range of the virtual disk, see `Writer::preallocate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preallocation {
+    /// Allocate clusters, but mark them as reading zeros instead of writing to them.
+    Metadata,
+    /// Allocate clusters, and fill them with zeros.
+    Full,
+}
+
+// What to do with a whole cluster, see `Writer::clusters_apply`.
+pub(crate) enum ClusterOp {
+    // Fill it with data, which must be a whole cluster.
+    Write(Vec<u8>),
+    // Make it read as zeros, even if the image has a backing file.
+    Zero,
+    // Drop its mapping, so it reads from the backing file, or as zeros without one.
+    Discard,
+}
+
+// An L2 table being modified by a write.
+struct L2Table {
+    entries: Vec<u64>,
+    dirty: bool,
+}
+
+// The changes made by a single write, that are not yet committed.
+#[derive(Default)]
+struct Pending {
+    tx: MetaTx,
+    l2: HashMap<u64, L2Table>,
+    // Indices of modified L1 entries.
+    l1: Vec<u64>,
+}
+
+/// A writer of data to the virtual disk image.
+///
+/// Each write is committed to the image before it returns. Metadata is updated in an order
+/// that keeps the image consistent, apart from possibly leaked clusters, if the writer is
+/// interrupted. Use `flush` to make sure written data is on stable storage.
+///
+/// Like writes to a slice, writes past the end of the virtual disk are cut short.
+///
+/// A writer can also read, and sees its own writes.
+pub struct Writer<'a, I: 'a + Storage, C: CachePolicy = SharedCache> {
+    pub(crate) q: &'a mut Qcow2<I, C>,
+    pub(crate) l1: ByteIo<Vec<u8>, BigEndian>,
+    alloc: Allocator,
+}
+
+impl<'a, I, C> Writer<'a, I, C>
+    where I: 'a + Storage,
+          C: CachePolicy
+{
+    fn new(q: &'a mut Qcow2<I, C>) -> Result<Self> {
+        q.ensure_writable()?;
+        q.bitmaps_mark_in_use()?;
+        let l1 = ByteIo::new(q.l1_read(q.header().c.l1_table_offset, q.header().l1_entries())?);
+        let alloc = Allocator::new(q)?;
+        Ok(Writer { q, l1, alloc })
+    }
+
+    // Forget any uncommitted changes, after a failed write.
+    fn reset(&mut self) -> Result<()> {
+        *self.l1 = self.q.l1_read(self.q.header().c.l1_table_offset, self.q.header().l1_entries())?;
+        self.alloc = Allocator::new(self.q)?;
+        Ok(())
+    }
+
+    fn l1_entry_write(&mut self, pending: &mut Pending, l1_idx: u64, entry: u64) {
+        let offset = l1_idx as usize * size_of::<u64>();
+        BigEndian::write_u64(&mut self.l1[offset..], entry);
+        if !pending.l1.contains(&l1_idx) {
+            pending.l1.push(l1_idx);
+        }
+    }
+
+    // Whether nothing else refers to a cluster, so it can be modified in place.
+    fn owned(&mut self, pos: u64, cow: bool) -> Result<bool> {
+        Ok(cow || self.alloc.get(self.q, pos / self.q.cluster_size())? == 1)
+    }
+
+    // Get the L2 table for an L1 index, ready to be modified. Returns its offset.
+    fn l2_for_write(&mut self, pending: &mut Pending, l1_idx: u64) -> Result<u64> {
+        let (pos, cow) = match self.q.l1_entry_read(&self.l1, l1_idx)? {
+            L1Entry::Empty => {
+                let pos = self.alloc.allocate(self.q)?;
+                let entries = vec![0; self.q.header().l2_entries() as usize];
+                pending.l2.insert(pos, L2Table { entries, dirty: true });
+                self.l1_entry_write(pending, l1_idx, pos | L1_COW);
+                return Ok(pos);
+            }
+            L1Entry::Standard { pos, cow } => (pos, cow),
+        };
+        if pending.l2.contains_key(&pos) {
+            return Ok(pos);
+        }
+        let mut entries = self.q.l2_table(pos)?.to_vec();
+        if self.owned(pos, cow)? {
+            if !cow {
+                self.l1_entry_write(pending, l1_idx, pos | L1_COW);
+            }
+            pending.l2.insert(pos, L2Table { entries, dirty: false });
+            return Ok(pos);
+        }
+
+        // The table is shared with a snapshot, so copy it. Refcounts of data count references
+        // from each L1 table, so they don't change, but none of the data is ours alone.
+        for entry in &mut entries {
+            *entry &= !L2_COW;
+        }
+        let new = self.alloc.allocate(self.q)?;
+        pending.l2.insert(new, L2Table { entries, dirty: true });
+        self.l1_entry_write(pending, l1_idx, new | L1_COW);
+        self.alloc.decrement(self.q, pos / self.q.cluster_size())?;
+        Ok(new)
+    }
+
+    // Write data within a single cluster.
+    fn cluster_write(&mut self,
+                     pending: &mut Pending,
+                     guest_block_pos: u64,
+                     offset: u64,
+                     buf: &[u8])
+                     -> Result<()> {
+        let (l1_idx, l2_idx, _) = self.q.header().guest_offset_info(guest_block_pos);
+        let l2_pos = self.l2_for_write(pending, l1_idx)?;
+        let raw = pending.l2[&l2_pos].entries[l2_idx as usize];
+        let entry = self.q.l2_entry_parse(raw)?;
+
+        let pos = match entry {
+            L2Entry::Compressed { .. } => {
+                return Err(Error::UnsupportedFeature("writing to compressed blocks".to_owned()));
+            }
+            L2Entry::Standard { pos, cow, zero: false } if self.owned(pos, cow)? => {
+                pending.tx.write(Stage::Data, pos + offset, buf.to_vec());
+                pos
+            }
+            _ => {
+                // Write a whole cluster, filling in whatever it held before.
+                let mut data = vec![0; self.q.cluster_size() as usize];
+                if buf.len() < data.len() {
+                    self.q.guest_block_read(entry, guest_block_pos, 0, &mut data)?;
+                }
+                data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+                let pos = match entry {
+                    L2Entry::Standard { pos, cow, .. } if self.owned(pos, cow)? => pos,
+                    L2Entry::Standard { pos, .. } => {
+                        self.alloc.decrement(self.q, pos / self.q.cluster_size())?;
+                        self.alloc.allocate(self.q)?
+                    }
+                    _ => self.alloc.allocate(self.q)?,
+                };
+                pending.tx.write(Stage::Data, pos, data);
+                pos
+            }
+        };
+
+        let new = pos | L2_COW;
+        if new != raw {
+            let table = pending.l2.get_mut(&l2_pos).unwrap();
+            table.entries[l2_idx as usize] = new;
+            table.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, pending: Pending) -> Result<()> {
+        let Pending { mut tx, l2, l1 } = pending;
+        let mut tables = Vec::new();
+        for (pos, table) in l2 {
+            if !table.dirty {
+                continue;
+            }
+            let mut buf = vec![0; self.q.cluster_size() as usize];
+            for (chunk, &entry) in buf.chunks_mut(size_of::<u64>()).zip(&table.entries) {
+                BigEndian::write_u64(chunk, entry);
+            }
+            tx.write(Stage::L2, pos, buf);
+            tables.push((pos, table.entries));
+        }
+        let l1_offset = self.q.header().c.l1_table_offset;
+        for idx in l1 {
+            let offset = idx as usize * size_of::<u64>();
+            let entry = self.l1[offset..offset + size_of::<u64>()].to_vec();
+            tx.write(Stage::L1, l1_offset + offset as u64, entry);
+        }
+        self.alloc.stage(self.q, &mut tx);
+
+        // Make sure nobody uses stale tables, even if the commit fails part way.
+        let image = self.q.image_id;
+        for &(offset, _) in &tables {
+            self.q.l2_cache.invalidate(CacheKey { image, offset });
+        }
+        self.q.commit(tx)?;
+        self.alloc.committed(self.q);
+        for (offset, entries) in tables {
+            self.q.l2_cache.put_l2(CacheKey { image, offset }, Arc::from(entries));
+        }
+        Ok(())
+    }
+
+    fn write_inner(&mut self, pos: u64, buf: &[u8]) -> Result<usize> {
+        let size = self.q.guest_size();
+        if pos >= size {
+            return Ok(0);
+        }
+        let len = min(buf.len() as u64, size - pos) as usize;
+
+        let mut pending = Pending::default();
+        let mut done = 0;
+        while done < len {
+            let guest_pos = pos + done as u64;
+            let offset = guest_pos % self.q.cluster_size();
+            let n = min((len - done) as u64, self.q.cluster_size() - offset) as usize;
+            self.cluster_write(&mut pending,
+                               guest_pos - offset,
+                               offset,
+                               &buf[done..done + n])?;
+            done += n;
+        }
+        self.commit(pending)?;
+        Ok(len)
+    }
+
+    /// Read from the virtual disk, copying whatever comes from the backing file into the image.
+    ///
+    /// Clusters that were read from the backing file are then allocated in the image, so later
+    /// reads don't need the backing file. Whole clusters are copied, even for partial reads.
+    /// The data returned is the same as from `read_at`, and failing to copy doesn't fail the
+    /// read.
+    pub fn copy_on_read(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.q.guest_size();
+        if pos >= size {
+            return Ok(0);
+        }
+        let ret = min(buf.len() as u64, size - pos) as usize;
+        let cluster_size = self.q.cluster_size();
+        let backing_size = self.q.backing().map_or(0, |b| b.size());
+        let mut done = 0;
+        while done < ret {
+            let guest_pos = pos + done as u64;
+            let offset = guest_pos % cluster_size;
+            let guest_block_pos = guest_pos - offset;
+            let n = min((ret - done) as u64, cluster_size - offset) as usize;
+            let entry = self.q.l2_entry_read(&self.l1, guest_block_pos)?;
+            let buf = &mut buf[done..done + n];
+            if matches!(entry, L2Entry::Empty) && guest_block_pos < backing_size {
+                let mut data = vec![0; cluster_size as usize];
+                self.q.guest_block_read(entry, guest_block_pos, 0, &mut data)?;
+                buf.copy_from_slice(&data[offset as usize..offset as usize + n]);
+                let mut pending = Pending::default();
+                let copied = self.cluster_write(&mut pending, guest_block_pos, 0, &data)
+                    .and_then(|_| self.commit(pending));
+                if copied.is_err() {
+                    // The data was read fine, so just forget about copying it.
+                    let _ = self.reset();
+                }
+            } else {
+                self.q.guest_block_read(entry, guest_block_pos, offset, buf)?;
+            }
+            done += n;
+        }
+        Ok(ret)
+    }
+
+    /// Make the whole virtual disk read as zeros, by dropping its mappings.
+    ///
+    /// This is much faster than writing zeros. Snapshots are unchanged. If `truncate` is true,
+    /// the file is then cut down to the last cluster still in use, if the storage supports it.
+    pub fn zero_entire_image(&mut self, mode: ZeroMode, truncate: bool) -> Result<()> {
+        let result = self.zero_entire_image_inner(mode, truncate);
+        if result.is_err() {
+            self.reset()?;
+        }
+        result
+    }
+
+    fn zero_entire_image_inner(&mut self, mode: ZeroMode, truncate: bool) -> Result<()> {
+        let cluster_size = self.q.cluster_size();
+        let mut tx = MetaTx::default();
+        let mut dropped = Vec::new();
+        for l1_idx in 0..self.q.header().l1_entries() {
+            if let L1Entry::Standard { pos, .. } = self.q.l1_entry_read(&self.l1, l1_idx)? {
+                // Refcounts count references from each L1 table, so drop a reference to the
+                // table and to everything in it.
+                for &entry in self.q.l2_table(pos)?.iter() {
+                    match self.q.l2_entry_parse(entry)? {
+                        L2Entry::Empty | L2Entry::Zero { .. } => {}
+                        L2Entry::Standard { pos, .. } => {
+                            self.alloc.decrement(self.q, pos / cluster_size)?
+                        }
+                        L2Entry::Compressed { pos, size, .. } => {
+                            for cluster in pos / cluster_size..(pos + size).div_ceil(cluster_size) {
+                                self.alloc.decrement(self.q, cluster)?;
+                            }
+                        }
+                    }
+                }
+                self.alloc.decrement(self.q, pos / cluster_size)?;
+                dropped.push(pos);
+            }
+
+            let entry = match mode {
+                ZeroMode::Discard => 0,
+                ZeroMode::ZeroFlag => {
+                    let pos = self.alloc.allocate(self.q)?;
+                    let mut table = vec![0; cluster_size as usize];
+                    for chunk in table.chunks_mut(size_of::<u64>()) {
+                        BigEndian::write_u64(chunk, L2_ZERO);
+                    }
+                    tx.write(Stage::L2, pos, table);
+                    dropped.push(pos);
+                    pos | L1_COW
+                }
+            };
+            let offset = l1_idx as usize * size_of::<u64>();
+            BigEndian::write_u64(&mut self.l1[offset..], entry);
+        }
+        tx.write(Stage::L1, self.q.header().c.l1_table_offset, self.l1.to_vec());
+        self.alloc.stage(self.q, &mut tx);
+
+        let image = self.q.image_id;
+        for offset in dropped {
+            self.q.l2_cache.invalidate(CacheKey { image, offset });
+        }
+        self.q.commit(tx)?;
+        self.alloc.committed(self.q);
+
+        if truncate {
+            // Nothing may point past the new end of the file, even after a crash.
+            self.q.sync()?;
+            let len = self.alloc.used_clusters(self.q)? * cluster_size;
+            self.q.io.set_len(len)?;
+            self.q.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Allocate space for a range of the virtual disk, so later writes to it never need to
+    /// allocate clusters.
+    ///
+    /// The contents of the range don't change. Clusters shared with a snapshot or read from the
+    /// backing file are copied, and clusters that are already allocated are left alone, so
+    /// preallocating a range again does nothing. The range is cut short at the end of the
+    /// virtual disk.
+    ///
+    /// Metadata is updated once per L2 table, and new clusters are contiguous where possible.
+    pub fn preallocate(&mut self, pos: u64, len: u64, mode: Preallocation) -> Result<()> {
+        let result = self.preallocate_inner(pos, len, mode);
+        if result.is_err() {
+            self.reset()?;
+        }
+        result
+    }
+
+    fn preallocate_inner(&mut self, pos: u64, len: u64, mode: Preallocation) -> Result<()> {
+        let cluster_size = self.q.cluster_size();
+        let l2_entries = self.q.header().l2_entries();
+        let end = min(pos.saturating_add(len), self.q.guest_size()).div_ceil(cluster_size);
+        let mut cluster = pos / cluster_size;
+        while cluster < end {
+            let mut pending = Pending::default();
+            let l2_pos = self.l2_for_write(&mut pending, cluster / l2_entries)?;
+            let table_end = min(end, (cluster / l2_entries + 1) * l2_entries);
+            // Clusters that read as zeros, and need a fresh cluster.
+            let mut fresh = Vec::new();
+            let mut copied = 0;
+            while cluster < table_end && copied < PREALLOCATE_COPY_LIMIT {
+                let idx = (cluster % l2_entries) as usize;
+                cluster += 1;
+                let raw = pending.l2[&l2_pos].entries[idx];
+                let entry = self.q.l2_entry_parse(raw)?;
+                let new = match entry {
+                    L2Entry::Compressed { .. } => {
+                        return Err(Error::UnsupportedFeature("preallocating compressed blocks"
+                            .to_owned()));
+                    }
+                    L2Entry::Standard { pos, cow, zero } if self.owned(pos, cow)? => {
+                        if zero && mode == Preallocation::Full {
+                            pending.tx.write_zeroes(Stage::Data, pos, cluster_size);
+                            pos | L2_COW
+                        } else {
+                            raw | L2_COW
+                        }
+                    }
+                    L2Entry::Standard { pos, zero: true, .. } => {
+                        self.alloc.decrement(self.q, pos / cluster_size)?;
+                        fresh.push(idx);
+                        continue;
+                    }
+                    L2Entry::Zero { .. } => {
+                        fresh.push(idx);
+                        continue;
+                    }
+                    L2Entry::Empty if self.q.backing().is_none() => {
+                        fresh.push(idx);
+                        continue;
+                    }
+                    _ => {
+                        // Shared with a snapshot, or read from the backing file, so copy the
+                        // data.
+                        let mut data = vec![0; cluster_size as usize];
+                        let guest_pos = (cluster - 1) * cluster_size;
+                        self.q.guest_block_read(entry, guest_pos, 0, &mut data)?;
+                        if let L2Entry::Standard { pos, .. } = entry {
+                            self.alloc.decrement(self.q, pos / cluster_size)?;
+                        }
+                        let new = self.alloc.allocate(self.q)?;
+                        pending.tx.write(Stage::Data, new, data);
+                        copied += cluster_size;
+                        new | L2_COW
+                    }
+                };
+                if new != raw {
+                    let table = pending.l2.get_mut(&l2_pos).unwrap();
+                    table.entries[idx] = new;
+                    table.dirty = true;
+                }
+            }
+
+            if !fresh.is_empty() {
+                let count = fresh.len() as u64;
+                let start = self.alloc.allocate_clusters(self.q, count)?;
+                let flags = match mode {
+                    Preallocation::Metadata => {
+                        // Make sure the file covers the clusters, even though we don't write
+                        // to them.
+                        pending.tx.write_zeroes(Stage::Data, start + count * cluster_size - 1, 1);
+                        L2_COW | L2_ZERO
+                    }
+                    Preallocation::Full => {
+                        pending.tx.write_zeroes(Stage::Data, start, count * cluster_size);
+                        L2_COW
+                    }
+                };
+                let table = pending.l2.get_mut(&l2_pos).unwrap();
+                for (i, &idx) in fresh.iter().enumerate() {
+                    table.entries[idx] = (start + i as u64 * cluster_size) | flags;
+                }
+                table.dirty = true;
+            }
+            self.commit(pending)?;
+        }
+        Ok(())
+    }
+
+    // Change whole clusters, each at a cluster-aligned guest offset, in one commit. They should
+    // all be covered by one L2 table.
+    pub(crate) fn clusters_apply(&mut self, ops: Vec<(u64, ClusterOp)>) -> Result<()> {
+        let result = self.clusters_apply_inner(ops);
+        if result.is_err() {
+            self.reset()?;
+        }
+        result
+    }
+
+    fn clusters_apply_inner(&mut self, ops: Vec<(u64, ClusterOp)>) -> Result<()> {
+        let mut pending = Pending::default();
+        for (pos, op) in ops {
+            match op {
+                ClusterOp::Write(data) => self.cluster_write(&mut pending, pos, 0, &data)?,
+                ClusterOp::Zero => self.cluster_unmap(&mut pending, pos, true)?,
+                ClusterOp::Discard => self.cluster_unmap(&mut pending, pos, false)?,
+            }
+        }
+        self.commit(pending)
+    }
+
+    // Drop the mapping of a single cluster, and mark it as reading zeros if `zero` is set.
+    fn cluster_unmap(&mut self,
+                     pending: &mut Pending,
+                     guest_block_pos: u64,
+                     zero: bool)
+                     -> Result<()> {
+        let cluster_size = self.q.cluster_size();
+        let (l1_idx, l2_idx, _) = self.q.header().guest_offset_info(guest_block_pos);
+        // Without an L2 table, there's no mapping to drop.
+        if !zero && matches!(self.q.l1_entry_read(&self.l1, l1_idx)?, L1Entry::Empty) {
+            return Ok(());
+        }
+        let l2_pos = self.l2_for_write(pending, l1_idx)?;
+        let raw = pending.l2[&l2_pos].entries[l2_idx as usize];
+        match self.q.l2_entry_parse(raw)? {
+            L2Entry::Empty | L2Entry::Zero { .. } => {}
+            L2Entry::Standard { pos, .. } => self.alloc.decrement(self.q, pos / cluster_size)?,
+            L2Entry::Compressed { pos, size, .. } => {
+                for cluster in pos / cluster_size..(pos + size).div_ceil(cluster_size) {
+                    self.alloc.decrement(self.q, cluster)?;
+                }
+            }
+        }
+
+        let new = if zero { L2_ZERO } else { 0 };
+        if new != raw {
+            let table = pending.l2.get_mut(&l2_pos).unwrap();
+            table.entries[l2_idx as usize] = new;
+            table.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, I, C> WriteAt for Writer<'a, I, C>
+    where I: 'a + Storage,
+          C: CachePolicy
+{
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        match self.write_inner(pos, buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                self.reset()?;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.q.sync()?;
+        Ok(())
+    }
+}
+
+impl<'a, I, C> ReadAt for Writer<'a, I, C>
+    where I: 'a + Storage,
+          C: CachePolicy
+{
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.q.guest_read(&self.l1, self.q.guest_size(), pos, buf)
+    }
+}
+
+impl<'a, I, C> Size for Writer<'a, I, C>
+    where I: 'a + Storage,
+          C: CachePolicy
+{
+    fn size(&self) -> io::Result<Option<u64>> {
+        Ok(Some(self.q.guest_size()))
+    }
+}
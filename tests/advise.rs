@@ -0,0 +1,131 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::io;
+use std::sync::Mutex;
+
+use common::{CountingIo, ImageBuilder};
+use positioned_io::ReadAt;
+use qcow2::{Advice, AdviseIo, GuestRange, Qcow2};
+
+// An image with 512 byte clusters, so each L2 table covers 32 KiB, and data at the start of
+// each table.
+fn image() -> Vec<u8> {
+    let span = 64 * 512;
+    (0..4)
+        .fold(ImageBuilder::new().cluster_bits(9).size(4 * span),
+              |b, i| b.data(i * span, &[i as u8 + 1; 4]))
+        .build()
+}
+
+// Count the reads needed to read some data.
+fn reads_for<I: ReadAt>(io: &CountingIo<Vec<u8>>, reader: &qcow2::Reader<I>, pos: u64) -> usize {
+    let before = io.reads();
+    let mut buf = [0; 4];
+    reader.read_exact_at(pos, &mut buf).unwrap();
+    io.reads() - before
+}
+
+#[test]
+fn advise_sequential() {
+    let io = CountingIo::new(image());
+    let qcow = Qcow2::open(&io).unwrap();
+    let mut reader = qcow.reader().unwrap();
+    reads_for(&io, &reader, 0);
+    // Both the L2 table and the data must be read.
+    assert_eq!(reads_for(&io, &reader, 64 * 512), 2);
+
+    reader.advise(Advice::Sequential).unwrap();
+    reads_for(&io, &reader, 64 * 512 + 4);
+    reader.advise(Advice::Random).unwrap();
+    // The next L2 table was already read.
+    assert_eq!(reads_for(&io, &reader, 128 * 512), 1);
+    assert_eq!(reads_for(&io, &reader, 192 * 512), 2);
+}
+
+#[test]
+fn advise_will_need() {
+    let io = CountingIo::new(image());
+    let qcow = Qcow2::open(&io).unwrap();
+    let mut reader = qcow.reader().unwrap();
+    reader.advise(Advice::WillNeed(GuestRange { offset: 100, len: 128 * 512 })).unwrap();
+    assert_eq!(reads_for(&io, &reader, 0), 1);
+    assert_eq!(reads_for(&io, &reader, 64 * 512), 1);
+    assert_eq!(reads_for(&io, &reader, 128 * 512), 1);
+    assert_eq!(reads_for(&io, &reader, 192 * 512), 2);
+
+    // Ranges past the end of the disk are cut short.
+    reader.advise(Advice::WillNeed(GuestRange { offset: 0, len: 1 << 30 })).unwrap();
+}
+
+// Storage that records the hints it gets.
+#[derive(Default)]
+struct HintIo {
+    inner: Vec<u8>,
+    needed: Mutex<Vec<(u64, u64)>>,
+    patterns: Mutex<Vec<Advice>>,
+}
+
+impl ReadAt for HintIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read_at(pos, buf)
+    }
+}
+
+impl AdviseIo for HintIo {
+    fn will_need(&self, pos: u64, len: u64) -> io::Result<()> {
+        self.needed.lock().unwrap().push((pos, len));
+        Ok(())
+    }
+
+    fn access_pattern(&self, advice: Advice) -> io::Result<()> {
+        self.patterns.lock().unwrap().push(advice);
+        Ok(())
+    }
+}
+
+#[test]
+fn advise_io() {
+    // The builder puts data from the sixth cluster on, in the order it's added.
+    let cs = 65536;
+    let img = ImageBuilder::new()
+        .size(64 * cs)
+        .data(0, &[1; 4])
+        .data(cs, &[2; 4])
+        .data(3 * cs, &[3; 4])
+        .data(40 * cs, &[4; 4])
+        .build();
+    let io = HintIo { inner: img, ..Default::default() };
+    let qcow = Qcow2::open(&io).unwrap();
+    let mut reader = qcow.reader().unwrap();
+
+    reader.advise_io(Advice::WillNeed(GuestRange { offset: 0, len: 4 * cs })).unwrap();
+    assert_eq!(*io.needed.lock().unwrap(), vec![(5 * cs, 3 * cs)]);
+    assert!(io.patterns.lock().unwrap().is_empty());
+
+    // Sequential reads hint ahead, but not on every read.
+    io.needed.lock().unwrap().clear();
+    reader.advise_io(Advice::Sequential).unwrap();
+    assert_eq!(*io.patterns.lock().unwrap(), vec![Advice::Sequential]);
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    reader.read_exact_at(4, &mut buf).unwrap();
+    assert_eq!(*io.needed.lock().unwrap(), vec![(5 * cs, 3 * cs)]);
+    reader.read_exact_at(20 * cs, &mut buf).unwrap();
+    assert_eq!(io.needed.lock().unwrap().last(), Some(&(8 * cs, cs)));
+}
+
+#[cfg(all(target_os = "linux", feature = "fadvise"))]
+#[test]
+fn advise_file() {
+    let file = std::fs::File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let mut reader = qcow.reader().unwrap();
+    reader.advise_io(Advice::Sequential).unwrap();
+    reader.advise_io(Advice::WillNeed(GuestRange { offset: 0, len: 1 << 20 })).unwrap();
+    let mut buf = [0; 4096];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    reader.advise_io(Advice::Normal).unwrap();
+}
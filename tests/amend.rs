@@ -0,0 +1,171 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use positioned_io::ReadAt;
+
+use common::{feature_name, ImageBuilder, Op, RecordingIo, EXT_FEATURE_NAME_TABLE};
+use qcow2::{FeatureKind, FeatureNameTableBuilder, Qcow2};
+
+const EXT_BACKING_FORMAT: u32 = 0xe2792aca;
+
+fn names(qcow: &Qcow2<&mut Vec<u8>>) -> Vec<(FeatureKind, u8, String)> {
+    qcow.feature_name_table().iter().map(|(k, b, n)| (k, b, n.to_owned())).collect()
+}
+
+#[test]
+fn feature_name_table_round_trip() {
+    let builder = ImageBuilder::new()
+        .compatible(1 << 5)
+        .extension(EXT_BACKING_FORMAT, b"qcow2".to_vec())
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"five"))
+        .extension(0x12345678, b"unknown".to_vec());
+    let mut img = ImageBuilder { header_length: 112, ..builder }.build();
+    // Put the backing file name far from the extensions.
+    let name = b"base.qcow2";
+    img[8..16].copy_from_slice(&4096u64.to_be_bytes());
+    img[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+    img[4096..4096 + name.len()].copy_from_slice(name);
+
+    let table = FeatureNameTableBuilder::new()
+        .feature(FeatureKind::Compatible, 5, "a much longer name for bit five")
+        .feature(FeatureKind::Autoclear, 63, "last")
+        .build()
+        .unwrap();
+    let expected: Vec<_> = table.iter().map(|(k, b, n)| (k, b, n.to_owned())).collect();
+    assert_eq!(expected[0], (FeatureKind::Incompatible, 0, "dirty".to_owned()));
+
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.set_feature_name_table(table).unwrap();
+        assert_eq!(names(&qcow), expected);
+    }
+    // The name now follows the extensions.
+    assert_ne!(u64::from_be_bytes(img[8..16].try_into().unwrap()), 4096);
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    assert_eq!(names(&qcow), expected);
+    let info = qcow.info().unwrap();
+    assert_eq!(info.backing_file, Some("base.qcow2".into()));
+    assert_eq!(info.backing_format, Some("qcow2".to_owned()));
+    assert!(format!("{:?}", qcow).contains("a much longer name for bit five"));
+    assert!(format!("{:?}", qcow).contains("code: \"0x12345678\", size: 7"));
+}
+
+// Get the bytes of each header extension, including its code, length and padding.
+fn extensions(img: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut exts: Vec<(String, Vec<u8>)> = Vec::new();
+    for span in qcow2::header_layout(img).unwrap() {
+        let name = match span.name.strip_prefix("extension[") {
+            Some(rest) => rest.split(']').next().unwrap().to_owned(),
+            None => continue,
+        };
+        match exts.last_mut() {
+            Some(last) if last.0 == name && !span.name.ends_with(" type") => {
+                last.1.extend_from_slice(&span.bytes)
+            }
+            _ => exts.push((name, span.bytes)),
+        }
+    }
+    exts
+}
+
+#[test]
+fn unknown_extensions_preserved() {
+    let mut img = ImageBuilder::new()
+        .extension(0x1111, b"vendor metadata".to_vec())
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"five"))
+        .extension(0x2222, vec![0xff; 3])
+        .build();
+    let before = extensions(&img);
+    let fixed = img[..104].to_vec();
+
+    let table = FeatureNameTableBuilder::new()
+        .feature(FeatureKind::Compatible, 5, "a much longer name for bit five")
+        .build()
+        .unwrap();
+    Qcow2::open(&mut img).unwrap().set_feature_name_table(table).unwrap();
+
+    // Unknown extensions keep their bytes and their place around the ones that changed.
+    let after = extensions(&img);
+    let codes: Vec<_> = after.iter().map(|e| e.0.as_str()).collect();
+    assert_eq!(codes, ["0x1111", "0x6803f857", "0x2222"]);
+    assert_eq!(after[0], before[0]);
+    assert_ne!(after[1], before[1]);
+    assert_eq!(after[2], before[2]);
+    assert_eq!(&img[..104], &fixed[..]);
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    let unknown: Vec<_> = qcow.unknown_extensions().iter().map(|u| (u.code(), u.data())).collect();
+    assert_eq!(unknown, [(0x1111, &b"vendor metadata"[..]), (0x2222, &[0xff; 3][..])]);
+}
+
+#[test]
+fn feature_name_table_remove() {
+    let mut img = ImageBuilder::new()
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"five"))
+        .data(0, b"data")
+        .build();
+    Qcow2::open(&mut img).unwrap().set_feature_name_table(Default::default()).unwrap();
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    assert!(qcow.feature_name_table().is_empty());
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn feature_name_table_builder_limits() {
+    let long = "x".repeat(47);
+    let err = FeatureNameTableBuilder::new()
+        .feature(FeatureKind::Compatible, 1, &long)
+        .build()
+        .unwrap_err();
+    assert_eq!(err.to_string(),
+               "Unsupported feature: feature names longer than 46 bytes");
+    assert!(FeatureNameTableBuilder::new()
+        .feature(FeatureKind::Compatible, 64, "x")
+        .build()
+        .is_err());
+}
+
+#[test]
+fn lazy_refcounts() {
+    let mut io = RecordingIo::new(ImageBuilder::new().compatible(1).data(0, b"data").build());
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        assert!(qcow.info().unwrap().lazy_refcounts);
+        qcow.set_lazy_refcounts(false).unwrap();
+        assert!(!qcow.info().unwrap().lazy_refcounts);
+    }
+    assert_eq!(io.ops, vec![Op::Write { pos: 80, len: 8 }, Op::Sync]);
+    assert!(!Qcow2::open(&mut io).unwrap().info().unwrap().lazy_refcounts);
+
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        qcow.set_compatible_feature(0, true).unwrap();
+        qcow.set_compatible_feature(7, true).unwrap();
+    }
+    let qcow = Qcow2::open(&mut io).unwrap();
+    let info = qcow.info().unwrap();
+    assert!(info.lazy_refcounts);
+    assert_eq!(info.features, vec!["lazy refcounts", "bit 7 of Compatible"]);
+}
+
+#[test]
+fn lazy_refcounts_inconsistent() {
+    let builder = ImageBuilder::new().compatible(1).data(0, b"data");
+    let mut img = builder.build();
+    // Drop the refcount of the data cluster.
+    builder.set_refcount(&mut img, 5, 0);
+
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    assert_eq!(qcow.set_lazy_refcounts(false).unwrap_err().to_string(),
+               "Malformed qcow2 file: can't disable lazy refcounts, found 1 corruptions");
+    // Turning them on is always fine.
+    qcow.set_lazy_refcounts(true).unwrap();
+    assert!(qcow.set_compatible_feature(64, true).is_err());
+}
@@ -0,0 +1,209 @@
+#![cfg(feature = "tokio")]
+
+extern crate positioned_io;
+extern crate qcow2;
+extern crate tokio;
+
+mod common;
+
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use common::{ImageBuilder, SnapshotSpec};
+use positioned_io::{ReadAt, Size};
+use qcow2::{ChainOptions, Error, open_chain};
+
+const CS: u64 = 65536;
+
+// What's happened to a set of images, read through SlowIo.
+#[derive(Default)]
+struct Stats {
+    // Reads in flight, and the most there have been at once.
+    active: AtomicUsize,
+    max_active: AtomicUsize,
+    // The images read from so far.
+    read: Mutex<HashSet<&'static str>>,
+    read_changed: Condvar,
+    // Whether a read gave up waiting for another image.
+    timed_out: AtomicBool,
+}
+
+// Storage that takes a while to answer each read, like storage across a network.
+#[derive(Clone)]
+struct SlowIo {
+    name: &'static str,
+    img: Arc<Vec<u8>>,
+    delay: Duration,
+    stats: Arc<Stats>,
+    // Reads past the first cluster wait until this image has been read from.
+    wait_for: Option<&'static str>,
+}
+
+impl ReadAt for SlowIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let stats = &self.stats;
+        {
+            let mut read = stats.read.lock().unwrap();
+            read.insert(self.name);
+            stats.read_changed.notify_all();
+            if let Some(other) = self.wait_for.filter(|_| pos >= CS) {
+                let timeout = Duration::from_secs(10);
+                let (_read, result) = stats.read_changed
+                    .wait_timeout_while(read, timeout, |read| !read.contains(other))
+                    .unwrap();
+                if result.timed_out() {
+                    stats.timed_out.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let active = stats.active.fetch_add(1, Ordering::SeqCst) + 1;
+        stats.max_active.fetch_max(active, Ordering::SeqCst);
+        thread::sleep(self.delay);
+        stats.active.fetch_sub(1, Ordering::SeqCst);
+        self.img.read_at(pos, buf)
+    }
+}
+
+impl Size for SlowIo {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.img.size()
+    }
+}
+
+// A set of named images, with storage that's slow to connect to and to read.
+struct Images {
+    images: HashMap<&'static str, SlowIo>,
+    stats: Arc<Stats>,
+}
+
+impl Images {
+    fn new(delay: Duration, images: Vec<(&'static str, Vec<u8>)>) -> Self {
+        let stats = Arc::new(Stats::default());
+        let images = images.into_iter()
+            .map(|(name, img)| {
+                let io = SlowIo {
+                    name,
+                    img: Arc::new(img),
+                    delay,
+                    stats: stats.clone(),
+                    wait_for: None,
+                };
+                (name, io)
+            })
+            .collect();
+        Images { images, stats }
+    }
+
+    fn get(&self, name: &str) -> SlowIo {
+        self.images[name].clone()
+    }
+
+    // Find an image by its backing file name, after a delay.
+    fn resolve(&self, name: &Path) -> impl Future<Output = qcow2::Result<SlowIo>> {
+        let found = name.to_str().and_then(|n| self.images.get(n)).cloned();
+        async move {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            found.ok_or_else(|| Error::Io(io::ErrorKind::NotFound.into()))
+        }
+    }
+
+    fn max_active(&self) -> usize {
+        self.stats.max_active.load(Ordering::SeqCst)
+    }
+}
+
+// A chain of qcow2 images, each backed by the next, with data at a different offset in each.
+fn chain(depth: usize) -> Vec<(&'static str, Vec<u8>)> {
+    let names = ["l0", "l1", "l2", "l3", "l4", "l5", "l6", "l7"];
+    (0..depth)
+        .map(|i| {
+            let mut builder = ImageBuilder::new().data(i as u64 * CS, &[i as u8 + 1; 16]);
+            if i + 1 < depth {
+                builder = builder.backing_file(names[i + 1]);
+            }
+            (names[i], builder.build())
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn open_chain_reads() {
+    let mut images = chain(3);
+    images.push(("raw", vec![9; 1 << 20]));
+    images[2].1 = ImageBuilder::new().backing_file("raw").data(2 * CS, &[3; 16]).build();
+    let images = Images::new(Duration::ZERO, images);
+    let opts = ChainOptions::new();
+    let qcow = open_chain(images.get("l0"), |name| images.resolve(name), &opts).await.unwrap();
+
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 16];
+    for (i, &expected) in [1, 2, 3, 9].iter().enumerate() {
+        reader.read_exact_at(i as u64 * CS, &mut buf).unwrap();
+        assert_eq!(buf, [expected; 16]);
+    }
+    let mid = qcow.backing().unwrap().image().unwrap();
+    assert_eq!(mid.backing_file_name(), Some(Path::new("l2")));
+    let bottom = mid.backing().unwrap().image().unwrap();
+    assert!(bottom.backing().unwrap().image().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn open_chain_overlaps() {
+    // The top image has a snapshot table to read. That waits for the backing file to start
+    // opening, so opening one after the other would time out.
+    let top = ImageBuilder::new()
+        .backing_file("l1")
+        .data(0, &[1; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    let mut images = Images::new(Duration::from_millis(5), chain(2));
+    images.images.get_mut("l0").unwrap().img = Arc::new(top);
+    images.images.get_mut("l0").unwrap().wait_for = Some("l1");
+
+    let opts = ChainOptions::new();
+    let qcow = open_chain(images.get("l0"), |name| images.resolve(name), &opts).await.unwrap();
+    assert!(!images.stats.timed_out.load(Ordering::SeqCst));
+    assert_eq!(qcow.snapshots().len(), 1);
+    assert!(images.max_active() >= 2);
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(CS, &mut buf).unwrap();
+    assert_eq!(buf, [2; 16]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn open_chain_concurrency() {
+    for &limit in &[1, 2, 3] {
+        let images = Images::new(Duration::from_millis(5), chain(8));
+        let mut opts = ChainOptions::new();
+        opts.concurrency(limit);
+        let qcow = open_chain(images.get("l0"), |name| images.resolve(name), &opts).await.unwrap();
+        // Each image is read while the one above finishes opening, so there's always some
+        // overlap unless the limit prevents it.
+        let max = images.max_active();
+        assert!(max <= limit && max >= min(limit, 2), "{} reads at once", max);
+
+        let mut buf = [0; 16];
+        qcow.reader().unwrap().read_exact_at(7 * CS, &mut buf).unwrap();
+        assert_eq!(buf, [8; 16]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn open_chain_missing() {
+    let mut images = Images::new(Duration::ZERO, chain(3));
+    images.images.remove("l2");
+    let opts = ChainOptions::new();
+    let result = open_chain(images.get("l0"), |name| images.resolve(name), &opts).await;
+    match result {
+        Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
@@ -0,0 +1,133 @@
+#![cfg(feature = "tokio")]
+
+extern crate positioned_io;
+extern crate qcow2;
+extern crate tokio;
+
+mod common;
+
+use std::future::Future;
+use std::io::{ErrorKind, SeekFrom};
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use common::{CountingIo, ImageBuilder};
+use positioned_io::ReadAt;
+use qcow2::{AsyncSeqReader, Backing, Qcow2};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const CS: u64 = 65536;
+
+// Data in cluster 0 and the short last cluster, zeros in cluster 1, and a hole in cluster 2.
+fn image() -> Vec<u8> {
+    ImageBuilder::new()
+        .size(3 * CS + 100)
+        .data(0, &[1; 16])
+        .zero_cluster(CS)
+        .data(3 * CS, &[2; 8])
+        .build()
+}
+
+fn sync_read<I: ReadAt>(qcow: &Qcow2<I>) -> Vec<u8> {
+    let mut buf = vec![0; qcow.guest_size() as usize];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    buf
+}
+
+#[tokio::test]
+async fn async_read_all() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let expected = sync_read(&qcow);
+    let mut reader = AsyncSeqReader::new(qcow.into_reader().unwrap());
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+    assert_eq!(reader.position(), 3 * CS + 100);
+    // At the end, there's nothing more.
+    assert_eq!(reader.read(&mut [0; 16]).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn async_read_backing() {
+    let base = Qcow2::open(ImageBuilder::new().size(4 * CS).data(2 * CS, &[3; 16]).build())
+        .unwrap();
+    let img = ImageBuilder::new()
+        .size(4 * CS)
+        .backing_file("base.qcow2")
+        .data(0, &[1; 16])
+        .build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.set_backing(Backing::qcow2(base).unwrap());
+    let expected = sync_read(&qcow);
+    assert_eq!(expected[2 * CS as usize], 3);
+
+    let mut reader = AsyncSeqReader::new(qcow.into_reader().unwrap());
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, expected);
+}
+
+#[tokio::test]
+async fn async_read_holes_not_read() {
+    let img = ImageBuilder::new().size(64 << 20).data(0, &[1; 16]).data(32 << 20, &[2; 16])
+        .build();
+    // The storage must outlive any loads still running on other threads.
+    let io: &'static CountingIo<Vec<u8>> = Box::leak(Box::new(CountingIo::new(img)));
+    let mut reader = AsyncSeqReader::new(Qcow2::open(io).unwrap().into_reader().unwrap());
+    let before = io.reads();
+    let copied = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await.unwrap();
+    assert_eq!(copied, 64 << 20);
+    // The L2 table, and the two data clusters.
+    assert_eq!(io.reads(), before + 3);
+}
+
+#[tokio::test]
+async fn async_seek() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let expected = sync_read(&qcow);
+    let mut reader = AsyncSeqReader::new(qcow.into_reader().unwrap());
+    let mut buf = [0; 8];
+
+    assert_eq!(reader.seek(SeekFrom::Start(12)).await.unwrap(), 12);
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected[12..20]);
+
+    assert_eq!(reader.seek(SeekFrom::End(-100)).await.unwrap(), 3 * CS);
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [2; 8]);
+
+    assert_eq!(reader.seek(SeekFrom::Current(-12)).await.unwrap(), 3 * CS - 4);
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, expected[3 * CS as usize - 4..][..8]);
+
+    let err = reader.seek(SeekFrom::Current(-(4 * CS as i64))).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(reader.position(), 3 * CS + 4);
+
+    // Past the end, reads find nothing.
+    reader.seek(SeekFrom::Start(10 * CS)).await.unwrap();
+    assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn async_read_dropped() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let expected = sync_read(&qcow);
+    let mut reader = AsyncSeqReader::new(qcow.into_reader().unwrap());
+    let mut buf = vec![0; 16];
+    {
+        // Start a read, and give up on it before it finishes.
+        let mut read = pin!(reader.read(&mut buf));
+        let mut cx = Context::from_waker(Waker::noop());
+        if let Poll::Ready(n) = read.as_mut().poll(&mut cx) {
+            assert_eq!(n.unwrap(), 16);
+        }
+    }
+    if reader.position() == 0 {
+        reader.read_exact(&mut buf).await.unwrap();
+    }
+    assert_eq!(buf, [1; 16]);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, expected[16..]);
+}
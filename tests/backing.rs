@@ -0,0 +1,182 @@
+extern crate positioned_io;
+extern crate qcow2;
+mod common;
+
+use std::io;
+use std::path::Path;
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{Backing, Error, Preallocation, Qcow2, Storage, ZeroMode};
+
+// A backend that refuses all writes.
+struct ReadOnlyIo(Vec<u8>);
+
+impl ReadAt for ReadOnlyIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_at(pos, buf)
+    }
+}
+
+impl WriteAt for ReadOnlyIo {
+    fn write_at(&mut self, _: u64, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Size for ReadOnlyIo {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.0.size()
+    }
+}
+
+impl Storage for ReadOnlyIo {}
+
+fn base() -> Vec<u8> {
+    ImageBuilder::new().data(0, &[1; 16]).data(65536, &[2; 16]).build()
+}
+
+fn overlay() -> Vec<u8> {
+    ImageBuilder::new().backing_file("base.qcow2").data(65536, &[3; 16]).build()
+}
+
+fn open_overlay<I: ReadAt>(io: I, base: I) -> Qcow2<I> {
+    let mut qcow = Qcow2::open(io).unwrap();
+    qcow.set_backing(Backing::qcow2(Qcow2::open(base).unwrap()).unwrap());
+    qcow
+}
+
+#[test]
+fn backing_missing() {
+    let qcow = Qcow2::open(overlay()).unwrap();
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("base.qcow2")));
+    match qcow.reader() {
+        Err(Error::UnsupportedFeature(_)) => {}
+        r => panic!("unexpected result {:?}", r.err()),
+    }
+}
+
+#[test]
+fn backing_qcow2() {
+    let qcow = open_overlay(overlay(), base());
+    assert_eq!(qcow.backing().unwrap().size(), 1 << 20);
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 16];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    reader.read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(buf, [3; 16]);
+    reader.read_exact_at(65536 * 2, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+}
+
+#[test]
+fn backing_raw() {
+    let mut qcow = Qcow2::open(overlay()).unwrap();
+    qcow.set_backing(Backing::raw(vec![4; 65536 * 2 + 100]).unwrap());
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 16];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [4; 16]);
+    reader.read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(buf, [3; 16]);
+    // Past the end of the backing file.
+    reader.read_exact_at(65536 * 2 + 92, &mut buf).unwrap();
+    assert_eq!(buf[..8], [4; 8]);
+    assert_eq!(buf[8..], [0; 8]);
+}
+
+#[test]
+fn backing_write() {
+    let mut qcow = open_overlay(overlay(), base());
+    qcow.writer().unwrap().write_all_at(4, &[5; 4]).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 5, 5, 5, 5, 1, 1, 1, 1, 1, 1, 1, 1]);
+    assert!(qcow.check().unwrap().is_clean());
+
+    let mut backing = [0; 16];
+    qcow.backing().unwrap().image().unwrap().reader().unwrap().read_exact_at(0, &mut backing)
+        .unwrap();
+    assert_eq!(backing, [1; 16]);
+}
+
+#[test]
+fn backing_zero() {
+    let mut qcow = open_overlay(overlay(), base());
+    qcow.writer().unwrap().zero_entire_image(ZeroMode::Discard, false).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    qcow.reader().unwrap().read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(buf, [2; 16]);
+
+    qcow.writer().unwrap().zero_entire_image(ZeroMode::ZeroFlag, false).unwrap();
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn copy_on_read() {
+    let mut img = overlay();
+    {
+        let mut base = base();
+        let mut qcow = open_overlay(&mut img, &mut base);
+        let mut writer = qcow.writer().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(writer.copy_on_read(8, &mut buf).unwrap(), 16);
+        assert_eq!(buf[..8], [1; 8]);
+        assert_eq!(buf[8..], [0; 8]);
+        // Past the end of the backing file's data, but not of the backing file.
+        assert_eq!(writer.copy_on_read(65536 * 2, &mut buf).unwrap(), 16);
+        assert_eq!(buf, [0; 16]);
+    }
+
+    // The copied clusters no longer need the backing file.
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.set_backing(Backing::raw(vec![9; 1 << 20]).unwrap());
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    qcow.reader().unwrap().read_exact_at(65536 * 2, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+    qcow.reader().unwrap().read_exact_at(65536 * 3, &mut buf).unwrap();
+    assert_eq!(buf, [9; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn copy_on_read_failure() {
+    let mut qcow = Qcow2::open(ReadOnlyIo(overlay())).unwrap();
+    qcow.set_backing(Backing::raw(ReadOnlyIo(vec![7; 1 << 20])).unwrap());
+    let mut writer = qcow.writer().unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(writer.copy_on_read(0, &mut buf).unwrap(), 16);
+    assert_eq!(buf, [7; 16]);
+    // The writer still works after failing to copy.
+    assert_eq!(writer.copy_on_read(65536 * 2, &mut buf).unwrap(), 16);
+    assert_eq!(buf, [7; 16]);
+}
+
+#[test]
+fn preallocate_backing() {
+    let mut img = overlay();
+    {
+        let mut base = base();
+        let mut qcow = open_overlay(&mut img, &mut base);
+        qcow.writer().unwrap().preallocate(0, 1 << 20, Preallocation::Metadata).unwrap();
+        assert!(qcow.check().unwrap().is_clean());
+    }
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.set_backing(Backing::raw(vec![9; 1 << 20]).unwrap());
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    qcow.reader().unwrap().read_exact_at(65536 * 3, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+}
@@ -0,0 +1,286 @@
+extern crate positioned_io;
+extern crate qcow2;
+mod common;
+
+use common::{BitmapSpec, ImageBuilder};
+use positioned_io::WriteAt;
+use qcow2::{BackupExtent, Error, ExtentKind, GuestRange, Qcow2};
+
+const CS: u64 = 65536;
+const AUTOCLEAR_BITMAPS: u64 = 1;
+
+fn range(offset: u64, len: u64) -> GuestRange {
+    GuestRange { offset, len }
+}
+
+fn extent(offset: u64, len: u64, kind: ExtentKind) -> BackupExtent {
+    BackupExtent { range: range(offset, len), kind }
+}
+
+// An image builder with the bitmaps autoclear bit set, so bitmaps are consistent.
+fn builder() -> ImageBuilder {
+    ImageBuilder::new().autoclear(AUTOCLEAR_BITMAPS)
+}
+
+fn extents(qcow: &Qcow2<Vec<u8>>) -> Vec<BackupExtent> {
+    let bitmap = &qcow.bitmaps().unwrap()[0];
+    let reader = qcow.reader().unwrap();
+    bitmap.backup_extents(&reader).unwrap().collect::<Result<_, _>>().unwrap()
+}
+
+// Data in the first two clusters, which are adjacent in the file, and zeros in the third.
+fn image(bitmap: BitmapSpec) -> Vec<u8> {
+    builder().data(0, &[1; 16]).data(CS, &[2; 16]).zero_cluster(2 * CS).bitmap(bitmap).build()
+}
+
+#[test]
+fn bitmaps() {
+    let img = builder()
+        .bitmap(BitmapSpec::new("a", 16))
+        .bitmap(BitmapSpec::new("longer name", 9))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let bitmaps = qcow.bitmaps().unwrap();
+    assert_eq!(bitmaps.len(), 2);
+    assert_eq!(bitmaps[0].name, "a");
+    assert_eq!(bitmaps[0].granularity(), CS);
+    assert_eq!(bitmaps[0].table_size, 1);
+    assert_eq!(bitmaps[1].name, "longer name");
+    assert_eq!(bitmaps[1].granularity(), 512);
+    assert_eq!(bitmaps[1].bitmap_type, 1);
+    assert_eq!(bitmaps[0].dirty_ranges(&qcow).unwrap(), vec![]);
+
+    let qcow = Qcow2::open(ImageBuilder::new().build()).unwrap();
+    assert_eq!(qcow.bitmaps().unwrap(), vec![]);
+}
+
+#[test]
+fn bitmap_invalid() {
+    let qcow = Qcow2::open(ImageBuilder::new().bitmap(BitmapSpec::new("a", 8)).build()).unwrap();
+    match qcow.bitmaps() {
+        Err(Error::FileFormat(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn dirty_ranges() {
+    let img = builder().bitmap(BitmapSpec::new("a", 9).dirty(&[0, 1, 5, 2046, 2047])).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let bitmap = &qcow.bitmaps().unwrap()[0];
+    assert_eq!(bitmap.dirty_ranges(&qcow).unwrap(),
+               vec![range(0, 1024), range(2560, 512), range((1 << 20) - 1024, 1024)]);
+
+    // Bits past the end of the virtual disk are ignored.
+    let mut img = builder().bitmap(BitmapSpec::new("a", 9).dirty(&[2046, 2047])).build();
+    img[24..32].copy_from_slice(&((1u64 << 20) - 1000).to_be_bytes());
+    let qcow = Qcow2::open(img).unwrap();
+    let bitmap = &qcow.bitmaps().unwrap()[0];
+    assert_eq!(bitmap.dirty_ranges(&qcow).unwrap(), vec![range((1 << 20) - 1024, 24)]);
+}
+
+#[test]
+fn backup_extents() {
+    // Small granularity is widened to whole clusters.
+    let qcow = Qcow2::open(image(BitmapSpec::new("a", 9).dirty(&[0, 128, 300, 2047]))).unwrap();
+    assert_eq!(extents(&qcow),
+               vec![extent(0, 2 * CS, ExtentKind::Data { host_offset: 5 * CS }),
+                    extent(2 * CS, CS, ExtentKind::Zero),
+                    extent(15 * CS, CS, ExtentKind::Unallocated)]);
+
+    // Large granularity is split by what each cluster holds.
+    let qcow = Qcow2::open(image(BitmapSpec::new("a", 18).dirty(&[0]))).unwrap();
+    assert_eq!(extents(&qcow),
+               vec![extent(0, 2 * CS, ExtentKind::Data { host_offset: 5 * CS }),
+                    extent(2 * CS, CS, ExtentKind::Zero),
+                    extent(3 * CS, CS, ExtentKind::Unallocated)]);
+}
+
+#[test]
+fn backup_extents_all_ones() {
+    let qcow = Qcow2::open(image(BitmapSpec::new("a", 17).all_ones(&[0]))).unwrap();
+    let bitmap = &qcow.bitmaps().unwrap()[0];
+    assert_eq!(bitmap.dirty_ranges(&qcow).unwrap(), vec![range(0, 1 << 20)]);
+    assert_eq!(extents(&qcow),
+               vec![extent(0, 2 * CS, ExtentKind::Data { host_offset: 5 * CS }),
+                    extent(2 * CS, CS, ExtentKind::Zero),
+                    extent(3 * CS, (1 << 20) - 3 * CS, ExtentKind::Unallocated)]);
+}
+
+#[test]
+fn reader_extents() {
+    let qcow = Qcow2::open(image(BitmapSpec::new("a", 16))).unwrap();
+    let reader = qcow.reader().unwrap();
+    let extents = |offset, len| -> Vec<BackupExtent> {
+        reader.extents(range(offset, len)).collect::<Result<_, _>>().unwrap()
+    };
+    assert_eq!(extents(0, 1 << 20),
+               vec![extent(0, 2 * CS, ExtentKind::Data { host_offset: 5 * CS }),
+                    extent(2 * CS, CS, ExtentKind::Zero),
+                    extent(3 * CS, (1 << 20) - 3 * CS, ExtentKind::Unallocated)]);
+
+    // Ranges are widened to whole clusters, and cut short at the end of the disk.
+    assert_eq!(extents(CS + 10, CS), vec![extent(CS, CS, ExtentKind::Data { host_offset: 6 * CS }),
+                                          extent(2 * CS, CS, ExtentKind::Zero)]);
+    assert_eq!(extents((1 << 20) - 1, 100),
+               vec![extent((1 << 20) - CS, CS, ExtentKind::Unallocated)]);
+    assert_eq!(extents(1 << 20, 100), vec![]);
+}
+
+fn autoclear(img: &[u8]) -> u64 {
+    u64::from_be_bytes(img[88..96].try_into().unwrap())
+}
+
+fn dirty(qcow: &Qcow2<&mut Vec<u8>>, name: &str) -> Vec<GuestRange> {
+    let bitmaps = qcow.bitmaps().unwrap();
+    bitmaps.iter().find(|b| b.name == name).unwrap().dirty_ranges(qcow).unwrap()
+}
+
+#[test]
+fn add_bitmap() {
+    let mut img = ImageBuilder::new().data(0, &[1; 16]).build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.add_bitmap("a", 16, true).unwrap();
+        qcow.add_bitmap("b", 9, false).unwrap();
+        assert!(qcow.check().unwrap().is_clean());
+        assert_eq!(dirty(&qcow, "a"), vec![]);
+        // Writes still allocate where they should.
+        qcow.writer().unwrap().write_all_at(CS, &[2; 16]).unwrap();
+        assert!(qcow.check().unwrap().is_clean());
+        match qcow.add_bitmap("a", 16, true) {
+            Err(Error::UnsupportedFeature(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match qcow.resize(2 << 20) {
+            Err(Error::UnsupportedFeature(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+    assert_eq!(autoclear(&img), AUTOCLEAR_BITMAPS);
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    let bitmaps = qcow.bitmaps().unwrap();
+    assert_eq!(bitmaps.len(), 2);
+    assert_eq!(bitmaps[0].name, "a");
+    assert_eq!(bitmaps[0].table_size, 1);
+    assert_eq!(bitmaps[1].name, "b");
+    assert_eq!(bitmaps[1].granularity(), 512);
+    // Writing marked the enabled bitmap as in use.
+    assert!(bitmaps[0].auto() && bitmaps[0].in_use() && !bitmaps[0].consistent);
+    assert!(!bitmaps[1].auto() && !bitmaps[1].in_use() && bitmaps[1].consistent);
+    assert_eq!(dirty(&qcow, "b"), vec![]);
+}
+
+#[test]
+fn clear_bitmap() {
+    // Each entry of the bitmap table covers 256 MiB.
+    let mut img = builder()
+        .size(1 << 29)
+        .bitmap(BitmapSpec::new("a", 9).dirty(&[0, 1000]).all_ones(&[1]))
+        .bitmap(BitmapSpec::new("b", 16).dirty(&[3]))
+        .build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        assert!(qcow.check().unwrap().is_clean());
+        qcow.clear_bitmap("a").unwrap();
+        assert_eq!(dirty(&qcow, "a"), vec![]);
+        assert_eq!(dirty(&qcow, "b"), vec![range(3 * CS, CS)]);
+        assert!(qcow.check().unwrap().is_clean());
+        match qcow.clear_bitmap("c") {
+            Err(Error::NoBitmap(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+    assert_eq!(autoclear(&img), AUTOCLEAR_BITMAPS);
+}
+
+#[test]
+fn remove_bitmap() {
+    let mut img = builder()
+        .bitmap(BitmapSpec::new("a", 9).dirty(&[0, 1000]))
+        .bitmap(BitmapSpec::new("b", 16).dirty(&[3]))
+        .build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.remove_bitmap("a").unwrap();
+        let bitmaps = qcow.bitmaps().unwrap();
+        assert_eq!(bitmaps.len(), 1);
+        assert_eq!(dirty(&qcow, "b"), vec![range(3 * CS, CS)]);
+        assert!(qcow.check().unwrap().is_clean());
+
+        qcow.remove_bitmap("b").unwrap();
+        assert_eq!(qcow.bitmaps().unwrap(), vec![]);
+        assert!(qcow.check().unwrap().is_clean());
+    }
+    assert_eq!(autoclear(&img), 0);
+    assert_eq!(Qcow2::open(img).unwrap().bitmaps().unwrap(), vec![]);
+}
+
+#[test]
+fn bitmap_in_use() {
+    let mut in_use = BitmapSpec::new("a", 16);
+    in_use.flags = 1;
+    let img = builder().bitmap(in_use).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    match qcow.clear_bitmap("a") {
+        Err(Error::UnsupportedFeature(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match qcow.remove_bitmap("a") {
+        Err(Error::UnsupportedFeature(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    qcow.add_bitmap("b", 16, true).unwrap();
+    assert_eq!(qcow.bitmaps().unwrap().len(), 2);
+}
+
+#[test]
+fn bitmap_autoclear_missing() {
+    // Something wrote to the image without updating the bitmaps.
+    let img = ImageBuilder::new().bitmap(BitmapSpec::new("a", 16)).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    match qcow.add_bitmap("b", 16, true) {
+        Err(Error::UnsupportedFeature(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn bitmap_flags() {
+    for &autoclear in &[0, AUTOCLEAR_BITMAPS] {
+        for flags in 0..4 {
+            let mut spec = BitmapSpec::new("a", 16).dirty(&[1]);
+            spec.flags = flags;
+            let img = ImageBuilder::new().autoclear(autoclear).bitmap(spec).build();
+            let qcow = Qcow2::open(img).unwrap();
+            let mut bitmap = qcow.bitmaps().unwrap().remove(0);
+            assert_eq!(bitmap.in_use(), flags & 1 != 0);
+            assert_eq!(bitmap.auto(), flags & 2 != 0);
+            let consistent = autoclear != 0 && flags & 1 == 0;
+            assert_eq!(bitmap.consistent, consistent);
+            match bitmap.dirty_ranges(&qcow) {
+                Ok(ref r) if consistent => assert_eq!(*r, vec![range(CS, CS)]),
+                Err(Error::InconsistentBitmap(ref name)) if !consistent => assert_eq!(name, "a"),
+                r => panic!("unexpected result {:?}", r),
+            }
+            // Reading an inconsistent bitmap can be forced.
+            bitmap.consistent = true;
+            assert_eq!(bitmap.dirty_ranges(&qcow).unwrap(), vec![range(CS, CS)]);
+        }
+    }
+}
+
+#[test]
+fn writer_marks_in_use() {
+    let mut auto = BitmapSpec::new("auto", 16);
+    auto.flags = 2;
+    let img = builder().bitmap(auto).bitmap(BitmapSpec::new("disabled", 16)).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    // Even a writer that never writes could have.
+    qcow.writer().unwrap();
+    let bitmaps = qcow.bitmaps().unwrap();
+    assert_eq!(bitmaps.iter().map(|b| (b.in_use(), b.consistent)).collect::<Vec<_>>(),
+               vec![(true, false), (false, true)]);
+    assert!(qcow.check().unwrap().is_clean());
+}
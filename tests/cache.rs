@@ -0,0 +1,623 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use common::{CountingIo, ImageBuilder};
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{CacheKey, CacheStats, Error, GuestRange, ImageId, LruMetadataCache, MetadataCache,
+            NoMetadataCache, OpenOptions, Qcow2, Qcow2Local, Storage, WarmStats};
+
+#[derive(Default)]
+struct MapCache {
+    tables: Mutex<HashMap<CacheKey, Arc<[u64]>>>,
+    gets: AtomicUsize,
+    puts: AtomicUsize,
+}
+
+impl MetadataCache for MapCache {
+    fn get_l2(&self, key: CacheKey) -> Option<Arc<[u64]>> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        self.tables.lock().unwrap().get(&key).cloned()
+    }
+    fn put_l2(&self, key: CacheKey, table: Arc<[u64]>) {
+        self.puts.fetch_add(1, Ordering::SeqCst);
+        self.tables.lock().unwrap().insert(key, table);
+    }
+    fn invalidate(&self, key: CacheKey) {
+        self.tables.lock().unwrap().remove(&key);
+    }
+    fn invalidate_image(&self, image: ImageId) {
+        self.tables.lock().unwrap().retain(|k, _| k.image != image);
+    }
+}
+
+fn image() -> Vec<u8> {
+    ImageBuilder::new()
+        .data(0, b"zero")
+        .data(65536, b"one")
+        .build()
+}
+
+#[test]
+fn custom_cache() {
+    let cache = Arc::new(MapCache::default());
+    let qcow = OpenOptions::new().cache(cache.clone()).open(image()).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"zero");
+    let mut buf = [0; 3];
+    reader.read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"one");
+
+    // Both clusters share one L2 table.
+    assert_eq!(cache.gets.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.puts.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.tables.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn default_cache_avoids_reads() {
+    let io = CountingIo::new(image());
+    let qcow = qcow2::Qcow2::open(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    let before = io.reads();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    // Only the data is read, not the L2 table.
+    assert_eq!(io.reads(), before + 1);
+}
+
+#[test]
+fn unallocated_reads_cached() {
+    // The first L1 entry has an L2 table, the rest of the disk has none.
+    let io = CountingIo::new(ImageBuilder::new().data(0, b"zero").size(4 << 30).build());
+    let qcow = qcow2::Qcow2::open(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut pos = 1u64;
+    let mut offsets = Vec::new();
+    for _ in 0..1000 {
+        pos = pos.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        offsets.push(65536 + (pos >> 20) % ((4 << 30) - 65536 - 16));
+    }
+
+    let mut buf = [0; 16];
+    let before = io.reads();
+    for &offset in &offsets {
+        reader.read_exact_at(offset, &mut buf).unwrap();
+        assert_eq!(buf, [0; 16]);
+    }
+    // Only the one L2 table is read, and holes in it come from the cache after that.
+    assert_eq!(io.reads(), before + 1);
+    for &offset in &offsets {
+        reader.read_exact_at(offset, &mut buf).unwrap();
+    }
+    assert_eq!(io.reads(), before + 1);
+}
+
+#[test]
+fn warm() {
+    // Each L2 table covers 64 clusters, and four of them exist.
+    let cs = 512;
+    let builder = (0..4).fold(ImageBuilder::new().cluster_bits(9).size(8 * 64 * cs),
+                              |b, i| b.data(i * 2 * 64 * cs, b"data"));
+    let io = CountingIo::new(builder.build());
+    let qcow = Qcow2::open(&io).unwrap();
+
+    let range = GuestRange { offset: 100 * cs, len: 200 * cs };
+    let stats = qcow.warm(range).unwrap();
+    assert_eq!(stats, WarmStats { tables: 2, tables_loaded: 2, bytes_loaded: 2 * cs });
+    let reader = qcow.reader().unwrap();
+    let before = io.reads();
+    let mut buf = [0; 4];
+    reader.read_exact_at(2 * 64 * cs, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    reader.read_exact_at(4 * 64 * cs, &mut buf).unwrap();
+    // Only the data is read.
+    assert_eq!(io.reads(), before + 2);
+
+    // Cached tables aren't read again, and nothing past the end of the disk is loaded.
+    let stats = qcow.warm(GuestRange { offset: 0, len: u64::MAX / 2 }).unwrap();
+    assert_eq!(stats, WarmStats { tables: 4, tables_loaded: 2, bytes_loaded: 2 * cs });
+    assert_eq!(qcow.warm(GuestRange { offset: 8 * 64 * cs, len: cs }).unwrap(),
+               WarmStats::default());
+}
+
+#[test]
+fn no_cache() {
+    let io = CountingIo::new(image());
+    let qcow = OpenOptions::new().cache(Arc::new(NoMetadataCache)).open(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    let before = io.reads();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"zero");
+    assert_eq!(io.reads(), before + 2);
+}
+
+#[test]
+fn batched_reads() {
+    // Each L2 table covers 64 clusters. Data clusters are adjacent in the file.
+    let cs = 512;
+    let mut builder = ImageBuilder::new().cluster_bits(9);
+    for cluster in [0, 1, 2, 4, 5, 63] {
+        builder = builder.data(cluster * cs, &[cluster as u8 + 1; 512]);
+    }
+    builder = builder.data(64 * cs, &[65; 512]);
+    let io = CountingIo::new(builder.build());
+    let qcow = OpenOptions::new().cache(Arc::new(NoMetadataCache)).open(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    // One read of the L2 table, then one for each run of data.
+    let mut buf = vec![0; 6 * cs as usize - 10];
+    let before = io.reads();
+    reader.read_exact_at(5, &mut buf).unwrap();
+    assert_eq!(io.reads(), before + 3);
+    assert_eq!(buf[..507], [1; 507]);
+    assert_eq!(buf[507..1019], [2; 512]);
+    assert_eq!(buf[1019..1531], [3; 512]);
+    assert_eq!(buf[1531..2043], [0; 512]);
+    assert_eq!(buf[2043..2555], [5; 512]);
+    assert_eq!(*buf.last().unwrap(), 6);
+
+    // Crossing into the next table needs another lookup.
+    let mut buf = vec![0; 2 * cs as usize];
+    let before = io.reads();
+    reader.read_exact_at(63 * cs, &mut buf).unwrap();
+    assert_eq!(io.reads(), before + 4);
+    assert_eq!(buf[..512], [64; 512]);
+    assert_eq!(buf[512..], [65; 512]);
+}
+
+fn read4(qcow: &qcow2::Qcow2<&CountingIo<Vec<u8>>>, pos: u64) -> [u8; 4] {
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(pos, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn shared_cache_distinct_images() {
+    // Both images have their L2 table at the same offset, with different contents.
+    let a = CountingIo::new(ImageBuilder::new().data(0, b"aaaa").build());
+    let b = CountingIo::new(ImageBuilder::new().data(65536, b"bbbb").build());
+    let cache = Arc::new(LruMetadataCache::new(8 << 16));
+    let qa = OpenOptions::new().cache(cache.clone()).open(&a).unwrap();
+    let qb = OpenOptions::new().cache(cache.clone()).open(&b).unwrap();
+    assert_ne!(qa.image_id(), qb.image_id());
+
+    assert_eq!(&read4(&qa, 0), b"aaaa");
+    assert_eq!(&read4(&qb, 0), &[0; 4]);
+    assert_eq!(&read4(&qb, 65536), b"bbbb");
+    assert_eq!(&read4(&qa, 65536), &[0; 4]);
+}
+
+#[test]
+fn shared_cache_identical_images() {
+    let img = ImageBuilder::new().data(0, b"base").build();
+    let a = CountingIo::new(img.clone());
+    let b = CountingIo::new(img);
+    let cache = Arc::new(LruMetadataCache::new(8 << 16));
+    let id = ImageId::user(1);
+    let qa = OpenOptions::new().cache(cache.clone()).image_id(id).open(&a).unwrap();
+    let qb = OpenOptions::new().cache(cache.clone()).image_id(id).open(&b).unwrap();
+
+    assert_eq!(&read4(&qa, 0), b"base");
+    // The second image finds the L2 table already cached, and only reads the L1 and data.
+    let before = b.reads();
+    assert_eq!(&read4(&qb, 0), b"base");
+    assert_eq!(b.reads(), before + 2);
+}
+
+#[test]
+fn shared_cache_global_eviction() {
+    let a = CountingIo::new(ImageBuilder::new().data(0, b"aaaa").build());
+    let b = CountingIo::new(ImageBuilder::new().data(0, b"bbbb").build());
+    let cache = Arc::new(LruMetadataCache::new(1 << 16));
+    let qa = OpenOptions::new().cache(cache.clone()).open(&a).unwrap();
+    let qb = OpenOptions::new().cache(cache.clone()).open(&b).unwrap();
+
+    read4(&qa, 0);
+    read4(&qb, 0);
+    // Reading the second image evicted the first image's table.
+    let before = a.reads();
+    read4(&qa, 0);
+    assert_eq!(a.reads(), before + 3);
+}
+
+#[test]
+fn cache_byte_limit() {
+    // An L2 table with 64 KiB clusters uses 64 KiB decoded, but only 512 bytes with 512 byte
+    // clusters.
+    let a = CountingIo::new(image());
+    let b = ImageBuilder::new()
+        .cluster_bits(9)
+        .size(1 << 16)
+        .data(0, b"b")
+        .data(64 * 512, b"b")
+        .build();
+    let b = CountingIo::new(b);
+    let cache = Arc::new(LruMetadataCache::new(1 << 16));
+    let qa = OpenOptions::new().cache(cache.clone()).open(&a).unwrap();
+    let qb = OpenOptions::new().cache(cache.clone()).open(&b).unwrap();
+    assert_eq!(cache.stats(), CacheStats { entries: 0, used: 0, limit: 1 << 16 });
+
+    read4(&qa, 0);
+    assert_eq!(cache.stats(), CacheStats { entries: 1, used: 1 << 16, limit: 1 << 16 });
+    read4(&qb, 0);
+    assert_eq!(cache.stats(), CacheStats { entries: 1, used: 512, limit: 1 << 16 });
+    read4(&qb, 64 * 512);
+    assert_eq!(cache.stats(), CacheStats { entries: 2, used: 1024, limit: 1 << 16 });
+
+    // Tables bigger than the limit aren't cached at all.
+    let small = Arc::new(LruMetadataCache::new(1000));
+    let qa = OpenOptions::new().cache(small.clone()).open(&a).unwrap();
+    read4(&qa, 0);
+    assert_eq!(small.stats().entries, 0);
+}
+
+// Storage that is slow to read, so concurrent reads overlap, and counts reads of one position.
+struct SlowIo {
+    inner: Vec<u8>,
+    pos: u64,
+    hits: AtomicUsize,
+}
+
+impl ReadAt for SlowIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if pos == self.pos {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+        }
+        thread::sleep(Duration::from_millis(10));
+        self.inner.read_at(pos, buf)
+    }
+}
+
+#[test]
+fn concurrent_misses() {
+    // The builder puts the L2 table in the fifth cluster.
+    let io = SlowIo { inner: image(), pos: 4 * 65536, hits: AtomicUsize::new(0) };
+    let qcow = Qcow2::open(&io).unwrap();
+    let barrier = Barrier::new(8);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                let reader = qcow.reader().unwrap();
+                barrier.wait();
+                let mut buf = [0; 4];
+                reader.read_exact_at(0, &mut buf).unwrap();
+                assert_eq!(&buf, b"zero");
+            });
+        }
+    });
+    assert_eq!(io.hits.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn concurrent_eviction() {
+    // Many small L2 tables, in a cache that only fits two of them.
+    let cs = 512;
+    let img = (0..512)
+        .fold(ImageBuilder::new().cluster_bits(9).size(512 * cs),
+              |b, i| b.data(i * cs, &[i as u8; 4]))
+        .build();
+    let cache = Arc::new(LruMetadataCache::new(2 * 512));
+    let qcow = OpenOptions::new().cache(cache.clone()).open(&img).unwrap();
+    thread::scope(|s| {
+        for t in 0..8u64 {
+            let qcow = &qcow;
+            s.spawn(move || {
+                let reader = qcow.reader().unwrap();
+                let mut buf = [0; 4];
+                for i in 0..2000u64 {
+                    let cluster = (i * 37 + t * 101) % 512;
+                    reader.read_exact_at(cluster * cs, &mut buf).unwrap();
+                    assert_eq!(buf, [cluster as u8; 4]);
+                }
+            });
+        }
+    });
+    let stats = cache.stats();
+    assert!(stats.entries <= 2 && stats.used <= stats.limit);
+}
+
+#[test]
+fn concurrent_hits() {
+    // Tables spread over every shard, in a cache too small for all of them, read from many
+    // threads while another keeps dropping them.
+    let (cs, tables) = (512, 64u64);
+    let table_span = cs / 8 * cs;
+    let img = (0..tables)
+        .fold(ImageBuilder::new().cluster_bits(9).size(tables * table_span),
+              |b, i| b.data(i * table_span, &[i as u8; 4]))
+        .build();
+    let id = ImageId::user(1);
+    let cache = Arc::new(LruMetadataCache::new(48 * 512));
+    let qcow = OpenOptions::new().cache(cache.clone()).image_id(id).open(&img).unwrap();
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                cache.invalidate_image(id);
+                thread::yield_now();
+            }
+        });
+        let readers: Vec<_> = (0..8u64)
+            .map(|t| {
+                let qcow = &qcow;
+                s.spawn(move || {
+                    let reader = qcow.reader().unwrap();
+                    let mut buf = [0; 4];
+                    for i in 0..20000u64 {
+                        let table = (i * 7 + t * 13) % tables;
+                        reader.read_exact_at(table * table_span, &mut buf).unwrap();
+                        assert_eq!(buf, [table as u8; 4]);
+                    }
+                })
+            })
+            .collect();
+        let results: Vec<_> = readers.into_iter().map(|r| r.join()).collect();
+        stop.store(true, Ordering::Relaxed);
+        assert!(results.iter().all(Result::is_ok));
+    });
+    let stats = cache.stats();
+    assert!(stats.used <= stats.limit);
+    assert_eq!(stats.used, stats.entries * 512);
+}
+
+// Storage that holds up the first read of one position until told to go on.
+struct GatedIo {
+    inner: Vec<u8>,
+    pos: u64,
+    gated: AtomicBool,
+    reading: Barrier,
+    resume: Barrier,
+}
+
+impl ReadAt for GatedIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if pos == self.pos && self.gated.swap(false, Ordering::SeqCst) {
+            self.reading.wait();
+            self.resume.wait();
+        }
+        self.inner.read_at(pos, buf)
+    }
+}
+
+#[test]
+fn invalidate_while_loading() {
+    let io = GatedIo {
+        inner: image(),
+        pos: 4 * 65536,
+        gated: AtomicBool::new(true),
+        reading: Barrier::new(2),
+        resume: Barrier::new(2),
+    };
+    let cache = Arc::new(LruMetadataCache::default());
+    let mut qcow = OpenOptions::new().cache(cache.clone()).open(&io).unwrap();
+    let other = qcow.clone();
+    thread::scope(|s| {
+        let reading = s.spawn(move || {
+            let mut buf = [0; 4];
+            other.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+            buf
+        });
+        io.reading.wait();
+        qcow.invalidate_caches().unwrap();
+        io.resume.wait();
+        assert_eq!(&reading.join().unwrap(), b"zero");
+    });
+
+    // The table read before invalidating isn't cached, but reading it again caches it.
+    assert_eq!(cache.stats().entries, 0);
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(cache.stats().entries, 1);
+}
+
+// Storage that several images can have open at once, like a file opened by two processes.
+#[derive(Clone)]
+struct SharedIo(Rc<RefCell<Vec<u8>>>);
+
+impl ReadAt for SharedIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow().read_at(pos, buf)
+    }
+}
+
+impl WriteAt for SharedIo {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write_at(pos, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Size for SharedIo {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.0.borrow().size()
+    }
+}
+
+impl Storage for SharedIo {}
+
+#[test]
+fn refresh() {
+    let io = SharedIo(Rc::new(RefCell::new(image())));
+    let mut qcow = Qcow2::open(io.clone()).unwrap();
+    {
+        let mut reader = qcow.reader().unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact_at(2 * 65536, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+
+        let mut other = Qcow2::open(io.clone()).unwrap();
+        other.writer().unwrap().write_all_at(2 * 65536, b"two!").unwrap();
+        // Growing this much needs a bigger L1 table, which moves.
+        other.resize(8 << 40).unwrap();
+
+        // The stale L2 table is still cached.
+        reader.read_exact_at(2 * 65536, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(reader.size().unwrap(), Some(1 << 20));
+
+        reader.refresh().unwrap();
+        reader.read_exact_at(2 * 65536, &mut buf).unwrap();
+        assert_eq!(&buf, b"two!");
+        assert_eq!(reader.size().unwrap(), Some(8 << 40));
+        reader.read_exact_at((8 << 40) - 4, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+    }
+
+    assert_eq!(qcow.guest_size(), 1 << 20);
+    qcow.invalidate_caches().unwrap();
+    assert_eq!(qcow.guest_size(), 8 << 40);
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(2 * 65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"two!");
+}
+
+#[test]
+fn refresh_clones() {
+    let io = SharedIo(Rc::new(RefCell::new(image())));
+    let mut qcow = Qcow2::open(io.clone()).unwrap();
+    let clone = qcow.clone();
+    let mut other = Qcow2::open(io.clone()).unwrap();
+    other.writer().unwrap().write_all_at(2 * 65536, b"two!").unwrap();
+    other.resize(2 << 20).unwrap();
+    drop(other);
+
+    // Refreshing one handle refreshes its clones.
+    assert_eq!(clone.guest_size(), 1 << 20);
+    qcow.invalidate_caches().unwrap();
+    assert_eq!(clone.guest_size(), 2 << 20);
+    assert_eq!(qcow.clone().guest_size(), 2 << 20);
+    let mut buf = [0; 4];
+    clone.reader().unwrap().read_exact_at(2 * 65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"two!");
+
+    // A clone that never catches up keeps every version, which can still be dropped.
+    for _ in 0..50000 {
+        qcow.invalidate_caches().unwrap();
+    }
+    drop(qcow);
+    assert_eq!(clone.guest_size(), 2 << 20);
+    drop(clone);
+}
+
+#[test]
+fn clone_shares_cache() {
+    let io = CountingIo::new(image());
+    let qcow = Qcow2::open(&io).unwrap();
+    let other = qcow.clone();
+    assert_eq!(other.image_id(), qcow.image_id());
+    read4(&qcow, 0);
+    // The clone finds the L2 table cached, and only reads the L1 table and the data.
+    let before = io.reads();
+    assert_eq!(&read4(&other, 0), b"zero");
+    assert_eq!(io.reads(), before + 2);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let handle = qcow.clone();
+            s.spawn(move || assert_eq!(&read4(&handle, 65536)[..3], b"one"));
+        }
+    });
+}
+
+#[test]
+fn clone_blocks_writes() {
+    let io = SharedIo(Rc::new(RefCell::new(image())));
+    let mut qcow = Qcow2::open(io).unwrap();
+    let other = qcow.clone();
+    match qcow.writer() {
+        Err(Error::UnsupportedFeature(_)) => {}
+        r => panic!("unexpected result {:?}", r.err()),
+    }
+    assert!(qcow.resize(2 << 20).is_err());
+
+    drop(other);
+    qcow.writer().unwrap().write_all_at(0, b"ZERO").unwrap();
+    let other = qcow.clone();
+    let mut buf = [0; 4];
+    other.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"ZERO");
+}
+
+#[test]
+fn local_cache() {
+    let io = CountingIo::new(image());
+    let qcow: Qcow2Local<_> = OpenOptions::new().open_local(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"zero");
+    let before = io.reads();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(io.reads(), before + 1);
+
+    // A clone has a cache of its own, so it reads the L1 table, the L2 table and the data.
+    let other = qcow.clone();
+    let before = io.reads();
+    other.reader().unwrap().read_exact_at(65536, &mut buf[..3]).unwrap();
+    assert_eq!(&buf[..3], b"one");
+    assert_eq!(io.reads(), before + 3);
+}
+
+#[test]
+fn local_cache_size() {
+    // The L2 table doesn't fit, so it's read every time.
+    let io = CountingIo::new(image());
+    let qcow = OpenOptions::new().cache_size(1000).open_local(&io).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 4];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    let before = io.reads();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(io.reads(), before + 2);
+
+    // The size applies to shared caches too.
+    let qcow = OpenOptions::new().cache_size(1000).open(&io).unwrap();
+    read4(&qcow, 0);
+    let before = io.reads();
+    read4(&qcow, 0);
+    assert_eq!(io.reads(), before + 3);
+}
+
+#[test]
+fn local_writes() {
+    let mut qcow = OpenOptions::new().open_local(image()).unwrap();
+    {
+        let mut writer = qcow.writer().unwrap();
+        writer.write_all_at(0, b"ZERO").unwrap();
+        writer.write_all_at(3 << 16, b"three").unwrap();
+    }
+    // What's cached matches what was written.
+    let mut buf = [0; 5];
+    let reader = qcow.reader().unwrap();
+    reader.read_exact_at(0, &mut buf[..4]).unwrap();
+    assert_eq!(&buf[..4], b"ZERO");
+    reader.read_exact_at(3 << 16, &mut buf).unwrap();
+    assert_eq!(&buf, b"three");
+    assert!(qcow.check().unwrap().is_clean());
+
+    let mut copy = Vec::new();
+    Qcow2::create_from(&reader, &mut copy, &qcow2::CreateOptions::new()).unwrap();
+    let copy = Qcow2::open(copy).unwrap();
+    copy.reader().unwrap().read_exact_at(3 << 16, &mut buf).unwrap();
+    assert_eq!(&buf, b"three");
+}
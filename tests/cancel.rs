@@ -0,0 +1,198 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::cell::Cell;
+use std::io::{self, Cursor};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, Size};
+use qcow2::{CancelToken, CopyOptions, CreateOptions, Error, MeasureOptions, Qcow2,
+            copy_range_reporting};
+
+const CS: u64 = 65536;
+const MB: u64 = 1 << 20;
+
+// Storage that cancels a token once it has been read from some number of times.
+struct CancelAfter {
+    img: Vec<u8>,
+    token: CancelToken,
+    reads: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl CancelAfter {
+    fn new(img: Vec<u8>, token: &CancelToken) -> Self {
+        CancelAfter {
+            img,
+            token: token.clone(),
+            reads: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    // Cancel after this many more reads.
+    fn cancel_after(&self, reads: usize) {
+        self.limit.store(self.reads() + reads, Ordering::SeqCst);
+    }
+}
+
+impl ReadAt for CancelAfter {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let reads = self.reads.fetch_add(1, Ordering::SeqCst) + 1;
+        if reads >= self.limit.load(Ordering::SeqCst) {
+            self.token.cancel();
+        }
+        self.img.read_at(pos, buf)
+    }
+}
+
+impl Size for CancelAfter {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.img.size()
+    }
+}
+
+#[test]
+fn cancel_token() {
+    let token = CancelToken::new();
+    let other = token.clone();
+    assert!(!token.is_cancelled());
+    other.cancel();
+    assert!(token.is_cancelled());
+    assert!(!CancelToken::new().is_cancelled());
+}
+
+#[test]
+fn check_cancelled() {
+    // Data in three L2 tables.
+    let img = ImageBuilder::new()
+        .size(2 << 30)
+        .data(0, &[1; 16])
+        .data(1 << 29, &[2; 16])
+        .data(1 << 30, &[3; 16])
+        .build();
+    let token = CancelToken::new();
+    let io = CancelAfter::new(img, &token);
+    let qcow = Qcow2::open(&io).unwrap();
+    assert!(qcow.check_reporting(&token).unwrap().is_clean());
+
+    // Cancelled once the L1 table is read, so no L2 table is.
+    io.cancel_after(1);
+    let before = io.reads();
+    assert!(matches!(qcow.check_reporting(&token), Err(Error::Cancelled)));
+    assert_eq!(io.reads(), before + 1);
+}
+
+#[test]
+fn dedup_cancelled() {
+    // Progress is reported for each megabyte.
+    let img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(MB, &[1; 16])
+        .data(2 * MB, &[1; 16])
+        .build();
+    let token = CancelToken::new();
+    let io = CancelAfter::new(img, &token);
+    let qcow = Qcow2::open(&io).unwrap();
+    // The L1 table, the L2 table, and then the first data cluster.
+    io.cancel_after(3);
+    let before = io.reads();
+    let result = qcow.dedup_report_reporting(CS, &Default::default(), &token);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(io.reads(), before + 3);
+}
+
+#[test]
+fn progress_cancelled() {
+    let qcow = Qcow2::open(ImageBuilder::new().data(0, &[1; 16]).build()).unwrap();
+    let reader = qcow.reader().unwrap();
+    let token = CancelToken::new();
+    let mut out = Cursor::new(Vec::new());
+    reader.export_raw_reporting(&mut out, true, &token).unwrap();
+    assert_eq!(out.get_ref().len(), 1 << 20);
+
+    token.cancel();
+    let mut out = Cursor::new(Vec::new());
+    let result = reader.export_raw_reporting(&mut out, true, &token);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    let result = Qcow2::create_from_reporting(&reader, Vec::new(), &CreateOptions::new(), &token);
+    assert!(matches!(result, Err(Error::Cancelled)));
+}
+
+#[test]
+fn scans_cancelled() {
+    let qcow = Qcow2::open(ImageBuilder::new().data(0, &[1; 16]).build()).unwrap();
+    let reader = qcow.reader().unwrap();
+    let token = CancelToken::new();
+    assert_eq!(reader.map_reporting(&token).unwrap(), reader.map().unwrap());
+    assert_eq!(qcow.allocation_stats_reporting(&token).unwrap(),
+               qcow.allocation_stats().unwrap());
+    let opts = MeasureOptions::new();
+    assert_eq!(qcow.measure_reporting(&opts, &token).unwrap(), qcow.measure(&opts).unwrap());
+
+    token.cancel();
+    assert!(matches!(reader.map_reporting(&token), Err(Error::Cancelled)));
+    assert!(matches!(qcow.allocation_stats_reporting(&token), Err(Error::Cancelled)));
+    assert!(matches!(qcow.measure_reporting(&opts, &token), Err(Error::Cancelled)));
+}
+
+#[test]
+fn export_parallel_cancelled() {
+    let img = ImageBuilder::new().size(4 * MB).data(0, &[1; 16]).data(3 * MB, &[2; 16]).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let cancel = |done, _, _| {
+        if done >= 2 * MB { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    };
+
+    // Written in order, the output holds what was reported.
+    let mut out = Cursor::new(Vec::new());
+    let result = reader.export_raw_parallel(&mut out, false, 3, &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(out.get_ref().len() as u64, 2 * MB);
+    assert_eq!(&out.get_ref()[..16], &[1; 16]);
+
+    let result = reader.export_raw_at(&mut Vec::new(), false, 3, &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+}
+
+#[test]
+fn copy_cancelled() {
+    let mut builder = ImageBuilder::new().size(4 * MB);
+    for i in 0..4 {
+        builder = builder.data(i * MB, &[i as u8 + 1; 16]);
+    }
+    let src = Qcow2::open(builder.build()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut dst = Qcow2::open(ImageBuilder::new().size(4 * MB).build()).unwrap();
+    let last = Cell::new(0);
+    let cancel = |done, _, _| {
+        last.set(done);
+        if done >= 2 * MB { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    };
+    let result = copy_range_reporting(&reader,
+                                      0,
+                                      &mut dst.writer().unwrap(),
+                                      0,
+                                      4 * MB,
+                                      &CopyOptions::new(),
+                                      &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(last.get(), 2 * MB);
+
+    // Everything up to the report was committed, and nothing after it.
+    let dst = dst.reader().unwrap();
+    let mut buf = [0; 16];
+    for i in 0..4 {
+        dst.read_exact_at(i * MB, &mut buf).unwrap();
+        assert_eq!(buf, if i < 2 { [i as u8 + 1; 16] } else { [0; 16] });
+    }
+}
@@ -0,0 +1,70 @@
+#![cfg(feature = "capi")]
+
+extern crate qcow2;
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+use std::thread;
+
+use qcow2::capi::*;
+
+struct Ptr(*mut Handle);
+unsafe impl Send for Ptr {}
+unsafe impl Sync for Ptr {}
+
+#[test]
+fn capi_read() {
+    let path = CString::new("tests/test.qcow2").unwrap();
+    let mut handle = ptr::null_mut();
+    unsafe {
+        assert_eq!(qcow2_open(path.as_ptr(), &mut handle), 0);
+        assert_eq!(qcow2_virtual_size(handle), 1000 * 1024 * 1024);
+
+        let mut buf = [0u8; 11];
+        let n = qcow2_pread(handle, buf.as_mut_ptr() as *mut _, buf.len(), 200 * 1024 * 1024);
+        assert_eq!(n, 11);
+        assert_eq!(&buf, b"Lorem ipsum");
+
+        // Short read at the end.
+        let end = 1000 * 1024 * 1024;
+        let n = qcow2_pread(handle, buf.as_mut_ptr() as *mut _, buf.len(), end - 4);
+        assert_eq!(n, 4);
+
+        // Concurrent reads through one handle.
+        let shared = Ptr(handle);
+        let shared = &shared;
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(move || {
+                    let mut buf = [0u8; 11];
+                    let n = qcow2_pread(shared.0, buf.as_mut_ptr() as *mut _, 11, 200 << 20);
+                    assert_eq!(n, 11);
+                    assert_eq!(&buf, b"Lorem ipsum");
+                });
+            }
+        });
+
+        qcow2_close(handle);
+    }
+}
+
+#[test]
+fn capi_errors() {
+    let path = CString::new("tests/no-such-file.qcow2").unwrap();
+    let mut handle = ptr::null_mut();
+    unsafe {
+        assert_eq!(qcow2_open(path.as_ptr(), &mut handle), -1);
+        assert!(handle.is_null());
+        let err = qcow2_last_error();
+        assert!(!err.is_null());
+        assert!(!CStr::from_ptr(err).to_str().unwrap().is_empty());
+
+        let lib = CString::new("Cargo.toml").unwrap();
+        assert_eq!(qcow2_open(lib.as_ptr(), &mut handle), -1);
+        assert_eq!(CStr::from_ptr(qcow2_last_error()).to_str().unwrap(),
+                   "Not a qcow2 file");
+
+        assert_eq!(qcow2_pread(ptr::null(), ptr::null_mut(), 1, 0), -1);
+        qcow2_close(ptr::null_mut());
+    }
+}
@@ -0,0 +1,75 @@
+extern crate qcow2;
+
+mod common;
+
+use std::fs;
+
+use common::{ImageBuilder, TempDir};
+use qcow2::{ChainLayer, backing_chain};
+
+fn summary(layer: &ChainLayer) -> (String, Option<&str>, Option<u64>) {
+    let name = layer.path.file_name().unwrap().to_string_lossy().into_owned();
+    (name, layer.format.as_deref(), layer.virtual_size)
+}
+
+#[test]
+fn chain() {
+    let dir = TempDir::new("chain");
+    let mid = ImageBuilder::new()
+        .backing_file("base.raw")
+        .extension(0xe2792aca, b"raw".to_vec())
+        .data(0, b"mid")
+        .build();
+    // The middle image is in a subdirectory, so its backing file is found relative to it.
+    fs::create_dir(dir.0.join("sub")).unwrap();
+    let mid_path = dir.write("sub/mid.qcow2", &mid);
+    dir.write("sub/base.raw", &[1; 4096]);
+    let top = dir.write("top.qcow2",
+                        &ImageBuilder::new().backing_file("sub/mid.qcow2").build());
+
+    let chain = backing_chain(&top);
+    let layers: Vec<_> = chain.iter().map(summary).collect();
+    assert_eq!(layers,
+               [("top.qcow2".to_owned(), Some("qcow2"), Some(1 << 20)),
+                ("mid.qcow2".to_owned(), Some("qcow2"), Some(1 << 20)),
+                ("base.raw".to_owned(), Some("raw"), Some(4096))]);
+    assert_eq!(chain[1].path, mid_path);
+    assert_eq!(chain[1].allocated_size, Some(65536));
+    assert_eq!(chain[2].allocated_size, Some(4096));
+    assert!(chain.iter().all(|l| l.error.is_none()));
+    assert!(chain[..2].iter().all(|l| l.problems.is_empty()));
+    assert_eq!(chain[2].problems,
+               ["virtual size 4096 is smaller than the 1048576 of the image above"]);
+}
+
+#[test]
+fn chain_missing() {
+    let dir = TempDir::new("missing");
+    let top = dir.write("top.qcow2", &ImageBuilder::new().backing_file("gone.qcow2").build());
+    let chain = backing_chain(&top);
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[1].path, dir.0.join("gone.qcow2"));
+    assert_eq!(chain[1].format, None);
+    assert!(chain[1].error.as_ref().unwrap().contains("No such file"));
+
+    // An image that exists but can't be opened still has a size.
+    let mut img = ImageBuilder::new().build();
+    img[32..36].copy_from_slice(&1u32.to_be_bytes());
+    let broken = dir.write("broken.qcow2", &img);
+    let chain = backing_chain(broken);
+    assert_eq!(chain.len(), 1);
+    assert_eq!(summary(&chain[0]).2, Some(1 << 20));
+    assert_eq!(chain[0].error.as_deref(), Some("Unsupported feature: encryption"));
+}
+
+#[test]
+fn chain_cycle() {
+    let dir = TempDir::new("cycle");
+    dir.write("a.qcow2", &ImageBuilder::new().backing_file("b.qcow2").build());
+    let b = dir.write("b.qcow2", &ImageBuilder::new().backing_file("./a.qcow2").build());
+    let chain = backing_chain(b);
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[1].problems,
+               [format!("backing file {} is already in the chain",
+                        dir.0.join("b.qcow2").display())]);
+}
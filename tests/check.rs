@@ -0,0 +1,222 @@
+extern crate qcow2;
+
+mod common;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::ops::ControlFlow;
+
+use common::{ImageBuilder, SnapshotSpec};
+use qcow2::{CheckFinding, Error, OpenOptions, Phase, Progress, Qcow2, Repair};
+
+fn check(img: Vec<u8>) -> qcow2::CheckResult {
+    Qcow2::open(img).unwrap().check().unwrap()
+}
+
+#[test]
+fn bundled_clean() {
+    let qcow = Qcow2::open(File::open("tests/test.qcow2").unwrap()).unwrap();
+    let result = qcow.check().unwrap();
+    assert!(result.is_clean(), "{:?}", result.findings);
+    assert_eq!(result.image_end_offset, 6 * 65536);
+}
+
+#[test]
+fn synthetic_clean() {
+    let builder = ImageBuilder::new()
+        .size(3 << 20)
+        .data(0, b"first")
+        .data(2 << 20, b"last")
+        .snapshot(SnapshotSpec::new("1", "snap"));
+    let img = builder.build();
+    let len = img.len() as u64;
+    let result = check(img);
+    assert!(result.is_clean(), "{:?}", result.findings);
+    assert_eq!(result.image_end_offset, len);
+}
+
+#[test]
+fn leak_and_corruption() {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let mut img = builder.build();
+    // Cluster 4 holds the L2 table, cluster 5 the data.
+    builder.set_refcount(&mut img, 5, 0);
+    img.resize(img.len() + 65536, 0);
+    builder.set_refcount(&mut img, 6, 1);
+
+    let result = check(img);
+    assert_eq!(result.corruptions, 1);
+    assert_eq!(result.leaks, 1);
+    assert_eq!(result.image_end_offset, 7 * 65536);
+    assert_eq!(result.findings,
+               vec![CheckFinding::Refcount { cluster: 5, refcount: 0, references: 1 },
+                    CheckFinding::Refcount { cluster: 6, refcount: 1, references: 0 }]);
+    assert_eq!(result.findings[1].to_string(), "Leaked cluster 6 refcount=1 reference=0");
+}
+
+#[test]
+fn entry_past_eof() {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let mut img = builder.build();
+    let l2 = 4 * 65536;
+    img[l2..l2 + 8].copy_from_slice(&((1u64 << 40) | (1 << 63)).to_be_bytes());
+
+    let result = check(img);
+    assert_eq!(result.corruptions, 1);
+    assert_eq!(result.leaks, 1);
+    match result.findings[0] {
+        CheckFinding::Invalid { offset, ref message } => {
+            assert_eq!(offset, 1 << 40);
+            assert_eq!(message, "data cluster is past the end of the file");
+        }
+        ref f => panic!("unexpected finding {:?}", f),
+    }
+}
+
+#[test]
+fn strict_padding() {
+    let builder = ImageBuilder { header_length: 112, ..ImageBuilder::new() };
+    let mut img = builder.extension(0x1234_5678, vec![1; 3]).build();
+    img[104] = 0; // zlib compression
+    // Dirty the header padding, the extension padding, and the end of the extensions.
+    img[106] = 1;
+    img[112 + 8 + 4] = 1;
+    img[112 + 16 + 7] = 1;
+
+    assert!(check(img.clone()).is_clean());
+    let qcow = OpenOptions::new().strict(true).open(img).unwrap();
+    let result = qcow.check().unwrap();
+    let offsets: Vec<_> = result.findings
+        .iter()
+        .map(|f| match *f {
+            CheckFinding::Invalid { offset, .. } => offset,
+            ref f => panic!("unexpected finding {}", f),
+        })
+        .collect();
+    assert_eq!(offsets, vec![105, 112 + 11, 112 + 16 + 4]);
+    assert_eq!(result.corruptions, 3);
+}
+
+#[test]
+fn repair() {
+    let builder = ImageBuilder { incompatible: 1, ..ImageBuilder::new().data(0, b"data") };
+    let mut img = builder.build();
+    builder.set_refcount(&mut img, 5, 0);
+    img.resize(img.len() + 65536, 0);
+    builder.set_refcount(&mut img, 6, 2);
+
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    let result = qcow.check().unwrap();
+    let plan = qcow.repair_plan(&result).unwrap();
+    let refblock = 2 * 65536;
+    assert_eq!(plan,
+               vec![Repair::Refcount { cluster: 5, block: refblock, old: 0, new: 1 },
+                    Repair::Refcount { cluster: 6, block: refblock, old: 2, new: 0 },
+                    Repair::ClearDirty]);
+    assert_eq!(plan[1].to_string(),
+               "set the refcount of cluster 6 from 2 to 0, in the refcount block at 0x20000");
+
+    // A refcount that changed since the check isn't touched.
+    let stale = Repair::Refcount { cluster: 6, block: refblock, old: 1, new: 0 };
+    assert!(qcow.repair(&stale).is_err());
+
+    for r in &plan {
+        qcow.repair(r).unwrap();
+    }
+    assert!(qcow.check().unwrap().is_clean());
+    assert!(!qcow.info().unwrap().dirty);
+    drop(qcow);
+    assert!(!Qcow2::open(img).unwrap().info().unwrap().dirty);
+}
+
+#[test]
+fn repair_keeps_dirty() {
+    // An invalid entry can't be repaired, so the image stays dirty.
+    let builder = ImageBuilder { incompatible: 1, ..ImageBuilder::new().data(0, b"data") };
+    let mut img = builder.build();
+    let l2 = 4 * 65536;
+    img[l2..l2 + 8].copy_from_slice(&((1u64 << 40) | (1 << 63)).to_be_bytes());
+
+    let qcow = Qcow2::open(img).unwrap();
+    let plan = qcow.repair_plan(&qcow.check().unwrap()).unwrap();
+    assert_eq!(plan, vec![Repair::Refcount { cluster: 5, block: 2 * 65536, old: 1, new: 0 }]);
+}
+
+// A phase, its step and number of steps, and each (done, total) update in it.
+type Recorded = (Phase, usize, usize, Vec<(u64, u64)>);
+
+// Records each phase, with the updates for it.
+#[derive(Default)]
+struct Recorder(RefCell<Vec<Recorded>>);
+
+impl Progress for Recorder {
+    fn update(&self, done: u64, total: u64, physical: u64) -> ControlFlow<()> {
+        assert_eq!(physical, 0);
+        self.0.borrow_mut().last_mut().unwrap().3.push((done, total));
+        ControlFlow::Continue(())
+    }
+
+    fn phase(&self, phase: Phase, step: usize, steps: usize) {
+        self.0.borrow_mut().push((phase, step, steps, Vec::new()));
+    }
+}
+
+// An image with a leak, a refcount that's too low, and the dirty bit set.
+fn repairable() -> Vec<u8> {
+    let builder = ImageBuilder { incompatible: 1, ..ImageBuilder::new().data(0, b"data") };
+    let mut img = builder.build();
+    builder.set_refcount(&mut img, 5, 0);
+    img.resize(img.len() + 65536, 0);
+    builder.set_refcount(&mut img, 6, 2);
+    img
+}
+
+#[test]
+fn check_reporting() {
+    let img = ImageBuilder::new().size(1 << 30).snapshot(SnapshotSpec::new("1", "a")).build();
+    let qcow = Qcow2::open(&img[..]).unwrap();
+    let recorder = Recorder::default();
+    assert!(qcow.check_reporting(&recorder).unwrap().is_clean());
+
+    let phases = recorder.0.into_inner();
+    assert_eq!(phases.len(), 2);
+    // Two L1 entries, in the image and in the snapshot.
+    let (phase, step, steps, ref updates) = phases[0];
+    assert_eq!((phase, step, steps), (Phase::ScanningTables, 0, 2));
+    assert_eq!(*updates, [(0, 4), (1, 4), (2, 4), (3, 4), (4, 4)]);
+    let (phase, step, steps, ref updates) = phases[1];
+    assert_eq!((phase, step, steps), (Phase::CheckingRefcounts, 1, 2));
+    let clusters = img.len() as u64 / 65536;
+    assert_eq!(updates.first(), Some(&(0, clusters)));
+    assert_eq!(updates.last(), Some(&(clusters, clusters)));
+    assert_eq!(Phase::CheckingRefcounts.to_string(), "checking refcounts");
+}
+
+#[test]
+fn repair_all() {
+    let mut img = repairable();
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    let plan = qcow.repair_plan(&qcow.check().unwrap()).unwrap();
+    assert_eq!(plan.len(), 3);
+    let recorder = Recorder::default();
+    qcow.repair_all(&plan, &recorder).unwrap();
+    assert_eq!(recorder.0.into_inner(),
+               vec![(Phase::Writing, 0, 1, vec![(0, 3), (1, 3), (2, 3), (3, 3)])]);
+    assert!(qcow.check().unwrap().is_clean());
+    assert!(!qcow.info().unwrap().dirty);
+}
+
+#[test]
+fn repair_all_cancelled() {
+    let mut img = repairable();
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    let plan = qcow.repair_plan(&qcow.check().unwrap()).unwrap();
+    // Stop after the first repair.
+    let progress = |done, _, _| {
+        if done < 1 { ControlFlow::Continue(()) } else { ControlFlow::Break(()) }
+    };
+    assert!(matches!(qcow.repair_all(&plan, &progress), Err(Error::Cancelled)));
+    let result = qcow.check().unwrap();
+    assert_eq!(result.findings.len(), 1);
+    assert!(qcow.info().unwrap().dirty);
+}
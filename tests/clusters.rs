@@ -0,0 +1,97 @@
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{Backing, Cluster, ClusterRef, Error, Qcow2};
+
+const CS: u64 = 65536;
+
+// Data in cluster 0 and the short last cluster, zeros in cluster 1, and a hole in cluster 2.
+fn image() -> Vec<u8> {
+    build(ImageBuilder::new())
+}
+
+fn build(builder: ImageBuilder) -> Vec<u8> {
+    builder.size(3 * CS + 100).data(0, &[1; 16]).zero_cluster(CS).data(3 * CS, &[2; 8]).build()
+}
+
+fn data(fill: &[u8], len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    buf[..fill.len()].copy_from_slice(fill);
+    buf
+}
+
+#[test]
+fn clusters() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let reader = qcow.reader().unwrap();
+    let clusters: Vec<Cluster> = reader.clusters().collect::<Result<_, _>>().unwrap();
+    assert_eq!(clusters,
+               vec![Cluster::Data(data(&[1; 16], CS as usize)),
+                    Cluster::Zero(CS),
+                    Cluster::Hole(CS),
+                    Cluster::Data(data(&[2; 8], 100))]);
+    assert_eq!(clusters.iter().map(Cluster::size).sum::<u64>(), qcow.guest_size());
+}
+
+#[test]
+fn for_each_cluster() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut seen = Vec::new();
+    reader.for_each_cluster(|pos, c| {
+            seen.push((pos, c.to_cluster()));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(seen.iter().map(|s| s.0).collect::<Vec<_>>(), vec![0, CS, 2 * CS, 3 * CS]);
+    assert_eq!(seen[3].1, Cluster::Data(data(&[2; 8], 100)));
+
+    // An error from the function stops iteration.
+    let mut count = 0;
+    let result = reader.for_each_cluster(|_, c| {
+        count += 1;
+        match c {
+            ClusterRef::Zero(_) => Err(Error::Internal("stop".to_owned())),
+            _ => Ok(()),
+        }
+    });
+    assert!(matches!(result, Err(Error::Internal(_))));
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn clusters_backing() {
+    let mut qcow = Qcow2::open(build(ImageBuilder::new().backing_file("base.raw"))).unwrap();
+    qcow.set_backing(Backing::raw(vec![7; 4 * CS as usize]).unwrap());
+    let reader = qcow.reader().unwrap();
+    let clusters: Vec<Cluster> = reader.clusters().collect::<Result<_, _>>().unwrap();
+    assert_eq!(clusters[1], Cluster::Zero(CS));
+    assert_eq!(clusters[2], Cluster::Backing(vec![7; CS as usize]));
+}
+
+#[test]
+fn clusters_compressed() {
+    let img = build(ImageBuilder::new().compressed(2 * CS, &[3; 16]));
+    let qcow = Qcow2::open(img).unwrap();
+    let clusters: Vec<_> = qcow.reader().unwrap().clusters().collect::<qcow2::Result<_>>().unwrap();
+    assert_eq!(clusters[2], Cluster::Compressed(data(&[3; 16], CS as usize)));
+    assert_eq!(clusters[2].size(), CS);
+}
+
+#[test]
+fn clusters_compressed_corrupt() {
+    let img = build(ImageBuilder::new().compressed_entry(2 * CS, &[3; 16]));
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let results: Vec<_> = reader.clusters().collect();
+    assert_eq!(results.len(), 3);
+    assert!(matches!(results[2], Err(Error::FileFormat(_))));
+}
+
+#[test]
+fn clusters_empty() {
+    let qcow = Qcow2::open(ImageBuilder::new().size(0).build()).unwrap();
+    assert_eq!(qcow.reader().unwrap().clusters().count(), 0);
+}
@@ -0,0 +1,615 @@
+// Helpers for building small qcow2 images in memory, so tests don't each need a fixture file.
+#![allow(dead_code)]
+
+extern crate positioned_io;
+extern crate qcow2;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use self::positioned_io::{ReadAt, Size, WriteAt};
+use self::qcow2::{Qcow2, Storage};
+
+pub const EXT_FEATURE_NAME_TABLE: u32 = 0x6803f857;
+pub const EXT_BITMAPS: u32 = 0x23852875;
+
+const L2_COPIED: u64 = 1 << 63;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1;
+
+// The file type tests write images through. `File` isn't `Storage` on Windows.
+#[cfg(not(windows))]
+pub type WriteFile = File;
+#[cfg(windows)]
+pub type WriteFile = qcow2::RandomAccessFile;
+
+// Open a file to read and write.
+#[cfg(not(windows))]
+pub fn open_rw<P: AsRef<Path>>(path: P) -> WriteFile {
+    fs::OpenOptions::new().read(true).write(true).open(path).unwrap()
+}
+
+#[cfg(windows)]
+pub fn open_rw<P: AsRef<Path>>(path: P) -> WriteFile {
+    qcow2::RandomAccessFile::open_rw(path).unwrap()
+}
+
+fn div_ceil(a: u64, b: u64) -> u64 {
+    a.div_ceil(b)
+}
+
+fn put_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+fn put_u64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_be_bytes());
+}
+
+// A backend that counts how often it's read from.
+pub struct CountingIo<I> {
+    pub inner: I,
+    pub reads: AtomicUsize,
+    // The biggest buffer any read asked to fill.
+    pub largest: AtomicUsize,
+}
+
+impl<I> CountingIo<I> {
+    pub fn new(inner: I) -> Self {
+        CountingIo {
+            inner,
+            reads: AtomicUsize::new(0),
+            largest: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    pub fn largest(&self) -> usize {
+        self.largest.load(Ordering::SeqCst)
+    }
+}
+
+impl<I: ReadAt> ReadAt for CountingIo<I> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.largest.fetch_max(buf.len(), Ordering::SeqCst);
+        self.inner.read_at(pos, buf)
+    }
+}
+
+impl<I: Size> Size for CountingIo<I> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.inner.size()
+    }
+}
+
+// An operation on a RecordingIo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Write { pos: u64, len: usize },
+    Sync,
+}
+
+// A backend that records every write and sync, so tests can check their order.
+#[derive(Default)]
+pub struct RecordingIo {
+    pub inner: Vec<u8>,
+    pub ops: Vec<Op>,
+}
+
+impl RecordingIo {
+    pub fn new(inner: Vec<u8>) -> Self {
+        RecordingIo { inner, ops: Vec::new() }
+    }
+}
+
+impl ReadAt for RecordingIo {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read_at(pos, buf)
+    }
+}
+
+impl WriteAt for RecordingIo {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        self.ops.push(Op::Write { pos, len: buf.len() });
+        self.inner.write_at(pos, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Size for RecordingIo {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.inner.size()
+    }
+}
+
+impl Storage for RecordingIo {
+    fn sync(&mut self) -> io::Result<()> {
+        self.ops.push(Op::Sync);
+        Ok(())
+    }
+}
+
+// A file in the temporary directory, removed when dropped.
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        let name = format!("qcow2-{}-{}", std::process::id(), name);
+        TempFile(std::env::temp_dir().join(name))
+    }
+
+    pub fn with_contents(name: &str, contents: &[u8]) -> Self {
+        let file = Self::new(name);
+        fs::write(&file.0, contents).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    pub fn open(&self) -> Qcow2<File> {
+        Qcow2::open(File::open(&self.0).unwrap()).unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// A directory in the temporary directory, removed with its contents when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("qcow2-{}-{}.d", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    // Write a file in the directory, making any subdirectories it needs.
+    pub fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Encode a feature name table entry.
+pub fn feature_name(kind: u8, bit: u8, name: &[u8]) -> Vec<u8> {
+    let mut v = vec![kind, bit];
+    let mut n = name.to_vec();
+    n.resize(46, 0);
+    v.extend_from_slice(&n);
+    v
+}
+
+// A snapshot to put in an image. It has the same contents as the active image.
+#[derive(Clone, Default)]
+pub struct SnapshotSpec {
+    pub id: String,
+    pub name: String,
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    pub vm_clock_nsec: u64,
+    pub extra: Vec<u8>,
+}
+
+impl SnapshotSpec {
+    pub fn new(id: &str, name: &str) -> Self {
+        SnapshotSpec {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            extra: vec![0; 16],
+            ..Default::default()
+        }
+    }
+}
+
+// A persistent dirty bitmap to put in an image.
+#[derive(Clone, Default)]
+pub struct BitmapSpec {
+    pub name: String,
+    pub flags: u32,
+    pub granularity_bits: u8,
+    // Indices of the bits that are set.
+    pub dirty: Vec<u64>,
+    // Indices of bitmap table entries that are all ones.
+    pub all_ones: Vec<u64>,
+}
+
+impl BitmapSpec {
+    pub fn new(name: &str, granularity_bits: u8) -> Self {
+        BitmapSpec {
+            name: name.to_owned(),
+            granularity_bits,
+            ..Default::default()
+        }
+    }
+
+    pub fn dirty(mut self, bits: &[u64]) -> Self {
+        self.dirty.extend_from_slice(bits);
+        self
+    }
+
+    pub fn all_ones(mut self, entries: &[u64]) -> Self {
+        self.all_ones.extend_from_slice(entries);
+        self
+    }
+}
+
+// A description of a qcow2 image, which can be turned into bytes.
+//
+// The layout is: header in cluster 0, refcount table in cluster 1, a single refcount block in
+// cluster 2, then the L1 table, then L2 tables and data clusters in order of allocation, then
+// snapshot L1 tables and the snapshot table, then bitmaps. Refcounts are consistent.
+pub struct ImageBuilder {
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub incompatible: u64,
+    pub compatible: u64,
+    pub autoclear: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+    pub extensions: Vec<(u32, Vec<u8>)>,
+    pub data: Vec<(u64, Vec<u8>)>,
+    pub zeros: Vec<u64>,
+    pub compressed: Vec<(u64, Vec<u8>)>,
+    pub snapshots: Vec<SnapshotSpec>,
+    pub bitmaps: Vec<BitmapSpec>,
+    pub backing_file: Option<Vec<u8>>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        ImageBuilder {
+            cluster_bits: 16,
+            size: 1 << 20,
+            incompatible: 0,
+            compatible: 0,
+            autoclear: 0,
+            refcount_order: 4,
+            header_length: 104,
+            extensions: Vec::new(),
+            data: Vec::new(),
+            zeros: Vec::new(),
+            compressed: Vec::new(),
+            snapshots: Vec::new(),
+            bitmaps: Vec::new(),
+            backing_file: None,
+        }
+    }
+}
+
+impl ImageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cluster_bits(mut self, bits: u32) -> Self {
+        self.cluster_bits = bits;
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn compatible(mut self, bits: u64) -> Self {
+        self.compatible = bits;
+        self
+    }
+
+    pub fn autoclear(mut self, bits: u64) -> Self {
+        self.autoclear = bits;
+        self
+    }
+
+    pub fn refcount_order(mut self, order: u32) -> Self {
+        self.refcount_order = order;
+        self
+    }
+
+    pub fn extension(mut self, code: u32, payload: Vec<u8>) -> Self {
+        self.extensions.push((code, payload));
+        self
+    }
+
+    // Store guest data at a cluster-aligned guest offset. Must fit in one cluster.
+    pub fn data(mut self, guest_offset: u64, bytes: &[u8]) -> Self {
+        self.data.push((guest_offset, bytes.to_vec()));
+        self
+    }
+
+    // Set the zero flag for a guest cluster. If it also has data, the cluster stays allocated.
+    pub fn zero_cluster(mut self, guest_offset: u64) -> Self {
+        self.zeros.push(guest_offset);
+        self
+    }
+
+    // Store guest data compressed at a cluster-aligned guest offset, padded with zeros to a
+    // whole cluster, the way qemu does.
+    pub fn compressed(self, guest_offset: u64, bytes: &[u8]) -> Self {
+        let mut cluster = vec![0; self.cluster_size() as usize];
+        cluster[..bytes.len()].copy_from_slice(bytes);
+        let stored = miniz_oxide::deflate::compress_to_vec(&cluster, 6);
+        self.compressed_entry(guest_offset, &stored)
+    }
+
+    // Make a guest cluster compressed, with the stored bytes in a host cluster of their own. The
+    // bytes are written as-is, so they needn't be a valid compressed stream.
+    pub fn compressed_entry(mut self, guest_offset: u64, stored: &[u8]) -> Self {
+        self.compressed.push((guest_offset, stored.to_vec()));
+        self
+    }
+
+    pub fn backing_file(self, name: &str) -> Self {
+        self.backing_file_bytes(name.as_bytes())
+    }
+
+    // A backing file name that needn't be UTF-8.
+    pub fn backing_file_bytes(mut self, name: &[u8]) -> Self {
+        self.backing_file = Some(name.to_vec());
+        self
+    }
+
+    pub fn snapshot(mut self, snap: SnapshotSpec) -> Self {
+        self.snapshots.push(snap);
+        self
+    }
+
+    // Add a bitmap, and the bitmaps extension. The autoclear bit is left alone.
+    pub fn bitmap(mut self, bitmap: BitmapSpec) -> Self {
+        self.bitmaps.push(bitmap);
+        self
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    pub fn l1_entries(&self) -> u64 {
+        div_ceil(div_ceil(self.size, self.cluster_size()), self.cluster_size() / 8)
+    }
+
+    pub fn l1_offset(&self) -> u64 {
+        3 * self.cluster_size()
+    }
+
+    // Get the number of refcounts in each refcount block.
+    pub fn refcount_block_size(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+
+    // Set the refcount of a cluster in an image built by this builder.
+    pub fn set_refcount(&self, img: &mut [u8], cluster: u64, refcount: u64) {
+        let cs = self.cluster_size();
+        let per_block = self.refcount_block_size();
+        let table_pos = (cs + cluster / per_block * 8) as usize;
+        let block = u64::from_be_bytes(img[table_pos..table_pos + 8].try_into().unwrap());
+        let bits = 1u64 << self.refcount_order;
+        let bit = cluster % per_block * bits;
+        let byte = (block + bit / 8) as usize;
+        if bits >= 8 {
+            let bytes = (bits / 8) as usize;
+            let be = refcount.to_be_bytes();
+            img[byte..byte + bytes].copy_from_slice(&be[8 - bytes..]);
+        } else {
+            let mask = ((1u64 << bits) - 1) << (bit % 8);
+            img[byte] = (img[byte] & !mask as u8) | ((refcount << (bit % 8)) & mask) as u8;
+        }
+    }
+
+    // Find the position in the image of the L2 entry for a guest offset, allocating the L2 table
+    // if there isn't one yet.
+    fn l2_entry(&self, img: &mut Vec<u8>, guest_offset: u64, copied: u64, shared: &mut Vec<u64>)
+                -> usize {
+        let cs = self.cluster_size();
+        let cluster = guest_offset / cs;
+        let (l1_idx, l2_idx) = (cluster / (cs / 8), cluster % (cs / 8));
+        let l1_pos = (self.l1_offset() + l1_idx * 8) as usize;
+        let mut l2 = u64::from_be_bytes(img[l1_pos..l1_pos + 8].try_into().unwrap()) & !L2_COPIED;
+        if l2 == 0 {
+            l2 = img.len() as u64;
+            shared.push(l2 / cs);
+            img.resize(img.len() + cs as usize, 0);
+            put_u64(img, l1_pos, l2 | copied);
+        }
+        (l2 + l2_idx * 8) as usize
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let cs = self.cluster_size();
+        let l1_entries = self.l1_entries();
+        let l1_clusters = div_ceil(l1_entries * 8, cs).max(1);
+        let l1_offset = self.l1_offset();
+
+        let mut img = vec![0u8; ((3 + l1_clusters) * cs) as usize];
+
+        // Data and L2 tables. Each is referenced once by the active image, and once by each
+        // snapshot.
+        let shared_refcount = 1 + self.snapshots.len() as u64;
+        let copied = if self.snapshots.is_empty() { L2_COPIED } else { 0 };
+        let mut shared = Vec::new();
+        for &(guest_offset, ref bytes) in &self.data {
+            let entry = self.l2_entry(&mut img, guest_offset, copied, &mut shared);
+            let pos = img.len() as u64;
+            shared.push(pos / cs);
+            img.resize(img.len() + cs as usize, 0);
+            img[pos as usize..pos as usize + bytes.len()].copy_from_slice(bytes);
+            put_u64(&mut img, entry, pos | copied);
+        }
+        for &guest_offset in &self.zeros {
+            let entry = self.l2_entry(&mut img, guest_offset, copied, &mut shared);
+            img[entry + 7] |= L2_ZERO as u8;
+        }
+        for &(guest_offset, ref stored) in &self.compressed {
+            let entry = self.l2_entry(&mut img, guest_offset, copied, &mut shared);
+            let pos = img.len() as u64;
+            shared.push(pos / cs);
+            img.resize(img.len() + cs as usize, 0);
+            img[pos as usize..pos as usize + stored.len()].copy_from_slice(stored);
+            // The sector count is one less than the number of 512-byte sectors touched.
+            let sectors = div_ceil(stored.len() as u64, 512).max(1) - 1;
+            let shift = 62 - (self.cluster_bits - 8);
+            put_u64(&mut img, entry, L2_COMPRESSED | (sectors << shift) | pos);
+        }
+
+        // Snapshot table, and a copy of the L1 table for each snapshot.
+        let mut snapshots_offset = 0;
+        if !self.snapshots.is_empty() {
+            let l1_bytes = (l1_entries * 8) as usize;
+            let l1_copy = img[l1_offset as usize..l1_offset as usize + l1_bytes].to_vec();
+            let mut table = Vec::new();
+            for snap in &self.snapshots {
+                let snap_l1 = img.len() as u64;
+                img.extend_from_slice(&l1_copy);
+                img.resize((snap_l1 + l1_clusters * cs) as usize, 0);
+
+                table.extend_from_slice(&snap_l1.to_be_bytes());
+                table.extend_from_slice(&(l1_entries as u32).to_be_bytes());
+                table.extend_from_slice(&(snap.id.len() as u16).to_be_bytes());
+                table.extend_from_slice(&(snap.name.len() as u16).to_be_bytes());
+                table.extend_from_slice(&snap.date_sec.to_be_bytes());
+                table.extend_from_slice(&snap.date_nsec.to_be_bytes());
+                table.extend_from_slice(&snap.vm_clock_nsec.to_be_bytes());
+                table.extend_from_slice(&0u32.to_be_bytes());
+                table.extend_from_slice(&(snap.extra.len() as u32).to_be_bytes());
+                // Like qemu, record the disk size if there's room.
+                let mut extra = snap.extra.clone();
+                if extra.len() >= 16 {
+                    extra[8..16].copy_from_slice(&self.size.to_be_bytes());
+                }
+                table.extend_from_slice(&extra);
+                table.extend_from_slice(snap.id.as_bytes());
+                table.extend_from_slice(snap.name.as_bytes());
+                table.resize(div_ceil(table.len() as u64, 8) as usize * 8, 0);
+            }
+            snapshots_offset = img.len() as u64;
+            let clusters = div_ceil(table.len() as u64, cs);
+            img.extend_from_slice(&table);
+            img.resize((snapshots_offset + clusters * cs) as usize, 0);
+        }
+
+        // Bitmap data clusters, then each bitmap table, then the bitmap directory.
+        let mut extensions = self.extensions.clone();
+        if !self.bitmaps.is_empty() {
+            let bits_per_cluster = cs * 8;
+            let mut dir = Vec::new();
+            for bitmap in &self.bitmaps {
+                let bits = div_ceil(self.size, 1 << bitmap.granularity_bits);
+                let mut table = vec![0u64; div_ceil(bits, bits_per_cluster) as usize];
+                for &bit in &bitmap.dirty {
+                    let entry = (bit / bits_per_cluster) as usize;
+                    if table[entry] == 0 {
+                        table[entry] = img.len() as u64;
+                        img.resize(img.len() + cs as usize, 0);
+                    }
+                    let byte = (table[entry] + bit % bits_per_cluster / 8) as usize;
+                    img[byte] |= 1 << (bit % 8);
+                }
+                for &entry in &bitmap.all_ones {
+                    table[entry as usize] = 1;
+                }
+
+                let table_offset = img.len() as u64;
+                for entry in &table {
+                    img.extend_from_slice(&entry.to_be_bytes());
+                }
+                img.resize((table_offset + div_ceil(table.len() as u64 * 8, cs).max(1) * cs)
+                               as usize,
+                           0);
+
+                dir.extend_from_slice(&table_offset.to_be_bytes());
+                dir.extend_from_slice(&(table.len() as u32).to_be_bytes());
+                dir.extend_from_slice(&bitmap.flags.to_be_bytes());
+                dir.push(1);
+                dir.push(bitmap.granularity_bits);
+                dir.extend_from_slice(&(bitmap.name.len() as u16).to_be_bytes());
+                dir.extend_from_slice(&0u32.to_be_bytes());
+                dir.extend_from_slice(bitmap.name.as_bytes());
+                dir.resize(div_ceil(dir.len() as u64, 8) as usize * 8, 0);
+            }
+            let dir_offset = img.len() as u64;
+            let clusters = div_ceil(dir.len() as u64, cs);
+            img.extend_from_slice(&dir);
+            img.resize((dir_offset + clusters * cs) as usize, 0);
+
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&(self.bitmaps.len() as u32).to_be_bytes());
+            payload.extend_from_slice(&0u32.to_be_bytes());
+            payload.extend_from_slice(&(dir.len() as u64).to_be_bytes());
+            payload.extend_from_slice(&dir_offset.to_be_bytes());
+            extensions.push((EXT_BITMAPS, payload));
+        }
+
+        // Refcounts, every cluster in the file is used. Blocks past the first go at the end, and
+        // need refcounts themselves.
+        put_u64(&mut img, cs as usize, 2 * cs);
+        let mut blocks = 1;
+        while blocks * self.refcount_block_size() < img.len() as u64 / cs {
+            let block = img.len() as u64;
+            put_u64(&mut img, (cs + blocks * 8) as usize, block);
+            img.resize(img.len() + cs as usize, 0);
+            blocks += 1;
+        }
+        for idx in 0..(img.len() as u64 / cs) {
+            let refcount = if shared.contains(&idx) { shared_refcount } else { 1 };
+            self.set_refcount(&mut img, idx, refcount);
+        }
+
+        // Header.
+        put_u32(&mut img, 0, 0x514649fb);
+        put_u32(&mut img, 4, 3);
+        put_u32(&mut img, 20, self.cluster_bits);
+        put_u64(&mut img, 24, self.size);
+        put_u32(&mut img, 36, l1_entries as u32);
+        put_u64(&mut img, 40, l1_offset);
+        put_u64(&mut img, 48, cs);
+        put_u32(&mut img, 56, 1);
+        put_u32(&mut img, 60, self.snapshots.len() as u32);
+        put_u64(&mut img, 64, snapshots_offset);
+        put_u64(&mut img, 72, self.incompatible);
+        put_u64(&mut img, 80, self.compatible);
+        put_u64(&mut img, 88, self.autoclear);
+        put_u32(&mut img, 96, self.refcount_order);
+        put_u32(&mut img, 100, self.header_length);
+
+        let mut pos = self.header_length as usize;
+        for &(code, ref payload) in &extensions {
+            put_u32(&mut img, pos, code);
+            put_u32(&mut img, pos + 4, payload.len() as u32);
+            img[pos + 8..pos + 8 + payload.len()].copy_from_slice(payload);
+            pos += 8 + div_ceil(payload.len() as u64, 8) as usize * 8;
+        }
+        // End of extensions is already zero.
+        pos += 8;
+
+        if let Some(ref name) = self.backing_file {
+            put_u64(&mut img, 8, pos as u64);
+            put_u32(&mut img, 16, name.len() as u32);
+            img[pos..pos + name.len()].copy_from_slice(name);
+        }
+
+        img
+    }
+}
@@ -0,0 +1,40 @@
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{Difference, Qcow2, compare};
+
+#[test]
+fn compare_contents() {
+    let a = Qcow2::open(ImageBuilder::new().data(1 << 16, b"data").build()).unwrap();
+    let b = Qcow2::open(ImageBuilder::new().data(1 << 16, b"dada").build()).unwrap();
+    let (ra, rb) = (a.reader().unwrap(), b.reader().unwrap());
+    assert_eq!(compare(&ra, &ra).unwrap(), None);
+    assert_eq!(compare(&ra, &rb).unwrap(), Some(Difference::Content((1 << 16) + 2)));
+
+    // Against a raw image, the extra part must be zeros.
+    let mut raw = vec![0; 2 << 20];
+    raw[1 << 16..(1 << 16) + 4].copy_from_slice(b"data");
+    assert_eq!(compare(&ra, &raw).unwrap(), None);
+    raw[(2 << 20) - 1] = 1;
+    assert_eq!(compare(&ra, &raw).unwrap(), Some(Difference::Content((2 << 20) - 1)));
+}
+
+#[test]
+fn compare_strict() {
+    let a = Qcow2::open(ImageBuilder::new().data(1 << 16, b"data").build()).unwrap();
+    let zeros = Qcow2::open(ImageBuilder::new().data(1 << 16, b"data").data(0, &[0; 4]).build())
+        .unwrap();
+    let small = Qcow2::open(ImageBuilder::new().size(1 << 17).data(1 << 16, b"data").build())
+        .unwrap();
+    let ra = a.reader().unwrap();
+    let rz = zeros.reader().unwrap();
+    let rs = small.reader().unwrap();
+
+    assert_eq!(compare(&ra, &rz).unwrap(), None);
+    assert_eq!(ra.compare_strict(&ra).unwrap(), None);
+    assert_eq!(ra.compare_strict(&rz).unwrap(), Some(Difference::Allocation(0)));
+    assert_eq!(compare(&ra, &rs).unwrap(), None);
+    assert_eq!(ra.compare_strict(&rs).unwrap(), Some(Difference::Size));
+}
@@ -0,0 +1,143 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, Size};
+use qcow2::{Backing, CopyOptions, CopyStats, Qcow2, copy_range};
+
+const CS: u64 = 65536;
+
+// Read all of a virtual disk.
+fn contents<R: ReadAt + Size>(reader: &R) -> Vec<u8> {
+    let mut buf = vec![0; reader.size().unwrap().unwrap() as usize];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    buf
+}
+
+fn first_l2_entries<I: ReadAt>(qcow: &Qcow2<I>) -> Vec<qcow2::L2TableEntry> {
+    let l1 = qcow.l1_table_entries().unwrap();
+    qcow.l2_table_entries(l1[0].l2_offset).unwrap()
+}
+
+// An image with data in the first cluster, a zero cluster third, and data in the sixth.
+fn source() -> Vec<u8> {
+    ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(2 * CS, &[2; 16])
+        .zero_cluster(2 * CS)
+        .data(5 * CS, &[3; 16])
+        .build()
+}
+
+// An image with data in each of the first six clusters.
+fn full() -> Vec<u8> {
+    let mut builder = ImageBuilder::new();
+    for i in 0..6 {
+        builder = builder.data(i * CS, &[9; 100]);
+    }
+    builder.build()
+}
+
+#[test]
+fn copy_sparse() {
+    let src = Qcow2::open(source()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut dst = Qcow2::open(full()).unwrap();
+    let stats = copy_range(&reader, 0, &mut dst.writer().unwrap(), 0, 6 * CS, &CopyOptions::new())
+        .unwrap();
+    assert_eq!(stats,
+               CopyStats {
+                   written: 2 * CS,
+                   zeroed: CS,
+                   discarded: 3 * CS,
+                   unchanged: 0,
+               });
+
+    assert_eq!(contents(&dst.reader().unwrap()), contents(&reader));
+    let entries = first_l2_entries(&dst);
+    assert!(entries[0].host_offset != 0 && entries[5].host_offset != 0);
+    assert!(entries[2].zero && entries[2].host_offset == 0);
+    assert!([1, 3, 4].iter().all(|&i| entries[i].raw == 0));
+    assert!(dst.check().unwrap().is_clean());
+}
+
+#[test]
+fn copy_unaligned() {
+    let src = Qcow2::open(source()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut dst = Qcow2::open(full()).unwrap();
+    {
+        let mut writer = dst.writer().unwrap();
+        let stats = copy_range(&reader, 5 * CS - 10, &mut writer, CS + 100, CS + 20,
+                               &CopyOptions::new())
+            .unwrap();
+        // Neither end is aligned, so everything is written.
+        assert_eq!(stats.written, CS + 20);
+        // Between aligned ends, the source's hole is discarded.
+        let stats = copy_range(&reader, 3 * CS + 7, &mut writer, 3 * CS, CS + 50,
+                               &CopyOptions::new())
+            .unwrap();
+        assert_eq!((stats.written, stats.discarded), (50, CS));
+    }
+
+    let mut expected = contents(&Qcow2::open(full()).unwrap().reader().unwrap());
+    let src_data = contents(&reader);
+    let (a, b) = ((CS + 100) as usize, (5 * CS - 10) as usize);
+    expected[a..a + CS as usize + 20].copy_from_slice(&src_data[b..b + CS as usize + 20]);
+    let (a, b) = ((3 * CS) as usize, (3 * CS + 7) as usize);
+    expected[a..a + CS as usize + 50].copy_from_slice(&src_data[b..b + CS as usize + 50]);
+    assert_eq!(contents(&dst.reader().unwrap()), expected);
+    assert_eq!(first_l2_entries(&dst)[3].raw, 0);
+    assert!(dst.check().unwrap().is_clean());
+}
+
+#[test]
+fn copy_skip_equal() {
+    let src = Qcow2::open(source()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut img = source();
+    let len = img.len();
+    {
+        let mut dst = Qcow2::open(&mut img).unwrap();
+        let mut opts = CopyOptions::new();
+        opts.skip_equal(true);
+        let stats = copy_range(&reader, 10, &mut dst.writer().unwrap(), 10, 1 << 20, &opts)
+            .unwrap();
+        assert_eq!(stats.unchanged, (1 << 20) - 10);
+        assert_eq!(stats.total(), stats.unchanged);
+    }
+    // Nothing was allocated.
+    assert_eq!(img.len(), len);
+}
+
+#[test]
+fn copy_to_overlay() {
+    // The destination has a backing file, so holes must become zero clusters.
+    let src = Qcow2::open(ImageBuilder::new().build()).unwrap();
+    let reader = src.reader().unwrap();
+    let base = full();
+    let mut dst = Qcow2::open(ImageBuilder::new().backing_file("base.qcow2").build()).unwrap();
+    dst.set_backing(Backing::qcow2(Qcow2::open(base).unwrap()).unwrap());
+    let stats = copy_range(&reader, 0, &mut dst.writer().unwrap(), CS, 2 * CS, &CopyOptions::new())
+        .unwrap();
+    assert_eq!(stats.zeroed, 2 * CS);
+
+    let data = contents(&dst.reader().unwrap());
+    assert_eq!(data[..100], [9; 100]);
+    assert!(data[CS as usize..3 * CS as usize].iter().all(|&b| b == 0));
+    assert_eq!(data[3 * CS as usize..3 * CS as usize + 100], [9; 100]);
+    assert!(dst.check().unwrap().is_clean());
+}
+
+#[test]
+fn copy_past_end() {
+    let src = Qcow2::open(source()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut dst = Qcow2::open(ImageBuilder::new().size(2 * CS + 5).build()).unwrap();
+    let stats = copy_range(&reader, 0, &mut dst.writer().unwrap(), CS, 10 * CS, &CopyOptions::new())
+        .unwrap();
+    assert_eq!(stats.total(), CS + 5);
+    assert_eq!(contents(&dst.reader().unwrap())[CS as usize..CS as usize + 16], [1; 16]);
+}
@@ -0,0 +1,274 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use common::{ImageBuilder, SnapshotSpec};
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{Backing, CreateOptions, Error, Qcow2};
+
+// Read all of a virtual disk.
+fn contents<R: ReadAt + Size>(reader: &R) -> Vec<u8> {
+    let mut buf = vec![0; reader.size().unwrap().unwrap() as usize];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    buf
+}
+
+fn first_l2_entries<I: ReadAt>(qcow: &Qcow2<I>) -> Vec<qcow2::L2TableEntry> {
+    let l1 = qcow.l1_table_entries().unwrap();
+    qcow.l2_table_entries(l1[0].l2_offset).unwrap()
+}
+
+// Count the data clusters in the first L2 table of an image.
+fn data_clusters<I: ReadAt>(qcow: &Qcow2<I>) -> usize {
+    first_l2_entries(qcow).iter().filter(|e| e.host_offset != 0).count()
+}
+
+#[test]
+fn create_from() {
+    let cs = 65536;
+    let img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(2 * cs as u64, &[2; 16])
+        .zero_cluster(2 * cs as u64)
+        .data(5 * cs as u64, &[3; 16])
+        .size((5 << 16) + 100)
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    let copy = Qcow2::create_from(&reader, Vec::new(), &CreateOptions::new()).unwrap();
+    assert_eq!(copy.guest_size(), qcow.guest_size());
+    assert_eq!(copy.cluster_size(), qcow.cluster_size());
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&reader));
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(data_clusters(&copy), 2);
+    assert!(first_l2_entries(&copy)[2].zero);
+}
+
+#[test]
+fn create_from_snapshot() {
+    let img = ImageBuilder::new().data(0, &[1; 16]).snapshot(SnapshotSpec::new("1", "s")).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.writer().unwrap().write_all_at(0, &[2; 16]).unwrap();
+
+    let snap = qcow.snapshot_reader("s").unwrap();
+    let copy = Qcow2::create_from(&snap, Vec::new(), &CreateOptions::new()).unwrap();
+    assert!(copy.snapshots().is_empty());
+    let mut buf = [0; 16];
+    copy.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&snap));
+}
+
+#[test]
+fn create_from_backing() {
+    let base = ImageBuilder::new().data(0, &[1; 16]).data(65536, &[2; 16]).build();
+    let overlay = ImageBuilder::new().backing_file("base.qcow2").data(65536, &[3; 16]).build();
+    let mut qcow = Qcow2::open(overlay).unwrap();
+    qcow.set_backing(Backing::qcow2(Qcow2::open(base.clone()).unwrap()).unwrap());
+    let reader = qcow.reader().unwrap();
+
+    // Flattening copies what the backing file provides.
+    let flat = Qcow2::create_from(&reader, Vec::new(), &CreateOptions::new()).unwrap();
+    assert_eq!(flat.backing_file_name(), None);
+    assert_eq!(data_clusters(&flat), 2);
+    assert_eq!(contents(&flat.reader().unwrap()), contents(&reader));
+
+    // Keeping the backing file makes an overlay.
+    let mut options = CreateOptions::new();
+    options.keep_backing(true);
+    let mut kept = Qcow2::create_from(&reader, Vec::new(), &options).unwrap();
+    assert_eq!(kept.backing_file_name(), Some(Path::new("base.qcow2")));
+    assert_eq!(data_clusters(&kept), 1);
+    kept.set_backing(Backing::qcow2(Qcow2::open(base).unwrap()).unwrap());
+    assert_eq!(contents(&kept.reader().unwrap()), contents(&reader));
+}
+
+#[test]
+fn create_from_many_refcount_blocks() {
+    // With 512-byte clusters, each refcount block covers only 256 clusters.
+    let size = 4 << 20;
+    let mut qcow = Qcow2::open(ImageBuilder::new().cluster_bits(9).size(size).build()).unwrap();
+    let chunk: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+    for pos in (0..size).step_by(3 * chunk.len()) {
+        qcow.writer().unwrap().write_all_at(pos, &chunk).unwrap();
+    }
+
+    let reader = qcow.reader().unwrap();
+    let copy = Qcow2::create_from(&reader, Vec::new(), &CreateOptions::new()).unwrap();
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&reader));
+}
+
+#[test]
+fn create_from_compressed() {
+    let cs = 65536;
+    let img = ImageBuilder::new().data(0, &[1; 16]).compressed(cs, &[2; 16]).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    // Compressed clusters are copied decompressed.
+    let copy = Qcow2::create_from(&reader, Vec::new(), &CreateOptions::new()).unwrap();
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&reader));
+    assert_eq!(data_clusters(&copy), 2);
+    assert!(first_l2_entries(&copy).iter().all(|e| e.compressed_size.is_none()));
+}
+
+#[test]
+fn create_from_compress() {
+    // Clusters of 4 KiB that compress to about half, so compressed data spans host clusters,
+    // and one in the middle that doesn't compress at all.
+    let bits = 12;
+    let (cs, count) = (1 << bits, 32);
+    let mut qcow = Qcow2::open(ImageBuilder::new().cluster_bits(bits).size(count * cs).build())
+        .unwrap();
+    let mut seed = 1u32;
+    for cluster in 0..count {
+        let mask = if cluster == 10 { 0xff } else { 0x0f };
+        let data: Vec<u8> = (0..cs)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 & mask
+            })
+            .collect();
+        qcow.writer().unwrap().write_all_at(cluster * cs, &data).unwrap();
+    }
+    let reader = qcow.reader().unwrap();
+
+    let mut dst = Vec::new();
+    let copy = Qcow2::create_from(&reader, &mut dst, CreateOptions::new().compress(true)).unwrap();
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&reader));
+    let entries = first_l2_entries(&copy);
+    let compressed = entries.iter().filter(|e| e.compressed_size.is_some()).count();
+    assert_eq!(compressed, count as usize - 1);
+    assert_eq!(entries[10].compressed_size, None);
+    drop(copy);
+    let mut plain = Vec::new();
+    Qcow2::create_from(&reader, &mut plain, &CreateOptions::new()).unwrap();
+    assert!(dst.len() < plain.len() * 3 / 4);
+}
+
+#[test]
+fn create_from_reporting() {
+    let mb = 1 << 20;
+    let img = ImageBuilder::new().size(3 * mb + 100).data(0, b"first").data(3 * mb, b"last")
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    // Reports come each megabyte, and at the end.
+    let reports = RefCell::new(Vec::new());
+    let progress = |done, total, physical| {
+        reports.borrow_mut().push((done, total, physical));
+        ControlFlow::Continue(())
+    };
+    let copy = Qcow2::create_from_reporting(&reader, Vec::new(), &CreateOptions::new(), &progress)
+        .unwrap();
+    assert_eq!(contents(&copy.reader().unwrap()), contents(&reader));
+    let total = 3 * mb + 100;
+    assert_eq!(*reports.borrow(),
+               vec![(mb, total, 65536),
+                    (2 * mb, total, 65536),
+                    (3 * mb, total, 65536),
+                    (total, total, 2 * 65536)]);
+
+    // Cancelling leaves something that isn't an image.
+    let mut dst = Vec::new();
+    let cancel = |done, _, _| {
+        if done >= mb { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    };
+    let result = Qcow2::create_from_reporting(&reader, &mut dst, &CreateOptions::new(), &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    drop(result);
+    assert!(matches!(Qcow2::open(dst), Err(Error::FileType)));
+}
+
+#[test]
+fn create() {
+    let size = (3 << 20) + 512;
+    let qcow = Qcow2::create(Vec::new(), size, &CreateOptions::new()).unwrap();
+    assert_eq!(qcow.guest_size(), size);
+    assert_eq!(qcow.cluster_size(), 65536);
+    assert_eq!(qcow.backing_file_name(), None);
+    assert!(qcow.check().unwrap().is_clean());
+    assert_eq!(qcow.info().unwrap().allocated_size, 0);
+    assert!(contents(&qcow.reader().unwrap()).iter().all(|&b| b == 0));
+
+    // With smaller clusters, and written to.
+    let mut qcow = Qcow2::create(Vec::new(), 1 << 20, CreateOptions::new().cluster_bits(12))
+        .unwrap();
+    assert_eq!(qcow.cluster_size(), 4096);
+    qcow.writer().unwrap().write_all_at(5000, b"data").unwrap();
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(5000, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert!(qcow.check().unwrap().is_clean());
+
+    let result = Qcow2::create(Vec::new(), 1 << 20, CreateOptions::new().cluster_bits(22));
+    assert!(matches!(result, Err(Error::UnsupportedFeature(_))));
+    let result = Qcow2::create(Vec::new(), u64::MAX, &CreateOptions::new());
+    assert!(matches!(result, Err(Error::UnsupportedFeature(_))));
+}
+
+#[test]
+fn create_from_raw() {
+    let cs = 4096;
+    let mut raw = vec![0; 5 * cs + 100];
+    raw[10] = 1;
+    raw[2 * cs..3 * cs].fill(2);
+    raw[5 * cs + 99] = 3;
+    let mut opts = CreateOptions::new();
+    opts.cluster_bits(12);
+
+    // Clusters of zeros are left out.
+    let copy = Qcow2::create_from_raw(&raw, Vec::new(), &opts).unwrap();
+    assert_eq!(copy.guest_size(), raw.len() as u64);
+    assert_eq!(copy.cluster_size(), cs as u64);
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(contents(&copy.reader().unwrap()), raw);
+    let entries = first_l2_entries(&copy);
+    let allocated: Vec<usize> = (0..6).filter(|&i| entries[i].host_offset != 0).collect();
+    assert_eq!(allocated, vec![0, 2, 5]);
+
+    let copy = Qcow2::create_from_raw(&raw, Vec::new(), opts.compress(true)).unwrap();
+    assert!(copy.check().unwrap().is_clean());
+    assert_eq!(contents(&copy.reader().unwrap()), raw);
+    assert_eq!(data_clusters(&copy), 3);
+    assert!(first_l2_entries(&copy)[..6].iter().all(|e| e.host_offset == 0 ||
+                                                         e.compressed_size.is_some()));
+}
+
+#[test]
+fn create_from_raw_reporting() {
+    let mb = 1 << 20;
+    let mut raw = vec![0; 2 * mb + 100];
+    raw[mb] = 1;
+
+    let reports = RefCell::new(Vec::new());
+    let progress = |done, total, physical| {
+        reports.borrow_mut().push((done, total, physical));
+        ControlFlow::Continue(())
+    };
+    let opts = CreateOptions::new();
+    let copy = Qcow2::create_from_raw_reporting(&raw, Vec::new(), &opts, &progress).unwrap();
+    assert_eq!(contents(&copy.reader().unwrap()), raw);
+    let total = 2 * mb as u64 + 100;
+    assert_eq!(*reports.borrow(),
+               vec![(mb as u64, total, 0), (2 * mb as u64, total, 65536), (total, total, 65536)]);
+
+    let mut dst = Vec::new();
+    let cancel = |done, _, _| {
+        if done >= 2 * mb as u64 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    };
+    let result = Qcow2::create_from_raw_reporting(&raw, &mut dst, &opts, &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)));
+    drop(result);
+    assert!(matches!(Qcow2::open(dst), Err(Error::FileType)));
+}
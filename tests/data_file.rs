@@ -0,0 +1,68 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use positioned_io::ReadAt;
+
+use common::ImageBuilder;
+use qcow2::{Error, OpenOptions, Qcow2};
+
+const DATA_FILE: u64 = 0b100;
+const DATA_FILE_RAW: u64 = 0b10;
+
+fn builder(autoclear: u64) -> ImageBuilder {
+    let builder = ImageBuilder::new().extension(0x44415441, b"disk.raw".to_vec());
+    ImageBuilder { incompatible: DATA_FILE, autoclear, ..builder }
+}
+
+#[test]
+fn data_file() {
+    // Clusters are found through the L2 tables, at their offsets in the data file.
+    let builder = builder(0).data(65536, b"data");
+    let mut raw = vec![0; 6 << 16];
+    raw[5 << 16..(5 << 16) + 5].copy_from_slice(b"other");
+    let img = builder.build();
+    let image_len = img.len() as u64;
+    let q = OpenOptions::new().open_with_data_file(img, raw).unwrap();
+    assert_eq!(q.data_file_name(), Some("disk.raw"));
+    let mut buf = [0; 5];
+    q.reader().unwrap().read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"other");
+    assert_eq!(q.physical_size().unwrap(), Some(image_len + (6 << 16)));
+}
+
+#[test]
+fn data_file_not_opened() {
+    let q = Qcow2::open(builder(0).data(0, b"data").build()).unwrap();
+    assert_eq!(q.data_file_name(), Some("disk.raw"));
+    let err = q.reader().unwrap().read_exact_at(0, &mut [0; 4]).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported feature: external data file that was not opened");
+}
+
+#[test]
+fn data_file_raw() {
+    // L2 tables are ignored, so clusters are found even if they're not allocated.
+    let mut raw = vec![0; 1 << 20];
+    raw[3 << 16..(3 << 16) + 8].copy_from_slice(b"raw data");
+    let img = builder(DATA_FILE_RAW).build();
+    let q = OpenOptions::new().open_with_data_file(img, raw).unwrap();
+    let mut buf = [0; 8];
+    q.reader().unwrap().read_exact_at(3 << 16, &mut buf).unwrap();
+    assert_eq!(&buf, b"raw data");
+    assert!(q.info().unwrap().features.contains(&"raw external data".to_owned()));
+
+    let result = q.check().unwrap();
+    assert!(result.is_clean(), "{:?}", result.findings);
+}
+
+#[test]
+fn data_file_raw_requires_data_file() {
+    let builder = ImageBuilder { autoclear: DATA_FILE_RAW, ..ImageBuilder::new() };
+    match Qcow2::open(builder.build()) {
+        Err(Error::FileFormat(ref msg)) => {
+            assert_eq!(msg, "raw external data bit set without an external data file")
+        }
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
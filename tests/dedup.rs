@@ -0,0 +1,88 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{DedupOptions, Error, Qcow2};
+
+const CS: u64 = 65536;
+
+// Three clusters with the same data, one different, one allocated but all zeros, and a
+// compressed cluster with the same data as the different one.
+fn image() -> Vec<u8> {
+    ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(CS, &[1; 16])
+        .data(2 * CS, &[2; 16])
+        .data(3 * CS, &[1; 16])
+        .data(5 * CS, &[0; 16])
+        .compressed(6 * CS, &[2; 16])
+        .build()
+}
+
+#[test]
+fn dedup_clusters() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let report = qcow.dedup_report_with(CS, DedupOptions::new().top(5)).unwrap();
+    assert_eq!(report.hash_window, CS);
+    assert_eq!((report.blocks, report.bytes), (6, 6 * CS));
+    assert_eq!(report.unique_blocks, 3);
+    assert_eq!((report.duplicate_groups, report.duplicate_blocks), (2, 3));
+    assert_eq!(report.savings, 3 * CS);
+    assert_eq!(report.zero_blocks, 1);
+    assert_eq!(report.compressed_clusters, 1);
+
+    assert_eq!(report.top.len(), 2);
+    assert_eq!((report.top[0].count, report.top[0].first_offset), (3, 0));
+    assert!(report.top[0].offsets.is_empty());
+    // The compressed cluster is hashed decompressed.
+    assert_eq!((report.top[1].count, report.top[1].first_offset), (2, 2 * CS));
+
+    // Without asking, nothing is listed.
+    let plain = qcow.dedup_report(CS).unwrap();
+    assert!(plain.top.is_empty());
+    assert_eq!(plain.savings, report.savings);
+}
+
+#[test]
+fn dedup_small_window() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let report = qcow.dedup_report_with(4096, DedupOptions::new().top(1).offsets(true)).unwrap();
+    assert_eq!((report.blocks, report.bytes), (96, 6 * CS));
+    // The start of each data cluster, and the zeros after it.
+    assert_eq!(report.unique_blocks, 3);
+    assert_eq!((report.duplicate_groups, report.duplicate_blocks), (3, 93));
+    assert_eq!(report.savings, 93 * 4096);
+    assert_eq!(report.zero_blocks, 91);
+
+    let zeros = &report.top[0];
+    assert_eq!((zeros.count, zeros.first_offset), (91, 4096));
+    assert_eq!(zeros.offsets.len(), 91);
+    assert_eq!(zeros.offsets[..2], [4096, 2 * 4096]);
+    assert_eq!(zeros.offsets[90], 7 * CS - 4096);
+    assert!(!zeros.offsets.contains(&CS));
+}
+
+#[test]
+fn dedup_short_last_cluster() {
+    let img = ImageBuilder::new()
+        .size(2 * CS + 100)
+        .data(0, &[1; 16])
+        .data(2 * CS, &[1; 16])
+        .build();
+    let report = Qcow2::open(img).unwrap().dedup_report(CS).unwrap();
+    // Only the part in the virtual disk is hashed, so the last cluster is different.
+    assert_eq!((report.blocks, report.bytes), (2, CS + 100));
+    assert_eq!((report.unique_blocks, report.savings), (2, 0));
+}
+
+#[test]
+fn dedup_bad_window() {
+    let qcow = Qcow2::open(image()).unwrap();
+    for &window in &[0, 3, 2 * CS] {
+        assert!(matches!(qcow.dedup_report(window), Err(Error::UnsupportedFeature(_))));
+    }
+    let empty = Qcow2::open(ImageBuilder::new().build()).unwrap().dedup_report(512).unwrap();
+    assert_eq!((empty.blocks, empty.unique_blocks, empty.savings), (0, 0, 0));
+}
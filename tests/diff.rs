@@ -0,0 +1,63 @@
+extern crate positioned_io;
+extern crate qcow2;
+mod common;
+
+use common::{ImageBuilder, SnapshotSpec};
+use positioned_io::WriteAt;
+use qcow2::{Error, GuestRange, Qcow2};
+
+fn diff(qcow: &Qcow2<Vec<u8>>) -> Vec<GuestRange> {
+    qcow.diff_snapshot("snap").unwrap().collect::<Result<_, _>>().unwrap()
+}
+
+fn range(offset: u64, len: u64) -> GuestRange {
+    GuestRange { offset, len }
+}
+
+#[test]
+fn diff_snapshot() {
+    let cs = 65536;
+    let img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(2 * cs, &[2; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    assert_eq!(diff(&qcow), vec![]);
+
+    {
+        let mut writer = qcow.writer().unwrap();
+        writer.write_all_at(2 * cs + 5, &[3; 4]).unwrap();
+        writer.write_all_at(4 * cs, &[4; 4]).unwrap();
+        writer.write_all_at(5 * cs, &[5; 4]).unwrap();
+        // Overwriting with the same data still counts as a change.
+        writer.write_all_at(0, &[1; 16]).unwrap();
+    }
+    assert_eq!(diff(&qcow), vec![range(0, cs), range(2 * cs, cs), range(4 * cs, 2 * cs)]);
+    assert_eq!(qcow.diff_snapshot("1").unwrap().count(), 3);
+    match qcow.diff_snapshot("nope") {
+        Err(Error::NoSnapshot(_)) => {}
+        r => panic!("unexpected result {:?}", r.err()),
+    }
+}
+
+#[test]
+fn diff_snapshot_l2_tables() {
+    // Each L2 table covers 64 clusters, so a range can cross tables.
+    let cs = 512;
+    let img = ImageBuilder::new()
+        .cluster_bits(9)
+        .size(1 << 16)
+        .data(0, &[1; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.writer().unwrap().write_all_at(63 * cs, &[2; 1024]).unwrap();
+    qcow.writer().unwrap().write_all_at(127 * cs + 1, &[3; 1]).unwrap();
+    assert_eq!(diff(&qcow), vec![range(63 * cs, 2 * cs), range(127 * cs, cs)]);
+
+    // The end of the disk may be in the middle of a cluster.
+    qcow.resize((1 << 16) + 100).unwrap();
+    qcow.writer().unwrap().write_all_at(1 << 16, &[4; 100]).unwrap();
+    assert_eq!(diff(&qcow), vec![range(63 * cs, 2 * cs), range(127 * cs, cs + 100)]);
+}
@@ -0,0 +1,67 @@
+#![cfg(all(any(target_os = "linux", target_os = "android", target_os = "freebsd"),
+           feature = "direct"))]
+
+extern crate qcow2;
+mod common;
+
+use std::io;
+use std::path::PathBuf;
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{DirectFile, Qcow2};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("qcow2-direct-{}-{}", name, std::process::id()))
+}
+
+// Open a file for direct IO, or return None if the filesystem doesn't support it.
+fn open_rw(path: &PathBuf) -> Option<DirectFile> {
+    match DirectFile::open_rw(path) {
+        Ok(f) => Some(f),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => None,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[test]
+fn direct_unaligned() {
+    let path = temp_path("unaligned");
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+    if let Some(mut file) = open_rw(&path) {
+        let mut buf = vec![0; 100];
+        file.read_exact_at(4000, &mut buf).unwrap();
+        assert_eq!(buf, data[4000..4100]);
+        assert_eq!(file.read_at(9990, &mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], data[9990..]);
+
+        file.write_all_at(4090, &[1; 20]).unwrap();
+        file.write_all_at(9995, &[2; 10]).unwrap();
+        assert_eq!(file.size().unwrap(), Some(10005));
+
+        let mut expected = data;
+        expected[4090..4110].copy_from_slice(&[1; 20]);
+        expected.truncate(9995);
+        expected.extend_from_slice(&[2; 10]);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn direct_image() {
+    let path = temp_path("image");
+    std::fs::write(&path, ImageBuilder::new().data(0, &[1; 16]).build()).unwrap();
+    if let Some(file) = open_rw(&path) {
+        let mut qcow = Qcow2::open(file).unwrap();
+        qcow.writer().unwrap().write_all_at(70000, &[2; 16]).unwrap();
+        let mut buf = [0; 16];
+        qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 16]);
+        qcow.reader().unwrap().read_exact_at(70000, &mut buf).unwrap();
+        assert_eq!(buf, [2; 16]);
+        assert!(qcow.check().unwrap().is_clean());
+    }
+    std::fs::remove_file(&path).unwrap();
+}
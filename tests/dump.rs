@@ -0,0 +1,301 @@
+mod common;
+
+use std::process::Command;
+
+use common::{ImageBuilder, SnapshotSpec, TempFile};
+
+fn dump(args: &[&str]) -> (i32, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_qcow2-dump")).args(args).output().unwrap();
+    (out.status.code().unwrap(), String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn info() {
+    let (code, out) = dump(&["info", "tests/test.qcow2", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert_eq!(out.matches("image: tests/test.qcow2\n").count(), 2);
+    assert!(out.contains("virtual size: 0.977 GiB (1048576000 bytes)\n"));
+
+    // The subcommand is optional.
+    let (code, default) = dump(&["tests/test.qcow2", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert_eq!(default, out);
+}
+
+#[test]
+fn info_missing_file() {
+    let (code, _) = dump(&["info", "tests/test.qcow2", "tests/does-not-exist.qcow2"]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn check() {
+    let (code, out) = dump(&["check", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert_eq!(out, "No errors were found on the image.\nImage end offset: 393216\n");
+}
+
+// Check an image with some refcounts changed, from a clean one with data in cluster 5.
+fn check_refcounts(name: &str, refcounts: &[(u64, u64)]) -> (i32, String) {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let mut img = builder.build();
+    img.resize(img.len() + 65536, 0);
+    for &(cluster, refcount) in refcounts {
+        builder.set_refcount(&mut img, cluster, refcount);
+    }
+    let file = TempFile::with_contents(name, &img);
+    dump(&["check", file.path()])
+}
+
+#[test]
+fn check_leaks() {
+    let (code, out) = check_refcounts("leaks.qcow2", &[(6, 1)]);
+    assert_eq!(code, 3);
+    assert!(out.contains("1 leaked clusters were found on the image.\n"), "{}", out);
+    assert!(!out.contains("errors were found"), "{}", out);
+}
+
+#[test]
+fn check_corruptions() {
+    // Corruptions decide the exit code, even with leaks too.
+    let (code, out) = check_refcounts("corrupt.qcow2", &[(5, 0)]);
+    assert_eq!(code, 2);
+    assert!(out.contains("1 errors were found on the image.\n"), "{}", out);
+    let (code, _) = check_refcounts("corrupt-leaks.qcow2", &[(5, 0), (6, 1)]);
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn check_header_only() {
+    let (code, out) = dump(&["check", "--header-only", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert!(out.ends_with("No errors were found in the header.\n"), "{}", out);
+
+    let mut img = ImageBuilder::new().build();
+    img[32..36].copy_from_slice(&1u32.to_be_bytes());
+    img[20..24].copy_from_slice(&8u32.to_be_bytes());
+    let file = TempFile::with_contents("bad-header.qcow2", &img);
+    let (code, out) = dump(&["check", "--header-only", file.path()]);
+    assert_eq!(code, 2);
+    assert_eq!(out, "error: cluster_bits: bad cluster_bits 8 (at offset 0x14)\n\
+                     1 errors were found in the header.\n");
+}
+
+#[test]
+fn read() {
+    let (code, out) = dump(&["read", "tests/test.qcow2", "--offset", "200M", "--length", "11"]);
+    assert_eq!(code, 0);
+    assert_eq!(out, "Lorem ipsum");
+
+    let (code, out) = dump(&["read", "tests/test.qcow2", "--offset", "200M", "--length", "4K",
+                             "--hex"]);
+    assert_eq!(code, 0);
+    assert!(out.starts_with("0c800000  4c 6f 72 65 6d 20 69 70  73 75 6d 20 64 6f 6c 6f  \
+                             |Lorem ipsum dolo|\n"));
+    assert!(out.ends_with("\n*\n0c801000\n"));
+
+    // Reads are clamped to the end of the disk.
+    let (code, out) = dump(&["read", "tests/test.qcow2", "--offset", "1048575990"]);
+    assert_eq!(code, 0);
+    assert_eq!(out.len(), 10);
+}
+
+#[test]
+fn info_quiet() {
+    // Opening an image with a backing file doesn't write anything but the information.
+    let img = ImageBuilder::new().backing_file("base.qcow2").build();
+    let img = TempFile::with_contents("quiet.qcow2", &img);
+    let (code, out) = dump(&["info", img.path()]);
+    assert_eq!(code, 0);
+    assert!(out.starts_with("image: "), "{}", out);
+    assert!(out.contains("backing file: base.qcow2\n"), "{}", out);
+}
+
+#[test]
+fn extract() {
+    let img = TempFile::with_contents("extract.qcow2",
+                                      &ImageBuilder::new().data(1 << 16, b"data").build());
+    let out = TempFile::new("extract.raw");
+    let (code, stdout) = dump(&["extract", img.path(), out.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Wrote 65536 bytes, skipped 983040 bytes of zeros\n");
+
+    let raw = std::fs::read(&out.0).unwrap();
+    assert_eq!(raw.len(), 1 << 20);
+    assert_eq!(&raw[1 << 16..(1 << 16) + 4], b"data");
+
+    let jobs = TempFile::new("extract-jobs.raw");
+    let (code, stdout) = dump(&["extract", "--jobs", "3", img.path(), jobs.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "Wrote 65536 bytes, skipped 983040 bytes of zeros\n");
+    assert!(std::fs::read(&jobs.0).unwrap() == raw);
+}
+
+#[test]
+fn compare() {
+    let builder = ImageBuilder::new().data(1 << 16, b"data");
+    let img = TempFile::with_contents("compare.qcow2", &builder.build());
+    let mut raw = vec![0; 1 << 20];
+    raw[1 << 16..(1 << 16) + 4].copy_from_slice(b"data");
+    let raw = TempFile::with_contents("compare.raw", &raw);
+
+    let (code, out) = dump(&["compare", img.path(), "--format", "raw", raw.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(out, "Images are identical.\n");
+
+    // The raw view of the header differs from the guest data.
+    let (code, out) = dump(&["compare", "--format", "raw", img.path(), img.path()]);
+    assert_eq!(code, 1);
+    assert_eq!(out, "Content mismatch at offset 0!\n");
+
+    let (code, out) = dump(&["compare", "--quiet", "--strict", img.path(), img.path()]);
+    assert_eq!((code, out.as_str()), (0, ""));
+
+    let (code, _) = dump(&["compare", img.path(), "tests/does-not-exist.qcow2"]);
+    assert_eq!(code, 2);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn check_json() {
+    let (code, out) = dump(&["check", "--json", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert!(out.contains("\"image_end_offset\": 393216"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn info_json() {
+    let (code, out) = dump(&["info", "--json", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert!(out.contains("\"filename\": \"tests/test.qcow2\""));
+    assert!(out.contains("\"virtual_size\": 1048576000"));
+
+    let (_, out) = dump(&["info", "--json", "tests/test.qcow2", "tests/test.qcow2"]);
+    assert!(out.starts_with('['));
+}
+
+#[test]
+fn tables() {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let mut img = builder.build();
+    let l2 = 4 * 65536;
+    img[l2 + 8..l2 + 16].copy_from_slice(&((6u64 << 16) + 512).to_be_bytes());
+    let img = TempFile::with_contents("tables.qcow2", &img);
+
+    let (code, out) = dump(&["tables", img.path()]);
+    assert_eq!(code, 0);
+    assert!(out.starts_with("L1 table, 1 entries:\n"));
+    assert!(out.contains("       0  0x8000000000040000  0x0000000000040000  COPIED\n"));
+
+    let (code, out) = dump(&["tables", "--l2", "0", "--refcounts", img.path()]);
+    assert_eq!(code, 0);
+    assert!(out.contains("       1  0x0000000000010000  0x0000000000060200  0x0000000000060200  \
+                          MISALIGNED\n"));
+    assert!(out.contains("(8190 empty entries not shown)\n"));
+    assert!(out.contains("Refcount block 0 at 0x20000, nonzero entries:\n"));
+
+    let (code, _) = dump(&["tables", "--l2", "5", img.path()]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn tables_header() {
+    let mut img = ImageBuilder::new().backing_file("base.qcow2").build();
+    // Even an image that can't be opened has a header to show.
+    img[20..24].copy_from_slice(&99u32.to_be_bytes());
+    let img = TempFile::with_contents("tables-header.qcow2", &img);
+
+    let (code, out) = dump(&["tables", "--header", img.path()]);
+    assert_eq!(code, 0);
+    assert!(out.starts_with("Header, 20 parts:\n\nmagic: 4 bytes at 0x0\n\
+                             00000000  51 46 49 fb                                       |QFI.|\n\
+                             00000004\n"));
+    assert!(out.contains("cluster_bits: 4 bytes at 0x14\n00000014  00 00 00 63  "));
+    assert!(out.contains("backing file name: 10 bytes at 0x70\n"));
+    assert!(out.contains("|base.qcow2|\n0000007a\n"));
+
+    let (code, _) = dump(&["tables", "--header", "--l1", img.path()]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn chain() {
+    let (code, out) = dump(&["chain", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert_eq!(out,
+               "layer  format    virtual size       allocated  path\n    \
+                0  qcow2       1048576000           65536  tests/test.qcow2\n");
+
+    let img = ImageBuilder::new().backing_file("qcow2-dump-no-such-base.qcow2").build();
+    let img = TempFile::with_contents("chain.qcow2", &img);
+    let (code, out) = dump(&["chain", img.path()]);
+    assert_eq!(code, 2);
+    assert!(out.contains("    1  -                    -               -  "));
+    assert!(out.contains("       error: "));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn chain_json() {
+    let (code, out) = dump(&["chain", "--json", "tests/test.qcow2"]);
+    assert_eq!(code, 0);
+    assert!(out.starts_with('['));
+    assert!(out.contains("\"path\": \"tests/test.qcow2\""));
+    assert!(out.contains("\"format\": \"qcow2\""));
+}
+
+#[test]
+fn refcounts() {
+    let builder = ImageBuilder::new().data(0, b"data").snapshot(SnapshotSpec::new("1", "s"));
+    let mut img = builder.build();
+    // Leak a cluster at the end of the file.
+    let leaked = img.len() as u64 / 65536;
+    img.resize(img.len() + 65536, 0);
+    builder.set_refcount(&mut img, leaked, 1);
+    let img = TempFile::with_contents("refcounts.qcow2", &img);
+
+    let (code, out) = dump(&["refcounts", img.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(out,
+               format!("Refcounts of {}:\n\
+                        {:>12}  clusters in the file\n\
+                        {:>12}  allocated clusters\n\
+                        {:>12}  with refcount 1\n\
+                        {:>12}  with refcount 2\n\
+                        {:>12}  with refcount >2\n\
+                        {:>12}  leaked clusters\n",
+                       img.path(),
+                       leaked + 1,
+                       leaked + 1,
+                       leaked - 1,
+                       2,
+                       0,
+                       1));
+
+    let (code, out) = dump(&["refcounts", "--raw", "4..6", img.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(out,
+               "     cluster  offset              refcount\n           \
+                4  0x0000000000040000  2\n           5  0x0000000000050000  2\n");
+    let (code, out) = dump(&["refcounts", "--raw", "40000..40001", img.path()]);
+    assert_eq!(code, 0);
+    assert!(out.ends_with("- (no refcount block)\n"));
+
+    let (code, _) = dump(&["refcounts", "--raw", "6..4", img.path()]);
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn features() {
+    let img = ImageBuilder { incompatible: 1 << 4, ..ImageBuilder::new().compatible(1) }.build();
+    let img = TempFile::with_contents("features.qcow2", &img);
+    let (code, out) = dump(&["features", img.path()]);
+    assert_eq!(code, 0);
+    assert_eq!(out,
+               "Incompatible features:\n   \
+                4  unknown  (no name)                         BLOCKS OPENING\n\n\
+                Compatible features:\n   \
+                0  known    lazy refcounts\n\n\
+                Autoclear features:\n  (none)\n");
+}
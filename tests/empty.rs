@@ -0,0 +1,177 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::io::Cursor;
+use std::ops::ControlFlow;
+
+use common::{BitmapSpec, ImageBuilder, SnapshotSpec};
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{Advice, CopyOptions, CreateOptions, GuestRange, MeasureOptions, Preallocation, Qcow2,
+            ZeroMode, compare, copy_range};
+
+const CS: u64 = 65536;
+
+// An image with a virtual size of zero, in the builder's layout: the L1 table has no entries,
+// but still gets a cluster.
+fn empty() -> ImageBuilder {
+    ImageBuilder::new().size(0)
+}
+
+// An image with a virtual size of zero, as made by `qemu-img create -f qcow2 empty.qcow2 0`: just
+// the header, the refcount table and one refcount block. The L1 table has no entries, and no
+// offset either.
+fn qemu_empty() -> Vec<u8> {
+    let builder = empty();
+    let mut img = builder.build();
+    img[40..48].copy_from_slice(&0u64.to_be_bytes());
+    builder.set_refcount(&mut img, 3, 0);
+    img.truncate(3 * CS as usize);
+    img
+}
+
+#[test]
+fn empty_header() {
+    let qcow = Qcow2::open(qemu_empty()).unwrap();
+    assert_eq!(qcow.guest_size(), 0);
+    assert_eq!(qcow.geometry().l1_entries(), 0);
+    assert_eq!(qcow.geometry().clusters(), 0);
+    let info = qcow.info().unwrap();
+    assert_eq!(info.virtual_size, 0);
+    assert!(qcow.l1_table_entries().unwrap().is_empty());
+    assert!(qcow.check().unwrap().is_clean());
+    assert!(qcow2::validate(qemu_empty()).unwrap().is_valid());
+}
+
+#[test]
+fn empty_read() {
+    let qcow = Qcow2::open(qemu_empty()).unwrap();
+    let reader = qcow.reader().unwrap();
+    assert_eq!(reader.size().unwrap(), Some(0));
+    let mut buf = [1; 16];
+    for &pos in &[0, 1, 65536, u64::MAX] {
+        assert_eq!(reader.read_at(pos, &mut buf).unwrap(), 0);
+    }
+    assert_eq!(buf, [1; 16]);
+    reader.read_exact_at(0, &mut []).unwrap();
+    assert!(reader.read_exact_at(0, &mut buf).is_err());
+
+    let owned = Qcow2::open(qemu_empty()).unwrap().into_reader().unwrap();
+    assert_eq!(owned.size().unwrap(), Some(0));
+    assert_eq!(owned.read_at(0, &mut buf).unwrap(), 0);
+    let dyn_reader = Qcow2::open(qemu_empty()).unwrap().into_dyn_reader().unwrap();
+    assert_eq!(dyn_reader.read_at(0, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn empty_iterate() {
+    let qcow = Qcow2::open(qemu_empty()).unwrap();
+    let reader = qcow.reader().unwrap();
+    assert_eq!(reader.clusters().count(), 0);
+    reader.for_each_cluster(|_, _| panic!("no clusters")).unwrap();
+    assert!(reader.map().unwrap().is_empty());
+
+    let stats = qcow.allocation_stats().unwrap();
+    assert_eq!((stats.data_clusters, stats.zero_clusters), (0, 0));
+    let measured = qcow.measure(&MeasureOptions::new()).unwrap();
+    assert_eq!(measured.required, measured.fully_allocated);
+    let warmed = qcow.warm(GuestRange { offset: 0, len: 1 << 20 }).unwrap();
+    assert_eq!(warmed.tables, 0);
+
+    let mut reader = qcow.reader().unwrap();
+    reader.advise(Advice::WillNeed(GuestRange { offset: 0, len: 65536 })).unwrap();
+    reader.advise(Advice::Sequential).unwrap();
+    assert_eq!(reader.read_at(0, &mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn empty_snapshot() {
+    let img = empty().snapshot(SnapshotSpec::new("1", "empty")).build();
+    let qcow = Qcow2::open(img).unwrap();
+    assert_eq!(qcow.diff_snapshot("1").unwrap().count(), 0);
+    let reader = qcow.snapshot_reader("1").unwrap();
+    assert_eq!(reader.read_at(0, &mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn empty_bitmap() {
+    // The bitmaps extension is only valid with the autoclear bit for bitmaps set.
+    let img = empty().autoclear(1).bitmap(BitmapSpec::new("b", 16)).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let bitmap = &qcow.bitmaps().unwrap()[0];
+    assert!(bitmap.dirty_ranges(&qcow).unwrap().is_empty());
+    let reader = qcow.reader().unwrap();
+    assert_eq!(bitmap.backup_extents(&reader).unwrap().count(), 0);
+}
+
+#[test]
+fn empty_export() {
+    let qcow = Qcow2::open(empty().build()).unwrap();
+    let reader = qcow.reader().unwrap();
+    for &sparse in &[false, true] {
+        let mut out = Cursor::new(Vec::new());
+        let stats = reader.export_raw(&mut out, sparse).unwrap();
+        assert_eq!(stats.written + stats.skipped, 0);
+        assert!(out.into_inner().is_empty());
+
+        let mut out = Cursor::new(Vec::new());
+        let progress = |_, _, _| ControlFlow::Continue(());
+        reader.export_raw_parallel(&mut out, sparse, 4, &progress).unwrap();
+        assert!(out.into_inner().is_empty());
+        let mut out = Vec::new();
+        reader.export_raw_at(&mut out, sparse, 4, &progress).unwrap();
+        assert!(out.is_empty());
+    }
+}
+
+#[test]
+fn empty_compare() {
+    let a = Qcow2::open(empty().build()).unwrap();
+    let b = Qcow2::open(empty().cluster_bits(9).build()).unwrap();
+    let (a, b) = (a.reader().unwrap(), b.reader().unwrap());
+    assert_eq!(compare(&a, &b).unwrap(), None);
+    assert_eq!(a.compare_strict(&b).unwrap(), None);
+    assert!(compare(&a, &Vec::new()).unwrap().is_none());
+}
+
+#[test]
+fn empty_copy() {
+    let src = Qcow2::open(empty().build()).unwrap();
+    let reader = src.reader().unwrap();
+    let mut copy = Vec::new();
+    let created = Qcow2::create_from(&reader, &mut copy, &CreateOptions::new()).unwrap();
+    assert_eq!(created.guest_size(), 0);
+    drop(created);
+    let copied = Qcow2::open(copy).unwrap();
+    assert_eq!(copied.guest_size(), 0);
+    assert!(copied.check().unwrap().is_clean());
+
+    let mut dst = Qcow2::open(ImageBuilder::new().build()).unwrap();
+    let mut writer = dst.writer().unwrap();
+    let stats = copy_range(&reader, 0, &mut writer, 0, 1 << 20, &CopyOptions::new()).unwrap();
+    assert_eq!(stats.total(), 0);
+}
+
+#[test]
+fn empty_write() {
+    let mut img = qemu_empty();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        let mut writer = qcow.writer().unwrap();
+        assert_eq!(writer.write_at(0, &[1; 16]).unwrap(), 0);
+        writer.preallocate(0, 0, Preallocation::Full).unwrap();
+        writer.zero_entire_image(ZeroMode::Discard, true).unwrap();
+        drop(writer);
+        assert!(qcow.check().unwrap().is_clean());
+
+        // The image can grow from nothing.
+        qcow.resize(65536).unwrap();
+        qcow.writer().unwrap().write_all_at(100, &[1; 16]).unwrap();
+    }
+    let qcow = Qcow2::open(img).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(100, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}
@@ -0,0 +1,51 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::io::{self, ErrorKind};
+
+use common::ImageBuilder;
+use positioned_io::ReadAt;
+use qcow2::{Error, Qcow2};
+
+#[test]
+fn io_error_kinds() {
+    let kind = |e: Error| io::Error::from(e).kind();
+    assert_eq!(kind(Error::FileType), ErrorKind::InvalidData);
+    assert_eq!(kind(Error::FileFormat("bad".to_owned())), ErrorKind::InvalidData);
+    assert_eq!(kind(Error::Version(4)), ErrorKind::Unsupported);
+    assert_eq!(kind(Error::UnsupportedFeature("new".to_owned())), ErrorKind::Unsupported);
+    assert_eq!(kind(Error::NoSnapshot("snap".to_owned())), ErrorKind::NotFound);
+    assert_eq!(kind(Error::Internal("bug".to_owned())), ErrorKind::Other);
+    assert_eq!(kind(Error::Cancelled), ErrorKind::Other);
+
+    // I/O errors aren't wrapped again.
+    let err = io::Error::from(Error::Io(io::Error::from(ErrorKind::TimedOut)));
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(err.get_ref().is_none());
+}
+
+#[test]
+fn io_error_round_trip() {
+    let err = io::Error::from(Error::FileFormat("bad".to_owned()));
+    match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::FileFormat(msg)) if msg == "bad" => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+    match Error::from(err) {
+        Error::FileFormat(msg) if msg == "bad" => {}
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn reader_error_kind() {
+    let mut img = ImageBuilder::new().data(65536, b"data").build();
+    img.truncate(5 * 65536 + 2);
+    let qcow = Qcow2::open(img).unwrap();
+    let mut buf = [0; 4];
+    let err = qcow.reader().unwrap().read_exact_at(65536, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(matches!(err.get_ref().and_then(|e| e.downcast_ref()), Some(Error::FileFormat(_))));
+}
@@ -0,0 +1,133 @@
+extern crate qcow2;
+
+mod common;
+
+use std::cell::Cell;
+use std::io::Cursor;
+use std::ops::ControlFlow;
+
+use common::ImageBuilder;
+use qcow2::{Error, ExportStats, Qcow2};
+
+#[test]
+fn export_sparse() {
+    let img = ImageBuilder::new()
+        .size(4 << 16)
+        .data(1 << 16, b"data")
+        .data(2 << 16, &[0; 16])
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    let mut expected = vec![0; 4 << 16];
+    expected[1 << 16..(1 << 16) + 4].copy_from_slice(b"data");
+
+    // Allocated clusters of zeros are skipped too, and the output still gets its full size.
+    let mut out = Cursor::new(Vec::new());
+    let stats = reader.export_raw(&mut out, true).unwrap();
+    assert_eq!(stats, ExportStats { written: 1 << 16, skipped: 3 << 16 });
+    assert_eq!(out.into_inner(), expected);
+
+    let mut out = Cursor::new(Vec::new());
+    let mut calls = 0;
+    let stats = reader.export_raw_with_progress(&mut out, false, |_| calls += 1).unwrap();
+    assert_eq!(stats, ExportStats { written: 4 << 16, skipped: 0 });
+    assert_eq!(calls, 4);
+    assert_eq!(out.into_inner(), expected);
+}
+
+#[test]
+fn export_partial_cluster() {
+    let img = ImageBuilder::new().size(100000).data(1 << 16, b"tail").build();
+    let qcow = Qcow2::open(img).unwrap();
+    let mut out = Cursor::new(Vec::new());
+    let stats = qcow.reader().unwrap().export_raw(&mut out, true).unwrap();
+    assert_eq!(stats, ExportStats { written: 100000 - (1 << 16), skipped: 1 << 16 });
+    let out = out.into_inner();
+    assert_eq!(out.len(), 100000);
+    assert_eq!(&out[1 << 16..(1 << 16) + 4], b"tail");
+}
+
+#[test]
+fn export_parallel() {
+    // Each thread reads 1 MiB at a time, which is many clusters.
+    let cs = 512;
+    let img = ImageBuilder::new()
+        .cluster_bits(9)
+        .size((4 << 20) + 1000)
+        .data(0, b"first")
+        .data(cs, b"second")
+        .data(3 * cs, &[0; 16])
+        .data((1 << 20) - cs, b"end of chunk")
+        .data(3 << 20, b"later")
+        .data(4 << 20, b"tail")
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    for &sparse in &[true, false] {
+        let mut expected = Cursor::new(Vec::new());
+        let expected_stats = reader.export_raw(&mut expected, sparse).unwrap();
+        let expected = expected.into_inner();
+        for &parallelism in &[0, 1, 3, 8] {
+            let mut out = Cursor::new(Vec::new());
+            let calls = Cell::new(0);
+            let progress = |_, _, _| {
+                calls.set(calls.get() + 1);
+                ControlFlow::Continue(())
+            };
+            let stats = reader.export_raw_parallel(&mut out, sparse, parallelism, &progress)
+                .unwrap();
+            assert_eq!(stats, expected_stats);
+            assert_eq!(calls.get(), 5);
+            assert!(out.into_inner() == expected);
+
+            let mut out = Vec::new();
+            let stats = reader.export_raw_at(&mut out, sparse, parallelism, &progress).unwrap();
+            assert_eq!(stats, expected_stats);
+            assert!(out == expected);
+        }
+    }
+}
+
+#[test]
+fn export_parallel_error() {
+    let builder = ImageBuilder::new().size(4 << 20).data(0, b"a").data(3 << 20, b"b");
+    let mut img = builder.build();
+    // Lose the last data cluster.
+    img.truncate(img.len() - (1 << 16));
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut out = Cursor::new(Vec::new());
+    let progress = |_, _, _| ControlFlow::Continue(());
+    assert!(reader.export_raw_parallel(&mut out, true, 4, &progress).is_err());
+    assert!(reader.export_raw_at(&mut Vec::new(), true, 2, &progress).is_err());
+}
+
+#[test]
+fn export_reporting() {
+    let mb = 1 << 20;
+    let img = ImageBuilder::new().size(4 * mb).data(0, b"first").data(2 * mb, b"later").build();
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+
+    let calls = Cell::new(0);
+    let progress = |done, total, physical| {
+        calls.set(calls.get() + 1);
+        assert_eq!((done, total), (calls.get() * mb, 4 * mb));
+        assert_eq!(physical, if done > 2 * mb { 2 } else { 1 } * 65536);
+        ControlFlow::Continue(())
+    };
+    let mut out = Cursor::new(Vec::new());
+    reader.export_raw_reporting(&mut out, true, &progress).unwrap();
+    assert_eq!(calls.get(), 4);
+    assert_eq!(out.into_inner().len() as u64, 4 * mb);
+
+    // Cancelling stops right after the report.
+    let cancel = |done, _, _| {
+        if done >= 2 * mb { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    };
+    let mut out = Cursor::new(Vec::new());
+    assert!(matches!(reader.export_raw_reporting(&mut out, false, &cancel), Err(Error::Cancelled)));
+    assert_eq!(out.into_inner().len() as u64, 2 * mb);
+}
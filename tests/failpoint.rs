@@ -0,0 +1,124 @@
+#![cfg(feature = "testing")]
+
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::{ImageBuilder, SnapshotSpec};
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::{FailpointIo, Fault, IoOp, Qcow2};
+
+type Change = fn(&mut FailpointIo<Vec<u8>>) -> qcow2::Result<()>;
+
+// Make a change to an image, failing in turn at each write it does. Afterwards, the image must
+// open and may leak clusters, but never be corrupt. Returns the number of writes.
+fn fail_each_write(img: &[u8], fault: Fault, change: Change) -> u64 {
+    let mut io = FailpointIo::new(img.to_vec());
+    change(&mut io).unwrap();
+    let writes = io.writes();
+    assert!(writes > 0);
+    for n in 0..writes {
+        let mut io = FailpointIo::new(img.to_vec());
+        io.fail_write(n, fault);
+        assert!(change(&mut io).is_err(), "write {} didn't fail", n);
+        let qcow = Qcow2::open(io.into_inner()).unwrap();
+        let result = qcow.check().unwrap();
+        assert_eq!(result.corruptions, 0, "write {}: {:?}", n, result.findings);
+        assert!(result.findings.iter().all(|f| f.is_leak()), "write {}", n);
+    }
+    writes
+}
+
+fn write_new(io: &mut FailpointIo<Vec<u8>>) -> qcow2::Result<()> {
+    let mut qcow = Qcow2::open(io)?;
+    qcow.writer()?.write_all_at(70000, &[2; 16])?;
+    qcow.sync()
+}
+
+fn write_snapshot(io: &mut FailpointIo<Vec<u8>>) -> qcow2::Result<()> {
+    let mut qcow = Qcow2::open(io)?;
+    qcow.writer()?.write_all_at(8, &[2; 4])?;
+    qcow.sync()
+}
+
+fn resize(io: &mut FailpointIo<Vec<u8>>) -> qcow2::Result<()> {
+    let mut qcow = Qcow2::open(io)?;
+    // Far enough that the L1 table must move.
+    qcow.resize(1 << 40)?;
+    qcow.sync()
+}
+
+fn lazy_refcounts(io: &mut FailpointIo<Vec<u8>>) -> qcow2::Result<()> {
+    let mut qcow = Qcow2::open(io)?;
+    qcow.set_lazy_refcounts(true)?;
+    qcow.sync()
+}
+
+#[test]
+fn crash_writing() {
+    let img = ImageBuilder::new().data(0, &[1; 16]).build();
+    fail_each_write(&img, Fault::Crash, write_new);
+    fail_each_write(&img, Fault::Error, write_new);
+}
+
+#[test]
+fn crash_writing_snapshot() {
+    let img = ImageBuilder::new().data(0, &[1; 16]).snapshot(SnapshotSpec::new("1", "s")).build();
+    fail_each_write(&img, Fault::Crash, write_snapshot);
+}
+
+#[test]
+fn crash_resizing() {
+    fail_each_write(&ImageBuilder::new().build(), Fault::Crash, resize);
+}
+
+#[test]
+fn crash_amending() {
+    fail_each_write(&ImageBuilder::new().build(), Fault::Crash, lazy_refcounts);
+}
+
+#[test]
+fn failpoint_faults() {
+    let img = ImageBuilder::new().build();
+
+    // A crash fails everything afterwards.
+    let mut io = FailpointIo::new(img.clone());
+    io.fail_write(0, Fault::Crash);
+    assert!(write_new(&mut io).is_err());
+    assert!(io.crashed());
+    assert!(io.read_at(0, &mut [0; 4]).is_err());
+    assert_eq!(io.writes(), 1);
+    assert_eq!(io.get_ref(), &img);
+
+    // Short writes are retried, so nothing is lost.
+    let mut io = FailpointIo::new(img.clone());
+    io.fail_write(0, Fault::Short(3));
+    write_new(&mut io).unwrap();
+    let qcow = Qcow2::open(io.into_inner()).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(70000, &mut buf).unwrap();
+    assert_eq!(buf, [2; 16]);
+
+    // A dropped write is lost without anyone knowing.
+    let mut io = FailpointIo::new(img.clone());
+    for n in 0..100 {
+        io.fail_write(n, Fault::Drop);
+    }
+    write_new(&mut io).unwrap();
+    assert_eq!(io.into_inner(), img);
+}
+
+#[test]
+fn failpoint_journal() {
+    let mut io = FailpointIo::new(ImageBuilder::new().build());
+    write_new(&mut io).unwrap();
+    let journal = io.journal();
+    assert_eq!(journal.last(), Some(&IoOp::Sync));
+    // Guest data is written and synced before anything points at it.
+    let data = journal.iter().position(|op| *op == IoOp::Write { pos: 5 << 16, len: 65536 });
+    let l2 = journal.iter().position(|op| matches!(*op, IoOp::Write { pos, .. } if pos == 4 << 16));
+    let (data, l2) = (data.unwrap(), l2.unwrap());
+    assert!(data < l2);
+    assert!(journal[data..l2].contains(&IoOp::Sync));
+}
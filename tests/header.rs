@@ -0,0 +1,524 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::path::Path;
+
+use positioned_io::ReadAt;
+
+use common::{feature_name, CountingIo, ImageBuilder, SnapshotSpec, EXT_FEATURE_NAME_TABLE};
+use qcow2::{Error, FeatureKind, OpenOptions, Qcow2, Severity};
+
+// Describe an unknown incompatible feature with a name, as `Feature::to_string` does when it
+// stops the image from opening.
+fn unknown_feature_string(name: &[u8]) -> String {
+    let builder = ImageBuilder::new().extension(EXT_FEATURE_NAME_TABLE, feature_name(0, 20, name));
+    let img = ImageBuilder { incompatible: 1 << 20, ..builder }.build();
+    match Qcow2::open(img) {
+        Err(Error::UnsupportedFeature(s)) => s,
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn utf8_feature_name() {
+    let img = ImageBuilder::new()
+        .compatible(1 << 5)
+        .extension(EXT_FEATURE_NAME_TABLE,
+                   feature_name(1, 5, "größe ✓".as_bytes()))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let names: Vec<_> = qcow.feature_name_table().iter().collect();
+    assert_eq!(names, vec![(FeatureKind::Compatible, 5, "größe ✓")]);
+    assert!(unknown_feature_string("größe ✓".as_bytes()).contains("größe ✓"));
+}
+
+#[test]
+fn invalid_utf8_feature_name() {
+    let img = ImageBuilder::new()
+        .compatible(1 << 5)
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"bad \xff name"))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let names: Vec<_> = qcow.feature_name_table().iter().collect();
+    assert_eq!(names, vec![(FeatureKind::Compatible, 5, "bad \u{fffd} name")]);
+    assert!(unknown_feature_string(b"bad \xff name").contains("bad \u{fffd} name"));
+}
+
+#[test]
+fn feature_name_table_iter() {
+    let mut table = feature_name(1, 5, b"five");
+    table.extend(feature_name(0, 7, b"seven"));
+    let img = ImageBuilder::new()
+        .extension(EXT_FEATURE_NAME_TABLE, table)
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let names: Vec<_> = qcow.feature_name_table().iter().collect();
+    assert_eq!(names,
+               vec![(FeatureKind::Compatible, 5, "five"), (FeatureKind::Incompatible, 7, "seven")]);
+}
+
+#[test]
+fn feature_name_table_bundled() {
+    let file = std::fs::File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let table = qcow.feature_name_table();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.name(FeatureKind::Compatible, 0), "lazy refcounts");
+}
+
+// Build an image whose header has a given length, with extensions after it.
+fn with_header_length(header_length: u32) -> Vec<u8> {
+    let builder = ImageBuilder::new()
+        .compatible(1 << 5)
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"five"))
+        .data(0, b"data");
+    ImageBuilder { header_length, ..builder }.build()
+}
+
+#[test]
+fn header_length_variants() {
+    // qemu before 5.0 writes 104 bytes, later versions add a compression type and padding.
+    for &len in &[104, 112, 120] {
+        let mut img = with_header_length(len);
+        if len > 104 {
+            img[104] = 0; // zlib compression
+            img[105..len as usize].fill(0xaa);
+        }
+        let qcow = Qcow2::open(img).unwrap();
+        assert_eq!(qcow.feature_name_table().name(FeatureKind::Compatible, 5), "five");
+        let mut buf = [0; 4];
+        qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+    }
+}
+
+#[test]
+fn header_length_invalid() {
+    // The header can't be shorter than the fields we read, so make it claim to be.
+    let mut short = with_header_length(104);
+    short[100..104].copy_from_slice(&96u32.to_be_bytes());
+    let cases = vec![(short, "header is 104 bytes, file claims 96"),
+                     (with_header_length(108), "header length 108 is not a multiple of 8"),
+                     (with_header_length(1 << 17), "complete header too big for first cluster")];
+    for (img, msg) in cases {
+        match Qcow2::open(img) {
+            Err(Error::FileFormat(ref m)) => assert_eq!(m, msg),
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
+
+// Give an image a backing file name at an offset, followed by garbage to the end of the first
+// cluster.
+fn with_backing_file(mut img: Vec<u8>, offset: usize, name: &[u8]) -> Vec<u8> {
+    img[8..16].copy_from_slice(&(offset as u64).to_be_bytes());
+    img[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+    img[offset..offset + name.len()].copy_from_slice(name);
+    img[offset + name.len()..65536].fill(0xab);
+    img
+}
+
+#[test]
+fn backing_file_trailing_garbage() {
+    // Extensions end at 168, the name may follow immediately or after a gap.
+    for &offset in &[168, 1024] {
+        let img = with_backing_file(with_header_length(104), offset, b"base.qcow2");
+        let qcow = Qcow2::open(img).unwrap();
+        let info = qcow.info().unwrap();
+        assert_eq!(info.backing_file, Some("base.qcow2".into()));
+        assert_eq!(qcow.feature_name_table().name(FeatureKind::Compatible, 5), "five");
+
+        // Reading needs the backing file to be attached.
+        match qcow.reader() {
+            Err(Error::UnsupportedFeature(ref f)) => {
+                assert_eq!(f, "backing file that was not attached")
+            }
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn backing_file_overlaps_extensions() {
+    // Point the name into the feature name table.
+    let mut img = with_header_length(104);
+    img[8..16].copy_from_slice(&112u64.to_be_bytes());
+    img[16..20].copy_from_slice(&10u32.to_be_bytes());
+    match Qcow2::open(img) {
+        Err(Error::FileFormat(ref m)) => {
+            assert_eq!(m, "backing file name overlaps header extensions")
+        }
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn header_info() {
+    let img = ImageBuilder::new()
+        .size(1 << 30)
+        .compatible(1)
+        .data(0, b"data")
+        .build();
+    let info = Qcow2::open(img).unwrap().header_info();
+    assert_eq!(info.version, 3);
+    assert_eq!(info.backing_file_offset, 0);
+    assert_eq!(info.cluster_bits, 16);
+    assert_eq!(info.size, 1 << 30);
+    assert_eq!(info.crypt_method, 0);
+    // The layout of the builder's images is fixed.
+    assert_eq!(info.l1_size, 2);
+    assert_eq!(info.l1_table_offset, 3 << 16);
+    assert_eq!(info.refcount_table_offset, 1 << 16);
+    assert_eq!(info.refcount_table_clusters, 1);
+    assert_eq!(info.nb_snapshots, 0);
+    assert_eq!(info.incompatible_features, 0);
+    assert_eq!(info.compatible_features, 1);
+    assert_eq!(info.autoclear_features, 0);
+    assert_eq!(info.refcount_order, 4);
+    assert_eq!(info.header_length, 104);
+}
+
+#[test]
+fn open_read_count() {
+    // Over a network every read is a round trip, so the header is read at once.
+    let img = ImageBuilder::new()
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"feature"))
+        .backing_file("base.qcow2")
+        .build();
+    let io = CountingIo::new(img);
+    Qcow2::open(&io).unwrap();
+    assert!(io.reads() <= 2, "{} reads", io.reads());
+
+    // The snapshot table takes one more read, however many snapshots there are.
+    let img = ImageBuilder::new()
+        .snapshot(SnapshotSpec::new("1", "first"))
+        .snapshot(SnapshotSpec::new("2", "a snapshot with a longer name"))
+        .snapshot(SnapshotSpec::new("3", "third"))
+        .build();
+    let io = CountingIo::new(img);
+    let qcow = Qcow2::open(&io).unwrap();
+    assert_eq!(qcow.snapshots().len(), 3);
+    assert!(io.reads() <= 3, "{} reads", io.reads());
+}
+
+#[test]
+fn open_many_snapshots_claimed() {
+    // A header claiming the most snapshots allowed, in a file just big enough for their fixed
+    // parts, but with junk after the one real entry.
+    let mut img = ImageBuilder::new().snapshot(SnapshotSpec::new("1", "only")).build();
+    let count = 65535;
+    img[60..64].copy_from_slice(&(count as u32).to_be_bytes());
+    let table = u64::from_be_bytes(img[64..72].try_into().unwrap()) as usize;
+    img.resize(table + count * 40, 0xff);
+
+    // The table isn't read with room for the biggest entries allowed, which would take 64 MiB.
+    let io = CountingIo::new(img);
+    let err = Qcow2::open(&io).unwrap_err().to_string();
+    assert!(err.starts_with("Malformed qcow2 file: snapshot "), "{}", err);
+    assert!(io.largest() <= 64 * 1024, "read of {} bytes", io.largest());
+    assert!(io.reads() <= 4, "{} reads", io.reads());
+}
+
+#[test]
+fn header_past_first_read() {
+    // A feature name table bigger than the first read.
+    let names: Vec<u8> = (0..3)
+        .flat_map(|kind| (0..64).flat_map(move |bit| feature_name(kind, bit, b"feature")))
+        .collect();
+    let img = ImageBuilder::new()
+        .extension(EXT_FEATURE_NAME_TABLE, names)
+        .backing_file("base.qcow2")
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("base.qcow2")));
+}
+
+// The backing file name stored in an image, after its header is rewritten.
+fn rewritten_backing_name(mut img: Vec<u8>) -> Vec<u8> {
+    Qcow2::open(&mut img).unwrap().set_feature_name_table(Default::default()).unwrap();
+    let offset = u64::from_be_bytes(img[8..16].try_into().unwrap()) as usize;
+    let len = u32::from_be_bytes(img[16..20].try_into().unwrap()) as usize;
+    img[offset..offset + len].to_vec()
+}
+
+#[test]
+fn backing_file_name_utf8() {
+    let img = ImageBuilder::new().backing_file("größe ✓.qcow2").build();
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("größe ✓.qcow2")));
+    assert_eq!(rewritten_backing_name(img), "größe ✓.qcow2".as_bytes());
+}
+
+#[cfg(unix)]
+#[test]
+fn backing_file_name_bytes() {
+    use std::os::unix::ffi::OsStrExt;
+
+    // Paths are bytes here, so a name that isn't UTF-8 is kept exactly.
+    let img = ImageBuilder::new().backing_file_bytes(b"bad \xff.qcow2").build();
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    assert_eq!(qcow.backing_file_name().unwrap().as_os_str().as_bytes(), b"bad \xff.qcow2");
+    assert_eq!(rewritten_backing_name(img), b"bad \xff.qcow2");
+}
+
+#[cfg(windows)]
+#[test]
+fn backing_file_name_lossy() {
+    // A name that isn't UTF-8 can't be a Windows path, so invalid bytes are replaced, and the
+    // replacement is what gets written back.
+    let img = ImageBuilder::new().backing_file_bytes(b"bad \xff.qcow2").build();
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("bad \u{fffd}.qcow2")));
+    assert_eq!(rewritten_backing_name(img), "bad \u{fffd}.qcow2".as_bytes());
+}
+
+#[test]
+fn geometry() {
+    let img = ImageBuilder::new().cluster_bits(9).size(100 * 512 + 7).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let geometry = qcow.geometry();
+    assert_eq!(geometry.cluster_size(), 512);
+    assert_eq!(geometry.l2_coverage(), 64 * 512);
+    assert_eq!(geometry.clusters(), 101);
+    assert_eq!(geometry.l1_entries(), 2);
+    assert_eq!(geometry.split(0), (0, 0, 0));
+    assert_eq!(geometry.split(64 * 512 - 1), (0, 63, 511));
+    assert_eq!(geometry.split(65 * 512 + 3), (1, 1, 3));
+
+    let range = geometry.cluster_containing(512 + 5).unwrap();
+    assert_eq!((range.offset, range.len), (512, 512));
+    // The last cluster is cut short.
+    let range = geometry.cluster_containing(100 * 512 + 6).unwrap();
+    assert_eq!((range.offset, range.len), (100 * 512, 7));
+    assert!(geometry.cluster_containing(100 * 512 + 7).is_none());
+}
+
+// Build an image laid out like `qemu-img create -o cluster_size=512` makes them. The header,
+// its padding and qemu's feature name table fill the first cluster exactly.
+fn qemu_small_cluster_image(extra_name: bool) -> Vec<u8> {
+    let mut names: Vec<(u8, u8, &[u8])> = vec![(0, 0, b"dirty bit"),
+                                               (0, 1, b"corrupt bit"),
+                                               (0, 2, b"external data file"),
+                                               (0, 3, b"compression type"),
+                                               (0, 4, b"extended L2 entries"),
+                                               (1, 0, b"lazy refcounts"),
+                                               (2, 0, b"bitmaps"),
+                                               (2, 1, b"raw external data")];
+    if extra_name {
+        names.push((1, 1, b"one too many"));
+    }
+    let table = names.into_iter().flat_map(|(k, b, n)| feature_name(k, b, n)).collect();
+    let builder = ImageBuilder::new()
+        .cluster_bits(9)
+        .extension(EXT_FEATURE_NAME_TABLE, table)
+        .data(512, b"data");
+    ImageBuilder { header_length: 112, ..builder }.build()
+}
+
+#[test]
+fn small_cluster_header() {
+    let mut img = qemu_small_cluster_image(false);
+    let layout = qcow2::header_layout(&img[..]).unwrap();
+    let end = layout.last().unwrap();
+    assert_eq!((end.name.as_str(), end.offset + end.len), ("end of extensions", 512));
+
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        assert_eq!(qcow.cluster_size(), 512);
+        assert_eq!(qcow.feature_name_table().iter().count(), 8);
+        let mut buf = [0; 4];
+        qcow.reader().unwrap().read_exact_at(512, &mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+        assert!(qcow.check().unwrap().is_clean());
+
+        // Rewriting the header still fits.
+        let table = qcow.feature_name_table().clone();
+        qcow.set_feature_name_table(table).unwrap();
+    }
+    assert_eq!(Qcow2::open(&mut img).unwrap().feature_name_table().iter().count(), 8);
+
+    // Like qemu, extensions must end in the first cluster, where nothing else can be.
+    assert_eq!(open_error(qemu_small_cluster_image(true)),
+               "complete header too big for first cluster");
+}
+
+// Build an image with many unknown header extensions.
+fn with_extensions(cluster_bits: u32, count: u32, len: usize) -> Vec<u8> {
+    (0..count)
+        .fold(ImageBuilder::new().cluster_bits(cluster_bits),
+              |b, i| b.extension(0x1000_0000 + i, vec![0; len]))
+        .build()
+}
+
+fn open_error(img: Vec<u8>) -> String {
+    match Qcow2::open(img) {
+        Err(Error::FileFormat(msg)) => msg,
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn extension_limits() {
+    let img = with_extensions(16, 100, 0);
+    assert_eq!(open_error(img.clone()), "more than 64 header extensions");
+    OpenOptions::new().max_header_extensions(100).open(img).unwrap();
+    assert!(Qcow2::open(with_extensions(16, 64, 0)).is_ok());
+
+    // Tiny extensions filling a huge first cluster are caught early.
+    assert_eq!(open_error(with_extensions(21, 200_000, 0)), "more than 64 header extensions");
+
+    assert_eq!(open_error(with_extensions(18, 2, 40_000)), "unknown header extensions too big");
+}
+
+#[test]
+fn duplicate_extensions() {
+    // Some tools write several vendor extensions with the same code.
+    let img = ImageBuilder::new()
+        .extension(0x1234_5678, b"one\0\0\0\0\0".to_vec())
+        .extension(0x1234_5678, b"two\0\0\0\0\0".to_vec())
+        .build();
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    let unknown: Vec<_> = qcow.unknown_extensions()
+        .iter()
+        .map(|e| (e.code(), &e.data()[..3]))
+        .collect();
+    assert_eq!(unknown, vec![(0x1234_5678, &b"one"[..]), (0x1234_5678, &b"two"[..])]);
+    assert!(qcow.check().unwrap().is_clean());
+
+    // Strict mode reports them.
+    let qcow = OpenOptions::new().strict(true).open(img).unwrap();
+    let findings = qcow.check().unwrap().findings;
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].to_string(),
+               "ERROR at offset 0x78: duplicate header extension 0x12345678");
+
+    // Duplicates of extensions we understand are still errors.
+    let img = ImageBuilder::new()
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 5, b"five"))
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(1, 6, b"six"))
+        .build();
+    assert_eq!(open_error(img), "duplicate header extension 0x6803f857");
+}
+
+#[test]
+fn validate_report() {
+    let img = ImageBuilder::new().extension(0x1234_5678, vec![1; 3]).build();
+    let report = qcow2::validate(&img[..]).unwrap();
+    assert!(report.is_valid(), "{}", report);
+    let notes: Vec<_> = report.findings.iter().map(|f| f.to_string()).collect();
+    assert_eq!(notes,
+               vec!["info: header_length: header is 104 bytes (at offset 0x64)",
+                    "info: extensions: unknown header extension 0x12345678, 3 bytes (at offset \
+                     0x68)"]);
+
+    // Every problem is reported, not just the first.
+    let mut bad = img.clone();
+    bad[32..36].copy_from_slice(&1u32.to_be_bytes());
+    bad[40..48].copy_from_slice(&(3 * 65536 + 8u64).to_be_bytes());
+    bad[72..80].copy_from_slice(&0b11u64.to_be_bytes());
+    bad[104 + 8 + 4] = 1;
+    bad.truncate(3 * 65536);
+    let report = qcow2::validate(&bad[..]).unwrap();
+    assert!(!report.is_valid());
+    let fields: Vec<_> = report.findings
+        .iter()
+        .filter(|f| f.severity != Severity::Info)
+        .map(|f| (f.severity, f.field.as_str()))
+        .collect();
+    assert_eq!(fields,
+               vec![(Severity::Error, "crypt_method"),
+                    (Severity::Error, "l1_table_offset"),
+                    (Severity::Warning, "extensions"),
+                    (Severity::Warning, "incompatible_features"),
+                    (Severity::Error, "incompatible_features"),
+                    (Severity::Error, "l1_table_offset")]);
+    assert_eq!(report.count(Severity::Error), 4);
+
+    // Only failing to read the first cluster is an error.
+    assert_eq!(qcow2::validate(&b"QFI"[..]).unwrap().findings[0].message, "not a qcow2 file");
+}
+
+// Get the names and ranges of the parts of a header.
+fn layout(img: &[u8]) -> Vec<(String, u64, u64)> {
+    qcow2::header_layout(img).unwrap().into_iter().map(|s| (s.name, s.offset, s.len)).collect()
+}
+
+#[test]
+fn header_layout() {
+    let mut builder = ImageBuilder::new()
+        .extension(0x1234, vec![7; 5])
+        .backing_file("base.qcow2");
+    builder.header_length = 112;
+    let img = builder.build();
+    let spans = layout(&img);
+    assert_eq!(spans[0], ("magic".to_owned(), 0, 4));
+    assert_eq!(spans[17], ("header_length".to_owned(), 100, 4));
+    assert_eq!(&spans[18..], &[
+        ("compression_type".to_owned(), 104, 1),
+        ("header padding".to_owned(), 105, 7),
+        ("extension[0x1234] type".to_owned(), 112, 4),
+        ("extension[0x1234] length".to_owned(), 116, 4),
+        ("extension[0x1234] payload".to_owned(), 120, 5),
+        ("extension[0x1234] padding".to_owned(), 125, 3),
+        ("end of extensions".to_owned(), 128, 8),
+        ("backing file name".to_owned(), 136, 10),
+    ]);
+    let full = qcow2::header_layout(&img[..]).unwrap();
+    assert_eq!(full.last().unwrap().bytes, b"base.qcow2");
+    assert!(full.iter().all(|s| s.bytes.len() as u64 == s.len));
+
+    // Broken headers still have a layout, as far as it can be followed.
+    let mut img = img;
+    img[20..24].copy_from_slice(&99u32.to_be_bytes());
+    img[128..132].copy_from_slice(&0x5678u32.to_be_bytes());
+    img[132..136].copy_from_slice(&u32::MAX.to_be_bytes());
+    let spans = layout(&img);
+    assert!(spans.contains(&("extension[0x5678] payload".to_owned(), 136, 3960)));
+    assert!(!spans.iter().any(|s| s.0 == "end of extensions"));
+    assert_eq!(spans.last().unwrap().0, "backing file name");
+
+    // Version 2 headers end before the incompatible features.
+    let mut v2 = ImageBuilder::new().build();
+    v2[4..8].copy_from_slice(&2u32.to_be_bytes());
+    let spans = layout(&v2);
+    assert_eq!(spans[12], ("snapshots_offset".to_owned(), 64, 8));
+    assert_eq!(spans[13].0, "end of extensions");
+
+    // A truncated file has only what's there.
+    assert_eq!(layout(&v2[..22]).last().unwrap(), &("cluster_bits".to_owned(), 20, 2));
+    assert_eq!(layout(b"not qcow2").len(), 3);
+}
+
+#[test]
+fn feature_bits() {
+    let builder = ImageBuilder::new()
+        .compatible(1)
+        .autoclear(1 << 40)
+        .extension(EXT_FEATURE_NAME_TABLE, feature_name(0, 4, b"extended L2 entries"));
+    let img = ImageBuilder { incompatible: 1 | (1 << 4), ..builder }.build();
+    assert_eq!(open_error_unsupported(img.clone()), "extended L2 entries");
+
+    let bits = qcow2::feature_bits(&img[..]).unwrap();
+    let summary: Vec<_> = bits.iter()
+        .map(|b| (b.kind, b.bit, b.known, b.name.as_deref(), b.blocks_open))
+        .collect();
+    assert_eq!(summary,
+               [(FeatureKind::Incompatible, 0, true, Some("dirty"), false),
+                (FeatureKind::Incompatible, 4, false, Some("extended L2 entries"), true),
+                (FeatureKind::Compatible, 0, true, Some("lazy refcounts"), false),
+                (FeatureKind::Autoclear, 40, false, None, false)]);
+
+    // The corrupt bit is known, but still stops opening.
+    let img = ImageBuilder { incompatible: 2, ..ImageBuilder::new() }.build();
+    assert!(qcow2::feature_bits(&img[..]).unwrap()[0].blocks_open);
+    assert!(matches!(qcow2::feature_bits(&b"not a qcow2 image"[..]), Err(Error::FileType)));
+}
+
+fn open_error_unsupported(img: Vec<u8>) -> String {
+    match Qcow2::open(img) {
+        Err(Error::UnsupportedFeature(msg)) => msg,
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
@@ -0,0 +1,293 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use common::{ImageBuilder, SnapshotSpec, TempFile};
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::Qcow2;
+
+fn img(args: &[&str]) -> (i32, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_qcow2-img")).args(args).output().unwrap();
+    (out.status.code().unwrap(), String::from_utf8(out.stdout).unwrap())
+}
+
+// Run qcow2-img with some standard input.
+#[cfg(all(target_os = "linux", feature = "locking"))]
+fn img_with_input(args: &[&str], input: &str) -> (i32, String) {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_qcow2-img"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    (out.status.code().unwrap(), String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn convert() {
+    let cs = 65536;
+    let base = ImageBuilder::new().data(0, &[1; 16]).data(cs, &[2; 16]).size(3 * cs).build();
+    let base = TempFile::with_contents("convert-base.qcow2", &base);
+    let name = base.0.file_name().unwrap().to_str().unwrap();
+    let overlay = ImageBuilder::new().backing_file(name).data(cs, &[3; 16]).size(3 * cs).build();
+    let overlay = TempFile::with_contents("convert.qcow2", &overlay);
+
+    // The default output is raw, with data from the backing file.
+    let raw = TempFile::new("convert.raw");
+    assert_eq!(img(&["convert", overlay.path(), raw.path()]).0, 0);
+    let data = std::fs::read(&raw.0).unwrap();
+    assert_eq!(data.len() as u64, 3 * cs);
+    assert_eq!((data[0], data[cs as usize], data[2 * cs as usize]), (1, 3, 0));
+
+    let copy = TempFile::new("convert-copy.qcow2");
+    assert_eq!(img(&["convert", "-f", "qcow2", "-O", "qcow2", overlay.path(), copy.path()]).0,
+               0);
+    let q = copy.open();
+    assert_eq!(q.backing_file_name(), None);
+    assert!(q.check().unwrap().is_clean());
+    let mut buf = vec![0; 3 * cs as usize];
+    q.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    // Compressed output is only for qcow2.
+    let compressed = TempFile::new("convert-compressed.qcow2");
+    assert_eq!(img(&["convert", "-c", "-O", "qcow2", overlay.path(), compressed.path()]).0, 0);
+    let q = compressed.open();
+    assert!(q.check().unwrap().is_clean());
+    q.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(img(&["convert", "-c", overlay.path(), raw.path()]).0, 1);
+}
+
+#[test]
+fn convert_raw() {
+    let cs = 65536;
+    let mut data = vec![0; 3 * cs + 100];
+    data[..16].fill(1);
+    data[3 * cs..].fill(2);
+    let raw = TempFile::with_contents("convert-raw.raw", &data);
+
+    // The format is probed, and clusters of zeros are left out.
+    let copy = TempFile::new("convert-raw.qcow2");
+    assert_eq!(img(&["convert", "-O", "qcow2", raw.path(), copy.path()]).0, 0);
+    let q = copy.open();
+    assert_eq!(q.guest_size(), data.len() as u64);
+    assert!(q.check().unwrap().is_clean());
+    assert_eq!(q.info().unwrap().allocated_size, cs as u64 + 100);
+    let mut buf = vec![0; data.len()];
+    q.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    let compressed = TempFile::new("convert-raw-compressed.qcow2");
+    let args = ["convert", "-f", "raw", "-O", "qcow2", "-c", raw.path(), compressed.path()];
+    assert_eq!(img(&args).0, 0);
+    let q = compressed.open();
+    assert!(q.check().unwrap().is_clean());
+    q.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert!(std::fs::metadata(&compressed.0).unwrap().len() <
+            std::fs::metadata(&copy.0).unwrap().len());
+
+    // Raw images have no snapshots, and can't become raw images.
+    let args = ["convert", "-l", "s", "-O", "qcow2", raw.path(), copy.path()];
+    assert_eq!(img(&args).0, 1);
+    let out = TempFile::new("convert-raw-out.raw");
+    assert_eq!(img(&["convert", "-f", "raw", raw.path(), out.path()]).0, 1);
+}
+
+#[test]
+fn create() {
+    let image = TempFile::new("create.qcow2");
+    let (code, out) = img(&["create", "-f", "qcow2", image.path(), "1M"]);
+    assert_eq!(code, 0);
+    assert_eq!(out,
+               format!("Formatting '{}', fmt=qcow2 cluster_size=65536 size=1048576\n",
+                       image.path()));
+    let q = image.open();
+    assert_eq!(q.guest_size(), 1 << 20);
+    assert!(q.check().unwrap().is_clean());
+    assert_eq!(q.info().unwrap().allocated_size, 0);
+
+    // The image must not exist yet.
+    assert_eq!(img(&["create", image.path(), "2M"]).0, 1);
+    assert_eq!(image.open().guest_size(), 1 << 20);
+
+    let image = TempFile::new("create-prealloc.qcow2");
+    let args = ["create", "-q", "-o", "cluster_size=4K,preallocation=full", image.path(), "1M"];
+    assert_eq!(img(&args), (0, String::new()));
+    let q = image.open();
+    assert_eq!(q.cluster_size(), 4096);
+    assert!(q.check().unwrap().is_clean());
+    assert_eq!(q.info().unwrap().allocated_size, 1 << 20);
+
+    let image = TempFile::new("create-bad.qcow2");
+    assert_eq!(img(&["create", "-o", "cluster_size=3K", image.path(), "1M"]).0, 1);
+    assert_eq!(img(&["create", "-o", "lazy_refcounts=on", image.path(), "1M"]).0, 1);
+    assert_eq!(img(&["create", image.path()]).0, 1);
+    assert!(!image.0.exists());
+}
+
+#[test]
+fn create_backing() {
+    let base = TempFile::with_contents("create-base.qcow2",
+                                       &ImageBuilder::new().data(0, b"base").size(1 << 20).build());
+    let image = TempFile::new("create-overlay.qcow2");
+    let (code, out) = img(&["create", "-b", base.path(), "-F", "qcow2", image.path(), "2M"]);
+    assert_eq!(code, 0);
+    let name = base.0.file_name().unwrap().to_str().unwrap();
+    assert_eq!(out,
+               format!("Formatting '{}', fmt=qcow2 cluster_size=65536 size=2097152 \
+                        backing_file={} backing_fmt=qcow2\n",
+                       image.path(),
+                       name));
+    let q = image.open();
+    assert_eq!(q.guest_size(), 2 << 20);
+    assert!(q.check().unwrap().is_clean());
+
+    // A given backing format must match.
+    let other = TempFile::new("create-overlay-raw.qcow2");
+    assert_eq!(img(&["create", "-b", base.path(), "-F", "raw", other.path()]).0, 1);
+    assert!(!other.0.exists());
+}
+
+#[test]
+fn convert_snapshot() {
+    let image = ImageBuilder::new().data(0, &[1; 16]).snapshot(SnapshotSpec::new("1", "s"));
+    let image = TempFile::with_contents("convert-snapshot.qcow2", &image.build());
+    let mut q = Qcow2::open(common::open_rw(&image.0)).unwrap();
+    q.writer().unwrap().write_all_at(0, &[2; 16]).unwrap();
+    drop(q);
+    let raw = TempFile::new("convert-snapshot.raw");
+    assert_eq!(img(&["convert", "-l", "s", image.path(), raw.path()]).0, 0);
+    assert_eq!(std::fs::read(&raw.0).unwrap()[..16], [1; 16]);
+}
+
+#[test]
+fn resize() {
+    let image = TempFile::with_contents("resize.qcow2", &ImageBuilder::new().size(1 << 20).build());
+    let (code, out) = img(&["resize", image.path(), "+1M"]);
+    assert_eq!(code, 0);
+    assert_eq!(out, "Image resized.\n");
+    assert_eq!(image.open().guest_size(), 2 << 20);
+    assert_eq!(img(&["resize", image.path(), "3M"]).0, 0);
+    assert_eq!(image.open().guest_size(), 3 << 20);
+
+    // Shrinking needs --shrink, and isn't supported even then.
+    assert_eq!(img(&["resize", image.path(), "-1M"]).0, 1);
+    assert_eq!(img(&["resize", "--shrink", image.path(), "-1M"]).0, 1);
+    assert_eq!(image.open().guest_size(), 3 << 20);
+}
+
+#[test]
+fn snapshot() {
+    let image = ImageBuilder::new()
+        .snapshot(SnapshotSpec::new("1", "first"))
+        .snapshot(SnapshotSpec::new("2", "second"));
+    let image = TempFile::with_contents("snapshot.qcow2", &image.build());
+    let (code, out) = img(&["snapshot", "-l", image.path()]);
+    assert_eq!(code, 0);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "Snapshot list:");
+    assert!(lines[2].starts_with("1         first "));
+    assert!(lines[3].starts_with("2         second "));
+
+    // Changing snapshots isn't supported.
+    assert_eq!(img(&["snapshot", "-c", "third", image.path()]).0, 1);
+    assert_eq!(image.open().snapshots().len(), 2);
+}
+
+#[test]
+fn unsupported() {
+    assert_eq!(img(&["commit", "tests/test.qcow2"]).0, 1);
+}
+
+// A dirty image with a leaked cluster.
+fn leaky(name: &str) -> TempFile {
+    let builder = ImageBuilder { incompatible: 1, ..ImageBuilder::new().data(0, b"data") };
+    let mut image = builder.build();
+    image.resize(image.len() + 65536, 0);
+    builder.set_refcount(&mut image, 6, 1);
+    TempFile::with_contents(name, &image)
+}
+
+#[test]
+fn check_dry_run() {
+    let image = leaky("check-dry-run.qcow2");
+    let before = std::fs::read(&image.0).unwrap();
+    let (code, out) = img(&["check", image.path()]);
+    assert_eq!(code, 3);
+    assert_eq!(out,
+               "Leaked cluster 6 refcount=1 reference=0\n\
+                0 errors and 1 leaked clusters were found on the image.\n");
+
+    let (code, out) = img(&["check", "--dry-run", "--repair-all", image.path()]);
+    assert_eq!(code, 3);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[2..],
+               ["Would set the refcount of cluster 6 from 1 to 0, in the refcount block at \
+                 0x20000",
+                "Would clear the dirty bit, in the incompatible features at 0x48 in the header"]);
+    assert_eq!(std::fs::read(&image.0).unwrap(), before);
+    assert!(!PathBuf::from(format!("{}.repair-backup", image.path())).exists());
+}
+
+#[cfg(not(all(unix, feature = "locking")))]
+#[test]
+fn check_repair_needs_locking() {
+    let image = leaky("check-repair-unlocked.qcow2");
+    assert_eq!(img(&["check", "--repair-all", image.path()]).0, 1);
+    assert_eq!(image.open().check().unwrap().leaks, 1);
+}
+
+#[cfg(all(target_os = "linux", feature = "locking"))]
+#[test]
+fn check_repair() {
+    let image = leaky("check-repair.qcow2");
+    let backup = TempFile(PathBuf::from(format!("{}.repair-backup", image.path())));
+    let before = std::fs::read(&image.0).unwrap();
+
+    // Declining the refcount repair leaves the image dirty too.
+    let (code, out) = img_with_input(&["check", "--repair", image.path()], "n\n");
+    assert_eq!(code, 3);
+    assert!(out.contains("Repair: set the refcount of cluster 6 from 1 to 0"));
+    assert!(out.contains("Leaving the image dirty"));
+    assert_eq!(std::fs::read(&image.0).unwrap(), before);
+
+    // The backup holds the first cluster, the refcount table and the refcount block.
+    let saved = std::fs::read(&backup.0).unwrap();
+    assert_eq!(saved.len(), 3 * (16 + 65536));
+    assert_eq!(saved[..16], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+    assert_eq!(saved[16..16 + 65536], before[..65536]);
+    let block = 2 * (16 + 65536);
+    assert_eq!(saved[block..block + 8], 0x20000u64.to_be_bytes());
+
+    // An existing backup is never replaced.
+    assert_eq!(img(&["check", "--repair-all", image.path()]).0, 1);
+    std::fs::remove_file(&backup.0).unwrap();
+
+    let (code, out) = img(&["check", "--repair-all", image.path()]);
+    assert_eq!(code, 0);
+    assert!(out.ends_with("No errors were found on the image.\n"));
+    let q = image.open();
+    assert!(q.check().unwrap().is_clean());
+    assert!(!q.info().unwrap().dirty);
+}
+
+#[cfg(all(target_os = "linux", feature = "locking"))]
+#[test]
+fn check_in_use() {
+    let image = leaky("check-in-use.qcow2");
+    let _vm = qcow2::LockedFile::open_rw(&image.0).unwrap();
+    assert_eq!(img(&["check", image.path()]).0, 1);
+    assert_eq!(img(&["check", "--repair-all", image.path()]).0, 1);
+}
@@ -1,9 +1,15 @@
 extern crate positioned_io;
 extern crate qcow2;
 
+mod common;
+
 use std::fs::File;
-use positioned_io::ReadAt;
-use qcow2::Qcow2;
+use std::io;
+use std::sync::Arc;
+use positioned_io::{ReadAt, Size, WriteAt};
+use common::{ImageBuilder, SnapshotSpec};
+use qcow2::{CheckFinding, CompressionType, DynBackend, DynReader, Error, MemBackend, OpenOptions,
+            Qcow2, SeekBackend, SharedBackend, Truncated};
 
 #[test]
 fn basic_read() {
@@ -15,3 +21,530 @@ fn basic_read() {
     let s = std::str::from_utf8(&buf).unwrap();
     assert_eq!(s, "Lorem ipsum");
 }
+
+#[test]
+fn info() {
+    let file = File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let info = qcow.info().unwrap();
+    assert_eq!(info.version, 3);
+    assert_eq!(info.virtual_size, 1000 * 1024 * 1024);
+    assert_eq!(info.cluster_size, 65536);
+    assert_eq!(info.allocated_size, 65536);
+    assert_eq!(info.backing_file, None);
+    assert_eq!(info.compression_type, CompressionType::Zlib);
+    assert!(info.snapshots.is_empty());
+    assert!(!info.lazy_refcounts);
+    assert_eq!(info.refcount_bits, 16);
+    assert!(!info.dirty && !info.corrupt);
+
+    let text = info.to_string();
+    assert!(text.contains("virtual size: 0.977 GiB (1048576000 bytes)\n"));
+    assert!(text.contains("allocated size: 64 KiB\n"));
+    assert!(text.contains("    refcount bits: 16\n"));
+}
+
+#[test]
+fn info_snapshots() {
+    let img = ImageBuilder::new()
+        .size(3 << 20)
+        .data(0, b"first")
+        .data(2 << 20, b"last")
+        .snapshot(SnapshotSpec::new("1", "before"))
+        .snapshot(SnapshotSpec::new("2", "after"))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let info = qcow.info().unwrap();
+    assert_eq!(info.allocated_size, 2 * 65536);
+    let names: Vec<_> = info.snapshots.iter().map(|s| (s.id.as_str(), s.name.as_str())).collect();
+    assert_eq!(names, vec![("1", "before"), ("2", "after")]);
+    assert_eq!(info.snapshots[0].disk_size, Some(3 << 20));
+    assert!(info.to_string().contains("Snapshot list:\n"));
+}
+
+#[test]
+fn info_features() {
+    let qcow = Qcow2::open(ImageBuilder::new().compatible(1).build()).unwrap();
+    let info = qcow.info().unwrap();
+    assert!(info.lazy_refcounts);
+    assert_eq!(info.features, vec!["lazy refcounts"]);
+    assert!(info.to_string().contains("    features: lazy refcounts\n"));
+}
+
+#[test]
+fn snapshot_reader() {
+    let img = ImageBuilder::new()
+        .data(65536, b"snapped")
+        .snapshot(SnapshotSpec::new("1", "first"))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    for name in &["1", "first"] {
+        let reader = qcow.snapshot_reader(name).unwrap();
+        let mut buf = [0; 7];
+        reader.read_exact_at(65536, &mut buf).unwrap();
+        assert_eq!(&buf, b"snapped");
+    }
+    match qcow.snapshot_reader("second") {
+        Err(Error::NoSnapshot(ref name)) if name == "second" => {}
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn reader_builder_raw_l1() {
+    let img = ImageBuilder::new().data(65536, b"raw").build();
+    let qcow = Qcow2::open(img).unwrap();
+    let info = qcow.header_info();
+    let reader = qcow.reader_builder()
+        .raw_l1(info.l1_table_offset, info.l1_size as u64)
+        .unwrap();
+    let mut buf = [0; 3];
+    reader.read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"raw");
+    assert!(qcow.reader_builder().active().is_ok());
+
+    for &(offset, entries) in &[(0, 1), (info.l1_table_offset + 8, 1), (65536, 1 << 30)] {
+        match qcow.reader_builder().raw_l1(offset, entries) {
+            Err(Error::FileFormat(_)) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn truncated_file() {
+    let img = ImageBuilder::new().data(0, b"data").build();
+    // Cut the file off in the middle of the L1 table, and of the header.
+    for &(len, expected) in &[(3 * 65536 + 4, 3 * 65536 + 8), (50, 104)] {
+        match Qcow2::open(img[..len].to_vec()) {
+            Err(Error::Truncated { expected_at_least, actual }) => {
+                assert_eq!((expected_at_least, actual), (expected, len as u64));
+            }
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+    let err = Qcow2::open(img[..2 * 65536].to_vec()).unwrap_err();
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn truncated_data() {
+    let mut img = ImageBuilder::new().data(65536, b"data").build();
+    // Cut the data cluster short.
+    img.truncate(5 * 65536 + 2);
+
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    let mut buf = [0; 4];
+    let err = qcow.reader().unwrap().read_exact_at(65536, &mut buf).unwrap_err();
+    assert_eq!(err.to_string(),
+               "Malformed qcow2 file: data for guest offset 0x10000 at host offset 0x50000 is \
+                past the end of the file");
+
+    let qcow = OpenOptions::new().truncated(Truncated::Zero).open(img).unwrap();
+    qcow.reader().unwrap().read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"da\0\0");
+}
+
+// Read the second cluster of an image, whose L2 entry claims compressed data at `pos`.
+fn read_compressed(pos: u64) -> String {
+    let mut img = ImageBuilder::new().data(0, b"data").build();
+    // Four sectors, with 64 KiB clusters.
+    let entry = (1u64 << 62) | (3 << 54) | pos;
+    img[4 * 65536 + 8..4 * 65536 + 16].copy_from_slice(&entry.to_be_bytes());
+    let qcow = Qcow2::open(img).unwrap();
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(65536, &mut buf).unwrap_err().to_string()
+}
+
+#[test]
+fn misaligned_tables() {
+    let cs = 65536;
+    let read = |img: &[u8], snapshot: Option<&str>| -> io::Result<()> {
+        let qcow = Qcow2::open(img.to_vec()).unwrap();
+        let reader = match snapshot {
+            Some(name) => qcow.snapshot_reader(name),
+            None => qcow.reader(),
+        };
+        reader.unwrap().read_exact_at(0, &mut [0; 4])
+    };
+    let misaligned = "Malformed qcow2 file: L2 table for L1 index 0 at 0x40200 is misaligned";
+
+    // The active L1 table, and a snapshot's, each affecting only their own reader.
+    let img = ImageBuilder::new().data(0, b"data").snapshot(SnapshotSpec::new("1", "s")).build();
+    let snap_l1 = Qcow2::open(img.clone()).unwrap().snapshots()[0].l1_table_offset as usize;
+    for (l1, bad, good) in [(3 * cs, None, Some("s")), (snap_l1, Some("s"), None)] {
+        let mut img = img.clone();
+        img[l1 + 6] |= 2;
+        assert_eq!(read(&img, bad).unwrap_err().to_string(), misaligned);
+        read(&img, good).unwrap();
+    }
+
+    // A refcount block, when allocating.
+    let mut img = ImageBuilder::new().build();
+    img[cs + 6] |= 2;
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    let err = qcow.writer().unwrap().write_all_at(0, b"data").unwrap_err();
+    assert_eq!(err.to_string(),
+               "Malformed qcow2 file: refcount block for refcount table index 0 at 0x20200 is \
+                misaligned");
+    // Checking still reports the problem, rather than failing.
+    let result = qcow.check().unwrap();
+    assert!(!result.is_clean());
+}
+
+#[test]
+fn misaligned_data() {
+    let cs = 65536;
+    let mut data = vec![0; 0x200];
+    data.extend_from_slice(b"data");
+    let mut img = ImageBuilder::new().data(cs as u64, &data).build();
+    // Point the second cluster's L2 entry into the middle of its data cluster.
+    img[4 * cs + 8 + 6] |= 2;
+
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    let err = qcow.reader().unwrap().read_exact_at(cs as u64, &mut [0; 4]).unwrap_err();
+    assert_eq!(err.to_string(),
+               "Malformed qcow2 file: data cluster for guest offset 0x10000 is misaligned, L2 \
+                entry 0x8000000000050200");
+    // Other clusters are unaffected.
+    qcow.reader().unwrap().read_exact_at(0, &mut [0; 4]).unwrap();
+
+    let mut buf = [0; 4];
+    let qcow = OpenOptions::new().misaligned_data(true).open(img).unwrap();
+    qcow.reader().unwrap().read_exact_at(cs as u64, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+}
+
+#[test]
+fn reserved_bits() {
+    let cs = 65536;
+    let img = ImageBuilder::new().data(0, b"data").build();
+    for (pos, what) in [(3 * cs + 7, "L1 entry"), (4 * cs + 7, "L2 entry")] {
+        let mut img = img.clone();
+        img[pos] |= 2;
+
+        let qcow = Qcow2::open(img.clone()).unwrap();
+        let err = qcow.reader().unwrap().read_exact_at(0, &mut [0; 4]).unwrap_err();
+        assert_eq!(err.to_string(),
+                   format!("Malformed qcow2 file: reserved bit used in {}", what));
+
+        let mut buf = [0; 4];
+        let qcow = OpenOptions::new().reserved_bits(true).metrics(true).open(img).unwrap();
+        qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(qcow.metrics().unwrap().reserved_entries(), 1);
+        // Checking still reports them.
+        let message = format!("reserved bit used in {}", what);
+        assert!(qcow.check().unwrap().findings.iter().any(|f| match *f {
+            CheckFinding::Invalid { message: ref m, .. } => *m == message,
+            _ => false,
+        }));
+    }
+}
+
+#[test]
+fn compressed_extents() {
+    assert_eq!(read_compressed(0x200),
+               "Malformed qcow2 file: compressed data for guest offset 0x10000 at host offset \
+                0x200, 2048 bytes, overlaps the header");
+    assert_eq!(read_compressed(7 << 16),
+               "Malformed qcow2 file: compressed data for guest offset 0x10000 at host offset \
+                0x70000, 2048 bytes, starts past the end of the file");
+
+    // The data must be something that can be decompressed.
+    assert_eq!(read_compressed((6 << 16) - 512),
+               "Malformed qcow2 file: compressed data for guest offset 0x10000 at host offset \
+                0x5fe00, 2048 bytes, can't be decompressed");
+}
+
+#[test]
+fn compressed_read() {
+    let cs = 65536;
+    let mut img = ImageBuilder::new().data(0, b"data").compressed(cs, b"packed").build();
+    let mut cluster = vec![0; cs as usize];
+    cluster[..6].copy_from_slice(b"packed");
+    let stored = miniz_oxide::deflate::compress_to_vec(&cluster, 6);
+    // The end of the last compressed cluster may be past the end of the file.
+    img.truncate(img.len() - cs as usize + stored.len());
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 8];
+    reader.read_exact_at(cs - 2, &mut buf).unwrap();
+    assert_eq!(&buf, b"\0\0packed");
+    reader.read_exact_at(2 * cs - 8, &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+}
+
+#[test]
+fn raw_compressed_cluster() {
+    let cs = 65536;
+    let read = |pos: u64| {
+        let mut img = ImageBuilder::new().data(0, b"data").build();
+        let entry = (1u64 << 62) | (3 << 54) | pos;
+        img[4 * cs + 8..4 * cs + 16].copy_from_slice(&entry.to_be_bytes());
+        let qcow = Qcow2::open(img).unwrap();
+        let reader = qcow.reader().unwrap();
+        assert_eq!(reader.raw_compressed_cluster(0).unwrap(), None);
+        assert_eq!(reader.raw_compressed_cluster(2 << 16).unwrap(), None);
+        reader.raw_compressed_cluster((1 << 16) + 5).unwrap().unwrap()
+    };
+
+    // Four sectors, counted from the sector the data starts in.
+    let cluster = read((5 << 16) + 0x100);
+    assert_eq!(cluster.host_offset, (5 << 16) + 0x100);
+    assert_eq!(cluster.compression, CompressionType::Zlib);
+    assert_eq!(cluster.data.len(), 2048 - 0x100);
+    assert_eq!(&cluster.data[..4], &[0; 4]);
+    let cluster = read((5 << 16) - 4);
+    assert_eq!(&cluster.data[4..8], b"data");
+    // The last cluster is cut short by the end of the file.
+    assert_eq!(read((6 << 16) - 512).data.len(), 512);
+}
+
+#[test]
+fn read_from_threads() {
+    // Positioned reads of one file from several threads must not interfere, on any platform.
+    let file = File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let reader = qcow.reader().unwrap();
+                for _ in 0..100 {
+                    let mut buf = [0; 11];
+                    reader.read_exact_at(1024 * 1024 * 200, &mut buf).unwrap();
+                    assert_eq!(&buf, b"Lorem ipsum");
+                }
+            });
+        }
+    });
+}
+
+#[cfg(windows)]
+#[test]
+fn file_read_at_windows() {
+    use std::io::{Seek, SeekFrom};
+
+    // Reads at any position give the file's contents, wherever the file's cursor is.
+    let contents = std::fs::read("tests/test.qcow2").unwrap();
+    let mut file = File::open("tests/test.qcow2").unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    for &pos in &[contents.len() as u64 - 512, 65536 + 7, 0] {
+        let mut buf = [0; 512];
+        file.read_exact_at(pos, &mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[pos as usize..pos as usize + 512]);
+    }
+    let mut buf = [0; 16];
+    assert!(file.read_exact_at(contents.len() as u64 - 8, &mut buf).is_err());
+}
+
+// Images are written through a RandomAccessFile on Windows, see common::open_rw.
+#[cfg(windows)]
+#[test]
+fn file_write_at_windows() {
+    let contents = std::fs::read("tests/test.qcow2").unwrap();
+    let copy = common::TempFile::with_contents("write-at.qcow2", &contents);
+    let mut qcow = Qcow2::open(common::open_rw(&copy.0)).unwrap();
+    qcow.writer().unwrap().write_all_at(4096, b"guest write").unwrap();
+    qcow.sync().unwrap();
+    drop(qcow);
+    let mut buf = [0; 11];
+    copy.open().reader().unwrap().read_exact_at(4096, &mut buf).unwrap();
+    assert_eq!(&buf, b"guest write");
+
+    // Writing past the end extends the file.
+    let end = std::fs::metadata(&copy.0).unwrap().len();
+    common::open_rw(&copy.0).write_all_at(end + 100, b"raw write").unwrap();
+    let written = std::fs::read(&copy.0).unwrap();
+    assert_eq!(written.len() as u64, end + 109);
+    assert_eq!(&written[end as usize + 100..], b"raw write");
+}
+
+#[test]
+fn send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<Qcow2<File>>();
+}
+
+#[test]
+fn open_in_memory() {
+    let img = ImageBuilder::new().data(65536, b"data").build();
+    let mut buf = [0; 4];
+    Qcow2::open(&img[..]).unwrap().reader().unwrap().read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+
+    let boxed = MemBackend(img.into_boxed_slice());
+    let qcow = Qcow2::open(boxed).unwrap();
+    let reader = qcow.reader().unwrap();
+    reader.read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+    assert_eq!(qcow.guest_size(), 1 << 20);
+}
+
+#[test]
+fn open_dyn() {
+    let img = ImageBuilder::new().data(65536, b"data").build();
+    let images: Vec<Qcow2<DynBackend>> = vec![
+        Qcow2::open_dyn(Box::new(File::open("tests/test.qcow2").unwrap())).unwrap(),
+        Qcow2::open_dyn(Box::new(img.clone())).unwrap(),
+        Qcow2::open_dyn(Box::new(MemBackend(img))).unwrap(),
+    ];
+
+    // Images with boxed storage can be checked and sent to other threads.
+    for qcow in &images {
+        assert!(qcow.check().unwrap().is_clean());
+    }
+    let images = std::thread::spawn(move || images).join().unwrap();
+    let mut buf = [0; 4];
+    images[2].reader().unwrap().read_exact_at(65536, &mut buf).unwrap();
+    assert_eq!(&buf, b"data");
+}
+
+// Check the sizes of an image, whatever its storage.
+fn assert_sizes<I: ReadAt + Size>(qcow: Qcow2<I>, guest: u64, physical: Option<u64>) {
+    assert_eq!(Size::size(&qcow).unwrap(), Some(guest));
+    assert_eq!(qcow.physical_size().unwrap(), physical);
+}
+
+#[test]
+fn image_size() {
+    let file_len = std::fs::metadata("tests/test.qcow2").unwrap().len();
+    let file = File::open("tests/test.qcow2").unwrap();
+    assert_sizes(Qcow2::open(file).unwrap(), 1048576000, Some(file_len));
+
+    let img = ImageBuilder::new().data(0, b"data").build();
+    let len = img.len() as u64;
+    assert_sizes(Qcow2::open(&img[..]).unwrap(), 1 << 20, Some(len));
+    assert_sizes(Qcow2::open(MemBackend(img.clone())).unwrap(), 1 << 20, Some(len));
+    let seek = SeekBackend::new(io::Cursor::new(img.clone()));
+    assert_sizes(Qcow2::open(seek).unwrap(), 1 << 20, Some(len));
+    assert_sizes(Qcow2::open_dyn(Box::new(img)).unwrap(), 1 << 20, Some(len));
+}
+
+#[test]
+fn shared_file() {
+    let file = Arc::new(File::open("tests/test.qcow2").unwrap());
+    let qcow = Qcow2::open(SharedBackend::from(file.clone())).unwrap();
+    let mut expected = vec![0; 1 << 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut expected).unwrap();
+
+    // Each thread reads through its own clone of the image, from the same file.
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let qcow = qcow.clone();
+            std::thread::spawn(move || {
+                let reader = qcow.reader().unwrap();
+                let mut buf = vec![0; 1 << 16];
+                for _ in 0..16 {
+                    reader.read_exact_at(0, &mut buf).unwrap();
+                }
+                buf
+            })
+        })
+        .collect();
+    for t in threads {
+        assert!(t.join().unwrap() == expected);
+    }
+    drop(qcow);
+    assert_eq!(Arc::strong_count(&file), 1);
+
+    let img = ImageBuilder::new().data(0, b"data").build();
+    let len = img.len() as u64;
+    assert_sizes(Qcow2::open(SharedBackend::new(img)).unwrap(), 1 << 20, Some(len));
+}
+
+#[test]
+fn metrics() {
+    let cs = 65536;
+    let img = ImageBuilder::new().data(0, b"data").data(cs, b"more").build();
+    assert!(Qcow2::open(img.clone()).unwrap().metrics().is_none());
+
+    let qcow = OpenOptions::new().metrics(true).open(img).unwrap();
+    let metrics = qcow.metrics().unwrap();
+    assert_eq!(metrics.read_amplification(), None);
+    let reader = qcow.reader().unwrap();
+    let mut buf = vec![0; 3 * cs as usize];
+    reader.read_exact_at(0, &mut buf).unwrap();
+
+    // The L1 and L2 tables are read, then both data clusters at once.
+    assert_eq!(metrics.guest_bytes(), 3 * cs);
+    assert_eq!(metrics.metadata_reads(), 2);
+    assert_eq!(metrics.data_reads(), 1);
+    assert_eq!(metrics.physical_reads(), 3);
+    assert_eq!(metrics.physical_bytes(), 8 + cs + 2 * cs);
+    assert_eq!(metrics.zero_bytes(), cs);
+    assert_eq!(metrics.read_amplification(), Some((8 + 3 * cs) as f64 / (3 * cs) as f64));
+
+    // The L2 table is cached now, and clones share metrics.
+    metrics.reset();
+    assert_eq!(metrics.physical_bytes(), 0);
+    let clone = qcow.clone();
+    clone.reader().unwrap().read_exact_at(10, &mut buf[..10]).unwrap();
+    assert_eq!(metrics.metadata_reads(), 1);
+    assert_eq!(metrics.data_reads(), 1);
+    assert_eq!(metrics.physical_bytes(), 8 + 10);
+    assert_eq!(metrics.guest_bytes(), 10);
+}
+
+#[test]
+fn dyn_reader() {
+    let img = ImageBuilder::new()
+        .cluster_bits(12)
+        .data(4096, b"data")
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    let storage = Arc::new(img);
+    let open = || Qcow2::open(SharedBackend::from(storage.clone())).unwrap();
+    let readers: Vec<DynReader> = vec![
+        open().into_dyn_reader().unwrap(),
+        Box::new(open().into_snapshot_reader("snap").unwrap()),
+    ];
+    assert!(matches!(open().into_snapshot_reader("nope"), Err(Error::NoSnapshot(_))));
+
+    // The readers own their images, so they can be sent to other threads.
+    let readers = std::thread::spawn(move || readers).join().unwrap();
+    for reader in &readers {
+        assert_eq!(reader.io_granularity(), 4096);
+        assert_eq!(reader.size().unwrap(), Some(1 << 20));
+        let mut buf = [0; 4];
+        reader.read_exact_at(4096, &mut buf).unwrap();
+        assert_eq!(&buf, b"data");
+    }
+
+    // Dropping them drops the storage.
+    assert_eq!(Arc::strong_count(&storage), 3);
+    drop(readers);
+    assert_eq!(Arc::strong_count(&storage), 1);
+}
+
+#[test]
+fn single_cluster_reads() {
+    let cs = 4096;
+    let img = ImageBuilder::new()
+        .cluster_bits(12)
+        .size(3 * cs + 100)
+        .data(cs, &[1; 4096])
+        .data(3 * cs, &[2; 100])
+        .build();
+    let qcow = OpenOptions::new().metrics(true).open(img).unwrap();
+    let metrics = qcow.metrics().unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = vec![9; cs as usize];
+
+    reader.read_exact_at(cs, &mut buf).unwrap();
+    assert_eq!(buf, [1; 4096]);
+    assert_eq!(metrics.data_reads(), 1);
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; 4096]);
+    reader.read_exact_at(cs + 10, &mut buf[..20]).unwrap();
+    assert_eq!(buf[..20], [1; 20]);
+    assert_eq!(metrics.guest_bytes(), 2 * cs + 20);
+
+    // A whole cluster at the end of the disk is cut short.
+    buf.fill(9);
+    assert_eq!(reader.read_at(3 * cs, &mut buf).unwrap(), 100);
+    assert_eq!(buf[..100], [2; 100]);
+    assert_eq!(buf[100], 9);
+    assert_eq!(reader.read_at(4 * cs, &mut buf).unwrap(), 0);
+    assert_eq!(reader.read_at(cs, &mut []).unwrap(), 0);
+}
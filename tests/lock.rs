@@ -0,0 +1,70 @@
+#![cfg(all(target_os = "linux", feature = "locking"))]
+
+extern crate qcow2;
+mod common;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::{LockMode, LockedFile, Qcow2};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("qcow2-lock-{}-{}", name, std::process::id()))
+}
+
+fn in_use(path: &Path, mode: LockMode) -> String {
+    let options = fs::OpenOptions::new().read(true).write(mode == LockMode::Exclusive).clone();
+    let err = LockedFile::open_with(&options, path, mode).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+    err.to_string()
+}
+
+#[test]
+fn lock_conflicts() {
+    let path = temp_path("conflicts");
+    fs::write(&path, ImageBuilder::new().build()).unwrap();
+
+    // Readers share with each other, but not with a writer.
+    let first = LockedFile::open(&path).unwrap();
+    let second = LockedFile::open(&path).unwrap();
+    assert_eq!(in_use(&path, LockMode::Exclusive),
+               "image is in use, failed to get lock on \"write\"");
+    drop((first, second));
+
+    let writer = LockedFile::open_rw(&path).unwrap();
+    assert_eq!(in_use(&path, LockMode::Shared),
+               "image is in use, failed to get shared lock on \"write\"");
+    assert_eq!(in_use(&path, LockMode::Exclusive),
+               "image is in use, failed to get lock on \"write\"");
+    // Forcing sharing lets reading go ahead anyway.
+    let forced = LockedFile::open_with(fs::OpenOptions::new().read(true),
+                                       &path,
+                                       LockMode::ForceShare)
+        .unwrap();
+    drop(forced);
+
+    // Getting the file back releases its locks.
+    let file = writer.into_inner();
+    LockedFile::open(&path).unwrap();
+    drop(file);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn lock_image() {
+    let path = temp_path("image");
+    fs::write(&path, ImageBuilder::new().data(0, &[1; 16]).build()).unwrap();
+    let mut qcow = Qcow2::open(LockedFile::open_rw(&path).unwrap()).unwrap();
+    qcow.writer().unwrap().write_all_at(70000, &[2; 16]).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(70000, &mut buf).unwrap();
+    assert_eq!(buf, [2; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+    assert!(LockedFile::open(&path).is_err());
+    drop(qcow);
+    LockedFile::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+}
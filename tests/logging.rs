@@ -0,0 +1,67 @@
+#![cfg(feature = "logging")]
+
+mod common;
+
+use std::sync::Mutex;
+
+use common::ImageBuilder;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use positioned_io::ReadAt;
+use qcow2::Qcow2;
+
+// Keeps every message logged.
+struct Recorder(Mutex<Vec<(Level, String)>>);
+
+impl Log for Recorder {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target().starts_with("qcow2") {
+            self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static RECORDER: Recorder = Recorder(Mutex::new(Vec::new()));
+
+fn take_messages() -> Vec<(Level, String)> {
+    std::mem::take(&mut *RECORDER.0.lock().unwrap())
+}
+
+#[test]
+fn logging() {
+    log::set_logger(&RECORDER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let payload = vec![1; 8];
+    let img = ImageBuilder::new()
+        .data(0, b"data")
+        .extension(0x1234, payload.clone())
+        .extension(0x1234, payload)
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let messages = take_messages();
+    let has = |level, msg: &str| messages.iter().any(|m| m.0 == level && m.1.starts_with(msg));
+    assert!(has(Level::Debug, "read qcow2 header: version 3, cluster size 65536"),
+            "{:?}",
+            messages);
+    assert!(has(Level::Debug, "header extension 0x1234 at "), "{:?}", messages);
+    assert!(has(Level::Warn, "ignoring duplicate header extension 0x1234"), "{:?}", messages);
+
+    // Reads summarize how the cache did, once the image is closed.
+    let mut buf = [0; 4];
+    let reader = qcow.reader().unwrap();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    reader.read_exact_at(0, &mut buf).unwrap();
+    drop(reader);
+    let clone = qcow.clone();
+    drop(qcow);
+    assert_eq!(take_messages(), vec![(Level::Trace, "reading L2 table at 0x40000".to_owned())]);
+    drop(clone);
+    assert_eq!(take_messages(),
+               vec![(Level::Debug, "closing image, L2 cache: 1 hits, 1 misses".to_owned())]);
+}
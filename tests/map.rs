@@ -0,0 +1,142 @@
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{Backing, MapEntry, Qcow2};
+
+const CS: u64 = 65536;
+
+fn data(start: u64, length: u64, depth: u64, offset: u64) -> MapEntry {
+    MapEntry {
+        start,
+        length,
+        depth,
+        present: true,
+        zero: false,
+        data: true,
+        compressed: false,
+        offset: Some(offset),
+    }
+}
+
+fn absent(start: u64, length: u64, depth: u64) -> MapEntry {
+    MapEntry {
+        start,
+        length,
+        depth,
+        present: false,
+        zero: true,
+        data: false,
+        compressed: false,
+        offset: None,
+    }
+}
+
+// An image with data in the first two clusters, a zero cluster third, and data in the sixth.
+fn builder() -> ImageBuilder {
+    ImageBuilder::new()
+        .size(8 * CS)
+        .data(0, &[1; 16])
+        .data(CS, &[2; 16])
+        .data(2 * CS, &[3; 16])
+        .zero_cluster(2 * CS)
+        .data(5 * CS, &[4; 16])
+}
+
+fn image() -> Vec<u8> {
+    builder().build()
+}
+
+#[test]
+fn map_extents() {
+    let qcow = Qcow2::open(image()).unwrap();
+    let map = qcow.reader().unwrap().map().unwrap();
+    let zero = MapEntry {
+        start: 2 * CS,
+        length: CS,
+        depth: 0,
+        present: true,
+        zero: true,
+        data: false,
+        compressed: false,
+        offset: Some(7 * CS),
+    };
+    // The first two clusters are contiguous on disk, so they're merged.
+    assert_eq!(map,
+               vec![data(0, 2 * CS, 0, 5 * CS),
+                    zero,
+                    absent(3 * CS, 2 * CS, 0),
+                    data(5 * CS, CS, 0, 8 * CS),
+                    absent(6 * CS, 2 * CS, 0)]);
+}
+
+#[test]
+fn map_compressed() {
+    let img = builder().compressed_entry(3 * CS, &[5; 16]).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let map = qcow.reader().unwrap().map().unwrap();
+    assert_eq!(map[2],
+               MapEntry {
+                   start: 3 * CS,
+                   length: CS,
+                   depth: 0,
+                   present: true,
+                   zero: false,
+                   data: true,
+                   compressed: true,
+                   offset: None,
+               });
+}
+
+#[test]
+fn map_backing() {
+    let base = Qcow2::open(ImageBuilder::new().size(4 * CS).data(0, &[1; 16]).build()).unwrap();
+    let img = ImageBuilder::new()
+        .size(8 * CS)
+        .backing_file("base.qcow2")
+        .data(CS, &[2; 16])
+        .build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.set_backing(Backing::qcow2(base).unwrap());
+    let map = qcow.reader().unwrap().map().unwrap();
+    // Past the end of the backing file, the overlay is the last place looked.
+    assert_eq!(map,
+               vec![data(0, CS, 1, 5 * CS),
+                    data(CS, CS, 0, 5 * CS),
+                    absent(2 * CS, 2 * CS, 1),
+                    absent(4 * CS, 4 * CS, 0)]);
+}
+
+#[test]
+fn map_raw_backing() {
+    let img = ImageBuilder::new().size(8 * CS).backing_file("base.raw").data(CS, &[2; 16]).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    qcow.set_backing(Backing::raw(vec![7; 3 * CS as usize + 5]).unwrap());
+    let map = qcow.reader().unwrap().map().unwrap();
+    assert_eq!(map,
+               vec![data(0, CS, 1, 0),
+                    data(CS, CS, 0, 5 * CS),
+                    data(2 * CS, CS + 5, 1, 2 * CS),
+                    absent(3 * CS + 5, 5 * CS - 5, 0)]);
+}
+
+#[test]
+fn map_json() {
+    let img = ImageBuilder::new().size(4 * CS).data(CS, &[2; 16]).build();
+    let qcow = Qcow2::open(img).unwrap();
+    let mut out = Vec::new();
+    qcow.reader().unwrap().map_json(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "[{ \"start\": 0, \"length\": 65536, \"depth\": 0, \"present\": false, \"zero\": \
+                true, \"data\": false, \"compressed\": false},\n\
+                { \"start\": 65536, \"length\": 65536, \"depth\": 0, \"present\": true, \"zero\": \
+                false, \"data\": true, \"compressed\": false, \"offset\": 327680},\n\
+                { \"start\": 131072, \"length\": 131072, \"depth\": 0, \"present\": false, \
+                \"zero\": true, \"data\": false, \"compressed\": false}]\n");
+
+    let qcow = Qcow2::open(ImageBuilder::new().size(0).build()).unwrap();
+    let mut out = Vec::new();
+    qcow.reader().unwrap().map_json(&mut out).unwrap();
+    assert_eq!(out, b"[]\n");
+}
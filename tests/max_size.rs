@@ -0,0 +1,84 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, Size};
+use qcow2::{Error, Qcow2};
+
+// The largest clusters, so the largest virtual disk: 8 EiB, mapped by a 32 MiB L1 table.
+const CLUSTER_BITS: u32 = 22;
+const CS: u64 = 1 << CLUSTER_BITS;
+const MAX: u64 = 1 << 63;
+
+// An image of the largest size, with data in the last cluster.
+fn biggest() -> Vec<u8> {
+    ImageBuilder::new().cluster_bits(CLUSTER_BITS).size(MAX).data(MAX - CS, &[1; 16]).build()
+}
+
+// Claim a virtual size, with the L1 table size that goes with it.
+fn set_size(img: &mut [u8], size: u64) {
+    let l1_entries = size.div_ceil(CS).div_ceil(CS / 8);
+    img[24..32].copy_from_slice(&size.to_be_bytes());
+    img[36..40].copy_from_slice(&(l1_entries as u32).to_be_bytes());
+}
+
+#[test]
+fn max_size_read() {
+    let qcow = Qcow2::open(biggest()).unwrap();
+    assert_eq!(qcow.geometry().max_size(), MAX);
+    assert_eq!(qcow.guest_size(), MAX);
+    let reader = qcow.reader().unwrap();
+    assert_eq!(reader.size().unwrap(), Some(MAX));
+
+    let mut buf = [0; 32];
+    reader.read_exact_at(MAX - CS - 16, &mut buf).unwrap();
+    assert_eq!(buf[..16], [0; 16]);
+    assert_eq!(buf[16..], [1; 16]);
+    // Reads that would go past the end stop there, even near the end of a u64.
+    assert_eq!(reader.read_at(MAX - 8, &mut buf).unwrap(), 8);
+    assert_eq!(buf[..8], [0; 8]);
+    for &pos in &[MAX, u64::MAX - 8, u64::MAX] {
+        assert_eq!(reader.read_at(pos, &mut buf).unwrap(), 0);
+    }
+
+    let last = qcow.geometry().cluster_containing(MAX - 1).unwrap();
+    assert_eq!(last.end(), MAX);
+    assert!(qcow2::validate(biggest()).unwrap().is_valid());
+}
+
+#[test]
+fn max_size_smaller_clusters() {
+    let mut img = ImageBuilder::new().build();
+    let max = Qcow2::open(&img[..]).unwrap().geometry().max_size();
+    assert_eq!(max, 2 << 50);
+    img[24..32].copy_from_slice(&(max + 1).to_be_bytes());
+    img[36..40].copy_from_slice(&((1u32 << 22) + 1).to_be_bytes());
+    assert!(matches!(Qcow2::open(img), Err(Error::FileFormat(_))));
+}
+
+#[test]
+fn past_max_size() {
+    let mut img = biggest();
+    set_size(&mut img, MAX + 1);
+    match Qcow2::open(&img[..]) {
+        Err(Error::FileFormat(_)) => {}
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+    let report = qcow2::validate(&img[..]).unwrap();
+    assert!(!report.is_valid());
+    assert!(report.findings.iter().any(|f| f.field == "size"));
+
+    set_size(&mut img, u64::MAX);
+    assert!(matches!(Qcow2::open(img), Err(Error::FileFormat(_))));
+}
+
+#[test]
+fn resize_past_max_size() {
+    let mut img = ImageBuilder::new().cluster_bits(CLUSTER_BITS).size(1 << 30).build();
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    assert!(matches!(qcow.resize(MAX + 1), Err(Error::UnsupportedFeature(_))));
+    assert!(matches!(qcow.resize(u64::MAX), Err(Error::UnsupportedFeature(_))));
+    assert_eq!(qcow.guest_size(), 1 << 30);
+}
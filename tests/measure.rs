@@ -0,0 +1,102 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::{ImageBuilder, SnapshotSpec};
+use positioned_io::WriteAt;
+use qcow2::{CreateOptions, Error, MeasureOptions, Preallocation, Qcow2};
+
+// An image with every cluster allocated.
+fn full(cluster_bits: u32, size: u64) -> Qcow2<Vec<u8>> {
+    let mut qcow = Qcow2::open(ImageBuilder::new().cluster_bits(cluster_bits).size(size).build())
+        .unwrap();
+    let data = vec![1; size as usize];
+    qcow.writer().unwrap().write_all_at(0, &data).unwrap();
+    qcow
+}
+
+#[test]
+fn measure_fully_allocated() {
+    // Sizes around the edges of L2 tables and refcount blocks, and some others.
+    let mut sizes = vec![0, 1, 511, 512, 513, 32768, 32769, 65536, 1 << 20, (1 << 20) + 1];
+    let mut x: u64 = 12345;
+    for _ in 0..10 {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        sizes.push((x >> 40) % (4 << 20));
+    }
+
+    for &bits in &[9, 10, 12, 16] {
+        for &size in &sizes {
+            let src = full(bits, size);
+            let measured = src.measure(&MeasureOptions::new()).unwrap();
+            assert_eq!(measured.required, measured.fully_allocated);
+
+            // A copy is laid out with no wasted space, so it's exactly the predicted size.
+            let mut copy = Vec::new();
+            Qcow2::create_from(&src.reader().unwrap(), &mut copy, &CreateOptions::new()).unwrap();
+            assert_eq!(copy.len() as u64, measured.fully_allocated, "bits {} size {}", bits, size);
+
+            // Preallocating in place gives the same size, as long as the refcount table doesn't
+            // need to grow. The test images always give the L1 table a cluster, even when it's
+            // empty.
+            if bits < 16 || size == 0 {
+                continue;
+            }
+            let mut img = ImageBuilder::new().cluster_bits(bits).size(size).build();
+            {
+                let mut qcow = Qcow2::open(&mut img).unwrap();
+                qcow.writer().unwrap().preallocate(0, size, Preallocation::Full).unwrap();
+            }
+            assert_eq!(img.len() as u64, measured.fully_allocated, "size {}", size);
+        }
+    }
+}
+
+#[test]
+fn measure_required() {
+    let cs = 65536;
+    let img = ImageBuilder::new()
+        .size(10 << 20)
+        .data(0, &[1; 16])
+        .data(3 * cs, &[2; 16])
+        .data(4 * cs, &[3; 16])
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let measured = qcow.measure(&MeasureOptions::new()).unwrap();
+    assert_eq!(measured.fully_allocated - measured.required, (10 << 20) - 3 * cs);
+    let mut copy = Vec::new();
+    Qcow2::create_from(&qcow.reader().unwrap(), &mut copy, &CreateOptions::new()).unwrap();
+    assert!(copy.len() as u64 <= measured.required);
+
+    // With bigger clusters, all the data is in the first one.
+    let big = qcow.measure(MeasureOptions::new().cluster_bits(21)).unwrap();
+    assert_eq!(big.fully_allocated - big.required, (10 << 20) - (2 << 20));
+    // With 64-bit refcounts, the refcount blocks take up more.
+    let narrow = qcow.measure(MeasureOptions::new().cluster_bits(9)).unwrap();
+    let wide = qcow.measure(MeasureOptions::new().cluster_bits(9).refcount_bits(64)).unwrap();
+    assert!(wide.fully_allocated > narrow.fully_allocated);
+
+    assert!(matches!(qcow.measure(MeasureOptions::new().cluster_bits(22)),
+                     Err(Error::UnsupportedFeature(_))));
+    assert!(matches!(qcow.measure(MeasureOptions::new().refcount_bits(3)),
+                     Err(Error::UnsupportedFeature(_))));
+}
+
+#[test]
+fn measure_snapshots() {
+    let img = ImageBuilder::new().data(0, &[1; 16]).snapshot(SnapshotSpec::new("1", "s")).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    let before = qcow.measure(MeasureOptions::new().snapshots(true)).unwrap();
+    // The snapshot table and the snapshot's L1 table.
+    assert_eq!(before.fully_allocated - qcow.measure(&MeasureOptions::new()).unwrap()
+                   .fully_allocated,
+               2 * 65536);
+
+    // Once the active disk has its own copy, the old data and L2 table are the snapshot's.
+    qcow.writer().unwrap().write_all_at(0, &[2; 16]).unwrap();
+    let after = qcow.measure(MeasureOptions::new().snapshots(true)).unwrap();
+    assert_eq!(after.fully_allocated - before.fully_allocated, 2 * 65536);
+    assert!(matches!(qcow.measure(MeasureOptions::new().snapshots(true).cluster_bits(12)),
+                     Err(Error::UnsupportedFeature(_))));
+}
@@ -0,0 +1,354 @@
+#![cfg(feature = "nbd")]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+
+use common::{ImageBuilder, TempDir};
+
+const CS: u64 = 65536;
+const SIZE: u64 = 1 << 20;
+
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+#[cfg(unix)]
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_LIST: u32 = 3;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_SET_META_CONTEXT: u32 = 10;
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_BLOCK_STATUS: u16 = 7;
+const CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) + 1;
+const ZERO: u32 = 2;
+const HOLE_ZERO: u32 = 3;
+const EPERM: u32 = 1;
+const EINVAL: u32 = 22;
+
+// A running server, killed when dropped.
+struct Server {
+    child: Child,
+    // Where it's listening.
+    addr: String,
+    _dir: TempDir,
+}
+
+impl Server {
+    // Serve an image with data in the first two clusters, and zeros in the third.
+    fn start(name: &str, args: &[&str]) -> Self {
+        let dir = TempDir::new(name);
+        let img = ImageBuilder::new().data(0, b"hello").data(CS, &[2; 16]).zero_cluster(2 * CS)
+            .build();
+        dir.write("disk.qcow2", &img);
+        Self::serve(dir, "disk.qcow2", args)
+    }
+
+    // Serve an image from a directory holding it and any files it needs.
+    fn serve(dir: TempDir, image: &str, args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_qcow2-nbd"))
+            .args(args)
+            .arg(dir.0.join(image))
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stderr.as_mut().unwrap()).read_line(&mut line).unwrap();
+        let addr = line.trim().strip_prefix("Listening on ").unwrap().to_owned();
+        Server { child, addr, _dir: dir }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A reply to an option.
+struct OptionReply {
+    kind: u32,
+    data: Vec<u8>,
+}
+
+// A reply to a request, with only one chunk if it's structured.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Simple { error: u32, handle: u64 },
+    Chunk { kind: u16, handle: u64, data: Vec<u8> },
+}
+
+struct Client<S> {
+    stream: S,
+    handle: u64,
+}
+
+impl<S: Read + Write> Client<S> {
+    fn new(mut stream: S) -> Self {
+        let mut hello = [0; 18];
+        stream.read_exact(&mut hello).unwrap();
+        assert_eq!(&hello[..8], b"NBDMAGIC");
+        assert_eq!(u64::from_be_bytes(hello[8..16].try_into().unwrap()), IHAVEOPT);
+        // Fixed newstyle, with no zeroes.
+        assert_eq!(&hello[16..], &[0, 3]);
+        stream.write_all(&3u32.to_be_bytes()).unwrap();
+        Client { stream, handle: 0 }
+    }
+
+    fn u16(&mut self) -> u16 {
+        let mut buf = [0; 2];
+        self.stream.read_exact(&mut buf).unwrap();
+        u16::from_be_bytes(buf)
+    }
+
+    fn u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.stream.read_exact(&mut buf).unwrap();
+        u32::from_be_bytes(buf)
+    }
+
+    fn u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.stream.read_exact(&mut buf).unwrap();
+        u64::from_be_bytes(buf)
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        self.stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn option(&mut self, opt: u32, data: &[u8]) {
+        let mut buf = IHAVEOPT.to_be_bytes().to_vec();
+        buf.extend_from_slice(&opt.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf).unwrap();
+    }
+
+    fn option_reply(&mut self, opt: u32) -> OptionReply {
+        assert_eq!(self.u64(), REPLY_MAGIC);
+        assert_eq!(self.u32(), opt);
+        let kind = self.u32();
+        let len = self.u32() as usize;
+        OptionReply { kind, data: self.bytes(len) }
+    }
+
+    fn request(&mut self, kind: u16, flags: u16, offset: u64, len: u32) -> u64 {
+        self.handle += 1;
+        let mut buf = 0x2560_9513u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&kind.to_be_bytes());
+        buf.extend_from_slice(&self.handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
+        self.stream.write_all(&buf).unwrap();
+        self.handle
+    }
+
+    // Read a reply. A simple reply to a read must be read separately.
+    fn reply(&mut self) -> Reply {
+        match self.u32() {
+            0x6744_6698 => Reply::Simple { error: self.u32(), handle: self.u64() },
+            0x668e_33ef => {
+                // Everything is done in one chunk.
+                assert_eq!(self.u16(), 1);
+                let kind = self.u16();
+                let handle = self.u64();
+                let len = self.u32() as usize;
+                Reply::Chunk { kind, handle, data: self.bytes(len) }
+            }
+            m => panic!("bad reply magic {:#x}", m),
+        }
+    }
+
+    fn go(&mut self, name: &str) -> OptionReply {
+        let mut data = (name.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&0u16.to_be_bytes());
+        self.option(OPT_GO, &data);
+        self.option_reply(OPT_GO)
+    }
+
+    // Read some data, with structured replies.
+    fn read(&mut self, offset: u64, len: u32) -> Vec<u8> {
+        let handle = self.request(CMD_READ, 0, offset, len);
+        let data = match self.reply() {
+            Reply::Chunk { kind: REPLY_TYPE_OFFSET_DATA, handle: h, data } if h == handle => data,
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert_eq!(&data[..8], &offset.to_be_bytes());
+        data[8..].to_vec()
+    }
+
+    fn block_status(&mut self, offset: u64, len: u32, flags: u16) -> Vec<(u32, u32)> {
+        let handle = self.request(CMD_BLOCK_STATUS, flags, offset, len);
+        let data = match self.reply() {
+            Reply::Chunk { kind: REPLY_TYPE_BLOCK_STATUS, handle: h, data } if h == handle => data,
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert_eq!(&data[..4], &1u32.to_be_bytes());
+        data[4..]
+            .chunks(8)
+            .map(|d| {
+                (u32::from_be_bytes(d[..4].try_into().unwrap()),
+                 u32::from_be_bytes(d[4..].try_into().unwrap()))
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn structured() {
+    let server = Server::start("structured.qcow2", &["--port", "0", "--name", "disk"]);
+    let mut client = Client::new(TcpStream::connect(&server.addr).unwrap());
+
+    client.option(OPT_LIST, &[]);
+    let reply = client.option_reply(OPT_LIST);
+    assert_eq!(reply.kind, REP_SERVER);
+    assert_eq!(reply.data, b"\0\0\0\x04disk");
+    assert_eq!(client.option_reply(OPT_LIST).kind, REP_ACK);
+
+    client.option(OPT_STRUCTURED_REPLY, &[]);
+    assert_eq!(client.option_reply(OPT_STRUCTURED_REPLY).kind, REP_ACK);
+    let mut meta = b"\0\0\0\x04disk\0\0\0\x01\0\0\0\x0fbase:allocation".to_vec();
+    client.option(OPT_SET_META_CONTEXT, &meta);
+    let reply = client.option_reply(OPT_SET_META_CONTEXT);
+    assert_eq!(reply.kind, REP_META_CONTEXT);
+    assert_eq!(reply.data, b"\0\0\0\x01base:allocation");
+    assert_eq!(client.option_reply(OPT_SET_META_CONTEXT).kind, REP_ACK);
+    // Asking for an unknown export fails, without ending the handshake.
+    meta[4] = b'x';
+    client.option(OPT_SET_META_CONTEXT, &meta);
+    assert_eq!(client.option_reply(OPT_SET_META_CONTEXT).kind, REP_ERR_UNKNOWN);
+    assert_eq!(client.go("nope").kind, REP_ERR_UNKNOWN);
+
+    // The export is read-only, and as big as the virtual disk.
+    let reply = client.go("disk");
+    assert_eq!(reply.kind, REP_INFO);
+    assert_eq!(&reply.data[..10], &[&[0, 0][..], &SIZE.to_be_bytes()].concat()[..]);
+    assert_eq!(u16::from_be_bytes(reply.data[10..].try_into().unwrap()) & 3, 3);
+    assert_eq!(client.option_reply(OPT_GO).kind, REP_ACK);
+
+    let handle = client.request(CMD_READ, 0, 1, 8);
+    let mut expected = 1u64.to_be_bytes().to_vec();
+    expected.extend_from_slice(b"ello\0\0\0\0");
+    assert_eq!(client.reply(),
+               Reply::Chunk { kind: REPLY_TYPE_OFFSET_DATA, handle, data: expected });
+
+    // The zero cluster is allocated, so it isn't a hole.
+    assert_eq!(client.block_status(0, SIZE as u32, 0),
+               vec![(2 * CS as u32, 0), (CS as u32, ZERO), ((SIZE - 3 * CS) as u32, HOLE_ZERO)]);
+    assert_eq!(client.block_status(CS + 1, 2 * CS as u32, 0),
+               vec![(CS as u32 - 1, 0), (CS as u32, ZERO), (1, HOLE_ZERO)]);
+    assert_eq!(client.block_status(10, SIZE as u32 - 10, CMD_FLAG_REQ_ONE),
+               vec![(2 * CS as u32 - 10, 0)]);
+
+    // Reading past the end, and writing, fail.
+    let handle = client.request(CMD_READ, 0, SIZE - 1, 2);
+    let mut expected = EINVAL.to_be_bytes().to_vec();
+    expected.extend_from_slice(&[0, 0]);
+    assert_eq!(client.reply(), Reply::Chunk { kind: REPLY_TYPE_ERROR, handle, data: expected });
+    let handle = client.request(CMD_WRITE, 0, 0, 4);
+    client.stream.write_all(b"data").unwrap();
+    let mut expected = EPERM.to_be_bytes().to_vec();
+    expected.extend_from_slice(&[0, 0]);
+    assert_eq!(client.reply(), Reply::Chunk { kind: REPLY_TYPE_ERROR, handle, data: expected });
+
+    client.request(CMD_DISC, 0, 0, 0);
+    assert_eq!(client.stream.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn backing() {
+    // The middle image's backing file is raw, and smaller than the rest of the chain. Each
+    // backing file is found relative to the image that names it.
+    let dir = TempDir::new("backing");
+    dir.write("sub/base.raw", &[7; 4 * CS as usize]);
+    let mid = ImageBuilder::new()
+        .backing_file("base.raw")
+        .extension(0xe2792aca, b"raw".to_vec())
+        .data(CS, &[2; 16])
+        .build();
+    dir.write("sub/mid.qcow2", &mid);
+    dir.write("top.qcow2",
+              &ImageBuilder::new().backing_file("sub/mid.qcow2").data(0, b"top").build());
+    let server = Server::serve(dir, "top.qcow2", &["--port", "0"]);
+
+    let mut client = Client::new(TcpStream::connect(&server.addr).unwrap());
+    client.option(OPT_STRUCTURED_REPLY, &[]);
+    assert_eq!(client.option_reply(OPT_STRUCTURED_REPLY).kind, REP_ACK);
+    client.option(OPT_SET_META_CONTEXT, b"\0\0\0\0\0\0\0\x01\0\0\0\x0fbase:allocation");
+    assert_eq!(client.option_reply(OPT_SET_META_CONTEXT).kind, REP_META_CONTEXT);
+    assert_eq!(client.option_reply(OPT_SET_META_CONTEXT).kind, REP_ACK);
+    assert_eq!(client.go("").kind, REP_INFO);
+    assert_eq!(client.option_reply(OPT_GO).kind, REP_ACK);
+
+    assert_eq!(client.read(0, 4), b"top\0");
+    assert_eq!(client.read(CS, 16), [2; 16]);
+    assert_eq!(client.read(3 * CS, 16), [7; 16]);
+    assert_eq!(client.read(4 * CS - 8, 16), [&[7; 8][..], &[0; 8]].concat());
+    assert_eq!(client.block_status(0, SIZE as u32, 0),
+               vec![(4 * CS as u32, 0), ((SIZE - 4 * CS) as u32, HOLE_ZERO)]);
+    assert_eq!(client.block_status(3 * CS, 2 * CS as u32, CMD_FLAG_REQ_ONE),
+               vec![(CS as u32, 0)]);
+}
+
+#[test]
+fn backing_missing() {
+    let dir = TempDir::new("backing-missing");
+    let top = dir.write("top.qcow2", &ImageBuilder::new().backing_file("gone.qcow2").build());
+    let out = Command::new(env!("CARGO_BIN_EXE_qcow2-nbd"))
+        .args(["--port", "0"])
+        .arg(top)
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.starts_with("Error opening file `") && err.contains("gone.qcow2"), "{}", err);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    use common::TempFile;
+
+    let socket = TempFile::new("nbd.sock");
+    let server = Server::start("unix.qcow2", &["--socket", socket.path()]);
+    assert_eq!(server.addr, socket.path());
+
+    // Several clients can read at once.
+    let mut clients: Vec<_> = (0..3)
+        .map(|_| {
+            let mut client = Client::new(UnixStream::connect(socket.path()).unwrap());
+            client.option(OPT_EXPORT_NAME, &[]);
+            assert_eq!(client.u64(), SIZE);
+            assert_eq!(client.u16() & 3, 3);
+            client
+        })
+        .collect();
+    for client in &mut clients {
+        let handle = client.request(CMD_READ, 0, 0, 5);
+        assert_eq!(client.reply(), Reply::Simple { error: 0, handle });
+        assert_eq!(client.bytes(5), b"hello");
+
+        // Without structured replies, there's no block status.
+        let handle = client.request(CMD_BLOCK_STATUS, 0, 0, 5);
+        assert_eq!(client.reply(), Reply::Simple { error: EINVAL, handle });
+    }
+}
@@ -0,0 +1,119 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use common::{ImageBuilder, TempDir};
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::{Backing, Error, OverlayOptions, Qcow2, backing_chain, create_overlay};
+
+fn open(path: &Path) -> Qcow2<File> {
+    Qcow2::open(File::open(path).unwrap()).unwrap()
+}
+
+#[test]
+fn overlay() {
+    let dir = TempDir::new("overlay");
+    let base_img = ImageBuilder::new().data(0, b"base").size(3 << 20).build();
+    let base = dir.write("base.qcow2", &base_img);
+    let top = dir.0.join("top.qcow2");
+    create_overlay(&base, &top, &OverlayOptions::new()).unwrap();
+
+    let mut qcow = open(&top);
+    assert_eq!(qcow.guest_size(), 3 << 20);
+    assert_eq!(qcow.cluster_size(), 65536);
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("base.qcow2")));
+    assert_eq!(qcow.backing_format(), Some("qcow2"));
+    assert!(qcow.check().unwrap().is_clean());
+    assert_eq!(qcow.info().unwrap().allocated_size, 0);
+
+    // It reads the same as the base, and writes leave the base alone.
+    qcow.set_backing(Backing::qcow2(open(&base)).unwrap());
+    let mut buf = [0; 4];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"base");
+    let mut qcow = Qcow2::open(common::open_rw(&top)).unwrap();
+    let base_file = common::WriteFile::open(&base).unwrap();
+    qcow.set_backing(Backing::qcow2(Qcow2::open(base_file).unwrap()).unwrap());
+    qcow.writer().unwrap().write_all_at(0, b"top").unwrap();
+    assert_eq!(fs::read(&base).unwrap(), base_img);
+
+    let chain = backing_chain(&top);
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[1].path, dir.0.join("base.qcow2"));
+    assert!(chain.iter().all(|l| l.error.is_none() && l.problems.is_empty()));
+}
+
+#[test]
+fn overlay_relative() {
+    let dir = TempDir::new("relative");
+    let base = dir.write("images/base/disk.raw", &[7; 10000]);
+    fs::create_dir_all(dir.0.join("images/snaps")).unwrap();
+    let top = dir.0.join("images/snaps/top.qcow2");
+    create_overlay(&base, &top, &OverlayOptions::new()).unwrap();
+
+    let qcow = open(&top);
+    assert_eq!(qcow.guest_size(), 10000);
+    assert_eq!(qcow.cluster_size(), 65536);
+    assert_eq!(qcow.backing_file_name(), Some(Path::new("../base/disk.raw")));
+    assert_eq!(qcow.backing_format(), Some("raw"));
+    let chain = backing_chain(&top);
+    assert_eq!(chain[1].format.as_deref(), Some("raw"));
+    assert!(chain[1].error.is_none());
+
+    // Or the path can be absolute.
+    let top = dir.0.join("images/snaps/absolute.qcow2");
+    create_overlay(&base, &top, OverlayOptions::new().absolute_backing(true)).unwrap();
+    assert_eq!(open(&top).backing_file_name(), Some(fs::canonicalize(&base).unwrap().as_path()));
+}
+
+#[test]
+fn overlay_options() {
+    let dir = TempDir::new("options");
+    let base = dir.write("base.qcow2", &ImageBuilder::new().cluster_bits(12).build());
+    let top = dir.0.join("top.qcow2");
+    create_overlay(&base, &top, &OverlayOptions::new()).unwrap();
+    assert_eq!(open(&top).cluster_size(), 4096);
+
+    let top = dir.0.join("big.qcow2");
+    create_overlay(&base, &top, OverlayOptions::new().size(5 << 30).cluster_bits(20)).unwrap();
+    let qcow = open(&top);
+    assert_eq!(qcow.guest_size(), 5 << 30);
+    assert_eq!(qcow.cluster_size(), 1 << 20);
+    assert!(qcow.check().unwrap().is_clean());
+
+    let top = dir.0.join("bad.qcow2");
+    let err = create_overlay(&base, &top, OverlayOptions::new().size(4096)).unwrap_err();
+    assert!(matches!(err, Error::UnsupportedFeature(_)));
+    let err = create_overlay(&base, &top, OverlayOptions::new().cluster_bits(8)).unwrap_err();
+    assert!(matches!(err, Error::UnsupportedFeature(_)));
+    assert!(!top.exists());
+}
+
+#[test]
+fn overlay_unreadable() {
+    let dir = TempDir::new("unreadable");
+    let top = dir.0.join("top.qcow2");
+    let err = create_overlay(dir.0.join("missing.qcow2"), &top, &OverlayOptions::new())
+        .unwrap_err();
+    assert!(matches!(err, Error::Io(ref e) if e.kind() == ErrorKind::NotFound));
+
+    // An encrypted base can't be read.
+    let mut img = ImageBuilder::new().build();
+    img[32..36].copy_from_slice(&1u32.to_be_bytes());
+    let base = dir.write("encrypted.qcow2", &img);
+    let err = create_overlay(&base, &top, &OverlayOptions::new()).unwrap_err();
+    assert!(matches!(err, Error::UnsupportedFeature(_)));
+    assert!(!top.exists());
+
+    // Nothing already at the destination is replaced.
+    let base = dir.write("base.qcow2", &ImageBuilder::new().build());
+    let top = dir.write("top.qcow2", b"precious");
+    let err = create_overlay(&base, &top, &OverlayOptions::new()).unwrap_err();
+    assert!(matches!(err, Error::Io(ref e) if e.kind() == ErrorKind::AlreadyExists));
+    assert_eq!(fs::read(&top).unwrap(), b"precious");
+}
@@ -0,0 +1,45 @@
+extern crate qcow2;
+
+mod common;
+
+use std::fs::File;
+
+use common::ImageBuilder;
+use qcow2::{Probe, Qcow2};
+
+#[test]
+fn probe_bundled() {
+    let probe = qcow2::probe(&File::open("tests/test.qcow2").unwrap()).unwrap();
+    assert_eq!(probe,
+               Probe {
+                   is_qcow2: true,
+                   version: 3,
+                   virtual_size: 1048576000,
+                   cluster_bits: 16,
+                   crypt_method: 0,
+                   has_backing_file: false,
+               });
+}
+
+#[test]
+fn probe_unsupported() {
+    // Images that can't be opened can still be probed.
+    let builder = ImageBuilder::new();
+    let mut img = ImageBuilder { incompatible: 1 << 40, ..builder }.build();
+    img[32..36].copy_from_slice(&1u32.to_be_bytes());
+    img[8..16].copy_from_slice(&512u64.to_be_bytes());
+    assert!(Qcow2::open(img.clone()).is_err());
+
+    let probe = qcow2::probe(&img).unwrap();
+    assert!(probe.is_qcow2);
+    assert_eq!(probe.crypt_method, 1);
+    assert!(probe.has_backing_file);
+}
+
+#[test]
+fn probe_not_qcow2() {
+    assert!(!qcow2::probe(&vec![0u8; 4096]).unwrap().is_qcow2);
+    let mut short = ImageBuilder::new().build();
+    short.truncate(40);
+    assert_eq!(qcow2::probe(&short).unwrap(), Probe::default());
+}
@@ -0,0 +1,62 @@
+#![cfg(any(windows, all(unix, feature = "random-access")))]
+
+extern crate qcow2;
+mod common;
+
+use std::thread;
+
+use common::TempFile;
+use positioned_io::{ReadAt, Size, WriteAt};
+use qcow2::{Qcow2, RandomAccessFile, SharedBackend};
+
+#[test]
+fn random_access_read() {
+    let qcow = Qcow2::open_random_access("tests/test.qcow2").unwrap();
+    let file = Qcow2::open(std::fs::File::open("tests/test.qcow2").unwrap()).unwrap();
+    let (mut buf, mut expected) = (vec![0; 65536], vec![0; 65536]);
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    file.reader().unwrap().read_exact_at(0, &mut expected).unwrap();
+    assert_eq!(buf, expected);
+}
+
+#[test]
+fn random_access_write() {
+    let contents = std::fs::read("tests/test.qcow2").unwrap();
+    let copy = TempFile::with_contents("random-access-write.qcow2", &contents);
+
+    let mut qcow = Qcow2::open(RandomAccessFile::open_rw(&copy.0).unwrap()).unwrap();
+    qcow.writer().unwrap().write_all_at(4096, b"guest write").unwrap();
+    qcow.sync().unwrap();
+    drop(qcow);
+    let mut buf = [0; 11];
+    copy.open().reader().unwrap().read_exact_at(4096, &mut buf).unwrap();
+    assert_eq!(&buf, b"guest write");
+
+    // Writing past the end extends the file.
+    let mut raw = RandomAccessFile::open_rw(&copy.0).unwrap();
+    let end = raw.size().unwrap().unwrap();
+    raw.write_all_at(end + 100, b"raw write").unwrap();
+    assert_eq!(raw.size().unwrap(), Some(end + 109));
+    let written = std::fs::read(&copy.0).unwrap();
+    assert_eq!(&written[end as usize + 100..], b"raw write");
+}
+
+#[test]
+fn random_access_threads() {
+    let qcow = Qcow2::open(SharedBackend::new(RandomAccessFile::open("tests/test.qcow2").unwrap()))
+        .unwrap();
+    let mut expected = vec![0; 8 * 4096];
+    qcow.reader().unwrap().read_exact_at(0, &mut expected).unwrap();
+    thread::scope(|s| {
+        for i in 0..8 {
+            let (qcow, expected) = (qcow.clone(), &expected);
+            s.spawn(move || {
+                let mut buf = vec![0; 4096];
+                for _ in 0..50 {
+                    qcow.reader().unwrap().read_exact_at(i * 4096, &mut buf).unwrap();
+                    assert_eq!(buf, expected[i as usize * 4096..][..4096]);
+                }
+            });
+        }
+    });
+}
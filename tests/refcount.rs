@@ -0,0 +1,71 @@
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{CheckFinding, Qcow2};
+
+// An image with 512-byte clusters, and enough data to need more than one refcount block.
+fn builder(refcount_order: u32) -> ImageBuilder {
+    let mut builder = ImageBuilder::new()
+        .cluster_bits(9)
+        .size(4 << 20)
+        .refcount_order(refcount_order);
+    for i in 0..builder.refcount_block_size() + 64 {
+        builder = builder.data(i * 512, b"data");
+    }
+    builder
+}
+
+fn check_order(order: u32, per_block: u64) {
+    let builder = builder(order);
+    assert_eq!(builder.refcount_block_size(), per_block);
+    let mut img = builder.build();
+    let len = img.len() as u64;
+    let result = Qcow2::open(img.clone()).unwrap().check().unwrap();
+    assert!(result.is_clean(), "{:?}", result.findings);
+    assert_eq!(result.image_end_offset, len);
+
+    let q = Qcow2::open(img.clone()).unwrap();
+    let table = q.refcount_table_entries().unwrap();
+    let blocks: Vec<_> = table.iter().filter(|e| e.block_offset != 0).collect();
+    assert_eq!(blocks.len() as u64, (len / 512).div_ceil(per_block));
+    let block = q.refcount_block_entries(blocks[0].block_offset).unwrap();
+    assert_eq!(block.len() as u64, per_block);
+
+    // A cluster just past the first refcount block.
+    builder.set_refcount(&mut img, per_block, 0);
+    let result = Qcow2::open(img).unwrap().check().unwrap();
+    assert_eq!(result.findings,
+               vec![CheckFinding::Refcount { cluster: per_block, refcount: 0, references: 1 }]);
+}
+
+#[test]
+fn order_0() {
+    check_order(0, 4096);
+}
+
+#[test]
+fn order_4() {
+    check_order(4, 256);
+}
+
+#[test]
+fn order_6() {
+    check_order(6, 64);
+}
+
+#[test]
+fn order_6_wide_values() {
+    let builder = builder(6);
+    let mut img = builder.build();
+    builder.set_refcount(&mut img, 100, 1 << 40);
+    let q = Qcow2::open(img).unwrap();
+    let offset = q.refcount_table_entries().unwrap()[1].block_offset;
+    assert_eq!(q.refcount_block_entries(offset).unwrap()[100 - 64], 1 << 40);
+
+    let result = q.check().unwrap();
+    assert_eq!(result.leaks, 1);
+    assert_eq!(result.findings,
+               vec![CheckFinding::Refcount { cluster: 100, refcount: 1 << 40, references: 1 }]);
+}
@@ -0,0 +1,75 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::{ImageBuilder, Op, RecordingIo};
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::Qcow2;
+
+// With 512-byte clusters, each L1 entry covers 32 KiB, and each L1 cluster covers 2 MiB.
+fn builder() -> ImageBuilder {
+    ImageBuilder::new().cluster_bits(9).data(0, &[1; 16])
+}
+
+#[test]
+fn resize_in_place() {
+    let mut io = RecordingIo::new(builder().build());
+    let mut qcow = Qcow2::open(&mut io).unwrap();
+    qcow.resize(2 << 20).unwrap();
+    assert_eq!(qcow.guest_size(), 2 << 20);
+    drop(qcow);
+    // The L1 table fills its cluster exactly.
+    assert_eq!(io.ops,
+               vec![Op::Write { pos: 3 * 512 + 32 * 8, len: 32 * 8 },
+                    Op::Sync,
+                    Op::Write { pos: 24, len: 24 }]);
+
+    let qcow = Qcow2::open(io.inner).unwrap();
+    assert_eq!(qcow.guest_size(), 2 << 20);
+    assert_eq!(qcow.l1_table_entries().unwrap().len(), 64);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn resize_relocate() {
+    let size = (2 << 20) + 1;
+    let mut io = RecordingIo::new(builder().build());
+    let mut qcow = Qcow2::open(&mut io).unwrap();
+    qcow.resize(size).unwrap();
+    drop(qcow);
+    // One more entry needs another cluster, so the L1 table moves to clusters 6 and 7.
+    assert_eq!(io.ops,
+               vec![Op::Write { pos: 2 * 512 + 12, len: 4 },
+                    Op::Sync,
+                    Op::Write { pos: 6 * 512, len: 1024 },
+                    Op::Sync,
+                    Op::Write { pos: 24, len: 24 },
+                    Op::Sync,
+                    Op::Write { pos: 2 * 512 + 6, len: 2 }]);
+
+    let mut img = io.inner;
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        assert_eq!(qcow.guest_size(), size);
+        assert_eq!(qcow.l1_table_entries().unwrap().len(), 65);
+        assert!(qcow.check().unwrap().is_clean());
+        qcow.writer().unwrap().write_all_at(size - 1, &[2]).unwrap();
+    }
+
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 16];
+    reader.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    reader.read_exact_at(size - 16, &mut buf).unwrap();
+    assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn resize_shrink() {
+    let mut qcow = Qcow2::open(builder().build()).unwrap();
+    assert_eq!(qcow.resize(1000).unwrap_err().to_string(),
+               "Unsupported feature: shrinking images");
+}
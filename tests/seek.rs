@@ -0,0 +1,61 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use positioned_io::{ReadAt, Size};
+use qcow2::{Qcow2, SeekBackend};
+
+// A source that counts seeks.
+struct CountingSeek<'a> {
+    inner: Cursor<Vec<u8>>,
+    seeks: &'a AtomicUsize,
+}
+
+impl Read for CountingSeek<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for CountingSeek<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.seeks.fetch_add(1, Ordering::SeqCst);
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn seek_backend() {
+    let bytes = fs::read("tests/test.qcow2").unwrap();
+    let backend = SeekBackend::new(Cursor::new(bytes.clone()));
+    assert_eq!(backend.size().unwrap(), Some(bytes.len() as u64));
+
+    let qcow = Qcow2::open(backend).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = vec![0; 1 << 20];
+    reader.read_exact_at(0, &mut buf).unwrap();
+
+    let file = Qcow2::open(File::open("tests/test.qcow2").unwrap()).unwrap();
+    let mut expected = vec![0; 1 << 20];
+    file.reader().unwrap().read_exact_at(0, &mut expected).unwrap();
+    assert_eq!(buf, expected);
+}
+
+#[test]
+fn seek_backend_skips_seeks() {
+    let seeks = AtomicUsize::new(0);
+    let source = CountingSeek { inner: Cursor::new((0..=255).collect()), seeks: &seeks };
+    let backend = SeekBackend::new(source);
+    let mut buf = [0; 4];
+    backend.read_exact_at(8, &mut buf).unwrap();
+    assert_eq!(buf, [8, 9, 10, 11]);
+    backend.read_exact_at(12, &mut buf).unwrap();
+    assert_eq!(seeks.load(Ordering::SeqCst), 1);
+    backend.read_exact_at(4, &mut buf).unwrap();
+    assert_eq!(buf, [4, 5, 6, 7]);
+    assert_eq!(seeks.load(Ordering::SeqCst), 2);
+    assert_eq!(backend.into_inner().inner.position(), 8);
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "serde")]
+
+extern crate qcow2;
+extern crate serde_json;
+
+use std::fs::File;
+
+use qcow2::Qcow2;
+
+#[test]
+fn info_json() {
+    let file = File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let info = qcow.info().unwrap();
+    let json = serde_json::to_value(&info).unwrap();
+
+    let golden = File::open("tests/test.qcow2.info.json").unwrap();
+    let golden: serde_json::Value = serde_json::from_reader(golden).unwrap();
+    assert_eq!(json, golden);
+
+    let back: qcow2::ImageInfo = serde_json::from_value(json).unwrap();
+    assert_eq!(back.virtual_size, info.virtual_size);
+}
+
+#[test]
+fn feature_name_table_json() {
+    let file = File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let json = serde_json::to_value(qcow.feature_name_table()).unwrap();
+    assert_eq!(json[2],
+               serde_json::json!({"kind": "compatible", "bit": 0, "name": "lazy refcounts"}));
+}
+
+#[test]
+fn map_json() {
+    let file = File::open("tests/test.qcow2").unwrap();
+    let qcow = Qcow2::open(file).unwrap();
+    let map = qcow.reader().unwrap().map().unwrap();
+    let json = serde_json::to_value(&map).unwrap();
+    for (entry, value) in map.iter().zip(json.as_array().unwrap()) {
+        assert_eq!(value["start"], entry.start);
+        // Holes have no offset, which is left out like qemu does.
+        assert_eq!(value.get("offset").and_then(|o| o.as_u64()), entry.offset);
+    }
+    let back: Vec<qcow2::MapEntry> = serde_json::from_value(json).unwrap();
+    assert_eq!(back, map);
+}
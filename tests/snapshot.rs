@@ -0,0 +1,128 @@
+extern crate qcow2;
+
+mod common;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::{ImageBuilder, SnapshotSpec};
+use qcow2::{Error, Qcow2};
+
+// Build an image with two snapshots, returning it and the offset of the snapshot table.
+fn image() -> (Vec<u8>, usize) {
+    let img = ImageBuilder::new()
+        .data(0, b"data")
+        .snapshot(SnapshotSpec::new("1", "first"))
+        .snapshot(SnapshotSpec::new("2", "second"))
+        .build();
+    let offset = u64::from_be_bytes(img[64..72].try_into().unwrap()) as usize;
+    (img, offset)
+}
+
+fn open_error(img: Vec<u8>) -> String {
+    match Qcow2::open(img) {
+        Err(Error::FileFormat(msg)) => msg,
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn snapshot_count() {
+    // Only the declared number of entries is read, even if more follow.
+    let (mut img, _) = image();
+    img[60..64].copy_from_slice(&1u32.to_be_bytes());
+    let qcow = Qcow2::open(img.clone()).unwrap();
+    assert_eq!(qcow.snapshots().len(), 1);
+    assert_eq!(qcow.snapshots()[0].name, "first");
+
+    img[60..64].copy_from_slice(&100_000u32.to_be_bytes());
+    assert_eq!(open_error(img), "too many snapshots (100000)");
+}
+
+#[test]
+fn snapshot_table_past_eof() {
+    let (mut img, offset) = image();
+    let len = img.len() as u64;
+    img[60..64].copy_from_slice(&5000u32.to_be_bytes());
+    match Qcow2::open(img.clone()) {
+        Err(Error::Truncated { expected_at_least, actual }) => {
+            assert_eq!((expected_at_least, actual), (offset as u64 + 5000 * 40, len));
+        }
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+
+    // The fixed part of the entry is there, but not its name.
+    img[60..64].copy_from_slice(&1u32.to_be_bytes());
+    img.truncate(offset + 45);
+    assert_eq!(open_error(img), "snapshot table is past the end of the file");
+}
+
+#[test]
+fn snapshot_entry_limits() {
+    let (img, offset) = image();
+    let mut bad_id = img.clone();
+    bad_id[offset + 12..offset + 14].copy_from_slice(&1000u16.to_be_bytes());
+    assert_eq!(open_error(bad_id), "snapshot 0: snapshot ID too long (1000 bytes)");
+
+    let mut bad_extra = img;
+    bad_extra[offset + 36..offset + 40].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert_eq!(open_error(bad_extra),
+               "snapshot 0: snapshot extra data too big (4294967295 bytes)");
+}
+
+#[test]
+fn snapshot_date() {
+    let dated = |sec, nsec| SnapshotSpec {
+        date_sec: sec,
+        date_nsec: nsec,
+        ..SnapshotSpec::new(&sec.to_string(), "snap")
+    };
+    let img = ImageBuilder::new()
+        .snapshot(dated(1700000000, 500))
+        .snapshot(dated(951782400, 1_500_000_000))
+        .snapshot(dated(0, 0))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    let dates: Vec<_> = qcow.snapshots().iter().map(|s| s.date()).collect();
+    assert_eq!(dates,
+               vec![Some(UNIX_EPOCH + Duration::new(1700000000, 500)),
+                    Some(UNIX_EPOCH + Duration::new(951782401, 500_000_000)),
+                    None]);
+
+    let text = qcow.info().unwrap().to_string();
+    assert!(text.contains(" 2023-11-14T22:13:20Z "));
+    // A leap day.
+    assert!(text.contains(" 2000-02-29T00:00:01Z "));
+    assert!(text.contains(" unknown "));
+}
+
+#[test]
+fn snapshot_find() {
+    // The name of the first snapshot is the ID of the second.
+    let img = ImageBuilder::new()
+        .snapshot(SnapshotSpec::new("1", "2"))
+        .snapshot(SnapshotSpec::new("2", "second"))
+        .build();
+    let qcow = Qcow2::open(img).unwrap();
+    assert_eq!(qcow.find_snapshot("2").unwrap().name, "second");
+    assert_eq!(qcow.find_snapshot_by_name("2").unwrap().id, "1");
+    assert_eq!(qcow.find_snapshot_by_id("2").unwrap().id, "2");
+    assert_eq!(qcow.find_snapshot("second").unwrap().id, "2");
+    assert!(qcow.find_snapshot_by_id("second").is_none());
+    assert!(qcow.find_snapshot("3").is_none());
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn snapshot_duplicate_id() {
+    let img = ImageBuilder::new()
+        .snapshot(SnapshotSpec::new("1", "first"))
+        .snapshot(SnapshotSpec::new("1", "second"))
+        .build();
+    let offset = u64::from_be_bytes(img[64..72].try_into().unwrap());
+    let qcow = Qcow2::open(img).unwrap();
+    assert_eq!(qcow.find_snapshot("1").unwrap().name, "first");
+    let result = qcow.check().unwrap();
+    assert_eq!(result.corruptions, 1);
+    assert_eq!(result.findings[0].to_string(),
+               format!("ERROR at offset {:#x}: duplicate snapshot ID 1", offset));
+}
@@ -0,0 +1,101 @@
+extern crate qcow2;
+
+mod common;
+
+use common::ImageBuilder;
+use qcow2::{AllocationStats, Qcow2};
+
+#[test]
+fn clean_tables() {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let q = Qcow2::open(builder.build()).unwrap();
+
+    let l1 = q.l1_table_entries().unwrap();
+    assert_eq!(l1.len() as u64, builder.l1_entries());
+    assert_eq!(l1[0].l2_offset, 4 * 65536);
+    assert!(l1[0].copied);
+    assert_eq!((l1[0].reserved_bits, l1[0].misaligned), (0, false));
+
+    let l2 = q.l2_table_entries(l1[0].l2_offset).unwrap();
+    assert_eq!(l2.len(), 8192);
+    assert_eq!(l2[0].host_offset, 5 * 65536);
+    assert!(l2[0].copied && !l2[0].zero);
+    assert_eq!(l2[0].compressed_size, None);
+    assert_eq!(l2[1].raw, 0);
+
+    let reft = q.refcount_table_entries().unwrap();
+    assert_eq!(reft[0].block_offset, 2 * 65536);
+    let block = q.refcount_block_entries(reft[0].block_offset).unwrap();
+    assert_eq!(block.len(), 32768);
+    assert_eq!(&block[..7], &[1, 1, 1, 1, 1, 1, 0]);
+}
+
+#[test]
+fn invalid_entries() {
+    let builder = ImageBuilder::new().data(0, b"data");
+    let mut img = builder.build();
+    let l2 = 4 * 65536;
+    img[l2..l2 + 8].copy_from_slice(&((5u64 << 16) | 0x2 | (1 << 58)).to_be_bytes());
+    img[l2 + 8..l2 + 16].copy_from_slice(&((6u64 << 16) + 512 + 1).to_be_bytes());
+
+    // Invalid entries are decoded rather than rejected.
+    let q = Qcow2::open(img).unwrap();
+    let entries = q.l2_table_entries(l2 as u64).unwrap();
+    assert_eq!(entries[0].reserved_bits, (1 << 58) | 0x2);
+    assert!(!entries[0].misaligned);
+    assert!(entries[1].zero);
+    assert_eq!(entries[1].host_offset, (6 << 16) + 512);
+    assert!(entries[1].misaligned);
+}
+
+// Decode a compressed L2 entry in an image with the given cluster size.
+fn compressed(cluster_bits: u32, entry: u64) -> (u64, Option<u64>) {
+    let builder = ImageBuilder::new().cluster_bits(cluster_bits).data(0, b"data");
+    let mut img = builder.build();
+    let l2 = 4 << cluster_bits;
+    img[l2..l2 + 8].copy_from_slice(&entry.to_be_bytes());
+    let q = Qcow2::open(img).unwrap();
+    let e = q.l2_table_entries(l2 as u64).unwrap()[0];
+    (e.host_offset, e.compressed_size)
+}
+
+#[test]
+fn compressed_descriptors() {
+    // 4 KiB clusters: 58 bits of offset, 6 sectors.
+    assert_eq!(compressed(12, 0x5400_0010_0000_0010), (0x10_0000_0010, Some(6 * 512 - 0x10)));
+    // 64 KiB clusters: 54 bits of offset, 4 sectors.
+    assert_eq!(compressed(16, 0x40c0_0000_0005_0123), (0x5_0123, Some(4 * 512 - 0x123)));
+    // 2 MiB clusters: 49 bits of offset, 4096 sectors.
+    assert_eq!(compressed(21, 0x5ffe_0001_2345_6789),
+               (0x1_2345_6789, Some(4096 * 512 - 0x189)));
+}
+
+#[test]
+fn allocation_stats() {
+    let cs = 65536;
+    let builder = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .data(cs, &[2; 16])
+        .data(2 * cs, &[3; 16])
+        .data(4 * cs, &[4; 16])
+        .size(10 * cs + 100);
+    let mut img = builder.build();
+    let l2 = 4 * cs as usize;
+    img[l2 + 5 * 8..l2 + 6 * 8].copy_from_slice(&0x40c0_0000_0005_0123u64.to_be_bytes());
+    img[l2 + 6 * 8..l2 + 7 * 8].copy_from_slice(&1u64.to_be_bytes());
+    let q = Qcow2::open(img).unwrap();
+
+    // The first three data clusters are one extent, the fourth isn't next to them in the disk.
+    let stats = q.allocation_stats().unwrap();
+    assert_eq!((stats.data_clusters, stats.data_bytes), (4, 4 * cs));
+    assert_eq!((stats.zero_clusters, stats.zero_bytes), (1, cs));
+    assert_eq!((stats.compressed_clusters, stats.compressed_bytes), (1, cs));
+    assert_eq!(stats.compressed_stored_bytes, 4 * 512 - 0x123);
+    assert_eq!((stats.hole_clusters, stats.hole_bytes), (5, 4 * cs + 100));
+    assert_eq!(stats.l2_tables, 1);
+    assert_eq!(stats.extent_histogram, vec![1, 1]);
+
+    assert_eq!(AllocationStats::extent_bucket(0), (1, 1));
+    assert_eq!(AllocationStats::extent_bucket(1), (2, 4));
+    assert_eq!(AllocationStats::extent_bucket(3), (17, 64));
+}
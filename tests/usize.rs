@@ -0,0 +1,101 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::io;
+
+use common::ImageBuilder;
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::{Qcow2, Storage};
+
+const CS: u64 = 65536;
+
+#[test]
+fn offsets_past_4g() {
+    let img = ImageBuilder::new().size(8 << 30).data((5 << 30) + CS, &[1; 16]).build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    let mut buf = [0; 8];
+    qcow.reader().unwrap().read_exact_at((5 << 30) + CS + 4, &mut buf).unwrap();
+    assert_eq!(buf, [1; 8]);
+    // The same offset less 4 GiB is a hole, so a truncated offset would read zeros.
+    qcow.reader().unwrap().read_exact_at((1 << 30) + CS + 4, &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+
+    qcow.writer().unwrap().write_all_at((7 << 30) - 4, &[2; 8]).unwrap();
+    let reader = qcow.reader().unwrap();
+    reader.read_exact_at((7 << 30) - 4, &mut buf).unwrap();
+    assert_eq!(buf, [2; 8]);
+    reader.read_exact_at((3 << 30) - 4, &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
+}
+
+#[test]
+fn vec_set_len_too_big() {
+    let mut v = vec![1; 16];
+    let err = Storage::set_len(&mut v, u64::MAX).unwrap_err();
+    assert!(matches!(err.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::OutOfMemory));
+    assert_eq!(v.len(), 16);
+    Storage::set_len(&mut v, 8).unwrap();
+    assert_eq!(v, [1; 8]);
+}
+
+// On a 64-bit target, these tables would really be read into memory, so they're only tried with
+// a 32-bit usize. On an x86_64 host, `cargo test --target i686-unknown-linux-musl` runs them,
+// and `cross` can run them for targets such as armv7.
+#[cfg(target_pointer_width = "32")]
+mod huge {
+    use std::cmp::min;
+
+    use super::*;
+    use common::SnapshotSpec;
+    use positioned_io::Size;
+    use qcow2::Error;
+
+    // An image followed by zeros, up to a size much bigger than memory.
+    struct Sparse {
+        img: Vec<u8>,
+        size: u64,
+    }
+
+    impl ReadAt for Sparse {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            if pos >= self.size {
+                return Ok(0);
+            }
+            let len = min(buf.len() as u64, self.size - pos) as usize;
+            let buf = &mut buf[..len];
+            buf.fill(0);
+            self.img.read_at(pos, buf)?;
+            Ok(len)
+        }
+    }
+
+    impl Size for Sparse {
+        fn size(&self) -> io::Result<Option<u64>> {
+            Ok(Some(self.size))
+        }
+    }
+
+    // A snapshot with an L1 table of 4 GiB. The active L1 table can't be so big, since the
+    // virtual size is limited.
+    #[test]
+    fn huge_l1_table() {
+        let mut img = ImageBuilder::new().snapshot(SnapshotSpec::new("1", "huge")).build();
+        let snapshots = u64::from_be_bytes(img[64..72].try_into().unwrap()) as usize;
+        img[snapshots + 8..snapshots + 12].copy_from_slice(&(1u32 << 29).to_be_bytes());
+        let qcow = Qcow2::open(Sparse { img, size: 1 << 33 }).unwrap();
+        // Readers refuse such a big table anyway, but checking reads it.
+        assert!(matches!(qcow.snapshot_reader("1"), Err(Error::FileFormat(_))));
+        assert!(matches!(qcow.check(), Err(Error::UnsupportedFeature(_))));
+    }
+
+    // A refcount table of 4 GiB.
+    #[test]
+    fn huge_refcount_table() {
+        let mut img = ImageBuilder::new().build();
+        img[56..60].copy_from_slice(&(1u32 << 16).to_be_bytes());
+        let qcow = Qcow2::open(Sparse { img, size: 1 << 33 }).unwrap();
+        assert!(matches!(qcow.check(), Err(Error::UnsupportedFeature(_))));
+    }
+}
@@ -0,0 +1,56 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+use common::ImageBuilder;
+use positioned_io::ReadAt;
+use qcow2::{Cluster, MemBackend, Qcow2};
+
+const CS: u64 = 65536;
+
+// What a browser would do with an image dropped on a page: parse it from memory, describe it,
+// and read from it. Nothing here needs a filesystem.
+#[test]
+fn in_memory() {
+    let img = ImageBuilder::new().size(4 * CS).data(CS, b"hello").build();
+    let qcow = Qcow2::open(MemBackend(img.into_boxed_slice())).unwrap();
+    let info = qcow.info().unwrap();
+    assert_eq!(info.virtual_size, 4 * CS);
+    assert_eq!(info.cluster_size, CS);
+
+    let reader = qcow.reader().unwrap();
+    let mut buf = [0; 5];
+    reader.read_exact_at(CS, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    let data = reader.clusters().filter(|c| matches!(c, Ok(Cluster::Data(_)))).count();
+    assert_eq!(data, 1);
+}
+
+// Check that the library builds for wasm32, if that target is installed. Nothing is run, since
+// that needs a wasm runtime.
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+fn wasm32_builds() {
+    let target = "wasm32-unknown-unknown";
+    let libdir = Command::new("rustc")
+        .args(["--print", "target-libdir", "--target", target])
+        .output()
+        .unwrap();
+    let libdir = String::from_utf8(libdir.stdout).unwrap();
+    if !Path::new(libdir.trim()).exists() {
+        eprintln!("skipping, the {} target isn't installed", target);
+        return;
+    }
+
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--all-features", "--target", target])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("CARGO_TARGET_DIR", Path::new(env!("CARGO_TARGET_TMPDIR")).join("wasm32"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
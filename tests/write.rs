@@ -0,0 +1,342 @@
+extern crate positioned_io;
+extern crate qcow2;
+
+mod common;
+
+use common::{ImageBuilder, Op, RecordingIo, SnapshotSpec};
+use positioned_io::{ReadAt, WriteAt};
+use qcow2::{Preallocation, Qcow2, Storage, ZeroMode};
+
+fn assert_storage<S: Storage>() {}
+
+#[test]
+fn storage_impls() {
+    assert_storage::<common::WriteFile>();
+    assert_storage::<Vec<u8>>();
+    assert_storage::<&mut Vec<u8>>();
+    assert_storage::<&mut RecordingIo>();
+}
+
+#[test]
+fn sync() {
+    let mut io = RecordingIo::new(ImageBuilder::new().build());
+    let mut qcow = Qcow2::open(&mut io).unwrap();
+    qcow.sync().unwrap();
+    drop(qcow);
+    assert_eq!(io.ops, vec![Op::Sync]);
+}
+
+#[test]
+fn sync_file() {
+    let path = std::env::temp_dir().join(format!("qcow2-write-{}.qcow2", std::process::id()));
+    std::fs::write(&path, ImageBuilder::new().build()).unwrap();
+    let mut qcow = Qcow2::open(common::open_rw(&path)).unwrap();
+    qcow.sync().unwrap();
+    drop(qcow);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn write_read() {
+    let mut img = ImageBuilder::new().build();
+    let data: Vec<u8> = (0..100).collect();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        let mut writer = qcow.writer().unwrap();
+        writer.write_all_at(65536 - 50, &data).unwrap();
+        writer.write_all_at(700000, &data).unwrap();
+        let mut buf = vec![0; 100];
+        writer.read_exact_at(65536 - 50, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    let qcow = Qcow2::open(img).unwrap();
+    let reader = qcow.reader().unwrap();
+    let mut buf = vec![0; 200];
+    reader.read_exact_at(65536 - 100, &mut buf).unwrap();
+    assert_eq!(buf[..50], [0; 50]);
+    assert_eq!(buf[50..150], data[..]);
+    assert_eq!(buf[150..], [0; 50]);
+    reader.read_exact_at(700000, &mut buf[..100]).unwrap();
+    assert_eq!(buf[..100], data[..]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn write_order() {
+    let cs = 65536;
+    let mut io = RecordingIo::new(ImageBuilder::new().build());
+    let mut qcow = Qcow2::open(&mut io).unwrap();
+    qcow.writer().unwrap().write_all_at(0, &[1]).unwrap();
+    drop(qcow);
+    // The L2 table goes in cluster 4 and the data in cluster 5.
+    assert_eq!(io.ops,
+               vec![Op::Write { pos: 2 * cs + 8, len: 4 },
+                    Op::Sync,
+                    Op::Write { pos: 5 * cs, len: cs as usize },
+                    Op::Sync,
+                    Op::Write { pos: 4 * cs, len: cs as usize },
+                    Op::Sync,
+                    Op::Write { pos: 3 * cs, len: 8 }]);
+}
+
+#[test]
+fn write_in_place() {
+    let cs = 65536;
+    let mut io = RecordingIo::new(ImageBuilder::new().data(0, &[1; 16]).build());
+    let mut qcow = Qcow2::open(&mut io).unwrap();
+    qcow.writer().unwrap().write_all_at(8, &[2; 4]).unwrap();
+    drop(qcow);
+    assert_eq!(io.ops, vec![Op::Write { pos: 5 * cs + 8, len: 4 }]);
+
+    let qcow = Qcow2::open(io.inner).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1]);
+}
+
+#[test]
+fn write_snapshot() {
+    let mut img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        let mut writer = qcow.writer().unwrap();
+        writer.write_all_at(8, &[2; 4]).unwrap();
+        writer.write_all_at(65536, &[3; 4]).unwrap();
+    }
+
+    let qcow = Qcow2::open(img).unwrap();
+    let mut buf = [0; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1]);
+    qcow.reader().unwrap().read_exact_at(65536, &mut buf[..4]).unwrap();
+    assert_eq!(buf[..4], [3; 4]);
+
+    let snap = qcow.snapshot_reader("snap").unwrap();
+    snap.read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    snap.read_exact_at(65536, &mut buf[..4]).unwrap();
+    assert_eq!(buf[..4], [0; 4]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn write_past_end() {
+    let size = 1 << 20;
+    let mut img = ImageBuilder::new().size(size).build();
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    let mut writer = qcow.writer().unwrap();
+    assert_eq!(writer.write_at(size, &[1; 4]).unwrap(), 0);
+    assert_eq!(writer.write_at(size - 2, &[1; 4]).unwrap(), 2);
+}
+
+#[test]
+fn write_dirty() {
+    let img = ImageBuilder { incompatible: 1, ..ImageBuilder::new() }.build();
+    let mut qcow = Qcow2::open(img).unwrap();
+    assert_eq!(qcow.writer().err().unwrap().to_string(),
+               "Unsupported feature: writing to a dirty image");
+}
+
+#[test]
+fn grow_refcount_table() {
+    // With 512-byte clusters and 64-bit refcounts, one refcount table cluster covers only 4096
+    // clusters, or 2 MiB.
+    let size = 4 << 20;
+    let builder = ImageBuilder::new().cluster_bits(9).refcount_order(6).size(size);
+    let mut img = builder.build();
+    let chunk: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        let mut writer = qcow.writer().unwrap();
+        for pos in (0..3 << 20).step_by(chunk.len()) {
+            writer.write_all_at(pos, &chunk).unwrap();
+        }
+    }
+
+    let qcow = Qcow2::open(img).unwrap();
+    let table = qcow.refcount_table_entries().unwrap();
+    assert!(table.len() > 64);
+    assert!(qcow.check().unwrap().is_clean());
+    let reader = qcow.reader().unwrap();
+    let mut buf = vec![0; chunk.len()];
+    for pos in (0..3 << 20).step_by(chunk.len()) {
+        reader.read_exact_at(pos, &mut buf).unwrap();
+        assert_eq!(buf, chunk);
+    }
+}
+
+// Get the L2 entries of the first L2 table.
+fn first_l2_entries<I: ReadAt>(qcow: &Qcow2<I>) -> Vec<qcow2::L2TableEntry> {
+    let l1 = qcow.l1_table_entries().unwrap();
+    qcow.l2_table_entries(l1[0].l2_offset).unwrap()
+}
+
+// Check that writes to a preallocated range need no allocation.
+fn check_preallocated(mut io: RecordingIo, len: u64) {
+    let refcount_block = 2 * 65536..3 * 65536;
+    io.ops.clear();
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        let mut writer = qcow.writer().unwrap();
+        for pos in (0..len - 100).step_by(30000) {
+            writer.write_all_at(pos, &[7; 100]).unwrap();
+        }
+    }
+    for op in &io.ops {
+        if let Op::Write { pos, .. } = *op {
+            assert!(!refcount_block.contains(&pos));
+        }
+    }
+    let qcow = Qcow2::open(io.inner).unwrap();
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn preallocate_metadata() {
+    let mut io = RecordingIo::new(ImageBuilder::new().build());
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        qcow.writer().unwrap().preallocate(0, 1 << 20, Preallocation::Metadata).unwrap();
+    }
+    // Refcounts, the end of the data, the L2 table and the L1 table.
+    assert_eq!(io.ops.iter().filter(|op| **op != Op::Sync).count(), 4);
+    {
+        let qcow = Qcow2::open(&mut io).unwrap();
+        let entries = first_l2_entries(&qcow);
+        assert!(entries[..16].iter().all(|e| e.zero && e.copied && e.host_offset != 0));
+        let mut buf = vec![1; 1 << 20];
+        qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert!(qcow.check().unwrap().is_clean());
+    }
+
+    // Nothing more to do the second time.
+    io.ops.clear();
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        qcow.writer().unwrap().preallocate(0, 1 << 20, Preallocation::Metadata).unwrap();
+    }
+    assert_eq!(io.ops, vec![]);
+    check_preallocated(io, 1 << 20);
+}
+
+#[test]
+fn preallocate_full() {
+    let mut io = RecordingIo::new(ImageBuilder::new().data(0, b"data").build());
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        qcow.writer().unwrap().preallocate(100, 70000, Preallocation::Full).unwrap();
+    }
+    {
+        let qcow = Qcow2::open(&mut io).unwrap();
+        let entries = first_l2_entries(&qcow);
+        assert!(entries[..2].iter().all(|e| !e.zero && e.copied && e.host_offset != 0));
+        assert_eq!(entries[2].host_offset, 0);
+        let mut buf = [0; 8];
+        qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"data\0\0\0\0");
+        assert!(qcow.check().unwrap().is_clean());
+    }
+
+    // Writes to the full clusters go straight to the data.
+    io.ops.clear();
+    {
+        let mut qcow = Qcow2::open(&mut io).unwrap();
+        qcow.writer().unwrap().write_all_at(65536, &[1; 10]).unwrap();
+    }
+    assert_eq!(io.ops.len(), 1);
+    check_preallocated(io, 2 * 65536);
+}
+
+#[test]
+fn preallocate_snapshot() {
+    let mut img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.writer().unwrap().preallocate(0, 1 << 20, Preallocation::Metadata).unwrap();
+    }
+    let qcow = Qcow2::open(&mut img).unwrap();
+    assert!(first_l2_entries(&qcow)[..16].iter().all(|e| e.copied));
+    let mut buf = [0; 17];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf[..16], [1; 16]);
+    assert_eq!(buf[16], 0);
+    qcow.snapshot_reader("snap").unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf[..16], [1; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+    drop(qcow);
+    check_preallocated(RecordingIo::new(img), 1 << 20);
+}
+
+#[test]
+fn zero_discard() {
+    let mut img = ImageBuilder::new().data(0, &[1; 16]).data(655360, &[2; 16]).build();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.writer().unwrap().zero_entire_image(ZeroMode::Discard, true).unwrap();
+    }
+    // Only the header, refcount table, refcount block and L1 table are left.
+    assert_eq!(img.len(), 4 * 65536);
+
+    let mut qcow = Qcow2::open(&mut img).unwrap();
+    assert!(qcow.l1_table_entries().unwrap().iter().all(|e| e.raw == 0));
+    let mut buf = vec![1; 1 << 20];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+    assert!(qcow.check().unwrap().is_clean());
+
+    qcow.writer().unwrap().write_all_at(0, &[3; 16]).unwrap();
+    qcow.reader().unwrap().read_exact_at(0, &mut buf[..16]).unwrap();
+    assert_eq!(buf[..16], [3; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn zero_flag() {
+    let mut img = ImageBuilder::new().data(0, &[1; 16]).build();
+    let len = img.len();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.writer().unwrap().zero_entire_image(ZeroMode::ZeroFlag, false).unwrap();
+    }
+    // The new L2 table can't reuse the old one, which is only freed once nothing points at it.
+    assert_eq!(img.len(), len + 65536);
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    let entries = first_l2_entries(&qcow);
+    assert!(entries.iter().all(|e| e.zero && e.host_offset == 0));
+    let mut buf = [1; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}
+
+#[test]
+fn zero_snapshot() {
+    let mut img = ImageBuilder::new()
+        .data(0, &[1; 16])
+        .snapshot(SnapshotSpec::new("1", "snap"))
+        .build();
+    let len = img.len();
+    {
+        let mut qcow = Qcow2::open(&mut img).unwrap();
+        qcow.writer().unwrap().zero_entire_image(ZeroMode::Discard, true).unwrap();
+    }
+    // The snapshot still uses everything.
+    assert_eq!(img.len(), len);
+
+    let qcow = Qcow2::open(&mut img).unwrap();
+    let mut buf = [1; 16];
+    qcow.reader().unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [0; 16]);
+    qcow.snapshot_reader("snap").unwrap().read_exact_at(0, &mut buf).unwrap();
+    assert_eq!(buf, [1; 16]);
+    assert!(qcow.check().unwrap().is_clean());
+}